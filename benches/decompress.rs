@@ -0,0 +1,72 @@
+//! Compares LZO implementations on a chunk-sized buffer and ways to read chunk files.
+//!
+//! Run with `cargo bench --features lzokay` to include all codecs.
+
+use backy_extract::{Codec, CHUNKSZ, READ_STRATEGIES};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rand::prelude::*;
+use std::fs::{self, File};
+use tempdir::TempDir;
+
+// Number of chunk files read per iteration in the `load` benchmark
+const FILES: usize = 64;
+
+// Half random, half repetitive data resembles typical VM image chunks.
+fn sample_chunk() -> Vec<u8> {
+    let mut rng = StdRng::seed_from_u64(0x6261636b79);
+    let mut buf = vec![0; CHUNKSZ];
+    for (i, block) in buf.chunks_mut(4096).enumerate() {
+        if i % 2 == 0 {
+            rng.fill(block);
+        } else {
+            for (j, b) in block.iter_mut().enumerate() {
+                *b = (j % 64) as u8;
+            }
+        }
+    }
+    buf
+}
+
+fn decompress(c: &mut Criterion) {
+    let compressed = Codec::Minilzo.compress(&sample_chunk()).unwrap();
+    let mut group = c.benchmark_group("decompress");
+    group.throughput(Throughput::Bytes(CHUNKSZ as u64));
+    for &codec in Codec::available() {
+        group.bench_function(codec.to_string(), |b| {
+            b.iter(|| codec.decompress(&compressed, CHUNKSZ).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// Reads and decompresses chunk files which are in the page cache, so that only the cost of
+// copying or mapping shows.
+fn load(c: &mut Criterion) {
+    let compressed = Codec::Minilzo.compress(&sample_chunk()).unwrap();
+    let td = TempDir::new("bench-load").unwrap();
+    let paths: Vec<_> = (0..FILES)
+        .map(|i| {
+            let p = td.path().join(format!("{}.chunk.lzo", i));
+            fs::write(&p, &compressed).unwrap();
+            p
+        })
+        .collect();
+    let mut group = c.benchmark_group("load");
+    group.throughput(Throughput::Bytes((FILES * CHUNKSZ) as u64));
+    for &reader in &READ_STRATEGIES {
+        group.bench_function(reader.to_string(), |b| {
+            b.iter(|| {
+                for p in &paths {
+                    let mut f = File::open(p).unwrap();
+                    let len = f.metadata().unwrap().len();
+                    let raw = reader.read(&mut f, len).unwrap();
+                    Codec::Minilzo.decompress(&raw, CHUNKSZ).unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decompress, load);
+criterion_main!(benches);
@@ -0,0 +1,336 @@
+//! Single-file archives of a revision.
+//!
+//! An archive carries everything needed to restore one revision: the revision map, the `.rev`
+//! file (if present) and all referenced chunk files in their compressed on-disk form. Archives
+//! are written and read sequentially, so they can be piped through ssh or uploaded as-is.
+//!
+//! # Format
+//!
+//! All integers are big-endian. `str` is a u16 length followed by UTF-8 bytes, `blob` is a u32
+//! length followed by raw bytes.
+//!
+//! ```text
+//! header:  "BKYARCH\0" version:u8 revision:str map:blob rev:blob
+//! chunks:  (0x01 id:str data:blob)* 0x00
+//! index:   count:u32 (id:str offset:u64 length:u32)*
+//! trailer: index_offset:u64 "BKYINDEX"
+//! ```
+//!
+//! Chunk data is the complete chunk file including its header. Index offsets point to the
+//! first data byte of the respective chunk record.
+
+use crate::backend::{self, Backend};
+use crate::chunkvec::ChunkVec;
+use crate::export::{self, resolve};
+use crate::framing::{read_str, write_blob, write_str, Counting};
+#[cfg(not(feature = "read-only"))]
+use crate::{backend::Fsync, framing::read_blob};
+use crate::{purgelock, ExtractError};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+#[cfg(not(feature = "read-only"))]
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const MAGIC: &[u8; 8] = b"BKYARCH\0";
+const INDEX_MAGIC: &[u8; 8] = b"BKYINDEX";
+const VERSION: u8 = 1;
+const TAG_END: u8 = 0;
+const TAG_CHUNK: u8 = 1;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Not a backy-extract archive")]
+    Magic,
+    #[error("Unsupported archive version {0}")]
+    Version(u8),
+    #[error("Malformed archive: {0}")]
+    Format(String),
+    #[error("Chunk {id} does not match its checksum (content hashes to {actual})")]
+    Checksum { id: String, actual: String },
+    #[error("Revision {} already exists in '{}'", .0, .1.display())]
+    RevisionExists(String, PathBuf),
+    #[error("Failed to read revision '{}'", .0.display())]
+    Revision(PathBuf, #[source] io::Error),
+    #[error("Failed to parse revision map")]
+    Map(#[source] ExtractError),
+    #[error("Failed to acquire purge lock for backup dir '{}'", .0.display())]
+    Lock(PathBuf, #[source] io::Error),
+    #[error(transparent)]
+    Export(#[from] export::Error),
+    #[error("Chunked backend error")]
+    Backend(#[from] backend::Error),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Summary of a pack or unpack run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveStats {
+    pub revision: String,
+    /// Chunks contained in the archive
+    pub chunks: usize,
+    /// Chunks which were already present in the destination store (unpack only)
+    pub skipped: usize,
+    /// Compressed chunk bytes transferred
+    pub bytes: u64,
+}
+
+/// Location of a chunk inside an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub id: String,
+    pub offset: u64,
+    pub length: u32,
+}
+
+// Upper bound for map and .rev sizes: a map for a 16 TiB image is about 270 MiB
+#[cfg(not(feature = "read-only"))]
+const MAX_META: usize = 1 << 30;
+// Incompressible chunks grow slightly when LZO compressed
+#[cfg(not(feature = "read-only"))]
+const MAX_CHUNK: usize = crate::CHUNKSZ + crate::CHUNKSZ / 16 + 1024;
+
+/// Writes the revision `revfile` (a revision map path as passed to `Extractor::init`) and all
+/// chunks it references as archive to `out`.
+pub fn pack<P: AsRef<Path>, W: Write>(revfile: P, out: W) -> Result<ArchiveStats> {
+    let (basedir, map) = resolve(revfile.as_ref())?;
+    let _lock = purgelock(&basedir).map_err(|e| Error::Lock(basedir.clone(), e))?;
+    let be = Backend::open(&basedir)?;
+    let json = fs::read(&map).map_err(|e| Error::Revision(map.clone(), e))?;
+    let chunks = ChunkVec::from_slice(&json).map_err(Error::Map)?;
+    let rev = match fs::read(map.with_extension("rev")) {
+        Ok(r) => r,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(Error::Revision(map.with_extension("rev"), e)),
+    };
+    let name = map
+        .file_name()
+        .expect("canonical path has a file name")
+        .to_string_lossy()
+        .into_owned();
+
+    let mut w = Counting::new(out);
+    w.write_all(MAGIC)?;
+    w.write_u8(VERSION)?;
+    write_str(&mut w, &name)?;
+    write_blob(&mut w, &json)?;
+    write_blob(&mut w, &rev)?;
+    let mut stats = ArchiveStats {
+        revision: name,
+        ..ArchiveStats::default()
+    };
+    let mut index = Vec::with_capacity(chunks.unique());
+    for id in chunks.ids() {
+        let data = fs::read(be.filename(id))?;
+        w.write_u8(TAG_CHUNK)?;
+        write_str(&mut w, id)?;
+        index.push(IndexEntry {
+            id: id.to_string(),
+            offset: w.pos + 4,
+            length: data.len() as u32,
+        });
+        write_blob(&mut w, &data)?;
+        stats.chunks += 1;
+        stats.bytes += data.len() as u64;
+    }
+    w.write_u8(TAG_END)?;
+    let index_offset = w.pos;
+    w.write_u32::<BigEndian>(index.len() as u32)?;
+    for e in &index {
+        write_str(&mut w, &e.id)?;
+        w.write_u64::<BigEndian>(e.offset)?;
+        w.write_u32::<BigEndian>(e.length)?;
+    }
+    w.write_u64::<BigEndian>(index_offset)?;
+    w.write_all(INDEX_MAGIC)?;
+    w.flush()?;
+    Ok(stats)
+}
+
+/// Reads the chunk index from the end of an archive.
+pub fn read_index<R: Read + Seek>(mut r: R) -> Result<Vec<IndexEntry>> {
+    r.seek(SeekFrom::End(-16))?;
+    let index_offset = r.read_u64::<BigEndian>()?;
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != INDEX_MAGIC {
+        return Err(Error::Magic);
+    }
+    r.seek(SeekFrom::Start(index_offset))?;
+    let n = r.read_u32::<BigEndian>()?;
+    (0..n)
+        .map(|_| {
+            Ok(IndexEntry {
+                id: read_str(&mut r)?,
+                offset: r.read_u64::<BigEndian>()?,
+                length: r.read_u32::<BigEndian>()?,
+            })
+        })
+        .collect()
+}
+
+/// Reads an archive from `input` and adds its revision to the store at `dest`. A new store is
+/// created if `dest` does not contain one. Chunks already present are not overwritten.
+///
+/// Every chunk is decompressed and checked against its ID before it is written, and once more
+/// after it has been written. The revision map is written last so that backy never sees a
+/// revision with missing chunks. Chunk files and directories are flushed to disk.
+///
+/// Not available with the `read-only` feature.
+#[cfg(not(feature = "read-only"))]
+pub fn unpack<R: Read, P: AsRef<Path>>(input: R, dest: P) -> Result<ArchiveStats> {
+    unpack_with(input, dest, Fsync::default())
+}
+
+/// Like [unpack](fn.unpack.html), but flushes data to disk according to `fsync`.
+#[cfg(not(feature = "read-only"))]
+pub fn unpack_with<R: Read, P: AsRef<Path>>(
+    mut input: R,
+    dest: P,
+    fsync: Fsync,
+) -> Result<ArchiveStats> {
+    let dest = dest.as_ref();
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::Magic);
+    }
+    match input.read_u8()? {
+        VERSION => (),
+        v => return Err(Error::Version(v)),
+    }
+    let name = read_str(&mut input)?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Error::Format(format!("invalid revision name '{}'", name)));
+    }
+    let json = read_blob(&mut input, MAX_META)?;
+    let rev = read_blob(&mut input, MAX_META)?;
+    let chunks = ChunkVec::from_slice(&json).map_err(Error::Map)?;
+
+    let be = Backend::create(dest)?.with_fsync(fsync);
+    let _lock = purgelock(dest).map_err(|e| Error::Lock(dest.to_owned(), e))?;
+    let map = dest.join(&name);
+    if map.exists() {
+        return Err(Error::RevisionExists(name, dest.to_owned()));
+    }
+    let mut stats = ArchiveStats {
+        revision: name,
+        ..ArchiveStats::default()
+    };
+    let mut seen = HashSet::with_capacity(chunks.unique());
+    loop {
+        match input.read_u8()? {
+            TAG_END => break,
+            TAG_CHUNK => (),
+            t => return Err(Error::Format(format!("unknown record type {}", t))),
+        }
+        let id = read_str(&mut input)?;
+        let data = read_blob(&mut input, MAX_CHUNK)?;
+        if id.len() < 2 || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::Format(format!("invalid chunk id '{}'", id)));
+        }
+        let actual = be.hash(&backend::decode(&data, Default::default())?);
+        if actual != id {
+            return Err(Error::Checksum { id, actual });
+        }
+        stats.chunks += 1;
+        stats.bytes += data.len() as u64;
+        if be.filename(&id).exists() {
+            stats.skipped += 1;
+        } else {
+            be.commit(&id, &data)?;
+        }
+        seen.insert(id);
+    }
+    if let Some(missing) = chunks.ids().find(|id| !seen.contains(id.as_str())) {
+        return Err(Error::Format(format!("chunk {} missing", missing)));
+    }
+    if !rev.is_empty() {
+        fs::write(map.with_extension("rev"), rev)?;
+    }
+    fs::write(map, json)?;
+    Ok(stats)
+}
+
+#[cfg(all(test, not(feature = "read-only")))]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Slot, StoreBuilder};
+    use crate::{Extractor, Stream};
+    use std::io::Cursor;
+    use tempdir::TempDir;
+
+    const REV: &str = "ArchivedRevisionzzzzzz";
+
+    fn packed(tmp: &TempDir, slots: &[Slot]) -> Vec<u8> {
+        let maps = StoreBuilder::new()
+            .revision(REV, testing::layout(slots))
+            .write(tmp.path().join("src"))
+            .unwrap();
+        let mut buf = Vec::new();
+        let stats = pack(&maps[0], &mut buf).unwrap();
+        assert_eq!(stats.revision, REV);
+        buf
+    }
+
+    #[test]
+    fn pack_unpack_restore() {
+        let tmp = TempDir::new("archive").unwrap();
+        let slots = [Slot::Data(1), Slot::Hole, Slot::Dup(0), Slot::Data(2)];
+        let archive = packed(&tmp, &slots);
+
+        let index = read_index(Cursor::new(&archive)).unwrap();
+        assert_eq!(index.len(), 2);
+        let e = &index[0];
+        let data = &archive[e.offset as usize..e.offset as usize + e.length as usize];
+        assert_eq!(
+            backend::hash(&backend::decode(data, Default::default()).unwrap()),
+            e.id
+        );
+
+        let dest = tmp.path().join("dest");
+        let stats = unpack(&archive[..], &dest).unwrap();
+        assert_eq!((stats.chunks, stats.skipped), (2, 0));
+        assert!(dest.join(REV).with_extension("rev").exists());
+        let mut buf = Vec::new();
+        Extractor::init(dest.join(REV))
+            .unwrap()
+            .extract(Stream::new(&mut buf))
+            .unwrap();
+        assert!(buf == testing::image(&testing::layout(&slots)));
+
+        match unpack(&archive[..], &dest) {
+            Err(Error::RevisionExists(..)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let file = tmp.path().join("rev.bkyarch");
+        fs::write(&file, &archive).unwrap();
+        assert!(matches!(
+            Extractor::init(&file),
+            Err(ExtractError::Backend(backend::Error::Unsupported(
+                backend::Layout::Packed
+            )))
+        ));
+    }
+
+    #[test]
+    fn reject_damaged_archive() {
+        let tmp = TempDir::new("archive").unwrap();
+        let mut archive = packed(&tmp, &[Slot::Data(7)]);
+        let e = read_index(Cursor::new(&archive)).unwrap().remove(0);
+        archive[e.offset as usize + 100] ^= 0xff;
+        let dest = tmp.path().join("dest");
+        assert!(unpack(&archive[..], &dest).is_err());
+        assert!(!dest.join(REV).exists());
+        assert!(!testing::chunk_path(&dest, &e.id).exists());
+
+        assert!(matches!(unpack(&b"garbage!"[..], &dest), Err(Error::Magic)));
+    }
+}
@@ -0,0 +1,111 @@
+//! Audit trail of chunk accesses.
+//!
+//! Every chunk which an audited restore reads from the store is recorded as one JSON object per
+//! line, e.g.:
+//!
+//! ```text
+//! {"time":"2021-03-01T12:00:00.000Z","job":"restore-42","uid":0,"pid":4711,
+//!  "revision":"VNzWKjnMqd6w58nzJwUZ98","chunk":"4db6e194fd398e8edb76e11054d73eb0"}
+//! ```
+//!
+//! (wrapped here for readability). The log file is only ever appended to. Records are written
+//! before the chunk is read, so failed reads show up as well.
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+/// Append-only JSONL file which receives audit records.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    job: &'a str,
+    uid: u32,
+    pid: u32,
+    revision: &'a str,
+    chunk: &'a str,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending. The file is created if necessary.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Arc<Self>> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Arc::new(Self {
+            file: Mutex::new(file),
+        }))
+    }
+
+    fn write(&self, rec: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(rec)?;
+        line.push(b'\n');
+        // a single write keeps lines intact even if several processes share the file
+        self.file.lock().expect("poisoned lock").write_all(&line)
+    }
+}
+
+/// Context in which chunks are accessed: who reads which revision.
+#[derive(Debug, Clone)]
+pub struct Trail {
+    log: Arc<AuditLog>,
+    job: String,
+    revision: String,
+}
+
+impl Trail {
+    pub fn new(log: Arc<AuditLog>, job: &str, revision: &str) -> Self {
+        Self {
+            log,
+            job: job.to_owned(),
+            revision: revision.to_owned(),
+        }
+    }
+
+    /// Records an access to chunk `id`.
+    pub fn record(&self, id: &str) -> io::Result<()> {
+        self.log.write(&Record {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            job: &self.job,
+            uid: unsafe { libc::getuid() },
+            pid: process::id(),
+            revision: &self.revision,
+            chunk: id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn append_records() -> io::Result<()> {
+        let td = TempDir::new("audit")?;
+        let path = td.path().join("audit.log");
+        fs::write(&path, "{}\n")?;
+        let t = Trail::new(AuditLog::open(&path)?, "job1", "rev1");
+        t.record("0123")?;
+        t.record("4567")?;
+        let lines: Vec<Value> = fs::read_to_string(&path)?
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["job"], "job1");
+        assert_eq!(lines[1]["revision"], "rev1");
+        assert_eq!(lines[2]["chunk"], "4567");
+        assert_eq!(lines[2]["pid"], process::id());
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! Store layout detection.
+
+use super::{Error, Result};
+
+use murmur3::murmur3_x64_128;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+// Leading bytes of single-file archives written by `backy-extract pack`
+const PACKED_MAGIC: &[u8; 8] = b"BKYARCH\0";
+
+/// On-disk organization of a backup directory or file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Layout {
+    /// backy's chunked store: one LZO compressed file per chunk below `chunks/`
+    V2,
+    /// Successor of v2. The tag is recognized, but the format cannot be read yet.
+    V3,
+    /// v2 store with encrypted chunk files (tag `v2-encrypted`), not readable yet
+    Encrypted,
+    /// Single-file archive of a revision as written by `pack`. Must be unpacked first.
+    Packed,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Layout::V2 => "v2",
+            Layout::V3 => "v3",
+            Layout::Encrypted => "v2-encrypted",
+            Layout::Packed => "packed",
+        })
+    }
+}
+
+/// Algorithm which chunk IDs are derived from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgo {
+    /// Hex-encoded 128 bit x64 murmur3 hash
+    Murmur3,
+}
+
+impl HashAlgo {
+    /// Computes the chunk ID of uncompressed chunk data.
+    pub fn hash(self, data: &[u8]) -> String {
+        match self {
+            HashAlgo::Murmur3 => hex::encode(
+                murmur3_x64_128(&mut io::Cursor::new(data), 0)
+                    .expect("in-memory read")
+                    .to_le_bytes(),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgo::Murmur3 => write!(f, "murmur3"),
+        }
+    }
+}
+
+/// What a store supports, as determined by [probe](fn.probe.html).
+///
+/// Code which saves chunks, reads single chunks or computes chunk IDs asks here instead of
+/// assuming a v2 store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub layout: Layout,
+    /// Chunks can be read by this version at all
+    pub readable: bool,
+    /// Chunks can be added to the store
+    pub save: bool,
+    /// How chunk IDs are computed
+    pub hash: HashAlgo,
+}
+
+impl Capabilities {
+    /// Capabilities of a store with layout `layout`.
+    pub fn of(layout: Layout) -> Self {
+        let v2 = layout == Layout::V2;
+        Self {
+            layout,
+            readable: v2,
+            save: v2 && cfg!(any(test, feature = "testing", not(feature = "read-only"))),
+            hash: HashAlgo::Murmur3,
+        }
+    }
+}
+
+// Missing files mean that there is no store, other I/O errors are passed on.
+fn not_found(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::NotFound => Error::NoStore,
+        _ => e.into(),
+    }
+}
+
+/// Determines the layout of the store at `path`, which is either a backup directory or a packed
+/// archive.
+///
+/// # Errors
+///
+/// Error::NoStore if `path` is neither, Error::VersionTag for unknown version tags. I/O errors
+/// other than missing files are returned as such, e.g. Error::PermissionDenied.
+pub fn probe<P: AsRef<Path>>(path: P) -> Result<Capabilities> {
+    let path = path.as_ref();
+    if path.is_file() {
+        let mut magic = [0; 8];
+        return match File::open(path).and_then(|mut f| f.read_exact(&mut magic)) {
+            Ok(()) if &magic == PACKED_MAGIC => Ok(Capabilities::of(Layout::Packed)),
+            Ok(()) => Err(Error::NoStore),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(Error::NoStore),
+            Err(e) => Err(not_found(e)),
+        };
+    }
+    let s = fs::read_to_string(path.join("chunks/store")).map_err(not_found)?;
+    let layout = match s.trim() {
+        "v2" => Layout::V2,
+        "v3" => Layout::V3,
+        "v2-encrypted" => Layout::Encrypted,
+        tag => return Err(Error::VersionTag(tag.to_owned())),
+    };
+    Ok(Capabilities::of(layout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir, write};
+    use tempdir::TempDir;
+
+    #[test]
+    fn probe_layouts() -> Result<()> {
+        let tmp = TempDir::new("probe_layouts")?;
+        create_dir(tmp.path().join("chunks"))?;
+        for (tag, layout) in &[
+            ("v2\n", Layout::V2),
+            ("v3", Layout::V3),
+            ("v2-encrypted", Layout::Encrypted),
+        ] {
+            write(tmp.path().join("chunks/store"), tag)?;
+            assert_eq!(probe(tmp.path())?.layout, *layout);
+        }
+        let caps = Capabilities::of(Layout::V2);
+        assert!(caps.readable && caps.save);
+
+        let archive = tmp.path().join("rev.bkyarch");
+        write(&archive, b"BKYARCH\0\x01")?;
+        let caps = probe(&archive)?;
+        assert_eq!(caps.layout, Layout::Packed);
+        assert!(!caps.readable && !caps.save);
+        write(&archive, b"garbage")?;
+        assert!(matches!(probe(&archive), Err(Error::NoStore)));
+        assert!(matches!(
+            probe(tmp.path().join("none")),
+            Err(Error::NoStore)
+        ));
+        fs::remove_file(tmp.path().join("chunks/store"))?;
+        create_dir(tmp.path().join("chunks/store"))?;
+        assert!(matches!(probe(tmp.path()), Err(Error::Corrupt(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! LZO implementations for chunk (de)compression.
+//!
+//! minilzo is always available. Alternative implementations are compiled in via cargo features
+//! and selected at runtime.
+
+use super::{Error, Result};
+
+use std::fmt;
+use std::str::FromStr;
+
+/// LZO implementation used to decompress chunks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// C reference implementation (liblzo2/minilzo)
+    #[default]
+    Minilzo,
+    /// Pure Rust implementation from the `lzokay` crate
+    #[cfg(feature = "lzokay")]
+    Lzokay,
+}
+
+impl Codec {
+    /// All codecs compiled into this binary.
+    pub fn available() -> &'static [Codec] {
+        &[
+            Codec::Minilzo,
+            #[cfg(feature = "lzokay")]
+            Codec::Lzokay,
+        ]
+    }
+
+    /// Decompresses raw LZO data (without chunk header). `len` is the expected uncompressed size.
+    pub fn decompress(self, src: &[u8], len: usize) -> Result<Vec<u8>> {
+        match self {
+            Codec::Minilzo => Ok(minilzo::decompress(src, len)?),
+            #[cfg(feature = "lzokay")]
+            Codec::Lzokay => {
+                let mut buf = vec![0; len];
+                let n = lzokay::decompress::decompress(src, &mut buf).map_err(Error::Lzokay)?;
+                buf.truncate(n);
+                Ok(buf)
+            }
+        }
+    }
+
+    /// Compresses data. Always uses minilzo since the output format is identical.
+    pub fn compress(self, src: &[u8]) -> Result<Vec<u8>> {
+        Ok(minilzo::compress(src)?)
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Codec::Minilzo => write!(f, "minilzo"),
+            #[cfg(feature = "lzokay")]
+            Codec::Lzokay => write!(f, "lzokay"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Codec::available()
+            .iter()
+            .find(|c| c.to_string() == s)
+            .copied()
+            .ok_or_else(|| Error::Codec(s.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip_all_codecs() -> Result<()> {
+        let data: Vec<u8> = (0..65536u32).map(|i| (i % 251) as u8).collect();
+        for c in Codec::available() {
+            let compressed = c.compress(&data)?;
+            assert_eq!(c.decompress(&compressed, data.len())?, data, "codec {}", c);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parse_codec() {
+        assert_eq!("minilzo".parse::<Codec>().unwrap(), Codec::Minilzo);
+        assert!("gzip".parse::<Codec>().is_err());
+    }
+}
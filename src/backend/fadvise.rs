@@ -1,13 +1,13 @@
-#![cfg(os = "linux")]
+#![cfg(target_os = "linux")]
 
-use libc::{c_int, posix_fadvise, POSIX_FADV_DONTNEED};
+use libc::{posix_fadvise, POSIX_FADV_DONTNEED};
 use std::fs::File;
 use std::os::unix::io::AsRawFd;
 
-pub fn dontneed(f: File) {
+/// Asks the kernel to drop cached pages of `f`, which has been read completely.
+pub fn dontneed(f: &File) {
     unsafe {
-        posix_fadvise(f.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED);
-        // Swallow file since it should not be used anyway ;-)
         // Swallow return code since we wouldn't bail out on error anyway
+        posix_fadvise(f.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED);
     }
 }
@@ -0,0 +1,181 @@
+//! Budget for open chunk files.
+//!
+//! Each chunk load opens a file of its own. With many decompression threads, concurrent restores
+//! or FUSE readers this may exhaust `RLIMIT_NOFILE`, which then shows up as EMFILE at random
+//! places. Chunk files are opened through an `FdBudget`: callers wait for a free slot instead of
+//! failing, and EMFILE caused by descriptors outside the budget is retried after a pause.
+
+use lazy_static::lazy_static;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Descriptors left to targets, log files, sockets etc.
+const RESERVE: usize = 64;
+const MIN_SLOTS: usize = 4;
+const MAX_SLOTS: usize = 1 << 16;
+// Pauses before retrying an open which failed with EMFILE/ENFILE
+const RETRIES: u32 = 8;
+const BACKOFF: Duration = Duration::from_millis(10);
+
+lazy_static! {
+    /// Budget shared by all backends in the process unless set otherwise
+    pub(crate) static ref DEFAULT: Arc<FdBudget> = Arc::new(FdBudget::from_rlimit());
+}
+
+/// Limits the number of chunk files open at the same time.
+#[derive(Debug)]
+pub struct FdBudget {
+    slots: usize,
+    free: Mutex<usize>,
+    cv: Condvar,
+}
+
+/// Permission to keep one file open. Returned to the budget when dropped.
+#[derive(Debug)]
+pub(crate) struct Slot<'a>(&'a FdBudget);
+
+/// File opened within a budget.
+#[derive(Debug)]
+pub(crate) struct BudgetFile<'a> {
+    // dropped before the slot
+    pub file: File,
+    _slot: Slot<'a>,
+}
+
+impl FdBudget {
+    /// Allows `slots` files to be open at the same time (at least 1).
+    pub fn new(slots: usize) -> Self {
+        let slots = slots.max(1);
+        Self {
+            slots,
+            free: Mutex::new(slots),
+            cv: Condvar::new(),
+        }
+    }
+
+    /// Derives the budget from the soft `RLIMIT_NOFILE`, keeping some descriptors in reserve.
+    pub fn from_rlimit() -> Self {
+        let mut lim = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        let soft = if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut lim) } == 0 {
+            lim.rlim_cur.min(MAX_SLOTS as libc::rlim_t) as usize
+        } else {
+            1024
+        };
+        Self::new(soft.saturating_sub(RESERVE).max(MIN_SLOTS))
+    }
+
+    /// Total number of slots.
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// Blocks until a slot is free.
+    pub(crate) fn acquire(&self) -> Slot<'_> {
+        let mut free = self.free.lock().expect("poisoned lock");
+        while *free == 0 {
+            free = self.cv.wait(free).expect("poisoned lock");
+        }
+        *free -= 1;
+        Slot(self)
+    }
+
+    /// Opens `path` for reading once a slot is free.
+    pub(crate) fn open(&self, path: &Path) -> io::Result<BudgetFile<'_>> {
+        self.open_with(path, |p| File::open(p))
+    }
+
+    /// Opens a file with `open` once a slot is free. Running out of descriptors anyway means
+    /// that someone outside the budget holds them, so the open is retried a few times.
+    pub(crate) fn open_with<F>(&self, path: &Path, open: F) -> io::Result<BudgetFile<'_>>
+    where
+        F: Fn(&Path) -> io::Result<File>,
+    {
+        let slot = self.acquire();
+        let mut pause = BACKOFF;
+        for _ in 0..RETRIES {
+            match open(path) {
+                Err(e) if exhausted(&e) => {
+                    thread::sleep(pause);
+                    pause *= 2;
+                }
+                res => {
+                    return res.map(|file| BudgetFile { file, _slot: slot });
+                }
+            }
+        }
+        open(path).map(|file| BudgetFile { file, _slot: slot })
+    }
+}
+
+fn exhausted(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().expect("poisoned lock") += 1;
+        self.0.cv.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn wait_for_free_slot() {
+        let budget = FdBudget::new(1);
+        let slot = budget.acquire();
+        let acquired = AtomicBool::new(false);
+        crossbeam::thread::scope(|s| {
+            s.spawn(|_| {
+                let _slot = budget.acquire();
+                acquired.store(true, Ordering::SeqCst);
+            });
+            thread::sleep(Duration::from_millis(50));
+            assert!(!acquired.load(Ordering::SeqCst));
+            drop(slot);
+        })
+        .unwrap();
+        assert!(acquired.load(Ordering::SeqCst));
+        assert_eq!(*budget.free.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn retry_on_emfile() {
+        let budget = FdBudget::new(2);
+        let tries = Mutex::new(0);
+        let f = budget
+            .open_with(Path::new("/dev/null"), |p| {
+                let mut tries = tries.lock().unwrap();
+                *tries += 1;
+                match *tries {
+                    1 | 2 => Err(io::Error::from_raw_os_error(libc::EMFILE)),
+                    _ => File::open(p),
+                }
+            })
+            .unwrap();
+        assert_eq!(*tries.lock().unwrap(), 3);
+        assert_eq!(*budget.free.lock().unwrap(), 1);
+        drop(f);
+        assert_eq!(*budget.free.lock().unwrap(), 2);
+        let err = budget
+            .open_with(Path::new("/nonexistent"), |p| File::open(p))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(*budget.free.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn budget_from_rlimit() {
+        assert!(FdBudget::from_rlimit().slots() >= MIN_SLOTS);
+    }
+}
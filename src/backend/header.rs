@@ -0,0 +1,295 @@
+//! Chunk file header.
+//!
+//! backy writes chunk files with a fixed 5 byte header followed by the LZO1X compressed
+//! contents:
+//!
+//! ```text
+//! 0xF0 len:u32
+//! ```
+//!
+//! `len` is the uncompressed length (big endian, like all numbers below). The extended header
+//! carries a versioned metadata block in front of the compressed data, so that future writers
+//! can add fields without breaking readers:
+//!
+//! ```text
+//! 0xF1 version:u8 mlen:u16 metadata[mlen]
+//! metadata: (tag:u8 len:u8 value[len])*
+//! ```
+//!
+//! Known records (version 1):
+//!
+//! ```text
+//! 0x01 codec:u8                   compression, 0 = LZO1X (default)
+//! 0x02 len:u32                    uncompressed length (mandatory)
+//! 0x03 algo:u8 digest[16]         checksum of the uncompressed data, 0 = murmur3
+//! ```
+//!
+//! Unknown tags below 0x80 are optional and skipped. Tags from 0x80 upwards mark records which
+//! must be understood to decode the chunk. Chunks with unknown critical records or header
+//! versions are rejected as unsupported rather than reported as corrupt.
+
+use super::{Error, HashAlgo, Result};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+const LEGACY: u8 = 0xF0;
+const EXTENDED: u8 = 0xF1;
+const VERSION: u8 = 1;
+
+const TAG_CODEC: u8 = 0x01;
+const TAG_LEN: u8 = 0x02;
+const TAG_CHECKSUM: u8 = 0x03;
+const CRITICAL: u8 = 0x80;
+
+const CODEC_LZO1X: u8 = 0;
+const ALGO_MURMUR3: u8 = 0;
+
+/// Size of the largest possible header
+pub const MAX_SIZE: usize = 4 + u16::MAX as usize;
+
+/// Parsed chunk file header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Metadata block version, 0 for the legacy header
+    pub version: u8,
+    /// Uncompressed length
+    pub len: usize,
+    /// murmur3 digest of the uncompressed data, if recorded
+    pub checksum: Option<[u8; 16]>,
+    /// Header length, i.e. offset of the compressed data
+    pub size: usize,
+}
+
+impl Header {
+    /// Legacy header as written by backy.
+    pub fn legacy(len: usize) -> Self {
+        Self {
+            version: 0,
+            len,
+            checksum: None,
+            size: 5,
+        }
+    }
+
+    /// Extended header with an optional checksum of the uncompressed data. Fails if `len`
+    /// does not fit into the header.
+    pub fn extended(len: usize, checksum: Option<[u8; 16]>) -> io::Result<Self> {
+        let mut h = Self {
+            version: VERSION,
+            len,
+            checksum,
+            size: 0,
+        };
+        h.size = h.to_vec()?.len();
+        Ok(h)
+    }
+
+    /// Reads a header from the start of `r`. Leaves `r` positioned at the compressed data.
+    pub fn read_from<R: Read>(mut r: R) -> Result<Self> {
+        match r.read_u8().map_err(eof_is_magic)? {
+            LEGACY => {
+                let len = r.read_u32::<BigEndian>().map_err(eof_is_magic)?;
+                Ok(Self::legacy(len as usize))
+            }
+            EXTENDED => {
+                let version = r.read_u8().map_err(eof_is_magic)?;
+                if version != VERSION {
+                    return Err(Error::HeaderVersion(version));
+                }
+                let mlen = r.read_u16::<BigEndian>().map_err(eof_is_magic)?;
+                let mut meta = vec![0; mlen as usize];
+                r.read_exact(&mut meta).map_err(eof_is_magic)?;
+                Self::parse_metadata(&meta)
+            }
+            _ => Err(Error::Magic),
+        }
+    }
+
+    /// Parses the header at the start of a chunk file's contents.
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        Self::read_from(buf)
+    }
+
+    fn parse_metadata(mut meta: &[u8]) -> Result<Self> {
+        let size = 4 + meta.len();
+        let mut len = None;
+        let mut checksum = None;
+        while !meta.is_empty() {
+            let (tag, rlen) = match meta {
+                [tag, rlen, ..] => (*tag, *rlen as usize),
+                _ => return Err(Error::Magic),
+            };
+            let value = meta.get(2..2 + rlen).ok_or(Error::Magic)?;
+            meta = &meta[2 + rlen..];
+            match (tag, value) {
+                (TAG_CODEC, &[CODEC_LZO1X]) => (),
+                (TAG_CODEC, &[codec]) => return Err(Error::Compression(codec)),
+                (TAG_LEN, &[a, b, c, d]) => len = Some(u32::from_be_bytes([a, b, c, d])),
+                (TAG_CHECKSUM, [ALGO_MURMUR3, digest @ ..]) if digest.len() == 16 => {
+                    checksum = <[u8; 16]>::try_from(digest).ok()
+                }
+                // checksums of other algorithms cannot be verified, but are not required
+                (TAG_CHECKSUM, [algo, ..]) if *algo != ALGO_MURMUR3 => (),
+                (TAG_CODEC, _) | (TAG_LEN, _) | (TAG_CHECKSUM, _) => return Err(Error::Magic),
+                (t, _) if t >= CRITICAL => return Err(Error::HeaderField(t)),
+                _ => (),
+            }
+        }
+        Ok(Self {
+            version: VERSION,
+            len: len.ok_or(Error::Magic)? as usize,
+            checksum,
+            size,
+        })
+    }
+
+    /// Writes the header in the format given by `version`, which must be 0 (legacy) or 1.
+    pub fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let len =
+            u32::try_from(self.len).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        match self.version {
+            0 => {
+                w.write_u8(LEGACY)?;
+                return w.write_u32::<BigEndian>(len);
+            }
+            VERSION => (),
+            v => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cannot write chunk header version {}", v),
+                ))
+            }
+        }
+        let mut meta = vec![TAG_LEN, 4];
+        meta.write_u32::<BigEndian>(len)?;
+        if let Some(digest) = &self.checksum {
+            meta.extend_from_slice(&[TAG_CHECKSUM, 17, ALGO_MURMUR3]);
+            meta.extend_from_slice(digest);
+        }
+        w.write_u8(EXTENDED)?;
+        w.write_u8(self.version)?;
+        w.write_u16::<BigEndian>(meta.len() as u16)?;
+        w.write_all(&meta)
+    }
+
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.size);
+        self.write(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Checks `data` against the recorded checksum, if any.
+    pub fn verify(&self, data: &[u8]) -> Result<()> {
+        match &self.checksum {
+            Some(digest) => {
+                let actual = HashAlgo::Murmur3.hash(data);
+                if actual == hex(digest) {
+                    Ok(())
+                } else {
+                    Err(Error::Checksum(actual))
+                }
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// A truncated header is not recognizable as header.
+fn eof_is_magic(e: io::Error) -> Error {
+    if e.kind() == io::ErrorKind::UnexpectedEof {
+        Error::Magic
+    } else {
+        e.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{hash, ZERO_HASH};
+    use crate::CHUNKSZ;
+
+    fn digest(hash: &str) -> [u8; 16] {
+        u128::from_str_radix(hash, 16).unwrap().to_be_bytes()
+    }
+
+    #[test]
+    fn legacy_header() {
+        let buf = [0xF0, 0, 0x40, 0, 0, 0x11];
+        let h = Header::parse(&buf).unwrap();
+        assert_eq!(h, Header::legacy(CHUNKSZ));
+        assert_eq!(h.size, 5);
+        assert_eq!(h.to_vec().unwrap(), &buf[..5]);
+        assert!(matches!(Header::parse(&buf[..4]), Err(Error::Magic)));
+        assert!(matches!(Header::parse(&[0x11; 5]), Err(Error::Magic)));
+    }
+
+    #[test]
+    fn extended_roundtrip() {
+        let h = Header::extended(CHUNKSZ, Some(digest(ZERO_HASH))).unwrap();
+        let mut buf = h.to_vec().unwrap();
+        assert_eq!(buf.len(), h.size);
+        assert_eq!(&buf[..4], &[0xF1, 1, 0, 25]);
+        buf.push(0x11);
+        assert_eq!(Header::parse(&buf).unwrap(), h);
+        assert!(matches!(Header::parse(&buf[..10]), Err(Error::Magic)));
+
+        let h = Header::extended(CHUNKSZ, None).unwrap();
+        assert_eq!(Header::parse(&h.to_vec().unwrap()).unwrap(), h);
+
+        // neither too long nor unknown versions are written
+        assert!(Header::extended(1 << 32, None).is_err());
+        let h = Header { version: 2, ..h };
+        assert!(h.to_vec().is_err());
+    }
+
+    #[test]
+    fn unknown_records() {
+        let with = |rec: &[u8]| {
+            let mut meta = vec![TAG_LEN, 4, 0, 0x40, 0, 0];
+            meta.extend_from_slice(rec);
+            let mut buf = vec![0xF1, 1, 0, meta.len() as u8];
+            buf.extend_from_slice(&meta);
+            Header::parse(&buf)
+        };
+        let h = with(&[0x7f, 3, 1, 2, 3]).unwrap();
+        assert_eq!((h.len, h.size), (CHUNKSZ, 15));
+        assert!(with(&[TAG_CHECKSUM, 2, 9, 9]).unwrap().checksum.is_none());
+        assert!(matches!(with(&[0x80, 0]), Err(Error::HeaderField(0x80))));
+        assert!(matches!(
+            with(&[TAG_CODEC, 1, 7]),
+            Err(Error::Compression(7))
+        ));
+        assert!(matches!(with(&[0x7f, 3, 1]), Err(Error::Magic)));
+        assert!(matches!(
+            Header::parse(&[0xF1, 2, 0, 0]),
+            Err(Error::HeaderVersion(2))
+        ));
+        // uncompressed length is mandatory
+        assert!(matches!(Header::parse(&[0xF1, 1, 0, 0]), Err(Error::Magic)));
+    }
+
+    #[test]
+    fn verify_checksum() {
+        let data = vec![0; CHUNKSZ];
+        Header::extended(CHUNKSZ, Some(digest(ZERO_HASH)))
+            .unwrap()
+            .verify(&data)
+            .unwrap();
+        Header::legacy(CHUNKSZ).verify(&data).unwrap();
+        let other = digest(&hash(b"other"));
+        match Header::extended(CHUNKSZ, Some(other))
+            .unwrap()
+            .verify(&data)
+        {
+            Err(Error::Checksum(actual)) => assert_eq!(actual, ZERO_HASH),
+            res => panic!("unexpected: {:?}", res),
+        }
+    }
+}
@@ -1,70 +1,319 @@
 //! Chunked backend access.
 //!
-//! Currently, we support only backy's chunked v2 data store. Other store
-//! formats may follow in the future.
+//! Currently, we support only backy's chunked v2 data store. Other layouts are recognized by
+//! [probe](fn.probe.html) so that they can be rejected with a meaningful message.
 
-#[cfg(feature = "fuse_driver")]
+mod caps;
+mod codec;
+mod fds;
+mod header;
+mod reader;
 mod rev;
-#[cfg(feature = "fuse_driver")]
-pub use rev::{Error as RevError, Rev, RevId};
+pub use caps::{probe, Capabilities, HashAlgo, Layout};
+pub use codec::Codec;
+pub use fds::FdBudget;
+pub use header::Header;
+pub use reader::{map_file, RawChunk, ReadStrategy, READ_STRATEGIES};
+#[cfg(test)]
+pub use rev::RevId;
+pub use rev::{Error as RevError, Rev, Trust};
 
-#[cfg(os = "linux")]
+#[cfg(target_os = "linux")]
 mod fadvise;
 
+use crate::audit::Trail;
 use crate::CHUNKSZ;
 
-use byteorder::{BigEndian, WriteBytesExt};
-use lazy_static::lazy_static;
 use log::debug;
-use smallvec::{smallvec, SmallVec};
-use std::convert::TryFrom;
-use std::fs::{self, File};
-use std::io::{self, Read, Write};
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+#[cfg(any(test, feature = "testing", not(feature = "read-only")))]
+use std::{fs, io::Write};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Backend metadata not found")]
-    NotFound,
+    NoStore,
     #[error("Unexpected version tag in chunk store: {0}")]
     VersionTag(String),
     #[error("Decompressed chunk has wrong size: {0}B")]
     Missized(usize),
     #[error("Compressed chunk does not start with magic number")]
     Magic,
+    #[error("Chunk header version {0} is not supported")]
+    HeaderVersion(u8),
+    #[error("Chunk header contains unknown mandatory field {0:#04x}")]
+    HeaderField(u8),
+    #[error("Chunk compression {0} is not supported")]
+    Compression(u8),
     #[error("Lzo compression format error")]
     Lzo(#[from] minilzo::Error),
+    #[cfg(feature = "lzokay")]
+    #[error("Lzo compression format error (lzokay): {0:?}")]
+    Lzokay(lzokay::Error),
+    #[error("Unknown LZO codec '{0}'")]
+    Codec(String),
+    #[error("Unknown chunk reader '{0}' (expected read or mmap)")]
+    ReadStrategy(String),
+    #[error("File not found")]
+    NotFound(#[source] io::Error),
+    #[error("Permission denied")]
+    PermissionDenied(#[source] io::Error),
+    #[error("Temporary I/O error")]
+    Transient(#[source] io::Error),
     #[error("I/O error")]
-    Io(#[from] io::Error),
+    Corrupt(#[source] io::Error),
+    #[error("Failed to write audit log")]
+    Audit(#[source] io::Error),
+    #[error("Chunk {id} reads back with wrong checksum {actual} after writing")]
+    Verify { id: String, actual: String },
+    #[error("Chunk contents have wrong checksum {0}")]
+    Checksum(String),
+    #[error("Unknown fsync policy '{0}' (expected never, chunks or all)")]
+    Fsync(String),
+    #[error("Chunk file has implausible size {0}B")]
+    FileSize(u64),
+    #[error("Chunk store layout '{0}' is not supported{}", unsupported_hint(*.0))]
+    Unsupported(Layout),
+    #[error("Chunk store layout '{0}' does not support saving chunks")]
+    ReadOnly(Layout),
+}
+
+fn unsupported_hint(layout: Layout) -> &'static str {
+    match layout {
+        Layout::Packed => " (unpack the archive first)",
+        _ => "",
+    }
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-lazy_static! {
-    pub static ref MAGIC: SmallVec<[u8; 5]> = {
-        let mut m = smallvec![0xF0];
-        m.write_u32::<BigEndian>(u32::try_from(CHUNKSZ).unwrap())
-            .unwrap();
-        m
-    };
+/// How a failure to access stored data should be dealt with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fault {
+    /// The file (or store) does not exist
+    NotFound,
+    /// Access has been refused
+    PermissionDenied,
+    /// The same operation may succeed if retried later, e.g. when out of file descriptors
+    Transient,
+    /// The data is damaged or cannot be read back
+    Corrupt,
 }
 
-fn decompress(f: &mut File) -> Result<Vec<u8>> {
-    debug!("read lzo from {:?}", f);
-    let mut buf = Vec::with_capacity(f.metadata()?.len() as usize);
-    f.read_to_end(&mut buf)?;
-    // first 5 bytes contain header
-    if buf[0..5] != MAGIC[..] {
-        Err(Error::Magic)
+impl Fault {
+    /// Classifies an I/O error.
+    pub fn of(e: &io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => return Fault::NotFound,
+            io::ErrorKind::PermissionDenied => return Fault::PermissionDenied,
+            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                return Fault::Transient
+            }
+            _ => (),
+        }
+        match e.raw_os_error() {
+            Some(libc::EROFS) => Fault::PermissionDenied,
+            Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOMEM) | Some(libc::ENOSPC)
+            | Some(libc::EBUSY) | Some(libc::ESTALE) => Fault::Transient,
+            // EIO, short reads and everything unexpected: don't count on it going away
+            _ => Fault::Corrupt,
+        }
+    }
+
+    pub fn is_transient(self) -> bool {
+        self == Fault::Transient
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Fault::NotFound => "not-found",
+            Fault::PermissionDenied => "permission-denied",
+            Fault::Transient => "transient",
+            Fault::Corrupt => "corrupt",
+        })
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match Fault::of(&e) {
+            Fault::NotFound => Error::NotFound(e),
+            Fault::PermissionDenied => Error::PermissionDenied(e),
+            Fault::Transient => Error::Transient(e),
+            Fault::Corrupt => Error::Corrupt(e),
+        }
+    }
+}
+
+impl Error {
+    /// Classification of errors which concern stored data. None for invalid settings, audit
+    /// log failures and chunks written in a newer format than this version understands.
+    pub fn fault(&self) -> Option<Fault> {
+        match self {
+            Error::NoStore | Error::NotFound(_) => Some(Fault::NotFound),
+            Error::PermissionDenied(_) => Some(Fault::PermissionDenied),
+            Error::Transient(_) => Some(Fault::Transient),
+            Error::VersionTag(_)
+            | Error::Missized(_)
+            | Error::Magic
+            | Error::Lzo(_)
+            | Error::Corrupt(_)
+            | Error::Verify { .. }
+            | Error::Checksum(_)
+            | Error::FileSize(_) => Some(Fault::Corrupt),
+            #[cfg(feature = "lzokay")]
+            Error::Lzokay(_) => Some(Fault::Corrupt),
+            Error::HeaderVersion(_)
+            | Error::HeaderField(_)
+            | Error::Compression(_)
+            | Error::Codec(_)
+            | Error::ReadStrategy(_)
+            | Error::Fsync(_)
+            | Error::Audit(_)
+            | Error::Unsupported(_)
+            | Error::ReadOnly(_) => None,
+        }
+    }
+
+    /// Integrity check which has failed. None for I/O errors and invalid settings.
+    pub fn check(&self) -> Option<Check> {
+        match self {
+            Error::Magic => Some(Check::Magic),
+            Error::Missized(_) | Error::FileSize(_) => Some(Check::Size),
+            Error::Lzo(_) => Some(Check::Lzo),
+            #[cfg(feature = "lzokay")]
+            Error::Lzokay(_) => Some(Check::Lzo),
+            Error::Verify { .. } | Error::Checksum(_) => Some(Check::Hash),
+            _ => None,
+        }
+    }
+}
+
+/// Integrity check which a chunk has failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Check {
+    /// The file does not start with the chunk header
+    Magic,
+    /// The file size or the decompressed size is wrong
+    Size,
+    /// The compressed data cannot be decompressed
+    Lzo,
+    /// The contents do not hash to the chunk ID
+    Hash,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Check::Magic => "magic",
+            Check::Size => "size",
+            Check::Lzo => "lzo",
+            Check::Hash => "hash",
+        })
+    }
+}
+
+// Valid chunk files hold at least the legacy header and the LZO end-of-stream marker.
+// Compressed data never exceeds LZO1X's worst case expansion of incompressible input.
+const MIN_FILESZ: u64 = 5 + 3;
+const MAX_FILESZ: u64 = (header::MAX_SIZE + CHUNKSZ + CHUNKSZ / 16 + 64 + 3) as u64;
+
+/// Computes the chunk ID for uncompressed chunk data.
+///
+/// backy names chunks after the hex-encoded 128 bit x64 murmur3 hash of their contents. Use
+/// [Backend::hash](struct.Backend.html#method.hash) where a store is at hand.
+pub fn hash(data: &[u8]) -> String {
+    HashAlgo::Murmur3.hash(data)
+}
+
+/// [hash] of a chunk consisting of NUL bytes only.
+pub const ZERO_HASH: &str = "c72b4ba82d1f51b71c8a18195ad33fc8";
+
+/// Decodes the contents of a chunk file: checks the header and decompresses the payload.
+/// Checksums recorded in extended headers are verified.
+pub fn decode(buf: &[u8], codec: Codec) -> Result<Vec<u8>> {
+    let hdr = Header::parse(buf)?;
+    if hdr.len != CHUNKSZ {
+        return Err(Error::Missized(hdr.len));
+    }
+    let data = codec.decompress(&buf[hdr.size..], CHUNKSZ)?;
+    hdr.verify(&data)?;
+    Ok(data)
+}
+
+fn check_size(data: Vec<u8>) -> Result<Vec<u8>> {
+    if data.len() != CHUNKSZ {
+        Err(Error::Missized(data.len()))
     } else {
-        Ok(minilzo::decompress(&buf[5..], CHUNKSZ)?)
+        Ok(data)
+    }
+}
+
+fn decompress(f: &mut File, reader: ReadStrategy, codec: Codec) -> Result<Vec<u8>> {
+    debug!("read lzo from {:?}", f);
+    let len = f.metadata()?.len();
+    decode(&reader.read(f, len)?, codec)
+}
+
+/// Controls when chunk files written to the store are flushed to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fsync {
+    /// Leave flushing to the OS
+    Never,
+    /// Flush each chunk file before it is renamed into place
+    Chunks,
+    /// Flush chunk files and the directories which contain them
+    #[default]
+    All,
+}
+
+impl fmt::Display for Fsync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fsync::Never => write!(f, "never"),
+            Fsync::Chunks => write!(f, "chunks"),
+            Fsync::All => write!(f, "all"),
+        }
+    }
+}
+
+impl FromStr for Fsync {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "never" => Ok(Fsync::Never),
+            "chunks" => Ok(Fsync::Chunks),
+            "all" => Ok(Fsync::All),
+            _ => Err(Error::Fsync(s.to_owned())),
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Backend {
     pub dir: PathBuf,
+    caps: Capabilities,
+    codec: Codec,
+    reader: ReadStrategy,
+    audit: Option<Trail>,
+    #[cfg_attr(feature = "read-only", allow(dead_code))]
+    fsync: Fsync,
+    fds: Arc<FdBudget>,
+    drop_cache: bool,
+    /// Stores which are asked for chunks missing in this one
+    fallback: Vec<Backend>,
 }
 
 impl Backend {
@@ -72,18 +321,106 @@ impl Backend {
     ///
     /// # Errors
     ///
-    /// Fails with Error::NotFound or Error::VersionTag if no
-    /// valid version tag is present in the store directory.
+    /// Fails with Error::NoStore or Error::VersionTag if no valid version tag is present in the
+    /// store directory and with Error::Unsupported if the layout is known but cannot be read.
     pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
         let dir = dir.as_ref();
-        let s = fs::read_to_string(dir.join("chunks/store")).map_err(|_| Error::NotFound)?;
-        let version_tag = s.trim();
-        if version_tag != "v2" {
-            Err(Error::VersionTag(version_tag.to_owned()))
-        } else {
-            Ok(Self {
-                dir: dir.to_owned(),
-            })
+        let caps = probe(dir)?;
+        if !caps.readable {
+            return Err(Error::Unsupported(caps.layout));
+        }
+        Ok(Self {
+            dir: dir.to_owned(),
+            caps,
+            codec: Codec::default(),
+            reader: ReadStrategy::default(),
+            audit: None,
+            fsync: Fsync::default(),
+            fds: Arc::clone(&fds::DEFAULT),
+            drop_cache: false,
+            fallback: Vec::new(),
+        })
+    }
+
+    /// Initializes an empty chunk store in `dir`, which is created if necessary. An existing
+    /// store is opened as is.
+    #[cfg(any(test, feature = "testing", not(feature = "read-only")))]
+    pub fn create<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref();
+        if !dir.join("chunks/store").exists() {
+            fs::create_dir_all(dir.join("chunks"))?;
+            fs::write(dir.join("chunks/store"), "v2")?;
+        }
+        if !dir.join(".purge").exists() {
+            File::create(dir.join(".purge"))?;
+        }
+        Self::open(dir)
+    }
+
+    /// What this store supports.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.caps
+    }
+
+    /// Computes the chunk ID of uncompressed chunk data with the store's hash algorithm.
+    pub fn hash(&self, data: &[u8]) -> String {
+        self.caps.hash.hash(data)
+    }
+
+    /// Selects the LZO implementation used to load chunks.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Selects how chunk files are read. Defaults to `ReadStrategy::Read`.
+    pub fn with_reader(mut self, reader: ReadStrategy) -> Self {
+        self.reader = reader;
+        self
+    }
+
+    /// Selects when chunk files are flushed to disk. Defaults to `Fsync::All`.
+    #[cfg(any(test, feature = "testing", not(feature = "read-only")))]
+    pub fn with_fsync(mut self, fsync: Fsync) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Records all chunk loads in an audit log.
+    pub fn with_audit(mut self, trail: Trail) -> Self {
+        self.audit = Some(trail);
+        self
+    }
+
+    /// Limits the number of chunk files open at the same time. By default, all backends share a
+    /// budget derived from `RLIMIT_NOFILE`.
+    pub fn with_fd_budget(mut self, budget: Arc<FdBudget>) -> Self {
+        self.fds = budget;
+        self
+    }
+
+    /// Drops chunk files from the page cache after they have been read, so that restores don't
+    /// push out data of other processes. Not worthwhile if the same chunks are read repeatedly.
+    pub fn with_drop_cache(mut self, drop: bool) -> Self {
+        self.drop_cache = drop;
+        self
+    }
+
+    /// Loads chunks which are missing in this store from `stores` instead, in the order given,
+    /// e.g. from a replica of a partially purged store. Chunks are still saved to this store.
+    #[cfg(feature = "fuse_driver")]
+    pub fn with_fallback(mut self, stores: Vec<Backend>) -> Self {
+        self.fallback = stores;
+        self
+    }
+
+    // Applies the page cache policy to a chunk file which has been read.
+    fn done_reading(&self, _f: &File) {
+        #[cfg(target_os = "linux")]
+        {
+            if self.drop_cache {
+                fadvise::dontneed(_f);
+            }
         }
     }
 
@@ -101,33 +438,172 @@ impl Backend {
     ///
     /// Fails with Error::Missized if decompressed data does not fix exactly into a chunk.
     pub fn load(&self, id: &str) -> Result<Vec<u8>> {
-        let mut f = File::open(self.filename(id))?;
-        let data = decompress(&mut f)?;
-        #[cfg(os = "linux")]
-        fadvise::dontneed(f);
+        let err = match self.load_here(id) {
+            Err(Error::NotFound(e)) => e,
+            res => return res,
+        };
+        for store in &self.fallback {
+            match store.load(id) {
+                Err(Error::NotFound(_)) => continue,
+                res => return res,
+            }
+        }
+        Err(Error::NotFound(err))
+    }
+
+    // Loads chunk `id` from this store only.
+    fn load_here(&self, id: &str) -> Result<Vec<u8>> {
+        if let Some(t) = &self.audit {
+            t.record(id).map_err(Error::Audit)?;
+        }
+        let mut f = self.fds.open(&self.filename(id))?;
+        let data = decompress(&mut f.file, self.reader, self.codec)?;
+        self.done_reading(&f.file);
+        check_size(data)
+    }
+
+    /// Reads the file of chunk `id` without decompressing it. Together with
+    /// [decode_chunk](#method.decode_chunk), this does the same as [load](#method.load) in two
+    /// steps which may run on different threads.
+    pub fn read(&self, id: &str) -> Result<RawChunk> {
+        if let Some(t) = &self.audit {
+            t.record(id).map_err(Error::Audit)?;
+        }
+        let mut f = self.fds.open(&self.filename(id))?;
+        let len = f.file.metadata()?.len();
+        let raw = self.reader.read(&mut f.file, len)?;
+        self.done_reading(&f.file);
+        Ok(raw)
+    }
+
+    /// Decompresses the contents of a chunk file as returned by [read](#method.read).
+    pub fn decode_chunk(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        check_size(decode(buf, self.codec)?)
+    }
 
-        if data.len() != CHUNKSZ {
-            Err(Error::Missized(data.len()))
-        } else {
-            Ok(data)
+    /// Checks that the file of chunk `id` exists, has a plausible size and starts with the chunk
+    /// header. Nothing is decompressed, so this is much cheaper than [load](#method.load).
+    pub fn check_file(&self, id: &str) -> Result<()> {
+        let mut f = self.fds.open(&self.filename(id))?;
+        let len = f.file.metadata()?.len();
+        if !(MIN_FILESZ..=MAX_FILESZ).contains(&len) {
+            return Err(Error::FileSize(len));
         }
+        let hdr = Header::read_from(&mut f.file)?;
+        if hdr.len != CHUNKSZ {
+            return Err(Error::Missized(hdr.len));
+        }
+        Ok(())
     }
 
-    #[allow(unused)]
+    /// Compresses `buf` and stores it as chunk `id` (see [commit](#method.commit)).
+    #[cfg(any(test, feature = "testing", not(feature = "read-only")))]
     pub fn save(&self, id: &str, buf: &[u8]) -> Result<()> {
         if buf.len() != CHUNKSZ {
             return Err(Error::Missized(buf.len()));
         }
-        let dir = self.dir.join(format!("chunks/{}", &id[0..2]));
-        if fs::metadata(&dir).is_err() {
-            fs::create_dir(dir)?;
+        // stick to the legacy header which backy understands
+        let mut data = Header::legacy(CHUNKSZ).to_vec()?;
+        data.extend_from_slice(&self.codec.compress(buf)?);
+        self.commit(id, &data)
+    }
+
+    /// Stores a complete chunk file (header and compressed data) as chunk `id`.
+    ///
+    /// The data goes to a temporary file of its own first, which is read back, decompressed and
+    /// hashed. It is renamed into place only if the result matches `id`, so that torn writes
+    /// never show up as chunk files, even if several writers store the same chunk at once. An
+    /// existing chunk file is replaced.
+    #[cfg(any(test, feature = "testing", not(feature = "read-only")))]
+    pub fn commit(&self, id: &str, data: &[u8]) -> Result<()> {
+        if !self.caps.save {
+            return Err(Error::ReadOnly(self.caps.layout));
+        }
+        let path = self.filename(id);
+        let dir = path.parent().expect("chunk path has a parent");
+        match fs::create_dir(dir) {
+            Ok(()) => self.sync_dir(&self.dir.join("chunks"))?,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e.into()),
+        }
+        // concurrent writers of the same chunk must not share a temporary file
+        let tmp = crate::tmp_path(&path);
+        if let Err(e) = self
+            .write_verified(id, &tmp, data)
+            .and_then(|_| Ok(fs::rename(&tmp, &path)?))
+        {
+            fs::remove_file(&tmp).ok();
+            return Err(e);
+        }
+        self.sync_dir(dir)
+    }
+
+    /// Moves the file of chunk `id` out of the way into `chunks/quarantine/`, so that a repair
+    /// or the next backup can store an intact copy. Quarantined files are named
+    /// `<id>.<time>.corrupt`, which no chunk file pattern matches. Each move is recorded in
+    /// `chunks/quarantine/manifest.jsonl` together with `reason`. Returns the new file name.
+    #[cfg(any(test, feature = "testing", not(feature = "read-only")))]
+    pub fn quarantine(&self, id: &str, reason: &str) -> Result<PathBuf> {
+        #[derive(Serialize)]
+        struct Entry<'a> {
+            time: String,
+            chunk: &'a str,
+            file: String,
+            reason: &'a str,
+        }
+        let dir = self.dir.join("chunks/quarantine");
+        match fs::create_dir(&dir) {
+            Ok(()) => self.sync_dir(&self.dir.join("chunks"))?,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+            Err(e) => return Err(e.into()),
+        }
+        let now = chrono::Utc::now();
+        let name = format!("{}.{}.corrupt", id, now.format("%Y%m%dT%H%M%S%.3f"));
+        let dest = dir.join(&name);
+        fs::rename(self.filename(id), &dest)?;
+        let mut line = serde_json::to_vec(&Entry {
+            time: now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            chunk: id,
+            file: name,
+            reason,
+        })
+        .map_err(io::Error::from)?;
+        line.push(b'\n');
+        fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(dir.join("manifest.jsonl"))?
+            .write_all(&line)?;
+        self.sync_dir(&dir)?;
+        Ok(dest)
+    }
+
+    #[cfg(any(test, feature = "testing", not(feature = "read-only")))]
+    fn write_verified(&self, id: &str, path: &Path, data: &[u8]) -> Result<()> {
+        let mut f = self.fds.open_with(path, |p| {
+            fs::OpenOptions::new().write(true).create_new(true).open(p)
+        })?;
+        debug!("write lzo to {:?}", f.file);
+        f.file.write_all(data)?;
+        if self.fsync != Fsync::Never {
+            f.file.sync_data()?;
+        }
+        drop(f);
+        let actual = self.hash(&decode(&fs::read(path)?, self.codec)?);
+        if actual != id {
+            return Err(Error::Verify {
+                id: id.to_owned(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "testing", not(feature = "read-only")))]
+    fn sync_dir(&self, dir: &Path) -> Result<()> {
+        if self.fsync == Fsync::All {
+            File::open(dir)?.sync_all()?;
         }
-        let mut f = File::create(self.filename(id))?;
-        debug!("write lzo to {:?}", f);
-        f.write_all(&MAGIC)?;
-        f.write_all(&minilzo::compress(buf)?)?;
-        #[cfg(os = "linux")]
-        fadvise::dontneed(f);
         Ok(())
     }
 }
@@ -142,6 +618,11 @@ mod tests {
     use std::hash::Hasher;
     use tempdir::TempDir;
 
+    #[test]
+    fn zero_hash() {
+        assert_eq!(hash(&vec![0; CHUNKSZ]), ZERO_HASH);
+    }
+
     #[test]
     fn check_backend_store() -> Result<()> {
         let tmp = TempDir::new("check_backend_store")?;
@@ -154,12 +635,35 @@ mod tests {
         write(tmp.path().join("chunks/store"), b"v1")?;
         assert!(Backend::open(tmp.path()).is_err());
 
-        // (3) acceptable contents
+        // (3) known, but unreadable layout
+        write(tmp.path().join("chunks/store"), b"v3")?;
+        assert!(matches!(
+            Backend::open(tmp.path()),
+            Err(Error::Unsupported(Layout::V3))
+        ));
+
+        // (4) acceptable contents
         write(tmp.path().join("chunks/store"), b"v2")?;
-        assert!(Backend::open(tmp.path()).is_ok());
+        let be = Backend::open(tmp.path())?;
+        assert_eq!(be.capabilities().layout, Layout::V2);
         Ok(())
     }
 
+    #[test]
+    fn classify_io_errors() {
+        let err = |errno| Error::from(io::Error::from_raw_os_error(errno));
+        assert!(matches!(err(libc::ENOENT), Error::NotFound(_)));
+        assert!(matches!(err(libc::EACCES), Error::PermissionDenied(_)));
+        assert!(matches!(err(libc::EROFS), Error::PermissionDenied(_)));
+        assert!(matches!(err(libc::EMFILE), Error::Transient(_)));
+        assert!(matches!(err(libc::EAGAIN), Error::Transient(_)));
+        assert!(matches!(err(libc::EIO), Error::Corrupt(_)));
+        assert_eq!(err(libc::ENFILE).fault(), Some(Fault::Transient));
+        assert_eq!(Error::Magic.fault(), Some(Fault::Corrupt));
+        assert_eq!(Error::NoStore.fault(), Some(Fault::NotFound));
+        assert_eq!(Error::Codec("x".into()).fault(), None);
+    }
+
     #[test]
     fn decode_chunk() -> Result<()> {
         let s = store_tar();
@@ -169,12 +673,34 @@ mod tests {
         Ok(assert_eq!(h.finish(), 4783617329521481478))
     }
 
+    #[test]
+    fn read_and_decode_separately() -> Result<()> {
+        let s = store_tar();
+        let id = "4db6e194fd398e8edb76e11054d73eb0";
+        for &r in &READ_STRATEGIES {
+            let be = Backend::open(s.path())?.with_reader(r);
+            let raw = be.read(id)?;
+            assert!(matches!(
+                (r, &raw),
+                (ReadStrategy::Read, RawChunk::Buf(_)) | (ReadStrategy::Mmap, RawChunk::Map(_))
+            ));
+            assert_eq!(raw.len() as u64, metadata(be.filename(id))?.len());
+            assert_eq!(be.decode_chunk(&raw)?, be.load(id)?);
+            assert!(matches!(be.decode_chunk(&raw[1..]), Err(Error::Magic)));
+        }
+        Ok(())
+    }
+
     #[test]
     fn encode_chunk() {
         let s = store_tar();
         let be = Backend::open(s.path()).unwrap();
         let buf = be.load("4db6e194fd398e8edb76e11054d73eb0").unwrap();
-        be.save("00000000000000000000000000000000", &buf).unwrap();
+        let dest = TempDir::new("encode_chunk").unwrap();
+        Backend::create(dest.path())
+            .unwrap()
+            .save("4db6e194fd398e8edb76e11054d73eb0", &buf)
+            .unwrap();
         assert_eq!(
             read(&format!(
                 "{}/chunks/{}/{}.chunk.lzo",
@@ -185,14 +711,85 @@ mod tests {
             .unwrap(),
             read(&format!(
                 "{}/chunks/{}/{}.chunk.lzo",
-                s.path().display(),
-                "00",
-                "00000000000000000000000000000000"
+                dest.path().display(),
+                "4d",
+                "4db6e194fd398e8edb76e11054d73eb0"
             ))
             .unwrap()
         )
     }
 
+    #[test]
+    fn commit_verifies_written_data() -> Result<()> {
+        let s = store_tar();
+        let src = Backend::open(s.path())?;
+        let data = read(src.filename("4db6e194fd398e8edb76e11054d73eb0"))?;
+        let dest = TempDir::new("commit")?;
+        for &fsync in &[Fsync::Never, Fsync::Chunks, Fsync::All] {
+            let be = Backend::create(dest.path())?.with_fsync(fsync);
+            be.commit("4db6e194fd398e8edb76e11054d73eb0", &data)?;
+            assert_eq!(read(be.filename("4db6e194fd398e8edb76e11054d73eb0"))?, data);
+            // contents do not match the ID
+            let id = "c72b4ba82d1f51b71c8a18195ad33fc8";
+            assert!(matches!(be.commit(id, &data), Err(Error::Verify { .. })));
+            assert!(!be.filename(id).exists());
+            // no temporary file left behind
+            let dir = be.filename(id).parent().unwrap().to_owned();
+            assert_eq!(fs::read_dir(dir)?.count(), 0);
+        }
+        // concurrent writers of the same chunk
+        let be = Backend::create(dest.path())?.with_fsync(Fsync::Never);
+        crossbeam::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|_| {
+                    for _ in 0..10 {
+                        be.commit("4db6e194fd398e8edb76e11054d73eb0", &data)
+                            .unwrap();
+                    }
+                });
+            }
+        })
+        .unwrap();
+        assert_eq!(read(be.filename("4db6e194fd398e8edb76e11054d73eb0"))?, data);
+        assert_eq!("chunks".parse::<Fsync>()?, Fsync::Chunks);
+        assert!("sometimes".parse::<Fsync>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn short_chunk() {
+        assert!(matches!(decode(&[], Codec::default()), Err(Error::Magic)));
+        assert!(matches!(
+            decode(&[0xF0, 0], Codec::default()),
+            Err(Error::Magic)
+        ));
+    }
+
+    #[test]
+    fn decode_extended_header() {
+        let digest = |hash| u128::from_str_radix(hash, 16).unwrap().to_be_bytes();
+        let payload = Codec::default().compress(&[0; CHUNKSZ]).unwrap();
+        let chunk = |hdr: Header| [hdr.to_vec().unwrap(), payload.clone()].concat();
+        let data = decode(
+            &chunk(Header::extended(CHUNKSZ, Some(digest(ZERO_HASH))).unwrap()),
+            Codec::default(),
+        )
+        .unwrap();
+        assert_eq!(hash(&data), ZERO_HASH);
+        let bad =
+            Header::extended(CHUNKSZ, Some(digest("4db6e194fd398e8edb76e11054d73eb0"))).unwrap();
+        assert!(matches!(
+            decode(&chunk(bad), Codec::default()),
+            Err(Error::Checksum(_))
+        ));
+        assert!(matches!(
+            decode(&chunk(Header::legacy(CHUNKSZ / 2)), Codec::default()),
+            Err(Error::Missized(_))
+        ));
+        let err = decode(&[0xF1, 9, 0, 0], Codec::default()).unwrap_err();
+        assert!(err.fault().is_none());
+    }
+
     #[test]
     fn corrupted_chunk() -> Result<()> {
         let s = store_tar();
@@ -209,4 +806,50 @@ mod tests {
             other => panic!("unexpected: {:?}", other),
         }
     }
+
+    #[test]
+    fn check_chunk_file() -> Result<()> {
+        let s = store_tar();
+        let be = Backend::open(s.path())?;
+        let id = "4db6e194fd398e8edb76e11054d73eb0";
+        be.check_file(id)?;
+        assert!(matches!(
+            be.check_file("ffff0000000000000000000000000000"),
+            Err(Error::NotFound(_))
+        ));
+        let file = be.filename(id);
+        let mut p = metadata(&file)?.permissions();
+        p.set_readonly(false);
+        set_permissions(&file, p)?;
+        let mut data = read(&file)?;
+        data[0] = 0;
+        write(&file, &data)?;
+        assert!(matches!(be.check_file(id), Err(Error::Magic)));
+        OpenOptions::new().write(true).open(&file)?.set_len(5)?;
+        assert!(matches!(be.check_file(id), Err(Error::FileSize(5))));
+        Ok(())
+    }
+
+    #[test]
+    fn quarantine_chunk() -> Result<()> {
+        let s = store_tar();
+        let be = Backend::open(s.path())?;
+        let id = "4db6e194fd398e8edb76e11054d73eb0";
+        let dest = be.quarantine(id, "bad magic")?;
+        assert!(dest.starts_with(s.path().join("chunks/quarantine")));
+        assert!(dest.to_string_lossy().ends_with(".corrupt"));
+        assert!(matches!(be.load(id), Err(Error::NotFound(_))));
+        let manifest =
+            std::fs::read_to_string(s.path().join("chunks/quarantine/manifest.jsonl")).unwrap();
+        let entry: serde_json::Value = serde_json::from_str(manifest.trim_end()).unwrap();
+        assert_eq!(entry["chunk"], id);
+        assert_eq!(entry["reason"], "bad magic");
+        assert_eq!(
+            entry["file"].as_str(),
+            dest.file_name().and_then(|n| n.to_str())
+        );
+        // nothing to move
+        assert!(be.quarantine(id, "again").is_err());
+        Ok(())
+    }
 }
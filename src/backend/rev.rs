@@ -1,8 +1,9 @@
 //! Rev files (*.rev) contain additional information to the chunk map
 
 use chrono::{DateTime, TimeZone, Utc};
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
 use smallstr::SmallString;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
@@ -19,6 +20,10 @@ pub enum Error {
     },
     #[error("Unknown backend_type '{}' found in {}", betype, path.display())]
     WrongType { betype: String, path: PathBuf },
+    #[error("Unknown trust level '{0}' (expected distrusted, unknown, trusted or verified)")]
+    Trust(String),
+    #[error("Revision map '{}' is missing", .0.display())]
+    NoMap(PathBuf),
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -32,6 +37,16 @@ where
     Ok(RevId::from(String::deserialize(deserializer)?))
 }
 
+// backy writes `parent: ''` or `parent: null` for full backups
+fn parent_de<'de, D>(deserializer: D) -> Result<Option<RevId>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?
+        .filter(|p| !p.is_empty())
+        .map(RevId::from))
+}
+
 static TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f%z";
 fn timestamp_de<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
 where
@@ -41,6 +56,55 @@ where
         .map_err(serde::de::Error::custom)
 }
 
+/// How much backy trusts the data of a revision, in ascending order.
+///
+/// backy distrusts revisions after detecting inconsistencies and marks them verified once they
+/// have been compared against the source. Revisions without (or with an unknown) trust field
+/// are `Unknown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Trust {
+    Distrusted,
+    #[default]
+    Unknown,
+    Trusted,
+    Verified,
+}
+
+impl std::str::FromStr for Trust {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "distrusted" => Ok(Trust::Distrusted),
+            "unknown" => Ok(Trust::Unknown),
+            "trusted" => Ok(Trust::Trusted),
+            "verified" => Ok(Trust::Verified),
+            _ => Err(Error::Trust(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Trust {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Trust::Distrusted => "distrusted",
+            Trust::Unknown => "unknown",
+            Trust::Trusted => "trusted",
+            Trust::Verified => "verified",
+        })
+    }
+}
+
+fn trust_de<'de, D>(deserializer: D) -> Result<Trust, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?
+        .and_then(|t| t.parse().ok())
+        .unwrap_or_default())
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Rev {
     pub backend_type: String,
@@ -48,25 +112,71 @@ pub struct Rev {
     pub timestamp: DateTime<Utc>,
     #[serde(deserialize_with = "revid_de")]
     pub uuid: RevId,
+    #[serde(default, deserialize_with = "trust_de")]
+    pub trust: Trust,
+    #[serde(default, deserialize_with = "parent_de")]
+    pub parent: Option<RevId>,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
 impl Rev {
     pub fn load<P: AsRef<Path>, I: AsRef<str>>(dir: P, id: I) -> Result<Self> {
         let map = dir.as_ref().join(id.as_ref());
         let rev = map.with_extension("rev");
-        let r: Self =
-            serde_yaml::from_reader(fs::File::open(&rev)?).map_err(|source| Error::ParseRev {
-                path: rev.to_path_buf(),
-                source,
-            })?;
+        Self::parse(&fs::read_to_string(&rev)?, rev)
+    }
+
+    /// Parses .rev file contents. `path` is only used for error reporting.
+    pub fn parse(yaml: &str, path: PathBuf) -> Result<Self> {
+        let r: Self = serde_yaml::from_str(yaml).map_err(|source| Error::ParseRev {
+            path: path.clone(),
+            source,
+        })?;
         if r.backend_type != "chunked" {
             return Err(Error::WrongType {
                 betype: r.backend_type,
-                path: rev,
+                path,
             });
         }
         Ok(r)
     }
+
+    /// Finds all revisions in the backup directory `dir`, oldest first. `.rev` files which
+    /// cannot be parsed or lack their revision map are returned as errors after the valid ones.
+    pub fn discover<P: AsRef<Path>>(dir: P) -> io::Result<Vec<Result<Self>>> {
+        Self::discover_with(dir, |path| {
+            fs::read_to_string(path)
+                .map_err(Error::from)
+                .and_then(|yaml| Self::parse(&yaml, path.to_owned()))
+        })
+    }
+
+    /// Like [discover](#method.discover), but gets the contents of each `.rev` file from `load`.
+    pub fn discover_with<P, F>(dir: P, mut load: F) -> io::Result<Vec<Result<Self>>>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&Path) -> Result<Self>,
+    {
+        let mut revs = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().unwrap_or_default() != "rev" {
+                continue;
+            }
+            let map = path.with_extension("");
+            let rev = load(&path);
+            revs.push(match rev {
+                Ok(_) if !map.exists() => Err(Error::NoMap(map)),
+                rev => rev,
+            });
+        }
+        revs.sort_by_key(|r| match r {
+            Ok(rev) => (false, Some(rev.timestamp)),
+            Err(_) => (true, None),
+        });
+        Ok(revs)
+    }
 }
 
 // see src/fuse/access.rb for tests
@@ -5,18 +5,89 @@
 #[macro_use]
 extern crate clap;
 
-use anyhow::{ensure, Context, Result};
-use atty::{self, Stream::Stdout};
-use backy_extract::{Extractor, RandomAccess, Stream};
-use clap::{app_from_crate, crate_authors, crate_description, crate_name, crate_version, Arg};
-use std::ffi::OsStr;
-use std::io;
+use anyhow::{bail, ensure, Context, Result};
+use atty::{self, Stream::Stdin, Stream::Stdout};
+use backy_extract::archive::{self, ArchiveStats};
+use backy_extract::audit::AuditLog;
+#[cfg(not(feature = "read-only"))]
+use backy_extract::export::{export_store_with, mirror as mirror_store};
+use backy_extract::filter;
+use backy_extract::health;
+use backy_extract::jobs;
+use backy_extract::picker;
+use backy_extract::prep::dm::{self, DmSnapshot};
+use backy_extract::prep::zfs::ZfsSnapshot;
+use backy_extract::prep::Prepare;
+use backy_extract::refs;
+use backy_extract::remote::{self, TransferStats};
+use backy_extract::revisions;
+#[cfg(target_os = "linux")]
+use backy_extract::watch::{self, Watch};
+#[cfg(not(feature = "read-only"))]
+use backy_extract::Fsync;
+#[cfg(feature = "tus")]
+use backy_extract::Tus;
+use backy_extract::{
+    BarStyle, Concurrency, ExtractError, ExtractReport, Extractor, HumanBytes, Job, Limits,
+    MapCache, Nbd, Profile, RandomAccess, RbdDiff, RestoreOrder, Retry, Stream, StreamHeader, Vhd,
+    CHUNKSZ,
+};
+use clap::{
+    app_from_crate, crate_authors, crate_description, crate_name, crate_version, App, AppSettings,
+    Arg, ArgMatches, SubCommand,
+};
+use serde::Deserialize;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpListener;
+use std::ops::Range;
+use std::os::unix::io::{AsFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 // Detect static linkage and add lzo2 in this case
 #[cfg(target_feature = "crt-static")]
 #[link(name = "lzo2", kind = "static")]
 extern "C" {}
 
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum HealthFormat {
+        Text,
+        Json,
+        Prometheus
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum ListFormat {
+        Table,
+        Json,
+        Csv
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum TreeFormat {
+        Text,
+        Json
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum TrialAction {
+        Merge,
+        Discard
+    }
+}
+
 arg_enum! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     enum Sparse {
@@ -26,61 +97,2029 @@ arg_enum! {
     }
 }
 
-fn main() -> Result<()> {
-    let m = app_from_crate!()
+/// Read if present and no other configuration file is given
+const CONFIG: &str = "/etc/backy-extract.yaml";
+
+/// Defaults read from the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    profile: Option<Profile>,
+}
+
+fn revision_arg() -> Arg<'static, 'static> {
+    Arg::with_name("REVISION")
+        .help("Backy backup revision file (e.g., `2hQmTeMjRaFG9jonuXeCnR' or `last')")
+        .required(true)
+}
+
+// REVISION argument which may be left out or given as `-` to pick the revision interactively
+fn pickable_revision_arg() -> Arg<'static, 'static> {
+    revision_arg().required(false).help(
+        "Backy backup revision file (e.g., `2hQmTeMjRaFG9jonuXeCnR' or `last') or `-' to pick \
+         one of the revisions in the current directory if stdin is a terminal [default: -]",
+    )
+}
+
+// Returns the REVISION argument (see `pickable_revision_arg`). Without one or with `-`, the user
+// picks a revision from the current directory. `-` is needed to give further positional
+// arguments, e.g. a restore target, which would otherwise be taken as REVISION.
+fn revision(m: &ArgMatches) -> Result<OsString> {
+    match m.value_of_os("REVISION") {
+        Some(rev) if rev != "-" => return Ok(rev.to_owned()),
+        _ => (),
+    }
+    ensure!(
+        atty::is(Stdin),
+        "No REVISION given (run on a terminal to pick one interactively)"
+    );
+    let revs = revisions::summaries(".")?;
+    ensure!(
+        !revs.is_empty(),
+        "No revisions found in the current directory"
+    );
+    match picker::pick(&revs, "Revision>")? {
+        Some(r) => Ok(r.uuid.clone().into()),
+        None => bail!("No revision picked"),
+    }
+}
+
+// Options shared by all commands which write images
+fn restore_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("THREADS")
+            .value_name("N")
+            .long("threads")
+            .short("t")
+            .help("Uses N parallel threads for decompression [default: auto]"),
+        Arg::with_name("SPARSE")
+            .long("sparse")
+            .short("s")
+            .value_name("WHEN")
+            .possible_values(&Sparse::variants())
+            .case_insensitive(true)
+            .help("Skips over contiguous regions of NUL bytes"),
+        Arg::with_name("CHECK_HOLES")
+            .long("check-holes")
+            .requires("OUTPUT")
+            .help("Checks after a sparse restore that all skipped regions read as zeros"),
+        Arg::with_name("CODEC")
+            .long("codec")
+            .value_name("NAME")
+            .help("LZO implementation used for decompression [default: minilzo]"),
+        Arg::with_name("READER")
+            .long("reader")
+            .value_name("HOW")
+            .possible_values(&["read", "mmap"])
+            .help(
+                "Copies chunk files into memory (`read') or decompresses them from memory \
+                 mappings (`mmap') [default: read]",
+            ),
+        Arg::with_name("MAP_CACHE")
+            .long("map-cache")
+            .value_name("DIR")
+            .help("Keeps parsed revision maps in DIR to speed up repeated restores"),
+        Arg::with_name("QUIET")
+            .long("quiet")
+            .short("q")
+            .help("Does not display progress indication"),
+        Arg::with_name("PROGRESS_STYLE")
+            .long("progress-style")
+            .value_name("STYLE")
+            .possible_values(&["default", "detailed"])
+            .conflicts_with("QUIET")
+            .help(
+                "Shows page cache hit rate, read and write throughput and write queue fill \
+                 level next to the progress bar with `detailed' [default: default]",
+            ),
+        Arg::with_name("ATOMIC")
+            .long("atomic")
+            .requires("OUTPUT")
+            .conflicts_with("KEEP_GOING")
+            .help("Restores to OUTPUT.partial and renames it to OUTPUT when done"),
+        Arg::with_name("PREALLOC")
+            .long("prealloc")
+            .value_name("MODE")
+            .possible_values(&["none", "truncate", "fallocate", "full"])
+            .help("Allocates file targets before restoring [default: truncate]"),
+        Arg::with_name("RETRIES")
+            .long("retries")
+            .value_name("N")
+            .help("Retries chunk writes which fail with EIO, ENOSPC or similar up to N times"),
+        Arg::with_name("UPLOAD_HEADER")
+            .long("upload-header")
+            .value_name("NAME: VALUE")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Sends an additional header with tus upload requests, e.g. for authentication"),
+        Arg::with_name("KEEP_GOING").long("keep-going").help(
+            "Goes on with the remaining chunks if a chunk cannot be written and lists the \
+                 regions which are missing in the end",
+        ),
+    ]
+}
+
+fn output_arg() -> Arg<'static, 'static> {
+    Arg::with_name("OUTPUT").help(
+        "Output file, block device, NBD export (nbd://HOST[:PORT]/EXPORT or \
+         nbd+unix:///EXPORT?socket=PATH) or tus upload endpoint (tus+https://HOST/PATH) (or \
+         stdout if absent)",
+    )
+}
+
+fn threads(m: &ArgMatches) -> Result<u8> {
+    Ok(m.value_of("THREADS")
+        .map(|t| t.parse::<u8>().context("Invalid number of threads"))
+        .transpose()?
+        .unwrap_or(0))
+}
+
+// Profile given on the command line or in the configuration file
+fn profile(m: &ArgMatches) -> Result<Option<Profile>> {
+    if let Some(p) = m.value_of("PROFILE") {
+        return Ok(Some(p.parse()?));
+    }
+    let path = match m.value_of_os("CONFIG") {
+        Some(path) => Path::new(path),
+        None if Path::new(CONFIG).exists() => Path::new(CONFIG),
+        None => return Ok(None),
+    };
+    let read = || -> Result<Config> {
+        let text = fs::read_to_string(path)?;
+        if text.trim().is_empty() {
+            return Ok(Config::default());
+        }
+        Ok(serde_yaml::from_str(&text)?)
+    };
+    let config = read().with_context(|| format!("Cannot read '{}'", path.display()))?;
+    Ok(config.profile)
+}
+
+fn concurrency(m: &ArgMatches) -> Result<Concurrency> {
+    let count = |name, what| -> Result<Option<u8>> {
+        m.value_of(name)
+            .map(|n| {
+                n.parse::<u8>()
+                    .with_context(|| format!("Invalid number of {}", what))
+            })
+            .transpose()
+    };
+    let default = profile(m)?.map_or_else(Concurrency::default, Profile::concurrency);
+    Ok(Concurrency {
+        readers: count("READERS", "reader threads")?.unwrap_or(default.readers),
+        decompressors: threads(m)?,
+        writers: count("WRITERS", "writer threads")?.unwrap_or(default.writers),
+        ..default
+    })
+}
+
+fn sparse(m: &ArgMatches) -> Result<Option<bool>> {
+    Ok(match value_t!(m, "SPARSE", Sparse) {
+        Ok(Sparse::Always) => Some(true),
+        Ok(Sparse::Never) => Some(false),
+        Ok(Sparse::Auto) => None,
+        Err(_) => profile(m)?.and_then(Profile::sparse),
+    })
+}
+
+// Returns stdout as file if it has been redirected to an empty regular file, so that zero chunks
+// can be skipped over. Not for files opened with `>>`, where all writes go to the end, nor for
+// files with contents, which would show through the skipped parts.
+fn sparse_stdout(m: &ArgMatches) -> Result<Option<File>> {
+    if sparse(m)? == Some(false) {
+        return Ok(None);
+    }
+    let f = File::from(io::stdout().as_fd().try_clone_to_owned()?);
+    let append = unsafe { libc::fcntl(f.as_raw_fd(), libc::F_GETFL) } & libc::O_APPEND != 0;
+    Ok(Some(f).filter(|f| !append && f.metadata().is_ok_and(|m| m.is_file() && m.len() == 0)))
+}
+
+fn retry(m: &ArgMatches) -> Result<Retry> {
+    let attempts = m
+        .value_of("RETRIES")
+        .map(|n| n.parse().context("Invalid number of retries"))
+        .transpose()?
+        .unwrap_or(0);
+    Ok(Retry::new(attempts).keep_going(m.is_present("KEEP_GOING")))
+}
+
+#[cfg(feature = "tus")]
+fn tus(m: &ArgMatches, url: &OsStr) -> Result<Tus> {
+    ensure!(
+        ![
+            "ATOMIC",
+            "ZFS_SNAPSHOT",
+            "TRIAL",
+            "PREALLOC",
+            "VHD",
+            "KEEP_GOING"
+        ]
+        .iter()
+        .any(|a| m.is_present(a)),
+        "--atomic, --prealloc, --vhd, --zfs-snapshot, --trial and --keep-going don't work with \
+         tus uploads"
+    );
+    let mut tus = Tus::new(url.to_str().unwrap())?;
+    if m.is_present("RETRIES") {
+        tus = tus.retry(retry(m)?);
+    }
+    for h in m.values_of("UPLOAD_HEADER").into_iter().flatten() {
+        let (name, value) = h
+            .split_once(':')
+            .with_context(|| format!("Invalid header '{}' (expected NAME: VALUE)", h))?;
+        tus = tus.header(name.trim(), value.trim());
+    }
+    Ok(tus)
+}
+
+fn rbd_diff_out<W: Write + Send + Sync>(m: &ArgMatches, out: W) -> RbdDiff<W> {
+    let diff = RbdDiff::new(out);
+    match m.value_of("RBD_SNAP") {
+        Some(snap) => diff.to_snap(snap),
+        None => diff,
+    }
+}
+
+fn random_access(m: &ArgMatches, path: &OsStr) -> Result<RandomAccess> {
+    let mut ra = RandomAccess::new(path, sparse(m)?).retry(retry(m)?);
+    if let Some(p) = m.value_of("PREALLOC") {
+        ra = ra.prealloc(p.parse()?);
+    }
+    if m.is_present("CHECK_HOLES") {
+        ra = ra.check_holes();
+    }
+    if m.is_present("AUTO_NAME") {
+        // the name may have been taken since `auto_output` checked it
+        ra = ra.exclusive();
+    }
+    if !m.is_present("ATOMIC") {
+        return Ok(ra);
+    }
+    if let Ok(meta) = fs::metadata(path) {
+        ensure!(
+            meta.is_file(),
+            "--atomic works only with regular files, but '{}' is not",
+            Path::new(path).display()
+        );
+    }
+    Ok(ra.atomic())
+}
+
+// Path of a new file in the directory OUTPUT named after the revision, see `--auto-name`.
+fn auto_output(e: &Extractor, m: &ArgMatches) -> Result<PathBuf> {
+    let dir = Path::new(m.value_of_os("OUTPUT").unwrap());
+    ensure!(
+        dir != Path::new("-") && !dir.to_string_lossy().contains("://"),
+        "--auto-name needs a directory as OUTPUT"
+    );
+    let ext = if m.is_present("VHD") { "vhd" } else { "raw" };
+    let path = dir.join(e.auto_name(ext)?);
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    ensure!(
+        fs::symlink_metadata(&path).is_err(),
+        "'{}' exists already",
+        path.display()
+    );
+    if !m.is_present("QUIET") {
+        eprintln!("Restoring to {}", path.display());
+    }
+    Ok(path)
+}
+
+// Returns None if the image should go to stdout.
+fn output<'a>(m: &'a ArgMatches) -> Result<Option<&'a OsStr>> {
+    match m.value_of_os("OUTPUT") {
+        Some(o) if o != "-" => Ok(Some(o)),
+        _ => {
+            ensure!(
+                atty::isnt(Stdout),
+                "cowardly refusing to restore to the terminal"
+            );
+            Ok(None)
+        }
+    }
+}
+
+// Parses a byte size with optional K/M/G/T suffix (powers of 1024).
+fn parse_size(s: &str) -> Result<u64> {
+    let (num, shift) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 10),
+        Some((i, 'M')) => (&s[..i], 20),
+        Some((i, 'G')) => (&s[..i], 30),
+        Some((i, 'T')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let n: u64 = num
+        .parse()
+        .with_context(|| format!("Invalid size `{}'", s))?;
+    n.checked_mul(1 << shift)
+        .with_context(|| format!("Size `{}' too large", s))
+}
+
+// Parses an age like `30d` with s/m/h/d/w suffix (seconds without suffix).
+fn parse_age(s: &str) -> Result<chrono::Duration> {
+    let (num, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c),
+        _ => (s, 's'),
+    };
+    let n: i64 = num
+        .parse()
+        .with_context(|| format!("Invalid age `{}'", s))?;
+    Ok(match unit {
+        's' => chrono::Duration::seconds(n),
+        'm' => chrono::Duration::minutes(n),
+        'h' => chrono::Duration::hours(n),
+        'd' => chrono::Duration::days(n),
+        'w' => chrono::Duration::weeks(n),
+        _ => bail!("Invalid age `{}' (expected s, m, h, d or w suffix)", s),
+    })
+}
+
+// Translates `--priority` specs into byte ranges.
+fn priority(e: &Extractor, specs: Vec<&str>) -> Result<Vec<Range<u64>>> {
+    let mut table = None;
+    let mut ranges = Vec::with_capacity(specs.len());
+    for spec in specs {
+        if spec == "table" {
+            ranges.push(0..1 << 20);
+        } else if let Some(n) = spec.strip_prefix('p') {
+            let n: u32 = n
+                .parse()
+                .with_context(|| format!("Invalid partition `{}'", spec))?;
+            if table.is_none() {
+                table = Some(e.partition_table()?);
+            }
+            match table
+                .as_ref()
+                .unwrap()
+                .partitions
+                .iter()
+                .find(|p| p.number == n)
+            {
+                Some(p) => ranges.push(p.start..p.end),
+                None => bail!("Partition {} not found", n),
+            }
+        } else {
+            let mut it = spec.splitn(2, '-');
+            let start = parse_size(it.next().unwrap())?;
+            let end = match it.next() {
+                Some(end) => parse_size(end)?,
+                None => bail!("Invalid range `{}' (expected START-END)", spec),
+            };
+            ensure!(start < end, "Empty range `{}'", spec);
+            ranges.push(start..end);
+        }
+    }
+    Ok(ranges)
+}
+
+fn restore_order(spec: &str) -> Result<RestoreOrder> {
+    Ok(match spec {
+        "forward" => RestoreOrder::Forward,
+        "reverse" => RestoreOrder::Reverse,
+        _ => match spec.strip_prefix("outward:") {
+            Some(offset) => RestoreOrder::Outward(parse_size(offset)?),
+            None => bail!(
+                "Invalid order `{}' (expected forward, reverse or outward:OFFSET)",
+                spec
+            ),
+        },
+    })
+}
+
+fn damage_report(m: &ArgMatches) -> Result<()> {
+    let mut e = Extractor::init(revision(m)?)?;
+    // the cache file lives in the chunk store
+    e.hash_cache(cfg!(not(feature = "read-only")) && !m.is_present("NO_HASH_CACHE"));
+    let report = e.damage_report()?;
+    if m.is_present("JSON") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
+    #[cfg(not(feature = "read-only"))]
+    {
+        if m.is_present("QUARANTINE") {
+            let moved = e.quarantine(&report)?;
+            eprintln!(
+                "Moved {} corrupt chunk file(s) to chunks/quarantine/",
+                moved.len()
+            );
+        }
+    }
+    if !report.is_clean() {
+        bail!("{} damaged chunk(s) found", report.chunks.len());
+    }
+    Ok(())
+}
+
+// Restores to `path` after setting it up with `prep`.
+fn restore_prepared<P: Prepare>(
+    e: &Extractor,
+    m: &ArgMatches,
+    path: &OsStr,
+    prep: &mut P,
+) -> Result<ExtractReport> {
+    let path = prep.prepare(Path::new(path))?;
+    let res = random_access(m, path.as_os_str()).and_then(|ra| Ok(e.extract(ra)?));
+    prep.finish(res.is_ok())?;
+    res
+}
+
+fn restore_zfs(e: &Extractor, m: &ArgMatches, path: &OsStr) -> Result<ExtractReport> {
+    let mut zfs = ZfsSnapshot::new(e.name()).after(m.is_present("ZFS_SNAPSHOT_AFTER"));
+    let res = restore_prepared(e, m, path, &mut zfs);
+    if !m.is_present("QUIET") {
+        for snap in zfs.snapshots() {
+            eprintln!("Created ZFS snapshot {}", snap);
+        }
+    }
+    if let (Err(_), Some(snap)) = (&res, zfs.snapshots().first()) {
+        eprintln!("Restore failed, roll back with `zfs rollback {}'", snap);
+    }
+    res
+}
+
+fn restore_trial(e: &Extractor, m: &ArgMatches, path: &OsStr) -> Result<ExtractReport> {
+    let mut dm = DmSnapshot::new(m.value_of_os("TRIAL").unwrap());
+    let report = restore_prepared(e, m, path, &mut dm)?;
+    let target = Path::new(path).display();
+    eprintln!(
+        "Trial restore is available as {}. Apply it with `backy-extract trial merge {}' or \
+         drop it with `backy-extract trial discard {}'.",
+        dm.device().unwrap_or_default().display(),
+        target,
+        target
+    );
+    Ok(report)
+}
+
+fn trial(m: &ArgMatches) -> Result<()> {
+    let target = Path::new(m.value_of_os("TARGET").unwrap());
+    match value_t!(m, "ACTION", TrialAction)? {
+        TrialAction::Merge => dm::merge(target)?,
+        TrialAction::Discard => dm::discard(target)?,
+    }
+    Ok(())
+}
+
+// Runs `cmd` as hook and sets `failed` if it does not succeed.
+fn hook(cmd: &str, failed: &Arc<AtomicBool>) -> impl Fn(&Job) + Send + Sync {
+    let (cmd, failed) = (cmd.to_owned(), Arc::clone(failed));
+    move |job| {
+        let err = match job.run(&cmd) {
+            Ok(status) if status.success() => return,
+            Ok(status) => format!("exited with {}", status),
+            Err(e) => e.to_string(),
+        };
+        eprintln!("Hook `{}' {}", cmd, err);
+        failed.store(true, Ordering::SeqCst);
+    }
+}
+
+fn precheck(e: &Extractor, m: &ArgMatches) -> Result<()> {
+    let timeout = m
+        .value_of("PRECHECK_TIMEOUT")
+        .map(|t| t.parse().context("Invalid precheck timeout"))
+        .transpose()?
+        .map(Duration::from_secs);
+    let pc = e.precheck(timeout)?;
+    for c in &pc.chunks {
+        eprintln!("Chunk {} (#{}): {}", c.id, c.seqs[0], c.error);
+    }
+    if !pc.is_clean() {
+        bail!(
+            "Precheck failed: {} of {} chunk(s) are missing or damaged",
+            pc.chunks.len(),
+            pc.total
+        );
+    }
+    if !pc.is_complete() && !m.is_present("QUIET") {
+        eprintln!(
+            "Precheck timed out after {} of {} chunks, restoring anyway",
+            pc.checked, pc.total
+        );
+    }
+    Ok(())
+}
+
+fn compression_report(m: &ArgMatches) -> Result<()> {
+    let e = Extractor::init(revision(m)?)?;
+    let bucket = m.value_of("BUCKET").map(parse_size).transpose()?;
+    let report = e.compression_report(bucket)?;
+    if m.is_present("JSON") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else if m.is_present("CSV") {
+        report.write_csv(io::stdout().lock())?;
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+fn health(m: &ArgMatches) -> Result<()> {
+    let samples = m
+        .value_of("SAMPLES")
+        .unwrap()
+        .parse()
+        .context("Invalid number of samples")?;
+    let checks = m
+        .values_of_os("DIR")
+        .unwrap()
+        .map(|dir| health::check(dir, samples))
+        .collect::<Result<Vec<_>, _>>()?;
+    let out = match value_t!(m, "FORMAT", HealthFormat).unwrap_or(HealthFormat::Text) {
+        HealthFormat::Text => checks.iter().map(ToString::to_string).collect(),
+        HealthFormat::Json => serde_json::to_string_pretty(&checks)? + "\n",
+        HealthFormat::Prometheus => health::prometheus(&checks),
+    };
+    match m.value_of_os("OUTPUT") {
+        Some(path) => replace_file(Path::new(path), &out)?,
+        None => print!("{}", out),
+    }
+    Ok(())
+}
+
+// The textfile collector must never see partially written files
+fn replace_file(path: &Path, contents: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents)
+        .and_then(|_| fs::rename(&tmp, path))
+        .with_context(|| format!("Failed to write '{}'", path.display()))
+}
+
+#[cfg(target_os = "linux")]
+static STOP_WATCH: AtomicBool = AtomicBool::new(false);
+
+#[cfg(target_os = "linux")]
+extern "C" fn stop_watch(_sig: libc::c_int) {
+    STOP_WATCH.store(true, Ordering::SeqCst);
+}
+
+// Runs until SIGINT or SIGTERM. Pending hash cache entries are written when `w` goes away.
+#[cfg(target_os = "linux")]
+fn watch(m: &ArgMatches) -> Result<()> {
+    let dir = Path::new(m.value_of_os("DIR").unwrap());
+    let mut w = Watch::new(dir)?
+        // the cache file lives in the chunk store
+        .hash_cache(cfg!(not(feature = "read-only")) && !m.is_present("NO_HASH_CACHE"));
+    let textfile = m.value_of_os("TEXTFILE").map(Path::new);
+    if !m.is_present("QUIET") {
+        eprintln!("Watching {} for new chunk files", dir.display());
+    }
+    for sig in &[libc::SIGINT, libc::SIGTERM] {
+        // interrupts poll(2), so the flag is noticed right away
+        unsafe { libc::signal(*sig, stop_watch as *const () as libc::sighandler_t) };
+    }
+    while !STOP_WATCH.load(Ordering::SeqCst) {
+        if let Some(path) = textfile {
+            replace_file(path, &watch::prometheus(&w))?;
+        }
+        for v in w.poll(Duration::from_secs(10))? {
+            match v.error {
+                Some(e) => eprintln!(
+                    "{} chunk {} FAILED: {}",
+                    chrono::Local::now().format("%F %T"),
+                    v.id,
+                    e
+                ),
+                None if m.is_present("QUIET") => (),
+                None => eprintln!("{} chunk {} OK", chrono::Local::now().format("%F %T"), v.id),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn list(m: &ArgMatches) -> Result<()> {
+    let revs = revisions::list(m.value_of_os("BASEDIR").unwrap_or_else(|| OsStr::new(".")))?;
+    let out = io::stdout();
+    match value_t!(m, "FORMAT", ListFormat).unwrap_or(ListFormat::Table) {
+        ListFormat::Table => revisions::write_table(&revs, out.lock())?,
+        ListFormat::Json => println!("{}", serde_json::to_string_pretty(&revs)?),
+        ListFormat::Csv => revisions::write_csv(&revs, out.lock())?,
+    }
+    Ok(())
+}
+
+fn tree(m: &ArgMatches) -> Result<()> {
+    let roots = revisions::tree(m.value_of_os("BASEDIR").unwrap_or_else(|| OsStr::new(".")))?;
+    match value_t!(m, "FORMAT", TreeFormat).unwrap_or(TreeFormat::Text) {
+        TreeFormat::Text => revisions::write_tree(&roots, io::stdout().lock())?,
+        TreeFormat::Json => println!("{}", serde_json::to_string_pretty(&roots)?),
+    }
+    Ok(())
+}
+
+fn estimate(m: &ArgMatches) -> Result<()> {
+    let est = revisions::estimate(m.value_of_os("BASEDIR").unwrap_or_else(|| OsStr::new(".")))?;
+    if m.is_present("JSON") {
+        println!("{}", serde_json::to_string_pretty(&est)?);
+    } else {
+        revisions::write_estimates(&est, io::stdout().lock())?;
+    }
+    Ok(())
+}
+
+fn jobs(m: &ArgMatches) -> Result<()> {
+    let jobs = jobs::list(m.value_of_os("BASEDIR").unwrap_or_else(|| OsStr::new(".")))?;
+    if m.is_present("JSON") {
+        println!("{}", serde_json::to_string_pretty(&jobs)?);
+    } else {
+        jobs::write_jobs(&jobs, io::stdout().lock())?;
+    }
+    Ok(())
+}
+
+fn referenced_chunks(m: &ArgMatches) -> Result<()> {
+    let dir = m.value_of_os("BASEDIR").unwrap_or_else(|| OsStr::new("."));
+    let stats = match m.value_of_os("OUTPUT") {
+        Some(path) if path != "-" => refs::write_snapshot(dir, path)?,
+        _ => refs::snapshot(dir, BufWriter::new(io::stdout().lock()))?,
+    };
+    eprintln!(
+        "{} revision(s) reference {} distinct chunks ({} references)",
+        stats.revisions, stats.chunks, stats.references
+    );
+    Ok(())
+}
+
+fn diff(m: &ArgMatches) -> Result<()> {
+    let stats = revisions::similarity(
+        m.value_of_os("REVISION").unwrap(),
+        m.value_of_os("OTHER").unwrap(),
+    )?;
+    if m.is_present("JSON") {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        print!("{}", stats);
+    }
+    Ok(())
+}
+
+fn retention_report(m: &ArgMatches) -> Result<()> {
+    let selection = revisions::Selection {
+        revisions: m
+            .values_of("REVISION")
+            .map_or_else(Vec::new, |r| r.map(String::from).collect()),
+        tags: m
+            .values_of("TAG")
+            .map_or_else(Vec::new, |t| t.map(String::from).collect()),
+        before: m
+            .value_of("OLDER_THAN")
+            .map(parse_age)
+            .transpose()?
+            .map(|age| chrono::Utc::now() - age),
+    };
+    ensure!(
+        !selection.is_empty(),
+        "Select revisions with --revision, --tag or --older-than"
+    );
+    let dir = m.value_of_os("BASEDIR").unwrap_or_else(|| OsStr::new("."));
+    let report = revisions::retention_report(dir, &selection)?;
+    if m.is_present("JSON") {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+fn files(m: &ArgMatches) -> Result<()> {
+    let e = Extractor::init(revision(m)?)?;
+    let paths: Vec<&OsStr> = m.values_of_os("PATH").unwrap().collect();
+    let partition = m
+        .value_of("PARTITION")
+        .map(|p| {
+            p.trim_start_matches('p')
+                .parse()
+                .context("Invalid partition")
+        })
+        .transpose()?;
+    ensure!(
+        atty::isnt(Stdout),
+        "cowardly refusing to write a tar stream to the terminal"
+    );
+    let stats = e.files(&paths, partition, BufWriter::new(io::stdout()))?;
+    eprintln!(
+        "Extracted {} files ({}), {} directories and {} symlinks{}",
+        stats.files,
+        HumanBytes(stats.bytes),
+        stats.dirs,
+        stats.symlinks,
+        if stats.skipped > 0 {
+            format!(", skipped {} special files", stats.skipped)
+        } else {
+            String::new()
+        }
+    );
+    Ok(())
+}
+
+fn serve(m: &ArgMatches) -> Result<()> {
+    let e = Extractor::init(revision(m)?)?;
+    let listen = m.value_of("LISTEN").unwrap();
+    let addr = match listen.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => listen.to_owned(),
+    };
+    let listener =
+        TcpListener::bind(&addr).with_context(|| format!("Failed to listen on {}", addr))?;
+    eprintln!(
+        "Serving {} at http://{}/{}",
+        e.name(),
+        listener.local_addr()?,
+        e.name()
+    );
+    Ok(e.serve(listener)?)
+}
+
+fn rbd_diff(m: &ArgMatches) -> Result<()> {
+    let base = Extractor::init(m.value_of_os("FROM").unwrap())?;
+    let mut e = Extractor::init(m.value_of_os("TO").unwrap())?;
+    let unchanged = e.unchanged_since(&base)?;
+    let skipped = unchanged.len();
+    let from_snap = m.value_of("FROM_SNAP").unwrap_or_else(|| base.name());
+    let to_snap = m.value_of("TO_SNAP").unwrap_or_else(|| e.name()).to_owned();
+    let out: Box<dyn Write + Send + Sync> = match m.value_of_os("OUTPUT") {
+        Some(path) => Box::new(
+            File::create(path)
+                .with_context(|| format!("Failed to create {}", Path::new(path).display()))?,
+        ),
+        None => {
+            ensure!(
+                atty::isnt(Stdout),
+                "cowardly refusing to write an rbd diff stream to the terminal"
+            );
+            Box::new(io::stdout())
+        }
+    };
+    if !m.is_present("QUIET") {
+        e.progress(true);
+    }
+    e.extract(
+        RbdDiff::new(BufWriter::new(out))
+            .since(from_snap, unchanged)
+            .to_snap(&to_snap),
+    )?;
+    if !m.is_present("QUIET") {
+        eprintln!("Left out {} unchanged chunks", skipped);
+    }
+    Ok(())
+}
+
+fn patch(m: &ArgMatches) -> Result<()> {
+    let mut e = Extractor::init(m.value_of_os("TO").unwrap())?;
+    e.threads(threads(m)?).verify(m.is_present("VERIFY"));
+    let target = m.value_of_os("TARGET").unwrap();
+    let report = e.patch(target)?;
+    if !m.is_present("QUIET") {
+        eprint!("{}", report);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+fn export(m: &ArgMatches) -> Result<()> {
+    let revs: Vec<&OsStr> = m.values_of_os("REVISION").unwrap().collect();
+    let dest = m.value_of_os("DEST").unwrap();
+    let stats = export_store_with(&revs, dest, fsync(m)?)?;
+    eprintln!(
+        "Exported {} revision(s) with {} chunks ({}) to {}",
+        stats.revisions,
+        stats.chunks,
+        HumanBytes(stats.bytes),
+        dest.to_string_lossy()
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+fn mirror(m: &ArgMatches) -> Result<()> {
+    let dest = m.value_of_os("DEST").unwrap();
+    let stats = mirror_store(m.value_of_os("SRC").unwrap(), dest, fsync(m)?)?;
+    eprintln!(
+        "Mirrored {} revision(s) with {} chunks ({}) to {}, {} revision(s) and {} chunks \
+         already present",
+        stats.revisions,
+        stats.chunks,
+        HumanBytes(stats.bytes),
+        dest.to_string_lossy(),
+        stats.unchanged,
+        stats.skipped
+    );
+    ensure!(
+        stats.unreadable == 0,
+        "{} revision(s) could not be read and have not been mirrored",
+        stats.unreadable
+    );
+    Ok(())
+}
+
+fn print_archive_stats(verb: &str, stats: &ArchiveStats) {
+    eprintln!(
+        "{} revision {} with {} chunks ({}){}",
+        verb,
+        stats.revision,
+        stats.chunks,
+        HumanBytes(stats.bytes),
+        if stats.skipped > 0 {
+            format!(", {} already present", stats.skipped)
+        } else {
+            String::new()
+        }
+    );
+}
+
+fn pack(m: &ArgMatches) -> Result<()> {
+    let rev = &revision(m)?;
+    let stats = match m.value_of_os("ARCHIVE") {
+        Some(path) if path != "-" => {
+            let f = File::create(path)
+                .with_context(|| format!("Failed to create {}", path.to_string_lossy()))?;
+            archive::pack(rev, BufWriter::new(f))?
+        }
+        _ => {
+            ensure!(
+                atty::isnt(Stdout),
+                "cowardly refusing to write an archive to the terminal"
+            );
+            archive::pack(rev, BufWriter::new(io::stdout()))?
+        }
+    };
+    print_archive_stats("Packed", &stats);
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+fn unpack(m: &ArgMatches) -> Result<()> {
+    let path = m.value_of_os("ARCHIVE").unwrap();
+    let dest = m.value_of_os("DEST").unwrap();
+    let fsync = fsync(m)?;
+    let stats = if path == "-" {
+        archive::unpack_with(BufReader::new(io::stdin()), dest, fsync)?
+    } else {
+        let f = File::open(path)
+            .with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+        archive::unpack_with(BufReader::new(f), dest, fsync)?
+    };
+    print_archive_stats("Unpacked", &stats);
+    Ok(())
+}
+
+fn print_transfer_stats(verb: &str, stats: &TransferStats) {
+    eprintln!(
+        "{} {} chunks ({}{}) and {} zero runs{}",
+        verb,
+        stats.chunks,
+        HumanBytes(stats.bytes),
+        if stats.plain > 0 {
+            format!(", {} uncompressed", stats.plain)
+        } else {
+            String::new()
+        },
+        stats.zero_runs,
+        if stats.kept > 0 || stats.copied > 0 {
+            format!(
+                ", {} chunks unchanged, {} copied within target",
+                stats.kept, stats.copied
+            )
+        } else {
+            String::new()
+        }
+    );
+}
+
+// Pipes the send stream into a shell command. In delta mode, the command's output is expected
+// to be an advertisement as written by `receive --delta`.
+fn send_to(rev: &OsStr, cmd: &str, delta: bool, plain: bool) -> Result<TransferStats> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::piped())
+        .stdout(if delta {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .spawn()
+        .with_context(|| format!("Failed to run `{}'", cmd))?;
+    let input = BufWriter::new(child.stdin.take().unwrap());
+    let stats = match child.stdout.take() {
+        Some(output) => {
+            let have = remote::Advertisement::read(BufReader::new(output))
+                .context("Failed to read advertisement from receiver")?;
+            remote::send_delta(rev, &have, input)
+        }
+        None if plain => remote::send_delta(rev, &accept_plain(), input),
+        None => remote::send(rev, input),
+    };
+    let status = child.wait()?;
+    ensure!(status.success(), "`{}' failed: {}", cmd, status);
+    Ok(stats?)
+}
+
+// Advertisement of an empty target which wants chunks decompressed by the sender
+fn accept_plain() -> remote::Advertisement {
+    remote::Advertisement::accepting(&[remote::Encoding::Plain])
+}
+
+fn send(m: &ArgMatches) -> Result<()> {
+    let rev = &revision(m)?;
+    let plain = m.is_present("PLAIN");
+    let stats = match m.value_of("TO") {
+        Some(cmd) => send_to(rev, cmd, m.is_present("DELTA"), plain)?,
+        None => {
+            ensure!(
+                atty::isnt(Stdout),
+                "cowardly refusing to send to the terminal"
+            );
+            let out = BufWriter::new(io::stdout());
+            if plain {
+                remote::send_delta(rev, &accept_plain(), out)?
+            } else {
+                remote::send(rev, out)?
+            }
+        }
+    };
+    print_transfer_stats("Sent", &stats);
+    Ok(())
+}
+
+fn receive(m: &ArgMatches) -> Result<()> {
+    let mut r = remote::Receiver::new(BufReader::new(io::stdin()))
+        .threads(threads(m)?)
+        .progress(!m.is_present("QUIET"));
+    if let Some(c) = m.value_of("CODEC") {
+        r = r.codec(c.parse()?);
+    }
+    let stats = if m.is_present("DELTA") {
+        ensure!(
+            !m.is_present("ATOMIC"),
+            "--atomic cannot be used with --delta"
+        );
+        let path = m.value_of_os("OUTPUT").unwrap();
+        let mut have = remote::Advertisement::scan(path)
+            .with_context(|| format!("Failed to scan {}", path.to_string_lossy()))?;
+        if m.is_present("PLAIN") {
+            have.encodings = vec![remote::Encoding::Plain];
+        }
+        have.write(io::stdout())?;
+        r.receive_delta(path)?
+    } else {
+        match output(m)? {
+            Some(path) => r.receive(random_access(m, path)?)?,
+            None => r.receive(Stream::new(io::stdout()))?,
+        }
+    };
+    if !m.is_present("QUIET") {
+        print_transfer_stats("Received", &stats);
+    }
+    Ok(())
+}
+
+fn from_stream(m: &ArgMatches) -> Result<()> {
+    let mut input = BufReader::with_capacity(CHUNKSZ, io::stdin());
+    let header = if m.is_present("EXPECT_HEADER") {
+        Some(StreamHeader::read_from(&mut input).context("Failed to read stream header")?)
+    } else {
+        None
+    };
+    let limit = header.map_or(u64::MAX, |h| h.size);
+    let copied = match output(m)? {
+        Some(path) => {
+            let mut f = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+                .with_context(|| format!("Cannot open '{}'", Path::new(path).display()))?;
+            if let Some(h) = header {
+                if f.metadata()?.is_file() {
+                    f.set_len(h.size)?;
+                }
+            }
+            let n = io::copy(&mut (&mut input).take(limit), &mut f)?;
+            f.sync_all()?;
+            n
+        }
+        None => io::copy(&mut (&mut input).take(limit), &mut io::stdout().lock())?,
+    };
+    if let Some(h) = header {
+        ensure!(
+            copied == h.size,
+            "Stream ended after {} of {} announced bytes",
+            copied,
+            h.size
+        );
+        ensure!(
+            input.read(&mut [0])? == 0,
+            "Stream continues beyond the announced {} bytes",
+            h.size
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+fn fsync(m: &ArgMatches) -> Result<Fsync> {
+    Ok(m.value_of("FSYNC").unwrap_or("all").parse()?)
+}
+
+fn damage_report_subcommand() -> App<'static, 'static> {
+    let sub = SubCommand::with_name("damage-report")
+        .about("Lists corrupt or missing chunks and the partitions they affect")
         .arg(
-            Arg::with_name("THREADS")
-                .value_name("N")
-                .long("threads")
-                .short("t")
-                .help("Uses N parallel threads for decompression [default: auto]"),
+            Arg::with_name("JSON")
+                .long("json")
+                .help("Prints the report as JSON"),
+        )
+        .arg(pickable_revision_arg());
+    #[cfg(not(feature = "read-only"))]
+    let sub = sub
+        .arg(
+            Arg::with_name("QUARANTINE").long("quarantine").help(
+                "Moves corrupt chunk files to chunks/quarantine/ so that they can be replaced",
+            ),
+        )
+        .arg(
+            Arg::with_name("NO_HASH_CACHE")
+                .long("no-hash-cache")
+                .help("Checks all chunks, even those verified before whose files are unchanged"),
+        );
+    sub
+}
+
+// Subcommands which write to chunk stores
+#[cfg(not(feature = "read-only"))]
+fn store_subcommands() -> Vec<App<'static, 'static>> {
+    let fsync = Arg::with_name("FSYNC")
+        .long("fsync")
+        .value_name("POLICY")
+        .possible_values(&["never", "chunks", "all"])
+        .help(
+            "Flushes chunk files (`chunks') or chunk files and their directories (`all') to \
+             disk [default: all]",
+        );
+    vec![
+        SubCommand::with_name("export-store")
+            .about("Copies revisions and the chunks they reference into a new store")
+            .arg(revision_arg().multiple(true))
+            .arg(
+                Arg::with_name("DEST")
+                    .help("Directory for the new store (must not exist or be empty)")
+                    .required(true),
+            )
+            .arg(fsync.clone()),
+        SubCommand::with_name("mirror")
+            .about("Copies all revisions and chunks missing in a store from another store")
+            .arg(
+                Arg::with_name("SRC")
+                    .help("Backup directory to copy from")
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("DEST")
+                    .help("Backup directory to copy to (created if necessary)")
+                    .required(true),
+            )
+            .arg(fsync.clone()),
+        SubCommand::with_name("unpack")
+            .about("Adds a revision from an archive file to a (new or existing) store")
+            .arg(
+                Arg::with_name("ARCHIVE")
+                    .help("Archive file (`-' for stdin)")
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("DEST")
+                    .help("Backup directory to unpack into")
+                    .required(true),
+            )
+            .arg(fsync),
+    ]
+}
+
+#[cfg(feature = "read-only")]
+fn store_subcommands() -> Vec<App<'static, 'static>> {
+    Vec::new()
+}
+
+// inotify is Linux only
+#[cfg(target_os = "linux")]
+fn watch_subcommands() -> Vec<App<'static, 'static>> {
+    vec![SubCommand::with_name("watch")
+        .about("Verifies chunk files as they are written to a backup directory")
+        .arg(
+            Arg::with_name("TEXTFILE")
+                .long("textfile")
+                .value_name("FILE")
+                .help(
+                    "Keeps counters in FILE for the Prometheus node exporter's textfile \
+                     collector",
+                ),
         )
         .arg(
-            Arg::with_name("SPARSE")
-                .long("sparse")
-                .short("s")
-                .value_name("WHEN")
-                .possible_values(&Sparse::variants())
-                .case_insensitive(true)
-                .help("Skips over contiguous regions of NUL bytes"),
+            Arg::with_name("NO_HASH_CACHE")
+                .long("no-hash-cache")
+                .help("Does not add verified chunks to the hash cache"),
         )
         .arg(
             Arg::with_name("QUIET")
                 .long("quiet")
                 .short("q")
-                .help("Does not display progress indication"),
+                .help("Reports failures only"),
         )
         .arg(
-            Arg::with_name("REVISION")
-                .help("Backy backup revision file (e.g., `2hQmTeMjRaFG9jonuXeCnR' or `last')")
-                .required(true),
-        )
-        .arg(Arg::with_name("OUTPUT").help("Output file or block device (or stdout if absent)"))
-        .get_matches();
-    let mut e = Extractor::init(m.value_of_os("REVISION").unwrap())?;
-    if let Some(t) = m.value_of("THREADS") {
-        e.threads(t.parse::<u8>().context("Invalid number of threads")?);
+            Arg::with_name("DIR")
+                .required(true)
+                .help("Backup directory containing revisions and chunks/"),
+        )]
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch_subcommands() -> Vec<App<'static, 'static>> {
+    Vec::new()
+}
+
+// Points to the chunk file behind a chunk error as absolute path, ready to be inspected.
+fn print_chunk_file(err: &anyhow::Error) {
+    let chunk_err = err
+        .chain()
+        .filter_map(|e| e.downcast_ref::<ExtractError>())
+        .find(|e| e.chunk_file().is_some());
+    if let Some(e) = chunk_err {
+        let path = e.chunk_file().unwrap();
+        let path = std::env::current_dir()
+            .map(|cwd| cwd.join(path))
+            .unwrap_or_else(|_| path.to_owned());
+        match e.failed_check() {
+            Some(check) => eprintln!("Chunk file: {} (failed {} check)", path.display(), check),
+            None => eprintln!("Chunk file: {}", path.display()),
+        }
     }
-    if !m.is_present("QUIET") {
-        e.progress(true);
+}
+
+// Restores the revision given on the command line. Failures which happen before the extractor
+// has run its hooks, e.g. in the precheck or while setting up the target, run the failure hook
+// here.
+fn restore(m: &ArgMatches) -> Result<()> {
+    let start = Instant::now();
+    let fired = Arc::new(AtomicBool::new(false));
+    let err = match setup_and_restore(m, &fired) {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+    if let (Some(cmd), false) = (m.value_of("ON_FAILURE"), fired.load(Ordering::SeqCst)) {
+        let revision = m
+            .value_of_os("REVISION")
+            .filter(|r| *r != "-")
+            .map(Path::new)
+            .map_or_else(String::new, |r| {
+                fs::canonicalize(r)
+                    .unwrap_or_else(|_| r.to_owned())
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            });
+        let class = err
+            .chain()
+            .find_map(|e| e.downcast_ref::<ExtractError>())
+            .map_or("system", ExtractError::class);
+        hook(cmd, &Arc::new(AtomicBool::new(false)))(&Job {
+            revision,
+            elapsed: start.elapsed(),
+            error: Some(format!("{:#}", err)),
+            error_class: Some(class),
+            ..Job::default()
+        });
+    }
+    Err(err)
+}
+
+fn setup_and_restore(m: &ArgMatches, fired: &Arc<AtomicBool>) -> Result<()> {
+    let mut e = Extractor::init(revision(m)?)?;
+    if let Some(path) = m.value_of_os("AUDIT_LOG") {
+        let log = AuditLog::open(path)
+            .with_context(|| format!("Cannot open audit log '{}'", Path::new(path).display()))?;
+        let job = match m.value_of("JOB_ID") {
+            Some(id) => id.to_owned(),
+            None => format!("backy-extract-{}", process::id()),
+        };
+        e.audit(log, &job);
+    }
+    let profile = profile(m)?;
+    if let Some(p) = profile {
+        e.profile(p);
+    }
+    e.threads(concurrency(m)?);
+    if let Some(c) = m.value_of("CODEC") {
+        e.codec(c.parse()?);
     }
-    let output = m.value_of_os("OUTPUT").unwrap_or_else(|| OsStr::new("-"));
-    if output.to_string_lossy() == "-" {
+    if let Some(r) = m.value_of("READER") {
+        e.reader(r.parse()?);
+    }
+    if let Some(dir) = m.value_of_os("MAP_CACHE") {
+        e.map_cache(MapCache::new(dir));
+    }
+    e.verify(m.is_present("VERIFY"));
+    if let Some(p) = m.value_of("VERIFY_SAMPLE") {
+        let p: f64 = p.parse().context("Invalid sample percentage")?;
         ensure!(
-            atty::isnt(Stdout),
-            "cowardly refusing to restore to the terminal"
+            (0.0..=100.0).contains(&p),
+            "Sample percentage must be between 0 and 100"
         );
-        e.extract(Stream::new(io::stdout()))?;
-    } else {
-        let sparse = value_t!(m, "SPARSE", Sparse).unwrap_or(Sparse::Auto);
-        e.extract(RandomAccess::new(
-            output,
-            match sparse {
-                Sparse::Always => Some(true),
-                Sparse::Never => Some(false),
-                Sparse::Auto => None,
-            },
-        ))?;
+        e.verify_sample(p);
+    }
+    e.skip_unallocated(m.is_present("SKIP_UNALLOCATED"));
+    if let Some(level) = m.value_of("REQUIRE_TRUST") {
+        e.require_trust(level.parse()?);
+    }
+    if let Some(specs) = m.values_of("PRIORITY") {
+        let ranges = priority(&e, specs.collect())?;
+        e.priority(&ranges);
+    }
+    for spec in m.values_of("FILTER").into_iter().flatten() {
+        let f = filter::builtin(spec, &e)?;
+        e.filter(f);
     }
+    e.shared_first(m.is_present("SHARED_FIRST"));
+    if let Some(order) = m.value_of("ORDER") {
+        e.order(restore_order(order)?);
+    }
+    if let Some(n) = m.value_of("REORDER_WINDOW") {
+        e.reorder_window(n.parse().context("Invalid reorder window")?);
+    }
+    e.limits(Limits {
+        io_class: m.value_of("IO_CLASS").map(str::parse).transpose()?,
+        nice: m
+            .value_of("NICE")
+            .map(|n| n.parse().context("Invalid nice value"))
+            .transpose()?,
+        ..profile.map_or_else(Limits::default, Profile::limits)
+    });
+    let hook_failed = Arc::new(AtomicBool::new(false));
+    if let Some(cmd) = m.value_of("ON_SUCCESS") {
+        e.on_success(hook(cmd, &hook_failed));
+    }
+    if let Some(cmd) = m.value_of("ON_FAILURE") {
+        let (hook, fired) = (hook(cmd, &hook_failed), Arc::clone(fired));
+        e.on_failure(move |job| {
+            fired.store(true, Ordering::SeqCst);
+            hook(job)
+        });
+    }
+    if m.is_present("VERBOSE") {
+        eprint!("{}", e.plan()?);
+    }
+    if m.is_present("PRECHECK") {
+        precheck(&e, m)?;
+    }
+    if !m.is_present("QUIET") {
+        e.progress(true);
+    }
+    if m.value_of("PROGRESS_STYLE") == Some("detailed") {
+        e.progress_style(BarStyle::Detailed);
+    }
+    let auto = if m.is_present("AUTO_NAME") {
+        Some(auto_output(&e, m)?)
+    } else {
+        None
+    };
+    let target = match &auto {
+        Some(path) => Some(path.as_os_str()),
+        None => output(m)?,
+    };
+    match target {
+        Some(_) if m.is_present("STREAM_HEADER") => {
+            bail!("--stream-header works only when restoring to stdout")
+        }
+        Some(path) if m.is_present("RBD_DIFF") => {
+            let f = File::create(path)
+                .with_context(|| format!("Failed to create {}", Path::new(path).display()))?;
+            e.extract(rbd_diff_out(m, BufWriter::new(f)))?
+        }
+        None if m.is_present("RBD_DIFF") => {
+            ensure!(
+                atty::isnt(Stdout),
+                "cowardly refusing to write an rbd diff stream to the terminal"
+            );
+            e.extract(rbd_diff_out(m, BufWriter::new(io::stdout())))?
+        }
+        Some(url) if url.to_str().is_some_and(Nbd::is_url) => {
+            ensure!(
+                !["ATOMIC", "ZFS_SNAPSHOT", "TRIAL", "PREALLOC", "VHD"]
+                    .iter()
+                    .any(|a| m.is_present(a)),
+                "--atomic, --prealloc, --vhd, --zfs-snapshot and --trial don't work with NBD \
+                 exports"
+            );
+            e.extract(Nbd::new(url.to_str().unwrap())?.retry(retry(m)?))?
+        }
+        #[cfg(feature = "tus")]
+        Some(url) if url.to_str().is_some_and(Tus::is_url) => e.extract(tus(m, url)?)?,
+        Some(path) if m.is_present("ZFS_SNAPSHOT") => restore_zfs(&e, m, path)?,
+        Some(path) if m.is_present("TRIAL") => restore_trial(&e, m, path)?,
+        Some(path) if m.is_present("VHD") => e.extract(Vhd::new(random_access(m, path)?))?,
+        Some(path) => e.extract(random_access(m, path)?)?,
+        None => {
+            let header = m.is_present("STREAM_HEADER");
+            match sparse_stdout(m)? {
+                Some(f) => e.extract(Stream::sparse(BufWriter::new(f)).header(header))?,
+                None => e.extract(Stream::new(io::stdout()).header(header))?,
+            }
+        }
+    };
+    ensure!(!hook_failed.load(Ordering::SeqCst), "Success hook failed");
     Ok(())
 }
+
+fn main() -> Result<()> {
+    run().inspect_err(print_chunk_file)
+}
+
+fn run() -> Result<()> {
+    let m = app_from_crate!()
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .args(&restore_args())
+        .arg(
+            Arg::with_name("VERIFY")
+                .long("verify")
+                .help("Verifies chunk checksums while restoring"),
+        )
+        .arg(
+            Arg::with_name("VERIFY_SAMPLE")
+                .long("verify-sample")
+                .value_name("P")
+                .conflicts_with("VERIFY")
+                .help(
+                    "Verifies checksums of P percent of the chunks, chosen by revision, and of \
+                     all chunks used more than once",
+                ),
+        )
+        .arg(Arg::with_name("STREAM_HEADER").long("stream-header").help(
+            "Starts the image on stdout with a header which states its size (see \
+                     `from-stream --expect-header')",
+        ))
+        .arg(
+            Arg::with_name("FILTER")
+                .long("filter")
+                .value_name("SPEC")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Transforms image contents while restoring, e.g. `zero-swap' or \
+                     `byteswap:4,0-1G'. Repeat to apply several filters in order",
+                ),
+        )
+        .arg(
+            Arg::with_name("VHD")
+                .long("vhd")
+                .requires("OUTPUT")
+                .conflicts_with_all(&["ZFS_SNAPSHOT", "TRIAL", "STREAM_HEADER"])
+                .help("Writes OUTPUT as fixed VHD which Hyper-V and Azure accept directly"),
+        )
+        .arg(
+            Arg::with_name("AUTO_NAME")
+                .long("auto-name")
+                .requires("OUTPUT")
+                .conflicts_with_all(&["ZFS_SNAPSHOT", "TRIAL", "STREAM_HEADER", "RBD_DIFF"])
+                .help(
+                    "Restores into a new file in the directory OUTPUT which is named \
+                     VM-TIMESTAMP-UUID.raw (or .vhd) after the revision",
+                ),
+        )
+        .arg(
+            Arg::with_name("RBD_DIFF")
+                .long("rbd-diff")
+                .conflicts_with_all(&[
+                    "VHD",
+                    "STREAM_HEADER",
+                    "ZFS_SNAPSHOT",
+                    "TRIAL",
+                    "ATOMIC",
+                    "PREALLOC",
+                ])
+                .help("Writes the image as stream for `rbd import-diff'"),
+        )
+        .arg(
+            Arg::with_name("RBD_SNAP")
+                .long("rbd-snap")
+                .value_name("NAME")
+                .requires("RBD_DIFF")
+                .help("Makes `rbd import-diff' create snapshot NAME after the import"),
+        )
+        .arg(
+            Arg::with_name("VERBOSE")
+                .long("verbose")
+                .short("v")
+                .help("Prints what the restore is going to read and write before starting"),
+        )
+        .arg(
+            Arg::with_name("PRECHECK")
+                .long("precheck")
+                .help("Checks that all chunk files are present and intact before writing"),
+        )
+        .arg(
+            Arg::with_name("PRECHECK_TIMEOUT")
+                .long("precheck-timeout")
+                .value_name("SECONDS")
+                .requires("PRECHECK")
+                .help("Restores anyway if the precheck takes longer [default: no limit]"),
+        )
+        .arg(
+            Arg::with_name("ON_SUCCESS")
+                .long("on-success")
+                .value_name("CMD")
+                .help("Runs shell command CMD after a successful restore"),
+        )
+        .arg(
+            Arg::with_name("ON_FAILURE")
+                .long("on-failure")
+                .value_name("CMD")
+                .help("Runs shell command CMD after a failed restore"),
+        )
+        .arg(
+            Arg::with_name("ZFS_SNAPSHOT")
+                .long("zfs-snapshot")
+                .requires("OUTPUT")
+                .conflicts_with("ATOMIC")
+                .help("Snapshots the ZFS volume OUTPUT before restoring to it"),
+        )
+        .arg(
+            Arg::with_name("ZFS_SNAPSHOT_AFTER")
+                .long("zfs-snapshot-after")
+                .requires("ZFS_SNAPSHOT")
+                .help("Snapshots the ZFS volume once more after a successful restore"),
+        )
+        .arg(
+            Arg::with_name("TRIAL")
+                .long("trial")
+                .value_name("COW")
+                .requires("OUTPUT")
+                .conflicts_with_all(&["ATOMIC", "ZFS_SNAPSHOT"])
+                .help(
+                    "Restores into a device-mapper snapshot of OUTPUT which keeps changes on \
+                     block device COW, see the `trial' subcommand",
+                ),
+        )
+        .arg(
+            Arg::with_name("REQUIRE_TRUST")
+                .long("require-trust")
+                .value_name("LEVEL")
+                .possible_values(&["distrusted", "unknown", "trusted", "verified"])
+                .help("Refuses to restore revisions which backy trusts less than LEVEL"),
+        )
+        .arg(
+            Arg::with_name("SKIP_UNALLOCATED")
+                .long("skip-unallocated")
+                .help(
+                    "Writes zeros instead of chunks which contain only free space of \
+                     ext2/3/4 guest file systems",
+                ),
+        )
+        .arg(
+            Arg::with_name("REORDER_WINDOW")
+                .long("reorder-window")
+                .value_name("N")
+                .help("Pauses decompression when writing to stdout falls behind by N chunks"),
+        )
+        .arg(
+            Arg::with_name("IO_CLASS")
+                .long("io-class")
+                .value_name("CLASS")
+                .help(
+                    "Reads and writes with I/O scheduling class `idle', `best-effort[:LEVEL]' \
+                     or `realtime[:LEVEL]'",
+                ),
+        )
+        .arg(
+            Arg::with_name("NICE")
+                .long("nice")
+                .value_name("N")
+                .allow_hyphen_values(true)
+                .help("Runs decompression and writeout threads with nice value N"),
+        )
+        .arg(
+            Arg::with_name("AUDIT_LOG")
+                .long("audit-log")
+                .value_name("FILE")
+                .help("Appends a record for each chunk read from the backup store to FILE"),
+        )
+        .arg(
+            Arg::with_name("JOB_ID")
+                .long("job-id")
+                .value_name("ID")
+                .requires("AUDIT_LOG")
+                .help("Identifies this restore in the audit log [default: backy-extract-PID]"),
+        )
+        .arg(
+            Arg::with_name("PRIORITY")
+                .long("priority")
+                .short("p")
+                .value_name("REGION")
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Restores REGION before everything else. REGION is `table' (first MiB), a \
+                     partition like `p1', or a byte range like `0-64M'. Repeat for further \
+                     regions in descending priority",
+                ),
+        )
+        .arg(
+            Arg::with_name("READERS")
+                .long("readers")
+                .value_name("N")
+                .help(
+                    "Reads chunk files with N separate threads, e.g. for stores on network file \
+                     systems [default: decompression threads read]",
+                ),
+        )
+        .arg(
+            Arg::with_name("WRITERS")
+                .long("writers")
+                .value_name("N")
+                .help("Writes to files and block devices with N parallel threads [default: 1]"),
+        )
+        .arg(
+            Arg::with_name("PROFILE")
+                .long("profile")
+                .value_name("NAME")
+                .possible_values(&["local-nvme", "nfs-store", "low-memory"])
+                .help(
+                    "Presets threads, queues, page cache use and sparse mode for the environment \
+                     (explicit options take precedence)",
+                ),
+        )
+        .arg(
+            Arg::with_name("CONFIG")
+                .long("config")
+                .value_name("FILE")
+                .help("Reads the default profile from FILE [default: /etc/backy-extract.yaml]"),
+        )
+        .arg(
+            Arg::with_name("SHARED_FIRST")
+                .long("shared-first")
+                .help("Restores chunks which occur many times in the image first"),
+        )
+        .arg(
+            Arg::with_name("ORDER")
+                .long("order")
+                .value_name("ORDER")
+                .requires("OUTPUT")
+                .help(
+                    "Restores chunks `forward', in `reverse' (end of the image first) or \
+                     `outward:OFFSET' from OFFSET towards both ends [default: forward]",
+                ),
+        )
+        .arg(pickable_revision_arg())
+        .arg(output_arg())
+        .subcommand(damage_report_subcommand())
+        .subcommand(
+            SubCommand::with_name("compression-report")
+                .about("Shows how well the chunks of a revision compress, by image region")
+                .arg(
+                    Arg::with_name("BUCKET")
+                        .long("bucket")
+                        .value_name("SIZE")
+                        .help(
+                            "Aggregates regions of SIZE bytes (K/M/G/T suffix) \
+                             [default: 1/64 of the image]",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("JSON")
+                        .long("json")
+                        .help("Prints the report including all chunks as JSON"),
+                )
+                .arg(
+                    Arg::with_name("CSV")
+                        .long("csv")
+                        .conflicts_with("JSON")
+                        .help("Prints the heatmap as CSV"),
+                )
+                .arg(pickable_revision_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("health")
+                .about("Checks backup directories quickly, e.g. for monitoring")
+                .arg(
+                    Arg::with_name("FORMAT")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&HealthFormat::variants())
+                        .case_insensitive(true)
+                        .help("Output format [default: text]"),
+                )
+                .arg(
+                    Arg::with_name("SAMPLES")
+                        .long("samples")
+                        .value_name("N")
+                        .default_value("8")
+                        .help("Reads N randomly chosen chunks per directory"),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .long("output")
+                        .short("o")
+                        .value_name("FILE")
+                        .help("Replaces FILE atomically instead of writing to stdout"),
+                )
+                .arg(
+                    Arg::with_name("DIR")
+                        .required(true)
+                        .multiple(true)
+                        .help("Backup directory containing revisions and chunks/"),
+                ),
+        )
+        .subcommands(watch_subcommands())
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists the revisions of a backup directory")
+                .arg(
+                    Arg::with_name("FORMAT")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&ListFormat::variants())
+                        .case_insensitive(true)
+                        .help("Output format [default: table]"),
+                )
+                .arg(
+                    Arg::with_name("BASEDIR")
+                        .help("Backup directory containing revisions and chunks/ [default: .]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("tree")
+                .about("Shows the parent relationships of all revisions")
+                .arg(
+                    Arg::with_name("FORMAT")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&TreeFormat::variants())
+                        .case_insensitive(true)
+                        .help("Output format [default: text]"),
+                )
+                .arg(
+                    Arg::with_name("BASEDIR")
+                        .help("Backup directory containing revisions and chunks/ [default: .]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("estimate")
+                .about("Estimates how much restoring each revision writes, without reading chunks")
+                .arg(
+                    Arg::with_name("JSON")
+                        .long("json")
+                        .help("Prints the estimates as JSON"),
+                )
+                .arg(
+                    Arg::with_name("BASEDIR")
+                        .help("Backup directory containing revisions and chunks/ [default: .]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("jobs")
+                .about("Lists the restores currently running on a backup directory")
+                .arg(
+                    Arg::with_name("JSON")
+                        .long("json")
+                        .help("Prints the jobs as JSON"),
+                )
+                .arg(
+                    Arg::with_name("BASEDIR")
+                        .help("Backup directory containing revisions and chunks/ [default: .]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("referenced-chunks")
+                .about("Writes the sorted set of chunk IDs referenced by any revision, with counts")
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .long("output")
+                        .short("o")
+                        .value_name("FILE")
+                        .help("Replaces FILE atomically instead of writing to stdout"),
+                )
+                .arg(
+                    Arg::with_name("BASEDIR")
+                        .help("Backup directory containing revisions and chunks/ [default: .]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Shows how many chunks two revisions have in common")
+                .arg(
+                    Arg::with_name("JSON")
+                        .long("json")
+                        .help("Prints the comparison as JSON"),
+                )
+                .arg(revision_arg())
+                .arg(
+                    Arg::with_name("OTHER")
+                        .help("Revision file to compare with, may be in another backup directory")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("retention-report")
+                .about("Shows how much space purging selected revisions would free")
+                .arg(
+                    Arg::with_name("REVISION")
+                        .long("revision")
+                        .short("r")
+                        .value_name("UUID")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Selects revision UUID, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("TAG")
+                        .long("tag")
+                        .short("t")
+                        .value_name("TAG")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Selects revisions tagged with TAG, may be repeated"),
+                )
+                .arg(
+                    Arg::with_name("OLDER_THAN")
+                        .long("older-than")
+                        .value_name("AGE")
+                        .help("Selects revisions older than AGE, e.g. `30d' (s/m/h/d/w suffix)"),
+                )
+                .arg(
+                    Arg::with_name("JSON")
+                        .long("json")
+                        .help("Prints the report as JSON"),
+                )
+                .arg(
+                    Arg::with_name("BASEDIR")
+                        .help("Backup directory containing revisions and chunks/ [default: .]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trial")
+                .about("Applies or drops a restore made with --trial")
+                .arg(
+                    Arg::with_name("ACTION")
+                        .required(true)
+                        .possible_values(&TrialAction::variants())
+                        .case_insensitive(true)
+                        .help("`merge' writes the trial restore to TARGET, `discard' drops it"),
+                )
+                .arg(
+                    Arg::with_name("TARGET")
+                        .required(true)
+                        .help("Block device given as OUTPUT to the trial restore"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("files")
+                .about("Writes files from inside the image as tar stream to stdout")
+                .arg(
+                    Arg::with_name("PATH")
+                        .long("path")
+                        .value_name("PATH")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .required(true)
+                        .help("Absolute path inside the guest (repeat for more paths)"),
+                )
+                .arg(
+                    Arg::with_name("PARTITION")
+                        .long("partition")
+                        .value_name("N")
+                        .help("Reads from partition N [default: first ext2/3/4 file system]"),
+                )
+                .arg(pickable_revision_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Serves the image read-only over HTTP with support for byte ranges")
+                .arg(
+                    Arg::with_name("LISTEN")
+                        .long("listen")
+                        .value_name("ADDR")
+                        .default_value("127.0.0.1:8080")
+                        .help("Address and port to listen on (\":PORT\" for all interfaces)"),
+                )
+                .arg(pickable_revision_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("rbd-diff")
+                .about("Writes the changes between two revisions as stream for `rbd import-diff'")
+                .arg(
+                    Arg::with_name("FROM")
+                        .long("from")
+                        .value_name("REVISION")
+                        .required(true)
+                        .help("Revision the RBD image holds already"),
+                )
+                .arg(
+                    Arg::with_name("TO")
+                        .long("to")
+                        .value_name("REVISION")
+                        .required(true)
+                        .help("Revision the RBD image should be brought to"),
+                )
+                .arg(
+                    Arg::with_name("FROM_SNAP")
+                        .long("from-snap")
+                        .value_name("NAME")
+                        .help("RBD snapshot which holds the --from revision [default: its ID]"),
+                )
+                .arg(
+                    Arg::with_name("TO_SNAP")
+                        .long("to-snap")
+                        .value_name("NAME")
+                        .help("RBD snapshot created after the import [default: --to revision ID]"),
+                )
+                .arg(
+                    Arg::with_name("QUIET")
+                        .long("quiet")
+                        .short("q")
+                        .help("Does not display progress indication"),
+                )
+                .arg(Arg::with_name("OUTPUT").help("Output file (or stdout if absent)")),
+        )
+        .subcommand(
+            SubCommand::with_name("patch")
+                .about(
+                    "Rewrites only those chunks of an existing image which differ from a revision",
+                )
+                .arg(
+                    Arg::with_name("TO")
+                        .long("to")
+                        .value_name("REVISION")
+                        .required(true)
+                        .help("Backy backup revision file the image should match"),
+                )
+                .arg(
+                    Arg::with_name("THREADS")
+                        .value_name("N")
+                        .long("threads")
+                        .short("t")
+                        .help("Uses N parallel threads for comparing and writing [default: auto]"),
+                )
+                .arg(
+                    Arg::with_name("VERIFY")
+                        .long("verify")
+                        .help("Verifies checksums of chunks loaded from the store"),
+                )
+                .arg(
+                    Arg::with_name("QUIET")
+                        .long("quiet")
+                        .short("q")
+                        .help("Does not print a summary"),
+                )
+                .arg(
+                    Arg::with_name("TARGET")
+                        .required(true)
+                        .help("Image file or block device to update in place"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pack")
+                .about("Writes a revision and its chunks into a single archive file")
+                .arg(pickable_revision_arg())
+                .arg(Arg::with_name("ARCHIVE").help("Archive file (or stdout if absent)")),
+        )
+        .subcommands(store_subcommands())
+        .subcommand(
+            SubCommand::with_name("send")
+                .about("Writes a revision as send stream to stdout (see `receive')")
+                .arg(
+                    Arg::with_name("TO")
+                        .long("to")
+                        .value_name("COMMAND")
+                        .help("Pipes the stream into COMMAND instead of stdout"),
+                )
+                .arg(Arg::with_name("DELTA").long("delta").requires("TO").help(
+                    "Sends only chunks missing on the receiving side. COMMAND must run \
+                             `receive --delta'",
+                ))
+                .arg(
+                    Arg::with_name("PLAIN")
+                        .long("plain")
+                        .conflicts_with("DELTA")
+                        .help(
+                            "Decompresses chunks before sending them, for receivers short of \
+                             CPU time. With --delta, the receiver decides (see `receive --plain')",
+                        ),
+                )
+                .arg(pickable_revision_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("receive")
+                .about("Restores an image from a send stream read from stdin")
+                .args(&restore_args())
+                .arg(
+                    Arg::with_name("DELTA")
+                        .long("delta")
+                        .requires("OUTPUT")
+                        .help(
+                            "Updates OUTPUT in place. Advertises existing contents on stdout \
+                             (see `send --delta')",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("PLAIN")
+                        .long("plain")
+                        .requires("DELTA")
+                        .help("Asks the sender to decompress chunks"),
+                )
+                .arg(output_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("from-stream")
+                .about("Writes an image read from stdin to OUTPUT")
+                .arg(Arg::with_name("EXPECT_HEADER").long("expect-header").help(
+                    "Requires the stream to start with a header (see `--stream-header') and \
+                     checks that it contains exactly the announced number of bytes",
+                ))
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("Output file or block device (or stdout if absent)"),
+                ),
+        )
+        .get_matches();
+    if let Some(sub) = m.subcommand_matches("damage-report") {
+        return damage_report(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("compression-report") {
+        return compression_report(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("health") {
+        return health(sub);
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(sub) = m.subcommand_matches("watch") {
+            return watch(sub);
+        }
+    }
+    if let Some(sub) = m.subcommand_matches("list") {
+        return list(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("tree") {
+        return tree(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("estimate") {
+        return estimate(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("jobs") {
+        return jobs(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("referenced-chunks") {
+        return referenced_chunks(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("diff") {
+        return diff(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("retention-report") {
+        return retention_report(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("trial") {
+        return trial(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("files") {
+        return files(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("serve") {
+        return serve(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("rbd-diff") {
+        return rbd_diff(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("patch") {
+        return patch(sub);
+    }
+    #[cfg(not(feature = "read-only"))]
+    {
+        if let Some(sub) = m.subcommand_matches("export-store") {
+            return export(sub);
+        }
+        if let Some(sub) = m.subcommand_matches("mirror") {
+            return mirror(sub);
+        }
+        if let Some(sub) = m.subcommand_matches("unpack") {
+            return unpack(sub);
+        }
+    }
+    if let Some(sub) = m.subcommand_matches("pack") {
+        return pack(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("send") {
+        return send(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("receive") {
+        return receive(sub);
+    }
+    if let Some(sub) = m.subcommand_matches("from-stream") {
+        return from_stream(sub);
+    }
+    restore(&m)
+}
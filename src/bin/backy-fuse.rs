@@ -1,8 +1,14 @@
 use anyhow::Result;
-use backy_extract::fuse;
+use backy_extract::fuse::{self, helper};
+use std::env;
+use std::process;
 use structopt::StructOpt;
 
 fn main() -> Result<()> {
     env_logger::init();
-    fuse::App::from_args().run()
+    let args: Vec<_> = env::args_os().collect();
+    if !args.is_empty() && helper::is_helper(&args[0]) {
+        process::exit(helper::main(&args));
+    }
+    Ok(fuse::App::from_args().run()?)
 }
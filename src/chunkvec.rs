@@ -1,65 +1,186 @@
-use crate::backend::Backend;
+use crate::backend::{self, Backend, RawChunk};
+use crate::damage::DamagedChunk;
+use crate::hashcache::HashCache;
 use crate::{pos2chunk, Chunk, Data, ExtractError, Result, CHUNKSZ};
 
-use crossbeam::channel::Sender;
+use crossbeam::channel::{Receiver, Sender};
+use murmur3::murmur3_x64_128;
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, Visitor};
 use serde::{Deserialize, Serialize};
 use smallstr::SmallString;
 use smallvec::SmallVec;
-use std::collections::{BTreeMap, HashMap};
+use std::cmp::Reverse;
+#[cfg(any(test, feature = "testing"))]
+use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
 use std::iter::IntoIterator;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Instant;
 
 pub type ChunkId = SmallString<[u8; 32]>;
+#[cfg(any(test, feature = "testing"))]
 pub type Seq = SmallString<[u8; 7]>;
 
 // Format of the revision file as deserialized from JSON
+#[cfg(any(test, feature = "testing"))]
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct RevisionMap {
     pub mapping: HashMap<Seq, ChunkId>,
     pub size: u64,
 }
 
-impl IntoIterator for RevisionMap {
-    type Item = (u32, Option<ChunkId>);
-    type IntoIter = RevisionMapIterator;
+// Key of a mapping entry. Invalid seqs are kept as string to be reported later.
+struct SeqKey(std::result::Result<u32, String>);
 
-    fn into_iter(self) -> RevisionMapIterator {
-        RevisionMapIterator::new(self)
+impl<'de> Deserialize<'de> for SeqKey {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        struct V;
+        impl<'de> Visitor<'de> for V {
+            type Value = SeqKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a seq number")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> std::result::Result<SeqKey, E> {
+                Ok(SeqKey(s.parse().map_err(|_| s.to_owned())))
+            }
+        }
+        d.deserialize_str(V)
     }
 }
 
-pub struct RevisionMapIterator {
-    map: HashMap<u32, ChunkId>,
-    i: u32,
-    max: u32,
+// Revision map under construction while the JSON is parsed.
+#[derive(Default)]
+struct MapBuilder {
+    chunks: ChunkMap,
+    size: u64,
+    /// First problem found in the mapping
+    invalid: Option<String>,
 }
 
-impl RevisionMapIterator {
-    fn new(map: RevisionMap) -> Self {
-        let max = pos2chunk(map.size);
-        Self {
-            map: map
-                .mapping
-                .into_iter()
-                .map(|(k, v)| (k.parse().expect("numeric key"), v))
-                .collect(),
-            i: 0,
-            max,
+impl MapBuilder {
+    fn add(&mut self, seq: SeqKey, id: ChunkId) {
+        if self.invalid.is_some() {
+            return;
+        }
+        match seq.0 {
+            Err(seq) => self.invalid = Some(format!("invalid seq '{}'", seq)),
+            Ok(seq) if id.len() < 2 || !id.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                self.invalid = Some(format!("invalid chunk id '{}' at seq {}", id, seq))
+            }
+            Ok(seq) => self.chunks.entry(id).or_default().push(seq),
+        }
+    }
+
+    // Checks that all seqs are inside the image and unique and collects zero seqs.
+    fn finish(mut self) -> Result<ChunkVec> {
+        if !self.size.is_multiple_of(CHUNKSZ as u64) {
+            return Err(ExtractError::UnalignedSize(self.size));
+        }
+        let max = self.size >> crate::CHUNKSZ_LOG;
+        if max > u64::from(u32::MAX) {
+            return Err(ExtractError::InvalidMap(format!(
+                "image size {} too large",
+                self.size
+            )));
+        }
+        if let Some(invalid) = self.invalid {
+            return Err(ExtractError::InvalidMap(invalid));
         }
+        let mut mapped = vec![false; max as usize];
+        for seqs in self.chunks.values_mut() {
+            for &seq in seqs.iter() {
+                match mapped.get_mut(seq as usize) {
+                    Some(m) if *m => {
+                        return Err(ExtractError::InvalidMap(format!("duplicate seq '{}'", seq)))
+                    }
+                    Some(m) => *m = true,
+                    None => return Err(ExtractError::InvalidMap(format!("invalid seq '{}'", seq))),
+                }
+            }
+            seqs.sort_unstable();
+        }
+        let zero_seqs = (0..max as u32).filter(|&s| !mapped[s as usize]).collect();
+        Ok(ChunkVec {
+            size: self.size,
+            chunks: self.chunks,
+            zero_seqs,
+            priority: Vec::new(),
+            shared_first: false,
+            order: RestoreOrder::default(),
+        })
     }
 }
 
-impl Iterator for RevisionMapIterator {
-    type Item = (u32, Option<ChunkId>);
+// Feeds the entries of the `mapping` object into a `MapBuilder` one by one.
+struct MappingSeed<'a>(&'a mut MapBuilder);
+
+impl<'de, 'a> DeserializeSeed<'de> for MappingSeed<'a> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, d: D) -> std::result::Result<(), D::Error> {
+        d.deserialize_map(self)
+    }
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let i = self.i;
-        self.i += 1;
-        if i < self.max {
-            let id: Option<ChunkId> = self.map.remove(&i);
-            Some((i, id))
-        } else {
-            None
+impl<'de, 'a> Visitor<'de> for MappingSeed<'a> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of seqs to chunk IDs")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        while let Some((seq, id)) = map.next_entry::<SeqKey, ChunkId>()? {
+            self.0.add(seq, id);
         }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for MapBuilder {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        struct V;
+        impl<'de> Visitor<'de> for V {
+            type Value = MapBuilder;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a revision map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> std::result::Result<MapBuilder, A::Error> {
+                let mut b = MapBuilder::default();
+                let (mut mapping, mut size) = (false, None);
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "mapping" if mapping => return Err(de::Error::duplicate_field("mapping")),
+                        "mapping" => {
+                            map.next_value_seed(MappingSeed(&mut b))?;
+                            mapping = true;
+                        }
+                        "size" if size.is_some() => return Err(de::Error::duplicate_field("size")),
+                        "size" => size = Some(map.next_value()?),
+                        _ => {
+                            map.next_value::<de::IgnoredAny>()?;
+                        }
+                    }
+                }
+                if !mapping {
+                    return Err(de::Error::missing_field("mapping"));
+                }
+                b.size = size.ok_or_else(|| de::Error::missing_field("size"))?;
+                Ok(b)
+            }
+        }
+        d.deserialize_map(V)
     }
 }
 
@@ -76,31 +197,99 @@ pub struct ChunkVec {
     chunks: ChunkMap,
     /// Empty seqs not found in `chunks`
     zero_seqs: Vec<u32>,
+    /// Seq ranges to load first, highest priority first
+    priority: Vec<Range<u32>>,
+    /// Load chunks with many references before others
+    shared_first: bool,
+    /// Order of everything else
+    order: RestoreOrder,
+}
+
+/// Order in which chunks are loaded by restores to random access targets, see
+/// [Extractor::order](struct.Extractor.html#method.order).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestoreOrder {
+    /// Lowest offsets first
+    #[default]
+    Forward,
+    /// Highest offsets first, e.g. to get the GPT backup header in place early
+    Reverse,
+    /// Chunks nearest to the given byte offset first, expanding in both directions
+    Outward(u64),
+}
+
+impl RestoreOrder {
+    // Sort key of a chunk mapped to `seqs`, which are in ascending order
+    fn key(self, seqs: &[u32]) -> u32 {
+        match self {
+            RestoreOrder::Forward => seqs[0],
+            RestoreOrder::Reverse => u32::MAX - seqs[seqs.len() - 1],
+            RestoreOrder::Outward(pos) => {
+                let center = pos2chunk(pos);
+                seqs.iter().map(|&s| s.abs_diff(center)).min().unwrap_or(0)
+            }
+        }
+    }
+}
+
+/// Outcome of comparing two chunk maps with [ChunkVec::compare].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct MapDiff {
+    /// Seqs which refer to the same chunk in both maps, zero chunks included
+    pub same: usize,
+    /// Seqs which differ, including seqs beyond the end of the smaller image
+    pub changed: usize,
+    /// Distinct chunk IDs which both maps reference
+    pub shared: usize,
+    /// Distinct chunk IDs of the compared map
+    pub unique: usize,
+    /// Distinct chunk IDs of the other map
+    pub other_unique: usize,
 }
 
 impl ChunkVec {
     /// Parses backup spec JSON and constructs chunk map.
+    #[cfg(test)]
     pub fn decode(input: &str) -> Result<Self> {
-        let rev: RevisionMap =
-            serde_json::from_str(input).map_err(|e| ExtractError::DecodeMap(input.into(), e))?;
-        let size = rev.size;
-        if size % CHUNKSZ as u64 != 0 {
-            return Err(ExtractError::UnalignedSize(rev.size));
-        }
-        let mut chunks = BTreeMap::new();
-        let mut zero_seqs = Vec::new();
-        for (seq, id) in rev {
-            if let Some(id) = id {
-                chunks.entry(id).or_insert_with(SmallVec::new).push(seq);
-            } else {
-                zero_seqs.push(seq);
-            }
-        }
-        Ok(Self {
+        Self::from_slice(input.as_bytes())
+    }
+
+    /// Parses backup spec JSON from raw bytes, e.g. a memory-mapped map file (see
+    /// [load](#method.load)). Mapping entries go straight into the chunk map, so no intermediate
+    /// copy of the whole map is held in memory.
+    pub fn from_slice(input: &[u8]) -> Result<Self> {
+        let rev: MapBuilder = serde_json::from_slice(input).map_err(|e| {
+            let excerpt = String::from_utf8_lossy(&input[..input.len().min(64)]).into_owned();
+            ExtractError::DecodeMap(excerpt, e)
+        })?;
+        rev.finish()
+    }
+
+    /// Loads the revision map at `path`. Large maps are mapped into memory instead of being read.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let map =
+            backend::map_file(path).map_err(|e| ExtractError::LoadSpec(path.to_owned(), e))?;
+        Self::from_slice(&map)
+    }
+
+    /// Builds a chunk map from chunk IDs and their seqs, e.g. as read from a cache. The same
+    /// checks apply as for JSON input.
+    pub(crate) fn from_entries<I>(size: u64, entries: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (ChunkId, SmallVec<[u32; 4]>)>,
+    {
+        MapBuilder {
+            chunks: entries.into_iter().collect(),
             size,
-            chunks,
-            zero_seqs,
-        })
+            invalid: None,
+        }
+        .finish()
+    }
+
+    /// All chunk IDs with their seqs in ascending ID order
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (&ChunkId, &SmallVec<[u32; 4]>)> {
+        self.chunks.iter()
     }
 
     /// Number of chunks to restore
@@ -108,15 +297,117 @@ impl ChunkVec {
         pos2chunk(self.size) as usize
     }
 
-    /// Reads chunks from disk and decompresses them. The iterator `idx` controls which chunks are
-    /// to be read. Parallel instances of `read` can be fed with disjunct sequences.
-    pub fn send_decompressed(
-        &self,
-        threadid: u8,
-        nthreads: u8,
-        backend: &Backend,
-        tx: Sender<Chunk>,
-    ) -> Result<()> {
+    /// Number of distinct chunk IDs referenced by the revision
+    pub fn unique(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Distinct chunk IDs referenced by the revision in ascending order
+    pub fn ids(&self) -> impl Iterator<Item = &ChunkId> {
+        self.chunks.keys()
+    }
+
+    /// Returns the chunk ID which is mapped to `seq` or None if this is a zero chunk.
+    pub fn find(&self, seq: u32) -> Option<&ChunkId> {
+        self.chunks
+            .iter()
+            .find(|(_, seqs)| seqs.contains(&seq))
+            .map(|(id, _)| id)
+    }
+
+    /// Seqs which are mapped to chunk `id`, empty if the revision does not reference it
+    pub fn seqs_of(&self, id: &str) -> &[u32] {
+        self.chunks.get(id).map_or(&[], |seqs| &seqs[..])
+    }
+
+    /// Chunk ID for each seq, None for zero chunks
+    pub fn by_seq(&self) -> Vec<Option<ChunkId>> {
+        let mut map = vec![None; self.len()];
+        for (id, seqs) in &self.chunks {
+            for &seq in seqs {
+                map[seq as usize] = Some(id.clone());
+            }
+        }
+        map
+    }
+
+    /// All chunk IDs with their seqs, ordered by their lowest seq
+    pub fn ordered(&self) -> Vec<(&ChunkId, &SmallVec<[u32; 4]>)> {
+        self.partition(0, 1)
+    }
+
+    /// Seqs which are not mapped to any chunk in ascending order
+    pub fn zero_seqs(&self) -> &[u32] {
+        &self.zero_seqs
+    }
+
+    /// Compares the chunk map with the map of another revision, usually its parent.
+    pub fn compare(&self, other: &ChunkVec) -> MapDiff {
+        let (a, b) = (self.by_seq(), other.by_seq());
+        let same = a.iter().zip(&b).filter(|(x, y)| x == y).count();
+        MapDiff {
+            same,
+            changed: a.len().max(b.len()) - same,
+            shared: self
+                .ids()
+                .filter(|id| other.chunks.contains_key(*id))
+                .count(),
+            unique: self.unique(),
+            other_unique: other.unique(),
+        }
+    }
+
+    /// Seqs which are mapped to the same chunk in `other` or are zero chunks in both.
+    pub fn unchanged(&self, other: &ChunkVec) -> Vec<u32> {
+        let (a, b) = (self.by_seq(), other.by_seq());
+        (0..a.len().min(b.len()) as u32)
+            .filter(|&seq| a[seq as usize] == b[seq as usize])
+            .collect()
+    }
+
+    /// Turns the given seqs into zero chunks. Chunk IDs which are no longer referenced are not
+    /// loaded anymore.
+    pub fn discard(&mut self, seqs: &[u32]) {
+        let discarded: HashSet<u32> = seqs.iter().copied().collect();
+        for mapped in self.chunks.values_mut() {
+            mapped.retain(|s| !discarded.contains(s));
+        }
+        self.chunks.retain(|_, mapped| !mapped.is_empty());
+        self.zero_seqs.extend(seqs);
+        self.zero_seqs.sort_unstable();
+        self.zero_seqs.dedup();
+    }
+
+    /// Makes `send_decompressed` load chunks which are mapped into one of `ranges` before all
+    /// other chunks. Earlier ranges take precedence over later ones.
+    pub fn prioritize(&mut self, ranges: Vec<Range<u32>>) {
+        self.priority = ranges;
+    }
+
+    /// Makes `send_decompressed` load the most referenced chunk IDs first, within the bounds of
+    /// `prioritize`. Chunks which are duplicated all over the image then settle many positions
+    /// early on, and the remainder of the restore consists mostly of chunks needed only once.
+    pub fn shared_first(&mut self, enable: bool) {
+        self.shared_first = enable;
+    }
+
+    /// Makes `send_decompressed` load chunks in `order` after prioritized and shared ones.
+    pub fn order(&mut self, order: RestoreOrder) {
+        self.order = order;
+    }
+
+    // Index of the first priority range which contains any of `seqs`. Chunks outside all ranges
+    // come last.
+    fn rank(&self, seqs: &[u32]) -> usize {
+        self.priority
+            .iter()
+            .position(|r| seqs.iter().any(|s| r.contains(s)))
+            .unwrap_or(self.priority.len())
+    }
+
+    // Chunk IDs assigned to thread `threadid`, prioritized ones first, then most referenced ones if
+    // requested, then in restore order with lowest seq_ids breaking ties.
+    fn partition(&self, threadid: u8, nthreads: u8) -> Vec<(&ChunkId, &SmallVec<[u32; 4]>)> {
         assert!(nthreads > 0 && threadid < nthreads);
         let mut ids: Vec<(&ChunkId, &SmallVec<[u32; 4]>)> = self
             .chunks
@@ -124,17 +415,140 @@ impl ChunkVec {
             .skip(threadid as usize)
             .step_by(nthreads as usize)
             .collect();
-        // lowest seq_ids first
-        ids.sort_unstable_by_key(|e| e.1[0]);
-        for (id, seqs) in ids {
-            let decompressed = backend.load(id).map_err(|e| ExtractError::InvalidChunk {
-                seq: seqs[0],
-                id: id.to_string(),
-                source: e,
-            })?;
-            tx.send(Chunk {
-                data: Data::Some(decompressed),
+        let refs = |seqs: &[u32]| Reverse(if self.shared_first { seqs.len() } else { 0 });
+        ids.sort_unstable_by_key(|e| (self.rank(e.1), refs(e.1), self.order.key(e.1), e.1[0]));
+        ids
+    }
+
+    /// Loads and hashes all chunks assigned to thread `threadid` and returns those that fail.
+    /// Parallel instances work on disjunct subsets just like `send_decompressed`. Chunks which
+    /// `cache` lists as verified are skipped and counted, verified chunks are added to it.
+    pub fn scan(
+        &self,
+        threadid: u8,
+        nthreads: u8,
+        backend: &Backend,
+        cache: Option<&HashCache>,
+    ) -> (usize, Vec<DamagedChunk>) {
+        let mut cached = 0;
+        let mut damaged = Vec::new();
+        for (id, seqs) in self.partition(threadid, nthreads) {
+            // taken before loading: if the file changes in between, it is checked next time
+            let stat = cache.and_then(|_| fs::metadata(backend.filename(id)).ok());
+            if let (Some(c), Some(m)) = (cache, &stat) {
+                if c.is_verified(id, m) {
+                    cached += 1;
+                    continue;
+                }
+            }
+            let res = backend.load(id).and_then(|data| {
+                let actual = backend.hash(&data);
+                if actual == id.as_str() {
+                    Ok(())
+                } else {
+                    Err(backend::Error::Checksum(actual))
+                }
+            });
+            match res {
+                Ok(()) => {
+                    if let (Some(c), Some(m)) = (cache, &stat) {
+                        c.insert(id, m);
+                    }
+                }
+                Err(e) => {
+                    if let Some(c) = cache {
+                        c.remove(id);
+                    }
+                    damaged.push(DamagedChunk::new(id, seqs, &e));
+                }
+            }
+        }
+        (cached, damaged)
+    }
+
+    /// Checks the files of all chunks assigned to thread `threadid` with
+    /// [Backend::check_file](../backend/struct.Backend.html#method.check_file). Stops at
+    /// `deadline` and returns the number of chunks checked so far along with those that fail.
+    pub fn check_files(
+        &self,
+        threadid: u8,
+        nthreads: u8,
+        backend: &Backend,
+        deadline: Option<Instant>,
+    ) -> (usize, Vec<DamagedChunk>) {
+        let mut checked = 0;
+        let mut damaged = Vec::new();
+        for (id, seqs) in self.partition(threadid, nthreads) {
+            if matches!(deadline, Some(d) if Instant::now() >= d) {
+                break;
+            }
+            if let Err(e) = backend.check_file(id) {
+                damaged.push(DamagedChunk::new(id, seqs, &e));
+            }
+            checked += 1;
+        }
+        (checked, damaged)
+    }
+
+    /// Reads chunks from disk and decompresses them. `threadid` and `nthreads` control which
+    /// chunks are to be read. Parallel instances can be fed with disjunct sequences. `throttle`
+    /// is called with the lowest seq of each chunk before it is loaded and may delay loading.
+    /// Whatever it returns is kept until the chunk has been decompressed. Each decompressed chunk
+    /// is handed to `emit` together with its ID.
+    pub fn send_decompressed<T, G, F>(
+        &self,
+        threadid: u8,
+        nthreads: u8,
+        backend: &Backend,
+        throttle: T,
+        mut emit: F,
+    ) -> Result<()>
+    where
+        T: Fn(u32) -> G,
+        F: FnMut(&ChunkId, Chunk) -> Result<()>,
+    {
+        for (id, seqs) in self.partition(threadid, nthreads) {
+            let guard = throttle(seqs[0]);
+            let decompressed = backend
+                .load(id)
+                .map_err(|e| ExtractError::invalid_chunk(backend, seqs[0], id, e))?;
+            drop(guard);
+            emit(
+                id,
+                Chunk {
+                    data: Data::Some(decompressed),
+                    seqs: seqs.clone(),
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Reads chunk files without decompressing them, for a separate reader stage. Chunks are
+    /// distributed and throttled like in `send_decompressed`, but `throttle`'s guard is released
+    /// as soon as the file has been read. Each chunk file is handed to `emit`.
+    pub fn send_compressed<T, G, F>(
+        &self,
+        threadid: u8,
+        nthreads: u8,
+        backend: &Backend,
+        throttle: T,
+        mut emit: F,
+    ) -> Result<()>
+    where
+        T: Fn(u32) -> G,
+        F: FnMut(Compressed) -> Result<()>,
+    {
+        for (id, seqs) in self.partition(threadid, nthreads) {
+            let guard = throttle(seqs[0]);
+            let raw = backend
+                .read(id)
+                .map_err(|e| ExtractError::invalid_chunk(backend, seqs[0], id, e))?;
+            drop(guard);
+            emit(Compressed {
+                id: id.clone(),
                 seqs: seqs.clone(),
+                raw,
             })?;
         }
         Ok(())
@@ -151,4 +565,294 @@ impl ChunkVec {
     }
 }
 
-// XXX unit tests
+/// Chunk file contents as read by `ChunkVec::send_compressed`.
+#[derive(Debug)]
+pub struct Compressed {
+    pub id: ChunkId,
+    pub seqs: SmallVec<[u32; 4]>,
+    pub raw: RawChunk,
+}
+
+/// Decompression stage behind `ChunkVec::send_compressed`. Several instances may share the same
+/// channel. `permit` is called before each chunk is decompressed and whatever it returns is kept
+/// until decompression has finished. Each decompressed chunk is handed to `emit` together with
+/// its ID.
+pub fn decompress<P, G, F>(
+    rx: Receiver<Compressed>,
+    backend: &Backend,
+    permit: P,
+    mut emit: F,
+) -> Result<()>
+where
+    P: Fn() -> G,
+    F: FnMut(&ChunkId, Chunk) -> Result<()>,
+{
+    for c in rx {
+        let guard = permit();
+        let data = backend
+            .decode_chunk(&c.raw)
+            .map_err(|e| ExtractError::invalid_chunk(backend, c.seqs[0], &c.id, e))?;
+        drop(guard);
+        emit(
+            &c.id,
+            Chunk {
+                data: Data::Some(data),
+                seqs: c.seqs,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Picks chunks for sampled verification. A distinct chunk is picked with a probability of
+/// `percent`%, derived from a hash of `seed` (the revision ID) and the chunk ID so that repeated
+/// runs against the same revision check the same chunks. Chunks which are mapped to several
+/// positions are always picked since damage there affects more of the image.
+pub fn sampled(seed: &str, id: &ChunkId, seqs: &[u32], percent: f64) -> bool {
+    if seqs.len() > 1 || percent >= 100.0 {
+        return true;
+    }
+    let key = [seed.as_bytes(), id.as_bytes()].concat();
+    let h = murmur3_x64_128(&mut io::Cursor::new(key), 0).expect("in-memory read");
+    ((h >> 64) as u64 as f64 / u64::MAX as f64) * 100.0 < percent
+}
+
+/// Verification stage: checks that decompressed chunks hash to their IDs and passes them on to
+/// `emit`.
+/// Several instances may share the same channels. `backend` is only used for error reports.
+pub fn verify<F>(rx: Receiver<(ChunkId, Chunk)>, backend: &Backend, mut emit: F) -> Result<()>
+where
+    F: FnMut(Chunk) -> Result<()>,
+{
+    for (id, chunk) in rx {
+        if let Data::Some(ref data) = chunk.data {
+            let actual = backend.hash(data);
+            if actual != id.as_str() {
+                return Err(ExtractError::checksum(backend, chunk.seqs[0], &id, actual));
+            }
+        }
+        emit(chunk)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::store_tar;
+    use crossbeam::channel::unbounded;
+    use smallvec::smallvec;
+
+    #[test]
+    fn reject_invalid_maps() {
+        for json in &[
+            r#"{"mapping": {"x": "4db6e194fd398e8edb76e11054d73eb0"}, "size": 4194304}"#,
+            r#"{"mapping": {"1": "4db6e194fd398e8edb76e11054d73eb0"}, "size": 4194304}"#,
+            r#"{"mapping": {"0": "4"}, "size": 4194304}"#,
+            r#"{"mapping": {"0": "../../etc/passwd"}, "size": 4194304}"#,
+            r#"{"mapping": {"0": "a0", "0": "a1"}, "size": 4194304}"#,
+        ] {
+            match ChunkVec::decode(json) {
+                Err(ExtractError::InvalidMap(_)) => (),
+                other => panic!("unexpected result for {}: {:?}", json, other),
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_parser() {
+        let json = r#"{"size": 50331648, "backend_type": "chunked",
+                       "mapping": {"5": "a1", "10": "a0", "1": "a1", "3": "a0"}}"#;
+        let cv = ChunkVec::decode(json).unwrap();
+        assert_eq!(cv.len(), 12);
+        assert_eq!(cv.seqs_of("a0"), &[3, 10]);
+        assert_eq!(cv.seqs_of("a1"), &[1, 5]);
+        assert_eq!(cv.zero_seqs(), &[0, 2, 4, 6, 7, 8, 9, 11]);
+
+        let td = tempdir::TempDir::new("chunkvec").unwrap();
+        let path = td.path().join("map");
+        std::fs::write(&path, json).unwrap();
+        assert_eq!(ChunkVec::load(&path).unwrap().by_seq(), cv.by_seq());
+        std::fs::write(&path, "").unwrap();
+        assert!(matches!(
+            ChunkVec::load(&path),
+            Err(ExtractError::DecodeMap(..))
+        ));
+        assert!(matches!(
+            ChunkVec::decode(r#"{"mapping": {}}"#),
+            Err(ExtractError::DecodeMap(..))
+        ));
+    }
+
+    #[test]
+    fn compare_maps() {
+        let parent =
+            ChunkVec::decode(r#"{"mapping": {"0": "a0", "1": "a1", "3": "a1"}, "size": 16777216}"#)
+                .unwrap();
+        let child = ChunkVec::decode(
+            r#"{"mapping": {"0": "a0", "1": "b1", "3": "a1", "4": "b4"}, "size": 25165824}"#,
+        )
+        .unwrap();
+        let d = child.compare(&parent);
+        assert_eq!(
+            d,
+            MapDiff {
+                same: 3,
+                changed: 3,
+                shared: 2,
+                unique: 4,
+                other_unique: 2
+            }
+        );
+        assert_eq!(parent.compare(&child).shared, 2);
+        assert_eq!(parent.compare(&parent).changed, 0);
+        assert_eq!(child.unchanged(&parent), &[0, 2, 3]);
+    }
+
+    #[test]
+    fn prioritized_chunks_come_first() {
+        let mut cv = ChunkVec::decode(
+            r#"{"mapping": {"0": "a0", "1": "a1", "2": "a2", "3": "a3", "5": "a1"},
+                "size": 25165824}"#,
+        )
+        .unwrap();
+        cv.prioritize(vec![3..4, 5..6]);
+        let order: Vec<&str> = cv
+            .ordered()
+            .into_iter()
+            .map(|(id, _)| id.as_str())
+            .collect();
+        assert_eq!(order, &["a3", "a1", "a0", "a2"]);
+    }
+
+    #[test]
+    fn shared_chunks_come_first() {
+        let mut cv = ChunkVec::decode(
+            r#"{"mapping": {"0": "a0", "1": "a1", "2": "a2", "3": "a2", "4": "a1", "5": "a1",
+                            "6": "a3"}, "size": 29360128}"#,
+        )
+        .unwrap();
+        cv.shared_first(true);
+        let order = |cv: &ChunkVec| -> Vec<String> {
+            cv.ordered()
+                .into_iter()
+                .map(|(id, _)| id.to_string())
+                .collect()
+        };
+        assert_eq!(order(&cv), &["a1", "a2", "a0", "a3"]);
+        // explicit priorities still take precedence
+        cv.prioritize(vec![6..7, 0..1]);
+        assert_eq!(order(&cv), &["a3", "a0", "a1", "a2"]);
+    }
+
+    #[test]
+    fn restore_order() {
+        let mut cv = ChunkVec::decode(
+            r#"{"mapping": {"0": "a0", "1": "a1", "2": "a2", "3": "a3", "5": "a1"},
+                "size": 25165824}"#,
+        )
+        .unwrap();
+        let order = |cv: &ChunkVec| -> Vec<String> {
+            cv.ordered()
+                .into_iter()
+                .map(|(id, _)| id.to_string())
+                .collect()
+        };
+        cv.order(RestoreOrder::Reverse);
+        assert_eq!(order(&cv), &["a1", "a3", "a2", "a0"]);
+        // offset 9 MiB lies in seq 2
+        cv.order(RestoreOrder::Outward(9 << 20));
+        assert_eq!(order(&cv), &["a2", "a1", "a3", "a0"]);
+        cv.prioritize(vec![0..1, 3..4]);
+        assert_eq!(order(&cv), &["a0", "a3", "a2", "a1"]);
+    }
+
+    #[test]
+    fn discarded_chunks_become_zero() {
+        let mut cv = ChunkVec::decode(
+            r#"{"mapping": {"0": "a0", "1": "a1", "2": "a2", "5": "a1"}, "size": 25165824}"#,
+        )
+        .unwrap();
+        cv.discard(&[1, 2, 4]);
+        assert_eq!(cv.zero_seqs(), &[1, 2, 3, 4]);
+        assert_eq!(
+            cv.ids().map(|id| id.as_str()).collect::<Vec<_>>(),
+            &["a0", "a1"]
+        );
+        assert_eq!(cv.find(5).map(|id| id.as_str()), Some("a1"));
+    }
+
+    #[test]
+    fn verify_passes_matching_chunks() {
+        let data = vec![1u8; 4096];
+        let (in_tx, in_rx) = unbounded();
+        let (out_tx, out_rx) = unbounded();
+        in_tx
+            .send((
+                ChunkId::from(backend::hash(&data)),
+                Chunk {
+                    data: Data::Some(data),
+                    seqs: smallvec![0],
+                },
+            ))
+            .unwrap();
+        drop(in_tx);
+        let store = store_tar();
+        verify(in_rx, &Backend::open(store.path()).unwrap(), |c| {
+            Ok(out_tx.send(c)?)
+        })
+        .unwrap();
+        drop(out_tx);
+        assert_eq!(out_rx.iter().count(), 1);
+    }
+
+    #[test]
+    fn verify_detects_mismatch() {
+        let (in_tx, in_rx) = unbounded();
+        let (out_tx, _out_rx) = unbounded();
+        in_tx
+            .send((
+                ChunkId::from("00000000000000000000000000000000"),
+                Chunk {
+                    data: Data::Some(vec![1u8; 4096]),
+                    seqs: smallvec![7],
+                },
+            ))
+            .unwrap();
+        drop(in_tx);
+        let store = store_tar();
+        let err = verify(in_rx, &Backend::open(store.path()).unwrap(), |c| {
+            Ok(out_tx.send(c)?)
+        })
+        .unwrap_err();
+        match &err {
+            ExtractError::Checksum { seq, file, .. } => {
+                assert_eq!(*seq, 7);
+                assert!(file
+                    .path
+                    .ends_with("chunks/00/00000000000000000000000000000000.chunk.lzo"));
+                assert_eq!(file.compressed, None);
+            }
+            other => panic!("unexpected: {:?}", other),
+        }
+        assert_eq!(err.failed_check(), Some(crate::Check::Hash));
+        assert!(err.to_string().contains("at offset 29360128"));
+    }
+
+    #[test]
+    fn sample_is_deterministic() {
+        let ids: Vec<ChunkId> = (0..1000).map(|i| format!("{:032x}", i).into()).collect();
+        let picked = |seed, percent| {
+            ids.iter()
+                .filter(|id| sampled(seed, id, &[0], percent))
+                .collect::<Vec<_>>()
+        };
+        let sample = picked("rev1", 10.0);
+        assert!((50..150).contains(&sample.len()), "{}", sample.len());
+        assert_eq!(sample, picked("rev1", 10.0));
+        assert_ne!(sample, picked("rev2", 10.0));
+        assert!(picked("rev1", 0.0).is_empty());
+        assert_eq!(picked("rev1", 100.0).len(), ids.len());
+        assert!(sampled("rev1", &ids[0], &[0, 5], 0.0));
+    }
+}
@@ -0,0 +1,241 @@
+//! Compression statistics for revisions.
+//!
+//! Chunks always decompress to exactly `CHUNKSZ` bytes, so the sizes of the chunk files tell how
+//! well each part of an image compresses. Nothing is read or decompressed to build a report.
+
+use crate::backend::Backend;
+use crate::chunkvec::ChunkVec;
+use crate::{chunk2pos, CHUNKSZ};
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+
+// Number of heatmap buckets if no bucket size is given
+const DEFAULT_BUCKETS: u64 = 64;
+
+/// Compressed size of a single chunk in the image.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChunkRatio {
+    pub seq: u32,
+    pub offset: u64,
+    /// None for zero chunks, which are not stored
+    pub id: Option<String>,
+    /// Size of the chunk file, 0 for zero chunks and None if the file is missing
+    pub compressed: Option<u64>,
+}
+
+/// Image region covering one or more chunks.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeatmapBucket {
+    pub offset: u64,
+    pub length: u64,
+    pub zero_chunks: u32,
+    pub missing_chunks: u32,
+    /// Total size of the chunk files which make up the region
+    pub compressed: u64,
+    /// Compressed to uncompressed size of the stored chunks, 0 if there are none
+    pub ratio: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CompressionReport {
+    /// Image size in bytes
+    pub size: u64,
+    /// Number of distinct chunks
+    pub unique: usize,
+    /// Number of distinct chunks whose files are missing
+    pub missing: usize,
+    /// Total size of all distinct chunk files
+    pub stored: u64,
+    pub chunks: Vec<ChunkRatio>,
+    pub heatmap: Vec<HeatmapBucket>,
+}
+
+impl CompressionReport {
+    /// Looks up the chunk file sizes of `chunks` and aggregates them into regions of `bucket`
+    /// bytes, which is rounded up to whole chunks.
+    pub(crate) fn new(chunks: &ChunkVec, be: &Backend, bucket: Option<u64>) -> Self {
+        let size = chunks.size;
+        let sizes: HashMap<&str, Option<u64>> = chunks
+            .ids()
+            .map(|id| {
+                let len = fs::metadata(be.filename(id)).ok().map(|m| m.len());
+                (id.as_str(), len)
+            })
+            .collect();
+        let chunks: Vec<ChunkRatio> = chunks
+            .by_seq()
+            .into_iter()
+            .enumerate()
+            .map(|(seq, id)| ChunkRatio {
+                seq: seq as u32,
+                offset: chunk2pos(seq as u32),
+                compressed: match &id {
+                    Some(id) => sizes[id.as_str()],
+                    None => Some(0),
+                },
+                id: id.map(|id| id.to_string()),
+            })
+            .collect();
+        let chunksz = CHUNKSZ as u64;
+        let bucket = bucket.unwrap_or(size / DEFAULT_BUCKETS).max(1);
+        let per_bucket = usize::try_from(bucket.div_ceil(chunksz)).unwrap_or(usize::MAX);
+        let heatmap = chunks
+            .chunks(per_bucket)
+            .map(|region| {
+                let zero_chunks = region.iter().filter(|c| c.id.is_none()).count();
+                let missing_chunks = region.iter().filter(|c| c.compressed.is_none()).count();
+                let compressed: u64 = region.iter().filter_map(|c| c.compressed).sum();
+                let stored = (region.len() - zero_chunks - missing_chunks) as u64;
+                HeatmapBucket {
+                    offset: region[0].offset,
+                    length: region.len() as u64 * chunksz,
+                    zero_chunks: zero_chunks as u32,
+                    missing_chunks: missing_chunks as u32,
+                    compressed,
+                    ratio: if stored > 0 {
+                        compressed as f64 / (stored * chunksz) as f64
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect();
+        Self {
+            size,
+            unique: sizes.len(),
+            missing: sizes.values().filter(|s| s.is_none()).count(),
+            stored: sizes.values().filter_map(|&s| s).sum(),
+            chunks,
+            heatmap,
+        }
+    }
+
+    /// Compressed to uncompressed size of all distinct chunks which are present.
+    pub fn ratio(&self) -> f64 {
+        match (self.unique - self.missing) as u64 {
+            0 => 0.0,
+            present => self.stored as f64 / (present * CHUNKSZ as u64) as f64,
+        }
+    }
+
+    /// Writes the heatmap as CSV with a header line.
+    pub fn write_csv<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(
+            out,
+            "offset,length,zero_chunks,missing_chunks,compressed,ratio"
+        )?;
+        for b in &self.heatmap {
+            writeln!(
+                out,
+                "{},{},{},{},{},{:.4}",
+                b.offset, b.length, b.zero_chunks, b.missing_chunks, b.compressed, b.ratio
+            )?;
+        }
+        out.flush()
+    }
+}
+
+impl fmt::Display for CompressionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} chunks ({} distinct) stored in {} bytes, ratio {:.1}%",
+            self.chunks.len(),
+            self.unique,
+            self.stored,
+            self.ratio() * 100.0
+        )?;
+        for b in &self.heatmap {
+            let bar = if b.ratio > 0.0 {
+                "#".repeat((b.ratio * 40.0).ceil().min(40.0) as usize)
+            } else {
+                String::new()
+            };
+            let line = format!(
+                "{:>14} +{:<12} {:>6.1}% {:<40}{}",
+                b.offset,
+                b.length,
+                b.ratio * 100.0,
+                bar,
+                if b.missing_chunks > 0 {
+                    format!(" {} missing", b.missing_chunks)
+                } else {
+                    String::new()
+                }
+            );
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::store_tar;
+
+    fn report(bucket: Option<u64>) -> CompressionReport {
+        let store = store_tar();
+        let chunks = ChunkVec::decode(
+            r#"{"mapping": {"0": "4db6e194fd398e8edb76e11054d73eb0",
+                            "1": "c72b4ba82d1f51b71c8a18195ad33fc8",
+                            "3": "c72b4ba82d1f51b71c8a18195ad33fc8",
+                            "4": "ffff0000000000000000000000000000"}, "size": 25165824}"#,
+        )
+        .unwrap();
+        CompressionReport::new(&chunks, &Backend::open(store.path()).unwrap(), bucket)
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        let r = report(Some(8 << 20));
+        assert_eq!(r.size, 6 * CHUNKSZ as u64);
+        assert_eq!((r.unique, r.missing), (3, 1));
+        assert_eq!(r.stored, 46205 + 18628);
+        let sizes: Vec<Option<u64>> = r.chunks.iter().map(|c| c.compressed).collect();
+        assert_eq!(
+            sizes,
+            &[
+                Some(46205),
+                Some(18628),
+                Some(0),
+                Some(18628),
+                None,
+                Some(0)
+            ]
+        );
+        assert_eq!(r.chunks[2].id, None);
+    }
+
+    #[test]
+    fn heatmap_buckets() {
+        let r = report(Some(8 << 20));
+        assert_eq!(r.heatmap.len(), 3);
+        let b = &r.heatmap[1];
+        assert_eq!((b.offset, b.length), (8 << 20, 8 << 20));
+        assert_eq!(
+            (b.zero_chunks, b.missing_chunks, b.compressed),
+            (1, 0, 18628)
+        );
+        assert!((b.ratio - 18628.0 / CHUNKSZ as f64).abs() < 1e-9);
+        assert_eq!(r.heatmap[2].missing_chunks, 1);
+        // bucket sizes are rounded up to whole chunks
+        assert_eq!(report(Some(1)).heatmap.len(), 6);
+        assert_eq!(report(Some(u64::MAX)).heatmap.len(), 1);
+    }
+
+    #[test]
+    fn csv_output() {
+        let mut out = Vec::new();
+        report(Some(12 << 20)).write_csv(&mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], "0,12582912,1,0,64833,0.0077");
+    }
+}
@@ -0,0 +1,249 @@
+//! CPU usage accounting for restore pipeline stages.
+//!
+//! Each stage thread measures its own user and system time with getrusage(2). Comparing a
+//! stage's CPU time with the wall clock time of the restore tells whether it was busy computing
+//! or waiting for I/O. Per-thread figures are available on Linux only; elsewhere, each stage
+//! reports the CPU time of the whole process instead.
+
+use serde::Serialize;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// CPU time used by the threads of one pipeline stage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CpuTime {
+    pub user: Duration,
+    pub sys: Duration,
+    /// Number of threads measured
+    pub threads: u32,
+}
+
+impl CpuTime {
+    pub fn total(&self) -> Duration {
+        self.user + self.sys
+    }
+
+    /// Average share of `elapsed` each thread has spent on the CPU, from 0 to 1. Values close to
+    /// 1 mean that the stage is CPU-bound.
+    pub fn utilization(&self, elapsed: Duration) -> f64 {
+        let avail = elapsed.as_secs_f64() * f64::from(self.threads);
+        if avail > 0.0 {
+            (self.total().as_secs_f64() / avail).min(1.0)
+        } else {
+            0.0
+        }
+    }
+
+    fn since(self, earlier: Self) -> Self {
+        Self {
+            user: self.user.saturating_sub(earlier.user),
+            sys: self.sys.saturating_sub(earlier.sys),
+            threads: 1,
+        }
+    }
+
+    fn add(&mut self, other: Self) {
+        self.user += other.user;
+        self.sys += other.sys;
+        self.threads += other.threads;
+    }
+}
+
+fn rusage(who: libc::c_int) -> CpuTime {
+    let mut ru = MaybeUninit::<libc::rusage>::zeroed();
+    if unsafe { libc::getrusage(who, ru.as_mut_ptr()) } < 0 {
+        return CpuTime::default();
+    }
+    let ru = unsafe { ru.assume_init() };
+    let dur = |tv: libc::timeval| {
+        Duration::new(tv.tv_sec as u64, 0) + Duration::from_micros(tv.tv_usec as u64)
+    };
+    CpuTime {
+        user: dur(ru.ru_utime),
+        sys: dur(ru.ru_stime),
+        threads: 0,
+    }
+}
+
+/// CPU time used by the calling thread so far.
+#[cfg(target_os = "linux")]
+pub(crate) fn thread() -> CpuTime {
+    rusage(libc::RUSAGE_THREAD)
+}
+
+/// CPU time used by the whole process so far, for lack of per-thread accounting.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn thread() -> CpuTime {
+    rusage(libc::RUSAGE_SELF)
+}
+
+/// CPU time used by all threads of the process so far.
+pub(crate) fn process() -> CpuTime {
+    rusage(libc::RUSAGE_SELF)
+}
+
+/// CPU time per pipeline stage of a completed restore.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CpuReport {
+    /// Loading the chunk map and preparing the target, before any chunk is read
+    pub load: CpuTime,
+    /// Separate reader threads, see [Concurrency](struct.Concurrency.html)
+    pub read: CpuTime,
+    /// Decompression threads, including reading if there are no separate readers
+    pub decompress: CpuTime,
+    pub verify: CpuTime,
+    /// Main writer thread. Additional writer threads are only counted in `total`.
+    pub write: CpuTime,
+    /// All threads of the process during the restore, including progress display. This
+    /// includes other restores which run concurrently in the same process.
+    pub total: CpuTime,
+}
+
+impl fmt::Display for CpuReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stages = [
+            ("load", &self.load),
+            ("read", &self.read),
+            ("decompress", &self.decompress),
+            ("verify", &self.verify),
+            ("write", &self.write),
+        ];
+        let mut sep = "";
+        for (name, t) in stages.iter().filter(|(_, t)| t.threads > 0) {
+            write!(
+                f,
+                "{}{} {:.1}s/{:.1}s",
+                sep,
+                name,
+                t.user.as_secs_f64(),
+                t.sys.as_secs_f64()
+            )?;
+            sep = ", ";
+        }
+        write!(
+            f,
+            "{}total {:.1}s/{:.1}s (user/sys)",
+            sep,
+            self.total.user.as_secs_f64(),
+            self.total.sys.as_secs_f64()
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Stage {
+    Load,
+    Read,
+    Decompress,
+    Verify,
+    Write,
+}
+
+/// Collects CPU times from stage threads while a restore is running.
+#[derive(Debug)]
+pub(crate) struct Account {
+    start: CpuTime,
+    report: Mutex<CpuReport>,
+}
+
+impl Account {
+    /// Starts accounting at the current process CPU time.
+    pub(crate) fn new() -> Self {
+        Self {
+            start: process(),
+            report: Mutex::new(CpuReport::default()),
+        }
+    }
+
+    /// Adds CPU time spent by the calling thread since `since` to `stage`.
+    pub(crate) fn add(&self, stage: Stage, since: CpuTime) {
+        let used = thread().since(since);
+        let mut r = self.report.lock().expect("poisoned lock");
+        match stage {
+            Stage::Load => r.load.add(used),
+            Stage::Read => r.read.add(used),
+            Stage::Decompress => r.decompress.add(used),
+            Stage::Verify => r.verify.add(used),
+            Stage::Write => r.write.add(used),
+        }
+    }
+
+    /// Runs `f` and accounts the CPU time of the calling thread to `stage`.
+    pub(crate) fn measure<T>(&self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let before = thread();
+        let res = f();
+        self.add(stage, before);
+        res
+    }
+
+    pub(crate) fn report(self) -> CpuReport {
+        let mut report = self.report.into_inner().expect("poisoned lock");
+        report.total = process().since(self.start);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Burns `d` of CPU time in the calling thread
+    fn spin(d: Duration) -> u64 {
+        let start = thread();
+        let mut n = 0u64;
+        while thread().since(start).total() < d {
+            n = n.wrapping_add(1);
+        }
+        n
+    }
+
+    #[test]
+    fn measure_stage_threads() {
+        let acc = Account::new();
+        crossbeam::thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|_| acc.measure(Stage::Decompress, || spin(Duration::from_millis(50))));
+            }
+        })
+        .unwrap();
+        acc.measure(Stage::Write, || ());
+        let r = acc.report();
+        assert_eq!((r.decompress.threads, r.write.threads), (2, 1));
+        assert!(r.decompress.total() >= Duration::from_millis(100));
+        assert!(r.total.total() >= r.decompress.total());
+        assert_eq!(r.read, CpuTime::default());
+    }
+
+    #[test]
+    fn utilization() {
+        let t = CpuTime {
+            user: Duration::from_millis(1500),
+            sys: Duration::from_millis(500),
+            threads: 4,
+        };
+        assert!((t.utilization(Duration::from_secs(1)) - 0.5).abs() < 1e-9);
+        assert!((t.utilization(Duration::from_millis(100)) - 1.0).abs() < 1e-9);
+        assert_eq!(CpuTime::default().utilization(Duration::from_secs(1)), 0.0);
+    }
+
+    #[test]
+    fn display_active_stages() {
+        let t = |ms| CpuTime {
+            user: Duration::from_millis(ms),
+            sys: Duration::from_millis(100),
+            threads: 1,
+        };
+        let r = CpuReport {
+            decompress: t(2500),
+            write: t(200),
+            total: t(2800),
+            ..CpuReport::default()
+        };
+        assert_eq!(
+            r.to_string(),
+            "decompress 2.5s/0.1s, write 0.2s/0.1s, total 2.8s/0.1s (user/sys)"
+        );
+    }
+}
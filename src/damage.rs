@@ -0,0 +1,254 @@
+//! Damage reports for revisions with corrupt or missing chunks.
+//!
+//! A report lists every chunk that fails to load together with the image extents it covers and
+//! the partitions these extents fall into.
+
+use crate::backend::{self, Fault};
+use crate::partition::PartitionTable;
+use crate::{chunk2pos, error_chain, CHUNKSZ};
+
+use serde::Serialize;
+use std::fmt;
+
+/// Chunk which could not be loaded from the store, i.e. an entry in the corruption list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DamagedChunk {
+    pub id: String,
+    pub seqs: Vec<u32>,
+    /// Error message including all causes
+    pub error: String,
+    pub fault: Option<Fault>,
+}
+
+impl DamagedChunk {
+    pub(crate) fn new(id: &str, seqs: &[u32], err: &backend::Error) -> Self {
+        Self {
+            id: id.to_owned(),
+            seqs: seqs.to_vec(),
+            error: error_chain(err),
+            fault: err.fault(),
+        }
+    }
+}
+
+/// Outcome of a quick check of the chunk files referenced by a revision.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Precheck {
+    /// Number of distinct chunks
+    pub total: usize,
+    /// Number of distinct chunks checked before running out of time
+    pub checked: usize,
+    pub chunks: Vec<DamagedChunk>,
+}
+
+impl Precheck {
+    /// Returns true if all chunks have been checked in time.
+    pub fn is_complete(&self) -> bool {
+        self.checked == self.total
+    }
+
+    /// Returns true if no chunk file failed the check.
+    pub fn is_clean(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+/// Contiguous image region affected by damage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DamagedExtent {
+    pub offset: u64,
+    pub length: u64,
+    pub chunk: String,
+    /// Numbers of the partitions which overlap this extent
+    pub partitions: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DamageReport {
+    /// Image size in bytes
+    pub size: u64,
+    /// Number of distinct chunks checked
+    pub checked: usize,
+    /// Checked chunks which have been skipped since the hash cache lists them as verified
+    pub cached: usize,
+    pub partition_table: PartitionTable,
+    /// True if the partition table could not be read because the first chunk is damaged
+    pub table_unreadable: bool,
+    pub chunks: Vec<DamagedChunk>,
+    pub extents: Vec<DamagedExtent>,
+}
+
+impl DamageReport {
+    pub(crate) fn new(
+        size: u64,
+        checked: usize,
+        partition_table: Option<PartitionTable>,
+        mut chunks: Vec<DamagedChunk>,
+    ) -> Self {
+        chunks.sort_unstable_by_key(|c| c.seqs[0]);
+        let table_unreadable = partition_table.is_none();
+        let partition_table = partition_table.unwrap_or_default();
+        let mut extents: Vec<DamagedExtent> = chunks
+            .iter()
+            .flat_map(|c| c.seqs.iter().map(move |&seq| (seq, &c.id)))
+            .map(|(seq, id)| {
+                let offset = chunk2pos(seq);
+                let length = CHUNKSZ as u64;
+                DamagedExtent {
+                    offset,
+                    length,
+                    chunk: id.clone(),
+                    partitions: partition_table
+                        .partitions
+                        .iter()
+                        .filter(|p| p.overlaps(offset, offset + length))
+                        .map(|p| p.number)
+                        .collect(),
+                }
+            })
+            .collect();
+        extents.sort_unstable_by_key(|e| e.offset);
+        Self {
+            size,
+            checked,
+            cached: 0,
+            partition_table,
+            table_unreadable,
+            chunks,
+            extents,
+        }
+    }
+
+    /// Returns true if no damage has been found.
+    pub fn is_clean(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Total number of damaged bytes in the image.
+    pub fn damaged_bytes(&self) -> u64 {
+        self.extents.iter().map(|e| e.length).sum()
+    }
+
+    /// Damaged bytes per partition number. Bytes outside any partition are not counted.
+    pub fn damaged_partitions(&self) -> Vec<(u32, u64)> {
+        self.partition_table
+            .partitions
+            .iter()
+            .filter_map(|p| {
+                let bytes: u64 = self
+                    .extents
+                    .iter()
+                    .filter(|e| p.overlaps(e.offset, e.offset + e.length))
+                    .map(|e| (e.offset + e.length).min(p.end) - e.offset.max(p.start))
+                    .sum();
+                if bytes > 0 {
+                    Some((p.number, bytes))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for DamageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Checked {} chunks, {} damaged ({} bytes of {})",
+            self.checked,
+            self.chunks.len(),
+            self.damaged_bytes(),
+            self.size
+        )?;
+        if self.cached > 0 {
+            writeln!(
+                f,
+                "Skipped {} unchanged chunks verified before",
+                self.cached
+            )?;
+        }
+        if self.table_unreadable {
+            writeln!(f, "Partition table: unreadable (first chunk is damaged)")?;
+        } else if let Some(t) = self.partition_table.table {
+            writeln!(f, "Partition table: {}", t)?;
+            for p in &self.partition_table.partitions {
+                writeln!(
+                    f,
+                    "  #{:<3} {:>14} - {:<14} {} {}",
+                    p.number, p.start, p.end, p.kind, p.name
+                )?;
+            }
+        } else {
+            writeln!(f, "Partition table: none")?;
+        }
+        for (num, bytes) in self.damaged_partitions() {
+            writeln!(f, "Partition #{}: {} bytes damaged", num, bytes)?;
+        }
+        for e in &self.extents {
+            writeln!(
+                f,
+                "{:>14} +{} chunk {} partitions {:?}",
+                e.offset, e.length, e.chunk, e.partitions
+            )?;
+        }
+        for c in &self.chunks {
+            writeln!(f, "{}: {}", c.id, c.error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::{Partition, TableType};
+    use std::io;
+
+    fn table() -> PartitionTable {
+        PartitionTable {
+            table: Some(TableType::Mbr),
+            partitions: vec![
+                Partition {
+                    number: 1,
+                    start: 1 << 20,
+                    end: 6 << 20,
+                    kind: "83".to_owned(),
+                    name: String::new(),
+                },
+                Partition {
+                    number: 2,
+                    start: 6 << 20,
+                    end: 16 << 20,
+                    kind: "83".to_owned(),
+                    name: String::new(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn map_damage_to_partitions() {
+        let err = backend::Error::from(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        let r = DamageReport::new(
+            16 << 20,
+            3,
+            Some(table()),
+            vec![
+                DamagedChunk::new("bb", &[3], &err),
+                DamagedChunk::new("aa", &[1, 0], &err),
+            ],
+        );
+        assert!(!r.is_clean());
+        assert_eq!(r.chunks[0].id, "aa");
+        assert_eq!(
+            r.extents.iter().map(|e| e.offset).collect::<Vec<_>>(),
+            &[0, 4 << 20, 12 << 20]
+        );
+        assert_eq!(r.extents[0].partitions, &[1]);
+        assert_eq!(r.extents[1].partitions, &[1, 2]);
+        assert_eq!(r.extents[2].partitions, &[2]);
+        assert_eq!(r.damaged_partitions(), &[(1, 5 << 20), (2, 6 << 20)]);
+        assert_eq!(r.damaged_bytes(), 12 << 20);
+    }
+}
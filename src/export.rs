@@ -0,0 +1,353 @@
+//! Partial copies of a chunk store.
+//!
+//! [export_store](fn.export_store.html) creates a fresh store which contains only the given
+//! revisions and the chunks they reference. Chunk files are copied verbatim, so the result is
+//! a regular backy store which can be restored from or mounted like the original.
+//!
+//! [mirror](fn.mirror.html) brings an existing store up to date with all revisions of another
+//! one, e.g. a replica on a different host mounted via NFS. Chunks which the destination
+//! already has are left alone, so an interrupted mirror run just picks up where it stopped.
+
+use crate::backend;
+use crate::ExtractError;
+#[cfg(not(feature = "read-only"))]
+use crate::{
+    backend::{Backend, Fsync, Rev},
+    chunkvec::{ChunkId, ChunkVec},
+    purgelock,
+};
+
+#[cfg(not(feature = "read-only"))]
+use log::warn;
+#[cfg(not(feature = "read-only"))]
+use std::collections::BTreeSet;
+use std::fs;
+#[cfg(not(feature = "read-only"))]
+use std::fs::File;
+use std::io;
+#[cfg(not(feature = "read-only"))]
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("No revisions given")]
+    NoRevisions,
+    #[error("Revisions must be located in the same backup dir: '{}' != '{}'",
+            .0.display(), .1.display())]
+    MixedStores(PathBuf, PathBuf),
+    #[error("Destination '{}' exists and is not empty", .0.display())]
+    DestExists(PathBuf),
+    #[error("Failed to read revision '{}'", .0.display())]
+    Revision(PathBuf, #[source] io::Error),
+    #[error("Failed to parse revision '{}'", .0.display())]
+    Map(PathBuf, #[source] ExtractError),
+    #[error("Failed to acquire purge lock for backup dir '{}'", .0.display())]
+    Lock(PathBuf, #[source] io::Error),
+    #[error("Failed to copy '{}'", .0.display())]
+    Copy(PathBuf, #[source] io::Error),
+    #[error("Chunked backend error")]
+    Backend(#[from] backend::Error),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Summary of a mirror run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MirrorStats {
+    /// Revisions copied or updated
+    pub revisions: usize,
+    /// Revisions which were up to date in the destination already
+    pub unchanged: usize,
+    pub chunks: usize,
+    /// Chunks which were present in the destination already
+    pub skipped: usize,
+    /// Revisions of the source whose `.rev` file cannot be read. They are left out.
+    pub unreadable: usize,
+    /// Compressed size of all chunk files copied
+    pub bytes: u64,
+}
+
+/// Summary of an export run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportStats {
+    pub revisions: usize,
+    pub chunks: usize,
+    /// Compressed size of all chunk files copied
+    pub bytes: u64,
+}
+
+// Resolves symlinks like `last` so that the export gets the real revision names. Returns backup
+// dir and revision map path.
+pub(crate) fn resolve(revfile: &Path) -> Result<(PathBuf, PathBuf)> {
+    let real = fs::canonicalize(revfile).map_err(|e| Error::Revision(revfile.to_owned(), e))?;
+    let dir = real
+        .parent()
+        .unwrap_or_else(|| Path::new("/"))
+        .to_path_buf();
+    Ok((dir, real))
+}
+
+#[cfg(not(feature = "read-only"))]
+fn copy(src: &Path, dest: &Path) -> Result<u64> {
+    fs::copy(src, dest).map_err(|e| Error::Copy(src.to_owned(), e))
+}
+
+/// Copies `revisions` (paths to revision maps as passed to `Extractor::init`) together with all
+/// chunks they reference into the new store `dest`.
+///
+/// All revisions must come from the same backup dir. `dest` must not exist or be an empty
+/// directory. Revision maps and `.rev` files are copied after all chunks so that an interrupted
+/// export never contains revisions with missing chunks. Chunk files are verified after writing
+/// and flushed to disk.
+///
+/// Not available with the `read-only` feature.
+#[cfg(not(feature = "read-only"))]
+pub fn export_store<P, Q>(revisions: &[P], dest: Q) -> Result<ExportStats>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    export_store_with(revisions, dest, Fsync::default())
+}
+
+/// Like [export_store](fn.export_store.html), but flushes data to disk according to `fsync`.
+#[cfg(not(feature = "read-only"))]
+pub fn export_store_with<P, Q>(revisions: &[P], dest: Q, fsync: Fsync) -> Result<ExportStats>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let dest = dest.as_ref();
+    let mut revs = Vec::with_capacity(revisions.len());
+    for r in revisions {
+        revs.push(resolve(r.as_ref())?);
+    }
+    let basedir = match revs.first() {
+        Some((dir, _)) => dir.clone(),
+        None => return Err(Error::NoRevisions),
+    };
+    if let Some((dir, _)) = revs.iter().find(|(dir, _)| *dir != basedir) {
+        return Err(Error::MixedStores(basedir, dir.clone()));
+    }
+    let _lock = purgelock(&basedir).map_err(|e| Error::Lock(basedir.clone(), e))?;
+    let src = Backend::open(&basedir)?;
+
+    let mut ids: BTreeSet<ChunkId> = BTreeSet::new();
+    for (_, map) in &revs {
+        let json = backend::map_file(map).map_err(|e| Error::Revision(map.clone(), e))?;
+        let chunks = ChunkVec::from_slice(&json).map_err(|e| Error::Map(map.clone(), e))?;
+        ids.extend(chunks.ids().cloned());
+    }
+
+    if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+        return Err(Error::DestExists(dest.to_owned()));
+    }
+    let be = Backend::create(dest)?.with_fsync(fsync);
+
+    let mut stats = ExportStats::default();
+    for id in &ids {
+        let file = src.filename(id);
+        let data = fs::read(&file).map_err(|e| Error::Copy(file, e))?;
+        be.commit(id, &data)?;
+        stats.bytes += data.len() as u64;
+        stats.chunks += 1;
+    }
+    for (_, map) in &revs {
+        let name = map.file_name().expect("canonical path has a file name");
+        let rev = map.with_extension("rev");
+        if rev.exists() {
+            copy(&rev, &dest.join(name).with_extension("rev"))?;
+        }
+        copy(map, &dest.join(name))?;
+        stats.revisions += 1;
+    }
+    Ok(stats)
+}
+
+// Copies `src` to `dest` through a temporary file so that `dest` is never seen half-written.
+// Returns false if `dest` has the same contents already.
+#[cfg(not(feature = "read-only"))]
+fn replace(src: &Path, dest: &Path, fsync: Fsync) -> Result<bool> {
+    let data = fs::read(src).map_err(|e| Error::Copy(src.to_owned(), e))?;
+    if fs::read(dest).is_ok_and(|d| d == data) {
+        return Ok(false);
+    }
+    let tmp = dest.with_extension("mirror.tmp");
+    let write = || -> io::Result<()> {
+        let mut f = File::create(&tmp)?;
+        f.write_all(&data)?;
+        if fsync != Fsync::Never {
+            f.sync_all()?;
+        }
+        fs::rename(&tmp, dest)?;
+        if fsync == Fsync::All {
+            File::open(dest.parent().unwrap_or_else(|| Path::new(".")))?.sync_all()?;
+        }
+        Ok(())
+    };
+    write().map_err(|e| {
+        fs::remove_file(&tmp).ok();
+        Error::Copy(src.to_owned(), e)
+    })?;
+    Ok(true)
+}
+
+/// Copies all revisions of the store `src` which are missing or different in `dest`, together
+/// with the chunks they reference. `dest` is created if it does not exist.
+///
+/// Revisions are processed oldest first. Chunk files which exist in `dest` and decompress to data
+/// matching their chunk ID are skipped. All others are copied, and each copy is decompressed and
+/// checked against its chunk ID before it is renamed into place. A corrupt source chunk stops the mirror run with
+/// an error. The revision map and `.rev` file are written only after all chunks of a revision
+/// have arrived, so `dest` never shows revisions with missing chunks. Both stores are protected
+/// from purges while the mirror runs. Revisions whose `.rev` file cannot be read are counted in
+/// `unreadable` and left out.
+///
+/// Not available with the `read-only` feature.
+#[cfg(not(feature = "read-only"))]
+pub fn mirror<P, Q>(src: P, dest: Q, fsync: Fsync) -> Result<MirrorStats>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    let from = Backend::open(src)?;
+    let _src_lock = purgelock(src).map_err(|e| Error::Lock(src.to_owned(), e))?;
+    let to = Backend::create(dest)?.with_fsync(fsync);
+    let _dest_lock = purgelock(dest).map_err(|e| Error::Lock(dest.to_owned(), e))?;
+
+    let mut stats = MirrorStats::default();
+    let mut done: BTreeSet<ChunkId> = BTreeSet::new();
+    for rev in Rev::discover(src)? {
+        let rev = match rev {
+            Ok(rev) => rev,
+            Err(e) => {
+                warn!("Skipping revision in '{}': {}", src.display(), e);
+                stats.unreadable += 1;
+                continue;
+            }
+        };
+        let map = src.join(rev.uuid.as_str());
+        let json = backend::map_file(&map).map_err(|e| Error::Revision(map.clone(), e))?;
+        let chunks = ChunkVec::from_slice(&json).map_err(|e| Error::Map(map.clone(), e))?;
+        for id in chunks.ids() {
+            if done.contains(id) {
+                continue;
+            }
+            if to.load(id).is_ok_and(|data| to.hash(&data) == id.as_str()) {
+                stats.skipped += 1;
+            } else {
+                let file = from.filename(id);
+                let data = fs::read(&file).map_err(|e| Error::Copy(file, e))?;
+                to.commit(id, &data)?;
+                stats.bytes += data.len() as u64;
+                stats.chunks += 1;
+            }
+            done.insert(id.clone());
+        }
+        let name = map.file_name().expect("revision map has a file name");
+        let changed = replace(&map, &dest.join(name), fsync)?;
+        let rev_changed = replace(
+            &map.with_extension("rev"),
+            &dest.join(name).with_extension("rev"),
+            fsync,
+        )?;
+        if changed || rev_changed {
+            stats.revisions += 1;
+        } else {
+            stats.unchanged += 1;
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(all(test, not(feature = "read-only")))]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Corruption, Slot, StoreBuilder};
+    use crate::{Extractor, Stream};
+    use tempdir::TempDir;
+
+    #[test]
+    fn export_selected_revision() {
+        let tmp = TempDir::new("export").unwrap();
+        let src = tmp.path().join("src");
+        let first = testing::layout(&[Slot::Data(1), Slot::Hole, Slot::Data(2)]);
+        let second = testing::layout(&[Slot::Data(1), Slot::Data(3)]);
+        let maps = StoreBuilder::new()
+            .revision("Rev1zzzzzzzzzzzzzzzzzz", first.clone())
+            .revision("Rev2zzzzzzzzzzzzzzzzzz", second)
+            .write(&src)
+            .unwrap();
+        let dest = tmp.path().join("dest");
+        let stats = export_store(&maps[0..1], &dest).unwrap();
+        assert_eq!(stats.revisions, 1);
+        assert_eq!(stats.chunks, 2);
+        assert!(dest.join("Rev1zzzzzzzzzzzzzzzzzz.rev").exists());
+        assert!(!dest.join("Rev2zzzzzzzzzzzzzzzzzz").exists());
+        assert!(!testing::chunk_path(&dest, &testing::chunk_id(&testing::pattern(3))).exists());
+
+        let mut buf = Vec::new();
+        Extractor::init(dest.join("Rev1zzzzzzzzzzzzzzzzzz"))
+            .unwrap()
+            .extract(Stream::new(&mut buf))
+            .unwrap();
+        assert!(buf == testing::image(&first));
+
+        match export_store(&maps[1..2], &dest) {
+            Err(Error::DestExists(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mirror_resumes_and_repairs() {
+        let tmp = TempDir::new("mirror").unwrap();
+        let src = tmp.path().join("src");
+        let first = testing::layout(&[Slot::Data(1), Slot::Hole, Slot::Data(2)]);
+        let second = testing::layout(&[Slot::Data(1), Slot::Data(3)]);
+        StoreBuilder::new()
+            .revision("Rev1zzzzzzzzzzzzzzzzzz", first)
+            .revision("Rev2zzzzzzzzzzzzzzzzzz", second.clone())
+            .write(&src)
+            .unwrap();
+        let dest = tmp.path().join("dest");
+        let stats = mirror(&src, &dest, Fsync::Never).unwrap();
+        assert_eq!((stats.revisions, stats.chunks, stats.skipped), (2, 3, 0));
+
+        let stats = mirror(&src, &dest, Fsync::Never).unwrap();
+        assert_eq!((stats.unchanged, stats.chunks, stats.skipped), (2, 0, 3));
+
+        // damaged copies are replaced
+        let id = testing::chunk_id(&testing::pattern(3));
+        testing::corrupt(&dest, &id, Corruption::Truncate(3)).unwrap();
+        let stats = mirror(&src, &dest, Fsync::Never).unwrap();
+        assert_eq!((stats.chunks, stats.skipped), (1, 2));
+        let mut buf = Vec::new();
+        Extractor::init(dest.join("Rev2zzzzzzzzzzzzzzzzzz"))
+            .unwrap()
+            .extract(Stream::new(&mut buf))
+            .unwrap();
+        assert!(buf == testing::image(&second));
+
+        // copies with a valid header but wrong contents are replaced as well
+        testing::corrupt(&dest, &id, Corruption::FlipByte(20)).unwrap();
+        let stats = mirror(&src, &dest, Fsync::All).unwrap();
+        assert_eq!((stats.chunks, stats.skipped), (1, 2));
+        fs::write(src.join("Brokenzzzzzzzzzzzzzzzz.rev"), "uuid: [").unwrap();
+        let stats = mirror(&src, &dest, Fsync::Never).unwrap();
+        assert_eq!((stats.unchanged, stats.unreadable), (2, 1));
+        fs::remove_file(src.join("Brokenzzzzzzzzzzzzzzzz.rev")).unwrap();
+
+        // corrupt source chunks are never copied
+        let dest2 = tmp.path().join("dest2");
+        testing::corrupt(&src, &id, Corruption::FlipByte(20)).unwrap();
+        assert!(mirror(&src, &dest2, Fsync::Never).is_err());
+        assert!(!testing::chunk_path(&dest2, &id).exists());
+        assert!(!dest2.join("Rev2zzzzzzzzzzzzzzzzzz.rev").exists());
+    }
+}
@@ -0,0 +1,348 @@
+//! Restore-time transformation of image contents.
+//!
+//! Filters run between decompression (and verification, if enabled) and writeout. Each filter
+//! states which byte ranges of the image it may change. Chunks which are mapped to several
+//! positions are copied for each position a filter applies to, so that deduplicated data is
+//! transformed according to its place in the image. Zero chunks are passed on unchanged.
+//!
+//! Built-in filters are created from a textual spec `NAME[:PARAMS]` with [builtin]:
+//!
+//! * `zero-swap` zero-fills all swap partitions (MBR type 82 or the Linux swap GPT type).
+//! * `byteswap:WIDTH[,START-END]` reverses the byte order of each WIDTH (2, 4 or 8) byte word,
+//!   either in the whole image or in the given byte range. Offsets may carry a K/M/G/T suffix.
+//! * `scrub:START-END[,START-END...]` zero-fills the given byte ranges.
+//! * `scrub-file:PATH` zero-fills the data blocks of a regular file in the guest file system,
+//!   e.g. `/etc/shadow`. The file system is chosen like for
+//!   [Extractor::files](../struct.Extractor.html#method.files). File names and sizes remain
+//!   visible in the restored image, only the contents are removed.
+
+use crate::chunkvec::ChunkVec;
+use crate::partition::PartitionTable;
+use crate::{chunk2pos, Chunk, Data, Extractor, CHUNKSZ};
+
+use smallvec::SmallVec;
+use std::fmt::Debug;
+use std::ops::Range;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Unknown filter '{0}' (expected zero-swap, byteswap, scrub or scrub-file)")]
+    Unknown(String),
+    #[error("Invalid parameters for filter {0}: {1}")]
+    Params(String, String),
+}
+
+/// Transformation applied to decompressed image contents before they are written.
+pub trait ChunkFilter: Debug + Send + Sync {
+    /// Returns true if the filter may change anything in the image byte range `range`. Data
+    /// outside all such ranges is not copied and never passed to `apply`.
+    fn affects(&self, range: Range<u64>) -> bool;
+
+    /// Transforms `data`, which is written to the image at `offset`, in place.
+    fn apply(&self, offset: u64, data: &mut [u8]);
+}
+
+// Part of `range` which lies in `r`, relative to `range.start`
+fn clip(range: Range<u64>, r: &Range<u64>) -> Option<Range<usize>> {
+    let start = range.start.max(r.start);
+    let end = range.end.min(r.end);
+    if start < end {
+        Some((start - range.start) as usize..(end - range.start) as usize)
+    } else {
+        None
+    }
+}
+
+/// Zero-fills byte ranges of the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZeroRanges {
+    ranges: Vec<Range<u64>>,
+}
+
+impl ZeroRanges {
+    pub fn new(ranges: Vec<Range<u64>>) -> Self {
+        Self { ranges }
+    }
+
+    /// Covers all swap partitions in `table`.
+    pub fn swap(table: &PartitionTable) -> Self {
+        const LINUX_SWAP: &str = "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F";
+        Self::new(
+            table
+                .partitions
+                .iter()
+                .filter(|p| p.kind == "82" || p.kind == LINUX_SWAP)
+                .map(|p| p.start..p.end)
+                .collect(),
+        )
+    }
+}
+
+impl ChunkFilter for ZeroRanges {
+    fn affects(&self, range: Range<u64>) -> bool {
+        self.ranges.iter().any(|r| clip(range.clone(), r).is_some())
+    }
+
+    fn apply(&self, offset: u64, data: &mut [u8]) {
+        let range = offset..offset + data.len() as u64;
+        for r in &self.ranges {
+            if let Some(part) = clip(range.clone(), r) {
+                data[part].iter_mut().for_each(|b| *b = 0);
+            }
+        }
+    }
+}
+
+/// Reverses the byte order of fixed-size words, e.g. to fix up raw data written by a machine of
+/// different endianness.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteSwap {
+    width: usize,
+    range: Range<u64>,
+}
+
+impl ByteSwap {
+    /// Swaps `width` byte words in `range`. Words are aligned to multiples of `width` counted
+    /// from the image start. Partial words at the ends of `range` are left alone.
+    pub fn new(width: usize, range: Range<u64>) -> Self {
+        Self { width, range }
+    }
+}
+
+impl ChunkFilter for ByteSwap {
+    fn affects(&self, range: Range<u64>) -> bool {
+        clip(range, &self.range).is_some()
+    }
+
+    fn apply(&self, offset: u64, data: &mut [u8]) {
+        let w = self.width as u64;
+        // whole words within both the chunk and the range
+        let start = self.range.start.max(offset);
+        let start = start.div_ceil(w) * w;
+        let end = self.range.end.min(offset + data.len() as u64) / w * w;
+        if start < end {
+            data[(start - offset) as usize..(end - offset) as usize]
+                .chunks_exact_mut(self.width)
+                .for_each(<[u8]>::reverse);
+        }
+    }
+}
+
+// Parses a byte offset with optional K/M/G/T suffix (powers of 1024).
+fn parse_offset(s: &str) -> Option<u64> {
+    let (num, shift) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 10),
+        Some((i, 'M')) => (&s[..i], 20),
+        Some((i, 'G')) => (&s[..i], 30),
+        Some((i, 'T')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    num.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+// Parses `START-END`
+fn parse_range(s: &str) -> Option<Range<u64>> {
+    let mut it = s.splitn(2, '-');
+    let start = parse_offset(it.next()?)?;
+    let end = parse_offset(it.next()?)?;
+    if start < end {
+        Some(start..end)
+    } else {
+        None
+    }
+}
+
+/// Creates the built-in filter described by `spec`, see the [module documentation](index.html).
+/// `extractor` is consulted for filters which depend on the image, e.g. to look up partitions.
+pub fn builtin(spec: &str, extractor: &Extractor) -> crate::Result<Box<dyn ChunkFilter>> {
+    let mut it = spec.splitn(2, ':');
+    let name = it.next().unwrap_or_default();
+    let params = it.next();
+    let invalid = |msg: &str| Error::Params(name.to_owned(), msg.to_owned());
+    Ok(match (name, params) {
+        ("zero-swap", None) => Box::new(ZeroRanges::swap(&extractor.partition_table()?)),
+        ("zero-swap", Some(_)) => return Err(invalid("takes no parameters").into()),
+        ("byteswap", Some(p)) => {
+            let mut p = p.splitn(2, ',');
+            let width = match p.next().and_then(|w| w.parse().ok()) {
+                Some(w @ 2) | Some(w @ 4) | Some(w @ 8) => w,
+                _ => return Err(invalid("word width must be 2, 4 or 8").into()),
+            };
+            let range = match p.next() {
+                Some(r) => parse_range(r).ok_or_else(|| invalid("expected range START-END"))?,
+                None => 0..u64::MAX,
+            };
+            Box::new(ByteSwap::new(width, range))
+        }
+        ("byteswap", None) => return Err(invalid("word width missing").into()),
+        ("scrub", Some(p)) => {
+            let ranges = p
+                .split(',')
+                .map(parse_range)
+                .collect::<Option<_>>()
+                .ok_or_else(|| invalid("expected ranges START-END[,START-END...]"))?;
+            Box::new(ZeroRanges::new(ranges))
+        }
+        ("scrub-file", Some(path)) if path.starts_with('/') => {
+            Box::new(ZeroRanges::new(extractor.file_ranges(&[path], None)?))
+        }
+        ("scrub", _) | ("scrub-file", _) => {
+            return Err(invalid("expected ranges or an absolute path").into())
+        }
+        _ => return Err(Error::Unknown(name.to_owned()).into()),
+    })
+}
+
+// Byte range of the image at chunk position `seq`
+fn extent(seq: u32) -> Range<u64> {
+    chunk2pos(seq)..chunk2pos(seq) + CHUNKSZ as u64
+}
+
+/// Runs `chunk` through `filters` and passes the result on to `emit`. Positions which a filter
+/// applies to get their own copy of the data.
+pub(crate) fn apply<F>(
+    filters: &[Box<dyn ChunkFilter>],
+    chunk: Chunk,
+    mut emit: F,
+) -> crate::Result<()>
+where
+    F: FnMut(Chunk) -> crate::Result<()>,
+{
+    let data = match chunk.data {
+        Data::Some(data) if !filters.is_empty() => data,
+        _ => return emit(chunk),
+    };
+    let (hit, miss): (SmallVec<[u32; 4]>, SmallVec<[u32; 4]>) = chunk
+        .seqs
+        .into_iter()
+        .partition(|&seq| filters.iter().any(|f| f.affects(extent(seq))));
+    for seq in hit {
+        let mut copy = data.clone();
+        for f in filters.iter().filter(|f| f.affects(extent(seq))) {
+            f.apply(chunk2pos(seq), &mut copy);
+        }
+        emit(Chunk {
+            data: Data::Some(copy),
+            seqs: SmallVec::from_slice(&[seq]),
+        })?;
+    }
+    if !miss.is_empty() {
+        emit(Chunk {
+            data: Data::Some(data),
+            seqs: miss,
+        })?;
+    }
+    Ok(())
+}
+
+/// Number of chunks which `apply` emits in addition to the distinct chunks of `chunks`.
+pub(crate) fn extra_chunks(filters: &[Box<dyn ChunkFilter>], chunks: &ChunkVec) -> usize {
+    if filters.is_empty() {
+        return 0;
+    }
+    chunks
+        .entries()
+        .map(|(_, seqs)| {
+            let hit = seqs
+                .iter()
+                .filter(|&&seq| filters.iter().any(|f| f.affects(extent(seq))))
+                .count();
+            if hit == seqs.len() {
+                hit - 1
+            } else {
+                hit
+            }
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partition::Partition;
+    use crate::CHUNKSZ_LOG;
+    use smallvec::smallvec;
+
+    #[test]
+    fn zero_ranges() {
+        let f = ZeroRanges::new(vec![2..4, 10..20]);
+        assert!(f.affects(0..3));
+        assert!(!f.affects(4..10));
+        let mut data = [1u8; 8];
+        f.apply(1, &mut data);
+        assert_eq!(data, [1, 0, 0, 1, 1, 1, 1, 1]);
+        let mut data = [1u8; 8];
+        f.apply(8, &mut data);
+        assert_eq!(data, [1, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn swap_partitions() {
+        let part = |number, kind: &str| Partition {
+            number,
+            start: u64::from(number) << 20,
+            end: u64::from(number + 1) << 20,
+            kind: kind.to_owned(),
+            name: String::new(),
+        };
+        let table = PartitionTable {
+            table: None,
+            partitions: vec![
+                part(1, "83"),
+                part(2, "82"),
+                part(3, "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F"),
+            ],
+        };
+        assert_eq!(
+            ZeroRanges::swap(&table).ranges,
+            vec![2 << 20..3 << 20, 3 << 20..4 << 20]
+        );
+    }
+
+    #[test]
+    fn byte_swap_whole_words() {
+        let f = ByteSwap::new(4, 3..14);
+        let mut data: Vec<u8> = (0..16).collect();
+        f.apply(0, &mut data);
+        assert_eq!(data, [0, 1, 2, 3, 7, 6, 5, 4, 11, 10, 9, 8, 12, 13, 14, 15]);
+        // alignment counts from the image start
+        let mut data: Vec<u8> = (0..8).collect();
+        ByteSwap::new(2, 0..u64::MAX).apply(1, &mut data);
+        assert_eq!(data, [0, 2, 1, 4, 3, 6, 5, 7]);
+    }
+
+    #[test]
+    fn scrub_files() {
+        let store = crate::test_helper::store_tar();
+        let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98")).unwrap();
+        assert!(builtin("scrub:1K-2K,4M-5M", &e).is_ok());
+        assert!(builtin("scrub:1K", &e).is_err());
+        assert!(builtin("scrub-file:etc/passwd", &e).is_err());
+    }
+
+    #[test]
+    fn apply_splits_shared_chunks() {
+        let first_byte = (1 << CHUNKSZ_LOG)..(1 << CHUNKSZ_LOG) + 1;
+        let filters: Vec<Box<dyn ChunkFilter>> = vec![Box::new(ZeroRanges::new(vec![first_byte]))];
+        let mut out = Vec::new();
+        let chunk = Chunk {
+            data: Data::Some(vec![1; CHUNKSZ]),
+            seqs: smallvec![0, 1, 2],
+        };
+        apply(&filters, chunk, |c| {
+            out.push(c);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(out.len(), 2);
+        assert_eq!(&out[0].seqs[..], &[1]);
+        assert_eq!(out[0].data, {
+            let mut d = vec![1; CHUNKSZ];
+            d[0] = 0;
+            Data::Some(d)
+        });
+        assert_eq!(&out[1].seqs[..], &[0, 2]);
+        assert_eq!(out[1].data, Data::Some(vec![1; CHUNKSZ]));
+    }
+}
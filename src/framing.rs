@@ -0,0 +1,71 @@
+//! Length-prefixed encoding primitives shared by the archive and send/receive formats.
+//!
+//! All integers are big-endian. A `str` is a u16 length followed by UTF-8 bytes, a `blob` is a
+//! u32 length followed by raw bytes. Malformed input results in `io::ErrorKind::InvalidData`.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+fn invalid<S: Into<String>>(kind: io::ErrorKind, msg: S) -> io::Error {
+    io::Error::new(kind, msg.into())
+}
+
+/// Writer which keeps track of the current stream position.
+pub struct Counting<W> {
+    inner: W,
+    pub pos: u64,
+}
+
+impl<W: Write> Counting<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, pos: 0 }
+    }
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn write_str<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    let len = u16::try_from(s.len())
+        .map_err(|_| invalid(io::ErrorKind::InvalidInput, "string too long"))?;
+    w.write_u16::<BigEndian>(len)?;
+    w.write_all(s.as_bytes())
+}
+
+pub fn write_blob<W: Write>(w: &mut W, b: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(b.len())
+        .map_err(|_| invalid(io::ErrorKind::InvalidInput, "blob too long"))?;
+    w.write_u32::<BigEndian>(len)?;
+    w.write_all(b)
+}
+
+pub fn read_str<R: Read>(r: &mut R) -> io::Result<String> {
+    let mut buf = vec![0; r.read_u16::<BigEndian>()? as usize];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|_| invalid(io::ErrorKind::InvalidData, "invalid UTF-8"))
+}
+
+/// Reads a blob of at most `max` bytes. The length field is untrusted input, so it must be
+/// checked before allocating.
+pub fn read_blob<R: Read>(r: &mut R, max: usize) -> io::Result<Vec<u8>> {
+    let len = r.read_u32::<BigEndian>()? as usize;
+    if len > max {
+        return Err(invalid(
+            io::ErrorKind::InvalidData,
+            format!("blob length {} exceeds {}", len, max),
+        ));
+    }
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
@@ -1,22 +1,31 @@
 //! Fuse-driven access to revisions with in-memory COW
 
-use crate::backend::{self, Backend, Rev, RevError};
-use crate::chunkvec::{ChunkId, RevisionMap};
-use crate::{pos2chunk, CHUNKSZ, CHUNKSZ_LOG, ZERO_CHUNK};
+use super::acl::Acl;
+use super::cache::{PageCache, SharedCache};
+use super::hydrate::Hydration;
+use super::meta::Meta;
+use super::overlay::{Frozen, Overlay};
+use super::prefetch::Prefetcher;
+use super::snapshot::Snapshot;
+use crate::backend::{self, Backend, ReadStrategy, Rev, RevError, Trust};
+use crate::chunkvec::{ChunkId, ChunkVec};
+use crate::revindex::RevIndex;
+use crate::{pos2chunk, ExtractError, MapCache, CHUNKSZ, CHUNKSZ_LOG};
 
 use fnv::FnvHashMap as HashMap;
 use log::{debug, info};
 use lru::LruCache;
-use murmur3::murmur3_x64_128;
 use std::cmp::min;
+use std::collections::HashSet;
 use std::ffi::OsString;
-use std::fmt;
+use std::fmt::{self, Write};
 use std::fs;
 use std::io;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 static ID_SEQ: AtomicU64 = AtomicU64::new(4);
@@ -25,11 +34,8 @@ static ID_SEQ: AtomicU64 = AtomicU64::new(4);
 pub enum Error {
     #[error("I/O error")]
     Io(#[from] io::Error),
-    #[error("Invalid data in chunk map file '{}'", path.display())]
-    ParseMap {
-        path: PathBuf,
-        source: serde_json::Error,
-    },
+    #[error("Invalid chunk map file '{}'", path.display())]
+    Map { path: PathBuf, source: ExtractError },
     #[error("Failed to open data store")]
     Backend(#[from] backend::Error),
     #[error("Failed to load data chunk {chunk_id:?}")]
@@ -37,6 +43,8 @@ pub enum Error {
         chunk_id: ChunkId,
         source: backend::Error,
     },
+    #[error("Checksum mismatch in chunk {chunk_id:?}: content hashes to {actual}")]
+    Checksum { chunk_id: ChunkId, actual: String },
     #[error("Invalid revision file name '{}'", .0.display())]
     InvalidName(PathBuf),
     #[error("'{}' contains no revision or no chunks - is this really a backy directory?",
@@ -44,12 +52,66 @@ pub enum Error {
     NoRevisions(PathBuf),
     #[error(transparent)]
     Rev(#[from] RevError),
+    #[error("Cannot resize image from {size} to {requested} bytes")]
+    Resize { size: u64, requested: u64 },
+    #[error("Revision is read-only")]
+    ReadOnly,
+    #[error("Cache is exhausted by modified pages")]
+    DirtyFull,
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
 type Chunks = Vec<Option<ChunkId>>;
 
+/// Settings which apply to all revisions of a mount.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Directory to hydrate opened revisions into
+    pub hydrate: Option<PathBuf>,
+    /// Check the hash of every chunk loaded from the store
+    pub verify: bool,
+    /// Drop cached data of revisions which have not been opened for this long
+    pub idle_timeout: Option<Duration>,
+    /// Hide revisions which backy trusts less
+    pub require_trust: Option<Trust>,
+    /// Number of chunks to load ahead once a regular access pattern has been detected
+    pub prefetch: usize,
+    /// Allow images to be enlarged beyond the size of the stored revision
+    pub grow: bool,
+    /// Reject all modifications
+    pub read_only: bool,
+    /// Reject modifications of revisions which carry any of these tags
+    pub read_only_tags: Vec<String>,
+    /// How chunk files are read
+    pub reader: ReadStrategy,
+    /// Keep parsed revision maps here
+    pub map_cache: Option<MapCache>,
+    /// Move modified pages into encrypted files here once they exhaust the cache, instead of
+    /// saving them in the store
+    pub overlay: Option<PathBuf>,
+    /// Who may access which revisions
+    pub acl: Option<Acl>,
+}
+
+/// Loads chunk `id` from the store, optionally checking its hash.
+pub(super) fn load_chunk(backend: &Backend, id: &ChunkId, verify: bool) -> Result<Vec<u8>> {
+    let data = backend.load(id).map_err(|e| Error::BackendLoad {
+        chunk_id: id.clone(),
+        source: e,
+    })?;
+    if verify {
+        let actual = backend.hash(&data);
+        if actual != id.as_str() {
+            return Err(Error::Checksum {
+                chunk_id: id.clone(),
+                actual,
+            });
+        }
+    }
+    Ok(data)
+}
+
 #[derive(Clone)]
 struct Page {
     data: Rc<Vec<u8>>,
@@ -60,26 +122,11 @@ impl Page {
     /// Fetches a chunk from the backend.
     ///
     /// Panics if the requested page is a zero page and thus not present in the backend.
-    fn load(map: &[Option<ChunkId>], backend: &Backend, seq: u32) -> Result<Self> {
+    fn load(map: &[Option<ChunkId>], backend: &Backend, seq: u32, verify: bool) -> Result<Self> {
         let cid = map[seq as usize]
-            .clone()
+            .as_ref()
             .unwrap_or_else(|| panic!("failed to locate chunk {} in map", seq));
-        Ok((
-            backend.load(cid.as_str()).map_err(|e| Error::BackendLoad {
-                chunk_id: cid,
-                source: e,
-            })?,
-            seq,
-        )
-            .into())
-    }
-
-    #[cfg(test)]
-    fn new(data: Vec<u8>, seq: u32) -> Self {
-        Self {
-            data: Rc::new(data),
-            seq,
-        }
+        Ok((load_chunk(backend, cid, verify)?, seq).into())
     }
 
     /// Overwrites data region inside page. Panics if updated regions exceeds boundaries.
@@ -95,14 +142,12 @@ impl Page {
         self
     }
 
+    #[cfg(any(test, not(feature = "read-only")))]
     fn hash(&self) -> ChunkId {
-        ChunkId::from(hex::encode(
-            murmur3_x64_128(&mut io::Cursor::new(self.data.as_ref()), 0)
-                .unwrap()
-                .to_le_bytes(),
-        ))
+        ChunkId::from(backend::hash(&self.data))
     }
 
+    #[cfg(not(feature = "read-only"))]
     fn save(&self, be: &Backend) -> Result<ChunkId> {
         let id = self.hash();
         be.save(&id, &self.data)?;
@@ -155,38 +200,85 @@ pub struct FuseAccess {
     open_page: Page,
     zero_page: Page,
     dirty: LruCache<u32, Page>,
-    ro_cache: LruCache<u32, Page>,
+    /// Dirty pages which have been evicted from memory
+    overlay: Option<Overlay>,
+    cache: SharedCache,
+    opts: Options,
+    hydration: Option<Hydration>,
+    prefetch: Prefetcher,
+    /// Number of open file handles
+    handles: usize,
+    /// Chunk map differs from the one on disk
+    modified: bool,
+    /// Copies of metadata files, indexed by `Meta`
+    meta: [Option<Vec<u8>>; 4],
+    /// Set while data is loaded but no file handle is open
+    idle_since: Option<Instant>,
 }
 
 const OFFSET_MASK: u64 = CHUNKSZ as u64 - 1;
+// Largest image size whose chunk numbers fit into u32
+const MAX_SIZE: u64 = (u32::MAX as u64) << CHUNKSZ_LOG;
 
 /// API to read/write images from the upper-level FUSE driver.
 ///
-/// This layer implements simple CoW caching. Pages are put into the cache
-/// either when they are written to or when they are read for the second time.
-/// Modifications are only stored in memory and never written to disk. This
-/// enables filesystem tools like fsck to perform recovery.
+/// This layer implements simple CoW caching. Pages which are read are put into
+/// the page cache shared by all revisions. Pages which are written to are kept
+/// as dirty pages of this revision. Modifications are only stored in memory and
+/// written back to the store as new chunks (or into an encrypted overlay file) if
+/// the cache budget is exhausted. This enables filesystem tools like fsck to
+/// perform recovery.
 impl FuseAccess {
-    fn new<P: AsRef<Path>, I: AsRef<str>>(dir: P, id: I, cache_size: usize) -> Result<Self> {
-        let dir = dir.as_ref();
-        let rev = Rev::load(dir, id.as_ref())?;
-        let backend = Backend::open(dir)?;
-        Ok(Self {
+    #[cfg(test)]
+    fn new<P: AsRef<Path>, I: AsRef<str>>(
+        dir: P,
+        id: I,
+        cache: SharedCache,
+        opts: Options,
+    ) -> Result<Self> {
+        let rev = Rev::load(dir.as_ref(), id.as_ref())?;
+        Ok(Self::with_backend(
+            Backend::open(dir)?,
+            id,
+            rev,
+            cache,
+            opts,
+        ))
+    }
+
+    /// Accesses revision `id` with metadata `rev` in the store opened as `backend`.
+    fn with_backend<I: AsRef<str>>(
+        backend: Backend,
+        id: I,
+        rev: Rev,
+        cache: SharedCache,
+        opts: Options,
+    ) -> Self {
+        let prefetch = Prefetcher::new(backend.clone(), opts.verify, opts.prefetch);
+        Self {
             name: OsString::from(id.as_ref()),
             rev,
             size: 0,             // initialized by load_map()
             map: Vec::default(), // initialized by load_map()
             backend,
             open_page: Page::default(),
-            zero_page: Page::from((Vec::from(ZERO_CHUNK.as_ref()), u32::MAX)),
-            dirty: LruCache::new((cache_size >> CHUNKSZ_LOG) + 1),
-            ro_cache: LruCache::new((cache_size >> CHUNKSZ_LOG) + 1),
-        })
+            zero_page: Page::from((vec![0; CHUNKSZ], u32::MAX)),
+            dirty: LruCache::unbounded(),
+            overlay: None,
+            cache,
+            opts,
+            hydration: None,
+            prefetch,
+            handles: 0,
+            modified: false,
+            meta: Default::default(),
+            idle_since: None,
+        }
     }
 
     #[cfg(test)]
     fn load<P: AsRef<Path>, I: AsRef<str>>(dir: P, id: I) -> Result<Self> {
-        let mut res = Self::new(dir, id, 12 << 20)?;
+        let mut res = Self::new(dir, id, PageCache::shared(12 << 20), Options::default())?;
         res.load_map()?;
         Ok(res)
     }
@@ -203,23 +295,163 @@ impl FuseAccess {
                 self.map.len(),
                 self.name
             );
+            if self.handles == 0 {
+                self.idle_since = Some(Instant::now());
+            }
         }
         Ok(())
     }
 
     fn load_map(&mut self) -> Result<()> {
         let path = self.backend.dir.join(&self.name);
-        let revmap_s = fs::read_to_string(&path)?;
-        let revmap: RevisionMap =
-            serde_json::from_str(&revmap_s).map_err(|source| Error::ParseMap { path, source })?;
-        self.size = revmap.size;
-        self.map = revmap.into_iter().map(|(_seq, id)| id).collect();
+        let chunks = match &self.opts.map_cache {
+            Some(cache) => cache.load(&path),
+            None => ChunkVec::load(&path),
+        }
+        .map_err(|source| Error::Map { path, source })?;
+        self.size = chunks.size;
+        self.map = chunks.by_seq();
         Ok(())
     }
 
-    /// Drops read only cache to conserve memory. Note that the dirty page cache remains.
-    pub fn cleanup(&mut self) {
-        self.ro_cache.clear()
+    /// Returns a verbatim copy of a metadata file. The file is read on first access and kept in
+    /// memory afterwards.
+    pub fn meta(&mut self, kind: Meta) -> Result<&[u8]> {
+        if self.meta[kind as usize].is_none() {
+            let data = match kind {
+                Meta::Stats => self.stats().into_bytes(),
+                // too large to keep a copy, see `snapshot()`
+                Meta::Snapshot => Vec::new(),
+                _ => match kind.source(&self.name) {
+                    Some(src) => fs::read(self.backend.dir.join(src))?,
+                    None => Vec::new(),
+                },
+            };
+            self.meta[kind as usize] = Some(data);
+            if self.handles == 0 && self.idle_since.is_none() {
+                self.idle_since = Some(Instant::now());
+            }
+        }
+        Ok(self.meta[kind as usize].as_deref().unwrap_or_default())
+    }
+
+    /// Discards the current copy of a generated metadata file so that it reflects the current
+    /// state on next access.
+    pub fn refresh_meta(&mut self, kind: Meta) {
+        if kind.source(&self.name).is_none() {
+            self.meta[kind as usize] = None;
+        }
+    }
+
+    /// Formats cache and prefetch counters as `key: value` lines.
+    fn stats(&self) -> String {
+        let (hits, misses) = self.cache.borrow().stats();
+        let p = self.prefetch.stats();
+        let mut out = String::new();
+        writeln!(out, "page_cache_hits: {}", hits).unwrap();
+        writeln!(out, "page_cache_misses: {}", misses).unwrap();
+        writeln!(out, "prefetch_depth: {}", self.opts.prefetch).unwrap();
+        match p.stride {
+            Some(stride) => writeln!(out, "prefetch_stride: {}", stride).unwrap(),
+            None => writeln!(out, "prefetch_stride: none").unwrap(),
+        }
+        writeln!(out, "prefetch_issued: {}", p.issued).unwrap();
+        writeln!(out, "prefetch_used: {}", p.used).unwrap();
+        writeln!(out, "prefetch_failed: {}", p.failed).unwrap();
+        writeln!(out, "prefetch_hit_rate: {:.3}", p.hit_rate()).unwrap();
+        if let Some(o) = &self.overlay {
+            writeln!(out, "overlay_pages: {}", o.len()).unwrap();
+        }
+        out
+    }
+
+    /// Starts copying the whole revision into the hydration directory in the background, if one
+    /// is configured. Does nothing if hydration has already been started.
+    pub fn hydrate(&mut self) -> Result<()> {
+        if let (Some(dir), None) = (&self.opts.hydrate, &self.hydration) {
+            let path = dir.join(Path::new(&self.name).with_extension("img"));
+            info!("{:?}: hydrating to '{}'", self.name, path.display());
+            self.hydration = Some(Hydration::start(
+                &path,
+                self.backend.clone(),
+                self.map.clone(),
+                self.opts.verify,
+            )?);
+        }
+        Ok(())
+    }
+
+    /// Registers a new file handle. Loads the chunk map and starts hydration if necessary.
+    pub fn open(&mut self) -> Result<()> {
+        self.load_if_empty()?;
+        self.hydrate()?;
+        self.handles += 1;
+        self.idle_since = None;
+        Ok(())
+    }
+
+    /// Unregisters a file handle. The idle timer starts when the last handle is gone.
+    pub fn release(&mut self) {
+        self.handles = self.handles.saturating_sub(1);
+        if self.handles == 0 {
+            self.idle_since = Some(Instant::now());
+        }
+        self.cleanup();
+    }
+
+    /// Returns true if nobody has opened this revision for at least `timeout`.
+    fn idle(&self, timeout: Duration) -> bool {
+        match self.idle_since {
+            Some(t) => self.handles == 0 && t.elapsed() >= timeout,
+            None => false,
+        }
+    }
+
+    /// Returns true if the revision is opened or has been used recently, i.e. if it has not been
+    /// evicted since its data has been loaded.
+    fn active(&self) -> bool {
+        self.handles > 0 || self.idle_since.is_some()
+    }
+
+    /// Drops clean pages of this revision from the page cache, except those in `shared` which
+    /// active revisions still reference. The chunk map is dropped as well unless it has been
+    /// modified by writebacks; it is reloaded on next access, like metadata copies. Dirty pages
+    /// are kept since they exist nowhere else.
+    fn evict(&mut self, shared: &HashSet<&ChunkId>) {
+        self.open_page = Page::default();
+        let mut cache = self.cache.borrow_mut();
+        let dropped = self
+            .map
+            .iter()
+            .flatten()
+            .filter(|id| !shared.contains(id) && cache.remove(id))
+            .count();
+        drop(cache);
+        if self.dirty.is_empty() && !self.modified {
+            self.map = Vec::new();
+        }
+        self.meta = Default::default();
+        self.idle_since = None;
+        info!(
+            "{:?}: idle, evicted {} cached pages ({} dirty pages kept)",
+            self.name,
+            dropped,
+            self.dirty.len()
+        );
+    }
+
+    /// Releases the current page. Cached pages remain in the shared cache.
+    fn cleanup(&mut self) {
+        if let Some(h) = &self.hydration {
+            debug!(
+                "{:?}: {} of {} chunks hydrated",
+                self.name,
+                h.hydrated(),
+                self.map.len()
+            );
+        }
+        self.open_page = Page::default();
+        debug!("{:?}: released, {:?}", self.name, self.cache.borrow());
     }
 
     /// Seeks to offset and reads the specified amount of bytes. Note that this function may
@@ -236,7 +468,9 @@ impl FuseAccess {
                 if self.open_page.seq != seq {
                     self.open_page = self.read(seq)?;
                 }
-                Ok(&self.open_page[off..min(off + size, CHUNKSZ)])
+                // the last chunk of a grown image may extend beyond its end
+                let len = min(size as u64, self.size - offset) as usize;
+                Ok(&self.open_page[off..min(off + len, CHUNKSZ)])
             }
         }
     }
@@ -244,27 +478,99 @@ impl FuseAccess {
     /// Returns data from chunk `seq`. Data is fetched from the cache or loaded from disk if
     /// necessary.
     fn read(&mut self, seq: u32) -> Result<Page> {
+        self.predict(seq);
         if let Some(page) = self.dirty.get(&seq) {
             debug!("{:?}: hit #{} (dirty)", self.name, seq);
             Ok(page.clone())
-        } else if let Some(page) = self.ro_cache.get(&seq) {
-            debug!("{:?}: hit #{}", self.name, seq);
-            Ok(page.clone())
-        } else if self.map[seq as usize].is_none() {
+        } else if let Some(data) = self.overlay.as_ref().and_then(|o| o.get(seq)) {
+            debug!("{:?}: overlay #{}", self.name, seq);
+            Ok((data?, seq).into())
+        } else if let Some(id) = self.map[seq as usize].clone() {
+            self.prefetch
+                .collect(Some(&id), &mut self.cache.borrow_mut());
+            if let Some(page) = self.cached(&id, seq) {
+                self.prefetch.claim(&id);
+                return Ok(page);
+            }
+            let page = self.fetch(seq)?;
+            self.cache.borrow_mut().insert(id, Rc::clone(&page.data));
+            Ok(page)
+        } else {
             debug!("{:?}: zero #{}", self.name, seq);
             Ok(self.zero_page.clone())
-        } else {
-            info!("{:?}: load #{}", self.name, seq);
-            let page = Page::load(&self.map, &self.backend, seq)?;
-            self.ro_cache.put(seq, page.clone());
-            Ok(page)
         }
     }
 
+    /// Feeds the access to page `seq` into the prefetcher and issues loads for the pages it
+    /// predicts. Pages which are dirty, zero, cached or hydrated are skipped.
+    fn predict(&mut self, seq: u32) {
+        let ahead = self.prefetch.access(seq, self.map.len() as u32);
+        for s in ahead {
+            if self.dirty.contains(&s)
+                || self.overlay.as_ref().map(|o| o.contains(s)) == Some(true)
+                || self.hydration.as_ref().map(|h| h.has(s)) == Some(true)
+            {
+                continue;
+            }
+            if let Some(id) = &self.map[s as usize] {
+                if !self.cache.borrow().contains(id) {
+                    self.prefetch.issue(id);
+                }
+            }
+        }
+    }
+
+    /// Looks up chunk `id` in the shared page cache and presents it as page `seq`.
+    fn cached(&self, id: &ChunkId, seq: u32) -> Option<Page> {
+        let data = self.cache.borrow_mut().get(id)?;
+        debug!("{:?}: hit #{}", self.name, seq);
+        Some(Page { data, seq })
+    }
+
+    /// Gets a non-zero page from the hydrated image if available or from the backend otherwise.
+    fn fetch(&self, seq: u32) -> Result<Page> {
+        match self.hydration.as_ref().and_then(|h| h.read(seq)) {
+            Some(data) => {
+                debug!("{:?}: hydrated #{}", self.name, seq);
+                Ok((data?, seq).into())
+            }
+            None => {
+                info!("{:?}: load #{}", self.name, seq);
+                Page::load(&self.map, &self.backend, seq, self.opts.verify)
+            }
+        }
+    }
+
+    // The hydrated copy of a page becomes stale as soon as it is modified.
+    fn invalidate(&self, seq: u32) {
+        if let Some(h) = &self.hydration {
+            h.invalidate(seq);
+        }
+    }
+
+    /// Backup directory this revision lives in.
+    pub fn store(&self) -> &Path {
+        &self.backend.dir
+    }
+
+    /// Returns true if the image must not be modified, either because of the mount options or
+    /// because of the revision's tags.
+    pub fn read_only(&self) -> bool {
+        self.opts.read_only
+            || self
+                .rev
+                .tags
+                .iter()
+                .any(|t| self.opts.read_only_tags.contains(t))
+    }
+
     /// Saved dirty data to the CoW cache in memory. Data is never written to disk. Note that not
     /// all bytes may be written. In this case, the returned number is less than buf.len() and the
     /// write operation should be retried with the remainder.
     pub fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<usize> {
+        if self.read_only() {
+            return Err(Error::ReadOnly);
+        }
         if offset + buf.len() as u64 > self.size {
             return Err(
                 io::Error::new(io::ErrorKind::UnexpectedEof, "write beyond end of image").into(),
@@ -281,17 +587,138 @@ impl FuseAccess {
         self.write(seq, off as usize, buf)
     }
 
-    /// Pushes pages from the dirty cache to disk if the latter becomes too full.
+    /// Freezes the current state of the image, including modifications. Reads and writes carry
+    /// on while the snapshot is alive; pages modified afterwards are copied on write.
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        self.load_if_empty()?;
+        // drop our own reference so that only the snapshot causes copies
+        self.open_page = Page::default();
+        let dirty: HashMap<u32, Rc<Vec<u8>>> = self
+            .dirty
+            .iter()
+            .map(|(seq, page)| (*seq, Rc::clone(&page.data)))
+            .collect();
+        // spilled pages stay on disk, the overlay keeps them until the snapshot is gone
+        let spilled = self.overlay.as_ref().map(Overlay::freeze);
+        info!(
+            "{:?}: snapshot with {} dirty and {} spilled pages",
+            self.name,
+            dirty.len(),
+            spilled.as_ref().map_or(0, Frozen::len)
+        );
+        Ok(Snapshot::new(
+            self.name.clone(),
+            self.size,
+            self.map.clone(),
+            dirty,
+            self.backend.clone(),
+            Rc::clone(&self.cache),
+            self.opts.verify,
+        )
+        .spilled(spilled))
+    }
+
+    /// Changes the apparent size of the image. Only growing is supported and only if enabled in
+    /// the options. New chunks are backed by the zero page until they are written to; the part
+    /// of a partial last chunk beyond the old size is zeroed out. Images cannot grow beyond 2^54
+    /// bytes minus one chunk.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        if self.read_only() {
+            return Err(Error::ReadOnly);
+        }
+        self.load_if_empty()?;
+        if size == self.size {
+            return Ok(());
+        }
+        if size < self.size || size > MAX_SIZE || !self.opts.grow {
+            return Err(Error::Resize {
+                size: self.size,
+                requested: size,
+            });
+        }
+        let old = self.size;
+        let chunks = pos2chunk(size + OFFSET_MASK) as usize;
+        info!(
+            "{:?}: grow from {} to {} bytes ({} chunks)",
+            self.name, old, size, chunks
+        );
+        if chunks > self.map.len() {
+            self.map.resize(chunks, None);
+        }
+        self.size = size;
+        self.modified = true;
+        let tail = min(
+            (CHUNKSZ - (old & OFFSET_MASK) as usize) % CHUNKSZ,
+            (size - old) as usize,
+        );
+        if tail > 0 && self.map[pos2chunk(old) as usize].is_some() {
+            self.write_at(old, &vec![0; tail])?;
+        }
+        Ok(())
+    }
+
+    /// Pushes dirty pages out of memory while dirty pages exhaust the cache budget. Fails if the
+    /// budget is still exhausted afterwards, i.e. by dirty pages of other revisions (see
+    /// [FuseDirectory::make_room](struct.FuseDirectory.html#method.make_room)).
     fn writeback(&mut self) -> Result<()> {
-        while self.dirty.len() + 1 >= self.dirty.cap() {
-            let (seq, page) = self.dirty.pop_lru().unwrap();
-            debug!("{:?}: writeback #{}", self.name, seq);
-            let id = page.save(&self.backend)?;
-            self.map[seq as usize] = Some(id);
+        while self.cache.borrow().dirty_full() {
+            if !self.push_out()? {
+                return Err(Error::DirtyFull);
+            }
         }
         Ok(())
     }
 
+    /// Moves the least recently used dirty page into the revision's overlay file if one is
+    /// configured, or saves it as new chunk otherwise. In the latter case, the chunk map is
+    /// updated. Returns false if there is no dirty page.
+    fn push_out(&mut self) -> Result<bool> {
+        let (seq, page) = match self.dirty.pop_lru() {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        if let Some(dir) = &self.opts.overlay {
+            let overlay = match self.overlay.take() {
+                Some(o) => o,
+                None => Overlay::create(dir, &self.name)?,
+            };
+            let overlay = self.overlay.get_or_insert(overlay);
+            debug!("{:?}: spill #{}", self.name, seq);
+            if let Err(e) = overlay.put(seq, &page) {
+                self.dirty.put(seq, page);
+                return Err(e);
+            }
+        } else {
+            self.save(seq, page)?;
+        }
+        self.cache.borrow_mut().remove_dirty();
+        Ok(true)
+    }
+
+    #[cfg(not(feature = "read-only"))]
+    fn save(&mut self, seq: u32, page: Page) -> Result<()> {
+        debug!("{:?}: writeback #{}", self.name, seq);
+        match page.save(&self.backend) {
+            Ok(id) => {
+                self.map[seq as usize] = Some(id);
+                self.modified = true;
+                Ok(())
+            }
+            Err(e) => {
+                self.dirty.put(seq, page);
+                Err(e)
+            }
+        }
+    }
+
+    /// Without write access to the store, dirty pages cannot go anywhere but to an overlay. Without
+    /// overlay, further writes to new pages fail once the budget is exhausted.
+    #[cfg(feature = "read-only")]
+    fn save(&mut self, seq: u32, page: Page) -> Result<()> {
+        self.dirty.put(seq, page);
+        Err(Error::DirtyFull)
+    }
+
     /// Updates data in the dirty cache.
     fn write(&mut self, seq: u32, off: usize, buf: &[u8]) -> Result<usize> {
         // resets reference count
@@ -299,67 +726,178 @@ impl FuseAccess {
         if let Some(page) = self.dirty.get_mut(&seq) {
             debug!("{:?}: hit #{} (write)", self.name, seq);
             page.update(off, buf);
-        } else if let Some(mut page) = self.ro_cache.pop(&seq) {
-            info!("{:?}: dirty #{}", self.name, seq);
-            self.writeback()?;
-            page.update(off, buf);
-            self.dirty.put(seq, page);
         } else {
             self.alloc(seq, off, buf)?;
         }
         Ok(buf.len())
     }
 
-    /// Creates a new page in the dirty cache either from disk or as empty page. Writes `buf` into
-    /// that page.
+    /// Creates a new page in the dirty cache from the page cache, from disk or as empty page.
+    /// Writes `buf` into that page. The original page stays in the page cache since other
+    /// revisions may reference the same chunk.
     fn alloc(&mut self, seq: u32, off: usize, buf: &[u8]) -> Result<()> {
         self.writeback()?;
-        let mut page = if self.map[seq as usize].is_some() {
-            info!("{:?}: load #{} (write)", self.name, seq);
-            Page::load(&self.map, &self.backend, seq)?
-        } else {
-            debug!("{:?}: zero #{} (write)", self.name, seq);
-            self.zero_page.clone().set_seq(seq)
+        let spilled = self.overlay.as_ref().and_then(|o| o.get(seq));
+        let mut page = match (spilled, self.map[seq as usize].clone()) {
+            (Some(data), _) => {
+                debug!("{:?}: unspill #{}", self.name, seq);
+                (data?, seq).into()
+            }
+            (None, Some(id)) => match self.cached(&id, seq) {
+                Some(page) => {
+                    info!("{:?}: dirty #{}", self.name, seq);
+                    page
+                }
+                None => {
+                    debug!("{:?}: fetch #{} (write)", self.name, seq);
+                    let page = self.fetch(seq)?;
+                    self.cache.borrow_mut().insert(id, Rc::clone(&page.data));
+                    page
+                }
+            },
+            (None, None) => {
+                debug!("{:?}: zero #{} (write)", self.name, seq);
+                self.zero_page.clone().set_seq(seq)
+            }
         };
         page.update(off, buf);
         self.dirty.put(seq, page);
+        self.cache.borrow_mut().add_dirty();
+        self.invalidate(seq);
         Ok(())
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct FuseDirectory {
     pub basedir: PathBuf,
+    pub cache: SharedCache,
     revs: HashMap<u64, FuseAccess>,
 }
 
 impl FuseDirectory {
-    pub fn init<P: AsRef<Path>>(dir: P, cache_size: usize) -> Result<Self> {
-        let dir = dir.as_ref();
+    #[cfg(test)]
+    pub fn init<P: AsRef<Path>>(dir: P, cache_size: usize, opts: &Options) -> Result<Self> {
+        Self::merge(&[dir], cache_size, opts)
+    }
+
+    /// Presents the revisions of several stores, e.g. a local store and an offsite replica, as
+    /// one directory. Chunks missing in a revision's own store are loaded from the other stores.
+    /// If a revision exists in more than one store, the first one wins.
+    pub fn merge<P: AsRef<Path>>(dirs: &[P], cache_size: usize, opts: &Options) -> Result<Self> {
+        let dirs: Vec<&Path> = dirs.iter().map(AsRef::as_ref).collect();
+        let first = *dirs
+            .first()
+            .ok_or_else(|| Error::NoRevisions(PathBuf::new()))?;
+        for dir in &dirs {
+            if !dir.join("chunks").exists() {
+                return Err(Error::NoRevisions(dir.to_path_buf()));
+            }
+        }
+        let stores = dirs
+            .iter()
+            .map(|d| Backend::open(d).map(|be| be.with_reader(opts.reader)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let cache = PageCache::shared(cache_size);
         let mut d = Self {
-            basedir: dir.to_owned(),
+            basedir: first.to_owned(),
+            cache: Rc::clone(&cache),
             revs: HashMap::default(),
         };
-        for entry in fs::read_dir(&dir)? {
-            let e = entry?;
-            let p = PathBuf::from(e.file_name());
-            if p.extension().unwrap_or_default() == "rev" {
-                let ino = ID_SEQ.fetch_add(1, Ordering::SeqCst);
+        let mut seen = HashSet::new();
+        for (i, dir) in dirs.iter().enumerate() {
+            let mut others = stores.clone();
+            let backend = others.remove(i).with_fallback(others);
+            let mut index = RevIndex::open(dir);
+            for entry in fs::read_dir(&dir)? {
+                let e = entry?;
+                let p = PathBuf::from(e.file_name());
+                if p.extension().unwrap_or_default() != "rev" {
+                    continue;
+                }
                 let rid = p
                     .file_stem()
                     .ok_or_else(|| Error::InvalidName(p.to_owned()))?
                     .to_str()
                     .ok_or_else(|| Error::InvalidName(p.to_owned()))?;
-                let f = FuseAccess::new(&dir, rid, cache_size)?;
-                d.revs.insert(ino, f);
+                if !seen.insert(rid.to_owned()) {
+                    debug!("Revision {} in '{}' is shadowed", rid, dir.display());
+                    continue;
+                }
+                let rev = index.load(&e.path())?;
+                let f = FuseAccess::with_backend(
+                    backend.clone(),
+                    rid,
+                    rev,
+                    Rc::clone(&cache),
+                    opts.clone(),
+                );
+                match opts.require_trust {
+                    Some(required) if f.rev.trust < required => {
+                        info!("Hiding {} revision {}", f.rev.trust, rid)
+                    }
+                    _ => {
+                        let ino = ID_SEQ.fetch_add(1, Ordering::SeqCst);
+                        d.revs.insert(ino, f);
+                    }
+                }
+            }
+            if cfg!(not(feature = "read-only")) {
+                if let Err(e) = index.save() {
+                    debug!(
+                        "Failed to update revision index in {}: {}",
+                        dir.display(),
+                        e
+                    );
+                }
             }
         }
-        if !d.is_empty() && dir.join("chunks").exists() {
+        if !d.is_empty() {
             Ok(d)
         } else {
-            Err(Error::NoRevisions(PathBuf::from(dir)))
+            Err(Error::NoRevisions(first.to_owned()))
         }
     }
+
+    /// Pushes out dirty pages of other revisions while they exhaust the cache budget and
+    /// revision `ino` has no dirty pages of its own to make room with. Called before writes so
+    /// that the budget holds across all revisions.
+    pub fn make_room(&mut self, ino: u64) -> Result<()> {
+        match self.revs.get(&ino) {
+            Some(rev) if rev.dirty.is_empty() => (),
+            _ => return Ok(()),
+        }
+        while self.cache.borrow().dirty_full() {
+            let other = self
+                .revs
+                .values_mut()
+                .filter(|rev| !rev.dirty.is_empty())
+                .max_by_key(|rev| rev.dirty.len());
+            match other {
+                Some(rev) => {
+                    rev.push_out()?;
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Evicts cached data of all revisions which have been idle for at least `timeout`. Returns
+    /// the number of revisions affected.
+    pub fn evict_idle(&mut self, timeout: Duration) -> usize {
+        let (idle, rest): (Vec<_>, Vec<_>) =
+            self.revs.values_mut().partition(|rev| rev.idle(timeout));
+        if idle.is_empty() {
+            return 0;
+        }
+        let shared: HashSet<&ChunkId> = rest
+            .iter()
+            .filter(|rev| rev.active())
+            .flat_map(|rev| rev.map.iter().flatten())
+            .collect();
+        idle.into_iter().map(|rev| rev.evict(&shared)).count()
+    }
 }
 
 impl Deref for FuseDirectory {
@@ -381,11 +919,13 @@ mod test {
     use super::*;
     use crate::chunkvec::ChunkId;
     use crate::test_helper::*;
+    use crate::testing::{self, Slot, StoreBuilder};
     use crate::{chunk2pos, CHUNKSZ_LOG};
     use backend::RevId;
 
     use chrono::{TimeZone, Utc};
     use maplit::hashmap;
+    use proptest::prelude::*;
     use std::collections::HashMap;
     use std::fs;
     use tempdir::TempDir;
@@ -402,45 +942,12 @@ mod test {
 
     pub fn store(spec: HashMap<RevId, Vec<Option<Vec<u8>>>>) -> TempDir {
         let td = TempDir::new("backy-store-test").expect("tempdir");
-        let p = td.path();
-        for (rev, data) in spec {
-            fs::write(
-                p.join(&rev.as_str()).with_extension("rev"),
-                format!(
-                    r#"backend_type: chunked
-parent: JZ3zfSHq24Fy5ENgTgYLGF
-stats:
-    bytes_written: {written}
-    ceph-verification: partial
-    chunk_stats: {{write_full: {nchunks}, write_partial: 0}}
-    duration: 216.60814833641052
-tags: [daily]
-timestamp: 2019-11-14 14:21:18.289+00:00
-trust: trusted
-uuid: {rev}
-"#,
-                    written = chunk2pos((data.len() + 1) as u32),
-                    nchunks = data.len(),
-                    rev = rev.as_str()
-                ),
-            )
-            .expect("write .rev");
-            let mut map = RevisionMap {
-                size: (data.len() << CHUNKSZ_LOG) as u64,
-                mapping: Default::default(),
-            };
-            fs::create_dir(p.join("chunks")).ok();
-            fs::write(p.join("chunks/store"), "v2").unwrap();
-            let be = Backend::open(&p).unwrap();
-            for (i, chunk) in data.into_iter().enumerate() {
-                if let Some(c) = chunk {
-                    let id = Page::new(c, i as u32).save(&be).unwrap();
-                    map.mapping.insert(i.to_string().into(), id);
-                }
-            }
-            let f = fs::File::create(p.join(rev.as_str())).unwrap();
-            serde_json::to_writer(f, &map).unwrap();
-        }
+        spec.into_iter()
+            .fold(StoreBuilder::new(), |b, (rev, data)| {
+                b.revision(rev.as_str(), data)
+            })
+            .write(td.path())
+            .expect("write store");
         td
     }
 
@@ -453,6 +960,44 @@ uuid: {rev}
             Utc.ymd(2019, 11, 14).and_hms_milli(14, 21, 18, 289)
         );
         assert_eq!(rev.uuid, rid("pqEKi7Jfq4bps3NVNEU49K"));
+        assert_eq!(rev.trust, Trust::Trusted);
+        Ok(())
+    }
+
+    #[test]
+    fn rev_trust() -> Result<()> {
+        let yaml = "backend_type: chunked\n\
+                    timestamp: 2019-11-14 14:21:18.289+00:00\n\
+                    uuid: pqEKi7Jfq4bps3NVNEU49K\n";
+        let parse = |extra: &str| Rev::parse(&format!("{}{}", yaml, extra), "t.rev".into());
+        assert_eq!(parse("")?.trust, Trust::Unknown);
+        assert_eq!(parse("trust: null\n")?.trust, Trust::Unknown);
+        assert_eq!(parse("trust: sort of\n")?.trust, Trust::Unknown);
+        assert_eq!(parse("trust: distrusted\n")?.trust, Trust::Distrusted);
+        assert_eq!(parse("trust: verified\n")?.trust, Trust::Verified);
+        assert!(Trust::Distrusted < Trust::Unknown && Trust::Trusted < Trust::Verified);
+        assert!("sort of".parse::<Trust>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn hide_untrusted_revisions() -> Result<()> {
+        let s = store(hashmap! {
+            rid("pqEKi7Jfq4bps3NVNEU49K") => vec![],
+            rid("ra2kM8HNWMpT6F9FAJx6Lc") => vec![],
+        });
+        let rev = s.path().join("ra2kM8HNWMpT6F9FAJx6Lc.rev");
+        let yaml = fs::read_to_string(&rev)?.replace("trust: trusted", "trust: distrusted");
+        fs::write(&rev, yaml)?;
+        let mut opts = Options::default();
+        assert_eq!(FuseDirectory::init(s.path(), 16 << 20, &opts)?.len(), 2);
+        opts.require_trust = Some(Trust::Trusted);
+        let d = FuseDirectory::init(s.path(), 16 << 20, &opts)?;
+        assert_eq!(d.len(), 1);
+        assert_eq!(
+            d.values().next().unwrap().rev.uuid,
+            "pqEKi7Jfq4bps3NVNEU49K"
+        );
         Ok(())
     }
 
@@ -505,19 +1050,333 @@ uuid: {rev}
             ]
         });
         let mut fuse = FuseAccess::load(s.path(), "cachingfq4bps3NVNEU49K")?;
-        assert!(fuse.ro_cache.is_empty());
+        let id = |n| cid(&testing::chunk_id(&vec![n; SZ]));
+        assert_eq!(fuse.cache.borrow().len(), 0);
         fuse.read_at(chunk2pos(1), 1)?;
-        assert!(fuse.ro_cache.get(&1).is_some());
+        assert!(fuse.cache.borrow().contains(&id(1)));
         fuse.read_at(chunk2pos(0), 1)?;
-        assert_eq!(fuse.ro_cache.len(), 2);
-        assert!(fuse.ro_cache.get(&0).is_some());
+        assert_eq!(fuse.cache.borrow().len(), 2);
+        assert!(fuse.cache.borrow().contains(&id(0)));
         fuse.read_at(chunk2pos(1), 1)?;
-        assert_eq!(fuse.ro_cache.len(), 2);
+        assert_eq!(fuse.cache.borrow().len(), 2);
         fuse.write_at(chunk2pos(2), &[1])?;
-        assert!(fuse.ro_cache.get(&2).is_none());
-        assert!(fuse.dirty.get(&2).is_some());
+        // original contents remain available for other revisions
+        assert_eq!(fuse.cache.borrow_mut().get(&id(2)).unwrap()[0], 2);
+        assert_eq!(fuse.dirty.get(&2).unwrap()[0], 1);
         assert_eq!(fuse.dirty.len(), 1);
-        assert_eq!(fuse.ro_cache.len(), 2);
+        // budget of 3 pages: the least recently used clean page makes room for the dirty one
+        assert_eq!(fuse.cache.borrow().len(), 2);
+        assert!(!fuse.cache.borrow().contains(&id(0)));
+        Ok(())
+    }
+
+    #[test]
+    fn cache_shared_between_revisions() -> Result<()> {
+        let s = store(hashmap! {
+            rid("SharedAjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ]), Some(vec![2u8; SZ])],
+            rid("SharedBjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ]), Some(vec![3u8; SZ])],
+        });
+        // room for two pages
+        let cache = PageCache::shared(2 * SZ);
+        let mut a = FuseAccess::new(
+            s.path(),
+            "SharedAjMDZmMWQ5Y2JkMG",
+            Rc::clone(&cache),
+            Options::default(),
+        )?;
+        let mut b = FuseAccess::new(
+            s.path(),
+            "SharedBjMDZmMWQ5Y2JkMG",
+            Rc::clone(&cache),
+            Options::default(),
+        )?;
+        a.load_if_empty()?;
+        b.load_if_empty()?;
+        assert_eq!(a.read_at(0, 1)?, &[1]);
+        fs::remove_file(testing::chunk_path(
+            s.path(),
+            &testing::chunk_id(&vec![1u8; SZ]),
+        ))?;
+        // served from the page cached for `a`
+        assert_eq!(b.read_at(0, 1)?, &[1]);
+        assert_eq!(cache.borrow().len(), 1);
+        a.read_at(chunk2pos(1), 1)?;
+        b.read_at(chunk2pos(1), 1)?;
+        assert_eq!(cache.borrow().len(), 2);
+        // dirty pages count against the same budget
+        a.write_at(chunk2pos(1), &[9])?;
+        assert_eq!(cache.borrow().len(), 1);
+        assert_eq!(b.read_at(chunk2pos(1), 1)?, &[3]);
+        assert_eq!(cache.borrow().stats(), (2, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn merge_stores() -> Result<()> {
+        let local = store(hashmap! {
+            rid("LocalAjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ]), Some(vec![2u8; SZ])],
+        });
+        let offsite = store(hashmap! {
+            rid("LocalAjMDZmMWQ5Y2JkMG") => vec![Some(vec![7u8; SZ])],
+            rid("RemoteBMDZmMWQ5Y2JkMG") => vec![Some(vec![2u8; SZ])],
+        });
+        // partially purged
+        fs::remove_file(testing::chunk_path(
+            local.path(),
+            &testing::chunk_id(&vec![2u8; SZ]),
+        ))?;
+        let mut dir = FuseDirectory::merge(
+            &[local.path(), offsite.path()],
+            12 << 20,
+            &Options::default(),
+        )?;
+        let mut names: Vec<_> = dir.values().map(|r| r.name.clone()).collect();
+        names.sort();
+        assert_eq!(names, &["LocalAjMDZmMWQ5Y2JkMG", "RemoteBMDZmMWQ5Y2JkMG"]);
+        let a = dir
+            .values_mut()
+            .find(|r| r.name == "LocalAjMDZmMWQ5Y2JkMG")
+            .unwrap();
+        a.load_if_empty()?;
+        // the local copy of the revision shadows the offsite one
+        assert_eq!(a.size, chunk2pos(2));
+        assert_eq!(a.read_at(0, 1)?, &[1]);
+        assert_eq!(a.read_at(chunk2pos(1), 1)?, &[2]);
+        // a single store misses the chunk
+        let mut single = FuseDirectory::init(local.path(), 12 << 20, &Options::default())?;
+        let a = single.values_mut().next().unwrap();
+        a.load_if_empty()?;
+        assert!(a.read_at(chunk2pos(1), 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn dirty_budget_spans_revisions() -> Result<()> {
+        let s = store(hashmap! {
+            rid("DirtAAjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ]); 3],
+            rid("DirtBBjMDZmMWQ5Y2JkMG") => vec![Some(vec![2u8; SZ])],
+        });
+        let td = TempDir::new("overlay")?;
+        let opts = Options {
+            overlay: Some(td.path().to_owned()),
+            ..Options::default()
+        };
+        let mut dir = FuseDirectory::init(s.path(), 12 << 20, &opts)?;
+        let ino = |dir: &FuseDirectory, name: &str| {
+            *dir.iter().find(|(_, rev)| rev.name == name).unwrap().0
+        };
+        let (a, b) = (
+            ino(&dir, "DirtAAjMDZmMWQ5Y2JkMG"),
+            ino(&dir, "DirtBBjMDZmMWQ5Y2JkMG"),
+        );
+        for &i in &[a, b] {
+            dir.get_mut(&i).unwrap().open()?;
+        }
+        for seq in 0..3 {
+            dir.get_mut(&a).unwrap().write_at(chunk2pos(seq), &[9])?;
+        }
+        // `a` exhausts the budget of 3 pages on its own
+        assert!(matches!(
+            dir.get_mut(&b).unwrap().write_at(0, &[8]),
+            Err(Error::DirtyFull)
+        ));
+        dir.make_room(b)?;
+        dir.get_mut(&b).unwrap().write_at(0, &[8])?;
+        assert_eq!(dir.cache.borrow().dirty_pages(), 3);
+        assert_eq!((dir[&a].dirty.len(), dir[&b].dirty.len()), (2, 1));
+        assert_eq!(dir.get_mut(&a).unwrap().read_at(0, 1)?, &[9]);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_chunks_of_active_revisions() -> Result<()> {
+        let s = store(hashmap! {
+            rid("KeepAAjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ]), Some(vec![2u8; SZ])],
+            rid("KeepBBjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ])],
+        });
+        let mut dir = FuseDirectory::init(s.path(), 12 << 20, &Options::default())?;
+        let ino = |dir: &FuseDirectory, name: &str| {
+            *dir.iter().find(|(_, rev)| rev.name == name).unwrap().0
+        };
+        let (a, b) = (
+            ino(&dir, "KeepAAjMDZmMWQ5Y2JkMG"),
+            ino(&dir, "KeepBBjMDZmMWQ5Y2JkMG"),
+        );
+        for &i in &[a, b] {
+            let rev = dir.get_mut(&i).unwrap();
+            rev.open()?;
+            rev.read_at(0, 1)?;
+        }
+        dir.get_mut(&a).unwrap().read_at(chunk2pos(1), 1)?;
+        assert_eq!(dir.cache.borrow().len(), 2);
+        dir.get_mut(&a).unwrap().release();
+        assert_eq!(dir.evict_idle(Duration::from_secs(0)), 1);
+        // chunk 1 is still in use by `b`
+        let id = cid(&testing::chunk_id(&vec![1u8; SZ]));
+        assert_eq!(dir.cache.borrow().len(), 1);
+        assert!(dir.cache.borrow().contains(&id));
+        Ok(())
+    }
+
+    #[test]
+    fn evict_idle_revisions() -> Result<()> {
+        let s = store(hashmap! {
+            rid("IdleAAjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ]), Some(vec![2u8; SZ])],
+            rid("IdleBBjMDZmMWQ5Y2JkMG") => vec![Some(vec![3u8; SZ])],
+        });
+        let mut dir = FuseDirectory::init(s.path(), 12 << 20, &Options::default())?;
+        let ino = |dir: &FuseDirectory, name: &str| {
+            *dir.iter().find(|(_, rev)| rev.name == name).unwrap().0
+        };
+        let (a, b) = (
+            ino(&dir, "IdleAAjMDZmMWQ5Y2JkMG"),
+            ino(&dir, "IdleBBjMDZmMWQ5Y2JkMG"),
+        );
+        for &i in &[a, b] {
+            let rev = dir.get_mut(&i).unwrap();
+            rev.open()?;
+            rev.read_at(0, 1)?;
+        }
+        dir.get_mut(&a).unwrap().write_at(chunk2pos(1), &[9])?;
+        // budget of 3 pages, one of them dirty
+        assert_eq!(dir.cache.borrow().len(), 2);
+        // open revisions are never evicted
+        assert_eq!(dir.evict_idle(Duration::from_secs(0)), 0);
+        dir.get_mut(&a).unwrap().release();
+        dir.get_mut(&b).unwrap().release();
+        assert_eq!(dir.evict_idle(Duration::from_secs(3600)), 0);
+        assert_eq!(dir.evict_idle(Duration::from_secs(0)), 2);
+        assert_eq!(dir.cache.borrow().len(), 0);
+        let (ra, rb) = (&dir[&a], &dir[&b]);
+        // map of `a` is still needed to resolve its dirty page
+        assert_eq!((ra.map.len(), ra.dirty.len()), (2, 1));
+        assert!(rb.map.is_empty());
+        // evicted revisions are not evicted again until they have been used
+        assert_eq!(dir.evict_idle(Duration::from_secs(0)), 0);
+        let rev = dir.get_mut(&b).unwrap();
+        rev.open()?;
+        assert_eq!(rev.read_at(0, 1)?, &[3]);
+        assert_eq!(dir.get_mut(&a).unwrap().read_at(chunk2pos(1), 1)?, &[9]);
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_copies() -> Result<()> {
+        let s = store(hashmap! { rid("MetaAAjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ])] });
+        let mut fuse = FuseAccess::load(s.path(), "MetaAAjMDZmMWQ5Y2JkMG")?;
+        let rev = fs::read(s.path().join("MetaAAjMDZmMWQ5Y2JkMG.rev"))?;
+        assert_eq!(fuse.meta(Meta::Rev)?, &rev[..]);
+        // served from memory
+        fs::remove_file(s.path().join("MetaAAjMDZmMWQ5Y2JkMG"))?;
+        assert!(fuse.meta(Meta::Map).is_err());
+        assert_eq!(fuse.meta(Meta::Rev)?, &rev[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn prefetch_strided_reads() -> Result<()> {
+        let pages: Vec<_> = (0..16u8).map(|i| Some(vec![i; SZ])).collect();
+        let s = store(hashmap! { rid("PrefetchMDZmMWQ5Y2JkMG") => pages });
+        let opts = Options {
+            prefetch: 2,
+            ..Options::default()
+        };
+        let cache = PageCache::shared(16 * SZ);
+        let mut fuse = FuseAccess::new(s.path(), "PrefetchMDZmMWQ5Y2JkMG", cache, opts)?;
+        fuse.open()?;
+        for seq in (0..16).step_by(3) {
+            assert_eq!(fuse.read_at(chunk2pos(seq), 1)?, &[seq as u8]);
+        }
+        // the pattern is confirmed at #9, then #12 and #15 are loaded ahead
+        let stats = String::from_utf8(fuse.meta(Meta::Stats)?.to_vec()).unwrap();
+        assert!(stats.contains("prefetch_stride: 3\n"), "{}", stats);
+        assert!(stats.contains("prefetch_issued: 2\n"), "{}", stats);
+        assert!(stats.contains("prefetch_used: 2\n"), "{}", stats);
+        assert!(stats.contains("prefetch_hit_rate: 1.000\n"), "{}", stats);
+        // snapshot until refreshed
+        fuse.read_at(chunk2pos(1), 1)?;
+        assert!(fuse.meta(Meta::Stats)?.ends_with(b"1.000\n"));
+        fuse.refresh_meta(Meta::Stats);
+        let stats = String::from_utf8(fuse.meta(Meta::Stats)?.to_vec()).unwrap();
+        assert!(stats.contains("prefetch_stride: none\n"), "{}", stats);
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_revisions() -> Result<()> {
+        let s = store(hashmap! { rid("RdOnlyjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ])] });
+        let mut fuse = FuseAccess::load(s.path(), "RdOnlyjMDZmMWQ5Y2JkMG")?;
+        fuse.opts.grow = true;
+        fuse.opts.read_only_tags = vec!["weekly".to_owned()];
+        assert!(!fuse.read_only());
+        fuse.write_at(0, &[2])?;
+        // test revisions are tagged `daily'
+        fuse.opts.read_only_tags.push("daily".to_owned());
+        assert!(fuse.read_only());
+        assert!(matches!(fuse.write_at(0, &[3]), Err(Error::ReadOnly)));
+        assert!(matches!(fuse.resize(2 * SZ as u64), Err(Error::ReadOnly)));
+        assert_eq!(fuse.read_at(0, 2)?, &[2, 1]);
+        fuse.opts = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        assert!(matches!(fuse.write_at(0, &[3]), Err(Error::ReadOnly)));
+        Ok(())
+    }
+
+    #[test]
+    fn grow_image() -> Result<()> {
+        let s = store(hashmap! { rid("GrowAAjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ])] });
+        let mut fuse = FuseAccess::load(s.path(), "GrowAAjMDZmMWQ5Y2JkMG")?;
+        assert!(matches!(
+            fuse.resize(3 * SZ as u64),
+            Err(Error::Resize { size, .. }) if size == SZ as u64
+        ));
+        fuse.opts.grow = true;
+        assert!(fuse.resize(SZ as u64 / 2).is_err());
+        assert!(matches!(fuse.resize(1 << 54), Err(Error::Resize { .. })));
+        assert!(fuse.resize(u64::MAX).is_err());
+        // pretend that the stored image ends in the middle of its chunk
+        fuse.size = SZ as u64 / 2;
+        fuse.resize(2 * SZ as u64 + 10)?;
+        assert_eq!(fuse.size, 2 * SZ as u64 + 10);
+        assert_eq!(fuse.map.len(), 3);
+        assert_eq!(fuse.read_at(0, 4)?, &[1, 1, 1, 1]);
+        assert_eq!(fuse.read_at(SZ as u64 / 2, 4)?, &[0, 0, 0, 0]);
+        assert_eq!(fuse.read_at(chunk2pos(2), 10)?, &[0; 10]);
+        // reads stop at the unaligned end
+        assert_eq!(fuse.read_at(chunk2pos(2) + 8, 10)?, &[0, 0]);
+        assert_eq!(fuse.read_at(chunk2pos(2), SZ)?.len(), 10);
+        fuse.write_at(chunk2pos(1) + 5, &[7, 7])?;
+        assert_eq!(fuse.read_at(chunk2pos(1) + 4, 4)?, &[0, 7, 7, 0]);
+        assert!(fuse.write_at(chunk2pos(2) + 8, &[7, 7, 7]).is_err());
+        // the stored map is unaffected
+        assert_eq!(
+            fs::read(s.path().join("GrowAAjMDZmMWQ5Y2JkMG"))?,
+            fuse.meta(Meta::Map)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_is_frozen() -> Result<()> {
+        let s = store(hashmap! {
+            rid("SnapAAjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ]), None, Some(vec![3u8; SZ])]
+        });
+        let mut fuse = FuseAccess::load(s.path(), "SnapAAjMDZmMWQ5Y2JkMG")?;
+        fuse.write_at(0, &[9, 9])?;
+        let mut snap = fuse.snapshot()?;
+        // later modifications are not visible in the snapshot
+        fuse.write_at(1, &[8, 8])?;
+        fuse.write_at(chunk2pos(1), &[7])?;
+        fuse.opts.grow = true;
+        fuse.resize(4 * SZ as u64)?;
+        assert_eq!(fuse.read_at(0, 4)?, &[9, 8, 8, 1]);
+        assert_eq!(snap.read_at(0, 4)?, &[9, 9, 1, 1]);
+        assert_eq!(snap.read_at(chunk2pos(1), 2)?, &[0, 0]);
+        assert_eq!(snap.read_at(chunk2pos(2) + SZ as u64 - 2, 10)?, &[3, 3]);
+        assert_eq!(snap.read_at(chunk2pos(3), 10)?, &[] as &[u8]);
+        assert!(snap.read_at(chunk2pos(3) + 1, 10).is_err());
         Ok(())
     }
 
@@ -553,7 +1412,7 @@ uuid: {rev}
             rid("XmE1MThjMDZmMWQ5Y2JkMG") => vec![Some(vec![10u8; SZ]), Some(data)]
         });
         let mut fuse = FuseAccess::load(s.path(), "XmE1MThjMDZmMWQ5Y2JkMG")?;
-        assert!(fuse.ro_cache.is_empty());
+        assert_eq!(fuse.cache.borrow().len(), 0);
         // write at beginning boundary
         assert_eq!(fuse.write_at(0, &[0, 1, 2, 3])?, 4);
         assert_eq!(fuse.read_at(0, 5)?, &[0, 1, 2, 3, 10]);
@@ -586,9 +1445,8 @@ uuid: {rev}
     fn write_cow_empty_page() -> Result<()> {
         let s = store(hashmap! { rid("YmE1MThjMDZmMWQ5Y2JkMG") => vec![None] });
         let mut fuse = FuseAccess::load(s.path(), "YmE1MThjMDZmMWQ5Y2JkMG")?;
-        assert!(fuse.ro_cache.get(&0).is_none());
         assert_eq!(fuse.write_at(2, &[1])?, 1);
-        assert!(fuse.ro_cache.get(&0).is_none());
+        assert_eq!(fuse.cache.borrow().len(), 0);
         assert_eq!(&fuse.dirty.get(&0).unwrap().data[0..5], &[0, 0, 1, 0, 0]);
         assert_eq!(fuse.read_at(0, 5)?, &[0, 0, 1, 0, 0]);
         Ok(())
@@ -621,14 +1479,156 @@ uuid: {rev}
             .open(s.path().join("brokenhjMDZmMWQ5Y2JkMG"))?
             .set_len(30)?;
         // expected to succeed because map is not read at this step
-        let mut fuse = FuseAccess::new(s.path(), "brokenhjMDZmMWQ5Y2JkMG", 0)?;
+        let mut fuse = FuseAccess::new(
+            s.path(),
+            "brokenhjMDZmMWQ5Y2JkMG",
+            PageCache::shared(0),
+            Options::default(),
+        )?;
         match fuse.load_if_empty() {
-            Err(e @ Error::ParseMap { .. }) => println!("expected Err: {}", e),
+            Err(e @ Error::Map { .. }) => println!("expected Err: {}", e),
             res @ _ => panic!("Unexpected result: {:?}", res),
         }
         Ok(())
     }
 
+    #[test]
+    fn hydrate() -> Result<()> {
+        let s = store(hashmap! {
+            rid("hydratejMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ]), None, Some(vec![2u8; SZ])]
+        });
+        let td = TempDir::new("hydrate")?;
+        let mut fuse = FuseAccess::new(
+            s.path(),
+            "hydratejMDZmMWQ5Y2JkMG",
+            PageCache::shared(12 << 20),
+            Options {
+                hydrate: Some(td.path().to_owned()),
+                ..Options::default()
+            },
+        )?;
+        fuse.load_if_empty()?;
+        fuse.hydrate()?;
+        fuse.hydration.as_mut().unwrap().wait();
+        assert_eq!(fuse.hydration.as_ref().unwrap().hydrated(), 3);
+        // the backend is not needed anymore
+        fs::remove_dir_all(s.path().join("chunks"))?;
+        assert_eq!(fuse.read_at(chunk2pos(2), 2)?, &[2, 2]);
+        assert_eq!(fuse.write_at(1, &[7])?, 1);
+        assert_eq!(fuse.read_at(0, 2)?, &[1, 7]);
+        assert_eq!(fuse.hydration.as_ref().unwrap().hydrated(), 2);
+        assert_eq!(
+            fs::read(td.path().join("hydratejMDZmMWQ5Y2JkMG.img"))?,
+            testing::image(&[Some(vec![1u8; SZ]), None, Some(vec![2u8; SZ])])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn spill_to_overlay() -> Result<()> {
+        let s = store(hashmap! {
+            rid("overlayjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ]), None, Some(vec![2u8; SZ])]
+        });
+        let td = TempDir::new("overlay")?;
+        let mut fuse = FuseAccess::new(
+            s.path(),
+            "overlayjMDZmMWQ5Y2JkMG",
+            PageCache::shared(SZ),
+            Options {
+                overlay: Some(td.path().to_owned()),
+                ..Options::default()
+            },
+        )?;
+        fuse.load_if_empty()?;
+        let chunks = fs::read_dir(s.path().join("chunks"))?.count();
+        for seq in 0..3 {
+            assert_eq!(fuse.write_at(chunk2pos(seq) + 1, &[7])?, 1);
+        }
+        assert_eq!(fuse.overlay.as_ref().unwrap().len(), 2);
+        assert_eq!(fuse.dirty.len(), 1);
+        // nothing went into the store and the map is untouched
+        assert_eq!(fs::read_dir(s.path().join("chunks"))?.count(), chunks);
+        assert!(!fuse.modified);
+        assert_eq!(fuse.read_at(0, 3)?, &[1, 7, 1]);
+        assert_eq!(fuse.read_at(chunk2pos(1), 3)?, &[0, 7, 0]);
+        assert_eq!(fuse.read_at(chunk2pos(2), 3)?, &[2, 7, 2]);
+        // modifying a spilled page brings it back into memory
+        assert_eq!(fuse.write_at(2, &[8])?, 1);
+        assert_eq!(fuse.read_at(0, 3)?, &[1, 7, 8]);
+        let mut snap = fuse.snapshot()?;
+        assert_eq!(snap.read_at(chunk2pos(1), 2)?, &[0, 7]);
+        // spilling a newer version leaves the snapshot's copy alone
+        fuse.write_at(chunk2pos(1), &[5])?;
+        while fuse.push_out()? {}
+        assert_eq!(fuse.read_at(chunk2pos(1), 2)?, &[5, 7]);
+        assert_eq!(snap.read_at(chunk2pos(2), 2)?, &[2, 7]);
+        assert_eq!(snap.read_at(chunk2pos(1), 2)?, &[0, 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_detects_swapped_chunk() -> Result<()> {
+        let s = store(hashmap! {
+            rid("verifyJjMDZmMWQ5Y2JkMG") => vec![Some(vec![1u8; SZ]), Some(vec![2u8; SZ])]
+        });
+        let path = |data: &[u8]| {
+            let id = backend::hash(data);
+            s.path()
+                .join("chunks")
+                .join(&id[0..2])
+                .join(format!("{}.chunk.lzo", id))
+        };
+        let (victim, other) = (path(&[1u8; SZ]), path(&[2u8; SZ]));
+        fs::copy(&other, &victim)?;
+        let opts = Options {
+            verify: true,
+            ..Options::default()
+        };
+        let mut fuse = FuseAccess::new(
+            s.path(),
+            "verifyJjMDZmMWQ5Y2JkMG",
+            PageCache::shared(0),
+            opts,
+        )?;
+        fuse.load_if_empty()?;
+        match fuse.read_at(0, 1) {
+            Err(Error::Checksum { actual, .. }) => assert_eq!(actual, backend::hash(&[2u8; SZ])),
+            res => panic!("expected Error::Checksum, got {:?}", res),
+        }
+        assert_eq!(fuse.read_at(chunk2pos(1), 1)?, &[2]);
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(8))]
+
+        #[test]
+        fn roundtrip_synthetic_store(
+            slots in prop::collection::vec(
+                prop_oneof![Just(Slot::Hole), any::<u8>().prop_map(Slot::Data)],
+                1..5,
+            ),
+            readsz in 1usize..(1 << 20),
+        ) {
+            let chunks = testing::layout(&slots);
+            let td = TempDir::new("backy-store-test").unwrap();
+            StoreBuilder::new()
+                .revision("PropRevision0000000000", chunks.clone())
+                .write(td.path())
+                .unwrap();
+            let mut fuse = FuseAccess::load(td.path(), "PropRevision0000000000").unwrap();
+            let mut img = Vec::new();
+            loop {
+                let data = fuse.read_at(img.len() as u64, readsz).unwrap();
+                if data.is_empty() {
+                    break;
+                }
+                img.extend_from_slice(data);
+            }
+            prop_assert!(img == testing::image(&chunks));
+        }
+    }
+
     #[test]
     /// Verify that the hash function produces the same hash value as found in the supplied
     /// fixture.
@@ -0,0 +1,158 @@
+//! Per-revision access rules for shared restore hosts.
+//!
+//! An ACL file is a YAML list of rules. Each rule names the users (`uids`) and groups (`gids`)
+//! it applies to and the revisions it grants access to: revisions carrying any of `tags`,
+//! revisions listed in `revisions` or all revisions of the backup directories in `stores`. A rule
+//! without any of the latter three grants access to everything. Example:
+//!
+//! ```yaml
+//! - uids: [1001]
+//!   stores: [/srv/backy/customer-vm0]
+//! - gids: [500]
+//!   tags: [daily]
+//! - uids: [1000]
+//! ```
+//!
+//! Revisions which are not granted to a user are hidden from that user. root has access to
+//! everything.
+
+use serde::Deserialize;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read ACL file '{}'", .0.display())]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Invalid ACL file '{}'", .0.display())]
+    Parse(PathBuf, #[source] serde_yaml::Error),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    #[serde(default)]
+    uids: Vec<u32>,
+    #[serde(default)]
+    gids: Vec<u32>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    revisions: Vec<String>,
+    #[serde(default)]
+    stores: Vec<PathBuf>,
+}
+
+impl Rule {
+    fn applies(&self, uid: u32, gid: u32) -> bool {
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+
+    fn grants(&self, store: &Path, name: &OsStr, tags: &[String]) -> bool {
+        if self.tags.is_empty() && self.revisions.is_empty() && self.stores.is_empty() {
+            return true;
+        }
+        self.tags.iter().any(|t| tags.contains(t))
+            || self.revisions.iter().any(|r| OsStr::new(r) == name)
+            || (!self.stores.is_empty() && self.stores.contains(&real(store)))
+    }
+}
+
+// Resolves symlinks and relative paths so that rules match however the store has been passed on
+// the command line. Paths which don't exist (yet) are compared as given.
+fn real(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_owned())
+}
+
+/// Set of access rules. Access is denied unless a rule grants it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| Error::Read(path.to_owned(), e))?;
+        Self::parse(&text).map_err(|e| Error::Parse(path.to_owned(), e))
+    }
+
+    fn parse(yaml: &str) -> Result<Self, serde_yaml::Error> {
+        let mut rules: Vec<Rule> = serde_yaml::from_str(yaml)?;
+        for r in &mut rules {
+            for s in &mut r.stores {
+                *s = real(s);
+            }
+        }
+        Ok(Self { rules })
+    }
+
+    /// Returns true if user `uid` with primary group `gid` may access revision `name` with
+    /// `tags` in backup directory `store`.
+    pub fn permits(&self, uid: u32, gid: u32, store: &Path, name: &OsStr, tags: &[String]) -> bool {
+        uid == 0
+            || self
+                .rules
+                .iter()
+                .any(|r| r.applies(uid, gid) && r.grants(store, name, tags))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    const RULES: &str = "\
+- uids: [1001]
+  stores: [/srv/backy/vm0]
+- gids: [500]
+  tags: [daily]
+  revisions: [VNzWKjnMqd6w58nzJwUZ98]
+- uids: [1000]
+";
+
+    #[test]
+    fn rules() {
+        let acl = Acl::parse(RULES).unwrap();
+        let vm0 = Path::new("/srv/backy/vm0");
+        let vm1 = Path::new("/srv/backy/vm1");
+        let rev = OsStr::new("tAGKE5rrxReggVMtoPSr7");
+        let daily = &["daily".to_owned()];
+        assert!(acl.permits(1001, 100, vm0, rev, &[]));
+        assert!(!acl.permits(1001, 100, vm1, rev, daily));
+        assert!(acl.permits(1002, 500, vm1, rev, daily));
+        assert!(!acl.permits(1002, 500, vm1, rev, &[]));
+        assert!(acl.permits(1002, 500, vm1, OsStr::new("VNzWKjnMqd6w58nzJwUZ98"), &[]));
+        assert!(acl.permits(1000, 100, vm1, rev, &[]));
+        assert!(!acl.permits(1003, 100, vm0, rev, daily));
+        assert!(acl.permits(0, 0, vm1, rev, &[]));
+        // no rules at all: only root
+        assert!(!Acl::parse("[]").unwrap().permits(1000, 100, vm0, rev, &[]));
+    }
+
+    #[test]
+    fn compare_real_paths() {
+        let td = TempDir::new("acl").unwrap();
+        let store = td.path().join("vm0");
+        fs::create_dir(&store).unwrap();
+        std::os::unix::fs::symlink(&store, td.path().join("link")).unwrap();
+        let acl = Acl::parse(&format!(
+            "- uids: [1001]\n  stores: ['{}/link']",
+            td.path().display()
+        ))
+        .unwrap();
+        let rev = OsStr::new("tAGKE5rrxReggVMtoPSr7");
+        assert!(acl.permits(1001, 100, &store, rev, &[]));
+        assert!(acl.permits(1001, 100, &store.join("../vm0"), rev, &[]));
+        assert!(!acl.permits(1001, 100, td.path(), rev, &[]));
+    }
+
+    #[test]
+    fn reject_typos() {
+        assert!(Acl::parse("- uid: [1000]").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! Page cache shared by all revisions of a FUSE mount.
+//!
+//! Clean pages are keyed by chunk ID, so that revisions which reference the same chunk share a
+//! single copy. Dirty pages are owned by the individual revisions but count against the same
+//! memory budget.
+
+use crate::chunkvec::ChunkId;
+use crate::CHUNKSZ_LOG;
+
+use lru::LruCache;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+pub type SharedCache = Rc<RefCell<PageCache>>;
+
+pub struct PageCache {
+    /// Budget in pages
+    cap: usize,
+    /// Number of dirty pages held by all revisions
+    dirty: usize,
+    clean: LruCache<ChunkId, Rc<Vec<u8>>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl PageCache {
+    /// Creates a cache which holds up to `size` bytes (at least one page).
+    pub fn new(size: usize) -> Self {
+        Self {
+            cap: (size >> CHUNKSZ_LOG).max(1),
+            dirty: 0,
+            clean: LruCache::unbounded(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn shared(size: usize) -> SharedCache {
+        Rc::new(RefCell::new(Self::new(size)))
+    }
+
+    pub fn get(&mut self, id: &ChunkId) -> Option<Rc<Vec<u8>>> {
+        let res = self.clean.get(id).cloned();
+        match res {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        res
+    }
+
+    pub fn insert(&mut self, id: ChunkId, data: Rc<Vec<u8>>) {
+        self.clean.put(id, data);
+        self.shrink();
+    }
+
+    /// Drops a clean page. Returns true if it was present.
+    pub fn remove(&mut self, id: &ChunkId) -> bool {
+        self.clean.pop(id).is_some()
+    }
+
+    pub fn contains(&self, id: &ChunkId) -> bool {
+        self.clean.contains(id)
+    }
+
+    /// Number of clean pages
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.clean.len()
+    }
+
+    /// Number of dirty pages
+    #[cfg(test)]
+    pub fn dirty_pages(&self) -> usize {
+        self.dirty
+    }
+
+    /// Lookups answered from the cache and lookups which had to go to disk.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    /// Accounts for a new dirty page. Clean pages are evicted to make room.
+    pub fn add_dirty(&mut self) {
+        self.dirty += 1;
+        self.shrink();
+    }
+
+    /// Accounts for a dirty page which has been written back or spilled.
+    pub fn remove_dirty(&mut self) {
+        self.dirty -= 1;
+    }
+
+    /// Returns true if another dirty page would exceed the budget. The caller should write back
+    /// dirty pages first.
+    pub fn dirty_full(&self) -> bool {
+        self.dirty >= self.cap
+    }
+
+    fn shrink(&mut self) {
+        while self.clean.len() + self.dirty > self.cap && self.clean.pop_lru().is_some() {}
+    }
+}
+
+impl fmt::Debug for PageCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PageCache[{} clean, {} dirty, cap {}, {} hits, {} misses]",
+            self.clean.len(),
+            self.dirty,
+            self.cap,
+            self.hits,
+            self.misses
+        )
+    }
+}
@@ -0,0 +1,223 @@
+//! Process management: detaching from the calling process, pidfiles and unmounting the file
+//! system if backy-fuse goes away.
+
+use log::{error, info, warn};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+fn check(res: libc::c_int) -> io::Result<libc::c_int> {
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+/// Notification channel from the detached child to the waiting parent.
+#[derive(Debug)]
+pub struct Ready(File);
+
+impl Ready {
+    /// Tells the parent that the file system is mounted and cuts the remaining ties to it by
+    /// redirecting the standard streams to /dev/null.
+    pub fn notify(mut self) -> io::Result<()> {
+        self.0.write_all(&[0])?;
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")?;
+        for fd in 0..=2 {
+            check(unsafe { libc::dup2(null.as_raw_fd(), fd) })?;
+        }
+        Ok(())
+    }
+}
+
+/// Forks into the background. Must be called before any threads are started.
+///
+/// Only the child returns from this function. The parent waits until the child calls
+/// [Ready::notify] and exits with status 0 then. If the child terminates before, the parent exits
+/// with the child's exit status.
+pub fn detach() -> io::Result<Ready> {
+    let mut fds = [0; 2];
+    check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    let (rx, tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    match check(unsafe { libc::fork() })? {
+        0 => {
+            drop(rx);
+            check(unsafe { libc::setsid() })?;
+            Ok(Ready(tx))
+        }
+        child => {
+            drop(tx);
+            process::exit(wait(rx, child))
+        }
+    }
+}
+
+fn wait(mut rx: File, child: libc::pid_t) -> i32 {
+    let mut buf = [0];
+    loop {
+        match rx.read(&mut buf) {
+            Ok(1) => return 0,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            _ => break,
+        }
+    }
+    // child has gone away without notification
+    let mut status = 0;
+    match unsafe { libc::waitpid(child, &mut status, 0) } {
+        pid if pid == child && libc::WIFEXITED(status) && libc::WEXITSTATUS(status) != 0 => {
+            libc::WEXITSTATUS(status)
+        }
+        _ => 1,
+    }
+}
+
+/// Detaches `mountpoint` lazily, like `fusermount -u -z`.
+pub fn unmount(mountpoint: &Path) -> io::Result<()> {
+    let status = Command::new("fusermount")
+        .arg("-u")
+        .arg("-z")
+        .arg(mountpoint)
+        .stdin(Stdio::null())
+        .status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("fusermount failed ({})", status)))
+    }
+}
+
+/// Removes a dead mount which has been left behind by a crashed instance. Accessing such a
+/// mount point fails with ENOTCONN.
+pub fn clear_stale(mountpoint: &Path) -> io::Result<()> {
+    match fs::metadata(mountpoint) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => {
+            warn!("Removing stale mount on '{}'", mountpoint.display());
+            unmount(mountpoint)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug)]
+struct Mounted {
+    mountpoint: PathBuf,
+    active: AtomicBool,
+    /// A signal has been received
+    stop: AtomicBool,
+}
+
+impl Mounted {
+    // Unmounts at most once, no matter who comes first.
+    fn unmount(&self) {
+        if self.active.swap(false, Ordering::SeqCst) {
+            if let Err(e) = unmount(&self.mountpoint) {
+                error!("Cannot unmount '{}': {}", self.mountpoint.display(), e);
+            }
+        }
+    }
+}
+
+/// Unmounts the file system when dropped, when the process panics and when it receives SIGTERM,
+/// SIGINT or SIGHUP. Signals make the FUSE session return regularly, so that all other cleanup
+/// happens as usual. Install it before mounting and report the mount with
+/// [mounted](#method.mounted): a signal which arrives in between takes effect right after the
+/// mount instead of leaving it behind.
+#[derive(Debug)]
+pub struct AutoUnmount(Arc<Mounted>);
+
+impl AutoUnmount {
+    /// Must be called before any threads are started: signals are blocked for this thread and all
+    /// threads started later and handled by a dedicated thread instead.
+    pub fn install(mountpoint: &Path) -> io::Result<Self> {
+        let m = Arc::new(Mounted {
+            mountpoint: mountpoint.to_owned(),
+            active: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+        });
+        let set = unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for &sig in &[libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+                libc::sigaddset(&mut set, sig);
+            }
+            match libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) {
+                0 => set,
+                e => return Err(io::Error::from_raw_os_error(e)),
+            }
+        };
+        let sm = Arc::clone(&m);
+        thread::Builder::new()
+            .name("signals".into())
+            .spawn(move || loop {
+                let mut sig = 0;
+                if unsafe { libc::sigwait(&set, &mut sig) } == 0 {
+                    let again = sm.stop.swap(true, Ordering::SeqCst);
+                    if again && !sm.active.load(Ordering::SeqCst) {
+                        // unmounting did not help
+                        process::exit(128 + sig);
+                    }
+                    info!("Received signal {}, unmounting", sig);
+                    sm.unmount();
+                }
+            })?;
+        // release builds abort on panic without unwinding
+        let pm = Arc::clone(&m);
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            pm.unmount();
+            prev(info)
+        }));
+        Ok(Self(m))
+    }
+
+    /// To be called once the file system is mounted. Unmounts right away if a signal has been
+    /// received already.
+    pub fn mounted(&self) {
+        self.0.active.store(true, Ordering::SeqCst);
+        if self.0.stop.load(Ordering::SeqCst) {
+            info!("Signal received while mounting, unmounting");
+            self.0.unmount();
+        }
+    }
+
+    /// To be called when the file system has been unmounted by other means.
+    pub fn disarm(self) {
+        self.0.active.store(false, Ordering::SeqCst);
+        self.0.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for AutoUnmount {
+    fn drop(&mut self) {
+        self.0.unmount()
+    }
+}
+
+/// Contains the process ID as long as it exists.
+#[derive(Debug)]
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        fs::write(path, format!("{}\n", process::id()))?;
+        Ok(Self(path.to_owned()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
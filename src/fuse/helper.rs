@@ -0,0 +1,231 @@
+//! Calling convention of mount(8) helpers.
+//!
+//! For file systems of type `fuse.backyfuse`, mount(8) runs
+//!
+//! ```text
+//! mount.fuse.backyfuse DEVICE MOUNTPOINT [-sfnv] [-o OPTIONS]
+//! ```
+//!
+//! with the backup directory as DEVICE. backy-fuse follows this convention when it is invoked
+//! through a link with that name. This allows to declare mounts in /etc/fstab, e.g.:
+//!
+//! ```text
+//! /srv/backy/vm0  /mnt/backy-fuse  fuse.backyfuse  ro,noauto,cache=512  0 0
+//! ```
+
+use super::App;
+use crate::{error_chain, RevError};
+
+use std::ffi::{OsStr, OsString};
+use std::num::ParseIntError;
+use std::path::Path;
+use structopt::StructOpt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Argument is not valid UTF-8")]
+    NonUtf8,
+    #[error("-o needs an argument")]
+    MissingOptions,
+    #[error("Unknown flag -{0}")]
+    Flag(char),
+    #[error("Expected DEVICE and MOUNTPOINT")]
+    Usage,
+    #[error("Invalid value for mount option {0}")]
+    Value(&'static str, #[source] ParseIntError),
+    #[error("Invalid value for mount option require_trust")]
+    Trust(#[source] RevError),
+    #[error(transparent)]
+    Args(#[from] structopt::clap::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+pub const HELPER_NAME: &str = "mount.fuse.backyfuse";
+
+// Exit codes as documented in mount(8)
+const EX_USAGE: i32 = 1;
+const EX_FAIL: i32 = 32;
+
+/// Options which are meant for mount(8) itself and must not be passed to FUSE.
+const IGNORED: &[&str] = &[
+    "defaults", "auto", "noauto", "user", "users", "nouser", "owner", "group", "nofail", "_netdev",
+];
+
+/// Returns true if the program has been invoked as mount helper.
+pub fn is_helper(argv0: &OsStr) -> bool {
+    Path::new(argv0).file_name() == Some(OsStr::new(HELPER_NAME))
+}
+
+/// Translates one mount option into settings. Unknown options are passed on to FUSE.
+fn apply(app: &mut App, opt: &str) -> Result<()> {
+    let (key, val) = match opt.find('=') {
+        Some(i) => (&opt[..i], Some(&opt[i + 1..])),
+        None => (opt, None),
+    };
+    match (key, val) {
+        ("cache", Some(v)) => app.cache = v.parse().map_err(|e| Error::Value("cache", e))?,
+        ("hydrate", Some(v)) => app.hydrate = Some(v.into()),
+        ("overlay", Some(v)) => app.overlay = Some(v.into()),
+        ("acl", Some(v)) => app.acl = Some(v.into()),
+        ("store", Some(v)) => app.stores.push(v.into()),
+        ("verify", None) => app.verify = true,
+        ("grow", None) => app.grow = true,
+        // the kernel enforces it, but the file system should not prepare for writes either
+        ("ro", None) => {
+            app.read_only = true;
+            app.mountopts.push(opt.to_owned());
+        }
+        ("pidfile", Some(v)) => app.pidfile = Some(v.into()),
+        ("idle_timeout", Some(v)) => {
+            app.idle_timeout = v.parse().map_err(|e| Error::Value("idle_timeout", e))?
+        }
+        ("require_trust", Some(v)) => app.require_trust = Some(v.parse().map_err(Error::Trust)?),
+        ("prefetch", Some(v)) => {
+            app.prefetch = v.parse().map_err(|e| Error::Value("prefetch", e))?
+        }
+        (k, _) if IGNORED.contains(&k) || k.starts_with("x-") || k == "comment" => (),
+        _ => app.mountopts.push(opt.to_owned()),
+    }
+    Ok(())
+}
+
+/// Builds settings from helper arguments (without program name). Returns `None` if mount(8)
+/// asks to fake the mount.
+pub fn parse(args: &[OsString]) -> Result<Option<App>> {
+    let mut fake = false;
+    let mut positional = Vec::new();
+    let mut opts = Vec::new();
+    let mut args = args.iter().map(|a| a.to_str().ok_or(Error::NonUtf8));
+    while let Some(arg) = args.next() {
+        let arg = arg?;
+        if let Some(o) = arg.strip_prefix("-o") {
+            opts.push(match o {
+                "" => args.next().ok_or(Error::MissingOptions)??,
+                o => o,
+            });
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            for flag in arg[1..].chars() {
+                match flag {
+                    'f' => fake = true,
+                    // sloppy, no mtab, verbose
+                    's' | 'n' | 'v' => (),
+                    _ => return Err(Error::Flag(flag)),
+                }
+            }
+        } else {
+            positional.push(arg);
+        }
+    }
+    let mut app = match positional[..] {
+        [dev, mnt] => App::from_iter_safe(&[HELPER_NAME, "--basedir", dev, mnt])?,
+        _ => return Err(Error::Usage),
+    };
+    app.daemon = true;
+    app.mountopts.clear();
+    for opt in opts
+        .iter()
+        .flat_map(|o| o.split(','))
+        .filter(|o| !o.is_empty())
+    {
+        apply(&mut app, opt)?;
+    }
+    if !app
+        .mountopts
+        .iter()
+        .any(|o| o == "allow_root" || o == "allow_other")
+    {
+        app.mountopts.push("allow_root".into());
+    }
+    Ok(if fake { None } else { Some(app) })
+}
+
+/// Runs as mount helper and returns the exit code.
+pub fn main(args: &[OsString]) -> i32 {
+    let app = match parse(&args[1..]) {
+        Ok(Some(app)) => app,
+        Ok(None) => return 0,
+        Err(e) => {
+            eprintln!("{}: {}", HELPER_NAME, error_chain(&e));
+            eprintln!(
+                "Usage: {} DEVICE MOUNTPOINT [-sfnv] [-o OPTIONS]",
+                HELPER_NAME
+            );
+            return EX_USAGE;
+        }
+    };
+    match app.run() {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}: {}", HELPER_NAME, error_chain(&e));
+            EX_FAIL
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Trust;
+    use std::path::PathBuf;
+
+    fn args(a: &[&str]) -> Vec<OsString> {
+        a.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn fstab_options() -> Result<()> {
+        let app = parse(&args(&[
+            "/srv/backy/vm0",
+            "/mnt/backy",
+            "-n",
+            "-o",
+            "ro,noauto,x-systemd.automount,cache=512,verify,grow,hydrate=/var/tmp,nodev,require_trust=trusted,prefetch=16,store=/mnt/offsite/vm0,overlay=/var/tmp,acl=/etc/backy/acl.yaml",
+        ]))?
+        .unwrap();
+        assert_eq!(app.basedir, PathBuf::from("/srv/backy/vm0"));
+        assert_eq!(app.mountpoint, PathBuf::from("/mnt/backy"));
+        assert_eq!(app.mountopts, &["ro", "nodev", "allow_root"]);
+        assert!(app.read_only);
+        assert_eq!(app.cache, 512);
+        assert!(app.verify);
+        assert!(app.grow);
+        assert_eq!(app.hydrate, Some(PathBuf::from("/var/tmp")));
+        assert_eq!(app.overlay, Some(PathBuf::from("/var/tmp")));
+        assert_eq!(app.acl, Some(PathBuf::from("/etc/backy/acl.yaml")));
+        assert_eq!(app.require_trust, Some(Trust::Trusted));
+        assert_eq!(app.prefetch, 16);
+        assert_eq!(app.stores, &[PathBuf::from("/mnt/offsite/vm0")]);
+        assert!(app.daemon);
+        // defaults apply to everything else
+        assert_eq!(app.idle_timeout, 30);
+        Ok(())
+    }
+
+    #[test]
+    fn helper_invocation() {
+        assert!(is_helper(OsStr::new("/sbin/mount.fuse.backyfuse")));
+        assert!(!is_helper(OsStr::new("backy-fuse")));
+        assert!(parse(&args(&["/srv/backy/vm0", "/mnt", "-sf"]))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            parse(&args(&["/srv/backy/vm0"])),
+            Err(Error::Usage)
+        ));
+        assert!(matches!(
+            parse(&args(&["/srv/backy/vm0", "/mnt", "-x"])),
+            Err(Error::Flag('x'))
+        ));
+        assert!(matches!(
+            parse(&args(&["/srv/backy/vm0", "/mnt", "-o", "cache=lots"])),
+            Err(Error::Value("cache", _))
+        ));
+        let app = parse(&args(&["/srv/backy/vm0", "/mnt", "-oallow_other"]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(app.mountopts, &["allow_other"]);
+        assert!(!app.read_only);
+    }
+}
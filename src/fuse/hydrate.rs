@@ -0,0 +1,162 @@
+//! Background hydration of revisions into local image files.
+//!
+//! A hydration thread copies all chunks of a revision sequentially into a sparse file. Chunks
+//! which have arrived there are read from the file instead of the backend, so that once
+//! hydration is complete the backup store is not touched anymore (except for written-back dirty
+//! pages).
+
+use super::access::{load_chunk, Error};
+use crate::backend::Backend;
+use crate::chunkvec::ChunkId;
+use crate::{chunk2pos, CHUNKSZ};
+
+use log::{error, info};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+const PENDING: u8 = 0;
+const HYDRATED: u8 = 1;
+// Modified in memory: the file contents must not be used anymore.
+const DIRTY: u8 = 2;
+
+#[derive(Debug)]
+struct Shared {
+    state: Vec<AtomicU8>,
+    done: AtomicUsize,
+    stop: AtomicBool,
+}
+
+/// Handle to a running (or finished) hydration.
+#[derive(Debug)]
+pub struct Hydration {
+    file: File,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Hydration {
+    /// Creates (or truncates) `path` and starts copying the chunks listed in `map` into it.
+    pub fn start(
+        path: &Path,
+        backend: Backend,
+        map: Vec<Option<ChunkId>>,
+        verify: bool,
+    ) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(chunk2pos(map.len() as u32))?;
+        let shared = Arc::new(Shared {
+            state: map.iter().map(|_| AtomicU8::new(PENDING)).collect(),
+            done: AtomicUsize::new(0),
+            stop: AtomicBool::new(false),
+        });
+        let (out, s, p) = (file.try_clone()?, Arc::clone(&shared), path.to_owned());
+        let thread =
+            thread::Builder::new()
+                .name("hydrate".into())
+                .spawn(move || match Self::run(&out, &backend, &map, verify, &s) {
+                    Ok(()) if s.stop.load(Ordering::Relaxed) => (),
+                    Ok(()) => info!("Hydration of '{}' complete", p.display()),
+                    Err(e) => error!("Hydration of '{}' failed: {}", p.display(), e),
+                })?;
+        Ok(Self {
+            file,
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    fn run(
+        out: &File,
+        backend: &Backend,
+        map: &[Option<ChunkId>],
+        verify: bool,
+        s: &Shared,
+    ) -> Result<()> {
+        for (seq, id) in map.iter().enumerate() {
+            if s.stop.load(Ordering::Relaxed) {
+                break;
+            }
+            // holes are already present in the sparse file
+            if let Some(id) = id {
+                if s.state[seq].load(Ordering::Acquire) != PENDING {
+                    continue;
+                }
+                let data = load_chunk(backend, id, verify)?;
+                out.write_all_at(&data, chunk2pos(seq as u32))?;
+            }
+            // fails if the page has been dirtied in the meantime
+            if s.state[seq]
+                .compare_exchange(PENDING, HYDRATED, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                s.done.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the contents of chunk `seq` if it has been hydrated already.
+    pub fn read(&self, seq: u32) -> Option<Result<Vec<u8>>> {
+        if !self.has(seq) {
+            return None;
+        }
+        let mut buf = vec![0; CHUNKSZ];
+        Some(
+            self.file
+                .read_exact_at(&mut buf, chunk2pos(seq))
+                .map(|_| buf)
+                .map_err(Error::from),
+        )
+    }
+
+    /// Returns true if chunk `seq` can be served from the hydrated file. Chunks beyond the
+    /// hydrated image, e.g. after growing it, never are.
+    pub fn has(&self, seq: u32) -> bool {
+        match self.shared.state.get(seq as usize) {
+            Some(state) => state.load(Ordering::Acquire) == HYDRATED,
+            None => false,
+        }
+    }
+
+    /// Marks chunk `seq` as modified so that it is never read from the hydrated file again.
+    pub fn invalidate(&self, seq: u32) {
+        if let Some(state) = self.shared.state.get(seq as usize) {
+            if state.swap(DIRTY, Ordering::AcqRel) == HYDRATED {
+                self.shared.done.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Number of chunks which are served from the hydrated file.
+    pub fn hydrated(&self) -> usize {
+        self.shared.done.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the hydration thread has finished.
+    #[cfg(test)]
+    pub fn wait(&mut self) {
+        if let Some(t) = self.thread.take() {
+            t.join().expect("hydration thread panicked");
+        }
+    }
+}
+
+impl Drop for Hydration {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            t.join().ok();
+        }
+    }
+}
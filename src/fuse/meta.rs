@@ -0,0 +1,110 @@
+//! The read-only `meta/` directory.
+//!
+//! For each revision, `meta/` contains a verbatim copy of its `.rev` file as `<rev>.yaml` and
+//! of its chunk map as `<rev>.map.json`. `<rev>.stats` shows cache and prefetch counters and is
+//! generated whenever it is opened. `<rev>.snapshot.img` presents the image including all
+//! modifications, frozen when the file is opened. Inode numbers of these files are derived from
+//! the revision's inode number.
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+/// Inode of the `meta/` directory
+pub const META_INO: u64 = 2;
+pub const META_DIR: &str = "meta";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Meta {
+    /// `.rev` file
+    Rev = 0,
+    /// Chunk map
+    Map = 1,
+    /// Cache and prefetch statistics
+    Stats = 2,
+    /// Frozen copy of the image
+    Snapshot = 3,
+}
+
+impl Meta {
+    pub const ALL: [Meta; 4] = [Meta::Rev, Meta::Map, Meta::Stats, Meta::Snapshot];
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Meta::Rev => ".yaml",
+            Meta::Map => ".map.json",
+            Meta::Stats => ".stats",
+            Meta::Snapshot => ".snapshot.img",
+        }
+    }
+
+    /// Inode of this file for the revision with inode `rev`.
+    pub fn ino(self, rev: u64) -> u64 {
+        debug_assert!(rev >> 32 == 0);
+        rev | (self as u64 + 1) << 32
+    }
+
+    /// Splits a meta file inode into revision inode and kind.
+    pub fn from_ino(ino: u64) -> Option<(u64, Meta)> {
+        let rev = ino & 0xffff_ffff;
+        match ino >> 32 {
+            1 => Some((rev, Meta::Rev)),
+            2 => Some((rev, Meta::Map)),
+            3 => Some((rev, Meta::Stats)),
+            4 => Some((rev, Meta::Snapshot)),
+            _ => None,
+        }
+    }
+
+    /// Name of this file inside `meta/`.
+    pub fn file_name(self, rev: &OsStr) -> OsString {
+        let mut n = rev.to_owned();
+        n.push(self.suffix());
+        n
+    }
+
+    /// Parses a file name inside `meta/` into revision name and kind.
+    pub fn parse(name: &OsStr) -> Option<(&OsStr, Meta)> {
+        let name = name.as_bytes();
+        Self::ALL.iter().find_map(|&m| {
+            let s = m.suffix().as_bytes();
+            if name.len() > s.len() && name.ends_with(s) {
+                Some((OsStr::from_bytes(&name[..name.len() - s.len()]), m))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Name of the source file in the backup directory. None for generated files.
+    pub fn source(self, rev: &OsStr) -> Option<OsString> {
+        match self {
+            Meta::Rev => {
+                let mut n = rev.to_owned();
+                n.push(".rev");
+                Some(n)
+            }
+            Meta::Map => Some(rev.to_owned()),
+            Meta::Stats | Meta::Snapshot => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names_and_inodes() {
+        for &m in &Meta::ALL {
+            assert_eq!(Meta::from_ino(m.ino(17)), Some((17, m)));
+            let name = m.file_name(OsStr::new("VNzWKjnMqd6w58nzJwUZ98"));
+            assert_eq!(
+                Meta::parse(&name),
+                Some((OsStr::new("VNzWKjnMqd6w58nzJwUZ98"), m))
+            );
+        }
+        assert_eq!(Meta::from_ino(17), None);
+        assert_eq!(Meta::parse(OsStr::new(".yaml")), None);
+        assert_eq!(Meta::parse(OsStr::new("foo")), None);
+    }
+}
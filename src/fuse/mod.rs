@@ -1,25 +1,71 @@
 mod access;
+mod acl;
+mod cache;
+mod daemon;
+pub mod helper;
+mod hydrate;
+mod meta;
+mod overlay;
+mod prefetch;
+mod snapshot;
 
-use self::access::{FuseAccess, FuseDirectory};
-use crate::purgelock;
+pub use self::access::Error as AccessError;
+use self::access::{FuseAccess, FuseDirectory, Options};
+use self::acl::Acl;
+pub use self::acl::Error as AclError;
+use self::meta::{Meta, META_DIR, META_INO};
+use self::snapshot::Snapshot;
+use crate::{purgelock, MapCache, ReadStrategy, Trust};
 
-use anyhow::{Context, Result};
 use fuse::{
     FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
     ReplyOpen, ReplyStatfs, ReplyWrite, Request, FUSE_ROOT_ID,
 };
-use libc::{EINVAL, EIO, ENOENT, ENOTDIR};
-use log::{error, info, warn};
-use std::cmp::max;
+use libc::{c_int, EACCES, EBADF, EFBIG, EINVAL, EIO, ENOENT, ENOTDIR, EROFS, O_ACCMODE, O_RDONLY};
+use log::{debug, error, info, warn};
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ffi::{OsStr, OsString};
+use std::io;
+use std::iter;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
+use thiserror::Error;
 use time::Timespec;
 
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to detach")]
+    Detach(#[source] io::Error),
+    #[error("Failed to acquire .purge lock in '{}'", .0.display())]
+    Lock(PathBuf, #[source] io::Error),
+    #[error(transparent)]
+    Access(#[from] AccessError),
+    #[error(transparent)]
+    Acl(#[from] AclError),
+    #[error("Failed to clear stale mount")]
+    ClearStale(#[source] io::Error),
+    #[error("Failed to mount FUSE filesystem")]
+    Mount(#[source] io::Error),
+    #[error("Failed to install unmount handler")]
+    AutoUnmount(#[source] io::Error),
+    #[error("Failed to write pidfile")]
+    PidFile(#[source] io::Error),
+    #[error("FUSE session terminated")]
+    Session(#[source] io::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
 static TTL: Timespec = Timespec { sec: 1, nsec: 1 };
 
+// How often to look for idle revisions
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+// Bypass the page cache for a file (fuse_common.h)
+const FOPEN_DIRECT_IO: u32 = 1;
+
 static UNIX_EPOCH: Timespec = Timespec { sec: 0, nsec: 0 };
 
 static ROOT_NODE: FileAttr = FileAttr {
@@ -39,6 +85,12 @@ static ROOT_NODE: FileAttr = FileAttr {
     flags: 0,
 };
 
+static META_NODE: FileAttr = FileAttr {
+    ino: META_INO,
+    perm: 0o0555,
+    ..ROOT_NODE
+};
+
 fn fileattr(ino: u64, entry: &FuseAccess) -> FileAttr {
     let timestamp = Timespec::new(entry.rev.timestamp.timestamp(), 0);
     FileAttr {
@@ -50,7 +102,7 @@ fn fileattr(ino: u64, entry: &FuseAccess) -> FileAttr {
         ctime: timestamp,
         crtime: timestamp,
         kind: FileType::RegularFile,
-        perm: 0o0644,
+        perm: if entry.read_only() { 0o0444 } else { 0o0644 },
         nlink: 1,
         uid: 0,
         gid: 0,
@@ -59,25 +111,157 @@ fn fileattr(ino: u64, entry: &FuseAccess) -> FileAttr {
     }
 }
 
+fn metaattr(ino: u64, entry: &FuseAccess, size: u64) -> FileAttr {
+    FileAttr {
+        size,
+        blocks: size.div_ceil(512),
+        perm: 0o0444,
+        ..fileattr(ino, entry)
+    }
+}
+
 struct BackyFs {
     dir: FuseDirectory,
     reverse: HashMap<OsString, u64>,
+    idle_timeout: Option<Duration>,
+    acl: Option<Acl>,
+    last_sweep: Instant,
+    /// Open snapshot files, indexed by file handle
+    snapshots: HashMap<u64, Snapshot>,
+    next_fh: u64,
 }
 
 impl BackyFs {
-    fn init<P: AsRef<Path>>(dir: P, cache_size: usize) -> Result<Self> {
-        let dir = FuseDirectory::init(dir, cache_size)?;
+    fn init<P: AsRef<Path>>(dirs: &[P], cache_size: usize, opts: &Options) -> Result<Self> {
+        let dir = FuseDirectory::merge(dirs, cache_size, opts)?;
         let mut reverse = HashMap::new();
         for (ino, entry) in dir.iter() {
             reverse.insert(entry.name.to_owned(), *ino);
         }
-        Ok(Self { dir, reverse })
+        Ok(Self {
+            dir,
+            reverse,
+            idle_timeout: opts.idle_timeout,
+            acl: opts.acl.clone(),
+            last_sweep: Instant::now(),
+            snapshots: HashMap::new(),
+            next_fh: 1,
+        })
+    }
+
+    /// Evicts idle revisions. The fuse crate dispatches all requests from a single thread, so
+    /// this is done on incoming requests instead of from a timer.
+    fn sweep(&mut self) {
+        if let Some(timeout) = self.idle_timeout {
+            if self.last_sweep.elapsed() >= SWEEP_INTERVAL {
+                self.last_sweep = Instant::now();
+                if self.dir.evict_idle(timeout) > 0 {
+                    debug!("{:?}", self.dir.cache.borrow());
+                }
+            }
+        }
+    }
+
+    /// Returns true if the sender of `req` may see revision `rev`, i.e. if there is no ACL or
+    /// the ACL grants access.
+    fn permitted(&self, req: &Request, rev: u64) -> bool {
+        match (&self.acl, self.dir.get(&rev)) {
+            (None, _) => true,
+            (Some(acl), Some(e)) => {
+                acl.permits(req.uid(), req.gid(), e.store(), &e.name, &e.rev.tags)
+            }
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Loads a file in `meta/` and returns its attributes.
+    fn meta_attr(&mut self, rev: u64, kind: Meta) -> Result<FileAttr, c_int> {
+        let entry = self.dir.get_mut(&rev).ok_or(ENOENT)?;
+        let size = match kind {
+            Meta::Snapshot => entry.load_if_empty().map(|_| entry.size),
+            _ => entry.meta(kind).map(|data| data.len() as u64),
+        };
+        let size = match size {
+            Ok(size) => size,
+            Err(e) => {
+                error!("meta(0x{:x}, {:?}): {}", rev, kind, e);
+                return Err(EIO);
+            }
+        };
+        Ok(metaattr(kind.ino(rev), entry, size))
+    }
+
+    fn lookup_meta(&mut self, req: &Request, name: &OsStr, re: ReplyEntry) {
+        let attr = match Meta::parse(name) {
+            Some((rev, kind)) => match self.reverse.get(rev) {
+                Some(&ino) if self.permitted(req, ino) => self.meta_attr(ino, kind),
+                _ => Err(ENOENT),
+            },
+            None => Err(ENOENT),
+        };
+        match attr {
+            Ok(attr) => re.entry(&TTL, &attr, 0),
+            Err(e) => re.error(e),
+        }
+    }
+
+    /// Freezes revision `rev` and registers the snapshot under a new file handle.
+    fn open_snapshot(&mut self, rev: u64) -> Result<u64, c_int> {
+        let entry = self.dir.get_mut(&rev).ok_or(ENOENT)?;
+        let snap = entry.snapshot().map_err(|e| {
+            error!("snapshot(0x{:x}): {}", rev, e);
+            EIO
+        })?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.snapshots.insert(fh, snap);
+        Ok(fh)
+    }
+
+    fn read_snapshot(&mut self, fh: u64, off: i64, size: u32, re: ReplyData) {
+        let snap = match self.snapshots.get_mut(&fh) {
+            Some(snap) => snap,
+            None => return re.error(EBADF),
+        };
+        let mut off = off.max(0) as u64;
+        let mut buf = Vec::with_capacity(size as usize);
+        while buf.len() < size as usize {
+            match snap.read_at(off, size as usize - buf.len()) {
+                Ok([]) => break,
+                Ok(data) => {
+                    buf.extend_from_slice(data);
+                    off += data.len() as u64;
+                }
+                Err(e) => {
+                    error!("read({:?} @ {}): {}", snap, off, e);
+                    return re.error(EIO);
+                }
+            }
+        }
+        re.data(&buf)
+    }
+
+    fn read_meta(&mut self, rev: u64, kind: Meta, off: i64, size: u32, re: ReplyData) {
+        let entry = match self.dir.get_mut(&rev) {
+            Some(entry) => entry,
+            None => return re.error(ENOENT),
+        };
+        match entry.meta(kind) {
+            Ok(data) => {
+                let start = min(off.max(0) as usize, data.len());
+                re.data(&data[start..min(start + size as usize, data.len())])
+            }
+            Err(e) => {
+                error!("read(meta 0x{:x}, {:?}): {}", rev, kind, e);
+                re.error(EIO)
+            }
+        }
     }
 }
 
 macro_rules! reject_node1(
     ($op:expr, $ino:expr, $reply:expr) => {
-        if $ino == FUSE_ROOT_ID {
+        if $ino == FUSE_ROOT_ID || $ino == META_INO {
             error!("{}: trying to access directory as regular file", $op);
             $reply.error(EINVAL);
             return;
@@ -86,7 +270,16 @@ macro_rules! reject_node1(
 );
 
 impl Filesystem for BackyFs {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, re: ReplyEntry) {
+    fn destroy(&mut self, _req: &Request) {
+        let (hits, misses) = self.dir.cache.borrow().stats();
+        info!("Page cache: {} hits, {} misses", hits, misses);
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, re: ReplyEntry) {
+        self.sweep();
+        if parent == META_INO {
+            return self.lookup_meta(req, name, re);
+        }
         if parent != FUSE_ROOT_ID {
             warn!("lookup(): trying to use an invalid base directory");
             re.error(ENOENT);
@@ -94,12 +287,18 @@ impl Filesystem for BackyFs {
         }
         if name == "." || name == ".." {
             re.entry(&TTL, &ROOT_NODE, 0);
+        } else if name == META_DIR {
+            re.entry(&TTL, &META_NODE, 0);
         } else {
             let path = PathBuf::from(name);
-            if let Some(ino) = self.reverse.get(name) {
-                if let Some(entry) = self.dir.get_mut(ino) {
+            if let Some(&ino) = self.reverse.get(name) {
+                if !self.permitted(req, ino) {
+                    info!("lookup({:?}): access denied for uid {}", name, req.uid());
+                    return re.error(ENOENT);
+                }
+                if let Some(entry) = self.dir.get_mut(&ino) {
                     match entry.load_if_empty() {
-                        Ok(_) => re.entry(&TTL, &fileattr(*ino, entry), 0),
+                        Ok(_) => re.entry(&TTL, &fileattr(ino, entry), 0),
                         Err(e) => {
                             error!("lookup({:?}): {}", name, e);
                             re.error(EINVAL);
@@ -118,11 +317,26 @@ impl Filesystem for BackyFs {
         }
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, re: ReplyAttr) {
+    fn getattr(&mut self, req: &Request, ino: u64, re: ReplyAttr) {
         if ino == 1 {
             re.attr(&TTL, &ROOT_NODE);
             return;
         }
+        if ino == META_INO {
+            re.attr(&TTL, &META_NODE);
+            return;
+        }
+        let rev = Meta::from_ino(ino).map_or(ino, |(rev, _)| rev);
+        if !self.permitted(req, rev) {
+            return re.error(ENOENT);
+        }
+        if let Some((rev, kind)) = Meta::from_ino(ino) {
+            match self.meta_attr(rev, kind) {
+                Ok(attr) => re.attr(&TTL, &attr),
+                Err(e) => re.error(e),
+            }
+            return;
+        }
         if let Some(entry) = self.dir.get(&ino) {
             re.attr(&TTL, &fileattr(ino, entry));
         } else {
@@ -131,28 +345,135 @@ impl Filesystem for BackyFs {
         }
     }
 
-    fn readdir(&mut self, _r: &Request, ino: u64, _fh: u64, off: i64, mut re: ReplyDirectory) {
-        if ino != FUSE_ROOT_ID {
-            error!("readdir() failed - inode {} is not a directory", ino);
-            re.error(ENOTDIR);
-            return;
+    /// Only size changes are supported, i.e. growing images with `--grow`. Other attributes are
+    /// fixed and silently left alone.
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<Timespec>,
+        _mtime: Option<Timespec>,
+        _fh: Option<u64>,
+        _crtime: Option<Timespec>,
+        _chgtime: Option<Timespec>,
+        _bkuptime: Option<Timespec>,
+        _flags: Option<u32>,
+        re: ReplyAttr,
+    ) {
+        reject_node1!("setattr", ino, re);
+        if Meta::from_ino(ino).is_some() {
+            return re.error(EROFS);
         }
-        if off == 0 {
-            re.add(FUSE_ROOT_ID, 1, FileType::Directory, ".");
-            re.add(FUSE_ROOT_ID, 2, FileType::Directory, "..");
+        if !self.permitted(req, ino) {
+            info!("setattr(0x{:x}): access denied for uid {}", ino, req.uid());
+            return re.error(EACCES);
         }
-        if off < (self.dir.len() as i64) + 2 {
-            for (n, (ino, entry)) in self.dir.iter().enumerate().skip((off - 2).max(0) as usize) {
-                re.add(*ino, (n + 3) as i64, FileType::RegularFile, &entry.name);
+        if let Err(e) = self.dir.make_room(ino) {
+            error!("setattr(0x{:x}): {}", ino, e);
+            return re.error(EIO);
+        }
+        let entry = match self.dir.get_mut(&ino) {
+            Some(entry) => entry,
+            None => return re.error(ENOENT),
+        };
+        if let Some(size) = size {
+            match entry.resize(size) {
+                Ok(()) => (),
+                Err(AccessError::ReadOnly) => return re.error(EROFS),
+                Err(e @ AccessError::Resize { .. }) => {
+                    warn!("setattr(0x{:x}): {}", ino, e);
+                    return re.error(if size > entry.size { EFBIG } else { EINVAL });
+                }
+                Err(e) => {
+                    error!("setattr(0x{:x}): {}", ino, e);
+                    return re.error(EIO);
+                }
+            }
+        }
+        re.attr(&TTL, &fileattr(ino, entry))
+    }
+
+    fn readdir(&mut self, req: &Request, ino: u64, _fh: u64, off: i64, mut re: ReplyDirectory) {
+        let visible = self
+            .dir
+            .iter()
+            .filter(|(ino, _)| self.permitted(req, **ino));
+        let entries: Vec<(u64, FileType, OsString)> = match ino {
+            FUSE_ROOT_ID => iter::once((META_INO, FileType::Directory, META_DIR.into()))
+                .chain(visible.map(|(ino, e)| (*ino, FileType::RegularFile, e.name.clone())))
+                .collect(),
+            META_INO => visible
+                .flat_map(|(ino, e)| {
+                    Meta::ALL
+                        .iter()
+                        .map(move |m| (m.ino(*ino), FileType::RegularFile, m.file_name(&e.name)))
+                })
+                .collect(),
+            _ => {
+                error!("readdir() failed - inode {} is not a directory", ino);
+                re.error(ENOTDIR);
+                return;
+            }
+        };
+        let dots = vec![
+            (ino, FileType::Directory, ".".into()),
+            (FUSE_ROOT_ID, FileType::Directory, "..".into()),
+        ];
+        // offsets are 1-based: the kernel passes the offset of the last entry it has seen
+        for (n, (ino, kind, name)) in dots
+            .into_iter()
+            .chain(entries)
+            .enumerate()
+            .skip(off.max(0) as usize)
+        {
+            if re.add(ino, (n + 1) as i64, kind, name) {
+                break;
             }
         }
         re.ok()
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, re: ReplyOpen) {
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, re: ReplyOpen) {
         reject_node1!("open", ino, re);
+        self.sweep();
+        let rev = Meta::from_ino(ino).map_or(ino, |(rev, _)| rev);
+        if !self.permitted(req, rev) {
+            info!("open(0x{:x}): access denied for uid {}", ino, req.uid());
+            return re.error(EACCES);
+        }
+        if let Some((rev, kind)) = Meta::from_ino(ino) {
+            if flags & O_ACCMODE as u32 != O_RDONLY as u32 {
+                return re.error(EROFS);
+            }
+            if kind == Meta::Snapshot {
+                return match self.open_snapshot(rev) {
+                    Ok(fh) => re.opened(fh, FOPEN_DIRECT_IO),
+                    Err(e) => re.error(e),
+                };
+            }
+            if let Some(entry) = self.dir.get_mut(&rev) {
+                entry.refresh_meta(kind);
+            }
+            // generated files change size, so the kernel must not cache them
+            let flags = if kind == Meta::Stats {
+                FOPEN_DIRECT_IO
+            } else {
+                0
+            };
+            return match self.meta_attr(rev, kind) {
+                Ok(_) => re.opened(0, flags),
+                Err(e) => re.error(e),
+            };
+        }
         if let Some(entry) = self.dir.get_mut(&ino) {
-            match entry.load_if_empty() {
+            if entry.read_only() && flags & O_ACCMODE as u32 != O_RDONLY as u32 {
+                return re.error(EROFS);
+            }
+            match entry.open() {
                 Ok(_) => re.opened(0, 0),
                 Err(e) => {
                     error!("open(0x{:x}: {}", ino, e);
@@ -169,20 +490,36 @@ impl Filesystem for BackyFs {
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         _fl: u32,
         _owner: u64,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        if let Some(entry) = self.dir.get_mut(&ino) {
-            entry.cleanup();
-            reply.ok();
+        // files in meta/ need no cleanup except for snapshots
+        if let Some((_, Meta::Snapshot)) = Meta::from_ino(ino) {
+            self.snapshots.remove(&fh);
+        } else if let Some(entry) = self.dir.get_mut(&ino) {
+            entry.release();
         }
+        reply.ok();
+        self.sweep();
     }
 
-    fn read(&mut self, _r: &Request, ino: u64, _fh: u64, off: i64, size: u32, re: ReplyData) {
+    fn read(&mut self, req: &Request, ino: u64, fh: u64, off: i64, size: u32, re: ReplyData) {
         reject_node1!("read", ino, re);
+        self.sweep();
+        let rev = Meta::from_ino(ino).map_or(ino, |(rev, _)| rev);
+        if !self.permitted(req, rev) {
+            info!("read(0x{:x}): access denied for uid {}", ino, req.uid());
+            return re.error(EACCES);
+        }
+        if let Some((rev, kind)) = Meta::from_ino(ino) {
+            if kind == Meta::Snapshot {
+                return self.read_snapshot(fh, off, size, re);
+            }
+            return self.read_meta(rev, kind, off, size, re);
+        }
         if let Some(entry) = self.dir.get_mut(&ino) {
             let off: usize = off.try_into().unwrap();
             let size = size as usize;
@@ -219,7 +556,7 @@ impl Filesystem for BackyFs {
 
     fn write(
         &mut self,
-        _r: &Request,
+        req: &Request,
         ino: u64,
         _fh: u64,
         off: i64,
@@ -228,6 +565,17 @@ impl Filesystem for BackyFs {
         re: ReplyWrite,
     ) {
         reject_node1!("write", ino, re);
+        if Meta::from_ino(ino).is_some() {
+            return re.error(EROFS);
+        }
+        if !self.permitted(req, ino) {
+            info!("write(0x{:x}): access denied for uid {}", ino, req.uid());
+            return re.error(EACCES);
+        }
+        if let Err(e) = self.dir.make_room(ino) {
+            error!("write(0x{:x} @ {}): {}", ino, off, e);
+            return re.error(EIO);
+        }
         if let Some(entry) = self.dir.get_mut(&ino) {
             match entry.write_at(off.try_into().unwrap(), &data) {
                 Ok(n) if n == data.len() => re.written(n.try_into().unwrap()),
@@ -241,6 +589,7 @@ impl Filesystem for BackyFs {
                     );
                     re.error(EIO);
                 }
+                Err(AccessError::ReadOnly) => re.error(EROFS),
                 Err(e) => {
                     error!("write(0x{:x} @ {}): {}", ino, off, e);
                     re.error(EIO);
@@ -262,14 +611,14 @@ impl Filesystem for BackyFs {
             })
             .sum();
         re.statfs(
-            (total + 4095) / 4096,     // blocks
-            0,                         // bfree
-            0,                         // bavail
-            self.dir.len() as u64 + 2, // files
-            0,                         // ffree
-            4096,                      // bsize
-            1024,                      // namelen
-            4096,                      // fragment size
+            total.div_ceil(4096),          // blocks
+            0,                             // bfree
+            0,                             // bavail
+            5 * self.dir.len() as u64 + 3, // files
+            0,                             // ffree
+            4096,                          // bsize
+            1024,                          // namelen
+            4096,                          // fragment size
         )
     }
 }
@@ -285,6 +634,13 @@ pub struct App {
     /// Example: /srv/backy/vm0
     #[structopt(short = "d", long, value_name = "DIRECTORY", default_value = ".")]
     pub basedir: PathBuf,
+    /// Further backy base directory, e.g. an offsite replica
+    ///
+    /// Revisions of all stores are shown side by side. Chunks which are missing in a revision's
+    /// own store, e.g. after a partial purge, are loaded from the other stores. Repeat for more
+    /// stores.
+    #[structopt(long = "store", value_name = "DIRECTORY", number_of_values = 1)]
+    pub stores: Vec<PathBuf>,
     /// FUSE mount options
     ///
     /// See fuse(8) for possible values. Accepts multiple comma-separated
@@ -296,33 +652,159 @@ pub struct App {
         default_value = "allow_root"
     )]
     pub mountopts: Vec<String>,
-    /// Size of the chunk caches in MiB
+    /// Size of the page cache shared by all revisions in MiB
     #[structopt(short, long, value_name = "SIZE", default_value = "1024")]
     pub cache: usize,
+    /// Copy opened revisions into DIRECTORY in the background
+    ///
+    /// Each revision is written to DIRECTORY/<revision>.img as sparse file when it is opened for
+    /// the first time. Hydrated chunks are read from there instead of the backup store.
+    #[structopt(long, value_name = "DIRECTORY")]
+    pub hydrate: Option<PathBuf>,
+    /// Check every chunk loaded from the backup store against its checksum
+    ///
+    /// Reads which touch a corrupted chunk fail with EIO.
+    #[structopt(long)]
+    pub verify: bool,
+    /// Drop cached data of revisions which have not been open for MINUTES
+    ///
+    /// Dirty pages are kept. 0 disables eviction.
+    #[structopt(long, value_name = "MINUTES", default_value = "30")]
+    pub idle_timeout: u64,
+    /// Hide revisions which backy trusts less than LEVEL
+    ///
+    /// LEVEL is one of distrusted, unknown, trusted or verified. Revisions without trust
+    /// information count as unknown.
+    #[structopt(long, value_name = "LEVEL")]
+    pub require_trust: Option<Trust>,
+    /// Load up to N chunks ahead of regular access patterns
+    ///
+    /// Sequential reads as well as reads which skip a constant distance, like fsck visiting
+    /// filesystem metadata, are detected. Counters for tuning are shown in meta/<revision>.stats.
+    /// 0 disables prefetching.
+    #[structopt(long, value_name = "N", default_value = "4")]
+    pub prefetch: usize,
+    /// Allow images to be enlarged, e.g. with truncate(1) before resize2fs
+    ///
+    /// The added space reads as zeros. Like all writes, the new size is kept in memory only.
+    #[structopt(long)]
+    pub grow: bool,
+    /// Reject all writes with EROFS and mount read-only
+    ///
+    /// For setups which consider modifications held in memory a risk. Also prevents chunks from
+    /// being written back to the store once modified pages exhaust the cache.
+    #[structopt(long)]
+    pub read_only: bool,
+    /// Reject writes to revisions tagged TAG with EROFS
+    ///
+    /// Other revisions stay writable. Repeat for more tags.
+    #[structopt(long = "read-only-tag", value_name = "TAG", number_of_values = 1)]
+    pub read_only_tags: Vec<String>,
+    /// How chunk files are read: read or mmap
+    ///
+    /// `read' copies chunk files into a buffer, `mmap' decompresses them from memory mappings.
+    #[structopt(long, value_name = "HOW", default_value = "read")]
+    pub reader: ReadStrategy,
+    /// Cache parsed revision maps in DIRECTORY
+    ///
+    /// Revisions of large images then open much faster after the first time. Cache files are
+    /// renewed whenever a revision map changes.
+    #[structopt(long, value_name = "DIRECTORY")]
+    pub map_cache: Option<PathBuf>,
+    /// Restrict which users may see which revisions according to the rules in FILE
+    ///
+    /// FILE is a YAML list of rules, each granting the users in `uids` or groups in `gids`
+    /// access to revisions carrying any of `tags`, listed in `revisions` or stored in the
+    /// directories in `stores`. Other users get ENOENT or EACCES; root has access to everything.
+    /// Needs `-o allow_other` to let other users access the mount at all.
+    #[structopt(long, value_name = "FILE")]
+    pub acl: Option<PathBuf>,
+    /// Move modified pages into encrypted files in DIRECTORY once they exhaust the cache
+    ///
+    /// Without this option, such pages are saved as chunks in the backup store. Each revision
+    /// gets its own file and a random key which is never written anywhere. The files are
+    /// deleted right away and vanish with the process.
+    #[structopt(long, value_name = "DIRECTORY")]
+    pub overlay: Option<PathBuf>,
     #[structopt(name = "MOUNTPOINT")]
     /// Where to mount the FUSE filesystem [example: /mnt/backy-fuse]
     pub mountpoint: PathBuf,
+    /// Run in the background once the file system is mounted
+    #[structopt(long)]
+    pub daemon: bool,
+    /// Write the process ID to FILE while the file system is mounted
+    #[structopt(long, value_name = "FILE")]
+    pub pidfile: Option<PathBuf>,
 }
 
 impl App {
     pub fn run(&self) -> Result<()> {
-        let lock = purgelock(&self.basedir).context("Failed to acquire .purge lock")?;
+        let ready = if self.daemon {
+            Some(daemon::detach().map_err(Error::Detach)?)
+        } else {
+            None
+        };
+        let dirs: Vec<&Path> = iter::once(&self.basedir)
+            .chain(&self.stores)
+            .map(PathBuf::as_path)
+            .collect();
+        let locks = dirs
+            .iter()
+            .map(|d| purgelock(d).map_err(|e| Error::Lock(d.to_path_buf(), e)))
+            .collect::<Result<Vec<_>>>()?;
         info!("Loading revisions");
-        let fs = BackyFs::init(&self.basedir, max(self.cache, 16) << 20)?;
-        println!(
-            "Mounting FUSE fileystem... unmount with: fusermount -u '{}'",
-            self.mountpoint.display()
-        );
-        fuse::mount(
+        let opts = Options {
+            hydrate: self.hydrate.clone(),
+            verify: self.verify,
+            idle_timeout: match self.idle_timeout {
+                0 => None,
+                m => Some(Duration::from_secs(m * 60)),
+            },
+            require_trust: self.require_trust,
+            prefetch: self.prefetch,
+            grow: self.grow,
+            read_only: self.read_only,
+            read_only_tags: self.read_only_tags.clone(),
+            reader: self.reader,
+            map_cache: self.map_cache.clone().map(MapCache::new),
+            overlay: self.overlay.clone(),
+            acl: self.acl.as_ref().map(Acl::load).transpose()?,
+        };
+        let fs = BackyFs::init(&dirs, max(self.cache, 16) << 20, &opts)?;
+        if ready.is_none() {
+            println!(
+                "Mounting FUSE fileystem... unmount with: fusermount -u '{}'",
+                self.mountpoint.display()
+            );
+        }
+        daemon::clear_stale(&self.mountpoint).map_err(Error::ClearStale)?;
+        let mut mountopts = self.mountopts.clone();
+        if self.read_only && !mountopts.iter().any(|o| o == "ro") {
+            mountopts.push("ro".to_owned());
+        }
+        let guard = daemon::AutoUnmount::install(&self.mountpoint).map_err(Error::AutoUnmount)?;
+        let mut session = fuse::Session::new(
             fs,
             &self.mountpoint,
             &[&OsStr::new(&format!(
                 "-ofsname=backy,{}",
-                self.mountopts.join(",")
+                mountopts.join(",")
             ))],
         )
-        .context("Failed to mount FUSE filesystem")?;
-        drop(lock);
+        .map_err(Error::Mount)?;
+        guard.mounted();
+        let pidfile = match &self.pidfile {
+            Some(p) => Some(daemon::PidFile::create(p).map_err(Error::PidFile)?),
+            None => None,
+        };
+        if let Some(ready) = ready {
+            ready.notify().map_err(Error::Detach)?;
+        }
+        // returns regularly once the file system has been unmounted
+        session.run().map_err(Error::Session)?;
+        guard.disarm();
+        drop(pidfile);
+        drop(locks);
         Ok(())
     }
 }
@@ -0,0 +1,227 @@
+//! Encrypted on-disk storage for modified pages.
+//!
+//! Once dirty pages exhaust the cache budget, they are moved into an overlay file instead of
+//! being saved as chunks in the backup store. Each revision gets its own file and its own key.
+//! The key exists only in memory and the file is unlinked right after creation, so whatever has
+//! been written during an analysis session is unreadable after the process exits, even if the
+//! file's blocks are recovered from the restore host's disk.
+
+use super::access::Error;
+use crate::{chunk2pos, CHUNKSZ};
+
+use fnv::FnvHashMap as HashMap;
+use log::info;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::cell::RefCell;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+use std::rc::Rc;
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Location of a stored page in the overlay file. Slots which a snapshot still refers to are
+// never overwritten and return to the free list once the last reference is gone.
+struct Slot {
+    index: u32,
+    nonce: u64,
+    free: Rc<RefCell<Vec<u32>>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.free.borrow_mut().push(self.index);
+    }
+}
+
+/// Anonymous, encrypted page store of a single revision.
+pub struct Overlay {
+    file: Rc<File>,
+    key: [u8; 32],
+    pages: HashMap<u32, Rc<Slot>>,
+    slots: u32,
+    /// Slots which are not in use anymore
+    free: Rc<RefCell<Vec<u32>>>,
+    nonce: u64,
+}
+
+impl Overlay {
+    /// Creates an overlay file for revision `name` in `dir` and unlinks it immediately.
+    pub fn create(dir: &Path, name: &OsStr) -> Result<Self> {
+        let path = dir.join(format!(
+            ".{}.{:016x}.overlay",
+            name.to_string_lossy(),
+            rand::random::<u64>()
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        fs::remove_file(&path)?;
+        info!("{:?}: spilling modified pages to '{}'", name, dir.display());
+        Ok(Self {
+            file: Rc::new(file),
+            key: rand::random(),
+            pages: HashMap::default(),
+            slots: 0,
+            free: Rc::default(),
+            nonce: 0,
+        })
+    }
+
+    /// Stores page `seq`, replacing a previous version. Every write uses a fresh nonce so that
+    /// overwritten versions cannot be related to each other. A version which is part of a
+    /// [Frozen](struct.Frozen.html) view is left alone and the page goes into another slot.
+    pub fn put(&mut self, seq: u32, data: &[u8]) -> Result<()> {
+        assert_eq!(data.len(), CHUNKSZ, "overlay pages must be complete");
+        self.nonce += 1;
+        let mut buf = data.to_vec();
+        apply(&self.key, self.nonce, &mut buf);
+        // rewrite in place unless a frozen view still refers to the slot
+        if let Some(slot) = self.pages.get_mut(&seq).and_then(Rc::get_mut) {
+            self.file.write_all_at(&buf, chunk2pos(slot.index))?;
+            slot.nonce = self.nonce;
+            return Ok(());
+        }
+        let reused = self.free.borrow_mut().pop();
+        let index = match reused {
+            Some(index) => index,
+            None => {
+                self.slots += 1;
+                self.slots - 1
+            }
+        };
+        // dropped again if writing fails, which returns the slot to the free list
+        let slot = Slot {
+            index,
+            nonce: self.nonce,
+            free: Rc::clone(&self.free),
+        };
+        self.file.write_all_at(&buf, chunk2pos(index))?;
+        self.pages.insert(seq, Rc::new(slot));
+        Ok(())
+    }
+
+    /// Reads page `seq` back if it has been stored before.
+    pub fn get(&self, seq: u32) -> Option<Result<Vec<u8>>> {
+        Some(read(&self.file, &self.key, self.pages.get(&seq)?))
+    }
+
+    pub fn contains(&self, seq: u32) -> bool {
+        self.pages.contains_key(&seq)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Returns a view of all pages as they are stored now. Later writes don't affect it.
+    pub fn freeze(&self) -> Frozen {
+        Frozen {
+            file: Rc::clone(&self.file),
+            key: self.key,
+            pages: self.pages.clone(),
+        }
+    }
+}
+
+/// Pages of an [Overlay](struct.Overlay.html) at some point in time, see
+/// [freeze](struct.Overlay.html#method.freeze). Pages are read from the overlay file on demand.
+pub struct Frozen {
+    file: Rc<File>,
+    key: [u8; 32],
+    pages: HashMap<u32, Rc<Slot>>,
+}
+
+impl Frozen {
+    /// Reads page `seq` back if it has been stored before freezing.
+    pub fn get(&self, seq: u32) -> Option<Result<Vec<u8>>> {
+        Some(read(&self.file, &self.key, self.pages.get(&seq)?))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+// Reads and decrypts the page in `slot`.
+fn read(file: &File, key: &[u8; 32], slot: &Slot) -> Result<Vec<u8>> {
+    let mut buf = vec![0; CHUNKSZ];
+    file.read_exact_at(&mut buf, chunk2pos(slot.index))?;
+    apply(key, slot.nonce, &mut buf);
+    Ok(buf)
+}
+
+// XORs `buf` with the ChaCha20 key stream for `nonce`. Encrypts and decrypts alike.
+fn apply(key: &[u8; 32], nonce: u64, buf: &mut [u8]) {
+    let mut rng = ChaCha20Rng::from_seed(*key);
+    rng.set_stream(nonce);
+    let mut ks = vec![0; buf.len()];
+    rng.fill_bytes(&mut ks);
+    buf.iter_mut().zip(ks).for_each(|(b, k)| *b ^= k);
+}
+
+impl fmt::Debug for Overlay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Overlay[{} pages]", self.pages.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn pages_are_encrypted() -> Result<()> {
+        let tmp = TempDir::new("overlay")?;
+        let mut o = Overlay::create(tmp.path(), OsStr::new("rev"))?;
+        // file is gone from the directory right away
+        assert_eq!(fs::read_dir(tmp.path())?.count(), 0);
+        let page = vec![0x42; CHUNKSZ];
+        o.put(7, &page)?;
+        o.put(3, &vec![0x43; CHUNKSZ])?;
+        assert_eq!(o.get(7).unwrap()?, page);
+        assert!(o.get(0).is_none());
+        let mut raw = vec![0; CHUNKSZ];
+        o.file.read_exact_at(&mut raw, 0)?;
+        assert!(!raw.windows(16).any(|w| w == &page[..16]));
+        // rewrites stay in the same slot, but use a different key stream
+        o.put(7, &page)?;
+        let mut again = vec![0; CHUNKSZ];
+        o.file.read_exact_at(&mut again, 0)?;
+        assert_ne!(raw, again);
+        assert_eq!(o.get(7).unwrap()?, page);
+        assert_eq!(o.len(), 2);
+        assert_eq!(o.file.metadata()?.len(), 2 * CHUNKSZ as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn frozen_pages_are_kept() -> Result<()> {
+        let tmp = TempDir::new("overlay")?;
+        let mut o = Overlay::create(tmp.path(), OsStr::new("rev"))?;
+        o.put(7, &vec![1; CHUNKSZ])?;
+        o.put(3, &vec![2; CHUNKSZ])?;
+        let frozen = o.freeze();
+        o.put(7, &vec![3; CHUNKSZ])?;
+        assert_eq!(frozen.get(7).unwrap()?, vec![1; CHUNKSZ]);
+        assert_eq!(o.get(7).unwrap()?, vec![3; CHUNKSZ]);
+        assert!(frozen.get(5).is_none());
+        assert_eq!(o.file.metadata()?.len(), 3 * CHUNKSZ as u64);
+        // slots of dropped views are used again
+        drop(frozen);
+        o.put(3, &vec![4; CHUNKSZ])?;
+        o.put(5, &vec![5; CHUNKSZ])?;
+        assert_eq!(o.file.metadata()?.len(), 3 * CHUNKSZ as u64);
+        for (seq, b) in &[(3, 4), (5, 5), (7, 3)] {
+            assert_eq!(o.get(*seq).unwrap()?, vec![*b; CHUNKSZ]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,288 @@
+//! Prefetching of chunks based on the access pattern of a revision.
+//!
+//! Every page a revision moves to is fed into a detector which looks for a constant stride
+//! between consecutive pages. Sequential reads have a stride of 1. Tools like fsck which visit
+//! metadata scattered across the image, e.g. one block group every 128 MiB, show larger strides.
+//! Once the same stride has been seen three times in a row, the next pages along that stride are
+//! loaded by a background thread and put into the shared page cache.
+
+use super::access::{load_chunk, Error};
+use super::cache::PageCache;
+use crate::backend::Backend;
+use crate::chunkvec::ChunkId;
+
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::{debug, warn};
+use lru::LruCache;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+use std::thread::{self, JoinHandle};
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Number of equal strides needed to predict the next pages
+const CONFIRM: usize = 3;
+// Prefetched pages which have not been read yet are tracked up to this number
+const UNCLAIMED: usize = 1024;
+
+/// Prefetch counters of a revision.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Chunks handed to the background thread
+    pub issued: u64,
+    /// Prefetched chunks which have been read afterwards
+    pub used: u64,
+    /// Chunks which failed to load in the background
+    pub failed: u64,
+    /// Stride of the current access pattern in pages
+    pub stride: Option<i64>,
+}
+
+impl Stats {
+    /// Share of prefetched chunks which have actually been read.
+    pub fn hit_rate(&self) -> f64 {
+        match self.issued {
+            0 => 0.0,
+            n => self.used as f64 / n as f64,
+        }
+    }
+}
+
+/// Detects strided access and loads the predicted chunks in the background.
+pub struct Prefetcher {
+    /// Number of pages to load ahead
+    depth: usize,
+    backend: Backend,
+    verify: bool,
+    /// Most recently accessed pages, oldest first
+    recent: VecDeque<u32>,
+    worker: Option<Worker>,
+    inflight: HashSet<ChunkId>,
+    unclaimed: LruCache<ChunkId, ()>,
+    stats: Stats,
+}
+
+struct Worker {
+    tx: Sender<ChunkId>,
+    rx: Receiver<(ChunkId, Result<Vec<u8>>)>,
+    thread: JoinHandle<()>,
+}
+
+impl Prefetcher {
+    pub fn new(backend: Backend, verify: bool, depth: usize) -> Self {
+        Self {
+            depth,
+            backend,
+            verify,
+            recent: VecDeque::with_capacity(CONFIRM + 1),
+            worker: None,
+            inflight: HashSet::new(),
+            unclaimed: LruCache::new(UNCLAIMED),
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Records an access to page `seq`. Returns the pages which should be prefetched, nearest
+    /// first. `len` is the number of pages in the image.
+    pub fn access(&mut self, seq: u32, len: u32) -> Vec<u32> {
+        if self.recent.back() == Some(&seq) {
+            return Vec::new();
+        }
+        if self.recent.len() > CONFIRM {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(seq);
+        let strides: Vec<i64> = self
+            .recent
+            .iter()
+            .zip(self.recent.iter().skip(1))
+            .map(|(&a, &b)| i64::from(b) - i64::from(a))
+            .collect();
+        self.stats.stride = match strides[..] {
+            [s, ref rest @ ..] if rest.len() + 1 == CONFIRM && rest.iter().all(|&r| r == s) => {
+                Some(s)
+            }
+            _ => None,
+        };
+        match self.stats.stride {
+            Some(stride) => (1..=self.depth as i64)
+                .map(|i| i64::from(seq) + i * stride)
+                .take_while(|&s| s >= 0 && s < i64::from(len))
+                .map(|s| s as u32)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Hands chunk `id` to the background thread unless it is on its way already.
+    pub fn issue(&mut self, id: &ChunkId) {
+        if self.inflight.contains(id) {
+            return;
+        }
+        let worker = match &self.worker {
+            Some(w) => w,
+            None => match self.spawn() {
+                Ok(w) => self.worker.get_or_insert(w),
+                Err(e) => {
+                    warn!("Failed to start prefetch thread: {}", e);
+                    self.depth = 0;
+                    return;
+                }
+            },
+        };
+        if worker.tx.send(id.clone()).is_ok() {
+            self.inflight.insert(id.clone());
+            self.stats.issued += 1;
+        }
+    }
+
+    fn spawn(&self) -> Result<Worker> {
+        let (tx, requests) = unbounded::<ChunkId>();
+        let (results, rx) = unbounded();
+        let (be, verify) = (self.backend.clone(), self.verify);
+        let thread = thread::Builder::new()
+            .name("prefetch".into())
+            .spawn(move || {
+                for id in requests {
+                    let data = load_chunk(&be, &id, verify);
+                    if results.send((id, data)).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Worker { tx, rx, thread })
+    }
+
+    // Moves a loaded chunk into the cache.
+    fn arrived(&mut self, id: ChunkId, data: Result<Vec<u8>>, cache: &mut PageCache) {
+        self.inflight.remove(&id);
+        match data {
+            Ok(data) => {
+                debug!("prefetched {}", id);
+                cache.insert(id.clone(), Rc::new(data));
+                self.unclaimed.put(id, ());
+            }
+            Err(e) => {
+                debug!("prefetch {}: {}", id, e);
+                self.stats.failed += 1;
+            }
+        }
+    }
+
+    /// Puts all chunks loaded so far into `cache`. If chunk `want` is still being loaded, waits
+    /// for it.
+    pub fn collect(&mut self, want: Option<&ChunkId>, cache: &mut PageCache) {
+        let rx = match &self.worker {
+            Some(w) => w.rx.clone(),
+            None => return,
+        };
+        for (id, data) in rx.try_iter() {
+            self.arrived(id, data, cache);
+        }
+        if let Some(want) = want {
+            while self.inflight.contains(want) {
+                match rx.recv() {
+                    Ok((id, data)) => self.arrived(id, data, cache),
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+
+    /// Notes that chunk `id` has been read.
+    pub fn claim(&mut self, id: &ChunkId) {
+        if self.unclaimed.pop(id).is_some() {
+            self.stats.used += 1;
+        }
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        if let Some(w) = self.worker.take() {
+            drop(w.tx);
+            drop(w.rx);
+            w.thread.join().ok();
+        }
+    }
+}
+
+impl fmt::Debug for Prefetcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Prefetcher[depth {}, {} in flight, {:?}]",
+            self.depth,
+            self.inflight.len(),
+            self.stats
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helper::store_tar;
+
+    fn prefetcher(depth: usize) -> (tempdir::TempDir, Prefetcher) {
+        let s = store_tar();
+        let be = Backend::open(s.path()).unwrap();
+        (s, Prefetcher::new(be, false, depth))
+    }
+
+    #[test]
+    fn detect_strides() {
+        let (_s, mut p) = prefetcher(2);
+        assert!(p.access(0, 100).is_empty());
+        assert!(p.access(1, 100).is_empty());
+        assert!(p.access(2, 100).is_empty());
+        assert_eq!(p.access(3, 100), &[4, 5]);
+        // repeated accesses to the same page don't count
+        assert_eq!(p.access(3, 100), &[] as &[u32]);
+        assert_eq!(p.stats().stride, Some(1));
+        // a jump breaks the pattern until it has been confirmed again
+        assert!(p.access(40, 100).is_empty());
+        assert_eq!(p.stats().stride, None);
+        assert!(p.access(41, 100).is_empty());
+        assert!(p.access(42, 100).is_empty());
+        assert_eq!(p.access(43, 100), &[44, 45]);
+        // backwards, limited to the image
+        let (_s, mut p) = prefetcher(3);
+        for seq in &[90, 70, 50] {
+            p.access(*seq, 100);
+        }
+        assert_eq!(p.access(30, 100), &[10]);
+        assert_eq!(p.stats().stride, Some(-20));
+        let (_s, mut p) = prefetcher(3);
+        for seq in &[10, 42, 74] {
+            p.access(*seq, 200);
+        }
+        assert_eq!(p.access(106, 200), &[138, 170]);
+        assert_eq!(p.stats().stride, Some(32));
+    }
+
+    #[test]
+    fn load_in_background() {
+        let (_s, mut p) = prefetcher(4);
+        let mut cache = PageCache::new(16 << 20);
+        let good = ChunkId::from("4db6e194fd398e8edb76e11054d73eb0");
+        let missing = ChunkId::from("ffff0000000000000000000000000000");
+        p.issue(&good);
+        p.issue(&good);
+        p.issue(&missing);
+        p.collect(Some(&good), &mut cache);
+        p.collect(Some(&missing), &mut cache);
+        assert!(cache.contains(&good));
+        assert!(!cache.contains(&missing));
+        p.claim(&good);
+        p.claim(&good);
+        let st = p.stats();
+        assert_eq!((st.issued, st.used, st.failed), (2, 1, 1));
+        assert!((st.hit_rate() - 0.5).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,135 @@
+//! Frozen copies of revisions as seen through FUSE.
+//!
+//! A snapshot consists of a copy of the chunk map and references to all dirty pages of a revision
+//! at the time it has been taken. Dirty pages are shared with the revision: `Page::update` copies
+//! a page before modifying it as long as a snapshot holds a reference, so later writes never show
+//! up in a snapshot. Copies of that kind are not accounted in the cache budget and go away with
+//! the snapshot. Pages spilled to the revision's overlay file are read from there on demand: the
+//! overlay writes newer versions of them to other places in the file while the snapshot is
+//! alive. Clean pages are read through the shared page cache.
+
+use super::access::{load_chunk, Error};
+use super::cache::SharedCache;
+use super::overlay::Frozen;
+use crate::backend::Backend;
+use crate::chunkvec::ChunkId;
+use crate::{pos2chunk, zero_chunk, CHUNKSZ};
+
+use fnv::FnvHashMap as HashMap;
+use log::debug;
+use std::cmp::min;
+use std::ffi::OsString;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+const OFFSET_MASK: u64 = CHUNKSZ as u64 - 1;
+
+/// Read-only view of a revision frozen at some point in time.
+pub struct Snapshot {
+    name: OsString,
+    size: u64,
+    map: Vec<Option<ChunkId>>,
+    dirty: HashMap<u32, Rc<Vec<u8>>>,
+    spilled: Option<Frozen>,
+    backend: Backend,
+    cache: SharedCache,
+    verify: bool,
+    /// Page which has been read last
+    open: Option<(u32, Rc<Vec<u8>>)>,
+}
+
+impl Snapshot {
+    pub(super) fn new(
+        name: OsString,
+        size: u64,
+        map: Vec<Option<ChunkId>>,
+        dirty: HashMap<u32, Rc<Vec<u8>>>,
+        backend: Backend,
+        cache: SharedCache,
+        verify: bool,
+    ) -> Self {
+        Self {
+            name,
+            size,
+            map,
+            dirty,
+            spilled: None,
+            backend,
+            cache,
+            verify,
+            open: None,
+        }
+    }
+
+    /// Reads pages which are not dirty in memory from the overlay file, if any.
+    pub(super) fn spilled(mut self, spilled: Option<Frozen>) -> Self {
+        self.spilled = spilled;
+        self
+    }
+
+    /// Reads up to `size` bytes at `offset`. Like `FuseAccess::read_at`, reads don't cross page
+    /// boundaries.
+    pub fn read_at(&mut self, offset: u64, size: usize) -> Result<&[u8]> {
+        if offset >= self.size {
+            if offset == self.size {
+                return Ok(&[]);
+            }
+            return Err(
+                io::Error::new(io::ErrorKind::UnexpectedEof, "read beyond end of image").into(),
+            );
+        }
+        let seq = pos2chunk(offset);
+        let off = (offset & OFFSET_MASK) as usize;
+        let end = min(
+            min(off + size, CHUNKSZ) as u64,
+            self.size - (offset & !OFFSET_MASK),
+        ) as usize;
+        if !matches!(&self.open, Some((s, _)) if *s == seq) {
+            let page = self.page(seq)?;
+            self.open = Some((seq, page));
+        }
+        let (_, page) = self.open.as_ref().expect("page loaded");
+        if page.is_empty() {
+            Ok(&zero_chunk()?[off..end])
+        } else {
+            Ok(&page[off..end])
+        }
+    }
+
+    // Returns the frozen contents of page `seq`. Zero pages are represented by an empty vector.
+    fn page(&self, seq: u32) -> Result<Rc<Vec<u8>>> {
+        if let Some(page) = self.dirty.get(&seq) {
+            return Ok(Rc::clone(page));
+        }
+        if let Some(data) = self.spilled.as_ref().and_then(|s| s.get(seq)) {
+            return Ok(Rc::new(data?));
+        }
+        let id = match &self.map[seq as usize] {
+            Some(id) => id,
+            None => return Ok(Rc::default()),
+        };
+        if let Some(page) = self.cache.borrow_mut().get(id) {
+            return Ok(page);
+        }
+        debug!("{:?}: load #{} (snapshot)", self.name, seq);
+        let page = Rc::new(load_chunk(&self.backend, id, self.verify)?);
+        self.cache.borrow_mut().insert(id.clone(), Rc::clone(&page));
+        Ok(page)
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Snapshot[{:?}, {} bytes, {} dirty pages, {} spilled pages]",
+            self.name,
+            self.size,
+            self.dirty.len(),
+            self.spilled.as_ref().map_or(0, Frozen::len)
+        )
+    }
+}
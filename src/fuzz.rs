@@ -0,0 +1,44 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`.
+//!
+//! These functions must never panic, whatever input they get. Not part of the public API.
+
+use crate::backend::{self, Codec, Rev};
+use crate::chunkvec::ChunkVec;
+
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Largest image size accepted by [revision_map]. Construction of the chunk map allocates
+/// memory proportional to the image size, so larger sizes would only make the fuzzer run out
+/// of memory.
+const MAX_MAP_SIZE: u64 = 1 << 40;
+
+#[derive(Deserialize)]
+struct MapSize {
+    size: u64,
+}
+
+/// Chunk file header check and LZO decompression.
+pub fn chunk(data: &[u8]) {
+    for &codec in Codec::available() {
+        backend::decode(data, codec).ok();
+    }
+}
+
+/// Revision map JSON parsing and chunk map construction.
+pub fn revision_map(data: &[u8]) {
+    match serde_json::from_slice::<MapSize>(data) {
+        Ok(m) if m.size <= MAX_MAP_SIZE => (),
+        _ => return,
+    }
+    if let Ok(cv) = ChunkVec::from_slice(data) {
+        assert!(cv.unique() <= cv.len());
+    }
+}
+
+/// .rev YAML parsing.
+pub fn rev(data: &[u8]) {
+    if let Ok(s) = std::str::from_utf8(data) {
+        Rev::parse(s, PathBuf::from("fuzz.rev")).ok();
+    }
+}
@@ -0,0 +1,735 @@
+//! Read-only access to ext2, ext3 and ext4 file systems.
+//!
+//! Only what is needed to copy files out is implemented: inodes, extent trees and classic block
+//! maps, linear and hashed directories (hash indexes are skipped, all entries are found by a
+//! linear scan) and symlinks. The journal is not replayed, so files which were written shortly
+//! before the backup may appear in an older state.
+//!
+//! Block allocation bitmaps can be read to find free space, but only if the journal is clean.
+
+use super::{Error, ReadAt, Result};
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::Write;
+use std::ops::Range;
+
+const MAGIC: u16 = 0xEF53;
+const ROOT_INO: u32 = 2;
+
+const INCOMPAT_COMPRESSION: u32 = 0x1;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_JOURNAL_DEV: u32 = 0x8;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_DIRDATA: u32 = 0x1000;
+const INCOMPAT_ENCRYPT: u32 = 0x10000;
+const INCOMPAT_UNSUPPORTED: &[(u32, &str)] = &[
+    (INCOMPAT_COMPRESSION, "compression"),
+    (INCOMPAT_JOURNAL_DEV, "external journal device"),
+    (INCOMPAT_META_BG, "meta_bg"),
+    (INCOMPAT_DIRDATA, "dirdata"),
+    (INCOMPAT_ENCRYPT, "encryption"),
+];
+
+const COMPAT_SPARSE_SUPER2: u32 = 0x200;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_GDT_CSUM: u32 = 0x10;
+const RO_COMPAT_BIGALLOC: u32 = 0x200;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+// Group flag: the block bitmap has not been initialized, see `uninit_bitmap`
+const BG_BLOCK_UNINIT: u16 = 0x2;
+
+const EXTENTS_FL: u32 = 0x80000;
+const INLINE_DATA_FL: u32 = 0x1000_0000;
+const EXTENT_MAGIC: u16 = 0xF30A;
+// Extent trees deeper than this are considered corrupt
+const MAX_DEPTH: u16 = 5;
+// Extents longer than this are preallocated but unwritten
+const EXT_INIT_MAX_LEN: u16 = 32768;
+// Directories are read into memory as a whole
+const MAX_DIR_SIZE: u64 = 64 << 20;
+
+/// File type as stored in the inode mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    /// Devices, FIFOs and sockets
+    Other,
+}
+
+/// Inode metadata.
+#[derive(Debug, Clone)]
+pub struct Inode {
+    pub ino: u32,
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    /// Modification time in seconds since the epoch
+    pub mtime: u32,
+    pub links: u16,
+    flags: u32,
+    block: [u8; 60],
+}
+
+impl Inode {
+    pub fn kind(&self) -> Kind {
+        match self.mode & 0o170000 {
+            0o100000 => Kind::File,
+            0o040000 => Kind::Dir,
+            0o120000 => Kind::Symlink,
+            _ => Kind::Other,
+        }
+    }
+
+    /// Permission bits including setuid, setgid and sticky bit
+    pub fn perm(&self) -> u32 {
+        u32::from(self.mode & 0o7777)
+    }
+}
+
+/// Directory entry. Names are arbitrary bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub ino: u32,
+    pub name: Vec<u8>,
+}
+
+// Contiguous run of file blocks. `start` is 0 for holes and unwritten extents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Extent {
+    logical: u64,
+    start: u64,
+    len: u64,
+}
+
+/// An ext2/3/4 file system located at `offset` inside an image.
+#[derive(Debug)]
+pub struct Ext4<R> {
+    r: R,
+    offset: u64,
+    block_size: u64,
+    blocks_count: u64,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    desc_size: u64,
+    incompat: u32,
+    compat: u32,
+    ro_compat: u32,
+    reserved_gdt_blocks: u64,
+    backup_bgs: [u64; 2],
+}
+
+impl<R: ReadAt> Ext4<R> {
+    /// Returns true if there is an ext2/3/4 superblock at `offset`.
+    pub fn probe(r: &mut R, offset: u64) -> Result<bool> {
+        let mut magic = [0; 2];
+        r.read_at(offset + 1024 + 0x38, &mut magic)?;
+        Ok(LittleEndian::read_u16(&magic) == MAGIC)
+    }
+
+    /// Reads the superblock. Fails if the file system uses features which are not supported.
+    pub fn open(mut r: R, offset: u64) -> Result<Self> {
+        let mut sb = [0; 1024];
+        r.read_at(offset + 1024, &mut sb)?;
+        if LittleEndian::read_u16(&sb[0x38..]) != MAGIC {
+            return Err(Error::NotFound);
+        }
+        let log_block_size = LittleEndian::read_u32(&sb[0x18..]);
+        if log_block_size > 6 {
+            return Err(corrupt(format!("block size 2^{}", 10 + log_block_size)));
+        }
+        let incompat = LittleEndian::read_u32(&sb[0x60..]);
+        for (flag, name) in INCOMPAT_UNSUPPORTED {
+            if incompat & flag != 0 {
+                return Err(Error::Unsupported(format!("ext4 {}", name)));
+            }
+        }
+        let is64 = incompat & INCOMPAT_64BIT != 0;
+        let inode_size = match LittleEndian::read_u32(&sb[0x4C..]) {
+            0 => 128,
+            _ => u64::from(LittleEndian::read_u16(&sb[0x58..])),
+        };
+        let desc_size = match LittleEndian::read_u16(&sb[0xFE..]) {
+            n if is64 && n >= 32 => u64::from(n),
+            _ => 32,
+        };
+        let mut blocks_count = u64::from(LittleEndian::read_u32(&sb[0x4..]));
+        if is64 {
+            blocks_count |= u64::from(LittleEndian::read_u32(&sb[0x150..])) << 32;
+        }
+        let fs = Self {
+            r,
+            offset,
+            block_size: 1024 << log_block_size,
+            blocks_count,
+            first_data_block: u64::from(LittleEndian::read_u32(&sb[0x14..])),
+            blocks_per_group: u64::from(LittleEndian::read_u32(&sb[0x20..])),
+            inodes_per_group: LittleEndian::read_u32(&sb[0x28..]),
+            inode_size,
+            desc_size,
+            incompat,
+            compat: LittleEndian::read_u32(&sb[0x5C..]),
+            ro_compat: LittleEndian::read_u32(&sb[0x64..]),
+            reserved_gdt_blocks: u64::from(LittleEndian::read_u16(&sb[0xCE..])),
+            backup_bgs: [
+                u64::from(LittleEndian::read_u32(&sb[0x24C..])),
+                u64::from(LittleEndian::read_u32(&sb[0x250..])),
+            ],
+        };
+        // all byte offsets inside the file system must be representable
+        let end = blocks_count
+            .checked_mul(fs.block_size)
+            .and_then(|len| len.checked_add(offset));
+        if fs.blocks_per_group == 0
+            || fs.inodes_per_group == 0
+            || fs.inode_size < 128
+            || end.is_none()
+        {
+            return Err(corrupt("invalid superblock".into()));
+        }
+        Ok(fs)
+    }
+
+    /// File system block size in bytes
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn read(&mut self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        Ok(self.r.read_at(self.offset + pos, buf)?)
+    }
+
+    fn read_block(&mut self, block: u64) -> Result<Vec<u8>> {
+        if block >= self.blocks_count {
+            return Err(corrupt(format!("block {} out of range", block)));
+        }
+        let mut buf = vec![0; self.block_size as usize];
+        self.read(block * self.block_size, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Reads the group descriptor of block group `group`.
+    fn group_desc(&mut self, group: u64) -> Result<Vec<u8>> {
+        let gdt = (self.first_data_block + 1) * self.block_size;
+        let mut desc = vec![0; self.desc_size as usize];
+        self.read(gdt + group * self.desc_size, &mut desc)?;
+        Ok(desc)
+    }
+
+    // Combines the low and (with 64bit descriptors) high half of a block number.
+    fn desc_block(&self, desc: &[u8], lo: usize, hi: usize) -> u64 {
+        let mut n = u64::from(LittleEndian::read_u32(&desc[lo..]));
+        if self.desc_size >= 64 {
+            n |= u64::from(LittleEndian::read_u32(&desc[hi..])) << 32;
+        }
+        n
+    }
+
+    /// Loads inode number `ino`.
+    pub fn inode(&mut self, ino: u32) -> Result<Inode> {
+        if ino == 0 {
+            return Err(corrupt("inode 0".into()));
+        }
+        let group = u64::from((ino - 1) / self.inodes_per_group);
+        let index = u64::from((ino - 1) % self.inodes_per_group);
+        let desc = self.group_desc(group)?;
+        let table = self.desc_block(&desc, 0x8, 0x28);
+        if table >= self.blocks_count {
+            return Err(corrupt(format!(
+                "inode table of inode {} out of range",
+                ino
+            )));
+        }
+        let pos = (table * self.block_size)
+            .checked_add(index * self.inode_size)
+            .ok_or_else(|| corrupt(format!("inode table of inode {} out of range", ino)))?;
+        let mut raw = [0; 128];
+        self.read(pos, &mut raw)?;
+        let mut block = [0; 60];
+        block.copy_from_slice(&raw[0x28..0x64]);
+        Ok(Inode {
+            ino,
+            mode: LittleEndian::read_u16(&raw[0x0..]),
+            uid: u32::from(LittleEndian::read_u16(&raw[0x2..]))
+                | u32::from(LittleEndian::read_u16(&raw[0x78..])) << 16,
+            gid: u32::from(LittleEndian::read_u16(&raw[0x18..]))
+                | u32::from(LittleEndian::read_u16(&raw[0x7A..])) << 16,
+            size: u64::from(LittleEndian::read_u32(&raw[0x4..]))
+                | u64::from(LittleEndian::read_u32(&raw[0x6C..])) << 32,
+            mtime: LittleEndian::read_u32(&raw[0x10..]),
+            links: LittleEndian::read_u16(&raw[0x1A..]),
+            flags: LittleEndian::read_u32(&raw[0x20..]),
+            block,
+        })
+    }
+
+    /// Root directory inode
+    pub fn root(&mut self) -> Result<Inode> {
+        self.inode(ROOT_INO)
+    }
+
+    // Maps the file's blocks. Extents are sorted and lie inside the file system. Holes are not
+    // included.
+    fn extents(&mut self, inode: &Inode) -> Result<Vec<Extent>> {
+        if inode.flags & INLINE_DATA_FL != 0 {
+            return Err(Error::Unsupported("ext4 inline data".into()));
+        }
+        let nblocks = inode.size.div_ceil(self.block_size);
+        let mut out = Vec::new();
+        if inode.flags & EXTENTS_FL != 0 {
+            self.extent_node(&inode.block, MAX_DEPTH, &mut out)?;
+            out.sort_by_key(|e| e.logical);
+        } else {
+            for i in 0..12 {
+                let b = LittleEndian::read_u32(&inode.block[i * 4..]);
+                push_block(&mut out, i as u64, u64::from(b));
+            }
+            let per_block = self.block_size / 4;
+            let mut logical = 12;
+            for level in 1..=3 {
+                let b = LittleEndian::read_u32(&inode.block[(11 + level) * 4..]);
+                let span = per_block.pow(level as u32);
+                if logical >= nblocks {
+                    break;
+                }
+                self.indirect(u64::from(b), level, logical, nblocks, &mut out)?;
+                logical += span;
+            }
+        }
+        out.retain(|e| e.logical < nblocks);
+        if let Some(last) = out.last_mut() {
+            last.len = last.len.min(nblocks - last.logical);
+        }
+        let outside = |e: &Extent| match e.start.checked_add(e.len) {
+            Some(end) => end > self.blocks_count,
+            None => true,
+        };
+        if out.iter().any(outside) {
+            return Err(corrupt(format!(
+                "extent of inode {} out of range",
+                inode.ino
+            )));
+        }
+        Ok(out)
+    }
+
+    fn extent_node(&mut self, node: &[u8], max_depth: u16, out: &mut Vec<Extent>) -> Result<()> {
+        if node.len() < 12 || LittleEndian::read_u16(node) != EXTENT_MAGIC {
+            return Err(corrupt("bad extent header".into()));
+        }
+        let entries = LittleEndian::read_u16(&node[2..]) as usize;
+        let depth = LittleEndian::read_u16(&node[6..]);
+        if depth > max_depth || 12 + entries * 12 > node.len() {
+            return Err(corrupt("bad extent tree".into()));
+        }
+        for e in node[12..12 + entries * 12].chunks(12) {
+            let logical = u64::from(LittleEndian::read_u32(e));
+            if depth == 0 {
+                let len = LittleEndian::read_u16(&e[4..]);
+                let start = u64::from(LittleEndian::read_u16(&e[6..])) << 32
+                    | u64::from(LittleEndian::read_u32(&e[8..]));
+                if len > EXT_INIT_MAX_LEN {
+                    // unwritten extents read as zeros
+                    continue;
+                }
+                out.push(Extent {
+                    logical,
+                    start,
+                    len: u64::from(len),
+                });
+            } else {
+                let leaf = u64::from(LittleEndian::read_u32(&e[4..]))
+                    | u64::from(LittleEndian::read_u16(&e[8..])) << 32;
+                let child = self.read_block(leaf)?;
+                self.extent_node(&child, depth - 1, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Walks an indirect block of the classic block map. `level` 1 points to data blocks.
+    fn indirect(
+        &mut self,
+        block: u64,
+        level: usize,
+        logical: u64,
+        nblocks: u64,
+        out: &mut Vec<Extent>,
+    ) -> Result<()> {
+        if block == 0 {
+            return Ok(());
+        }
+        let ptrs = self.read_block(block)?;
+        let span = (self.block_size / 4).pow(level as u32 - 1);
+        for (i, p) in ptrs.chunks(4).enumerate() {
+            let l = logical + i as u64 * span;
+            if l >= nblocks {
+                break;
+            }
+            let b = u64::from(LittleEndian::read_u32(p));
+            if level == 1 {
+                push_block(out, l, b);
+            } else {
+                self.indirect(b, level - 1, l, nblocks, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Byte ranges of the image which hold the data of a regular file, in ascending order of file
+    /// offset. Whole blocks are covered, including the slack after the end of the file. Holes and
+    /// unwritten extents are left out.
+    pub fn file_ranges(&mut self, inode: &Inode) -> Result<Vec<Range<u64>>> {
+        if inode.kind() != Kind::File {
+            return Err(Error::NotAFile(format!("inode {}", inode.ino)));
+        }
+        let bs = self.block_size;
+        Ok(self
+            .extents(inode)?
+            .into_iter()
+            .map(|e| self.offset + e.start * bs..self.offset + (e.start + e.len) * bs)
+            .collect())
+    }
+
+    /// Copies the contents of a regular file to `out`. Holes are written as zeros. Returns the
+    /// number of bytes written.
+    pub fn copy_file<W: Write>(&mut self, inode: &Inode, out: &mut W) -> Result<u64> {
+        let bs = self.block_size;
+        let mut pos = 0;
+        let mut buf = Vec::new();
+        for e in self.extents(inode)? {
+            let range = e
+                .logical
+                .checked_mul(bs)
+                .zip((e.logical + e.len).checked_mul(bs));
+            let (start, end) = match range {
+                Some((start, end)) => (start, end.min(inode.size)),
+                None => return Err(corrupt(format!("file offset in inode {}", inode.ino))),
+            };
+            if start < pos {
+                return Err(corrupt(format!(
+                    "overlapping extents in inode {}",
+                    inode.ino
+                )));
+            }
+            zeros(out, start - pos)?;
+            let mut off = start;
+            while off < end {
+                let n = (end - off).min(1 << 20);
+                buf.resize(n as usize, 0);
+                self.read(e.start * bs + (off - start), &mut buf)?;
+                out.write_all(&buf)?;
+                off += n;
+            }
+            pos = end;
+        }
+        zeros(out, inode.size - pos)?;
+        Ok(inode.size)
+    }
+
+    fn read_all(&mut self, inode: &Inode) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(inode.size as usize);
+        self.copy_file(inode, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Lists a directory, including `.` and `..`.
+    pub fn read_dir(&mut self, dir: &Inode) -> Result<Vec<DirEntry>> {
+        if dir.kind() != Kind::Dir {
+            return Err(corrupt(format!("inode {} is not a directory", dir.ino)));
+        }
+        if dir.size > MAX_DIR_SIZE {
+            return Err(corrupt(format!(
+                "directory inode {} too large ({} bytes)",
+                dir.ino, dir.size
+            )));
+        }
+        let data = self.read_all(dir)?;
+        let mut entries = Vec::new();
+        for block in data.chunks(self.block_size as usize) {
+            let mut off = 0;
+            while off + 8 <= block.len() {
+                let ino = LittleEndian::read_u32(&block[off..]);
+                let rec_len = LittleEndian::read_u16(&block[off + 4..]) as usize;
+                let name_len = if self.incompat & INCOMPAT_FILETYPE != 0 {
+                    block[off + 6] as usize
+                } else {
+                    LittleEndian::read_u16(&block[off + 6..]) as usize
+                };
+                if rec_len < 8 || off + rec_len > block.len() || 8 + name_len > rec_len {
+                    return Err(corrupt(format!("bad directory entry in inode {}", dir.ino)));
+                }
+                if ino != 0 {
+                    entries.push(DirEntry {
+                        ino,
+                        name: block[off + 8..off + 8 + name_len].to_vec(),
+                    });
+                }
+                off += rec_len;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns the target of a symbolic link.
+    pub fn read_link(&mut self, inode: &Inode) -> Result<Vec<u8>> {
+        if inode.size > 4096 {
+            return Err(corrupt(format!("symlink {} too long", inode.ino)));
+        }
+        if inode.size < 60 && inode.flags & (EXTENTS_FL | INLINE_DATA_FL) == 0 {
+            // fast symlink: the target is stored in place of the block map
+            Ok(inode.block[..inode.size as usize].to_vec())
+        } else {
+            self.read_all(inode)
+        }
+    }
+
+    /// Resolves an absolute path. Symlinks are followed except for the last component, unless
+    /// the path ends with a slash.
+    pub fn lookup(&mut self, path: &[u8]) -> Result<Inode> {
+        let name = || String::from_utf8_lossy(path).into_owned();
+        let mut todo: Vec<Vec<u8>> = components(path).rev().collect();
+        let mut cur = self.root()?;
+        let mut links = 0;
+        // like POSIX, a trailing slash resolves a final symlink to a directory
+        let follow = path.ends_with(b"/");
+        while let Some(c) = todo.pop() {
+            if cur.kind() != Kind::Dir {
+                return Err(Error::NotADirectory(name()));
+            }
+            let entry = self
+                .read_dir(&cur)?
+                .into_iter()
+                .find(|e| e.name == c)
+                .ok_or_else(|| Error::NoSuchPath(name()))?;
+            let next = self.inode(entry.ino)?;
+            if next.kind() == Kind::Symlink && (follow || !todo.is_empty()) {
+                links += 1;
+                if links > 40 {
+                    return Err(Error::Loop(name()));
+                }
+                let target = self.read_link(&next)?;
+                todo.extend(components(&target).rev());
+                if target.starts_with(b"/") {
+                    cur = self.root()?;
+                }
+                continue;
+            }
+            cur = next;
+        }
+        Ok(cur)
+    }
+
+    /// Number of block groups
+    fn groups(&self) -> u64 {
+        self.blocks_count
+            .saturating_sub(self.first_data_block)
+            .div_ceil(self.blocks_per_group)
+    }
+
+    // Whether `group` holds a copy of the superblock and the group descriptor table.
+    fn has_super(&self, group: u64) -> bool {
+        if group == 0 {
+            return true;
+        }
+        if self.compat & COMPAT_SPARSE_SUPER2 != 0 {
+            return self.backup_bgs.contains(&group);
+        }
+        if group == 1 || self.ro_compat & RO_COMPAT_SPARSE_SUPER == 0 {
+            return true;
+        }
+        let power_of = |base: u64| {
+            let mut n = base;
+            while n < group {
+                n *= base;
+            }
+            n == group
+        };
+        group % 2 == 1 && (power_of(3) || power_of(5) || power_of(7))
+    }
+
+    // Block bitmap of a group with BLOCK_UNINIT set. Such groups contain nothing but their own
+    // metadata, which is marked in the same way the kernel does.
+    fn uninit_bitmap(&self, group: u64, desc: &[u8]) -> Vec<u8> {
+        let mut bitmap = vec![0; self.block_size as usize];
+        let first = self.first_data_block + group * self.blocks_per_group;
+        let mut used = Vec::new();
+        if self.has_super(group) {
+            let gdt = (self.groups() * self.desc_size).div_ceil(self.block_size);
+            used.push(first..first + 1 + gdt + self.reserved_gdt_blocks);
+        }
+        let itable = (u64::from(self.inodes_per_group) * self.inode_size).div_ceil(self.block_size);
+        let b = self.desc_block(desc, 0x0, 0x20);
+        used.push(b..b + 1);
+        let b = self.desc_block(desc, 0x4, 0x24);
+        used.push(b..b + 1);
+        let b = self.desc_block(desc, 0x8, 0x28);
+        used.push(b..b + itable);
+        for r in used {
+            for b in r.start.max(first)..r.end.min(first + self.blocks_per_group) {
+                let i = (b - first) as usize;
+                if let Some(byte) = bitmap.get_mut(i / 8) {
+                    *byte |= 1 << (i % 8);
+                }
+            }
+        }
+        bitmap
+    }
+
+    /// Byte ranges of the image which are not allocated according to the block bitmaps, in
+    /// ascending order. Fails if the journal needs recovery, as bitmaps may be outdated then.
+    pub fn unallocated(&mut self) -> Result<Vec<Range<u64>>> {
+        if self.incompat & INCOMPAT_RECOVER != 0 {
+            return Err(Error::Unclean);
+        }
+        if self.ro_compat & RO_COMPAT_BIGALLOC != 0 {
+            return Err(Error::Unsupported("ext4 bigalloc".into()));
+        }
+        let csum = self.ro_compat & (RO_COMPAT_GDT_CSUM | RO_COMPAT_METADATA_CSUM) != 0;
+        let bs = self.block_size;
+        let mut free: Vec<Range<u64>> = Vec::new();
+        for group in 0..self.groups() {
+            let desc = self.group_desc(group)?;
+            let bitmap = if csum && LittleEndian::read_u16(&desc[0x12..]) & BG_BLOCK_UNINIT != 0 {
+                self.uninit_bitmap(group, &desc)
+            } else {
+                let b = self.desc_block(&desc, 0x0, 0x20);
+                self.read_block(b)?
+            };
+            let first = self.first_data_block + group * self.blocks_per_group;
+            let n = self
+                .blocks_per_group
+                .min(self.blocks_count - first)
+                .min(bitmap.len() as u64 * 8);
+            let mut i = 0;
+            while i < n {
+                let byte = bitmap[(i / 8) as usize];
+                if i % 8 == 0 && byte == 0xFF {
+                    i += 8;
+                    continue;
+                }
+                let run = if i % 8 == 0 && byte == 0 && i + 8 <= n {
+                    8
+                } else {
+                    1
+                };
+                if run == 8 || byte & (1 << (i % 8)) == 0 {
+                    let start = self.offset + (first + i) * bs;
+                    match free.last_mut() {
+                        Some(last) if last.end == start => last.end += run * bs,
+                        _ => free.push(start..start + run * bs),
+                    }
+                }
+                i += run;
+            }
+        }
+        Ok(free)
+    }
+}
+
+fn corrupt(msg: String) -> Error {
+    Error::Corrupt(msg)
+}
+
+fn components(path: &[u8]) -> impl DoubleEndedIterator<Item = Vec<u8>> + '_ {
+    path.split(|&b| b == b'/')
+        .filter(|c| !c.is_empty() && c != b".")
+        .map(|c| c.to_vec())
+}
+
+// Appends a single block to the extent list, merging it with the previous extent if possible.
+fn push_block(out: &mut Vec<Extent>, logical: u64, block: u64) {
+    if block == 0 {
+        return;
+    }
+    if let Some(last) = out.last_mut() {
+        if last.logical + last.len == logical && last.start + last.len == block {
+            last.len += 1;
+            return;
+        }
+    }
+    out.push(Extent {
+        logical,
+        start: block,
+        len: 1,
+    });
+}
+
+fn zeros<W: Write>(out: &mut W, mut n: u64) -> Result<()> {
+    let buf = [0; 8192];
+    while n > 0 {
+        let k = n.min(buf.len() as u64) as usize;
+        out.write_all(&buf[..k])?;
+        n -= k as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // File system with 1 KiB blocks: group descriptors in block 2, inode table in block 4 and a
+    // root directory with a single extent starting at `root_block`. Block 10 holds a valid
+    // directory.
+    fn tiny_fs(root_block: u32) -> Vec<u8> {
+        let mut img = vec![0; 64 << 10];
+        let sb = &mut img[1024..2048];
+        LittleEndian::write_u32(&mut sb[0x4..], 64);
+        LittleEndian::write_u32(&mut sb[0x14..], 1);
+        LittleEndian::write_u32(&mut sb[0x20..], 8192);
+        LittleEndian::write_u32(&mut sb[0x28..], 16);
+        LittleEndian::write_u16(&mut sb[0x38..], MAGIC);
+        LittleEndian::write_u32(&mut img[2048 + 0x8..], 4);
+        let root = &mut img[4096 + 128..4096 + 256];
+        LittleEndian::write_u16(&mut root[0x0..], 0o040755);
+        LittleEndian::write_u32(&mut root[0x4..], 1024);
+        LittleEndian::write_u32(&mut root[0x20..], EXTENTS_FL);
+        let ext = &mut root[0x28..0x64];
+        LittleEndian::write_u16(&mut ext[0..], EXTENT_MAGIC);
+        LittleEndian::write_u16(&mut ext[2..], 1);
+        LittleEndian::write_u16(&mut ext[4..], 4);
+        LittleEndian::write_u16(&mut ext[16..], 1);
+        LittleEndian::write_u32(&mut ext[20..], root_block);
+        let dir = &mut img[10 << 10..11 << 10];
+        LittleEndian::write_u32(&mut dir[0..], 2);
+        LittleEndian::write_u16(&mut dir[4..], 1024);
+        LittleEndian::write_u16(&mut dir[6..], 1);
+        dir[8] = b'.';
+        img
+    }
+
+    #[test]
+    fn reject_out_of_range_metadata() {
+        let mut img = tiny_fs(10);
+        let mut fs = Ext4::open(&mut img[..], 0).unwrap();
+        let root = fs.root().unwrap();
+        assert!(fs.read_dir(&root).is_ok());
+
+        let mut img = tiny_fs(1 << 30);
+        let mut fs = Ext4::open(&mut img[..], 0).unwrap();
+        let root = fs.root().unwrap();
+        assert!(matches!(fs.read_dir(&root), Err(Error::Corrupt(_))));
+
+        // inode table beyond the last block
+        let mut img = tiny_fs(10);
+        LittleEndian::write_u32(&mut img[2048 + 0x8..], 1000);
+        let mut fs = Ext4::open(&mut img[..], 0).unwrap();
+        assert!(matches!(fs.root(), Err(Error::Corrupt(_))));
+
+        // block count which overflows byte offsets
+        let mut img = tiny_fs(10);
+        LittleEndian::write_u32(&mut img[1024 + 0x60..], INCOMPAT_64BIT);
+        LittleEndian::write_u32(&mut img[1024 + 0x150..], u32::MAX);
+        assert!(matches!(
+            Ext4::open(&mut img[..], 0),
+            Err(Error::Corrupt(_))
+        ));
+    }
+}
@@ -0,0 +1,189 @@
+//! Access to files inside guest file systems.
+//!
+//! Images often only need to be restored to get at a handful of files. The readers in this module
+//! locate file data inside an image directly, so that only the chunks which hold the requested
+//! files and the file system metadata leading to them are loaded.
+
+pub mod ext4;
+mod tar;
+
+use self::ext4::{Ext4, Inode, Kind};
+use self::tar::{Builder, Meta, Type};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("No supported file system found")]
+    NotFound,
+    #[error("Unsupported file system feature: {0}")]
+    Unsupported(String),
+    #[error("File system is corrupt: {0}")]
+    Corrupt(String),
+    #[error("'{0}' not found")]
+    NoSuchPath(String),
+    #[error("'{0}' is not a directory")]
+    NotADirectory(String),
+    #[error("'{0}' is not a regular file")]
+    NotAFile(String),
+    #[error("Too many levels of symbolic links in '{0}'")]
+    Loop(String),
+    #[error("File system journal needs recovery")]
+    Unclean,
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Positioned reads from an image.
+pub trait ReadAt {
+    /// Fills `buf` with image data starting at `offset`.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+impl ReadAt for File {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.read_exact_at(buf, offset)
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let src = usize::try_from(offset)
+            .ok()
+            .and_then(|o| self.get(o..o.checked_add(buf.len())?))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+}
+
+impl<R: ReadAt + ?Sized> ReadAt for &mut R {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        (**self).read_at(offset, buf)
+    }
+}
+
+/// Summary of a file extraction.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileStats {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    /// Hard links to files which are already in the archive
+    pub hardlinks: usize,
+    /// Devices, FIFOs and sockets, which are left out
+    pub skipped: usize,
+    /// Size of all regular files
+    pub bytes: u64,
+}
+
+struct TarWalk<'a, R, W: Write> {
+    fs: &'a mut Ext4<R>,
+    tar: Builder<W>,
+    stats: FileStats,
+    // archive names of files with more than one link
+    links: HashMap<u32, Vec<u8>>,
+    // directories on the current path, to survive corrupt directory loops
+    open_dirs: HashSet<u32>,
+}
+
+impl<'a, R: ReadAt, W: Write> TarWalk<'a, R, W> {
+    fn add(&mut self, name: &[u8], inode: &Inode) -> Result<()> {
+        let meta = Meta {
+            mode: inode.perm(),
+            uid: inode.uid,
+            gid: inode.gid,
+            mtime: inode.mtime,
+        };
+        match inode.kind() {
+            Kind::File => {
+                if inode.links > 1 {
+                    if let Some(target) = self.links.get(&inode.ino) {
+                        self.stats.hardlinks += 1;
+                        return Ok(self.tar.entry(Type::HardLink, name, &meta, target)?);
+                    }
+                    self.links.insert(inode.ino, name.to_vec());
+                }
+                let fs = &mut *self.fs;
+                let mut copied = Ok(0);
+                let written = self.tar.file(name, &meta, inode.size, |out| {
+                    copied = fs.copy_file(inode, out);
+                    Ok(())
+                });
+                self.stats.bytes += copied?;
+                written?;
+                self.stats.files += 1;
+            }
+            Kind::Symlink => {
+                let target = self.fs.read_link(inode)?;
+                self.tar.entry(Type::Symlink, name, &meta, &target)?;
+                self.stats.symlinks += 1;
+            }
+            Kind::Dir => {
+                if !self.open_dirs.insert(inode.ino) {
+                    return Err(Error::Corrupt(format!(
+                        "directory loop at inode {}",
+                        inode.ino
+                    )));
+                }
+                let mut dirname = name.to_vec();
+                dirname.push(b'/');
+                self.tar.entry(Type::Dir, &dirname, &meta, b"")?;
+                self.stats.dirs += 1;
+                let mut entries = self.fs.read_dir(inode)?;
+                entries.retain(|e| e.name != b"." && e.name != b"..");
+                entries.sort_by(|a, b| a.name.cmp(&b.name));
+                for e in entries {
+                    let child = self.fs.inode(e.ino)?;
+                    let mut path = dirname.clone();
+                    path.extend_from_slice(&e.name);
+                    self.add(&path, &child)?;
+                }
+                self.open_dirs.remove(&inode.ino);
+            }
+            Kind::Other => self.stats.skipped += 1,
+        }
+        Ok(())
+    }
+}
+
+/// Writes the given absolute `paths` from `fs` as tar stream to `out`. Directories are included
+/// recursively. Archive member names are the paths without leading slash.
+pub fn write_tar<R, P, W>(fs: &mut Ext4<R>, paths: &[P], out: W) -> Result<FileStats>
+where
+    R: ReadAt,
+    P: AsRef<Path>,
+    W: Write,
+{
+    let mut walk = TarWalk {
+        fs,
+        tar: Builder::new(out),
+        stats: FileStats::default(),
+        links: HashMap::new(),
+        open_dirs: HashSet::new(),
+    };
+    for p in paths {
+        let path = p.as_ref().as_os_str().as_bytes();
+        let inode = walk.fs.lookup(path)?;
+        let name: Vec<u8> = match path.iter().position(|&b| b != b'/') {
+            Some(i) => path[i..].to_vec(),
+            None => b".".to_vec(),
+        };
+        let name = match name.iter().rposition(|&b| b != b'/') {
+            Some(i) => name[..=i].to_vec(),
+            None => name,
+        };
+        walk.add(&name, &inode)?;
+    }
+    walk.tar.finish()?;
+    Ok(walk.stats)
+}
@@ -0,0 +1,153 @@
+//! Minimal writer for GNU tar streams.
+
+use std::io::{self, Write};
+
+const BLOCK: usize = 512;
+
+/// Entry type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Type {
+    File,
+    HardLink,
+    Symlink,
+    Dir,
+}
+
+impl Type {
+    fn flag(self) -> u8 {
+        match self {
+            Type::File => b'0',
+            Type::HardLink => b'1',
+            Type::Symlink => b'2',
+            Type::Dir => b'5',
+        }
+    }
+}
+
+/// Ownership, permissions and time stamp of an entry
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Meta {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+}
+
+#[derive(Debug)]
+pub(crate) struct Builder<W: Write> {
+    out: W,
+}
+
+// Writes `val` as NUL terminated octal number or in GNU base-256 encoding if it does not fit.
+fn number(field: &mut [u8], val: u64) {
+    let digits = field.len() - 1;
+    if digits < 22 && val >> (3 * digits) == 0 {
+        let s = format!("{:0w$o}", val, w = digits);
+        field[..digits].copy_from_slice(s.as_bytes());
+        field[digits] = 0;
+    } else {
+        let n = field.len();
+        for (i, b) in field.iter_mut().enumerate() {
+            let shift = 8 * (n - 1 - i);
+            *b = if shift < 64 { (val >> shift) as u8 } else { 0 };
+        }
+        field[0] |= 0x80;
+    }
+}
+
+fn header(name: &[u8], kind: u8, meta: &Meta, size: u64, link: &[u8]) -> [u8; BLOCK] {
+    let mut h = [0; BLOCK];
+    let n = name.len().min(100);
+    h[..n].copy_from_slice(&name[..n]);
+    number(&mut h[100..108], u64::from(meta.mode));
+    number(&mut h[108..116], u64::from(meta.uid));
+    number(&mut h[116..124], u64::from(meta.gid));
+    number(&mut h[124..136], size);
+    number(&mut h[136..148], u64::from(meta.mtime));
+    h[156] = kind;
+    let n = link.len().min(100);
+    h[157..157 + n].copy_from_slice(&link[..n]);
+    h[257..265].copy_from_slice(b"ustar  \0");
+    h[148..156].copy_from_slice(b"        ");
+    let sum: u32 = h.iter().map(|&b| u32::from(b)).sum();
+    h[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    h
+}
+
+impl<W: Write> Builder<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let rest = (BLOCK - (size % BLOCK as u64) as usize) % BLOCK;
+        self.out.write_all(&[0; BLOCK][..rest])
+    }
+
+    // Names which do not fit into the header are stored in a preceding pseudo entry.
+    fn long(&mut self, kind: u8, val: &[u8]) -> io::Result<()> {
+        if val.len() <= 100 {
+            return Ok(());
+        }
+        let size = val.len() as u64 + 1;
+        self.out
+            .write_all(&header(b"././@LongLink", kind, &Meta::default(), size, b""))?;
+        self.out.write_all(val)?;
+        self.out.write_all(&[0])?;
+        self.pad(size)
+    }
+
+    /// Adds an entry without contents.
+    pub fn entry(&mut self, t: Type, name: &[u8], meta: &Meta, link: &[u8]) -> io::Result<()> {
+        self.long(b'L', name)?;
+        self.long(b'K', link)?;
+        self.out.write_all(&header(name, t.flag(), meta, 0, link))
+    }
+
+    /// Adds a regular file. `data` must write exactly `size` bytes.
+    pub fn file<F>(&mut self, name: &[u8], meta: &Meta, size: u64, data: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut W) -> io::Result<()>,
+    {
+        self.long(b'L', name)?;
+        self.out
+            .write_all(&header(name, Type::File.flag(), meta, size, b""))?;
+        data(&mut self.out)?;
+        self.pad(size)
+    }
+
+    /// Writes the end-of-archive marker.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.write_all(&[0; 2 * BLOCK])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numeric_fields() {
+        let mut f = [0; 12];
+        number(&mut f, 0o644);
+        assert_eq!(&f, b"00000000644\0");
+        number(&mut f, 1 << 40);
+        assert_eq!(f[0], 0x80);
+        assert_eq!(&f[6..], &[1, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn long_names() {
+        let mut b = Builder::new(Vec::new());
+        let name = vec![b'x'; 150];
+        b.entry(Type::Dir, &name, &Meta::default(), b"").unwrap();
+        let out = b.finish().unwrap();
+        // long name header + 1 data block + header + 2 end blocks
+        assert_eq!(out.len(), 5 * BLOCK);
+        assert_eq!(out[156], b'L');
+        assert_eq!(&out[BLOCK..BLOCK + 150], &name[..]);
+        assert_eq!(out[2 * BLOCK + 156], b'5');
+    }
+}
@@ -0,0 +1,256 @@
+//! Record of chunks which have passed verification.
+//!
+//! Damage reports load and hash every chunk of a revision, which takes hours for large stores
+//! although most chunk files never change. [HashCache] remembers size and modification time of
+//! each chunk file that has been verified successfully. Files which are unchanged since are
+//! skipped by later reports. Bit rot does not touch modification times, so entries expire after
+//! [MAX_AGE] and the chunk is verified again.
+//!
+//! The cache is a single file which is written atomically. Damaged cache files are ignored.
+//! Several processes may use the same cache, e.g. a damage report and `watch`: saving merges the
+//! entries other processes have written in the meantime.
+//!
+//! # Format
+//!
+//! Integer and `str` encoding as in the [archive](../archive/index.html) format.
+//!
+//! ```text
+//! header:  "BKYHASH\0" version:u8 nentries:u32
+//! entries: (id:str len:u64 mtime:i64 mtime_nsec:i64 verified:i64)*
+//! trailer: hash of all preceding bytes, 32 hex digits
+//! ```
+//!
+//! `verified` is the time of the last successful verification in seconds since the epoch.
+
+use crate::backend;
+use crate::framing::{read_str, write_str};
+use crate::replace_file;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, Metadata};
+use std::io::{self, Cursor, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8] = b"BKYHASH\0";
+const VERSION: u8 = 1;
+const TRAILER: usize = 32;
+
+/// Time after which a verified chunk is verified again, in seconds
+pub const MAX_AGE: i64 = 30 * 86400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    len: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    verified: i64,
+}
+
+impl Entry {
+    fn matches(&self, m: &Metadata) -> bool {
+        self.len == m.len() && self.mtime == m.mtime() && self.mtime_nsec == m.mtime_nsec()
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Verified chunks of a store, keyed by chunk ID.
+#[derive(Debug)]
+pub struct HashCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Chunks removed since the last save, not to be merged back from the file
+    removed: Mutex<HashSet<String>>,
+}
+
+impl HashCache {
+    /// Loads the cache file `path`. Starts out empty if the file does not exist or cannot be
+    /// read. Expired entries are dropped.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        let entries = match read(&path, now() - MAX_AGE) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                debug!("ignoring hash cache {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+        Self {
+            path,
+            entries: Mutex::new(entries),
+            removed: Mutex::default(),
+        }
+    }
+
+    /// Returns true if chunk `id` has been verified and its file (with metadata `file`) is
+    /// unchanged since.
+    pub fn is_verified(&self, id: &str, file: &Metadata) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries.get(id).is_some_and(|e| e.matches(file))
+    }
+
+    /// Records that chunk `id` with file metadata `file` has passed verification just now.
+    pub fn insert(&self, id: &str, file: &Metadata) {
+        let entry = Entry {
+            len: file.len(),
+            mtime: file.mtime(),
+            mtime_nsec: file.mtime_nsec(),
+            verified: now(),
+        };
+        self.removed.lock().unwrap().remove(id);
+        self.entries.lock().unwrap().insert(id.to_owned(), entry);
+    }
+
+    /// Forgets chunk `id`, e.g. after it has failed verification.
+    pub fn remove(&self, id: &str) {
+        self.removed.lock().unwrap().insert(id.to_owned());
+        self.entries.lock().unwrap().remove(id);
+    }
+
+    /// Number of verified chunks.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes the cache file. Entries written by others since the file has been read are merged
+    /// in first, the more recent verification wins.
+    pub fn save(&self) -> io::Result<()> {
+        let on_disk = read(&self.path, now() - MAX_AGE).unwrap_or_default();
+        let mut entries = self.entries.lock().unwrap();
+        let mut removed = self.removed.lock().unwrap();
+        for (id, e) in on_disk {
+            if removed.contains(&id) {
+                continue;
+            }
+            match entries.get(&id) {
+                Some(ours) if ours.verified >= e.verified => (),
+                _ => {
+                    entries.insert(id, e);
+                }
+            }
+        }
+        let mut buf = MAGIC.to_vec();
+        buf.write_u8(VERSION)?;
+        buf.write_u32::<BigEndian>(entries.len() as u32)?;
+        for (id, e) in entries.iter() {
+            write_str(&mut buf, id)?;
+            buf.write_u64::<BigEndian>(e.len)?;
+            buf.write_i64::<BigEndian>(e.mtime)?;
+            buf.write_i64::<BigEndian>(e.mtime_nsec)?;
+            buf.write_i64::<BigEndian>(e.verified)?;
+        }
+        drop(entries);
+        let hash = backend::hash(&buf);
+        buf.extend_from_slice(hash.as_bytes());
+        replace_file(&self.path, &buf)?;
+        removed.clear();
+        Ok(())
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Reads all entries verified after `since`.
+fn read(path: &Path, since: i64) -> io::Result<HashMap<String, Entry>> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    if buf.len() < MAGIC.len() + TRAILER || &buf[..MAGIC.len()] != MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    let (body, trailer) = buf.split_at(buf.len() - TRAILER);
+    if backend::hash(body).as_bytes() != trailer {
+        return Err(invalid("checksum mismatch"));
+    }
+    let mut r = Cursor::new(&body[MAGIC.len()..]);
+    if r.read_u8()? != VERSION {
+        return Err(invalid("unsupported version"));
+    }
+    let n = r.read_u32::<BigEndian>()?;
+    let mut entries = HashMap::with_capacity(n.min(1 << 20) as usize);
+    for _ in 0..n {
+        let id = read_str(&mut r)?;
+        let e = Entry {
+            len: r.read_u64::<BigEndian>()?,
+            mtime: r.read_i64::<BigEndian>()?,
+            mtime_nsec: r.read_i64::<BigEndian>()?,
+            verified: r.read_i64::<BigEndian>()?,
+        };
+        if e.verified > since {
+            entries.insert(id, e);
+        }
+    }
+    if r.position() != r.get_ref().len() as u64 {
+        return Err(invalid("trailing garbage"));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    #[test]
+    fn remember_unchanged_files() {
+        let td = TempDir::new("hashcache").unwrap();
+        let chunk = td.path().join("chunk");
+        fs::write(&chunk, b"data").unwrap();
+        let path = td.path().join("hashcache");
+        let cache = HashCache::open(&path);
+        assert!(cache.is_empty());
+        cache.insert("aa", &fs::metadata(&chunk).unwrap());
+        cache.insert("bb", &fs::metadata(&chunk).unwrap());
+        cache.remove("bb");
+        cache.save().unwrap();
+        // temporary files of other writers are left alone, ours is gone
+        fs::write(td.path().join("hashcache.tmp"), b"").unwrap();
+        cache.save().unwrap();
+        assert_eq!(fs::read_dir(td.path()).unwrap().count(), 3);
+
+        let cache = HashCache::open(&path);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.is_verified("aa", &fs::metadata(&chunk).unwrap()));
+        assert!(!cache.is_verified("bb", &fs::metadata(&chunk).unwrap()));
+        let f = File::options().write(true).open(&chunk).unwrap();
+        f.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(!cache.is_verified("aa", &fs::metadata(&chunk).unwrap()));
+
+        // entries saved by others are merged, ones removed here stay removed
+        let other = HashCache::open(&path);
+        other.insert("cc", &fs::metadata(&chunk).unwrap());
+        other.save().unwrap();
+        cache.remove("aa");
+        cache.save().unwrap();
+        let merged = HashCache::open(&path);
+        assert_eq!(merged.len(), 1);
+        assert!(merged.is_verified("cc", &fs::metadata(&chunk).unwrap()));
+
+        // expired entries are dropped
+        assert!(read(&path, now() + 1).unwrap().is_empty());
+
+        // damaged cache file
+        let mut data = fs::read(&path).unwrap();
+        data[12] ^= 0xff;
+        fs::write(&path, data).unwrap();
+        assert!(HashCache::open(&path).is_empty());
+    }
+}
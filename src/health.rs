@@ -0,0 +1,340 @@
+//! Quick health checks of backup directories for monitoring.
+//!
+//! A check looks at the chunk store version tag, the purge lock and the `.rev` files and reads a
+//! few randomly chosen chunks. It is meant to run every few minutes, so it never scans the whole
+//! store. Results can be rendered in the Prometheus text exposition format for the node exporter's
+//! textfile collector.
+
+use crate::backend::{Backend, Rev, Trust};
+use crate::chunkvec::ChunkVec;
+
+use chrono::Utc;
+use rand::seq::SliceRandom;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read backup dir '{}'", .0.display())]
+    ReadDir(PathBuf, #[source] io::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Result of checking a single backup directory.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Health {
+    pub dir: PathBuf,
+    /// Contents of `chunks/store`, None if missing
+    pub version: Option<String>,
+    /// True if the store has a supported version tag
+    pub store_ok: bool,
+    /// True if backy holds the purge lock exclusively, e.g. while purging
+    pub purge_locked: bool,
+    /// Number of readable `.rev` files
+    pub revisions: usize,
+    /// Number of `.rev` files which cannot be parsed or lack their chunk map
+    pub invalid_revisions: usize,
+    /// Number of valid revisions which backy distrusts
+    pub distrusted_revisions: usize,
+    /// Time stamp of the newest revision (seconds since the epoch)
+    pub newest_revision: Option<i64>,
+    /// Seconds since the newest revision has been taken
+    pub newest_revision_age: Option<f64>,
+    pub chunks_sampled: usize,
+    /// Sampled chunks which are missing, corrupt or don't match their checksum
+    pub chunks_failed: usize,
+    /// Time spent loading sampled chunks
+    pub chunk_read_seconds: f64,
+    /// Duration of the whole check
+    pub check_seconds: f64,
+}
+
+impl Health {
+    /// True if nothing suspicious has been found.
+    pub fn is_healthy(&self) -> bool {
+        self.store_ok && self.invalid_revisions == 0 && self.chunks_failed == 0
+    }
+}
+
+// Returns true if the purge lock can't be shared because someone holds it exclusively.
+fn purge_locked(dir: &Path) -> bool {
+    match File::open(dir.join(".purge")) {
+        Ok(f) => match f.try_lock_shared() {
+            Ok(()) => {
+                f.unlock().ok();
+                false
+            }
+            Err(_) => true,
+        },
+        Err(_) => false,
+    }
+}
+
+// Loads `id` and compares its checksum. Any failure counts.
+fn sample_ok(be: &Backend, id: &str) -> bool {
+    match be.load(id) {
+        Ok(data) => be.hash(&data) == id,
+        Err(_) => false,
+    }
+}
+
+/// Checks the backup directory `dir`, reading `samples` randomly chosen chunks from randomly
+/// chosen revisions.
+pub fn check<P: AsRef<Path>>(dir: P, samples: usize) -> Result<Health> {
+    let start = Instant::now();
+    let dir = dir.as_ref();
+    let mut h = Health {
+        dir: dir.to_owned(),
+        version: fs::read_to_string(dir.join("chunks/store"))
+            .ok()
+            .map(|v| v.trim().to_owned()),
+        purge_locked: purge_locked(dir),
+        ..Health::default()
+    };
+    let (revs, invalid): (Vec<_>, Vec<_>) = Rev::discover(dir)
+        .map_err(|e| Error::ReadDir(dir.to_owned(), e))?
+        .into_iter()
+        .partition(Result::is_ok);
+    let revs: Vec<Rev> = revs.into_iter().filter_map(Result::ok).collect();
+    h.invalid_revisions = invalid.len();
+    h.revisions = revs.len();
+    h.distrusted_revisions = revs.iter().filter(|r| r.trust == Trust::Distrusted).count();
+    if let Some(newest) = revs.iter().map(|r| r.timestamp).max() {
+        h.newest_revision = Some(newest.timestamp());
+        h.newest_revision_age = Some((Utc::now() - newest).num_milliseconds().max(0) as f64 / 1e3);
+    }
+    let be = match Backend::open(dir) {
+        Ok(be) => be,
+        Err(_) => {
+            h.check_seconds = start.elapsed().as_secs_f64();
+            return Ok(h);
+        }
+    };
+    h.store_ok = true;
+    let mut rng = rand::thread_rng();
+    let mut maps: HashMap<&str, Option<ChunkVec>> = HashMap::new();
+    let read_start = Instant::now();
+    for _ in 0..samples {
+        let rev = match revs.choose(&mut rng) {
+            Some(rev) => rev,
+            None => break,
+        };
+        let chunks = maps
+            .entry(&rev.uuid)
+            .or_insert_with(|| ChunkVec::load(dir.join(rev.uuid.as_str())).ok());
+        let ids: Vec<_> = match chunks {
+            Some(chunks) => chunks.ids().collect(),
+            None => {
+                h.chunks_failed += 1;
+                continue;
+            }
+        };
+        if let Some(id) = ids.choose(&mut rng) {
+            h.chunks_sampled += 1;
+            if !sample_ok(&be, id) {
+                h.chunks_failed += 1;
+            }
+        }
+    }
+    h.chunk_read_seconds = read_start.elapsed().as_secs_f64();
+    h.check_seconds = start.elapsed().as_secs_f64();
+    Ok(h)
+}
+
+// Escapes a Prometheus label value.
+pub(crate) fn label(s: &str) -> String {
+    s.replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+/// Renders checks of one or more backup dirs in the Prometheus text exposition format.
+pub fn prometheus(checks: &[Health]) -> String {
+    type Metric = (&'static str, &'static str, fn(&Health) -> Option<f64>);
+    let metrics: &[Metric] = &[
+        (
+            "backy_store_up",
+            "Whether the chunk store has a supported version tag",
+            |h| Some(if h.store_ok { 1.0 } else { 0.0 }),
+        ),
+        (
+            "backy_store_purge_locked",
+            "Whether backy holds the purge lock exclusively",
+            |h| Some(if h.purge_locked { 1.0 } else { 0.0 }),
+        ),
+        ("backy_store_revisions", "Number of valid revisions", |h| {
+            Some(h.revisions as f64)
+        }),
+        (
+            "backy_store_invalid_revisions",
+            "Number of unreadable revisions",
+            |h| Some(h.invalid_revisions as f64),
+        ),
+        (
+            "backy_store_distrusted_revisions",
+            "Number of revisions distrusted by backy",
+            |h| Some(h.distrusted_revisions as f64),
+        ),
+        (
+            "backy_store_newest_revision_timestamp_seconds",
+            "Time stamp of the newest revision",
+            |h| h.newest_revision.map(|t| t as f64),
+        ),
+        (
+            "backy_store_newest_revision_age_seconds",
+            "Age of the newest revision",
+            |h| h.newest_revision_age,
+        ),
+        ("backy_store_chunks_sampled", "Number of chunks read", |h| {
+            Some(h.chunks_sampled as f64)
+        }),
+        (
+            "backy_store_chunks_failed",
+            "Number of sampled chunks which failed to load",
+            |h| Some(h.chunks_failed as f64),
+        ),
+        (
+            "backy_store_chunk_read_seconds",
+            "Time spent reading sampled chunks",
+            |h| Some(h.chunk_read_seconds),
+        ),
+        (
+            "backy_store_check_seconds",
+            "Duration of the health check",
+            |h| Some(h.check_seconds),
+        ),
+    ];
+    let mut out = String::new();
+    out.push_str("# HELP backy_store_info Version tag of the chunk store\n");
+    out.push_str("# TYPE backy_store_info gauge\n");
+    for h in checks {
+        let dir = label(&h.dir.to_string_lossy());
+        let version = label(h.version.as_deref().unwrap_or(""));
+        writeln!(
+            out,
+            "backy_store_info{{dir=\"{}\",version=\"{}\"}} 1",
+            dir, version
+        )
+        .unwrap();
+    }
+    for (name, help, value) in metrics {
+        writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name).unwrap();
+        for h in checks {
+            if let Some(v) = value(h) {
+                writeln!(
+                    out,
+                    "{}{{dir=\"{}\"}} {}",
+                    name,
+                    label(&h.dir.to_string_lossy()),
+                    v
+                )
+                .unwrap();
+            }
+        }
+    }
+    out
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {}",
+            self.dir.display(),
+            if self.is_healthy() { "OK" } else { "PROBLEM" }
+        )?;
+        writeln!(
+            f,
+            "  store version: {}{}",
+            self.version.as_deref().unwrap_or("missing"),
+            if self.purge_locked {
+                " (purge locked)"
+            } else {
+                ""
+            }
+        )?;
+        writeln!(
+            f,
+            "  revisions: {} ({} invalid, {} distrusted)",
+            self.revisions, self.invalid_revisions, self.distrusted_revisions
+        )?;
+        if let Some(age) = self.newest_revision_age {
+            writeln!(f, "  newest revision: {:.0}s ago", age)?;
+        }
+        writeln!(
+            f,
+            "  chunks sampled: {} ({} failed) in {:.3}s",
+            self.chunks_sampled, self.chunks_failed, self.chunk_read_seconds
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::store_tar;
+    use fs2::FileExt;
+
+    #[test]
+    fn healthy_store() {
+        let store = store_tar();
+        let h = check(store.path(), 4).unwrap();
+        assert!(h.is_healthy(), "{:?}", h);
+        assert_eq!(h.version.as_deref(), Some("v2"));
+        assert_eq!((h.revisions, h.invalid_revisions), (1, 0));
+        assert_eq!(h.distrusted_revisions, 0);
+        assert_eq!(h.newest_revision, Some(1547228725));
+        assert_eq!((h.chunks_sampled, h.chunks_failed), (4, 0));
+        assert!(!h.purge_locked);
+    }
+
+    #[test]
+    fn detects_problems() {
+        let store = store_tar();
+        for f in fs::read_dir(store.path().join("chunks/c7")).unwrap() {
+            fs::remove_file(f.unwrap().path()).unwrap();
+        }
+        fs::write(store.path().join("broken.rev"), "garbage").unwrap();
+        let lock = File::open(store.path().join(".purge")).unwrap();
+        lock.lock_exclusive().unwrap();
+        let h = check(store.path(), 20).unwrap();
+        assert!(!h.is_healthy());
+        assert_eq!(h.invalid_revisions, 1);
+        assert!(h.chunks_failed > 0, "{:?}", h);
+        assert!(h.purge_locked);
+    }
+
+    #[test]
+    fn unsupported_store() {
+        let store = store_tar();
+        fs::write(store.path().join("chunks/store"), "v1").unwrap();
+        let h = check(store.path(), 4).unwrap();
+        assert!(!h.store_ok);
+        assert_eq!(h.chunks_sampled, 0);
+    }
+
+    #[test]
+    fn prometheus_format() {
+        let h = Health {
+            dir: "/srv/backy/\"vm\"".into(),
+            version: Some("v2".into()),
+            store_ok: true,
+            revisions: 3,
+            newest_revision: Some(1547228725),
+            ..Health::default()
+        };
+        let out = prometheus(&[h]);
+        assert!(out.contains("backy_store_info{dir=\"/srv/backy/\\\"vm\\\"\",version=\"v2\"} 1\n"));
+        assert!(out.contains("# TYPE backy_store_up gauge\nbacky_store_up{dir="));
+        assert!(out.contains("backy_store_revisions{dir=\"/srv/backy/\\\"vm\\\"\"} 3\n"));
+        assert!(out.contains("backy_store_newest_revision_timestamp_seconds{"));
+        assert!(!out.contains("backy_store_newest_revision_age_seconds{"));
+    }
+}
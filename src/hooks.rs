@@ -0,0 +1,138 @@
+//! Callbacks and commands which run after a restore has finished.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::FromRawFd;
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::Duration;
+
+/// Description of a finished restore, handed to hooks.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Job {
+    /// Revision ID
+    pub revision: String,
+    /// Restore target, empty if the restore failed before the target has been set up
+    pub target: String,
+    /// Number of bytes written, 0 if the restore failed
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Error message including all causes if the restore failed
+    pub error: Option<String>,
+    /// See [ExtractError::class](enum.ExtractError.html#method.class)
+    pub error_class: Option<&'static str>,
+}
+
+impl Job {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Environment variables which describe the job to hook commands.
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            (
+                "BACKY_EXTRACT_STATUS",
+                if self.succeeded() {
+                    "success"
+                } else {
+                    "failure"
+                }
+                .to_owned(),
+            ),
+            ("BACKY_EXTRACT_REVISION", self.revision.clone()),
+            ("BACKY_EXTRACT_TARGET", self.target.clone()),
+            ("BACKY_EXTRACT_BYTES", self.bytes.to_string()),
+            (
+                "BACKY_EXTRACT_SECONDS",
+                format!("{:.3}", self.elapsed.as_secs_f64()),
+            ),
+        ];
+        if let Some(e) = &self.error {
+            env.push(("BACKY_EXTRACT_ERROR", e.clone()));
+        }
+        if let Some(c) = self.error_class {
+            env.push(("BACKY_EXTRACT_ERROR_CLASS", c.to_owned()));
+        }
+        env
+    }
+
+    /// Runs `cmd` with `sh -c` and the variables from [env](#method.env) added to its
+    /// environment. Its standard output goes to stderr, as stdout may carry the restored image.
+    pub fn run(&self, cmd: &str) -> io::Result<ExitStatus> {
+        let stderr = match unsafe { libc::dup(2) } {
+            -1 => return Err(io::Error::last_os_error()),
+            fd => unsafe { File::from_raw_fd(fd) },
+        };
+        Command::new("/bin/sh")
+            .arg("-c")
+            .arg(cmd)
+            .envs(self.env())
+            .stdin(Stdio::null())
+            .stdout(stderr)
+            .status()
+    }
+}
+
+type Callback = Box<dyn Fn(&Job) + Send + Sync>;
+
+/// Callbacks registered with an [Extractor](struct.Extractor.html).
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub success: Vec<Callback>,
+    pub failure: Vec<Callback>,
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.success.is_empty() && self.failure.is_empty()
+    }
+
+    pub fn call(&self, job: &Job) {
+        let hooks = if job.succeeded() {
+            &self.success
+        } else {
+            &self.failure
+        };
+        for h in hooks {
+            h(job)
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "<Hooks success={} failure={}>",
+            self.success.len(),
+            self.failure.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_environment() {
+        let job = Job {
+            revision: "VNzWKjnMqd6w58nzJwUZ98".into(),
+            target: "/dev/null".into(),
+            error: Some("Chunk missing".into()),
+            error_class: Some("chunk"),
+            ..Job::default()
+        };
+        let status = job
+            .run(
+                "test \"$BACKY_EXTRACT_STATUS:$BACKY_EXTRACT_REVISION:$BACKY_EXTRACT_BYTES\" = \
+                 failure:VNzWKjnMqd6w58nzJwUZ98:0 && test \"$BACKY_EXTRACT_ERROR_CLASS\" = chunk",
+            )
+            .unwrap();
+        assert!(status.success());
+        let env = Job::default().env();
+        assert!(env.contains(&("BACKY_EXTRACT_STATUS", "success".to_owned())));
+        assert!(!env.iter().any(|(k, _)| *k == "BACKY_EXTRACT_ERROR"));
+    }
+}
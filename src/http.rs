@@ -0,0 +1,380 @@
+//! Read-only HTTP access to revision images.
+//!
+//! The raw image is served with support for single byte ranges, which is all that HTTP-backed
+//! block drivers like qemu's curl driver (used by guestfish and virt-inspector) need. Only GET
+//! and HEAD are supported and the image is found at `/` and `/<revision>`. Each connection is
+//! handled by its own thread which loads chunks on demand, so nothing is restored up front.
+//! At most `MAX_CONNECTIONS` clients are served at a time, further ones get a 503. Connections
+//! which stall for `TIMEOUT` are dropped. There is neither authentication nor TLS.
+
+use crate::backend::Backend;
+use crate::chunkvec::ChunkVec;
+use crate::guestfs::ReadAt;
+use crate::image::Image;
+use crate::CHUNKSZ;
+
+use log::{debug, info, warn};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Upper bound for request and header lines
+const MAX_LINE: u64 = 8192;
+const MAX_HEADERS: usize = 100;
+// Each connection holds a thread and up to one chunk of buffer
+const MAX_CONNECTIONS: usize = 64;
+// Applies to every single read or write on a connection, including waiting for the next request
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Part of the image requested by a `Range` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Span {
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+impl Span {
+    /// Interprets the `Range` header value `h` for an image of `size` bytes. Malformed headers
+    /// and multiple ranges are ignored, which RFC 7233 permits, so the whole image is sent.
+    fn parse(h: Option<&str>, size: u64) -> Self {
+        let spec = match h.and_then(|h| h.trim().strip_prefix("bytes=")) {
+            Some(s) if !s.contains(',') => s.trim(),
+            _ => return Span::Full,
+        };
+        let (first, last) = match spec.split_once('-') {
+            Some(fl) => fl,
+            None => return Span::Full,
+        };
+        let (first, last) = (first.trim(), last.trim());
+        let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+            (Ok(f), Ok(l)) if f <= l => f..l.saturating_add(1).min(size),
+            (Ok(f), Err(_)) if last.is_empty() => f..size,
+            (Err(_), Ok(n)) if first.is_empty() => size.saturating_sub(n)..size,
+            _ => return Span::Full,
+        };
+        if range.start >= range.end {
+            Span::Unsatisfiable
+        } else {
+            Span::Partial(range)
+        }
+    }
+}
+
+/// What is needed from an HTTP request.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+    /// Close the connection after the response
+    close: bool,
+}
+
+impl Request {
+    /// Reads the next request from `r`. Returns None if the client has closed the connection.
+    fn read<R: BufRead>(r: &mut R) -> io::Result<Option<Self>> {
+        let line = match read_line(r)? {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        let mut parts = line.split_whitespace();
+        let (method, path, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(m), Some(p), Some(v)) => (m, p, v),
+            _ => return Err(invalid(format!("bad request line {:?}", line))),
+        };
+        let mut req = Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            range: None,
+            close: version == "HTTP/1.0",
+        };
+        for _ in 0..MAX_HEADERS {
+            let header = read_line(r)?.ok_or_else(|| invalid("truncated header".into()))?;
+            if header.is_empty() {
+                return Ok(Some(req));
+            }
+            let (name, value) = match header.split_once(':') {
+                Some((n, v)) => (n.trim().to_ascii_lowercase(), v.trim()),
+                None => continue,
+            };
+            match name.as_str() {
+                "range" => req.range = Some(value.to_owned()),
+                "connection" => req.close = !value.eq_ignore_ascii_case("keep-alive"),
+                _ => (),
+            }
+        }
+        Err(invalid("too many headers".into()))
+    }
+}
+
+// Reads a line without its line terminator. None at EOF.
+fn read_line<R: BufRead>(r: &mut R) -> io::Result<Option<String>> {
+    let mut buf = String::new();
+    if r.take(MAX_LINE).read_line(&mut buf)? == 0 {
+        return Ok(None);
+    }
+    if !buf.ends_with('\n') {
+        return Err(invalid("line too long".into()));
+    }
+    Ok(Some(buf.trim_end_matches(&['\r', '\n'][..]).to_owned()))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Answers requests for the image `img` named `name` until the client goes away.
+fn handle<S: Read + Write>(conn: S, name: &str, img: &mut dyn ReadAt, size: u64) -> io::Result<()> {
+    let mut conn = BufReader::new(conn);
+    // allocated once a request for data comes in, HEAD and errors don't need it
+    let mut buf = Vec::new();
+    while let Some(req) = Request::read(&mut conn)? {
+        debug!("{} {} (range {:?})", req.method, req.path, req.range);
+        let mut out = BufWriter::new(conn.get_mut());
+        let head = req.method == "HEAD";
+        let path = req.path.trim_start_matches('/');
+        let (status, range) = if req.method != "GET" && !head {
+            ("405 Method Not Allowed", None)
+        } else if !path.is_empty() && path != name {
+            ("404 Not Found", None)
+        } else {
+            match Span::parse(req.range.as_deref(), size) {
+                Span::Full => ("200 OK", Some(0..size)),
+                Span::Partial(r) => ("206 Partial Content", Some(r)),
+                Span::Unsatisfiable => ("416 Range Not Satisfiable", None),
+            }
+        };
+        // fail before sending headers if the first piece can't be loaded
+        let mut first = 0;
+        if let (Some(r), false) = (&range, head) {
+            first = (r.end - r.start).min(CHUNKSZ as u64) as usize;
+            if buf.len() < first {
+                buf.resize(first, 0);
+            }
+            if let Err(e) = img.read_at(r.start, &mut buf[..first]) {
+                warn!("GET {}: {}", req.path, e);
+                write!(out, "HTTP/1.1 500 Internal Server Error\r\n")?;
+                write!(out, "Content-Length: 0\r\nConnection: close\r\n\r\n")?;
+                return out.flush();
+            }
+        }
+        write!(out, "HTTP/1.1 {}\r\nAccept-Ranges: bytes\r\n", status)?;
+        match &range {
+            Some(r) => {
+                write!(out, "Content-Type: application/octet-stream\r\n")?;
+                write!(out, "Content-Length: {}\r\n", r.end - r.start)?;
+                if status.starts_with("206") {
+                    write!(
+                        out,
+                        "Content-Range: bytes {}-{}/{}\r\n",
+                        r.start,
+                        r.end - 1,
+                        size
+                    )?;
+                }
+            }
+            None if status.starts_with("416") => write!(
+                out,
+                "Content-Range: bytes */{}\r\nContent-Length: 0\r\n",
+                size
+            )?,
+            None if status.starts_with("405") => {
+                write!(out, "Allow: GET, HEAD\r\nContent-Length: 0\r\n")?
+            }
+            None => write!(out, "Content-Length: 0\r\n")?,
+        }
+        if req.close {
+            write!(out, "Connection: close\r\n")?;
+        }
+        write!(out, "\r\n")?;
+        if let (Some(r), false) = (range, head) {
+            out.write_all(&buf[..first])?;
+            let mut pos = r.start + first as u64;
+            while pos < r.end {
+                let n = (r.end - pos).min(first as u64) as usize;
+                // headers are out already: all we can do is to hang up
+                img.read_at(pos, &mut buf[..n])?;
+                out.write_all(&buf[..n])?;
+                pos += n as u64;
+            }
+        }
+        out.flush()?;
+        if req.close {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Counts a connection as active until dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(active: &Arc<AtomicUsize>) -> Option<Self> {
+        if active.fetch_add(1, Ordering::SeqCst) < MAX_CONNECTIONS {
+            Some(Self(Arc::clone(active)))
+        } else {
+            active.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Accepts connections on `listener` and serves the image described by `chunks` from
+/// `backend`, each connection in a thread of its own. Returns only if accepting fails.
+pub(crate) fn serve(
+    listener: TcpListener,
+    name: &str,
+    backend: Backend,
+    chunks: Arc<ChunkVec>,
+) -> io::Result<()> {
+    let active = Arc::new(AtomicUsize::new(0));
+    for conn in listener.incoming() {
+        let mut conn: TcpStream = match conn {
+            Ok(c) => c,
+            Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+            Err(e) => return Err(e),
+        };
+        let peer = conn
+            .peer_addr()
+            .map_or_else(|_| "?".to_owned(), |a| a.to_string());
+        if let Err(e) = conn
+            .set_read_timeout(Some(TIMEOUT))
+            .and_then(|_| conn.set_write_timeout(Some(TIMEOUT)))
+        {
+            warn!("{}: {}", peer, e);
+            continue;
+        }
+        let slot = match Slot::take(&active) {
+            Some(s) => s,
+            None => {
+                warn!("{}: too many connections", peer);
+                write!(
+                    conn,
+                    "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\
+                     Connection: close\r\n\r\n"
+                )
+                .ok();
+                continue;
+            }
+        };
+        info!("{}: connected", peer);
+        let (name, be, chunks) = (name.to_owned(), backend.clone(), Arc::clone(&chunks));
+        thread::Builder::new().name("http".into()).spawn(move || {
+            let _slot = slot;
+            let mut img = Image::new(be, &chunks);
+            match handle(conn, &name, &mut img, chunks.size) {
+                Ok(()) => info!("{}: closed", peer),
+                Err(e) => warn!("{}: {}", peer, e),
+            }
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn ranges() {
+        let p = |h| Span::parse(Some(h), 1000);
+        assert_eq!(Span::parse(None, 1000), Span::Full);
+        assert_eq!(p("bytes=0-99"), Span::Partial(0..100));
+        assert_eq!(p("bytes=900-2000"), Span::Partial(900..1000));
+        assert_eq!(p("bytes=990-"), Span::Partial(990..1000));
+        assert_eq!(p("bytes=-10"), Span::Partial(990..1000));
+        assert_eq!(p("bytes=-5000"), Span::Partial(0..1000));
+        assert_eq!(p("bytes=1000-"), Span::Unsatisfiable);
+        assert_eq!(p("bytes=-0"), Span::Unsatisfiable);
+        assert_eq!(p("bytes=0-1,5-6"), Span::Full);
+        assert_eq!(p("bytes=20-10"), Span::Full);
+        assert_eq!(p("items=0-1"), Span::Full);
+    }
+
+    // In-memory connection: reads the requests, collects the responses
+    struct Conn(Cursor<Vec<u8>>, Vec<u8>);
+
+    impl Read for Conn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Conn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn exchange(requests: &str, image: &[u8]) -> String {
+        let mut conn = Conn(Cursor::new(requests.as_bytes().to_vec()), Vec::new());
+        let mut img = Cursor::new(image.to_vec());
+        handle(&mut conn, "rev", &mut img, image.len() as u64).unwrap();
+        String::from_utf8(conn.1).unwrap()
+    }
+
+    impl ReadAt for Cursor<Vec<u8>> {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+            self.set_position(offset);
+            self.read_exact(buf)
+        }
+    }
+
+    #[test]
+    fn limit_connections() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let slots: Vec<Slot> = (0..MAX_CONNECTIONS)
+            .map(|_| Slot::take(&active).unwrap())
+            .collect();
+        assert!(Slot::take(&active).is_none());
+        assert_eq!(active.load(Ordering::SeqCst), MAX_CONNECTIONS);
+        drop(slots);
+        assert!(Slot::take(&active).is_some());
+    }
+
+    #[test]
+    fn responses() {
+        let img = b"0123456789";
+        let resp = exchange(
+            "GET /rev HTTP/1.1\r\nHost: x\r\nRange: bytes=2-4\r\n\r\n\
+             HEAD / HTTP/1.1\r\n\r\n\
+             GET /rev HTTP/1.1\r\nRange: bytes=10-\r\n\r\n\
+             GET /other HTTP/1.1\r\n\r\n\
+             PUT / HTTP/1.1\r\n\r\n\
+             GET / HTTP/1.1\r\nConnection: close\r\n\r\n\
+             GET / HTTP/1.1\r\n\r\n",
+            img,
+        );
+        let parts: Vec<&str> = resp.split("HTTP/1.1 ").skip(1).collect();
+        assert_eq!(parts.len(), 6, "{}", resp);
+        assert!(parts[0].starts_with("206 "));
+        assert!(parts[0].contains("Content-Range: bytes 2-4/10\r\n"));
+        assert!(parts[0].ends_with("\r\n\r\n234"));
+        assert!(parts[1].starts_with("200 "));
+        assert!(parts[1].contains("Content-Length: 10\r\n"));
+        assert!(parts[1].ends_with("\r\n\r\n"));
+        assert!(parts[2].starts_with("416 "));
+        assert!(parts[2].contains("Content-Range: bytes */10\r\n"));
+        assert!(parts[3].starts_with("404 "));
+        assert!(parts[4].starts_with("405 "));
+        assert!(parts[5].contains("Connection: close\r\n"));
+        assert!(parts[5].ends_with("\r\n\r\n0123456789"));
+    }
+}
@@ -0,0 +1,73 @@
+//! Random read access to revision images without restoring them.
+
+use crate::backend::Backend;
+use crate::chunkvec::{ChunkId, ChunkVec};
+use crate::guestfs::ReadAt;
+use crate::{pos2chunk, ExtractError, CHUNKSZ};
+
+use lru::LruCache;
+use std::io;
+use std::rc::Rc;
+
+// Number of decompressed chunks kept in memory
+const CACHED_CHUNKS: usize = 8;
+
+/// Image of a single revision. Chunks are loaded on demand.
+#[derive(Debug)]
+pub(crate) struct Image {
+    backend: Backend,
+    map: Vec<Option<ChunkId>>,
+    size: u64,
+    cache: LruCache<u32, Rc<Vec<u8>>>,
+}
+
+impl Image {
+    pub(crate) fn new(backend: Backend, chunks: &ChunkVec) -> Self {
+        Self {
+            backend,
+            map: chunks.by_seq(),
+            size: chunks.size,
+            cache: LruCache::new(CACHED_CHUNKS),
+        }
+    }
+
+    fn chunk(&mut self, seq: u32) -> io::Result<Option<Rc<Vec<u8>>>> {
+        let id = match self.map.get(seq as usize) {
+            Some(Some(id)) => id,
+            Some(None) => return Ok(None),
+            None => return Err(beyond_end()),
+        };
+        if let Some(data) = self.cache.get(&seq) {
+            return Ok(Some(Rc::clone(data)));
+        }
+        let data = Rc::new(self.backend.load(id).map_err(|e| {
+            io::Error::other(ExtractError::invalid_chunk(&self.backend, seq, id, e))
+        })?);
+        self.cache.put(seq, Rc::clone(&data));
+        Ok(Some(data))
+    }
+}
+
+fn beyond_end() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "read beyond end of image")
+}
+
+impl ReadAt for Image {
+    fn read_at(&mut self, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        match offset.checked_add(buf.len() as u64) {
+            Some(end) if end <= self.size => (),
+            _ => return Err(beyond_end()),
+        }
+        while !buf.is_empty() {
+            let start = (offset % CHUNKSZ as u64) as usize;
+            let n = buf.len().min(CHUNKSZ - start);
+            match self.chunk(pos2chunk(offset))? {
+                Some(data) => buf[..n].copy_from_slice(&data[start..start + n]),
+                None => buf[..n].iter_mut().for_each(|b| *b = 0),
+            }
+            offset += n as u64;
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+}
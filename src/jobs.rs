@@ -0,0 +1,196 @@
+//! Coordination file listing the restores which are running on a backup directory.
+//!
+//! Restores register themselves in `restore.lock` next to the `.purge` lock. The file contains
+//! one JSON object per active restore, e.g.:
+//!
+//! ```text
+//! {"pid":4711,"revision":"VNzWKjnMqd6w58nzJwUZ98","started_at":"2021-03-01T12:00:00.000Z",
+//!  "target":"/dev/vg/restore"}
+//! ```
+//!
+//! (wrapped here for readability). The file is advisory: it does not keep anyone from doing
+//! anything, but lets backy and other tools find out whether it is a good time to purge or to
+//! move a backup directory. Writers hold an exclusive `flock` on the file while rewriting it,
+//! readers a shared one. Entries of processes which no longer exist are left out when the file
+//! is read and dropped the next time it is written.
+
+use chrono::{SecondsFormat, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Name of the coordination file inside the backup directory.
+pub const RESTORE_LOCK: &str = "restore.lock";
+
+/// Entry in `restore.lock`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RestoreJob {
+    pub pid: u32,
+    /// Revision ID
+    pub revision: String,
+    /// RFC 3339 time stamp
+    pub started_at: String,
+    /// Restore target as shown to users
+    pub target: String,
+}
+
+impl RestoreJob {
+    /// Returns true if the process which has registered the job still exists.
+    pub fn is_alive(&self) -> bool {
+        let pid = self.pid as libc::pid_t;
+        // signal 0 only checks whether the process exists and may be signalled
+        pid > 0
+            && (unsafe { libc::kill(pid, 0) } == 0
+                || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+    }
+}
+
+impl fmt::Display for RestoreJob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>7}  {}  {}  {}",
+            self.pid, self.started_at, self.revision, self.target
+        )
+    }
+}
+
+fn parse(contents: &str) -> impl Iterator<Item = RestoreJob> + '_ {
+    // skip garbage instead of failing: the file is shared with other tools
+    contents
+        .lines()
+        .filter_map(|l| serde_json::from_str::<RestoreJob>(l).ok())
+        .filter(RestoreJob::is_alive)
+}
+
+/// Lists the active restores registered in the backup directory `dir`, oldest first.
+pub fn list<P: AsRef<Path>>(dir: P) -> io::Result<Vec<RestoreJob>> {
+    let mut f = match File::open(dir.as_ref().join(RESTORE_LOCK)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    f.lock_shared()?;
+    let mut contents = String::new();
+    f.read_to_string(&mut contents)?;
+    f.unlock()?;
+    let mut jobs: Vec<_> = parse(&contents).collect();
+    jobs.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+    Ok(jobs)
+}
+
+// Rewrites the coordination file with `f` applied to the list of live entries.
+fn update(path: &Path, f: impl FnOnce(&mut Vec<RestoreJob>)) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    file.lock_exclusive()?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let mut jobs: Vec<_> = parse(&contents).collect();
+    f(&mut jobs);
+    let mut out = Vec::new();
+    for j in &jobs {
+        serde_json::to_writer(&mut out, j)?;
+        out.push(b'\n');
+    }
+    file.seek(SeekFrom::Start(0))?;
+    file.set_len(0)?;
+    file.write_all(&out)?;
+    file.unlock()
+}
+
+/// Registration of a running restore. The entry is removed from `restore.lock` when this is
+/// dropped.
+#[derive(Debug)]
+pub struct Registration {
+    path: PathBuf,
+    job: RestoreJob,
+}
+
+impl Registration {
+    pub fn job(&self) -> &RestoreJob {
+        &self.job
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let job = &self.job;
+        update(&self.path, |jobs| {
+            if let Some(i) = jobs.iter().position(|j| j == job) {
+                jobs.remove(i);
+            }
+        })
+        .ok();
+    }
+}
+
+/// Adds a restore of `revision` to `target` by the current process to `restore.lock` in the
+/// backup directory `dir`. The file is created if necessary.
+pub fn register<P: AsRef<Path>>(dir: P, revision: &str, target: &str) -> io::Result<Registration> {
+    let path = dir.as_ref().join(RESTORE_LOCK);
+    let job = RestoreJob {
+        pid: process::id(),
+        revision: revision.to_owned(),
+        started_at: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        target: target.to_owned(),
+    };
+    update(&path, |jobs| jobs.push(job.clone()))?;
+    Ok(Registration { path, job })
+}
+
+/// Prints `jobs` as a table.
+pub fn write_jobs<W: Write>(jobs: &[RestoreJob], mut w: W) -> io::Result<()> {
+    writeln!(
+        w,
+        "{:>7}  {:24}  {:22}  TARGET",
+        "PID", "STARTED", "REVISION"
+    )?;
+    for j in jobs {
+        writeln!(w, "{}", j)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn register_and_list() -> io::Result<()> {
+        let td = TempDir::new("jobs")?;
+        assert!(list(td.path())?.is_empty());
+        let dead = RestoreJob {
+            pid: u32::MAX >> 1,
+            revision: "dead".to_owned(),
+            started_at: "2021-03-01T12:00:00.000Z".to_owned(),
+            target: "stdout".to_owned(),
+        };
+        fs::write(
+            td.path().join(RESTORE_LOCK),
+            format!("{}\ngarbage\n", serde_json::to_string(&dead)?),
+        )?;
+        let a = register(td.path(), "rev1", "/dev/null")?;
+        let b = register(td.path(), "rev2", "stdout")?;
+        let jobs = list(td.path())?;
+        assert_eq!(jobs, vec![a.job().clone(), b.job().clone()]);
+        assert_eq!(jobs[0].pid, process::id());
+        // entries of dead processes are dropped on write
+        assert!(!fs::read_to_string(td.path().join(RESTORE_LOCK))?.contains("dead"));
+        drop(a);
+        assert_eq!(list(td.path())?, vec![b.job().clone()]);
+        drop(b);
+        assert!(list(td.path())?.is_empty());
+        Ok(())
+    }
+}
@@ -3,31 +3,112 @@
 //! `backy_extract` reads an backup revision from a backy *chunked v2* data store, decompresses it
 //! on the fly and writes it to a restore target using pluggable writeout modules.
 
+pub mod archive;
+pub mod audit;
 mod backend;
 mod chunkvec;
+mod compression;
+mod cputime;
+mod damage;
+pub mod export;
+pub mod filter;
+mod framing;
 #[cfg(feature = "fuse_driver")]
 pub mod fuse;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
+pub mod guestfs;
+mod hashcache;
+pub mod health;
+mod hooks;
+mod http;
+mod image;
+pub mod jobs;
+mod limits;
+mod mapcache;
+#[cfg(feature = "cli")]
+mod metrics;
+pub mod partition;
+mod patch;
+#[cfg(feature = "cli")]
+pub mod picker;
+mod plan;
+mod pool;
+pub mod prep;
+mod profile;
+mod progress;
+pub mod refs;
+pub mod remote;
+mod revindex;
+pub mod revisions;
+mod spec;
+mod status;
 #[cfg(test)]
 mod test_helper;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(target_os = "linux")]
+pub mod watch;
 mod writeout;
 
-use self::backend::Backend;
-use self::chunkvec::ChunkVec;
-pub use self::writeout::{RandomAccess, Stream};
-use self::writeout::{WriteOut, WriteOutBuilder};
+use self::audit::{AuditLog, Trail};
+use self::backend::{Backend, RawChunk, Rev};
+pub use self::backend::{
+    Capabilities, Check, Codec, Error as BackendError, Fault, FdBudget, Fsync, HashAlgo,
+    Header as ChunkHeader, Layout, ReadStrategy, RevError, Trust, READ_STRATEGIES,
+};
+pub use self::chunkvec::RestoreOrder;
+use self::chunkvec::{ChunkId, ChunkVec};
+pub use self::compression::{ChunkRatio, CompressionReport, HeatmapBucket};
+use self::cputime::Stage;
+pub use self::cputime::{CpuReport, CpuTime};
+pub use self::damage::{DamageReport, DamagedChunk, DamagedExtent, Precheck};
+pub use self::filter::ChunkFilter;
+use self::guestfs::ext4::Ext4;
+use self::guestfs::FileStats;
+pub use self::hashcache::HashCache;
+use self::hooks::Hooks;
+pub use self::hooks::Job;
+use self::image::Image;
+pub use self::limits::{Concurrency, IoClass, Limits, ParseIoClassError};
+pub use self::mapcache::MapCache;
+pub use self::patch::PatchReport;
+pub use self::plan::RestorePlan;
+pub use self::pool::RestorePool;
+pub use self::profile::{ParseProfileError, Profile, PROFILES};
+#[cfg(feature = "cli")]
+pub use self::progress::ChunkBar;
+use self::progress::{style, Console};
+pub use self::progress::{BarStyle, HumanBytes, Progress, ProgressSink};
+pub use self::spec::RevisionSpec;
+pub use self::status::{Phase, RestoreStatus};
+#[cfg(feature = "tus")]
+pub use self::writeout::Tus;
+pub use self::writeout::{
+    ChunkIter, Error as WriteError, FailedRange, Memory, Nbd, Prealloc, RandomAccess, RbdDiff,
+    RbdDiffWriter, ReorderStats, Retry, Stream, StreamHeader, Vhd,
+};
+use self::writeout::{WriteOut, WriteOutBuilder, WriteReport};
 
-use console::{style, StyledObject};
-use crossbeam::channel::{bounded, unbounded, Receiver};
+use crossbeam::channel::{bounded, SendError};
 use crossbeam::thread;
 use fs2::FileExt;
-use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
-use lazy_static::lazy_static;
-use memmap::MmapMut;
+use log::warn;
+use memmap::{Mmap, MmapMut};
 use smallvec::SmallVec;
+use std::fmt;
 use std::fs::{self, File, OpenOptions};
-use std::io;
+use std::io::{self, Write};
+use std::iter;
+use std::net::TcpListener;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::time::Instant;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -38,33 +119,251 @@ pub enum ExtractError {
     DecodeMap(String, #[source] serde_json::Error),
     #[error("Image size {0} is not a multiple of chunk size")]
     UnalignedSize(u64),
+    #[error("Invalid revision map: {0}")]
+    InvalidMap(String),
     #[error("Unexpected file format in backup dir '{}'", .0.display())]
     BackupFormat(PathBuf),
     #[error("Failed to acquire purge lock for backup dir '{}'", .0.display())]
     Lock(PathBuf, #[source] io::Error),
-    #[error("Error while loading chunk #{seq} ({id})")]
+    #[error("Error while loading chunk #{seq} ({id}) at offset {} in {file}", chunk2pos(*.seq))]
     InvalidChunk {
         seq: u32,
         id: String,
+        file: Box<ChunkFile>,
         source: backend::Error,
     },
+    #[error(
+        "Checksum mismatch in chunk #{seq} ({id}) at offset {}: content hashes to {actual} in {file}",
+        chunk2pos(*.seq)
+    )]
+    Checksum {
+        seq: u32,
+        id: String,
+        actual: String,
+        file: Box<ChunkFile>,
+    },
     #[error("Chunked backend error")]
     Backend(#[from] backend::Error),
+    #[error("Failed to read revision metadata")]
+    RevMeta(#[source] RevError),
+    #[error("Revision {rev} is {trust}, but at least {required} is required")]
+    Untrusted {
+        rev: String,
+        trust: Trust,
+        required: Trust,
+    },
+    #[error("Failed to set scheduling priority")]
+    Priority(#[source] io::Error),
+    #[error("Partition {0} not found")]
+    NoPartition(u32),
+    #[error("Failed to read guest file system")]
+    GuestFs(#[from] guestfs::Error),
     #[error("IPC error")]
     SendChunk(#[from] crossbeam::channel::SendError<Chunk>),
     #[error("Write error")]
     WriteError(#[from] writeout::Error),
+    #[error("Invalid filter")]
+    Filter(#[from] filter::Error),
+    #[error("Failed to accept HTTP connections")]
+    Serve(#[source] io::Error),
+    #[error("Bucket size {0} exceeds the image size")]
+    BucketSize(u64),
 }
 
 type Result<T, E = ExtractError> = std::result::Result<T, E>;
 
+/// Chunk file behind a chunk error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkFile {
+    /// Path in the primary chunk store
+    pub path: PathBuf,
+    /// File size, None if the file cannot be accessed
+    pub compressed: Option<u64>,
+}
+
+impl ChunkFile {
+    fn new(be: &Backend, id: &str) -> Box<Self> {
+        let path = be.filename(id);
+        Box::new(Self {
+            compressed: fs::metadata(&path).ok().map(|m| m.len()),
+            path,
+        })
+    }
+}
+
+impl fmt::Display for ChunkFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.compressed {
+            Some(n) => write!(f, "{} ({} bytes)", self.path.display(), n),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
+impl ExtractError {
+    /// Failure to load chunk `id`, which belongs at `seq`, from `be`.
+    pub(crate) fn invalid_chunk(be: &Backend, seq: u32, id: &str, source: backend::Error) -> Self {
+        ExtractError::InvalidChunk {
+            seq,
+            id: id.to_owned(),
+            file: ChunkFile::new(be, id),
+            source,
+        }
+    }
+
+    /// Chunk `id` of `be` has been loaded but its contents hash to `actual`.
+    pub(crate) fn checksum(be: &Backend, seq: u32, id: &str, actual: String) -> Self {
+        ExtractError::Checksum {
+            seq,
+            id: id.to_owned(),
+            actual,
+            file: ChunkFile::new(be, id),
+        }
+    }
+
+    /// Chunk file concerned by a chunk error.
+    pub fn chunk_file(&self) -> Option<&Path> {
+        match self {
+            ExtractError::InvalidChunk { file, .. } | ExtractError::Checksum { file, .. } => {
+                Some(&file.path)
+            }
+            _ => None,
+        }
+    }
+
+    /// Integrity check which a chunk has failed. None for I/O errors and errors which don't
+    /// concern single chunks.
+    pub fn failed_check(&self) -> Option<Check> {
+        match self {
+            ExtractError::InvalidChunk { source, .. } => source.check(),
+            ExtractError::Checksum { .. } => Some(Check::Hash),
+            _ => None,
+        }
+    }
+
+    /// Coarse category of the error, suitable for scripts and alerting: `revision` (spec or map
+    /// unusable), `lock`, `store` (chunk store inaccessible), `chunk` (missing or corrupt chunk),
+    /// `write` (restore target), `guestfs`, `filter` (invalid filter spec), `usage` (other invalid
+    /// arguments) or `system`.
+    pub fn class(&self) -> &'static str {
+        use ExtractError::*;
+        match self {
+            LoadSpec(..)
+            | DecodeMap(..)
+            | UnalignedSize(_)
+            | InvalidMap(_)
+            | RevMeta(_)
+            | Untrusted { .. } => "revision",
+            Lock(..) => "lock",
+            BackupFormat(_) | Backend(_) => "store",
+            InvalidChunk { .. } | Checksum { .. } => "chunk",
+            WriteError(_) => "write",
+            NoPartition(_) | GuestFs(_) => "guestfs",
+            Filter(_) => "filter",
+            BucketSize(_) => "usage",
+            Priority(_) | SendChunk(_) | Serve(_) => "system",
+        }
+    }
+
+    /// Tells how a failure to access the revision, the purge lock, the chunk store or single
+    /// chunks should be dealt with, e.g. whether retrying makes sense. None for other errors.
+    pub fn fault(&self) -> Option<Fault> {
+        use ExtractError::*;
+        match self {
+            LoadSpec(_, e) | Lock(_, e) => Some(Fault::of(e)),
+            InvalidChunk { source, .. } => source.fault(),
+            Backend(e) => e.fault(),
+            DecodeMap(..)
+            | UnalignedSize(_)
+            | InvalidMap(_)
+            | BackupFormat(_)
+            | Checksum { .. } => Some(Fault::Corrupt),
+            _ => None,
+        }
+    }
+
+    /// Image regions which have not been written because the writer has gone on after write
+    /// errors, see [Retry](struct.Retry.html). Empty for other errors.
+    pub fn failed_ranges(&self) -> &[FailedRange] {
+        match self {
+            ExtractError::WriteError(writeout::Error::Partial(ranges)) => ranges,
+            _ => &[],
+        }
+    }
+
+    /// True if the operation may succeed when retried later.
+    pub fn is_transient(&self) -> bool {
+        matches!(self.fault(), Some(Fault::Transient))
+    }
+}
+
+// Formats an error message followed by all its causes.
+pub(crate) fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut msg = err.to_string();
+    let mut source = err.source();
+    while let Some(e) = source {
+        msg.push_str(&format!(": {}", e));
+        source = e.source();
+    }
+    msg
+}
+
 /// Size of an uncompressed Chunk in the backy store as 2's exponent.
 pub const CHUNKSZ_LOG: usize = 22; // 4 MiB
 pub const CHUNKSZ: usize = 1 << CHUNKSZ_LOG; // The value must fit into u32 because it is encoded
                                              // as 32 bit uint the chunk header.
 
-lazy_static! {
-    static ref ZERO_CHUNK: MmapMut = MmapMut::map_anon(CHUNKSZ).expect("mmap");
+// Compared against piece by piece in `is_zero`
+static ZEROS: [u8; 4096] = [0; 4096];
+
+/// Returns true if `buf` consists of NUL bytes only.
+pub(crate) fn is_zero(buf: &[u8]) -> bool {
+    buf.chunks(ZEROS.len()).all(|c| c == &ZEROS[..c.len()])
+}
+
+#[derive(Debug)]
+enum ZeroChunk {
+    Mapped(Mmap),
+    Heap(Vec<u8>),
+}
+
+impl AsRef<[u8]> for ZeroChunk {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            ZeroChunk::Mapped(m) => m,
+            ZeroChunk::Heap(v) => v,
+        }
+    }
+}
+
+static ZERO_CHUNK: OnceLock<ZeroChunk> = OnceLock::new();
+
+/// Returns a chunk of NUL bytes, e.g. to write zeros to targets which cannot punch holes.
+///
+/// The chunk is allocated on first use. Anonymous mappings are preferred since they don't
+/// take up memory until touched, but systems with tight mmap limits get a heap buffer
+/// instead. Fails if neither can be had. Failed attempts are not cached.
+pub(crate) fn zero_chunk() -> io::Result<&'static [u8]> {
+    if let Some(z) = ZERO_CHUNK.get() {
+        return Ok(z.as_ref());
+    }
+    let z = match MmapMut::map_anon(CHUNKSZ).and_then(MmapMut::make_read_only) {
+        Ok(m) => ZeroChunk::Mapped(m),
+        Err(e) => {
+            warn!("Failed to map zero chunk, allocating it on the heap: {}", e);
+            let mut buf = Vec::new();
+            buf.try_reserve_exact(CHUNKSZ).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::OutOfMemory,
+                    format!("Failed to allocate zero chunk: {}", e),
+                )
+            })?;
+            buf.resize(CHUNKSZ, 0);
+            ZeroChunk::Heap(buf)
+        }
+    };
+    // a concurrent caller may have won the race, the loser's allocation is dropped
+    Ok(ZERO_CHUNK.get_or_init(|| z).as_ref())
 }
 
 /// Transport of a single image data chunk.
@@ -95,6 +394,35 @@ fn chunk2pos(seq: u32) -> u64 {
     (seq as u64) << CHUNKSZ_LOG
 }
 
+/// Name for a temporary file next to `path` which no other process or thread uses at the same
+/// time. Create it with `create_new` to be sure.
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        SEQ.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+/// Replaces the file at `path` with `data` so that readers see either the old or the new contents.
+/// The temporary file's name is unique, so concurrent writers don't clobber each other's halves:
+/// the last rename wins.
+pub(crate) fn replace_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = tmp_path(path);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+        .and_then(|mut f| f.write_all(data))
+        .and_then(|_| fs::rename(&tmp, path))
+        .inspect_err(|_| {
+            fs::remove_file(&tmp).ok();
+        })
+}
+
 /// Aqcuire 'purge' lock which prevents backy from deleting chunks
 pub fn purgelock(basedir: &Path) -> Result<File, io::Error> {
     let f = OpenOptions::new()
@@ -105,10 +433,26 @@ pub fn purgelock(basedir: &Path) -> Result<File, io::Error> {
     Ok(f)
 }
 
-fn step(i: u32) -> StyledObject<String> {
+fn step(i: u32) -> impl fmt::Display {
     style(format!("[{}/4]", i)).blue()
 }
 
+/// Summary of a completed restore.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractReport {
+    /// Number of bytes written
+    pub bytes: u64,
+    /// Number of unique chunks written, counting all zero positions as one chunk
+    pub chunks: u64,
+    pub elapsed: Duration,
+    /// Reorder queue statistics if the writer restores in sequence order
+    pub reorder: Option<ReorderStats>,
+    /// Number of chunks written as zeros because they hold only free guest file system space
+    pub unallocated: usize,
+    /// CPU time used per pipeline stage
+    pub cpu: CpuReport,
+}
+
 /// Controls the extraction process.
 ///
 /// An `Extractor` must be initialized with a backy revision specification and a writer. It then
@@ -116,11 +460,41 @@ fn step(i: u32) -> StyledObject<String> {
 /// caller-supplied writer.
 #[derive(Debug)]
 pub struct Extractor {
-    revision: String,
-    threads: u8,
+    /// Revision ID
+    name: String,
+    /// Revision map file contents
+    revision: RawChunk,
+    map_cache: Option<MapCache>,
+    /// Skip chunks verified by earlier damage reports
+    hash_cache: bool,
+    /// Parsed on first use
+    spec: OnceLock<RevisionSpec>,
+    concurrency: Concurrency,
+    codec: Codec,
+    reader: ReadStrategy,
+    verify: bool,
+    /// Percentage of chunks verified if set
+    verify_sample: Option<f64>,
+    filters: Vec<Box<dyn ChunkFilter>>,
+    reorder_window: usize,
+    priority: Vec<Range<u64>>,
+    shared_first: bool,
+    order: RestoreOrder,
+    limits: Limits,
     basedir: PathBuf,
     lock: File,
-    progress: ProgressBar,
+    progress: Console,
+    bar_style: BarStyle,
+    sink: Option<Box<dyn ProgressSink>>,
+    /// Set while running as part of a `RestorePool`
+    pool: Option<Arc<pool::Shared>>,
+    audit: Option<Trail>,
+    fds: Option<Arc<FdBudget>>,
+    drop_cache: bool,
+    skip_unallocated: bool,
+    require_trust: Option<Trust>,
+    hooks: Hooks,
+    status: status::Tracker,
 }
 
 impl Extractor {
@@ -130,41 +504,355 @@ impl Extractor {
     /// the same directory as where revfile is located.
     pub fn init<P: AsRef<Path>>(revfile: P) -> Result<Self> {
         let revfile = revfile.as_ref();
+        if let Ok(caps) = backend::probe(revfile) {
+            // archives look like a file argument, but carry their own store
+            return Err(backend::Error::Unsupported(caps.layout).into());
+        }
         let basedir = revfile
             .parent()
             .unwrap_or_else(|| Path::new("."))
             .to_path_buf();
         let lock = purgelock(&basedir).map_err(|e| ExtractError::Lock(basedir.clone(), e))?;
-        let revision = fs::read_to_string(revfile)
+        let revision = backend::map_file(revfile)
             .map_err(|e| ExtractError::LoadSpec(revfile.to_owned(), e))?;
+        // resolve symlinks like `last` to get the actual revision ID
+        let name = fs::canonicalize(revfile)
+            .unwrap_or_else(|_| revfile.to_owned())
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
         Ok(Self {
+            name,
             revision,
-            threads: Self::default_threads(),
+            map_cache: None,
+            hash_cache: false,
+            spec: OnceLock::new(),
+            concurrency: Self::default_threads().into(),
+            codec: Codec::default(),
+            reader: ReadStrategy::default(),
+            verify: false,
+            verify_sample: None,
+            filters: Vec::new(),
+            reorder_window: 0,
+            priority: Vec::new(),
+            shared_first: false,
+            order: RestoreOrder::default(),
+            limits: Limits::default(),
             basedir,
             lock,
-            progress: ProgressBar::hidden(),
+            progress: Console::new(false),
+            bar_style: BarStyle::default(),
+            sink: None,
+            pool: None,
+            audit: None,
+            fds: None,
+            drop_cache: false,
+            skip_unallocated: false,
+            require_trust: None,
+            hooks: Hooks::default(),
+            status: status::Tracker::default(),
         })
     }
 
-    /// Sets number of decompression threads. Heuristics apply in this method is never called.
-    pub fn threads(&mut self, n: u8) -> &mut Self {
-        if n > 0 {
-            self.threads = n
+    /// Revision ID, with symlinks like `last` resolved.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sets the number of threads per stage and the queue depths in between, see
+    /// [Concurrency](struct.Concurrency.html). A plain number sets the number of decompression
+    /// threads. Heuristics apply if this method is never called or if the number of decompression
+    /// threads is 0.
+    pub fn threads<C: Into<Concurrency>>(&mut self, c: C) -> &mut Self {
+        let mut c = c.into();
+        if c.decompressors == 0 {
+            c.decompressors = self.concurrency.decompressors;
+        }
+        self.concurrency = c;
+        self
+    }
+
+    /// Selects the LZO implementation used for decompression. Defaults to minilzo.
+    pub fn codec(&mut self, codec: Codec) -> &mut Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Selects how chunk files are read. Defaults to `ReadStrategy::Read`.
+    pub fn reader(&mut self, reader: ReadStrategy) -> &mut Self {
+        self.reader = reader;
+        self
+    }
+
+    /// Keeps the parsed revision map in `cache`, so that further restores of the same revision
+    /// skip parsing the JSON map.
+    pub fn map_cache(&mut self, cache: MapCache) -> &mut Self {
+        self.map_cache = Some(cache);
+        self.spec.take();
+        self
+    }
+
+    /// Makes [damage_report](#method.damage_report) keep track of verified chunks in
+    /// `chunks/hashcache` and skip chunks whose files are unchanged since their last successful
+    /// verification (see [HashCache](struct.HashCache.html)). Off by default.
+    pub fn hash_cache(&mut self, enable: bool) -> &mut Self {
+        self.hash_cache = enable;
+        self
+    }
+
+    /// Parses and validates the revision map. This happens only once per `Extractor`: further
+    /// calls and restores use the result of the first successful call.
+    pub fn spec(&self) -> Result<&RevisionSpec> {
+        if let Some(spec) = self.spec.get() {
+            return Ok(spec);
+        }
+        let chunks = match &self.map_cache {
+            Some(cache) => cache.load(self.basedir.join(&self.name))?,
+            None => ChunkVec::from_slice(&self.revision)?,
+        };
+        Ok(self
+            .spec
+            .get_or_init(|| RevisionSpec::new(self.name.clone(), chunks)))
+    }
+
+    /// Estimates the cost of a restore with the current settings. Only the revision map is read.
+    pub fn plan(&self) -> Result<RestorePlan> {
+        Ok(RestorePlan::new(
+            self.spec()?,
+            self.limits.plan(self.concurrency),
+            self.verify,
+        ))
+    }
+
+    // Chunk map of the revision
+    fn chunk_vec(&self) -> Result<&ChunkVec> {
+        self.spec().map(RevisionSpec::chunk_vec)
+    }
+
+    /// Enables checksum verification of all chunks loaded from the store.
+    ///
+    /// Hashing runs in its own pool of threads between decompression and writeout so that
+    /// throughput is not halved on machines with enough cores.
+    pub fn verify(&mut self, verify: bool) -> &mut Self {
+        self.verify = verify;
+        self.verify_sample = None;
+        self
+    }
+
+    /// Verifies only a sample of about `percent`% of the distinct chunks of a restore, plus all
+    /// chunks which are mapped to several positions. The sample is derived from the revision ID,
+    /// so repeated restores of a revision check the same chunks. This bounds the time spent on
+    /// regular checks of large revisions while still detecting widespread damage. Patching
+    /// verifies all chunks regardless.
+    pub fn verify_sample(&mut self, percent: f64) -> &mut Self {
+        self.verify = true;
+        self.verify_sample = Some(percent.clamp(0.0, 100.0));
+        self
+    }
+
+    /// Adds a filter which transforms image contents before they are written. Filters are
+    /// applied in the order added, after verification. They take effect on restores, not on
+    /// patching or other operations. See [filter](filter/index.html) for built-in filters.
+    pub fn filter(&mut self, filter: Box<dyn ChunkFilter>) -> &mut Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Favours chunks with low sequence numbers when writing to a `Stream`: while more than `n`
+    /// decompressed chunks wait for reordering, decoder threads pause until the writer has caught
+    /// up. This keeps memory usage bounded if single chunks load slowly. Disabled if 0 (default).
+    pub fn reorder_window(&mut self, n: usize) -> &mut Self {
+        self.reorder_window = n;
+        self
+    }
+
+    /// Restores the given byte ranges of the image before everything else, in the order given.
+    ///
+    /// This is useful with random access targets to get boot-critical regions like the partition
+    /// table or superblocks in place early. Streaming targets are written in order regardless.
+    pub fn priority(&mut self, ranges: &[Range<u64>]) -> &mut Self {
+        self.priority = ranges.to_vec();
+        self
+    }
+
+    /// Loads chunks which occur at many positions of the image before chunks which occur only
+    /// once or a few times. On images with much duplicate data (e.g. VMs cloned from the same
+    /// golden image) most of the image is in place early. Regions given to
+    /// [priority](#method.priority) still come first. Ignored for streaming targets, which would
+    /// have to hold back all reordered chunks in memory.
+    pub fn shared_first(&mut self, enable: bool) -> &mut Self {
+        self.shared_first = enable;
+        self
+    }
+
+    /// Loads chunks in `order`, e.g. from the end of the image backwards for jobs which only
+    /// need the GPT backup header or the last partition. Regions given to
+    /// [priority](#method.priority) and shared chunks still come first. Ignored for streaming
+    /// targets, which can only write in ascending order.
+    pub fn order(&mut self, order: RestoreOrder) -> &mut Self {
+        self.order = order;
+        self
+    }
+
+    /// Restricts resource usage. Limits take precedence over [threads](#method.threads).
+    pub fn limits(&mut self, limits: Limits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Records every chunk read from the store in `log`. `job` identifies who reads.
+    ///
+    /// This covers restores, damage reports and partition table lookups. Raw copies of chunk
+    /// files (exports, packs, sends) are not audited.
+    pub fn audit(&mut self, log: Arc<AuditLog>, job: &str) -> &mut Self {
+        self.audit = Some(Trail::new(log, job, &self.name));
+        self
+    }
+
+    /// Opens chunk files within `budget` instead of the process-wide default, which is derived
+    /// from `RLIMIT_NOFILE`. Threads wait for a free slot rather than failing with EMFILE.
+    pub fn fd_budget(&mut self, budget: Arc<FdBudget>) -> &mut Self {
+        self.fds = Some(budget);
+        self
+    }
+
+    /// Drops chunk files from the page cache once they have been read. Useful on backup servers
+    /// where restores should not evict data of running backups. Off by default.
+    pub fn drop_cache(&mut self, drop: bool) -> &mut Self {
+        self.drop_cache = drop;
+        self
+    }
+
+    /// Applies the thread, queue and page cache settings of `profile`. Call this before other
+    /// settings which should take precedence. The profile's sparse policy is up to the caller,
+    /// see [Profile::sparse](enum.Profile.html#method.sparse).
+    pub fn profile(&mut self, profile: Profile) -> &mut Self {
+        self.threads(profile.concurrency())
+            .limits(profile.limits())
+            .drop_cache(profile.drop_cache())
+    }
+
+    /// Writes chunks which contain only free space of guest file systems as zero chunks, without
+    /// loading them. Sparse targets get holes in these places. Free space is looked up in the
+    /// block bitmaps of ext2/3/4 file systems. File systems with an unclean journal are restored
+    /// completely.
+    pub fn skip_unallocated(&mut self, skip: bool) -> &mut Self {
+        self.skip_unallocated = skip;
+        self
+    }
+
+    /// Refuses to restore if backy trusts the revision less than `level`, see
+    /// [Trust](enum.Trust.html).
+    pub fn require_trust(&mut self, level: Trust) -> &mut Self {
+        self.require_trust = Some(level);
+        self
+    }
+
+    /// Trust level from the revision's `.rev` file, `Unknown` if it cannot be read.
+    pub fn trust(&self) -> Trust {
+        Rev::load(&self.basedir, &self.name)
+            .map(|r| r.trust)
+            .unwrap_or_default()
+    }
+
+    /// File name for a restore of this revision: `<vm>-<timestamp>-<uuid>.<ext>`. `<vm>` is the
+    /// name of the backup directory, which backy names after the VM, and `<timestamp>` the time
+    /// of the backup from the `.rev` file in UTC, e.g. `20190111T174525Z`.
+    pub fn auto_name(&self, ext: &str) -> Result<String> {
+        let rev = Rev::load(&self.basedir, &self.name).map_err(ExtractError::RevMeta)?;
+        let basedir = fs::canonicalize(&self.basedir).unwrap_or_else(|_| self.basedir.clone());
+        let vm = basedir
+            .file_name()
+            .map_or("backup".into(), |n| n.to_string_lossy());
+        Ok(format!(
+            "{}-{}-{}.{}",
+            vm,
+            rev.timestamp.format("%Y%m%dT%H%M%SZ"),
+            self.name,
+            ext
+        ))
+    }
+
+    fn check_trust(&self) -> Result<()> {
+        let required = match self.require_trust {
+            Some(required) => required,
+            None => return Ok(()),
+        };
+        let trust = self.trust();
+        if trust < required {
+            return Err(ExtractError::Untrusted {
+                rev: self.name.clone(),
+                trust,
+                required,
+            });
         }
+        Ok(())
+    }
+
+    /// Calls `f` after each successful [extract](#method.extract).
+    pub fn on_success<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&Job) + Send + Sync + 'static,
+    {
+        self.hooks.success.push(Box::new(f));
+        self
+    }
+
+    /// Calls `f` after each failed [extract](#method.extract), before the error is returned.
+    pub fn on_failure<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&Job) + Send + Sync + 'static,
+    {
+        self.hooks.failure.push(Box::new(f));
         self
     }
 
-    fn default_threads() -> u8 {
+    fn backend(&self) -> Result<Backend> {
+        let be = match &self.pool {
+            Some(p) => p.backend(&self.basedir, self.codec)?,
+            None => Backend::open(&self.basedir)?.with_codec(self.codec),
+        };
+        let be = match &self.fds {
+            Some(fds) => be.with_fd_budget(Arc::clone(fds)),
+            None => be,
+        }
+        .with_reader(self.reader)
+        .with_drop_cache(self.drop_cache);
+        Ok(match &self.audit {
+            Some(t) => be.with_audit(t.clone()),
+            None => be,
+        })
+    }
+
+    fn verify_threads(&self, threads: u8) -> u8 {
+        if self.verify {
+            (threads / 2).max(1)
+        } else {
+            0
+        }
+    }
+
+    pub(crate) fn default_threads() -> u8 {
         num_cpus::get().max(2).min(24) as u8
     }
 
     /// Enables/disables a nice progress bar on stderr while restoring.
     pub fn progress(&mut self, show: bool) -> &mut Self {
-        self.progress = if show {
-            ProgressBar::new(1)
-        } else {
-            ProgressBar::hidden()
-        };
+        self.progress = Console::new(show);
+        self
+    }
+
+    /// Selects what the progress bar shows. [BarStyle::Detailed](enum.BarStyle.html) helps to
+    /// find out which stage slows down a restore.
+    pub fn progress_style(&mut self, style: BarStyle) -> &mut Self {
+        self.bar_style = style;
+        self
+    }
+
+    /// Reports restore progress in bytes and unique chunks to `sink` instead of the progress bar.
+    /// Status messages are still controlled by [progress](#method.progress).
+    pub fn progress_sink<S: ProgressSink + 'static>(&mut self, sink: S) -> &mut Self {
+        self.sink = Some(Box::new(sink));
         self
     }
 
@@ -173,46 +861,72 @@ impl Extractor {
             .println(format!("{} Loading chunk map", step(1)));
     }
 
-    fn print_decompress(&self, nchunks: usize) {
+    fn print_decompress(&self, nchunks: usize, plan: &Concurrency) {
+        let threads = plan.decompressors;
         self.progress.println(format!(
-            "{} Decompressing {} chunks in background using {} thread(s)",
+            "{} Decompressing {} chunks in background using {} thread(s){}{}",
             step(2),
             style(nchunks.to_string()).cyan(),
-            self.threads
+            threads,
+            if plan.readers > 0 {
+                format!(", reading with {}", plan.readers)
+            } else {
+                String::new()
+            },
+            match (self.verify, self.verify_sample) {
+                (true, Some(p)) => format!(
+                    ", verifying a {}% sample with {}",
+                    p,
+                    self.verify_threads(threads)
+                ),
+                (true, None) => format!(", verifying with {}", self.verify_threads(threads)),
+                _ => String::new(),
+            }
         ));
     }
 
-    fn print_progress(&self, total_size: u64, name: &str, written: Receiver<usize>) -> u64 {
+    fn print_progress(
+        &self,
+        chunks: &ChunkVec,
+        name: &str,
+        written: progress::Monitor,
+        queue: Option<progress::QueueGauge>,
+    ) -> (u64, u64) {
         self.progress
             .println(format!("{} Restoring to {}", step(3), style(name).yellow()));
-        self.progress.set_length(total_size);
-        self.progress
-            .set_style(ProgressStyle::default_bar().template(
-                "{bytes:>9.yellow}/{total_bytes:.green} {bar:52.cyan/blue} ({elapsed}/{eta})",
-            ));
-        self.progress.inc(0);
-        self.progress.set_draw_delta(total_size / 1000);
-        let mut total = 0;
-        for bytes in written {
-            let bytes = bytes as u64;
-            self.progress.inc(bytes);
-            total += bytes;
-        }
-        self.progress.finish_and_clear();
-        total
-    }
-
-    fn print_finished(&self, written: u64, started: Instant) {
-        let rt = Instant::now().duration_since(started);
+        let bar = self.progress.chunk_bar(queue);
+        let sink = status::Tee {
+            sink: self.sink.as_deref().unwrap_or(&*bar),
+            tracker: &self.status,
+        };
+        let unique = chunks.unique()
+            + usize::from(!chunks.zero_seqs().is_empty())
+            + filter::extra_chunks(&self.filters, chunks);
+        written.run(&sink, chunks.size, Some(unique as u64))
+    }
+
+    fn print_finished(&self, written: u64, chunks: u64, rt: Duration, cpu: &CpuReport) {
         let runtime = rt.as_secs() as f64 + f64::from(rt.subsec_micros()) / 1e6;
         let rate = written as f64 / runtime.max(1.0);
         self.progress.println(format!(
-            "{} Finished restoring {} in {:.1}s ({}/s)",
+            "{} Finished restoring {} from {} unique chunks in {:.1}s ({}/s)",
             step(4),
             style(HumanBytes(written)).green(),
+            chunks,
             runtime,
             HumanBytes(rate.round() as u64)
         ));
+        self.progress.println(format!("      CPU time: {}", cpu));
+    }
+
+    /// Returns the state of the running restore, or of the last one if none is running.
+    ///
+    /// This is meant to be polled from another thread while [extract](#method.extract) runs,
+    /// e.g. by embedders which report progress on their own schedule instead of through a
+    /// [ProgressSink](trait.ProgressSink.html). Counters are updated as the progress sink sees
+    /// them.
+    pub fn status(&self) -> RestoreStatus {
+        self.status.status()
     }
 
     /// Initiates the restore process.
@@ -220,36 +934,513 @@ impl Extractor {
     /// Accepts a `WriteOutBuilder` which is used to instantiate the final writer. Currently
     /// supported WriteOutBuilders are [Stream](struct.Stream.html) and
     /// [RandomAccess](struct.RandomAccess.html).
-    pub fn extract<W>(&self, w: W) -> Result<()>
+    pub fn extract<W>(&self, w: W) -> Result<ExtractReport>
+    where
+        W: WriteOutBuilder,
+    {
+        let start = Instant::now();
+        let mut target = String::new();
+        self.status.begin();
+        let res = self.restore(w, &mut target);
+        self.status.end(&res);
+        if !self.hooks.is_empty() {
+            self.hooks.call(&Job {
+                revision: self.name.clone(),
+                target,
+                bytes: res.as_ref().map_or(0, |r| r.bytes),
+                elapsed: start.elapsed(),
+                error: res.as_ref().err().map(|e| error_chain(e)),
+                error_class: res.as_ref().err().map(ExtractError::class),
+            });
+        }
+        res
+    }
+
+    /// Restores the image in a background thread and returns its contents as iterator over
+    /// `(offset, data)` pairs in sequence order, for consumers which don't fit the
+    /// [WriteOutBuilder](trait.WriteOutBuilder.html) model, e.g. uploaders. All settings apply as
+    /// with [extract](#method.extract), including hooks. Positions without data yield
+    /// `Data::Zero`.
+    pub fn chunks(self) -> ChunkIter {
+        ChunkIter::spawn(self)
+    }
+
+    // Does the actual work for `extract`. Sets `target` to the final restore target once known.
+    fn restore<W>(&self, w: W, target: &mut String) -> Result<ExtractReport>
     where
         W: WriteOutBuilder,
     {
         self.print_start();
         let start = Instant::now();
-        let be = Backend::open(&self.basedir)?;
-        let chunks = ChunkVec::decode(&self.revision)?;
+        let cpu = cputime::Account::new();
+        let main_cpu = cputime::thread();
+        self.check_trust()?;
+        let be = self.backend()?;
+        let mut chunks = self.chunk_vec()?.clone();
+        chunks.prioritize(
+            self.priority
+                .iter()
+                .map(|r| pos2chunk(r.start)..pos2chunk(r.end.saturating_add(CHUNKSZ as u64 - 1)))
+                .collect(),
+        );
+        let unallocated = if self.skip_unallocated {
+            let seqs = self.unallocated(&chunks, &be);
+            chunks.discard(&seqs);
+            seqs.len()
+        } else {
+            0
+        };
 
-        self.print_decompress(chunks.len());
-        let (progress, progress_rx) = unbounded();
-        let writer = w.build(chunks.size, self.threads);
+        let plan = self.limits.plan(self.concurrency);
+        let threads = plan.decompressors;
+        let mut writer = w.build(chunks.size, plan.writers);
+        if !writer.skip().is_empty() {
+            chunks.discard(writer.skip());
+        }
+        self.print_decompress(chunks.len(), &plan);
+        let (progress, progress_rx) = progress::channel();
         let name = writer.name();
+        let reorder = writer.reorder();
+        chunks.shared_first(self.shared_first && reorder.is_none());
+        if reorder.is_none() {
+            chunks.order(self.order);
+        }
+        *target = writer.target();
+        // best effort: restoring users may lack write access to the backup directory
+        let _job = jobs::register(&self.basedir, &self.name, target).ok();
 
-        let (chunk_tx, chunk_rx) = bounded(2 * self.threads as usize);
-        let total_bytes = thread::scope(|s| -> Result<u64> {
-            let mut hdl = vec![s.spawn(|_| writer.receive(chunk_rx, progress).map_err(Into::into))];
-            for threadid in 0..self.threads {
+        let (chunk_tx, chunk_rx) = bounded(plan.write_queue);
+        let queue = match self.bar_style {
+            BarStyle::Detailed => Some(progress::queue_gauge(&chunk_rx)),
+            BarStyle::Default => None,
+        };
+        let (verify_tx, verify_rx) = bounded(plan.write_queue);
+        let (read_tx, read_rx) = bounded(plan.read_queue);
+        let enter = || self.limits.enter().map_err(ExtractError::Priority);
+        let permit = || self.pool.as_ref().map(|p| p.acquire());
+        let throttle = |seq| {
+            if let (Some(r), n) = (&reorder, self.reorder_window) {
+                if n > 0 {
+                    r.throttle(seq, n)
+                }
+            }
+            permit()
+        };
+        let stage = |res| self.status.stage(res);
+        let account = &cpu;
+        account.add(Stage::Load, main_cpu);
+        let out = &mut writer;
+        let total = thread::scope(|s| -> Result<(u64, u64)> {
+            let write_hdl = s.spawn(|_| {
+                account.measure(Stage::Write, || {
+                    stage(enter().and_then(|()| {
+                        out.prepare()
+                            .and_then(|()| out.receive(chunk_rx, progress))
+                            .map_err(Into::into)
+                    }))
+                })
+            });
+            let mut hdl = Vec::new();
+            for threadid in 0..threads {
                 let c_tx = chunk_tx.clone();
-                let sd = |t| (&chunks).send_decompressed(t, self.threads, &be, c_tx);
-                hdl.push(s.spawn(move |_| sd(threadid)));
+                let v_tx = verify_tx.clone();
+                let r_rx = read_rx.clone();
+                let (chunks, be, verify) = (&chunks, &be, self.verify);
+                let (name, sample, filters) = (&self.name, self.verify_sample, &self.filters);
+                hdl.push(s.spawn(move |_| {
+                    let emit = |id: &ChunkId, chunk: Chunk| {
+                        let checked = match sample {
+                            Some(p) => chunkvec::sampled(name, id, &chunk.seqs, p),
+                            None => verify,
+                        };
+                        if checked {
+                            v_tx.send((id.clone(), chunk))
+                                .map_err(|e| SendError((e.0).1).into())
+                        } else {
+                            filter::apply(filters, chunk, |c| Ok(c_tx.send(c)?))
+                        }
+                    };
+                    account.measure(Stage::Decompress, || {
+                        stage(enter().and_then(|()| {
+                            if plan.readers > 0 {
+                                chunkvec::decompress(r_rx, be, permit, emit)
+                            } else {
+                                chunks.send_decompressed(threadid, threads, be, throttle, emit)
+                            }
+                        }))
+                    })
+                }));
+            }
+            drop(read_rx);
+            // after the decompressors, so that their errors are reported first
+            for threadid in 0..plan.readers {
+                let r_tx = read_tx.clone();
+                let (chunks, be) = (&chunks, &be);
+                hdl.push(s.spawn(move |_| {
+                    let emit = |c| {
+                        r_tx.send(c).map_err(|e| {
+                            let seqs = (e.0).seqs;
+                            SendError(Chunk {
+                                data: Data::Zero,
+                                seqs,
+                            })
+                            .into()
+                        })
+                    };
+                    account.measure(Stage::Read, || {
+                        stage(enter().and_then(|()| {
+                            chunks.send_compressed(threadid, plan.readers, be, throttle, emit)
+                        }))
+                    })
+                }));
+            }
+            drop(read_tx);
+            drop(verify_tx);
+            for _ in 0..self.verify_threads(threads) {
+                let (v_rx, c_tx, be) = (verify_rx.clone(), chunk_tx.clone(), &be);
+                let filters = &self.filters;
+                hdl.push(s.spawn(move |_| {
+                    let emit = |chunk| filter::apply(filters, chunk, |c| Ok(c_tx.send(c)?));
+                    account.measure(Stage::Verify, || {
+                        stage(enter().and_then(|()| chunkvec::verify(v_rx, be, emit)))
+                    })
+                }));
+            }
+            drop(verify_rx);
+            hdl.push(s.spawn(|_| stage(chunks.send_zero(chunk_tx))));
+            let total = self.print_progress(&chunks, &name, progress_rx, queue);
+            let upstream: Vec<Result<()>> = hdl
+                .into_iter()
+                .map(|h| h.join().expect("unhandled panic"))
+                .collect();
+            let written = write_hdl.join().expect("unhandled panic");
+            // writer errors are likely a consequence of failures upstream: report the latter
+            upstream
+                .into_iter()
+                .chain(iter::once(written))
+                .collect::<Result<()>>()?;
+            Ok(total)
+        })
+        .expect("subthread panic");
+        let (total_bytes, total_chunks) = match total {
+            Ok(total) => total,
+            Err(e) => {
+                writer.abort(&e);
+                return Err(e);
+            }
+        };
+        let elapsed = start.elapsed();
+        writer.finalize(&WriteReport {
+            size: chunks.size,
+            bytes: total_bytes,
+            elapsed,
+        })?;
+        let cpu = cpu.report();
+        self.print_finished(total_bytes, total_chunks, elapsed, &cpu);
+        Ok(ExtractReport {
+            bytes: total_bytes,
+            chunks: total_chunks,
+            elapsed,
+            reorder: reorder.map(|r| r.stats()),
+            unallocated,
+            cpu,
+        })
+    }
+
+    /// Checks all chunks of the revision and reports which image regions and partitions are
+    /// affected by corrupt or missing chunks. Nothing is written except for the hash cache if
+    /// enabled (see [hash_cache](#method.hash_cache)).
+    pub fn damage_report(&self) -> Result<DamageReport> {
+        let be = self.backend()?;
+        let chunks = self.chunk_vec()?;
+        let threads = self.limits.plan(self.concurrency).decompressors;
+        let cache = match self.hash_cache {
+            true => Some(HashCache::open(self.basedir.join("chunks/hashcache"))),
+            false => None,
+        };
+        let (cached, damaged) = thread::scope(|s| {
+            let hdl: Vec<_> = (0..threads)
+                .map(|t| {
+                    let (chunks, be, cache) = (chunks, &be, cache.as_ref());
+                    s.spawn(move |_| chunks.scan(t, threads, be, cache))
+                })
+                .collect();
+            hdl.into_iter()
+                .map(|h| h.join().expect("unhandled panic"))
+                .fold((0, Vec::new()), |(n, mut all), (cached, damaged)| {
+                    all.extend(damaged);
+                    (n + cached, all)
+                })
+        })
+        .expect("subthread panic");
+        if let Some(c) = &cache {
+            if let Err(e) = c.save() {
+                warn!("Failed to write hash cache: {}", e);
+            }
+        }
+        let table = Self::probe(chunks, &be).ok();
+        let mut report = DamageReport::new(chunks.size, chunks.unique(), table, damaged);
+        report.cached = cached;
+        Ok(report)
+    }
+
+    /// Moves the files of all corrupt chunks in `report` to the store's quarantine directory,
+    /// so that they can be replaced by intact copies. Missing chunks and chunks which failed for
+    /// other reasons, e.g. I/O errors, are left alone. Returns the new paths.
+    #[cfg(not(feature = "read-only"))]
+    pub fn quarantine(&self, report: &DamageReport) -> Result<Vec<PathBuf>> {
+        let be = self.backend()?;
+        report
+            .chunks
+            .iter()
+            .filter(|c| c.fault == Some(Fault::Corrupt))
+            .map(|c| Ok(be.quarantine(&c.id, &c.error)?))
+            .collect()
+    }
+
+    /// Quickly checks that all chunk files referenced by the revision exist, have plausible
+    /// sizes and start with a valid header, without decompressing anything. Meant to run before
+    /// a restore so that missing or truncated chunks are noticed before anything is written.
+    /// With a `timeout`, checking stops once it has elapsed and the result is incomplete.
+    pub fn precheck(&self, timeout: Option<Duration>) -> Result<Precheck> {
+        let be = self.backend()?;
+        let chunks = self.chunk_vec()?;
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let threads = self.limits.plan(self.concurrency).decompressors;
+        let (checked, mut damaged) = thread::scope(|s| {
+            let hdl: Vec<_> = (0..threads)
+                .map(|t| {
+                    let (chunks, be) = (chunks, &be);
+                    s.spawn(move |_| chunks.check_files(t, threads, be, deadline))
+                })
+                .collect();
+            hdl.into_iter()
+                .map(|h| h.join().expect("unhandled panic"))
+                .fold((0, Vec::new()), |(n, mut all), (checked, damaged)| {
+                    all.extend(damaged);
+                    (n + checked, all)
+                })
+        })
+        .expect("subthread panic");
+        damaged.sort_unstable_by_key(|c| c.seqs[0]);
+        Ok(Precheck {
+            total: chunks.unique(),
+            checked,
+            chunks: damaged,
+        })
+    }
+
+    /// Reports the compressed size of every chunk and aggregates them into a heatmap with
+    /// regions of `bucket` bytes (64 regions if None). Only chunk file sizes are looked at.
+    /// Buckets larger than the image are rejected.
+    pub fn compression_report(&self, bucket: Option<u64>) -> Result<CompressionReport> {
+        let be = self.backend()?;
+        let chunks = self.chunk_vec()?;
+        match bucket {
+            Some(b) if b > chunks.size.max(CHUNKSZ as u64) => {
+                return Err(ExtractError::BucketSize(b))
             }
-            hdl.push(s.spawn(|_| (&chunks).send_zero(chunk_tx)));
-            let total_bytes = self.print_progress(chunks.size, &name, progress_rx);
+            _ => (),
+        }
+        Ok(CompressionReport::new(chunks, &be, bucket))
+    }
+
+    /// Brings the existing image `target` up to date with the revision. Every chunk-sized block of
+    /// the target is read and only blocks whose contents differ from the revision are rewritten.
+    /// Regular files are truncated or extended to the image size first.
+    pub fn patch<P: AsRef<Path>>(&self, target: P) -> Result<PatchReport> {
+        let start = Instant::now();
+        let target = target.as_ref();
+        self.check_trust()?;
+        let be = self.backend()?;
+        let chunks = self.chunk_vec()?;
+        let map = chunks.by_seq();
+        let _job = jobs::register(&self.basedir, &self.name, &target.to_string_lossy()).ok();
+        let open_err = |e| writeout::Error::OutputFile(target.to_owned(), e);
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(target)
+            .map_err(open_err)?;
+        if f.metadata().map_err(open_err)?.is_file() {
+            f.set_len(chunks.size).map_err(open_err)?;
+        }
+        let threads = self.limits.plan(self.concurrency).decompressors;
+        let enter = || self.limits.enter().map_err(ExtractError::Priority);
+        let parts = thread::scope(|s| {
+            let hdl: Vec<_> = (0..threads)
+                .map(|t| {
+                    let (map, f, be) = (&map, &f, &be);
+                    s.spawn(move |_| {
+                        enter()?;
+                        patch::patch_part(map, t, threads, f, be, self.verify)
+                    })
+                })
+                .collect();
             hdl.into_iter()
-                .try_for_each(|h| h.join().expect("unhandled panic"))?;
-            Ok(total_bytes)
+                .map(|h| h.join().expect("unhandled panic"))
+                .collect::<Result<Vec<_>>>()
         })
         .expect("subthread panic")?;
-        self.print_finished(total_bytes, start);
-        Ok(())
+        let mut report = PatchReport::default();
+        for p in &parts {
+            report.add(p);
+        }
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+
+    fn probe(chunks: &ChunkVec, be: &Backend) -> Result<partition::PartitionTable> {
+        match chunks.find(0) {
+            Some(id) => be
+                .load(id)
+                .map(|head| partition::probe(&head))
+                .map_err(|e| ExtractError::invalid_chunk(be, 0, id, e)),
+            // zero chunk => no partition table
+            None => Ok(Default::default()),
+        }
+    }
+
+    /// Positions at which the revision holds the same data as revision `base`, e.g. for
+    /// differential exports.
+    pub fn unchanged_since(&self, base: &Extractor) -> Result<Vec<u32>> {
+        Ok(self.chunk_vec()?.unchanged(base.chunk_vec()?))
+    }
+
+    /// Reads the partition table from the first chunk of the revision.
+    pub fn partition_table(&self) -> Result<partition::PartitionTable> {
+        let be = self.backend()?;
+        Self::probe(self.chunk_vec()?, &be)
+    }
+
+    /// Serves the image read-only over HTTP with support for byte ranges, so that tools which
+    /// attach disk images by URL can inspect it without a restore. Chunks are loaded as
+    /// requested. Runs until accepting connections fails.
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        self.check_trust()?;
+        let be = self.backend()?;
+        let chunks = Arc::new(self.chunk_vec()?.clone());
+        http::serve(listener, &self.name, be, chunks).map_err(ExtractError::Serve)
+    }
+
+    /// Opens the file system on `partition`. Without partition, the first partition with a
+    /// supported file system is used, or the whole image if it has no partition table.
+    fn guest_fs(&self, partition: Option<u32>) -> Result<Ext4<Image>> {
+        let be = self.backend()?;
+        let chunks = self.chunk_vec()?;
+        let table = Self::probe(chunks, &be)?;
+        let mut img = Image::new(be, chunks);
+        let offset = match partition {
+            Some(n) => match table.partitions.iter().find(|p| p.number == n) {
+                Some(p) => p.start,
+                None => return Err(ExtractError::NoPartition(n)),
+            },
+            None if table.partitions.is_empty() => 0,
+            None => {
+                let mut found = None;
+                for p in &table.partitions {
+                    if Ext4::probe(&mut img, p.start)? {
+                        found = Some(p.start);
+                        break;
+                    }
+                }
+                found.ok_or(guestfs::Error::NotFound)?
+            }
+        };
+        Ok(Ext4::open(img, offset)?)
+    }
+
+    // Seqs of mapped chunks which lie completely in free space of guest file systems. File
+    // systems whose free space can't be determined are left alone. Free space never extends
+    // beyond the partition, whatever the file system's superblock says.
+    fn unallocated(&self, chunks: &ChunkVec, be: &Backend) -> Vec<u32> {
+        let table = Self::probe(chunks, be).unwrap_or_default();
+        let mut img = Image::new(be.clone(), chunks);
+        let mut locations: Vec<(String, Range<u64>)> = table
+            .partitions
+            .iter()
+            .map(|p| (format!("partition {}", p.number), p.start..p.end))
+            .collect();
+        if locations.is_empty() {
+            locations.push(("image".into(), 0..chunks.size));
+        }
+        let mut free = Vec::new();
+        for (name, part) in locations {
+            if !Ext4::probe(&mut img, part.start).unwrap_or(false) {
+                continue;
+            }
+            match Ext4::open(&mut img, part.start).and_then(|mut fs| fs.unallocated()) {
+                Ok(ranges) => free.extend(
+                    ranges
+                        .into_iter()
+                        .map(|r| r.start.max(part.start)..r.end.min(part.end))
+                        .filter(|r| r.start < r.end),
+                ),
+                Err(e) => self.progress.println(format!(
+                    "{} Restoring free space in {} ({})",
+                    step(1),
+                    name,
+                    style(e).red()
+                )),
+            }
+        }
+        free.sort_by_key(|r| r.start);
+        let mapped = chunks.by_seq();
+        let mut seqs = Vec::new();
+        let mut i = 0;
+        while i < free.len() {
+            let (start, mut end) = (free[i].start, free[i].end);
+            i += 1;
+            while i < free.len() && free[i].start <= end {
+                end = end.max(free[i].end);
+                i += 1;
+            }
+            let first = pos2chunk(start + CHUNKSZ as u64 - 1);
+            let last = pos2chunk(end.min(chunks.size));
+            seqs.extend((first..last).filter(|&s| mapped[s as usize].is_some()));
+        }
+        self.progress.println(format!(
+            "{} Skipping {} chunks of unallocated guest space",
+            step(1),
+            style(seqs.len().to_string()).cyan()
+        ));
+        seqs
+    }
+
+    /// Writes files and directories from inside the image as tar stream to `out`, without
+    /// restoring the image. `paths` are absolute paths inside the guest file system, directories
+    /// are included recursively. Only ext2/3/4 is supported. The file system is read from
+    /// `partition` or else from the first partition which contains a supported file system.
+    pub fn files<P, W>(&self, paths: &[P], partition: Option<u32>, out: W) -> Result<FileStats>
+    where
+        P: AsRef<Path>,
+        W: Write,
+    {
+        let mut fs = self.guest_fs(partition)?;
+        Ok(guestfs::write_tar(&mut fs, paths, out)?)
+    }
+
+    /// Locates regular files in the guest file system like [files](#method.files) does and
+    /// returns the byte ranges of the image which hold their data, e.g. to scrub them with a
+    /// [filter](filter/struct.ZeroRanges.html).
+    pub fn file_ranges<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+        partition: Option<u32>,
+    ) -> Result<Vec<Range<u64>>> {
+        let mut fs = self.guest_fs(partition)?;
+        let mut ranges = Vec::new();
+        for p in paths {
+            let path = p.as_ref().as_os_str().as_bytes();
+            let inode = fs.lookup(path)?;
+            match fs.file_ranges(&inode) {
+                Err(guestfs::Error::NotAFile(_)) => {
+                    let name = p.as_ref().display().to_string();
+                    return Err(guestfs::Error::NotAFile(name).into());
+                }
+                res => ranges.extend(res?),
+            }
+        }
+        Ok(ranges)
     }
 }
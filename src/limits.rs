@@ -0,0 +1,300 @@
+//! Thread configuration and resource limits for single restores.
+
+use crate::CHUNKSZ;
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// I/O scheduling class as understood by ioprio_set(2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    /// Realtime class with priority level 0 (highest) to 7
+    Realtime(u8),
+    /// Best-effort class with priority level 0 (highest) to 7
+    BestEffort(u8),
+    /// Only gets disk time when nobody else needs it
+    Idle,
+}
+
+#[derive(Error, Debug)]
+#[error("Invalid I/O class '{0}' (expected idle, best-effort[:0-7] or realtime[:0-7])")]
+pub struct ParseIoClassError(String);
+
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+impl IoClass {
+    #[cfg(any(test, target_os = "linux"))]
+    fn ioprio(self) -> libc::c_int {
+        let (class, level) = match self {
+            IoClass::Realtime(l) => (1, l.min(7)),
+            IoClass::BestEffort(l) => (2, l.min(7)),
+            IoClass::Idle => (3, 0),
+        };
+        class << IOPRIO_CLASS_SHIFT | libc::c_int::from(level)
+    }
+
+    /// Applies the class to the calling thread.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn set_current_thread(self) -> io::Result<()> {
+        // who = 0 means the calling thread
+        match unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, self.ioprio()) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    /// I/O scheduling classes are specific to Linux. Does nothing elsewhere.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn set_current_thread(self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl FromStr for IoClass {
+    type Err = ParseIoClassError;
+
+    /// Parses `idle`, `best-effort[:LEVEL]` or `realtime[:LEVEL]`. Levels default to 4.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseIoClassError(s.to_owned());
+        let (class, level) = match s.find(':') {
+            Some(i) => (&s[..i], Some(s[i + 1..].parse::<u8>().map_err(|_| err())?)),
+            None => (s, None),
+        };
+        match (class, level) {
+            ("idle", None) => Ok(IoClass::Idle),
+            (_, Some(l)) if l > 7 => Err(err()),
+            ("best-effort", l) | ("be", l) => Ok(IoClass::BestEffort(l.unwrap_or(4))),
+            ("realtime", l) | ("rt", l) => Ok(IoClass::Realtime(l.unwrap_or(4))),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl fmt::Display for IoClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoClass::Realtime(l) => write!(f, "realtime:{}", l),
+            IoClass::BestEffort(l) => write!(f, "best-effort:{}", l),
+            IoClass::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+/// Thread and queue configuration of a restore.
+///
+/// Chunks pass through up to three stages: reading chunk files from the store, decompressing them
+/// and writing them to the restore target. The best split depends on the store: chunk files on
+/// local NVMe are read so fast that decompression threads can read their chunks themselves
+/// (the default). Stores on NFS or other high-latency storage benefit from many reader threads
+/// which keep a smaller number of decompression threads busy.
+///
+/// A plain number converts into a configuration with that many decompression threads and
+/// defaults otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Concurrency {
+    /// Threads which read chunk files. If 0, decompression threads read their chunks themselves.
+    pub readers: u8,
+    /// Threads which decompress chunks. Heuristics apply if 0.
+    pub decompressors: u8,
+    /// Threads which write to file and block device targets. Other targets are always written by
+    /// a single thread.
+    pub writers: u8,
+    /// Chunks read but not yet decompressed. Defaults to two per decompression thread if 0.
+    pub read_queue: usize,
+    /// Chunks decompressed but not yet written. Defaults to two per decompression thread if 0.
+    pub write_queue: usize,
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Self {
+            readers: 0,
+            decompressors: 0,
+            writers: 1,
+            read_queue: 0,
+            write_queue: 0,
+        }
+    }
+}
+
+impl From<u8> for Concurrency {
+    fn from(decompressors: u8) -> Self {
+        Self {
+            decompressors,
+            ..Self::default()
+        }
+    }
+}
+
+/// Caps the resources a single restore may use.
+///
+/// This is mainly useful together with [RestorePool](struct.RestorePool.html), so that one huge
+/// restore cannot starve the others. Unset fields impose no limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of decompression threads
+    pub threads: Option<u8>,
+    /// Maximum number of chunk files open at the same time. Each reader thread reads one chunk
+    /// file at a time, so this caps the number of reader threads, or the number of decompression
+    /// threads if they read chunks themselves.
+    pub open_files: Option<usize>,
+    /// Approximate upper bound for decompressed data in flight, in bytes. Reorder buffers of
+    /// streaming targets are not included (see `reorder_window`).
+    pub memory: Option<usize>,
+    /// I/O scheduling class for all threads which read, check or write image data
+    pub io_class: Option<IoClass>,
+    /// Nice value (-20 to 19) for the same threads
+    pub nice: Option<i32>,
+}
+
+impl Limits {
+    /// Determines the number of threads per stage and the capacity of the queues between stages,
+    /// given the desired configuration. All values of the result are non-zero except `readers`.
+    pub(crate) fn plan(&self, want: Concurrency) -> Concurrency {
+        let mut threads = usize::from(want.decompressors.max(1));
+        let mut readers = usize::from(want.readers);
+        if let Some(n) = self.threads {
+            threads = threads.min(usize::from(n))
+        }
+        if let Some(n) = self.open_files {
+            // chunk files are opened by whoever reads them
+            if readers > 0 {
+                readers = readers.min(n).max(1)
+            } else {
+                threads = threads.min(n)
+            }
+        }
+        let auto = |q: usize| if q > 0 { q } else { 2 * threads };
+        let (mut read_queue, mut write_queue) = (auto(want.read_queue), auto(want.write_queue));
+        if let Some(mem) = self.memory {
+            // every thread holds one chunk while decompressing, the rest may wait in the queue
+            let chunks = mem / CHUNKSZ;
+            threads = threads.min(chunks / 3);
+            write_queue = write_queue.min(chunks.saturating_sub(threads));
+            // compressed chunks are smaller, but not by much in the worst case
+            read_queue = read_queue.min(write_queue);
+        }
+        Concurrency {
+            readers: readers as u8,
+            decompressors: threads.max(1) as u8,
+            writers: want.writers.max(1),
+            read_queue: read_queue.max(1),
+            write_queue: write_queue.max(1),
+        }
+    }
+
+    /// Applies per-thread settings to the calling thread.
+    pub(crate) fn enter(&self) -> io::Result<()> {
+        if let Some(c) = self.io_class {
+            c.set_current_thread()?;
+        }
+        if let Some(n) = self.nice {
+            set_thread_nice(n)?;
+        }
+        Ok(())
+    }
+}
+
+// Linux applies PRIO_PROCESS to single threads when given a thread ID
+#[cfg(target_os = "linux")]
+fn set_thread_nice(n: libc::c_int) -> io::Result<()> {
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, n) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Other systems have no per-thread priorities.
+#[cfg(not(target_os = "linux"))]
+fn set_thread_nice(_n: libc::c_int) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decompression threads and write queue capacity
+    fn plan(l: &Limits, threads: u8) -> (u8, usize) {
+        let c = l.plan(threads.into());
+        (c.decompressors, c.write_queue)
+    }
+
+    #[test]
+    fn plan_threads_and_queue() {
+        let l = Limits::default();
+        assert_eq!(plan(&l, 8), (8, 16));
+        let l = Limits {
+            threads: Some(4),
+            open_files: Some(2),
+            ..Limits::default()
+        };
+        assert_eq!(plan(&l, 8), (2, 4));
+        let l = Limits {
+            memory: Some(12 * CHUNKSZ),
+            ..Limits::default()
+        };
+        assert_eq!(plan(&l, 8), (4, 8));
+        let l = Limits {
+            memory: Some(1),
+            ..Limits::default()
+        };
+        assert_eq!(plan(&l, 8), (1, 1));
+    }
+
+    #[test]
+    fn plan_stages() {
+        let want = Concurrency {
+            readers: 16,
+            decompressors: 4,
+            writers: 2,
+            read_queue: 32,
+            write_queue: 0,
+        };
+        let l = Limits::default();
+        assert_eq!(
+            l.plan(want),
+            Concurrency {
+                write_queue: 8,
+                ..want
+            }
+        );
+        // open files limit readers instead of decompressors
+        let l = Limits {
+            open_files: Some(6),
+            memory: Some(20 * CHUNKSZ),
+            ..Limits::default()
+        };
+        let c = l.plan(want);
+        assert_eq!((c.readers, c.decompressors), (6, 4));
+        assert_eq!((c.read_queue, c.write_queue), (8, 8));
+    }
+
+    #[test]
+    fn ioprio_encoding() {
+        assert_eq!(IoClass::Idle.ioprio(), 3 << 13);
+        assert_eq!(IoClass::BestEffort(4).ioprio(), 2 << 13 | 4);
+        assert_eq!(IoClass::Realtime(9).ioprio(), 1 << 13 | 7);
+    }
+
+    #[test]
+    fn parse_io_class() {
+        assert_eq!("idle".parse::<IoClass>().unwrap(), IoClass::Idle);
+        assert_eq!("be".parse::<IoClass>().unwrap(), IoClass::BestEffort(4));
+        assert_eq!(
+            "realtime:0".parse::<IoClass>().unwrap(),
+            IoClass::Realtime(0)
+        );
+        for s in &["idle:3", "best-effort:8", "fast", "rt:"] {
+            assert!(s.parse::<IoClass>().is_err(), "{}", s);
+        }
+        for c in &[IoClass::Idle, IoClass::BestEffort(2), IoClass::Realtime(7)] {
+            assert_eq!(c.to_string().parse::<IoClass>().unwrap(), *c);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Binary cache of parsed revision maps.
+//!
+//! Parsing the JSON map of a multi-TiB image takes seconds. [MapCache] keeps the parsed chunk map
+//! of each revision in a compact binary file in a cache directory and uses it as long as size
+//! and modification time of the revision map are unchanged. Cache files are named after the
+//! revision and are written atomically. Stale or damaged cache files are replaced.
+//!
+//! # Format
+//!
+//! Integer and `str` encoding as in the [archive](../archive/index.html) format.
+//!
+//! ```text
+//! header:  "BKYMAPC\0" version:u8 source:str len:u64 mtime:i64 mtime_nsec:i64 size:u64
+//!          nchunks:u32
+//! chunks:  (id:str nseqs:u32 seq:u32*)*
+//! trailer: hash of all preceding bytes, 32 hex digits
+//! ```
+//!
+//! `source`, `len` and `mtime` identify the revision map the cache file has been created from.
+
+use crate::backend;
+use crate::chunkvec::ChunkVec;
+use crate::framing::{read_str, write_str};
+use crate::{ExtractError, Result};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use log::{debug, warn};
+use smallvec::SmallVec;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"BKYMAPC\0";
+const VERSION: u8 = 1;
+const TRAILER: usize = 32;
+
+// Identifies the revision map a cache file belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Source {
+    path: String,
+    len: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl Source {
+    fn of(path: &Path) -> io::Result<Self> {
+        let m = fs::metadata(path)?;
+        Ok(Self {
+            path: path.to_string_lossy().into_owned(),
+            len: m.len(),
+            mtime: m.mtime(),
+            mtime_nsec: m.mtime_nsec(),
+        })
+    }
+}
+
+/// Directory with cached revision maps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapCache {
+    dir: PathBuf,
+}
+
+impl MapCache {
+    /// Uses `dir` for cache files. It is created when the first file is written.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn file(&self, map: &Path) -> PathBuf {
+        let name = map.file_name().unwrap_or_default();
+        self.dir.join(name).with_extension("mapcache")
+    }
+
+    /// Loads the revision map `path` from the cache. Parses the map and updates the cache if
+    /// there is no valid cache file. Failing to write the cache is not an error.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> Result<ChunkVec> {
+        let path = fs::canonicalize(path.as_ref()).unwrap_or_else(|_| path.as_ref().to_owned());
+        let source = Source::of(&path).map_err(|e| ExtractError::LoadSpec(path.clone(), e))?;
+        let cache = self.file(&path);
+        match read(&cache, &source) {
+            Ok(Some(chunks)) => return Ok(chunks),
+            Ok(None) => debug!("{} is stale", cache.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => debug!("ignoring map cache {}: {}", cache.display(), e),
+        }
+        let chunks = ChunkVec::load(&path)?;
+        if let Err(e) = self.write(&cache, &source, &chunks) {
+            warn!("Failed to write map cache {}: {}", cache.display(), e);
+        }
+        Ok(chunks)
+    }
+
+    fn write(&self, cache: &Path, source: &Source, chunks: &ChunkVec) -> io::Result<()> {
+        let mut buf = MAGIC.to_vec();
+        buf.write_u8(VERSION)?;
+        write_str(&mut buf, &source.path)?;
+        buf.write_u64::<BigEndian>(source.len)?;
+        buf.write_i64::<BigEndian>(source.mtime)?;
+        buf.write_i64::<BigEndian>(source.mtime_nsec)?;
+        buf.write_u64::<BigEndian>(chunks.size)?;
+        buf.write_u32::<BigEndian>(chunks.unique() as u32)?;
+        for (id, seqs) in chunks.entries() {
+            write_str(&mut buf, id)?;
+            buf.write_u32::<BigEndian>(seqs.len() as u32)?;
+            for &seq in seqs {
+                buf.write_u32::<BigEndian>(seq)?;
+            }
+        }
+        let hash = backend::hash(&buf);
+        buf.extend_from_slice(hash.as_bytes());
+        fs::create_dir_all(&self.dir)?;
+        let tmp = cache.with_extension("mapcache.tmp");
+        fs::write(&tmp, &buf)
+            .and_then(|_| fs::rename(&tmp, cache))
+            .inspect_err(|_| {
+                fs::remove_file(&tmp).ok();
+            })
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Returns None if the cache file belongs to another version of the revision map.
+fn read(cache: &Path, source: &Source) -> io::Result<Option<ChunkVec>> {
+    let mut buf = Vec::new();
+    File::open(cache)?.read_to_end(&mut buf)?;
+    if buf.len() < MAGIC.len() + TRAILER || &buf[..MAGIC.len()] != MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    let (body, trailer) = buf.split_at(buf.len() - TRAILER);
+    if backend::hash(body).as_bytes() != trailer {
+        return Err(invalid("checksum mismatch"));
+    }
+    let mut r = Cursor::new(&body[MAGIC.len()..]);
+    if r.read_u8()? != VERSION {
+        return Err(invalid("unsupported version"));
+    }
+    let cached = Source {
+        path: read_str(&mut r)?,
+        len: r.read_u64::<BigEndian>()?,
+        mtime: r.read_i64::<BigEndian>()?,
+        mtime_nsec: r.read_i64::<BigEndian>()?,
+    };
+    if cached != *source {
+        return Ok(None);
+    }
+    let size = r.read_u64::<BigEndian>()?;
+    let n = r.read_u32::<BigEndian>()?;
+    let mut entries = Vec::with_capacity(n.min(1 << 20) as usize);
+    for _ in 0..n {
+        let id = read_str(&mut r)?;
+        let nseqs = r.read_u32::<BigEndian>()?;
+        let mut seqs = SmallVec::new();
+        for _ in 0..nseqs {
+            seqs.push(r.read_u32::<BigEndian>()?);
+        }
+        entries.push((id.into(), seqs));
+    }
+    if r.position() != r.get_ref().len() as u64 {
+        return Err(invalid("trailing garbage"));
+    }
+    ChunkVec::from_entries(size, entries)
+        .map(Some)
+        .map_err(|e| invalid(&e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+    use tempdir::TempDir;
+
+    const MAP: &str = r#"{"mapping": {"0": "a0", "1": "a1", "3": "a1"}, "size": 16777216}"#;
+
+    #[test]
+    fn cache_hit_and_invalidation() {
+        let td = TempDir::new("mapcache").unwrap();
+        let path = td.path().join("Rev1zzzzzzzzzzzzzzzzzz");
+        fs::write(&path, MAP).unwrap();
+        let cache = MapCache::new(td.path().join("cache"));
+        let parsed = cache.load(&path).unwrap();
+        let file = td.path().join("cache/Rev1zzzzzzzzzzzzzzzzzz.mapcache");
+        assert!(file.exists());
+
+        // same size and mtime: contents are not looked at
+        let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, MAP.replace("a0", "b0")).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let cached = cache.load(&path).unwrap();
+        assert_eq!(cached.by_seq(), parsed.by_seq());
+        assert_eq!(cached.zero_seqs(), &[2]);
+
+        // changed mtime
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now())
+            .unwrap();
+        assert_eq!(cache.load(&path).unwrap().seqs_of("b0"), &[0]);
+        assert_eq!(cache.load(&path).unwrap().seqs_of("b0"), &[0]);
+
+        // damaged cache file
+        let mut data = fs::read(&file).unwrap();
+        data[20] ^= 0xff;
+        fs::write(&file, data).unwrap();
+        assert_eq!(cache.load(&path).unwrap().seqs_of("b0"), &[0]);
+        assert!(read(&file, &Source::of(&path).unwrap()).unwrap().is_some());
+    }
+}
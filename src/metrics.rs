@@ -0,0 +1,155 @@
+//! Live figures for the detailed progress bar.
+//!
+//! The process' I/O counters and the fill level of the write queue are sampled as progress is
+//! reported. A full queue means that the target is the bottleneck, an empty one that reading or
+//! decompression is. The page cache hit rate tells whether reads are served from memory or have
+//! to go to the disks holding the backup store; it is not meaningful on network file systems,
+//! which don't account device reads.
+
+use crate::progress::{HumanBytes, QueueGauge};
+
+use std::fmt::{self, Write};
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Figures are refreshed at most this often, so that rates don't jitter
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Process-wide I/O counters as found in /proc/self/io
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct IoCounters {
+    /// Bytes read by syscalls, including those served from the page cache
+    rchar: u64,
+    /// Bytes written by syscalls
+    wchar: u64,
+    /// Bytes actually fetched from storage devices
+    read_bytes: u64,
+}
+
+impl IoCounters {
+    // None where /proc/self/io is not available
+    fn now() -> Option<Self> {
+        Self::parse(&fs::read_to_string("/proc/self/io").ok()?)
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        let (mut c, mut found) = (Self::default(), 0);
+        for line in s.lines() {
+            let (key, val) = line.split_once(':')?;
+            let field = match key {
+                "rchar" => &mut c.rchar,
+                "wchar" => &mut c.wchar,
+                "read_bytes" => &mut c.read_bytes,
+                _ => continue,
+            };
+            *field = val.trim().parse().ok()?;
+            found += 1;
+        }
+        if found == 3 {
+            Some(c)
+        } else {
+            None
+        }
+    }
+}
+
+/// Sampler for the figures of the detailed progress bar.
+pub(crate) struct Metrics {
+    queue: QueueGauge,
+    /// Time and counters of the last sample and the resulting figures
+    last: Mutex<(Instant, Option<IoCounters>, String)>,
+}
+
+impl Metrics {
+    pub(crate) fn new(queue: QueueGauge) -> Self {
+        Self {
+            queue,
+            last: Mutex::new((Instant::now(), IoCounters::now(), String::new())),
+        }
+    }
+
+    /// Returns the figures of the last complete sampling interval. Empty during the first one.
+    pub(crate) fn line(&self) -> String {
+        let mut last = self.last.lock().expect("poisoned lock");
+        let elapsed = last.0.elapsed();
+        if elapsed >= SAMPLE_INTERVAL {
+            let now = IoCounters::now();
+            let line = metrics_line(last.1, now, elapsed, (self.queue)());
+            *last = (Instant::now(), now, line);
+        }
+        last.2.clone()
+    }
+}
+
+// Formats rates between the I/O counter samples `a` and `b` and the queue fill level.
+fn metrics_line(
+    a: Option<IoCounters>,
+    b: Option<IoCounters>,
+    elapsed: Duration,
+    (queued, capacity): (usize, usize),
+) -> String {
+    let mut line = String::new();
+    if let (Some(a), Some(b)) = (a, b) {
+        let read = b.rchar.saturating_sub(a.rchar);
+        let fetched = b.read_bytes.saturating_sub(a.read_bytes);
+        let rate = |n: u64| HumanBytes((n as f64 / elapsed.as_secs_f64().max(1e-3)) as u64);
+        match read {
+            0 => line.push_str("cache -"),
+            _ => write!(
+                line,
+                "cache {:.0}%",
+                100.0 * (1.0 - fetched as f64 / read as f64).max(0.0)
+            )
+            .unwrap(),
+        }
+        write!(
+            line,
+            " read {}/s write {}/s ",
+            rate(read),
+            rate(b.wchar.saturating_sub(a.wchar))
+        )
+        .unwrap();
+    }
+    write!(line, "queue {}/{}", queued, capacity).unwrap();
+    line
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<Metrics>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn io_counters() {
+        let io = "rchar: 3980\nwchar: 12\nsyscr: 9\nsyscw: 0\nread_bytes: 4096\nwrite_bytes: 0\n";
+        let c = IoCounters::parse(io).unwrap();
+        assert_eq!((c.rchar, c.wchar, c.read_bytes), (3980, 12, 4096));
+        assert_eq!(IoCounters::parse("rchar: 1\n"), None);
+    }
+
+    #[test]
+    fn detailed_figures() {
+        let a = IoCounters::default();
+        let b = IoCounters {
+            rchar: 4 << 20,
+            wchar: 2 << 20,
+            read_bytes: 1 << 20,
+        };
+        let two = Duration::from_secs(2);
+        assert_eq!(
+            metrics_line(Some(a), Some(b), two, (3, 16)),
+            "cache 75% read 2.00MB/s write 1.00MB/s queue 3/16"
+        );
+        assert_eq!(
+            metrics_line(Some(a), Some(a), two, (0, 16)),
+            "cache - read 0B/s write 0B/s queue 0/16"
+        );
+        assert_eq!(metrics_line(None, Some(b), two, (16, 16)), "queue 16/16");
+    }
+}
@@ -0,0 +1,316 @@
+//! Partition table probing.
+//!
+//! Understands classic MBR (including logical partitions whose EBRs lie within the probed
+//! region) and GPT. Sectors are assumed to be 512 bytes, which holds for virtually all VM images.
+
+use byteorder::{ByteOrder, LittleEndian};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+
+const SECTOR: u64 = 512;
+/// Maximum number of EBRs followed in an extended partition
+const MAX_LOGICAL: usize = 128;
+
+/// Kind of partition table found at the start of an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TableType {
+    Mbr,
+    Gpt,
+}
+
+impl fmt::Display for TableType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableType::Mbr => write!(f, "MBR"),
+            TableType::Gpt => write!(f, "GPT"),
+        }
+    }
+}
+
+/// Single partition. Offsets are in bytes relative to the image start, `end` is exclusive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Partition {
+    pub number: u32,
+    pub start: u64,
+    pub end: u64,
+    /// MBR type byte as hex or GPT type GUID
+    pub kind: String,
+    /// GPT partition name (empty for MBR)
+    pub name: String,
+}
+
+impl Partition {
+    /// Returns true if the partition overlaps with the byte range [start, end).
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// Result of probing an image head.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PartitionTable {
+    pub table: Option<TableType>,
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Returns the partition which contains byte `pos`, if any.
+    pub fn find(&self, pos: u64) -> Option<&Partition> {
+        self.partitions
+            .iter()
+            .find(|p| p.start <= pos && pos < p.end)
+    }
+}
+
+fn is_extended(kind: u8) -> bool {
+    kind == 0x05 || kind == 0x0f || kind == 0x85
+}
+
+fn sector(head: &[u8], lba: u64) -> Option<&[u8]> {
+    let off = usize::try_from(lba.checked_mul(SECTOR)?).ok()?;
+    head.get(off..off.checked_add(SECTOR as usize)?)
+}
+
+fn mbr_entries(sect: &[u8]) -> Option<Vec<(u8, u64, u64)>> {
+    if sect[510..512] != [0x55, 0xAA] {
+        return None;
+    }
+    Some(
+        (0..4)
+            .map(|i| {
+                let e = &sect[446 + 16 * i..446 + 16 * (i + 1)];
+                (
+                    e[4],
+                    u64::from(LittleEndian::read_u32(&e[8..12])),
+                    u64::from(LittleEndian::read_u32(&e[12..16])),
+                )
+            })
+            .collect(),
+    )
+}
+
+fn guid(b: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        LittleEndian::read_u32(&b[0..4]),
+        LittleEndian::read_u16(&b[4..6]),
+        LittleEndian::read_u16(&b[6..8]),
+        b[8],
+        b[9],
+        b[10..16]
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>()
+    )
+}
+
+// Header fields are untrusted: any arithmetic overflow makes the whole table invalid.
+fn probe_gpt(head: &[u8]) -> Option<Vec<Partition>> {
+    let hdr = sector(head, 1)?;
+    if &hdr[0..8] != b"EFI PART" {
+        return None;
+    }
+    let entries_lba = LittleEndian::read_u64(&hdr[72..80]);
+    let n = LittleEndian::read_u32(&hdr[80..84]) as usize;
+    let entsz = LittleEndian::read_u32(&hdr[84..88]) as usize;
+    if entsz < 128 {
+        return None;
+    }
+    let base = usize::try_from(entries_lba.checked_mul(SECTOR)?).ok()?;
+    let mut parts = Vec::new();
+    for i in 0..n {
+        let off = i.checked_mul(entsz)?.checked_add(base)?;
+        let e = match head.get(off..off.checked_add(entsz)?) {
+            Some(e) => e,
+            None => break,
+        };
+        if e[0..16].iter().all(|&b| b == 0) {
+            continue;
+        }
+        let name: Vec<u16> = e[56..128]
+            .chunks(2)
+            .map(LittleEndian::read_u16)
+            .take_while(|&c| c != 0)
+            .collect();
+        parts.push(Partition {
+            number: i as u32 + 1,
+            start: LittleEndian::read_u64(&e[32..40]).checked_mul(SECTOR)?,
+            end: LittleEndian::read_u64(&e[40..48])
+                .checked_add(1)?
+                .checked_mul(SECTOR)?,
+            kind: guid(&e[0..16]),
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+    Some(parts)
+}
+
+fn probe_mbr(head: &[u8]) -> Option<Vec<Partition>> {
+    let entries = mbr_entries(sector(head, 0)?)?;
+    let mut parts = Vec::new();
+    for (i, &(kind, start, len)) in entries.iter().enumerate() {
+        if kind == 0 || len == 0 {
+            continue;
+        }
+        parts.push(Partition {
+            number: i as u32 + 1,
+            start: start * SECTOR,
+            end: (start + len) * SECTOR,
+            kind: format!("{:02x}", kind),
+            name: String::new(),
+        });
+        if is_extended(kind) {
+            parts.extend(probe_logical(head, start));
+        }
+    }
+    Some(parts)
+}
+
+// Follows the EBR chain as long as it stays inside `head`. Stops at the first EBR visited
+// twice, so that cyclic chains terminate.
+fn probe_logical(head: &[u8], ext_start: u64) -> Vec<Partition> {
+    let mut parts = Vec::new();
+    let mut visited = HashSet::new();
+    let mut ebr = ext_start;
+    while let Some(entries) = sector(head, ebr).and_then(mbr_entries) {
+        if !visited.insert(ebr) || visited.len() > MAX_LOGICAL {
+            break;
+        }
+        let (kind, start, len) = entries[0];
+        if kind != 0 && len > 0 {
+            parts.push(Partition {
+                number: 5 + parts.len() as u32,
+                start: (ebr + start) * SECTOR,
+                end: (ebr + start + len) * SECTOR,
+                kind: format!("{:02x}", kind),
+                name: String::new(),
+            });
+        }
+        let (next_kind, next_start, _) = entries[1];
+        if !is_extended(next_kind) || next_start == 0 {
+            break;
+        }
+        ebr = ext_start + next_start;
+    }
+    parts
+}
+
+/// Probes the partition table from the first bytes of an image.
+///
+/// `head` should cover at least the first chunk. Partitions whose metadata lies beyond `head`
+/// are not reported.
+pub fn probe(head: &[u8]) -> PartitionTable {
+    if let Some(parts) = mbr_entries(sector(head, 0).unwrap_or(&[0; 512]))
+        .filter(|e| e.iter().any(|&(kind, _, _)| kind == 0xee))
+        .and_then(|_| probe_gpt(head))
+    {
+        return PartitionTable {
+            table: Some(TableType::Gpt),
+            partitions: parts,
+        };
+    }
+    match probe_mbr(head) {
+        Some(parts) => PartitionTable {
+            table: Some(TableType::Mbr),
+            partitions: parts,
+        },
+        None => PartitionTable::default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::WriteBytesExt;
+
+    fn mbr_entry(buf: &mut [u8], i: usize, kind: u8, start: u32, len: u32) {
+        let e = &mut buf[446 + 16 * i..446 + 16 * (i + 1)];
+        e[4] = kind;
+        LittleEndian::write_u32(&mut e[8..12], start);
+        LittleEndian::write_u32(&mut e[12..16], len);
+    }
+
+    fn signed(buf: &mut [u8]) {
+        buf[510] = 0x55;
+        buf[511] = 0xAA;
+    }
+
+    #[test]
+    fn no_table() {
+        assert_eq!(probe(&[0; 4096]), PartitionTable::default());
+    }
+
+    #[test]
+    fn mbr_with_logical() {
+        let mut head = vec![0; 1 << 20];
+        signed(&mut head);
+        mbr_entry(&mut head, 0, 0x83, 2048, 1024);
+        mbr_entry(&mut head, 1, 0x05, 1024, 1000);
+        let ebr = &mut head[1024 * 512..1025 * 512];
+        signed(ebr);
+        mbr_entry(ebr, 0, 0x82, 8, 100);
+        let pt = probe(&head);
+        assert_eq!(pt.table, Some(TableType::Mbr));
+        assert_eq!(pt.partitions.len(), 3);
+        assert_eq!(pt.partitions[0].start, 2048 * 512);
+        assert_eq!(pt.partitions[0].end, 3072 * 512);
+        assert_eq!(pt.partitions[2].number, 5);
+        assert_eq!(pt.partitions[2].kind, "82");
+        assert_eq!(pt.partitions[2].start, 1032 * 512);
+        assert_eq!(pt.find(2048 * 512).map(|p| p.number), Some(1));
+        assert_eq!(pt.find(10), None);
+    }
+
+    #[test]
+    fn gpt() {
+        let mut head = vec![0; 1 << 20];
+        signed(&mut head);
+        mbr_entry(&mut head, 0, 0xee, 1, 0xffff_ffff);
+        let hdr = &mut head[512..1024];
+        hdr[0..8].copy_from_slice(b"EFI PART");
+        LittleEndian::write_u64(&mut hdr[72..80], 2);
+        LittleEndian::write_u32(&mut hdr[80..84], 128);
+        LittleEndian::write_u32(&mut hdr[84..88], 128);
+        let e = &mut head[1024 + 128..1024 + 256];
+        e[0] = 0xaf;
+        LittleEndian::write_u64(&mut e[32..40], 4096);
+        LittleEndian::write_u64(&mut e[40..48], 8191);
+        let mut name = &mut e[56..];
+        for c in "root".encode_utf16() {
+            name.write_u16::<LittleEndian>(c).unwrap();
+        }
+        let pt = probe(&head);
+        assert_eq!(pt.table, Some(TableType::Gpt));
+        assert_eq!(pt.partitions.len(), 1);
+        assert_eq!(pt.partitions[0].number, 2);
+        assert_eq!(pt.partitions[0].name, "root");
+        assert_eq!(pt.partitions[0].start, 4096 * 512);
+        assert_eq!(pt.partitions[0].end, 8192 * 512);
+
+        // overflowing offsets invalidate the GPT, leaving the protective MBR
+        LittleEndian::write_u64(&mut head[512 + 72..512 + 80], u64::MAX / 256);
+        assert_eq!(probe(&head).table, Some(TableType::Mbr));
+        LittleEndian::write_u64(&mut head[512 + 72..512 + 80], 2);
+        LittleEndian::write_u64(&mut head[1024 + 128 + 40..1024 + 128 + 48], u64::MAX);
+        assert_eq!(probe(&head).table, Some(TableType::Mbr));
+    }
+
+    #[test]
+    fn cyclic_ebr_chain() {
+        let mut head = vec![0; 1 << 20];
+        signed(&mut head);
+        mbr_entry(&mut head, 0, 0x05, 1024, 1000);
+        // the third EBR links back to the second; neither holds a partition
+        for (ebr, kind, next) in &[(1024, 0x83, 10), (1034, 0, 20), (1044, 0, 10)] {
+            let sect = &mut head[ebr * 512..(ebr + 1) * 512];
+            signed(sect);
+            mbr_entry(sect, 0, *kind, 8, 1);
+            mbr_entry(sect, 1, 0x05, *next, 100);
+        }
+        let pt = probe(&head);
+        assert_eq!(pt.partitions.len(), 2);
+    }
+}
@@ -0,0 +1,214 @@
+//! Bringing an existing image up to date with a revision by comparing contents.
+//!
+//! Each chunk-sized block of the target is read and hashed. Blocks whose hash differs from the
+//! chunk ID in the revision map (or which are not all zeros where the revision has a zero chunk)
+//! are overwritten with the chunk from the store. No knowledge about the target's history is
+//! needed, at the price of reading the whole target once.
+//!
+//! Holes in sparse target files are known to contain zeros. They are located with `SEEK_DATA`
+//! and compared against the hash of a zero chunk without reading them.
+
+use crate::backend::{self, Backend};
+use crate::chunkvec::ChunkId;
+use crate::writeout::Error as WriteError;
+use crate::{chunk2pos, is_zero, zero_chunk, ExtractError, HumanBytes, CHUNKSZ};
+
+use serde::Serialize;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+type Result<T, E = ExtractError> = std::result::Result<T, E>;
+
+/// Summary of a completed patch run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PatchReport {
+    /// Number of chunks in the revision
+    pub chunks: usize,
+    /// Number of chunks which have been rewritten
+    pub patched: usize,
+    /// Number of chunks which lie in holes of the target and have not been read
+    pub holes: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub elapsed: Duration,
+}
+
+impl PatchReport {
+    pub(crate) fn add(&mut self, other: &Self) {
+        self.chunks += other.chunks;
+        self.patched += other.patched;
+        self.holes += other.holes;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+    }
+}
+
+impl fmt::Display for PatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Patched {} of {} chunks ({} in holes): read {}, wrote {} in {:.1}s",
+            self.patched,
+            self.chunks,
+            self.holes,
+            HumanBytes(self.bytes_read),
+            HumanBytes(self.bytes_written),
+            self.elapsed.as_secs_f64()
+        )
+    }
+}
+
+// Returns true if `block` already has the contents of `id` (or zeros if None).
+fn matches(block: &[u8], id: Option<&ChunkId>) -> bool {
+    match id {
+        Some(id) => backend::hash(block) == id.as_str(),
+        None => is_zero(block),
+    }
+}
+
+/// Locates holes in a file with `SEEK_DATA`, remembering the start of the next data region.
+struct Holes<'a> {
+    f: &'a File,
+    /// Offset of the first data byte at or after the last query, None if there is no data left
+    next_data: Option<u64>,
+    /// Start of the last query
+    pos: u64,
+    /// Cleared if the file system or device does not report holes
+    supported: bool,
+}
+
+impl<'a> Holes<'a> {
+    fn new(f: &'a File) -> Self {
+        Self {
+            f,
+            next_data: Some(0),
+            pos: u64::MAX,
+            supported: true,
+        }
+    }
+
+    /// Returns true if `pos..pos + len` lies completely within a hole. Errors count as data.
+    fn hole(&mut self, pos: u64, len: u64) -> bool {
+        if !self.supported {
+            return false;
+        }
+        // the cached answer holds as long as we are between the last query and the next data
+        if pos < self.pos || matches!(self.next_data, Some(d) if d <= pos) {
+            let off =
+                unsafe { libc::lseek(self.f.as_raw_fd(), pos as libc::off_t, libc::SEEK_DATA) };
+            self.pos = pos;
+            self.next_data = if off >= 0 {
+                Some(off as u64)
+            } else {
+                match io::Error::last_os_error().raw_os_error() {
+                    // no more data up to the end of the file
+                    Some(libc::ENXIO) => None,
+                    _ => {
+                        self.supported = false;
+                        return false;
+                    }
+                }
+            };
+        }
+        !matches!(self.next_data, Some(d) if d < pos + len)
+    }
+}
+
+/// Compares and patches every `nthreads`th chunk of `map`, starting with `threadid`.
+pub(crate) fn patch_part(
+    map: &[Option<ChunkId>],
+    threadid: u8,
+    nthreads: u8,
+    target: &File,
+    be: &Backend,
+    verify: bool,
+) -> Result<PatchReport> {
+    let mut report = PatchReport::default();
+    let mut block = vec![0; CHUNKSZ];
+    let mut holes = Holes::new(target);
+    for (seq, id) in map
+        .iter()
+        .enumerate()
+        .skip(threadid as usize)
+        .step_by(nthreads.max(1) as usize)
+    {
+        let seq = seq as u32;
+        let pos = chunk2pos(seq);
+        report.chunks += 1;
+        if holes.hole(pos, CHUNKSZ as u64) {
+            report.holes += 1;
+            // holes read as zeros: only chunks with other contents are written
+            let zeros = match id {
+                Some(id) => id.as_str() == backend::ZERO_HASH,
+                None => true,
+            };
+            if zeros {
+                continue;
+            }
+        } else {
+            let complete = match target.read_exact_at(&mut block, pos) {
+                Ok(()) => {
+                    report.bytes_read += CHUNKSZ as u64;
+                    true
+                }
+                // the target is too short: whatever is missing gets written
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+                Err(e) => return Err(WriteError::ReadChunk(seq, e).into()),
+            };
+            if complete && matches(&block, id.as_ref()) {
+                continue;
+            }
+        }
+        let loaded;
+        let data: &[u8] = match id {
+            Some(id) => {
+                loaded = be
+                    .load(id)
+                    .map_err(|e| ExtractError::invalid_chunk(be, seq, id, e))?;
+                if verify {
+                    let actual = backend::hash(&loaded);
+                    if actual != id.as_str() {
+                        return Err(ExtractError::checksum(be, seq, id, actual));
+                    }
+                }
+                &loaded
+            }
+            None => zero_chunk().map_err(|e| WriteError::WriteChunk(seq, e))?,
+        };
+        target
+            .write_all_at(data, pos)
+            .map_err(|e| WriteError::WriteChunk(seq, e))?;
+        report.patched += 1;
+        report.bytes_written += data.len() as u64;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn find_holes() {
+        let td = TempDir::new("holes").unwrap();
+        let f = File::create(td.path().join("img")).unwrap();
+        let chunk = CHUNKSZ as u64;
+        f.set_len(4 * chunk).unwrap();
+        f.write_all_at(b"data", 2 * chunk + 5).unwrap();
+        let mut holes = Holes::new(&f);
+        let found: Vec<bool> = (0..4).map(|i| holes.hole(i * chunk, chunk)).collect();
+        if !holes.supported || found == vec![false; 4] {
+            // file system without hole reporting
+            return;
+        }
+        assert_eq!(found, vec![true, true, false, true]);
+        // going backwards queries again
+        assert!(holes.hole(0, chunk));
+        assert!(!holes.hole(2 * chunk, 1));
+    }
+}
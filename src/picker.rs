@@ -0,0 +1,245 @@
+//! Interactive selection of a revision on the terminal.
+//!
+//! [pick] lists revisions newest first, one line each with timestamp, ID, image size, trust
+//! level and tags. Typing narrows the list down: every whitespace-separated word of the query
+//! must match a line fuzzily, i.e. its characters must occur in this order, not necessarily
+//! next to each other. Lines with matches at word starts and runs of adjacent characters come
+//! first. Arrow keys and Page Up/Down move the selection, Enter confirms, Escape or Ctrl-C
+//! cancels and Ctrl-U clears the query.
+//!
+//! The list is drawn on stderr, so that stdout can be redirected to the restore target.
+
+use crate::revisions::Summary;
+use crate::HumanBytes;
+
+use console::{style, truncate_str, Key, Term};
+use std::io;
+
+/// Maximum number of lines shown at once
+const HEIGHT: usize = 15;
+
+const CTRL_C: char = '\u{3}';
+const CTRL_D: char = '\u{4}';
+const CTRL_U: char = '\u{15}';
+
+fn label(r: &Summary) -> String {
+    format!(
+        "{}  {}  {:>10}  {:<10}  {}",
+        r.timestamp.format("%Y-%m-%d %H:%M:%S"),
+        r.uuid,
+        r.size.map_or("?".to_owned(), |s| HumanBytes(s).to_string()),
+        r.trust.to_string(),
+        r.tags.join(",")
+    )
+    .trim_end()
+    .to_owned()
+}
+
+// Characters after which a match counts as word start.
+fn is_boundary(c: char) -> bool {
+    !c.is_alphanumeric()
+}
+
+// Scores the match of `word` in `text` which starts at `start`, taking the leftmost occurrence
+// of each following character. None if it does not match.
+fn score_from(word: &[char], text: &[char], start: usize) -> Option<u32> {
+    let mut score = 0;
+    let mut pos = start;
+    let mut last = None;
+    for &q in word {
+        let i = pos + text[pos..].iter().position(|&c| c == q)?;
+        score += 1;
+        if i == 0 || is_boundary(text[i - 1]) {
+            score += 3;
+        }
+        if last.is_some_and(|l| l + 1 == i) {
+            score += 4;
+        }
+        last = Some(i);
+        pos = i + 1;
+    }
+    Some(score)
+}
+
+// Scores how well `word` matches `text` fuzzily. None if it does not match at all.
+fn score_word(word: &str, text: &[char]) -> Option<u32> {
+    let word: Vec<char> = word.chars().flat_map(char::to_lowercase).collect();
+    (0..text.len())
+        .filter(|&i| Some(&text[i]) == word.first())
+        .filter_map(|i| score_from(&word, text, i))
+        .max()
+}
+
+/// Scores how well `query` matches `text`. All words of `query` must match. Higher scores are
+/// better; an empty query matches everything with score 0.
+fn score(query: &str, text: &str) -> Option<u32> {
+    let text: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+    query.split_whitespace().map(|w| score_word(w, &text)).sum()
+}
+
+/// Indices of the `labels` matching `query`, best match first. Equally good matches keep their
+/// order.
+fn filter(query: &str, labels: &[String]) -> Vec<usize> {
+    let mut hits: Vec<(u32, usize)> = labels
+        .iter()
+        .enumerate()
+        .filter_map(|(i, l)| score(query, l).map(|s| (s, i)))
+        .collect();
+    hits.sort_by_key(|&(s, i)| (std::cmp::Reverse(s), i));
+    hits.into_iter().map(|(_, i)| i).collect()
+}
+
+struct Picker<'a> {
+    term: Term,
+    labels: Vec<String>,
+    query: String,
+    hits: Vec<usize>,
+    /// Position of the selected line in `hits`
+    selected: usize,
+    /// Position of the first visible line in `hits`
+    top: usize,
+    /// Number of lines drawn last time
+    drawn: usize,
+    prompt: &'a str,
+}
+
+impl<'a> Picker<'a> {
+    fn update(&mut self) {
+        self.hits = filter(&self.query, &self.labels);
+        self.selected = 0;
+        self.top = 0;
+    }
+
+    fn height(&self) -> usize {
+        let rows = self.term.size().0 as usize;
+        HEIGHT.min(rows.saturating_sub(3)).max(1)
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        if self.hits.is_empty() {
+            return;
+        }
+        let max = self.hits.len() - 1;
+        self.selected = (self.selected as isize + delta).clamp(0, max as isize) as usize;
+        let height = self.height();
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + height {
+            self.top = self.selected + 1 - height;
+        }
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        self.term.clear_last_lines(self.drawn)?;
+        let width = self.term.size().1 as usize;
+        let height = self.height();
+        let mut lines = vec![format!("{} {}", style(self.prompt).bold(), self.query)];
+        for (n, &i) in self.hits.iter().enumerate().skip(self.top).take(height) {
+            let line = truncate_str(&self.labels[i], width.saturating_sub(3), "…");
+            lines.push(if n == self.selected {
+                format!("{} {}", style(">").cyan().bold(), style(line).reverse())
+            } else {
+                format!("  {}", line)
+            });
+        }
+        lines.push(
+            style(format!("  {}/{}", self.hits.len(), self.labels.len()))
+                .dim()
+                .to_string(),
+        );
+        for l in &lines {
+            self.term.write_line(l)?;
+        }
+        self.drawn = lines.len();
+        Ok(())
+    }
+
+    fn run(&mut self) -> io::Result<Option<usize>> {
+        loop {
+            self.draw()?;
+            match self.term.read_key()? {
+                Key::Enter => return Ok(self.hits.get(self.selected).copied()),
+                Key::Escape | Key::Char(CTRL_C) | Key::Char(CTRL_D) => return Ok(None),
+                Key::ArrowUp => self.move_by(-1),
+                Key::ArrowDown | Key::Tab => self.move_by(1),
+                Key::PageUp => self.move_by(-(self.height() as isize)),
+                Key::PageDown => self.move_by(self.height() as isize),
+                Key::Home => self.move_by(-(self.hits.len() as isize)),
+                Key::End => self.move_by(self.hits.len() as isize),
+                Key::Backspace => {
+                    self.query.pop();
+                    self.update();
+                }
+                Key::Char(CTRL_U) => {
+                    self.query.clear();
+                    self.update();
+                }
+                Key::Char(c) if !c.is_control() => {
+                    self.query.push(c);
+                    self.update();
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Lets the user pick one of `revs` (oldest first, as returned by
+/// [revisions::summaries](../revisions/fn.summaries.html)) on the terminal. Returns None if the
+/// user cancels.
+pub fn pick<'r>(revs: &'r [Summary], prompt: &str) -> io::Result<Option<&'r Summary>> {
+    let mut p = Picker {
+        term: Term::stderr(),
+        labels: revs.iter().rev().map(label).collect(),
+        query: String::new(),
+        hits: Vec::new(),
+        selected: 0,
+        top: 0,
+        drawn: 0,
+        prompt,
+    };
+    p.update();
+    p.term.hide_cursor()?;
+    let res = p.run();
+    p.term.clear_last_lines(p.drawn).ok();
+    p.term.show_cursor()?;
+    Ok(res?.map(|i| &revs[revs.len() - 1 - i]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(l: &[&str]) -> Vec<String> {
+        l.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn fuzzy_match() {
+        assert_eq!(score("", "anything"), Some(0));
+        assert!(score("vnz", "2019-01-11 VNzWKjnMqd6w58nzJwUZ98").is_some());
+        assert!(score("wkz", "VNzWKjnMqd6w58nzJwUZ98").is_some());
+        assert!(score("98v", "VNzWKjnMqd6w58nzJwUZ98").is_none());
+        // all words must match, in any order
+        assert!(score("weekly 01-11", "2019-01-11 12:00 daily,weekly").is_some());
+        assert!(score("weekly monthly", "2019-01-11 12:00 daily,weekly").is_none());
+        // adjacent characters and word starts are preferred
+        assert!(score("day", "daily") < score("dai", "daily"));
+        assert!(score("w", "a-w") > score("w", "aw"));
+    }
+
+    #[test]
+    fn best_matches_first() {
+        let l = labels(&[
+            "2019-01-13  Cccc  daily",
+            "2019-01-12  Bbbb  weekly",
+            "2019-01-11  Aaaa  daily,weekly",
+        ]);
+        assert_eq!(filter("", &l), &[0, 1, 2]);
+        assert_eq!(filter("weekly", &l), &[1, 2]);
+        assert_eq!(filter("dly", &l), &[0, 2]);
+        // fuzzy matches of dates are plentiful, the exact one comes first
+        assert_eq!(filter("01-11", &l)[0], 2);
+        assert!(filter("monthly", &l).is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+//! Cost estimate of a restore.
+
+use crate::{Concurrency, HumanBytes, RevisionSpec, CHUNKSZ};
+
+use std::fmt;
+
+/// What a restore is going to do, computed from the revision map alone.
+///
+/// Returned by [Extractor::plan](struct.Extractor.html#method.plan). Chunk files are not looked
+/// at, so the plan cannot tell how much compressed data is read, which chunks are missing or
+/// which data chunks consist of zeros. Chunks skipped with
+/// [skip_unallocated](struct.Extractor.html#method.skip_unallocated) are not accounted for
+/// either.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestorePlan {
+    pub revision: String,
+    /// Image size in bytes
+    pub size: u64,
+    /// Number of chunk positions in the image
+    pub chunks: usize,
+    /// Distinct chunks which are read from the store
+    pub unique: usize,
+    /// Positions without chunk. They are never read.
+    pub zero: usize,
+    /// Positions which are filled with a chunk which has been loaded for another position
+    pub reused: usize,
+    /// Bytes which decompression produces
+    pub decompress_bytes: u64,
+    /// Bytes written if zero chunks are skipped over (sparse mode)
+    pub sparse_bytes: u64,
+    /// Bytes written otherwise
+    pub full_bytes: u64,
+    /// Threads per stage and queue depths as the restore would use them
+    pub concurrency: Concurrency,
+    /// Chunks are hashed after decompression
+    pub verify: bool,
+}
+
+impl RestorePlan {
+    pub(crate) fn new(spec: &RevisionSpec, concurrency: Concurrency, verify: bool) -> Self {
+        let chunk = CHUNKSZ as u64;
+        let mapped = spec.len() - spec.zero_chunks();
+        Self {
+            revision: spec.revision().to_owned(),
+            size: spec.size(),
+            chunks: spec.len(),
+            unique: spec.unique(),
+            zero: spec.zero_chunks(),
+            reused: mapped - spec.unique(),
+            decompress_bytes: spec.unique() as u64 * chunk,
+            sparse_bytes: mapped as u64 * chunk,
+            full_bytes: spec.size(),
+            concurrency,
+            verify,
+        }
+    }
+
+    /// Share of the data positions which are served from chunks loaded for other positions
+    pub fn reuse_ratio(&self) -> f64 {
+        let mapped = self.chunks - self.zero;
+        if mapped == 0 {
+            0.0
+        } else {
+            self.reused as f64 / mapped as f64
+        }
+    }
+}
+
+impl fmt::Display for RestorePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let c = &self.concurrency;
+        writeln!(
+            f,
+            "Revision {}: {} in {} chunks, {} of them zero",
+            self.revision,
+            HumanBytes(self.size),
+            self.chunks,
+            self.zero
+        )?;
+        writeln!(
+            f,
+            "Read {} distinct chunks, decompress {}{}, {} positions ({:.1}%) reuse a loaded chunk",
+            self.unique,
+            HumanBytes(self.decompress_bytes),
+            if self.verify { " and verify" } else { "" },
+            self.reused,
+            self.reuse_ratio() * 100.0
+        )?;
+        writeln!(
+            f,
+            "Write {} in sparse mode, {} otherwise",
+            HumanBytes(self.sparse_bytes),
+            HumanBytes(self.full_bytes)
+        )?;
+        writeln!(
+            f,
+            "Threads: {} reader(s), {} decompressor(s), {} writer(s)",
+            c.readers, c.decompressors, c.writers
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::store_tar;
+    use crate::Extractor;
+
+    #[test]
+    fn plan_from_map() {
+        let store = store_tar();
+        let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98")).unwrap();
+        e.threads(3).verify(true);
+        let plan = e.plan().unwrap();
+        let spec = e.spec().unwrap();
+        assert_eq!(plan.chunks, spec.len());
+        assert_eq!(plan.unique, spec.unique());
+        assert_eq!(plan.zero + plan.reused + plan.unique, plan.chunks);
+        assert_eq!(plan.full_bytes, spec.size());
+        assert_eq!(
+            plan.sparse_bytes,
+            (plan.chunks - plan.zero) as u64 * CHUNKSZ as u64
+        );
+        assert_eq!(plan.decompress_bytes, plan.unique as u64 * CHUNKSZ as u64);
+        assert_eq!(plan.concurrency.decompressors, 3);
+        assert!(plan.to_string().contains("decompress 8.00MB and verify"));
+    }
+}
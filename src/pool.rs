@@ -0,0 +1,144 @@
+//! Running several restores at once.
+
+use crate::backend::Backend;
+use crate::writeout::WriteOutBuilder;
+use crate::{Codec, ExtractReport, Extractor, Result};
+
+use crossbeam::channel::unbounded;
+use crossbeam::thread;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Resources shared by all restores of a pool.
+#[derive(Debug)]
+pub(crate) struct Shared {
+    /// Number of chunks which may be loaded at the same time
+    free: Mutex<u8>,
+    cv: Condvar,
+    backends: Mutex<HashMap<PathBuf, Backend>>,
+}
+
+/// Right to load one chunk. Returned to the pool when dropped.
+#[derive(Debug)]
+pub(crate) struct Permit<'a>(&'a Shared);
+
+impl Shared {
+    fn new(threads: u8) -> Self {
+        Self {
+            free: Mutex::new(threads),
+            cv: Condvar::new(),
+            backends: Mutex::default(),
+        }
+    }
+
+    /// Blocks until a decoder slot is available.
+    pub(crate) fn acquire(&self) -> Permit<'_> {
+        let mut free = self.free.lock().expect("poisoned lock");
+        while *free == 0 {
+            free = self.cv.wait(free).expect("poisoned lock");
+        }
+        *free -= 1;
+        Permit(self)
+    }
+
+    /// Returns the backend for `dir`. Each store is opened only once per pool.
+    pub(crate) fn backend(&self, dir: &Path, codec: Codec) -> Result<Backend> {
+        let mut backends = self.backends.lock().expect("poisoned lock");
+        let be = match backends.get(dir) {
+            Some(be) => be.clone(),
+            None => {
+                let be = Backend::open(dir)?;
+                backends.insert(dir.to_owned(), be.clone());
+                be
+            }
+        };
+        Ok(be.with_codec(codec))
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().expect("poisoned lock") += 1;
+        self.0.cv.notify_one();
+    }
+}
+
+/// Runs several [Extractor](struct.Extractor.html)s concurrently.
+///
+/// All restores share a global budget of decoder threads: no matter how many restores are
+/// running, at most `threads` chunks are loaded and decompressed at the same time. Idle capacity
+/// is picked up by whichever restore has work left. Chunk stores are opened once and shared
+/// between restores from the same backup directory. Individual restores can be restricted further
+/// with [limits](struct.Extractor.html#method.limits).
+///
+/// Progress bars of concurrent restores get in each other's way. Disable them or use
+/// [progress_sink](struct.Extractor.html#method.progress_sink) instead.
+#[derive(Debug)]
+pub struct RestorePool {
+    threads: u8,
+    jobs: usize,
+    shared: Arc<Shared>,
+}
+
+impl Default for RestorePool {
+    fn default() -> Self {
+        Self::new(Extractor::default_threads())
+    }
+}
+
+impl RestorePool {
+    /// Creates a pool which decompresses with up to `threads` threads in total.
+    pub fn new(threads: u8) -> Self {
+        let threads = threads.max(1);
+        Self {
+            threads,
+            jobs: 4,
+            shared: Arc::new(Shared::new(threads)),
+        }
+    }
+
+    /// Sets the maximum number of restores running at the same time. Defaults to 4.
+    pub fn jobs(&mut self, n: usize) -> &mut Self {
+        self.jobs = n.max(1);
+        self
+    }
+
+    /// Runs all restores and waits until they are finished. Each restore writes to the target
+    /// which is paired with it. Results are returned in the order of `jobs`. A failing restore
+    /// does not affect the others.
+    pub fn run<W>(&self, jobs: Vec<(Extractor, W)>) -> Vec<Result<ExtractReport>>
+    where
+        W: WriteOutBuilder + Send,
+    {
+        let n = jobs.len();
+        let (tx, rx) = unbounded();
+        for job in jobs.into_iter().enumerate() {
+            tx.send(job).expect("channel closed");
+        }
+        drop(tx);
+        let mut results: Vec<_> = thread::scope(|s| {
+            let workers: Vec<_> = (0..self.jobs.min(n))
+                .map(|_| {
+                    let rx = rx.clone();
+                    s.spawn(move |_| {
+                        rx.into_iter()
+                            .map(|(i, (mut e, w)): (usize, (Extractor, W))| {
+                                e.concurrency.decompressors = self.threads;
+                                e.pool = Some(Arc::clone(&self.shared));
+                                (i, e.extract(w))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().expect("unhandled panic"))
+                .collect()
+        })
+        .expect("subthread panic");
+        results.sort_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, r)| r).collect()
+    }
+}
@@ -0,0 +1,323 @@
+//! Trial restores into device-mapper snapshots.
+//!
+//! A trial restore does not touch the target device. A `snapshot` mapping is set up on top of it
+//! and the restore goes into the mapping, which stores all changes on a separate COW device. The
+//! result can be inspected under `/dev/mapper/backy-trial-<device>` and is afterwards either
+//! merged into the target or discarded. The target must not be used while the trial is active.
+//!
+//! Mappings are managed with device-mapper ioctls directly, so neither `dmsetup` nor LVM is
+//! needed.
+
+use super::{Error, Prepare, Result};
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Size of COW chunks in 512 byte sectors
+const CHUNK_SECTORS: u64 = 32;
+
+// struct dm_ioctl and struct dm_target_spec from <linux/dm-ioctl.h>
+const HDR_SIZE: usize = 312;
+const SPEC_SIZE: usize = 40;
+const NAME_LEN: usize = 128;
+const TYPE_LEN: usize = 16;
+const BUF_SIZE: usize = 16 << 10;
+
+const DM_DEV_CREATE: u8 = 3;
+const DM_DEV_REMOVE: u8 = 4;
+const DM_DEV_SUSPEND: u8 = 6;
+const DM_TABLE_LOAD: u8 = 9;
+const DM_TABLE_STATUS: u8 = 12;
+
+const DM_STATUS_TABLE_FLAG: u32 = 1 << 4;
+const DM_BUFFER_FULL_FLAG: u32 = 1 << 8;
+
+// _IOWR(0xfd, nr, struct dm_ioctl)
+fn request(nr: u8) -> u64 {
+    (3 << 30) | ((HDR_SIZE as u64) << 16) | (0xfd << 8) | u64::from(nr)
+}
+
+fn put_u32(buf: &mut [u8], off: usize, v: u32) {
+    buf[off..off + 4].copy_from_slice(&v.to_ne_bytes());
+}
+
+fn put_u64(buf: &mut [u8], off: usize, v: u64) {
+    buf[off..off + 8].copy_from_slice(&v.to_ne_bytes());
+}
+
+fn get_u32(buf: &[u8], off: usize) -> u32 {
+    let mut b = [0; 4];
+    b.copy_from_slice(&buf[off..off + 4]);
+    u32::from_ne_bytes(b)
+}
+
+fn get_u64(buf: &[u8], off: usize) -> u64 {
+    let mut b = [0; 8];
+    b.copy_from_slice(&buf[off..off + 8]);
+    u64::from_ne_bytes(b)
+}
+
+// Returns the NUL-terminated string at the start of `buf`.
+fn get_str(buf: &[u8]) -> String {
+    let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+// Builds an ioctl buffer for mapping `name`, optionally with a single target.
+fn encode(name: &str, flags: u32, target: Option<(u64, &str, &str)>) -> Vec<u8> {
+    let mut buf = vec![0; BUF_SIZE];
+    for (i, v) in [4, 0, 0].iter().enumerate() {
+        put_u32(&mut buf, i * 4, *v);
+    }
+    put_u32(&mut buf, 12, BUF_SIZE as u32);
+    put_u32(&mut buf, 16, HDR_SIZE as u32);
+    put_u32(&mut buf, 28, flags);
+    let n = name.len().min(NAME_LEN - 1);
+    buf[48..48 + n].copy_from_slice(&name.as_bytes()[..n]);
+    if let Some((sectors, kind, params)) = target {
+        put_u32(&mut buf, 20, 1);
+        let spec = HDR_SIZE;
+        put_u64(&mut buf, spec + 8, sectors);
+        let k = kind.len().min(TYPE_LEN - 1);
+        buf[spec + 24..spec + 24 + k].copy_from_slice(&kind.as_bytes()[..k]);
+        let p = spec + SPEC_SIZE;
+        buf[p..p + params.len()].copy_from_slice(params.as_bytes());
+    }
+    buf
+}
+
+// Extracts the parameters of the first target from a DM_TABLE_STATUS result.
+fn decode_status(buf: &[u8]) -> Option<String> {
+    if get_u32(buf, 20) == 0 {
+        return None;
+    }
+    let spec = get_u32(buf, 16) as usize;
+    buf.get(spec + SPEC_SIZE..).map(get_str)
+}
+
+// Issues device-mapper ioctl `nr` on `buf`.
+fn ioctl(nr: u8, what: &'static str, name: &str, buf: &mut [u8]) -> Result<()> {
+    let err = |e| Error::Dm(what, name.to_owned(), e);
+    let ctl = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/mapper/control")
+        .map_err(err)?;
+    if unsafe { libc::ioctl(ctl.as_raw_fd(), request(nr) as _, buf.as_mut_ptr()) } < 0 {
+        return Err(err(io::Error::last_os_error()));
+    }
+    if get_u32(buf, 28) & DM_BUFFER_FULL_FLAG != 0 {
+        return Err(err(io::Error::from_raw_os_error(libc::ENOSPC)));
+    }
+    Ok(())
+}
+
+// Creates and activates mapping `name` with a single target of `sectors` length. Returns the
+// path of the new device node.
+fn create(name: &str, sectors: u64, kind: &str, params: &str) -> Result<PathBuf> {
+    ioctl(DM_DEV_CREATE, "create", name, &mut encode(name, 0, None))?;
+    let mut buf = encode(name, 0, Some((sectors, kind, params)));
+    let res = ioctl(DM_TABLE_LOAD, "table load", name, &mut buf).and_then(|_| {
+        let mut buf = encode(name, 0, None);
+        ioctl(DM_DEV_SUSPEND, "resume", name, &mut buf)?;
+        node(name, get_u64(&buf, 40))
+    });
+    if res.is_err() {
+        remove(name).ok();
+    }
+    res
+}
+
+// Waits for udev to create the device node of `name` and creates it if udev is not running.
+fn node(name: &str, dev: u64) -> Result<PathBuf> {
+    let path = Path::new("/dev/mapper").join(name);
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(3) {
+        if path.exists() {
+            return Ok(path);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let cpath = CString::new(path.as_os_str().as_bytes()).expect("no NUL in device name");
+    if unsafe { libc::mknod(cpath.as_ptr(), libc::S_IFBLK | 0o600, dev as libc::dev_t) } < 0 {
+        return Err(Error::Dm(
+            "mknod",
+            name.to_owned(),
+            io::Error::last_os_error(),
+        ));
+    }
+    Ok(path)
+}
+
+fn remove(name: &str) -> Result<()> {
+    ioctl(DM_DEV_REMOVE, "remove", name, &mut encode(name, 0, None))
+}
+
+// Returns the status (or table with `table`) of mapping `name`.
+fn status(name: &str, table: bool) -> Result<String> {
+    let flags = if table { DM_STATUS_TABLE_FLAG } else { 0 };
+    let mut buf = encode(name, flags, None);
+    ioctl(DM_TABLE_STATUS, "status", name, &mut buf)?;
+    decode_status(&buf).ok_or_else(|| Error::NoTrial(name.to_owned()))
+}
+
+// Tells whether mapping `name` exists.
+fn exists(name: &str) -> Result<bool> {
+    match ioctl(DM_TABLE_STATUS, "status", name, &mut encode(name, 0, None)) {
+        Ok(()) => Ok(true),
+        Err(Error::Dm(_, _, e)) if e.raw_os_error() == Some(libc::ENXIO) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Size of a block device or file in sectors.
+fn sectors(path: &Path) -> Result<u64> {
+    let mut f = File::open(path).map_err(|e| Error::Lookup(path.to_owned(), e))?;
+    let size = f
+        .seek(SeekFrom::End(0))
+        .map_err(|e| Error::Lookup(path.to_owned(), e))?;
+    Ok(size >> 9)
+}
+
+/// Name of the trial mapping for `target`.
+pub fn trial_name(target: &Path) -> Result<String> {
+    let dev = fs::canonicalize(target).map_err(|e| Error::Lookup(target.to_owned(), e))?;
+    Ok(format!(
+        "backy-trial-{}",
+        dev.file_name().unwrap_or_default().to_string_lossy()
+    ))
+}
+
+/// Sets up a trial restore of `target` with changes going to `cow`.
+#[derive(Debug, Clone)]
+pub struct DmSnapshot {
+    cow: PathBuf,
+    name: Option<String>,
+}
+
+impl DmSnapshot {
+    /// `cow` is a block device which receives all data written during the restore, so it should
+    /// be about as large as the target. Its previous contents are lost.
+    pub fn new<P: AsRef<Path>>(cow: P) -> Self {
+        Self {
+            cow: cow.as_ref().to_owned(),
+            name: None,
+        }
+    }
+
+    /// Device node of the trial restore once it has been set up.
+    pub fn device(&self) -> Option<PathBuf> {
+        self.name.as_ref().map(|n| Path::new("/dev/mapper").join(n))
+    }
+
+    // Zeroes the snapshot header so that the kernel initializes a new COW store.
+    fn wipe_cow(&self) -> Result<()> {
+        let err = |e| Error::Cow(self.cow.clone(), e);
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(&self.cow)
+            .map_err(err)?;
+        f.write_all(&[0; (CHUNK_SECTORS << 9) as usize])
+            .and_then(|_| f.sync_all())
+            .map_err(err)
+    }
+}
+
+impl Prepare for DmSnapshot {
+    fn prepare(&mut self, target: &Path) -> Result<PathBuf> {
+        let name = trial_name(target)?;
+        let size = sectors(target)?;
+        // the COW device of an active trial or merge still holds its changes
+        for n in [name.clone(), format!("{}-merge", name)] {
+            if exists(&n)? {
+                return Err(Error::TrialActive(n));
+            }
+        }
+        self.wipe_cow()?;
+        let params = format!(
+            "{} {} P {}",
+            target.display(),
+            self.cow.display(),
+            CHUNK_SECTORS
+        );
+        let path = create(&name, size, "snapshot", &params)?;
+        self.name = Some(name);
+        Ok(path)
+    }
+
+    /// Failed trials are discarded right away, successful ones are kept for inspection.
+    fn finish(&mut self, success: bool) -> Result<()> {
+        match &self.name {
+            Some(name) if !success => remove(name),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Drops the trial restore of `target`. The target remains as it was before.
+pub fn discard(target: &Path) -> Result<()> {
+    remove(&trial_name(target)?)
+}
+
+/// Writes the trial restore of `target` into the target and removes the trial mapping. Blocks
+/// until all changes have been merged.
+pub fn merge(target: &Path) -> Result<()> {
+    let name = trial_name(target)?;
+    // table is "<origin> <cow> P <chunksize>" with devices as major:minor
+    let table = status(&name, true)?;
+    let size = sectors(target)?;
+    remove(&name)?;
+    let merge = format!("{}-merge", name);
+    create(&merge, size, "snapshot-merge", &table)?;
+    loop {
+        // "<allocated>/<total> <metadata>": done when only metadata is left
+        let st = status(&merge, false)?;
+        let mut f = st.split(&['/', ' '][..]);
+        match (f.next(), f.nth(1)) {
+            (Some(alloc), Some(meta)) if alloc == meta => break,
+            (Some(_), Some(_)) => thread::sleep(Duration::from_millis(200)),
+            _ => return Err(Error::Merge(merge, st)),
+        }
+    }
+    remove(&merge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ioctl_numbers() {
+        // values from <linux/dm-ioctl.h> on x86_64
+        assert_eq!(request(DM_DEV_CREATE), 0xc138fd03);
+        assert_eq!(request(DM_TABLE_STATUS), 0xc138fd0c);
+    }
+
+    #[test]
+    fn encode_table() {
+        let buf = encode(
+            "backy-trial-sdb",
+            0,
+            Some((2048, "snapshot", "/dev/sdb /dev/sdc P 32")),
+        );
+        assert_eq!(get_u32(&buf, 0), 4);
+        assert_eq!(get_u32(&buf, 16), HDR_SIZE as u32);
+        assert_eq!(get_u32(&buf, 20), 1);
+        assert_eq!(get_str(&buf[48..]), "backy-trial-sdb");
+        assert_eq!(get_u64(&buf, HDR_SIZE), 0);
+        assert_eq!(get_u64(&buf, HDR_SIZE + 8), 2048);
+        assert_eq!(get_str(&buf[HDR_SIZE + 24..]), "snapshot");
+        assert_eq!(
+            get_str(&buf[HDR_SIZE + SPEC_SIZE..]),
+            "/dev/sdb /dev/sdc P 32"
+        );
+        assert_eq!(decode_status(&buf).unwrap(), "/dev/sdb /dev/sdc P 32");
+        assert_eq!(decode_status(&encode("x", 0, None)), None);
+    }
+}
@@ -0,0 +1,77 @@
+//! Preparation of restore targets.
+//!
+//! Some targets offer a safety net if they are set up before a restore starts, e.g. a snapshot
+//! to roll back to. Preparations wrap a restore: [prepare](trait.Prepare.html#tymethod.prepare)
+//! runs before anything is written and yields the path to restore to, and
+//! [finish](trait.Prepare.html#tymethod.finish) runs afterwards.
+
+pub mod dm;
+pub mod zfs;
+
+use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to run `{0}'")]
+    Spawn(String, #[source] io::Error),
+    #[error("`{0}' failed: {1}")]
+    Command(String, String),
+    #[error("'{}' is not a ZFS volume", .0.display())]
+    NoZvol(PathBuf),
+    #[error("Failed to look up '{}'", .0.display())]
+    Lookup(PathBuf, #[source] io::Error),
+    #[error("Device mapper {0} of '{1}' failed")]
+    Dm(&'static str, String, #[source] io::Error),
+    #[error("Failed to initialize COW device '{}'", .0.display())]
+    Cow(PathBuf, #[source] io::Error),
+    #[error("No trial restore '{0}' found")]
+    NoTrial(String),
+    #[error("Trial restore '{0}' is still active, merge or discard it first")]
+    TrialActive(String),
+    #[error("Unexpected status of '{0}' while merging: {1}")]
+    Merge(String, String),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Setup and teardown around a restore.
+pub trait Prepare: Debug {
+    /// Prepares `target` before the restore starts and returns the path to restore to.
+    fn prepare(&mut self, target: &Path) -> Result<PathBuf>;
+
+    /// Completes the preparation after the restore has finished, successfully or not.
+    fn finish(&mut self, success: bool) -> Result<()>;
+}
+
+// Runs an external command and returns its stdout. Fails with its stderr if it exits non-zero.
+fn run<S: AsRef<OsStr>>(cmd: &Path, args: &[S]) -> Result<String> {
+    let line = || {
+        let mut line = cmd.display().to_string();
+        for a in args {
+            line.push(' ');
+            line.push_str(&a.as_ref().to_string_lossy());
+        }
+        line
+    };
+    let out = Command::new(cmd)
+        .args(args)
+        .output()
+        .map_err(|e| Error::Spawn(line(), e))?;
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr).trim().to_owned();
+        return Err(Error::Command(
+            line(),
+            if stderr.is_empty() {
+                out.status.to_string()
+            } else {
+                stderr
+            },
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
@@ -0,0 +1,200 @@
+//! Snapshots of ZFS volumes around restores.
+//!
+//! A snapshot taken right before the restore allows to undo it instantly with `zfs rollback`,
+//! e.g. if it fails halfway or the wrong revision has been picked.
+
+use super::{run, Error, Prepare, Result};
+
+use chrono::Utc;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Snapshots the ZFS volume which is restored to before (and optionally after) the restore.
+#[derive(Debug, Clone)]
+pub struct ZfsSnapshot {
+    zfs: PathBuf,
+    zvol_dir: PathBuf,
+    label: String,
+    after: bool,
+    stamp: String,
+    dataset: Option<String>,
+    snapshots: Vec<String>,
+}
+
+impl ZfsSnapshot {
+    /// Snapshot names contain `label`, usually the revision ID, and the time of the restore.
+    pub fn new(label: &str) -> Self {
+        Self {
+            zfs: "zfs".into(),
+            zvol_dir: "/dev/zvol".into(),
+            label: label.to_owned(),
+            after: false,
+            stamp: String::new(),
+            dataset: None,
+            snapshots: Vec::new(),
+        }
+    }
+
+    /// Takes another snapshot after a successful restore.
+    pub fn after(mut self, after: bool) -> Self {
+        self.after = after;
+        self
+    }
+
+    /// Runs `cmd` instead of `zfs` from PATH.
+    pub fn command<P: AsRef<Path>>(mut self, cmd: P) -> Self {
+        self.zfs = cmd.as_ref().to_owned();
+        self
+    }
+
+    /// Full names of the snapshots taken so far.
+    pub fn snapshots(&self) -> &[String] {
+        &self.snapshots
+    }
+
+    // Finds the dataset name of the zvol device `target` via the symlinks in /dev/zvol.
+    fn dataset(&self, target: &Path) -> Result<String> {
+        let dev = fs::canonicalize(target).map_err(|e| Error::Lookup(target.to_owned(), e))?;
+        let name = dev.file_name().unwrap_or_default().to_string_lossy();
+        // partitions of zvols are named zdNpM
+        if !(name.starts_with("zd") && name[2..].chars().all(|c| c.is_ascii_digit())) {
+            return Err(Error::NoZvol(target.to_owned()));
+        }
+        if let Ok(rel) = target.strip_prefix(&self.zvol_dir) {
+            return Ok(rel.to_string_lossy().into_owned());
+        }
+        let mut dirs = vec![self.zvol_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).map_err(|e| Error::Lookup(dir.clone(), e))? {
+                let entry = entry.map_err(|e| Error::Lookup(dir.clone(), e))?;
+                let path = entry.path();
+                if entry
+                    .file_type()
+                    .map_err(|e| Error::Lookup(path.clone(), e))?
+                    .is_dir()
+                {
+                    dirs.push(path);
+                } else if fs::canonicalize(&path).ok().as_ref() == Some(&dev) {
+                    let rel = path.strip_prefix(&self.zvol_dir).expect("below zvol dir");
+                    return Ok(rel.to_string_lossy().into_owned());
+                }
+            }
+        }
+        Err(Error::NoZvol(target.to_owned()))
+    }
+
+    fn snapshot(&mut self, name: String) -> Result<()> {
+        run(&self.zfs, &["snapshot", &name])?;
+        self.snapshots.push(name);
+        Ok(())
+    }
+}
+
+impl Prepare for ZfsSnapshot {
+    fn prepare(&mut self, target: &Path) -> Result<PathBuf> {
+        let dataset = self.dataset(target)?;
+        self.stamp = Utc::now().format("%Y%m%dT%H%M%S").to_string();
+        self.snapshot(format!(
+            "{}@backy-extract-before-{}-{}",
+            dataset, self.label, self.stamp
+        ))?;
+        self.dataset = Some(dataset);
+        Ok(target.to_owned())
+    }
+
+    fn finish(&mut self, success: bool) -> Result<()> {
+        match &self.dataset {
+            Some(dataset) if success && self.after => {
+                let name = format!("{}@backy-extract-{}-{}", dataset, self.label, self.stamp);
+                self.snapshot(name)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use tempdir::TempDir;
+
+    // Fake `zfs` which logs its arguments and fails for snapshots of `pool/broken`
+    fn setup() -> (TempDir, ZfsSnapshot) {
+        let tmp = TempDir::new("zfs").unwrap();
+        let p = tmp.path();
+        fs::create_dir_all(p.join("zvol/pool/vms")).unwrap();
+        for (dev, vol) in &[("zd0", "pool/vms/vm0"), ("zd16", "pool/broken")] {
+            fs::write(p.join(dev), b"").unwrap();
+            symlink(p.join(dev), p.join("zvol").join(vol)).unwrap();
+        }
+        fs::write(p.join("zd16p1"), b"").unwrap();
+        let zfs = p.join("zfs");
+        fs::write(
+            &zfs,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {}/log\n\
+                 case \"$2\" in pool/broken@*) echo 'out of space' >&2; exit 1;; esac\n",
+                p.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&zfs, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut snap = ZfsSnapshot::new("VNzWKjnMqd6w58nzJwUZ98").command(zfs);
+        snap.zvol_dir = p.join("zvol");
+        (tmp, snap)
+    }
+
+    #[test]
+    fn find_dataset() {
+        let (tmp, snap) = setup();
+        let p = tmp.path();
+        assert_eq!(snap.dataset(&p.join("zd0")).unwrap(), "pool/vms/vm0");
+        assert_eq!(
+            snap.dataset(&p.join("zvol/pool/vms/vm0")).unwrap(),
+            "pool/vms/vm0"
+        );
+        assert!(matches!(
+            snap.dataset(&p.join("zd16p1")),
+            Err(Error::NoZvol(_))
+        ));
+        assert!(matches!(
+            snap.dataset(&p.join("log")),
+            Err(Error::Lookup(..))
+        ));
+    }
+
+    #[test]
+    fn snapshots_before_and_after() {
+        let (tmp, snap) = setup();
+        let target = tmp.path().join("zd0");
+        let mut snap = snap.after(true);
+        assert_eq!(snap.prepare(&target).unwrap(), target);
+        snap.finish(true).unwrap();
+        let log = fs::read_to_string(tmp.path().join("log")).unwrap();
+        let names: Vec<&str> = log.lines().collect();
+        assert_eq!(names.len(), 2);
+        assert!(names[0]
+            .starts_with("snapshot pool/vms/vm0@backy-extract-before-VNzWKjnMqd6w58nzJwUZ98-"));
+        assert!(names[1].starts_with("snapshot pool/vms/vm0@backy-extract-VNzWKjnMqd6w58nzJwUZ98-"));
+        assert_eq!(snap.snapshots().len(), 2);
+        // no second snapshot after failed restores
+        let (tmp, snap) = setup();
+        let mut snap = snap.after(true);
+        snap.prepare(&tmp.path().join("zd0")).unwrap();
+        snap.finish(false).unwrap();
+        assert_eq!(snap.snapshots().len(), 1);
+    }
+
+    #[test]
+    fn failing_zfs_command() {
+        let (tmp, mut snap) = setup();
+        match snap.prepare(&tmp.path().join("zd16")) {
+            Err(Error::Command(cmd, msg)) => {
+                assert!(cmd.contains("snapshot pool/broken@"));
+                assert_eq!(msg, "out of space");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
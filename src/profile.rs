@@ -0,0 +1,123 @@
+//! Curated restore settings for common environments.
+
+use crate::{Concurrency, Limits, CHUNKSZ};
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Bundle of thread counts, queue sizes, page cache and sparse policy.
+///
+/// Profiles are a starting point: settings made explicitly afterwards take precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Chunk store and target on fast local storage. Decompression threads read chunks
+    /// themselves and the target is written completely, zeros included.
+    LocalNvme,
+    /// Chunk store on a network file system. Many reader threads hide the latency of single
+    /// reads.
+    NfsStore,
+    /// Small hosts or containers with tight memory limits. Few threads and short queues keep
+    /// less than 64 MiB of chunk data in flight.
+    LowMemory,
+}
+
+/// All profiles in the order presented to users.
+pub const PROFILES: [Profile; 3] = [Profile::LocalNvme, Profile::NfsStore, Profile::LowMemory];
+
+#[derive(Error, Debug)]
+#[error("Unknown profile '{0}' (expected local-nvme, nfs-store or low-memory)")]
+pub struct ParseProfileError(String);
+
+impl Profile {
+    /// Threads per stage and queue depths. Decompression threads are left to heuristics unless
+    /// memory is tight.
+    pub fn concurrency(self) -> Concurrency {
+        match self {
+            Profile::LocalNvme => Concurrency::default(),
+            Profile::NfsStore => Concurrency {
+                readers: 16,
+                read_queue: 64,
+                ..Concurrency::default()
+            },
+            Profile::LowMemory => Concurrency {
+                decompressors: 2,
+                read_queue: 2,
+                write_queue: 2,
+                ..Concurrency::default()
+            },
+        }
+    }
+
+    pub fn limits(self) -> Limits {
+        match self {
+            Profile::LowMemory => Limits {
+                memory: Some(16 * CHUNKSZ),
+                ..Limits::default()
+            },
+            _ => Limits::default(),
+        }
+    }
+
+    /// Whether chunk files are dropped from the page cache after reading. The NFS client cache
+    /// is kept since concurrent restores of related revisions often read the same chunks.
+    pub fn drop_cache(self) -> bool {
+        !matches!(self, Profile::NfsStore)
+    }
+
+    /// Sparse mode for file and block device targets, None for auto-detection.
+    pub fn sparse(self) -> Option<bool> {
+        match self {
+            // writing zeros is cheap and guarantees that no stale data survives
+            Profile::LocalNvme => Some(false),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = ParseProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PROFILES
+            .iter()
+            .copied()
+            .find(|p| p.to_string() == s)
+            .ok_or_else(|| ParseProfileError(s.to_owned()))
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Profile::LocalNvme => write!(f, "local-nvme"),
+            Profile::NfsStore => write!(f, "nfs-store"),
+            Profile::LowMemory => write!(f, "low-memory"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_profiles() {
+        for p in &PROFILES {
+            assert_eq!(p.to_string().parse::<Profile>().unwrap(), *p);
+        }
+        assert!("nfs".parse::<Profile>().is_err());
+        let p: Profile = serde_yaml::from_str("nfs-store").unwrap();
+        assert_eq!(p, Profile::NfsStore);
+    }
+
+    #[test]
+    fn low_memory_fits_limit() {
+        let p = Profile::LowMemory;
+        let plan = p.limits().plan(p.concurrency());
+        let in_flight = usize::from(plan.decompressors) + plan.read_queue + plan.write_queue;
+        assert!(in_flight * CHUNKSZ <= p.limits().memory.unwrap());
+    }
+}
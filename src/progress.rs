@@ -0,0 +1,486 @@
+//! Restore progress reporting.
+//!
+//! Writers report the number of bytes written through a [Progress](struct.Progress.html) handle.
+//! Additionally, they report each unique chunk once it has been written to all of its positions.
+//! For heavily deduplicated images both measures diverge: a single chunk may settle dozens of
+//! positions at once, so byte-based estimates tend to be too optimistic early on.
+//! Reports never block the writer: a slow consumer (e.g., a stalled terminal) sees coalesced
+//! updates instead of an ever-growing backlog of messages. The consuming side is a
+//! [ProgressSink](trait.ProgressSink.html) which is driven from the thread that started the
+//! restore.
+//!
+//! Progress bars and colored status messages need the `cli` feature. Without it, status
+//! messages are printed as plain text and nothing is shown while restoring unless a sink is set.
+//!
+
+#[cfg(feature = "cli")]
+use crate::metrics::Metrics;
+
+use crossbeam::channel::{bounded, Receiver, Sender};
+#[cfg(feature = "cli")]
+use indicatif::{ProgressBar, ProgressStyle};
+use std::fmt;
+#[cfg(feature = "cli")]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[cfg(feature = "cli")]
+pub(crate) use console::style;
+
+/// Consumer of restore progress updates.
+///
+/// A sink sees exactly one `start` call, any number of `advance` calls and one `finish` call per
+/// restore. While the sink is busy, bytes written in the meantime are summed up and passed to the
+/// next `advance` call, so the number of calls is not related to the number of chunks. The sum of
+/// all `advance` arguments equals the number of bytes written. Unique chunks are reported likewise
+/// through `chunks_total` and `advance_chunks`. The latter is called before `advance` if both
+/// have progressed in the meantime.
+///
+/// Sinks are called from the restoring thread only but must be `Send + Sync` because they are
+/// owned by an [Extractor](struct.Extractor.html).
+pub trait ProgressSink: Send + Sync {
+    /// Restore of `total` bytes is about to begin.
+    fn start(&self, _total: u64) {}
+
+    /// The restore covers `total` unique chunks (including the zero chunk). Called right after
+    /// `start` if the number is known in advance.
+    fn chunks_total(&self, _total: u64) {}
+
+    /// `bytes` more have been written to the restore target.
+    fn advance(&self, bytes: u64);
+
+    /// `chunks` more unique chunks have been written to all of their positions.
+    fn advance_chunks(&self, _chunks: u64) {}
+
+    /// All writers have finished, successfully or not.
+    fn finish(&self) {}
+}
+
+impl fmt::Debug for dyn ProgressSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<ProgressSink>")
+    }
+}
+
+#[cfg(feature = "cli")]
+impl ProgressSink for ProgressBar {
+    fn start(&self, total: u64) {
+        self.set_length(total);
+        self.set_style(ProgressStyle::default_bar().template(
+            "{bytes:>9.yellow}/{total_bytes:.green} {bar:52.cyan/blue} ({elapsed}/{eta})",
+        ));
+        self.inc(0);
+        self.set_draw_delta(total / 1000);
+    }
+
+    fn advance(&self, bytes: u64) {
+        self.inc(bytes);
+    }
+
+    fn finish(&self) {
+        self.finish_and_clear();
+    }
+}
+
+/// What the restore progress bar shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BarStyle {
+    /// Bytes and unique chunks written
+    #[default]
+    Default,
+    /// Additionally page cache hit rate, read and write throughput and write queue fill level
+    Detailed,
+}
+
+/// Returns the number of queued items and the capacity of a queue.
+pub(crate) type QueueGauge = Box<dyn Fn() -> (usize, usize) + Send + Sync>;
+
+/// Watches the fill level of the channel `rx` belongs to.
+pub(crate) fn queue_gauge<T: Send + 'static>(rx: &Receiver<T>) -> QueueGauge {
+    let rx = rx.clone();
+    Box::new(move || (rx.len(), rx.capacity().unwrap_or(0)))
+}
+
+/// Progress bar which shows unique chunks next to the number of bytes.
+#[cfg(feature = "cli")]
+pub struct ChunkBar<'a> {
+    bar: &'a ProgressBar,
+    done: AtomicU64,
+    total: AtomicU64,
+    known: AtomicBool,
+    metrics: Option<Metrics>,
+}
+
+#[cfg(feature = "cli")]
+impl<'a> ChunkBar<'a> {
+    pub fn new(bar: &'a ProgressBar) -> Self {
+        Self {
+            bar,
+            done: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            known: AtomicBool::new(false),
+            metrics: None,
+        }
+    }
+
+    /// Like `new`, but with the figures of [BarStyle::Detailed](enum.BarStyle.html).
+    pub(crate) fn detailed(bar: &'a ProgressBar, queue: QueueGauge) -> Self {
+        Self {
+            metrics: Some(Metrics::new(queue)),
+            ..Self::new(bar)
+        }
+    }
+
+    fn update(&self) {
+        let done = self.done.load(Ordering::Acquire);
+        let mut msg = if self.known.load(Ordering::Acquire) {
+            let total = self.total.load(Ordering::Acquire);
+            format!("{}/{} chunks", done, total)
+        } else {
+            format!("{} chunks", done)
+        };
+        if let Some(m) = &self.metrics {
+            let line = m.line();
+            if !line.is_empty() {
+                msg = format!("{} | {}", msg, line);
+            }
+        }
+        self.bar.set_message(&msg);
+    }
+}
+
+#[cfg(feature = "cli")]
+impl ProgressSink for ChunkBar<'_> {
+    fn start(&self, total: u64) {
+        self.bar.set_length(total);
+        self.bar
+            .set_style(ProgressStyle::default_bar().template(match self.metrics {
+                Some(_) => "{bytes:>9.yellow}/{total_bytes:.green} {bar:20.cyan/blue} {msg:.cyan} ({elapsed}/{eta})",
+                None => "{bytes:>9.yellow}/{total_bytes:.green} {bar:40.cyan/blue} {msg:.cyan} ({elapsed}/{eta})",
+            }));
+        self.update();
+        self.bar.set_draw_delta(total / 1000);
+    }
+
+    fn chunks_total(&self, total: u64) {
+        self.total.store(total, Ordering::Release);
+        self.known.store(true, Ordering::Release);
+        self.update();
+    }
+
+    fn advance(&self, bytes: u64) {
+        self.bar.inc(bytes);
+        if self.metrics.is_some() {
+            self.update();
+        }
+    }
+
+    fn advance_chunks(&self, chunks: u64) {
+        self.done.fetch_add(chunks, Ordering::AcqRel);
+        self.update();
+    }
+
+    fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+// Sink which ignores everything.
+#[cfg(not(feature = "cli"))]
+struct Silent;
+
+#[cfg(not(feature = "cli"))]
+impl ProgressSink for Silent {
+    fn advance(&self, _bytes: u64) {}
+}
+
+/// Status messages and progress bar on stderr.
+#[derive(Debug, Clone)]
+pub(crate) struct Console {
+    #[cfg(feature = "cli")]
+    bar: ProgressBar,
+    #[cfg(not(feature = "cli"))]
+    show: bool,
+}
+
+#[cfg(feature = "cli")]
+impl Console {
+    /// Prints nothing at all unless `show` is set.
+    pub(crate) fn new(show: bool) -> Self {
+        let bar = if show {
+            ProgressBar::new(1)
+        } else {
+            ProgressBar::hidden()
+        };
+        Self { bar }
+    }
+
+    /// Prints a status line above the progress bar.
+    pub(crate) fn println(&self, msg: String) {
+        self.bar.println(msg);
+    }
+
+    /// Progress bar for bytes.
+    pub(crate) fn bar(&self) -> &dyn ProgressSink {
+        &self.bar
+    }
+
+    /// Progress bar for bytes and unique chunks. With `queue`, the bar shows the figures of
+    /// [BarStyle::Detailed](enum.BarStyle.html) for that queue.
+    pub(crate) fn chunk_bar(&self, queue: Option<QueueGauge>) -> Box<dyn ProgressSink + '_> {
+        match queue {
+            Some(q) => Box::new(ChunkBar::detailed(&self.bar, q)),
+            None => Box::new(ChunkBar::new(&self.bar)),
+        }
+    }
+}
+
+#[cfg(not(feature = "cli"))]
+impl Console {
+    pub(crate) fn new(show: bool) -> Self {
+        Self { show }
+    }
+
+    pub(crate) fn println(&self, msg: String) {
+        if self.show {
+            eprintln!("{}", msg);
+        }
+    }
+
+    pub(crate) fn bar(&self) -> &dyn ProgressSink {
+        &Silent
+    }
+
+    pub(crate) fn chunk_bar(&self, _queue: Option<QueueGauge>) -> Box<dyn ProgressSink + '_> {
+        Box::new(Silent)
+    }
+}
+
+/// Stand-in for `console::style` without the `cli` feature: text is not colored.
+#[cfg(not(feature = "cli"))]
+pub(crate) fn style<D>(text: D) -> Plain<D> {
+    Plain(text)
+}
+
+#[cfg(not(feature = "cli"))]
+pub(crate) struct Plain<D>(D);
+
+#[cfg(not(feature = "cli"))]
+impl<D> Plain<D> {
+    pub(crate) fn red(self) -> Self {
+        self
+    }
+
+    pub(crate) fn green(self) -> Self {
+        self
+    }
+
+    pub(crate) fn yellow(self) -> Self {
+        self
+    }
+
+    pub(crate) fn blue(self) -> Self {
+        self
+    }
+
+    pub(crate) fn cyan(self) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "cli"))]
+impl<D: fmt::Display> fmt::Display for Plain<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Formats a number of bytes with binary prefixes, e.g. `1.50MB` for 1.5 MiB. Same format as
+/// the progress bars use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HumanBytes(pub u64);
+
+impl fmt::Display for HumanBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const PREFIXES: &[char] = &['K', 'M', 'G', 'T', 'P', 'E'];
+        let mut n = self.0 as f64;
+        let mut prefix = None;
+        for p in PREFIXES {
+            if n < 1024.0 {
+                break;
+            }
+            n /= 1024.0;
+            prefix = Some(p);
+        }
+        match prefix {
+            Some(p) => write!(f, "{:.2}{}B", n, p),
+            None => write!(f, "{}B", self.0),
+        }
+    }
+}
+
+/// Reporting end of a progress channel, handed to writers.
+///
+/// Cloning yields another handle to the same channel. The channel is closed when all handles
+/// have been dropped.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pending: Arc<AtomicU64>,
+    chunks: Arc<AtomicU64>,
+    // Capacity 1: a queued tick means that the monitor has yet to collect `pending`.
+    tick: Sender<()>,
+}
+
+impl Progress {
+    /// Reports that `bytes` have been written. Never blocks.
+    pub fn add(&self, bytes: usize) {
+        self.pending.fetch_add(bytes as u64, Ordering::AcqRel);
+        // Full: the monitor will pick up our bytes with the tick already queued.
+        // Disconnected: nobody is interested anymore.
+        self.tick.try_send(()).ok();
+    }
+
+    /// Reports that `n` unique chunks have been written to all of their positions. The bytes
+    /// involved must be reported separately with [add](#method.add). Never blocks.
+    pub fn add_chunks(&self, n: usize) {
+        self.chunks.fetch_add(n as u64, Ordering::AcqRel);
+        self.tick.try_send(()).ok();
+    }
+}
+
+/// Consuming end of a progress channel.
+#[derive(Debug)]
+pub(crate) struct Monitor {
+    pending: Arc<AtomicU64>,
+    chunks: Arc<AtomicU64>,
+    tick: Receiver<()>,
+}
+
+impl Monitor {
+    /// Feeds `sink` until all `Progress` handles have been dropped. Returns the total number of
+    /// bytes and unique chunks reported.
+    pub(crate) fn run(
+        self,
+        sink: &dyn ProgressSink,
+        total_size: u64,
+        total_chunks: Option<u64>,
+    ) -> (u64, u64) {
+        sink.start(total_size);
+        if let Some(n) = total_chunks {
+            sink.chunks_total(n);
+        }
+        let mut total = (0, 0);
+        for () in &self.tick {
+            self.collect(sink, &mut total);
+        }
+        // progress added after the last tick has been consumed
+        self.collect(sink, &mut total);
+        sink.finish();
+        total
+    }
+
+    fn collect(&self, sink: &dyn ProgressSink, total: &mut (u64, u64)) {
+        let chunks = self.chunks.swap(0, Ordering::AcqRel);
+        if chunks > 0 {
+            sink.advance_chunks(chunks);
+            total.1 += chunks;
+        }
+        let bytes = self.pending.swap(0, Ordering::AcqRel);
+        if bytes > 0 {
+            sink.advance(bytes);
+            total.0 += bytes;
+        }
+    }
+}
+
+/// Creates a bounded, coalescing progress channel.
+pub(crate) fn channel() -> (Progress, Monitor) {
+    let pending = Arc::new(AtomicU64::new(0));
+    let chunks = Arc::new(AtomicU64::new(0));
+    let (tx, rx) = bounded(1);
+    (
+        Progress {
+            pending: Arc::clone(&pending),
+            chunks: Arc::clone(&chunks),
+            tick: tx,
+        },
+        Monitor {
+            pending,
+            chunks,
+            tick: rx,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<u64>>);
+
+    impl ProgressSink for Recorder {
+        fn advance(&self, bytes: u64) {
+            self.0.lock().unwrap().push(bytes);
+        }
+    }
+
+    #[test]
+    fn human_bytes() {
+        assert_eq!(HumanBytes(0).to_string(), "0B");
+        assert_eq!(HumanBytes(1023).to_string(), "1023B");
+        assert_eq!(HumanBytes(1536 << 10).to_string(), "1.50MB");
+        assert_eq!(HumanBytes(u64::MAX).to_string(), "16.00EB");
+    }
+
+    #[test]
+    fn coalesce_while_sink_is_busy() {
+        let (progress, monitor) = channel();
+        // nobody consumes yet: must neither block nor lose updates
+        for _ in 0..100_000 {
+            progress.add(10);
+        }
+        let p = progress.clone();
+        let t = thread::spawn(move || p.add(5));
+        t.join().unwrap();
+        drop(progress);
+        let rec = Recorder::default();
+        assert_eq!(monitor.run(&rec, 0, None), (1_000_005, 0));
+        let calls = rec.0.into_inner().unwrap();
+        assert_eq!(calls.iter().sum::<u64>(), 1_000_005);
+        assert!(calls.len() <= 2, "not coalesced: {:?}", calls);
+    }
+
+    #[test]
+    fn report_chunks_before_bytes() {
+        #[derive(Default)]
+        struct Events(Mutex<Vec<String>>);
+
+        impl ProgressSink for Events {
+            fn chunks_total(&self, total: u64) {
+                self.0.lock().unwrap().push(format!("total {}", total));
+            }
+            fn advance(&self, bytes: u64) {
+                self.0.lock().unwrap().push(format!("{} bytes", bytes));
+            }
+            fn advance_chunks(&self, chunks: u64) {
+                self.0.lock().unwrap().push(format!("{} chunks", chunks));
+            }
+        }
+
+        let (progress, monitor) = channel();
+        progress.add(300);
+        progress.add_chunks(1);
+        progress.add_chunks(1);
+        drop(progress);
+        let ev = Events::default();
+        assert_eq!(monitor.run(&ev, 300, Some(2)), (300, 2));
+        assert_eq!(
+            ev.0.into_inner().unwrap(),
+            vec!["total 2", "2 chunks", "300 bytes"]
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Snapshot of the set of chunks which the revisions in a backup directory reference.
+//!
+//! The snapshot is a text file with one line per chunk ID in ascending order, followed by the
+//! number of revisions which reference it:
+//!
+//! ```text
+//! # backy-extract referenced chunks v1
+//! # time: 2021-03-01T12:00:00.000Z
+//! # revision: VNzWKjnMqd6w58nzJwUZ98
+//! 4db6e194fd398e8edb76e11054d73eb0 1
+//! c72b4ba82d1f51b71c8a18195ad33fc8 1
+//! ```
+//!
+//! Comment lines at the top list the revisions the snapshot covers. A purge which consumes the
+//! snapshot must keep all chunks of revisions which are not listed there, e.g. of backups which
+//! have completed while the snapshot was taken.
+//!
+//! Maps are read one at a time and reduced to sorted runs in temporary files, which are merged in
+//! the end. Memory usage thus depends on the size of the largest revision, not on the size of the
+//! whole store.
+
+use crate::backend::{self, Rev};
+use crate::chunkvec::ChunkVec;
+use crate::{purgelock, ExtractError};
+
+use chrono::{SecondsFormat, Utc};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read backup dir '{}'", .0.display())]
+    ReadDir(PathBuf, #[source] io::Error),
+    #[error("Backup dir '{}' contains a damaged revision, refusing to guess which chunks it \
+             references", .0.display())]
+    Unreadable(PathBuf, #[source] backend::RevError),
+    #[error("Failed to read revision map '{}'", .0.display())]
+    ReadMap(PathBuf, #[source] io::Error),
+    #[error("Failed to parse revision map '{}'", .0.display())]
+    ParseMap(PathBuf, #[source] ExtractError),
+    #[error("Failed to acquire purge lock for backup dir '{}'", .0.display())]
+    Lock(PathBuf, #[source] io::Error),
+    #[error("Failed to write snapshot '{}'", .0.display())]
+    Write(PathBuf, #[source] io::Error),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+const HEADER: &str = "# backy-extract referenced chunks v1";
+
+// Number of runs merged at once. Limits the number of open files.
+const FAN_IN: usize = 64;
+
+/// Summary of a snapshot.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefStats {
+    pub revisions: usize,
+    /// Distinct chunks
+    pub chunks: usize,
+    /// Sum of all reference counts
+    pub references: u64,
+}
+
+// Unnamed temporary file holding a sorted run of "ID COUNT" lines.
+fn run_file() -> io::Result<File> {
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "backy-refs.{}.{}",
+        process::id(),
+        SEQ.fetch_add(1, Ordering::Relaxed)
+    ));
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(f)
+}
+
+// Merges sorted runs, adding up the counts of equal IDs. Returns the number of distinct IDs and
+// the sum of all counts written to `out`.
+fn merge<W: Write>(runs: Vec<File>, mut out: W) -> io::Result<(usize, u64)> {
+    let mut readers = Vec::with_capacity(runs.len());
+    let mut heap = BinaryHeap::new();
+    for (i, mut f) in runs.into_iter().enumerate() {
+        f.seek(SeekFrom::Start(0))?;
+        let mut lines = BufReader::new(f).lines();
+        if let Some(entry) = next_entry(&mut lines)? {
+            heap.push(Reverse((entry, i)));
+        }
+        readers.push(lines);
+    }
+    let (mut chunks, mut references) = (0, 0);
+    while let Some(Reverse(((id, mut count), i))) = heap.pop() {
+        if let Some(entry) = next_entry(&mut readers[i])? {
+            heap.push(Reverse((entry, i)));
+        }
+        while let Some(Reverse(((next, _), _))) = heap.peek() {
+            if *next != id {
+                break;
+            }
+            let Reverse(((_, c), j)) = heap.pop().unwrap();
+            count += c;
+            if let Some(entry) = next_entry(&mut readers[j])? {
+                heap.push(Reverse((entry, j)));
+            }
+        }
+        writeln!(out, "{} {}", id, count)?;
+        chunks += 1;
+        references += count;
+    }
+    out.flush()?;
+    Ok((chunks, references))
+}
+
+fn next_entry<B: BufRead>(lines: &mut io::Lines<B>) -> io::Result<Option<(String, u64)>> {
+    let line = match lines.next() {
+        Some(l) => l?,
+        None => return Ok(None),
+    };
+    let mut fields = line.split(' ');
+    match (fields.next(), fields.next().and_then(|c| c.parse().ok())) {
+        (Some(id), Some(count)) => Ok(Some((id.to_owned(), count))),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, line)),
+    }
+}
+
+/// Writes the snapshot of all chunks referenced by the revisions in `dir` to `out`.
+///
+/// Fails if any `.rev` file or revision map cannot be read, since a snapshot which misses chunks
+/// would make a purge delete data which is still needed. The purge lock is held while maps are
+/// read.
+pub fn snapshot<P: AsRef<Path>, W: Write>(dir: P, mut out: W) -> Result<RefStats> {
+    let dir = dir.as_ref();
+    let _lock = purgelock(dir).map_err(|e| Error::Lock(dir.to_owned(), e))?;
+    let mut stats = RefStats::default();
+    let mut uuids = Vec::new();
+    let mut runs = Vec::new();
+    for rev in Rev::discover(dir).map_err(|e| Error::ReadDir(dir.to_owned(), e))? {
+        let rev = rev.map_err(|e| Error::Unreadable(dir.to_owned(), e))?;
+        let path = dir.join(rev.uuid.as_str());
+        let json = backend::map_file(&path).map_err(|e| Error::ReadMap(path.clone(), e))?;
+        let map = ChunkVec::from_slice(&json).map_err(|e| Error::ParseMap(path.clone(), e))?;
+        let mut ids: Vec<_> = map.ids().collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let mut w = BufWriter::new(run_file()?);
+        for id in ids {
+            writeln!(w, "{} 1", id)?;
+        }
+        runs.push(w.into_inner().map_err(|e| e.into_error())?);
+        if runs.len() == FAN_IN {
+            let mut merged = BufWriter::new(run_file()?);
+            merge(runs.split_off(0), &mut merged)?;
+            runs.push(merged.into_inner().map_err(|e| e.into_error())?);
+        }
+        uuids.push(rev.uuid.to_string());
+        stats.revisions += 1;
+    }
+    writeln!(out, "{}", HEADER)?;
+    writeln!(
+        out,
+        "# time: {}",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+    )?;
+    for uuid in &uuids {
+        writeln!(out, "# revision: {}", uuid)?;
+    }
+    let (chunks, references) = merge(runs, out)?;
+    stats.chunks = chunks;
+    stats.references = references;
+    Ok(stats)
+}
+
+/// Like [snapshot](fn.snapshot.html), but writes to the file `path`. The file is replaced
+/// atomically, so readers see either the previous or the complete new snapshot.
+pub fn write_snapshot<P: AsRef<Path>, Q: AsRef<Path>>(dir: P, path: Q) -> Result<RefStats> {
+    let path = path.as_ref();
+    let tmp = path.with_extension("tmp");
+    let err = |e| Error::Write(path.to_owned(), e);
+    let mut w = BufWriter::new(File::create(&tmp).map_err(err)?);
+    let res = snapshot(dir, &mut w).and_then(|stats| {
+        let f = w.into_inner().map_err(|e| err(e.into_error()))?;
+        f.sync_all().map_err(err)?;
+        fs::rename(&tmp, path).map_err(err)?;
+        Ok(stats)
+    });
+    if res.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Slot, StoreBuilder};
+    use tempdir::TempDir;
+
+    #[test]
+    fn snapshot_counts_references() {
+        let tmp = TempDir::new("refs").unwrap();
+        let first = testing::layout(&[Slot::Data(1), Slot::Hole, Slot::Data(2), Slot::Data(1)]);
+        let second = testing::layout(&[Slot::Data(1), Slot::Data(3)]);
+        StoreBuilder::new()
+            .revision("Rev1zzzzzzzzzzzzzzzzzz", first)
+            .revision("Rev2zzzzzzzzzzzzzzzzzz", second)
+            .write(tmp.path())
+            .unwrap();
+        let mut out = Vec::new();
+        let stats = snapshot(tmp.path(), &mut out).unwrap();
+        assert_eq!(
+            stats,
+            RefStats {
+                revisions: 2,
+                chunks: 3,
+                references: 4
+            }
+        );
+        let out = String::from_utf8(out).unwrap();
+        let mut expected: Vec<_> = [(1, 2), (2, 1), (3, 1)]
+            .iter()
+            .map(|(seed, n)| format!("{} {}", testing::chunk_id(&testing::pattern(*seed)), n))
+            .collect();
+        expected.sort();
+        let body: Vec<_> = out.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(body, expected);
+        assert!(out.contains("# revision: Rev2zzzzzzzzzzzzzzzzzz\n"));
+    }
+
+    #[test]
+    fn merge_many_runs() {
+        let mut runs = Vec::new();
+        for i in 0..(FAN_IN * 2 + 1) {
+            let mut f = run_file().unwrap();
+            writeln!(f, "aa 1\nb{} 2", i % 3).unwrap();
+            runs.push(f);
+        }
+        let mut out = Vec::new();
+        assert_eq!(merge(runs, &mut out).unwrap(), (4, 129 * 3));
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "aa 129\nb0 86\nb1 86\nb2 86\n"
+        );
+    }
+
+    #[test]
+    fn damaged_revision_fails() {
+        let tmp = TempDir::new("refs").unwrap();
+        StoreBuilder::new()
+            .revision("Rev1zzzzzzzzzzzzzzzzzz", testing::layout(&[Slot::Data(1)]))
+            .write(tmp.path())
+            .unwrap();
+        fs::write(tmp.path().join("Rev1zzzzzzzzzzzzzzzzzz"), "{").unwrap();
+        let dest = tmp.path().join("refs");
+        assert!(write_snapshot(tmp.path(), &dest).is_err());
+        assert!(!dest.exists());
+        assert!(!dest.with_extension("tmp").exists());
+    }
+}
@@ -0,0 +1,1008 @@
+//! Restores over a pipe or network connection.
+//!
+//! [send](fn.send.html) serializes a revision into a self-contained stream which
+//! [Receiver](struct.Receiver.html) writes to any restore target, e.g.
+//!
+//! ```text
+//! backy-extract send REV | ssh host backy-extract receive /dev/vg/lv
+//! ```
+//!
+//! Chunks travel in their compressed on-disk form and are decompressed on the receiving side, so
+//! the sender does not spend any CPU time on them. Receivers which do not accept LZO data (see
+//! [Encoding](enum.Encoding.html)) get chunks decompressed by the sender instead. Since chunk
+//! IDs are content hashes, the receiver verifies every chunk before writing it. Unmapped
+//! regions are transferred as zero runs.
+//!
+//! For repeated restores to the same target, the receiver first hashes what is already there
+//! and sends an [Advertisement](struct.Advertisement.html) back. The sender then transmits only
+//! chunks which are missing on the receiving side (see [send_delta](fn.send_delta.html)). The
+//! advertisement also lists the chunk encodings the receiver accepts.
+//!
+//! # Wire format
+//!
+//! Integer, `str` and `blob` encoding as in the [archive](../archive/index.html) format.
+//!
+//! ```text
+//! header:  "BKYSEND\0" version:u8 size:u64
+//! records: (0x01 id:str nseqs:u32 seq:u32* data:blob     chunk data
+//!          | 0x02 first:u32 count:u32                     zero run
+//!          | 0x03 id:str src:u32 nseqs:u32 seq:u32*       copy of a kept chunk (v2)
+//!          | 0x04 first:u32 count:u32                     run of kept chunks (v2)
+//!          | 0x05 id:str nseqs:u32 seq:u32* data:blob)*   uncompressed chunk data (v3)
+//! end:     0x00 nrecords:u32
+//!
+//! advertisement: "BKYHAVE\0" version:u8 count:u32 id:str* nenc:u8 encoding:str* (v3)
+//! ```
+//!
+//! Streams without uncompressed chunks are written as version 2 so that older receivers can
+//! read them. Advertisements before version 3 lack the encodings, which means LZO only. For the
+//! same reason, advertisements which accept nothing but LZO are written as version 2.
+//!
+//! Kept chunks are already correct on the receiving side and are left alone. Records are sent
+//! in order of their lowest seq, so streaming targets need to buffer only duplicates.
+
+use crate::backend::{self, Backend, ZERO_HASH};
+use crate::chunkvec::ChunkVec;
+use crate::framing::{read_blob, read_str, write_blob, write_str};
+use crate::progress::Console;
+use crate::writeout::{self, WriteOut, WriteOutBuilder, WriteReport};
+use crate::{
+    chunk2pos, is_zero, progress, purgelock, Chunk, Codec, Data, ExtractError, Extractor,
+    RandomAccess, CHUNKSZ, CHUNKSZ_LOG,
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crossbeam::channel::{bounded, Sender};
+use crossbeam::thread;
+use smallvec::SmallVec;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Instant;
+use thiserror::Error;
+
+const MAGIC: &[u8; 8] = b"BKYSEND\0";
+const ADV_MAGIC: &[u8; 8] = b"BKYHAVE\0";
+const VERSION: u8 = 3;
+const TAG_END: u8 = 0;
+const TAG_CHUNK: u8 = 1;
+const TAG_ZEROS: u8 = 2;
+const TAG_REF: u8 = 3;
+const TAG_KEEP: u8 = 4;
+const TAG_PLAIN: u8 = 5;
+// Incompressible chunks grow slightly when LZO compressed
+const MAX_CHUNK: usize = CHUNKSZ + CHUNKSZ / 16 + 1024;
+// Maximum number of seqs per zero chunk passed to the writer
+const ZERO_BATCH: usize = 1024;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Input is not a backy-extract send stream or advertisement")]
+    Magic,
+    #[error("Unsupported send stream version {0}")]
+    Version(u8),
+    #[error("Receiver accepts none of the chunk encodings this sender supports")]
+    NoEncoding,
+    #[error("Unknown chunk encoding '{0}' (expected lzo or plain)")]
+    Encoding(String),
+    #[error("Malformed send stream: {0}")]
+    Format(String),
+    #[error("Send stream ended before chunk #{0} has been transferred")]
+    Incomplete(u32),
+    #[error("Checksum mismatch in chunk #{seq} ({id}): content hashes to {actual}")]
+    Checksum {
+        seq: u32,
+        id: String,
+        actual: String,
+    },
+    #[error("Failed to load revision '{}'", .0.display())]
+    Revision(PathBuf, #[source] io::Error),
+    #[error("Failed to parse revision map")]
+    Map(#[source] ExtractError),
+    #[error("Failed to acquire purge lock for backup dir '{}'", .0.display())]
+    Lock(PathBuf, #[source] io::Error),
+    #[error("Error while loading chunk #{seq} ({id})")]
+    InvalidChunk {
+        seq: u32,
+        id: String,
+        source: backend::Error,
+    },
+    #[error("Chunked backend error")]
+    Backend(#[from] backend::Error),
+    #[error("Write error")]
+    Write(#[from] writeout::Error),
+    #[error("IPC error")]
+    Ipc,
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Summary of a send or receive run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    /// Distinct chunks transferred
+    pub chunks: usize,
+    pub zero_runs: usize,
+    /// Chunk bytes transferred, compressed or not
+    pub bytes: u64,
+    /// Chunks which have been decompressed by the sender
+    pub plain: usize,
+    /// Chunks which have been copied from elsewhere in the target instead of being transferred
+    pub copied: usize,
+    /// Chunks left untouched because the target already contained them
+    pub kept: usize,
+}
+
+/// Form in which chunk data is transferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// LZO compressed as stored in the backup directory, decompressed by the receiver
+    Lzo,
+    /// Decompressed by the sender, e.g. for receivers which are short of CPU time
+    Plain,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Lzo => write!(f, "lzo"),
+            Encoding::Plain => write!(f, "plain"),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lzo" => Ok(Encoding::Lzo),
+            "plain" => Ok(Encoding::Plain),
+            _ => Err(Error::Encoding(s.to_owned())),
+        }
+    }
+}
+
+/// Contents of a restore target as seen by the receiver: the chunk ID found at each seq.
+///
+/// Only complete chunks are listed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advertisement {
+    pub ids: Vec<String>,
+    /// Chunk encodings the receiver accepts, preferred one first
+    pub encodings: Vec<Encoding>,
+}
+
+impl Default for Advertisement {
+    fn default() -> Self {
+        Self::accepting(&[Encoding::Lzo, Encoding::Plain])
+    }
+}
+
+impl Advertisement {
+    /// Advertises an empty target which accepts `encodings`, e.g. to make the sender decompress
+    /// chunks when there is no way to get an advertisement from the receiver.
+    pub fn accepting(encodings: &[Encoding]) -> Self {
+        Self {
+            ids: Vec::new(),
+            encodings: encodings.to_vec(),
+        }
+    }
+
+    /// Hashes all chunks of an existing file or block device.
+    pub fn scan<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut f = File::open(path)?;
+        let n = f.seek(SeekFrom::End(0))? >> CHUNKSZ_LOG;
+        let mut buf = vec![0; CHUNKSZ];
+        let mut ids = Vec::with_capacity(n as usize);
+        for seq in 0..n {
+            f.read_exact_at(&mut buf, seq << CHUNKSZ_LOG)?;
+            ids.push(if is_zero(&buf) {
+                ZERO_HASH.to_owned()
+            } else {
+                backend::hash(&buf)
+            });
+        }
+        Ok(Self {
+            ids,
+            ..Self::default()
+        })
+    }
+
+    fn get(&self, seq: u32) -> Option<&str> {
+        self.ids.get(seq as usize).map(|id| id.as_str())
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        // older senders understand LZO-only advertisements in version 2
+        let lzo_only = self.encodings == [Encoding::Lzo];
+        w.write_all(ADV_MAGIC)?;
+        w.write_u8(if lzo_only { 2 } else { VERSION })?;
+        w.write_u32::<BigEndian>(self.ids.len() as u32)?;
+        for id in &self.ids {
+            write_str(&mut w, id)?;
+        }
+        if lzo_only {
+            return w.flush();
+        }
+        w.write_u8(self.encodings.len() as u8)?;
+        for enc in &self.encodings {
+            write_str(&mut w, &enc.to_string())?;
+        }
+        w.flush()
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != ADV_MAGIC {
+            return Err(Error::Magic);
+        }
+        // advertisements have been introduced with version 2
+        let version = match r.read_u8()? {
+            v @ 2..=VERSION => v,
+            v => return Err(Error::Version(v)),
+        };
+        let n = r.read_u32::<BigEndian>()?;
+        let mut ids = Vec::with_capacity(n.min(1 << 20) as usize);
+        for _ in 0..n {
+            ids.push(read_str(&mut r)?);
+        }
+        let mut encodings = vec![Encoding::Lzo];
+        if version >= 3 {
+            encodings.clear();
+            for _ in 0..r.read_u8()? {
+                // skip encodings introduced by later versions
+                if let Ok(enc) = read_str(&mut r)?.parse() {
+                    encodings.push(enc);
+                }
+            }
+        }
+        Ok(Self { ids, encodings })
+    }
+
+    // Chunk encoding to use for this receiver: the one it prefers. `read` has already dropped
+    // encodings this sender doesn't know.
+    fn encoding(&self) -> Result<Encoding> {
+        self.encodings.first().copied().ok_or(Error::NoEncoding)
+    }
+}
+
+enum Record<'a> {
+    Chunk(&'a str, Vec<u32>),
+    Zeros(u32, u32),
+    Ref(&'a str, u32, Vec<u32>),
+    Keep(u32, u32),
+}
+
+// Groups ascending seqs into (first, count) runs.
+fn runs(seqs: &[u32]) -> Vec<(u32, u32)> {
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for &seq in seqs {
+        match runs.last_mut() {
+            Some((first, count)) if *first + *count == seq => *count += 1,
+            _ => runs.push((seq, 1)),
+        }
+    }
+    runs
+}
+
+// Merges chunks and zero runs into a single list ordered by lowest seq. Seqs at which the
+// receiver already has the right contents are kept, and chunks found at kept seqs are copied
+// from there. References are ordered by their source so that they follow the kept run.
+fn records<'a>(chunks: &'a ChunkVec, have: &Advertisement) -> Vec<(u32, Record<'a>)> {
+    let mut rec: Vec<(u32, Record)> = Vec::new();
+    for (id, seqs) in chunks.ordered() {
+        let (kept, rest): (Vec<u32>, Vec<u32>) = seqs
+            .iter()
+            .partition(|&&s| have.get(s) == Some(id.as_str()));
+        rec.extend(
+            runs(&kept)
+                .into_iter()
+                .map(|(f, c)| (f, Record::Keep(f, c))),
+        );
+        match (kept.first(), rest.first()) {
+            (_, None) => (),
+            (None, Some(&first)) => rec.push((first, Record::Chunk(id.as_str(), rest))),
+            (Some(&src), Some(_)) => rec.push((src, Record::Ref(id.as_str(), src, rest))),
+        }
+    }
+    let (kept, rest): (Vec<u32>, Vec<u32>) = chunks
+        .zero_seqs()
+        .iter()
+        .partition(|&&s| have.get(s) == Some(ZERO_HASH));
+    rec.extend(
+        runs(&kept)
+            .into_iter()
+            .map(|(f, c)| (f, Record::Keep(f, c))),
+    );
+    rec.extend(
+        runs(&rest)
+            .into_iter()
+            .map(|(f, c)| (f, Record::Zeros(f, c))),
+    );
+    // stable sort keeps runs of kept chunks in front of their references
+    rec.sort_by_key(|r| r.0);
+    rec
+}
+
+fn write_seqs<W: Write>(out: &mut W, seqs: &[u32]) -> io::Result<()> {
+    out.write_u32::<BigEndian>(seqs.len() as u32)?;
+    for &seq in seqs {
+        out.write_u32::<BigEndian>(seq)?;
+    }
+    Ok(())
+}
+
+/// Writes revision `revfile` (a revision map path as passed to `Extractor::init`) as send
+/// stream to `out`.
+pub fn send<P: AsRef<Path>, W: Write>(revfile: P, out: W) -> Result<TransferStats> {
+    send_delta(revfile, &Advertisement::default(), out)
+}
+
+/// Like [send](fn.send.html), but omits everything the receiver already has according to
+/// `have`. The resulting stream must be received with
+/// [Receiver::receive_delta](struct.Receiver.html#method.receive_delta) into the target `have`
+/// has been created from.
+pub fn send_delta<P: AsRef<Path>, W: Write>(
+    revfile: P,
+    have: &Advertisement,
+    mut out: W,
+) -> Result<TransferStats> {
+    let revfile = revfile.as_ref();
+    let basedir = revfile
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .to_path_buf();
+    let _lock = purgelock(&basedir).map_err(|e| Error::Lock(basedir.clone(), e))?;
+    let be = Backend::open(&basedir)?;
+    let json = backend::map_file(revfile).map_err(|e| Error::Revision(revfile.to_owned(), e))?;
+    let chunks = ChunkVec::from_slice(&json).map_err(Error::Map)?;
+    let encoding = have.encoding()?;
+
+    out.write_all(MAGIC)?;
+    out.write_u8(match encoding {
+        Encoding::Lzo => 2,
+        Encoding::Plain => VERSION,
+    })?;
+    out.write_u64::<BigEndian>(chunks.size)?;
+    let mut stats = TransferStats::default();
+    let records = records(&chunks, have);
+    for (_, rec) in &records {
+        match rec {
+            Record::Chunk(id, seqs) => {
+                let invalid = |e| Error::InvalidChunk {
+                    seq: seqs[0],
+                    id: (*id).to_owned(),
+                    source: e,
+                };
+                let data = match encoding {
+                    Encoding::Lzo => {
+                        out.write_u8(TAG_CHUNK)?;
+                        fs::read(be.filename(id)).map_err(|e| invalid(e.into()))?
+                    }
+                    Encoding::Plain => {
+                        out.write_u8(TAG_PLAIN)?;
+                        stats.plain += 1;
+                        be.load(id).map_err(invalid)?
+                    }
+                };
+                write_str(&mut out, id)?;
+                write_seqs(&mut out, seqs)?;
+                write_blob(&mut out, &data)?;
+                stats.chunks += 1;
+                stats.bytes += data.len() as u64;
+            }
+            Record::Zeros(first, count) => {
+                out.write_u8(TAG_ZEROS)?;
+                out.write_u32::<BigEndian>(*first)?;
+                out.write_u32::<BigEndian>(*count)?;
+                stats.zero_runs += 1;
+            }
+            Record::Ref(id, src, seqs) => {
+                out.write_u8(TAG_REF)?;
+                write_str(&mut out, id)?;
+                out.write_u32::<BigEndian>(*src)?;
+                write_seqs(&mut out, seqs)?;
+                stats.copied += 1;
+            }
+            Record::Keep(first, count) => {
+                out.write_u8(TAG_KEEP)?;
+                out.write_u32::<BigEndian>(*first)?;
+                out.write_u32::<BigEndian>(*count)?;
+                stats.kept += *count as usize;
+            }
+        }
+    }
+    out.write_u8(TAG_END)?;
+    out.write_u32::<BigEndian>(records.len() as u32)?;
+    out.flush()?;
+    Ok(stats)
+}
+
+type Compressed = (String, SmallVec<[u32; 4]>, Encoding, Vec<u8>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Seen {
+    Written,
+    Kept,
+}
+
+// Tracks which seqs have been transferred to detect duplicates and gaps. Transferred seqs are
+// kept as runs, keyed by their first seq, so that memory usage depends on the stream contents
+// and not on the image size announced in the header.
+struct Coverage {
+    nchunks: u32,
+    // first seq -> (end seq, exclusive, and how it has been transferred)
+    runs: BTreeMap<u32, (u32, Seen)>,
+}
+
+impl Coverage {
+    fn new(nchunks: u32) -> Self {
+        Self {
+            nchunks,
+            runs: BTreeMap::new(),
+        }
+    }
+
+    // Marks `count` seqs starting at `first`.
+    fn mark_run(&mut self, first: u32, count: u32, how: Seen) -> Result<()> {
+        let end = match first.checked_add(count) {
+            Some(end) if end <= self.nchunks => end,
+            _ => {
+                let seq = first.max(self.nchunks);
+                return Err(Error::Format(format!("seq {} out of range", seq)));
+            }
+        };
+        if count == 0 {
+            return Ok(());
+        }
+        let mut start = first;
+        if let Some((&s, &(e, _))) = self.runs.range(..end).next_back() {
+            if e > first {
+                let seq = first.max(s);
+                return Err(Error::Format(format!("seq {} transferred twice", seq)));
+            }
+        }
+        // coalesce with adjacent runs of the same kind
+        if let Some((&s, &(e, h))) = self.runs.range(..first).next_back() {
+            if e == first && h == how {
+                self.runs.remove(&s);
+                start = s;
+            }
+        }
+        let mut end = end;
+        if let Some(&(e, h)) = self.runs.get(&end) {
+            if h == how {
+                self.runs.remove(&end);
+                end = e;
+            }
+        }
+        self.runs.insert(start, (end, how));
+        Ok(())
+    }
+
+    fn mark(&mut self, seq: u32, how: Seen) -> Result<()> {
+        self.mark_run(seq, 1, how)
+    }
+
+    fn is_kept(&self, seq: u32) -> bool {
+        matches!(
+            self.runs.range(..=seq).next_back(),
+            Some((_, &(end, Seen::Kept))) if end > seq
+        )
+    }
+
+    fn first_missing(&self) -> Option<u32> {
+        let mut next = 0;
+        for (&start, &(end, _)) in &self.runs {
+            if start > next {
+                return Some(next);
+            }
+            next = end;
+        }
+        Some(next).filter(|&n| n < self.nchunks)
+    }
+}
+
+fn read_seqs<R: Read>(input: &mut R, cov: &mut Coverage) -> Result<SmallVec<[u32; 4]>> {
+    let n = input.read_u32::<BigEndian>()?;
+    if n == 0 || n > cov.nchunks {
+        return Err(Error::Format(format!("invalid seq count {}", n)));
+    }
+    let mut seqs = SmallVec::new();
+    for _ in 0..n {
+        let seq = input.read_u32::<BigEndian>()?;
+        cov.mark(seq, Seen::Written)?;
+        seqs.push(seq);
+    }
+    Ok(seqs)
+}
+
+// Reads a chunk that the receiver keeps from the target and checks that it is still there.
+fn copy_kept(target: &File, id: String, src: u32) -> Result<Vec<u8>> {
+    let mut data = vec![0; CHUNKSZ];
+    target.read_exact_at(&mut data, chunk2pos(src))?;
+    let actual = backend::hash(&data);
+    if actual != id {
+        return Err(Error::Checksum {
+            seq: src,
+            id,
+            actual,
+        });
+    }
+    Ok(data)
+}
+
+/// Receiving end of a send stream.
+#[derive(Debug)]
+pub struct Receiver<R> {
+    input: R,
+    threads: u8,
+    codec: Codec,
+    progress: Console,
+    // read access to the restore target for delta transfers
+    target: Option<File>,
+}
+
+impl<R: Read + Send> Receiver<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            threads: Extractor::default_threads(),
+            codec: Codec::default(),
+            progress: Console::new(false),
+            target: None,
+        }
+    }
+
+    /// Sets number of decompression threads.
+    pub fn threads(mut self, n: u8) -> Self {
+        if n > 0 {
+            self.threads = n;
+        }
+        self
+    }
+
+    /// Selects the LZO implementation used for decompression.
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Enables/disables a progress bar on stderr.
+    pub fn progress(mut self, show: bool) -> Self {
+        self.progress = Console::new(show);
+        self
+    }
+
+    fn header(&mut self) -> Result<u64> {
+        let mut magic = [0; 8];
+        self.input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Magic);
+        }
+        match self.input.read_u8()? {
+            1..=VERSION => (),
+            v => return Err(Error::Version(v)),
+        }
+        let size = self.input.read_u64::<BigEndian>()?;
+        if size % CHUNKSZ as u64 != 0 || size >> CHUNKSZ_LOG > u64::from(u32::MAX) {
+            return Err(Error::Format(format!("invalid image size {}", size)));
+        }
+        Ok(size)
+    }
+
+    // Parses records and dispatches them: compressed chunks go to the decoders, zero runs and
+    // copies of kept chunks directly to the writer.
+    fn read_records(
+        input: &mut R,
+        nchunks: u32,
+        target: Option<&File>,
+        dec: Sender<Compressed>,
+        out: Sender<Chunk>,
+        progress: progress::Progress,
+    ) -> Result<TransferStats> {
+        let mut stats = TransferStats::default();
+        let mut cov = Coverage::new(nchunks);
+        let mut n = 0;
+        loop {
+            match input.read_u8()? {
+                TAG_END => break,
+                tag @ (TAG_CHUNK | TAG_PLAIN) => {
+                    let id = read_str(input)?;
+                    let seqs = read_seqs(input, &mut cov)?;
+                    let data = read_blob(input, MAX_CHUNK)?;
+                    stats.chunks += 1;
+                    stats.bytes += data.len() as u64;
+                    let enc = if tag == TAG_PLAIN {
+                        stats.plain += 1;
+                        Encoding::Plain
+                    } else {
+                        Encoding::Lzo
+                    };
+                    dec.send((id, seqs, enc, data)).map_err(|_| Error::Ipc)?;
+                }
+                TAG_ZEROS => {
+                    let first = input.read_u32::<BigEndian>()?;
+                    let count = input.read_u32::<BigEndian>()?;
+                    cov.mark_run(first, count, Seen::Written)?;
+                    stats.zero_runs += 1;
+                    // long runs go out in pieces to keep the seq lists small
+                    for start in (first..first + count).step_by(ZERO_BATCH) {
+                        let end = (first + count).min(start.saturating_add(ZERO_BATCH as u32));
+                        out.send(Chunk {
+                            data: Data::Zero,
+                            seqs: (start..end).collect(),
+                        })
+                        .map_err(|_| Error::Ipc)?;
+                    }
+                }
+                TAG_REF => {
+                    let id = read_str(input)?;
+                    let src = input.read_u32::<BigEndian>()?;
+                    let seqs = read_seqs(input, &mut cov)?;
+                    // only kept chunks are guaranteed not to be overwritten in the meantime
+                    let target = match target {
+                        Some(t) if cov.is_kept(src) => t,
+                        _ => return Err(Error::Format(format!("invalid reference to #{}", src))),
+                    };
+                    let data = copy_kept(target, id, src)?;
+                    stats.copied += 1;
+                    out.send(Chunk {
+                        data: Data::Some(data),
+                        seqs,
+                    })
+                    .map_err(|_| Error::Ipc)?;
+                }
+                TAG_KEEP if target.is_some() => {
+                    let first = input.read_u32::<BigEndian>()?;
+                    let count = input.read_u32::<BigEndian>()?;
+                    cov.mark_run(first, count, Seen::Kept)?;
+                    stats.kept += count as usize;
+                    progress.add((count as usize) << CHUNKSZ_LOG);
+                }
+                TAG_KEEP => return Err(Error::Format("delta stream requires a target".into())),
+                t => return Err(Error::Format(format!("unknown record type {}", t))),
+            }
+            n += 1;
+        }
+        if input.read_u32::<BigEndian>()? != n {
+            return Err(Error::Format("record count mismatch".into()));
+        }
+        match cov.first_missing() {
+            Some(seq) => Err(Error::Incomplete(seq)),
+            None => Ok(stats),
+        }
+    }
+
+    fn decode(
+        rx: crossbeam::channel::Receiver<Compressed>,
+        tx: Sender<Chunk>,
+        codec: Codec,
+    ) -> Result<()> {
+        for (id, seqs, enc, data) in rx {
+            let decompressed = match enc {
+                Encoding::Lzo => backend::decode(&data, codec),
+                Encoding::Plain => Ok(data),
+            };
+            let decompressed = decompressed
+                .and_then(|d| match d.len() {
+                    CHUNKSZ => Ok(d),
+                    n => Err(backend::Error::Missized(n)),
+                })
+                .map_err(|e| Error::InvalidChunk {
+                    seq: seqs[0],
+                    id: id.clone(),
+                    source: e,
+                })?;
+            let actual = backend::hash(&decompressed);
+            if actual != id {
+                return Err(Error::Checksum {
+                    seq: seqs[0],
+                    id,
+                    actual,
+                });
+            }
+            tx.send(Chunk {
+                data: Data::Some(decompressed),
+                seqs,
+            })
+            .map_err(|_| Error::Ipc)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the send stream and writes the image to `w`. Returns what has been transferred.
+    pub fn receive<W: WriteOutBuilder>(mut self, w: W) -> Result<TransferStats> {
+        let size = self.header()?;
+        let start = Instant::now();
+        let mut writer = w.build(size, self.threads);
+        let (progress, monitor) = progress::channel();
+        let (chunk_tx, chunk_rx) = bounded(2 * self.threads as usize);
+        let (dec_tx, dec_rx) = bounded(2 * self.threads as usize);
+        let (threads, codec, bar) = (self.threads, self.codec, self.progress.bar());
+        let (input, target) = (&mut self.input, self.target.as_ref());
+        let kept_progress = progress.clone();
+        let out = &mut writer;
+        let mut written = 0;
+        let res = thread::scope(|s| {
+            let writer = s.spawn(|_| {
+                out.prepare()
+                    .and_then(|()| out.receive(chunk_rx, progress))
+                    .map_err(Error::from)
+            });
+            let decoders: Vec<_> = (0..threads)
+                .map(|_| {
+                    let (rx, tx) = (dec_rx.clone(), chunk_tx.clone());
+                    s.spawn(move |_| Self::decode(rx, tx, codec))
+                })
+                .collect();
+            drop(dec_rx);
+            let reader = s.spawn(move |_| {
+                let nchunks = (size >> CHUNKSZ_LOG) as u32;
+                Self::read_records(input, nchunks, target, dec_tx, chunk_tx, kept_progress)
+            });
+            written = monitor.run(bar, size, None).0;
+            let stats = reader.join().expect("unhandled panic");
+            let mut errors: Vec<Error> = decoders
+                .into_iter()
+                .map(|d| d.join().expect("unhandled panic"))
+                .chain(Some(writer.join().expect("unhandled panic")))
+                .filter_map(|r| r.err())
+                .collect();
+            let stats = match stats {
+                Ok(stats) => stats,
+                Err(e) => {
+                    errors.insert(0, e);
+                    Default::default()
+                }
+            };
+            // IPC errors and incomplete writes are mere consequences of another thread's failure
+            let root = errors.iter().position(|e| {
+                !matches!(e, Error::Ipc | Error::Write(writeout::Error::Incomplete(_)))
+            });
+            match root {
+                Some(i) => Err(errors.swap_remove(i)),
+                None if !errors.is_empty() => Err(errors.swap_remove(0)),
+                None => Ok(stats),
+            }
+        })
+        .expect("subthread panic");
+        match res {
+            Ok(stats) => {
+                writer.finalize(&WriteReport {
+                    size,
+                    bytes: written,
+                    elapsed: start.elapsed(),
+                })?;
+                Ok(stats)
+            }
+            Err(e) => {
+                writer.abort(&e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Receives a stream created by [send_delta](fn.send_delta.html) and updates `path` in
+    /// place. `path` must not be modified between creating the advertisement and receiving.
+    pub fn receive_delta<P: AsRef<Path>>(mut self, path: P) -> Result<TransferStats> {
+        let path = path.as_ref();
+        self.target = Some(File::open(path)?);
+        self.receive(RandomAccess::new(path, None).in_place())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, Slot, StoreBuilder};
+    use crate::{RandomAccess, Stream};
+    use tempdir::TempDir;
+
+    fn sent(tmp: &TempDir, slots: &[Slot]) -> (Vec<u8>, Vec<u8>) {
+        let chunks = testing::layout(slots);
+        let maps = StoreBuilder::new()
+            .revision("SentRevisionzzzzzzzzzz", chunks.clone())
+            .write(tmp.path())
+            .unwrap();
+        let mut buf = Vec::new();
+        send(&maps[0], &mut buf).unwrap();
+        (buf, testing::image(&chunks))
+    }
+
+    #[test]
+    fn send_receive() {
+        let tmp = TempDir::new("remote").unwrap();
+        let slots = [
+            Slot::Hole,
+            Slot::Data(1),
+            Slot::Hole,
+            Slot::Hole,
+            Slot::Dup(1),
+            Slot::Data(2),
+        ];
+        let (stream, expected) = sent(&tmp, &slots);
+        let mut img = Vec::new();
+        let stats = Receiver::new(&stream[..])
+            .threads(3)
+            .receive(Stream::new(&mut img))
+            .unwrap();
+        assert_eq!((stats.chunks, stats.zero_runs), (2, 2));
+        assert!(img == expected);
+
+        let tgt = tmp.path().join("target");
+        Receiver::new(&stream[..])
+            .receive(RandomAccess::new(&tgt, None))
+            .unwrap();
+        assert!(fs::read(&tgt).unwrap() == expected);
+    }
+
+    #[test]
+    fn delta_transfer() {
+        let tmp = TempDir::new("remote").unwrap();
+        let old = testing::layout(&[Slot::Data(1), Slot::Data(2), Slot::Hole, Slot::Data(3)]);
+        let new = testing::layout(&[
+            Slot::Data(1), // kept
+            Slot::Data(4), // changed
+            Slot::Data(2), // moved: seq 1 gets overwritten, so it must be transferred
+            Slot::Dup(0),  // copied from seq 0
+            Slot::Hole,    // new zeros
+        ]);
+        let maps = StoreBuilder::new()
+            .revision("OldRevisionzzzzzzzzzzz", old.clone())
+            .revision("NewRevisionzzzzzzzzzzz", new.clone())
+            .write(tmp.path())
+            .unwrap();
+        let tgt = tmp.path().join("target");
+        fs::write(&tgt, testing::image(&old)).unwrap();
+
+        let have = Advertisement::scan(&tgt).unwrap();
+        assert_eq!(have.ids[0], testing::chunk_id(&testing::pattern(1)));
+        assert_eq!(have.ids[2], ZERO_HASH);
+        let mut adv = Vec::new();
+        have.write(&mut adv).unwrap();
+        let have = Advertisement::read(&adv[..]).unwrap();
+
+        let mut stream = Vec::new();
+        let sent = send_delta(&maps[1], &have, &mut stream).unwrap();
+        assert_eq!((sent.chunks, sent.copied, sent.kept), (2, 1, 1));
+        let received = Receiver::new(&stream[..]).receive_delta(&tgt).unwrap();
+        assert_eq!(sent, received);
+        assert!(fs::read(&tgt).unwrap() == testing::image(&new));
+
+        // delta streams cannot be received without target
+        let res = Receiver::new(&stream[..]).receive(Stream::new(Vec::new()));
+        assert!(matches!(res, Err(Error::Format(_))), "{:?}", res);
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        let tmp = TempDir::new("remote").unwrap();
+        let chunks = testing::layout(&[Slot::Data(1), Slot::Hole, Slot::Data(2), Slot::Dup(0)]);
+        let maps = StoreBuilder::new()
+            .revision("SentRevisionzzzzzzzzzz", chunks.clone())
+            .write(tmp.path())
+            .unwrap();
+
+        let mut stream = Vec::new();
+        let have = Advertisement::accepting(&[Encoding::Plain]);
+        let sent = send_delta(&maps[0], &have, &mut stream).unwrap();
+        assert_eq!((sent.chunks, sent.plain), (2, 2));
+        assert_eq!(stream[8], 3);
+        let mut img = Vec::new();
+        let received = Receiver::new(&stream[..])
+            .receive(Stream::new(&mut img))
+            .unwrap();
+        assert_eq!(sent, received);
+        assert!(img == testing::image(&chunks));
+
+        // older receivers get what they are able to read
+        let mut stream = Vec::new();
+        let sent = send(&maps[0], &mut stream).unwrap();
+        assert_eq!((sent.chunks, sent.plain), (2, 0));
+        assert_eq!(stream[8], 2);
+
+        // the receiver's preference wins
+        let mut stream = Vec::new();
+        let have = Advertisement::accepting(&[Encoding::Plain, Encoding::Lzo]);
+        let sent = send_delta(&maps[0], &have, &mut stream).unwrap();
+        assert_eq!((sent.chunks, sent.plain), (2, 2));
+
+        let res = send_delta(&maps[0], &Advertisement::accepting(&[]), Vec::new());
+        assert!(matches!(res, Err(Error::NoEncoding)), "{:?}", res);
+    }
+
+    #[test]
+    fn read_advertisements() {
+        let mut v3 = Vec::new();
+        let mut have = Advertisement::accepting(&[Encoding::Plain, Encoding::Lzo]);
+        have.ids.push(ZERO_HASH.to_owned());
+        have.write(&mut v3).unwrap();
+        assert_eq!(Advertisement::read(&v3[..]).unwrap(), have);
+
+        // version 2: no encodings
+        let v2 = b"BKYHAVE\0\x02\0\0\0\0";
+        let have = Advertisement::read(&v2[..]).unwrap();
+        assert_eq!(have.encodings, &[Encoding::Lzo]);
+        let mut written = Vec::new();
+        have.write(&mut written).unwrap();
+        assert_eq!(written, &v2[..]);
+
+        // unknown encodings are ignored
+        let v3 = b"BKYHAVE\0\x03\0\0\0\0\x02\0\x04zstd\0\x05plain";
+        let have = Advertisement::read(&v3[..]).unwrap();
+        assert_eq!(have.encodings, &[Encoding::Plain]);
+        assert!("zstd".parse::<Encoding>().is_err());
+
+        // there never has been a version 1
+        let v1 = b"BKYHAVE\0\x01\0\0\0\0";
+        assert!(matches!(
+            Advertisement::read(&v1[..]),
+            Err(Error::Version(1))
+        ));
+    }
+
+    #[test]
+    fn reject_truncated_stream() {
+        let tmp = TempDir::new("remote").unwrap();
+        let (stream, _) = sent(&tmp, &[Slot::Data(1), Slot::Data(2)]);
+        let res = Receiver::new(&stream[..stream.len() / 2]).receive(Stream::new(Vec::new()));
+        assert!(matches!(res, Err(Error::Io(_))), "{:?}", res);
+    }
+
+    #[test]
+    fn coverage_runs() {
+        let mut cov = Coverage::new(10);
+        cov.mark_run(2, 3, Seen::Kept).unwrap();
+        cov.mark(5, Seen::Kept).unwrap();
+        cov.mark(0, Seen::Written).unwrap();
+        assert_eq!(cov.runs.len(), 2);
+        assert!(cov.is_kept(4) && cov.is_kept(5) && !cov.is_kept(0) && !cov.is_kept(6));
+        assert_eq!(cov.first_missing(), Some(1));
+        assert!(cov.mark(3, Seen::Written).is_err());
+        assert!(cov.mark_run(0, 2, Seen::Written).is_err());
+        assert!(cov.mark_run(8, 3, Seen::Written).is_err());
+        assert!(cov.mark_run(u32::MAX, 2, Seen::Written).is_err());
+        cov.mark(1, Seen::Written).unwrap();
+        cov.mark_run(6, 4, Seen::Written).unwrap();
+        assert_eq!(cov.first_missing(), None);
+    }
+
+    #[test]
+    fn huge_announced_size() {
+        let mut stream = MAGIC.to_vec();
+        stream.write_u8(VERSION).unwrap();
+        stream
+            .write_u64::<BigEndian>(u64::from(u32::MAX) << CHUNKSZ_LOG)
+            .unwrap();
+        stream.write_u8(TAG_END).unwrap();
+        stream.write_u32::<BigEndian>(0).unwrap();
+        let res = Receiver::new(&stream[..]).receive(Stream::new(io::sink()));
+        assert!(matches!(res, Err(Error::Incomplete(0))), "{:?}", res);
+    }
+
+    #[test]
+    fn detect_corrupted_chunk() {
+        let tmp = TempDir::new("remote").unwrap();
+        let (mut stream, _) = sent(&tmp, &[Slot::Data(1)]);
+        let l = stream.len();
+        stream[l - 1000] ^= 0x55;
+        let res = Receiver::new(&stream[..]).receive(Stream::new(Vec::new()));
+        assert!(
+            matches!(
+                res,
+                Err(Error::Checksum { .. }) | Err(Error::InvalidChunk { .. })
+            ),
+            "{:?}",
+            res
+        );
+    }
+}
@@ -0,0 +1,275 @@
+//! Index of the parsed `.rev` files of a store.
+//!
+//! Enumerating revisions means reading and parsing every `.rev` file, which takes minutes on
+//! stores with thousands of revisions and cold caches. [RevIndex] keeps the parsed contents
+//! together with size and modification time of each `.rev` file in `chunks/revindex`. A file
+//! whose metadata is unchanged is taken from the index, so enumeration needs only a directory
+//! listing and a `stat` per revision. Changed and new files are parsed and updated in the index
+//! on access. The index is written atomically; damaged index files are ignored.
+//!
+//! Only `.rev` files are covered. Commands which need figures from the revision maps, like the
+//! chunk counts of `list`, still parse every map.
+//!
+//! # Format
+//!
+//! Integer and `str` encoding as in the [archive](../archive/index.html) format.
+//!
+//! ```text
+//! header:  "BKYREVS\0" version:u8 nentries:u32
+//! entries: (name:str len:u64 mtime:i64 mtime_nsec:i64 uuid:str timestamp:i64
+//!           timestamp_nsec:u32 trust:u8 parent:str ntags:u32 tag:str*)*
+//! trailer: hash of all preceding bytes, 32 hex digits
+//! ```
+//!
+//! `name` is the `.rev` file name. `trust` counts from 0 (distrusted) to 3 (verified). An empty
+//! `parent` stands for none.
+
+use crate::backend::{self, Rev, RevError, Trust};
+use crate::framing::{read_str, write_str};
+use crate::replace_file;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use chrono::{TimeZone, Utc};
+use log::debug;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Cursor, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"BKYREVS\0";
+const VERSION: u8 = 1;
+const TRAILER: usize = 32;
+
+const TRUST: [Trust; 4] = [
+    Trust::Distrusted,
+    Trust::Unknown,
+    Trust::Trusted,
+    Trust::Verified,
+];
+
+#[derive(Debug, Clone)]
+struct Entry {
+    len: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    rev: Rev,
+}
+
+impl Entry {
+    fn matches(&self, m: &Metadata) -> bool {
+        self.len == m.len() && self.mtime == m.mtime() && self.mtime_nsec == m.mtime_nsec()
+    }
+}
+
+/// Parsed `.rev` files of a store, keyed by file name.
+#[derive(Debug)]
+pub(crate) struct RevIndex {
+    dir: PathBuf,
+    entries: HashMap<String, Entry>,
+    /// Entries used or updated since the index has been opened
+    seen: HashMap<String, Entry>,
+    modified: bool,
+}
+
+impl RevIndex {
+    /// Loads the index of the store in `dir`. Starts out empty if there is no valid index file.
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Self {
+        let dir = dir.into();
+        let path = dir.join("chunks/revindex");
+        let entries = match read(&path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                debug!("ignoring revision index {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+        Self {
+            dir,
+            entries,
+            seen: HashMap::new(),
+            modified: false,
+        }
+    }
+
+    /// Loads the `.rev` file `path`, from the index if the file is unchanged.
+    pub fn load(&mut self, path: &Path) -> Result<Rev, RevError> {
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let meta = fs::metadata(path)?;
+        if let Some(e) = self.entries.get(&name).filter(|e| e.matches(&meta)) {
+            let rev = e.rev.clone();
+            self.seen.insert(name, e.clone());
+            return Ok(rev);
+        }
+        let rev = Rev::parse(&fs::read_to_string(path)?, path.to_owned())?;
+        let entry = Entry {
+            len: meta.len(),
+            mtime: meta.mtime(),
+            mtime_nsec: meta.mtime_nsec(),
+            rev: rev.clone(),
+        };
+        self.seen.insert(name, entry);
+        self.modified = true;
+        Ok(rev)
+    }
+
+    /// Finds all revisions of the store like
+    /// [Rev::discover](../backend/struct.Rev.html#method.discover).
+    pub fn discover(&mut self) -> io::Result<Vec<Result<Rev, RevError>>> {
+        let dir = self.dir.clone();
+        Rev::discover_with(dir, |path| self.load(path))
+    }
+
+    /// Writes the index if anything has changed. Only entries which have been accessed since
+    /// the index has been opened are kept.
+    pub fn save(&self) -> io::Result<()> {
+        if !self.modified && self.seen.len() == self.entries.len() {
+            return Ok(());
+        }
+        let mut buf = MAGIC.to_vec();
+        buf.write_u8(VERSION)?;
+        buf.write_u32::<BigEndian>(self.seen.len() as u32)?;
+        for (name, e) in &self.seen {
+            write_str(&mut buf, name)?;
+            buf.write_u64::<BigEndian>(e.len)?;
+            buf.write_i64::<BigEndian>(e.mtime)?;
+            buf.write_i64::<BigEndian>(e.mtime_nsec)?;
+            write_str(&mut buf, &e.rev.uuid)?;
+            buf.write_i64::<BigEndian>(e.rev.timestamp.timestamp())?;
+            buf.write_u32::<BigEndian>(e.rev.timestamp.timestamp_subsec_nanos())?;
+            let trust = TRUST.iter().position(|&t| t == e.rev.trust).unwrap_or(1);
+            buf.write_u8(trust as u8)?;
+            write_str(&mut buf, e.rev.parent.as_deref().unwrap_or(""))?;
+            buf.write_u32::<BigEndian>(e.rev.tags.len() as u32)?;
+            for tag in &e.rev.tags {
+                write_str(&mut buf, tag)?;
+            }
+        }
+        let hash = backend::hash(&buf);
+        buf.extend_from_slice(hash.as_bytes());
+        let path = self.dir.join("chunks/revindex");
+        replace_file(&path, &buf)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read(path: &Path) -> io::Result<HashMap<String, Entry>> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    if buf.len() < MAGIC.len() + TRAILER || &buf[..MAGIC.len()] != MAGIC {
+        return Err(invalid("bad magic"));
+    }
+    let (body, trailer) = buf.split_at(buf.len() - TRAILER);
+    if backend::hash(body).as_bytes() != trailer {
+        return Err(invalid("checksum mismatch"));
+    }
+    let mut r = Cursor::new(&body[MAGIC.len()..]);
+    if r.read_u8()? != VERSION {
+        return Err(invalid("unsupported version"));
+    }
+    let n = r.read_u32::<BigEndian>()?;
+    let mut entries = HashMap::with_capacity(n.min(1 << 16) as usize);
+    for _ in 0..n {
+        let name = read_str(&mut r)?;
+        let len = r.read_u64::<BigEndian>()?;
+        let mtime = r.read_i64::<BigEndian>()?;
+        let mtime_nsec = r.read_i64::<BigEndian>()?;
+        let uuid = read_str(&mut r)?.into();
+        let secs = r.read_i64::<BigEndian>()?;
+        let nsecs = r.read_u32::<BigEndian>()?;
+        let timestamp = Utc
+            .timestamp_opt(secs, nsecs)
+            .single()
+            .ok_or_else(|| invalid("bad timestamp"))?;
+        let trust = *TRUST
+            .get(r.read_u8()? as usize)
+            .ok_or_else(|| invalid("bad trust level"))?;
+        let parent = Some(read_str(&mut r)?)
+            .filter(|p| !p.is_empty())
+            .map(Into::into);
+        let ntags = r.read_u32::<BigEndian>()?;
+        let tags = (0..ntags)
+            .map(|_| read_str(&mut r))
+            .collect::<io::Result<_>>()?;
+        let rev = Rev {
+            backend_type: "chunked".to_owned(),
+            timestamp,
+            uuid,
+            trust,
+            parent,
+            tags,
+        };
+        entries.insert(
+            name,
+            Entry {
+                len,
+                mtime,
+                mtime_nsec,
+                rev,
+            },
+        );
+    }
+    if r.position() != r.get_ref().len() as u64 {
+        return Err(invalid("trailing garbage"));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::store_tar;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn reuse_unchanged_rev_files() {
+        let store = store_tar();
+        let dir = store.path();
+        let mut index = RevIndex::open(dir);
+        let revs = index.discover().unwrap();
+        assert_eq!(revs.len(), 1);
+        let rev = revs[0].as_ref().unwrap().clone();
+        index.save().unwrap();
+        assert!(dir.join("chunks/revindex").exists());
+
+        // unparseable file with same size and mtime is served from the index
+        let path = dir.join(format!("{}.rev", rev.uuid));
+        let orig = fs::metadata(&path).unwrap();
+        fs::write(&path, vec![b'#'; orig.len() as usize]).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(orig.modified().unwrap())
+            .unwrap();
+        let mut index = RevIndex::open(dir);
+        let cached = index.load(&path).unwrap();
+        assert_eq!(cached.uuid, rev.uuid);
+        assert_eq!(cached.timestamp, rev.timestamp);
+        assert_eq!(cached.trust, rev.trust);
+        assert_eq!(cached.tags, rev.tags);
+
+        // changed files are parsed again
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert!(matches!(index.load(&path), Err(RevError::ParseRev { .. })));
+
+        // damaged index file
+        let mut data = fs::read(dir.join("chunks/revindex")).unwrap();
+        data[12] ^= 0xff;
+        fs::write(dir.join("chunks/revindex"), data).unwrap();
+        assert!(RevIndex::open(dir).entries.is_empty());
+    }
+}
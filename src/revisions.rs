@@ -0,0 +1,921 @@
+//! Listing and retention analysis of the revisions in a backup directory.
+//!
+//! Besides the metadata from the `.rev` files, the listing tells how much data is unique to each
+//! revision, i.e. not referenced by any other revision. This estimates what purging the revision
+//! would free, measured before compression.
+//!
+//! The [tree](fn.tree.html) of revisions follows their `parent` references and tells how many
+//! chunks each revision has in common with its parent. Any two revisions can be compared with
+//! [similarity](fn.similarity.html).
+//!
+//! An [estimate](fn.estimate.html) tells how much a restore of each revision would write,
+//! based on the revision maps alone.
+//!
+//! A [retention report](fn.retention_report.html) simulates purging a selection of revisions
+//! and tells which chunk files would become orphaned and how much disk space they take up.
+
+use crate::backend::{self, Backend, Rev, Trust};
+use crate::chunkvec::{ChunkId, ChunkVec, MapDiff};
+use crate::revindex::RevIndex;
+use crate::{ExtractError, HumanBytes, CHUNKSZ};
+
+use chrono::{DateTime, Utc};
+use log::debug;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read backup dir '{}'", .0.display())]
+    ReadDir(PathBuf, #[source] io::Error),
+    #[error("Revision map of {0} cannot be read, refusing to guess which chunks it references")]
+    Unreadable(String),
+    #[error("Backup dir '{}' contains a damaged revision, refusing to guess which chunks it \
+             references", .0.display())]
+    Damaged(PathBuf, #[source] backend::RevError),
+    #[error("Failed to read revision map '{}'", .0.display())]
+    ReadMap(PathBuf, #[source] io::Error),
+    #[error("Failed to parse revision map '{}'", .0.display())]
+    ParseMap(PathBuf, #[source] ExtractError),
+    #[error("Chunked backend error")]
+    Backend(#[from] backend::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+fn rfc3339<S: Serializer>(t: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&t.to_rfc3339())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Revision {
+    pub uuid: String,
+    #[serde(serialize_with = "rfc3339")]
+    pub timestamp: DateTime<Utc>,
+    /// Image size in bytes, None if the revision map cannot be read
+    pub size: Option<u64>,
+    pub tags: Vec<String>,
+    pub trust: Trust,
+    pub parent: Option<String>,
+    /// Number of distinct chunks
+    pub chunks: usize,
+    /// Uncompressed size of all chunks which no other revision references
+    pub unique_bytes: u64,
+}
+
+// Finds all revisions in `dir`, oldest first, including those whose `.rev` file is damaged.
+fn discover_all(dir: &Path) -> Result<Vec<Result<Rev, backend::RevError>>> {
+    let mut index = RevIndex::open(dir);
+    let revs = index
+        .discover()
+        .map_err(|e| Error::ReadDir(dir.to_owned(), e))?;
+    if cfg!(not(feature = "read-only")) {
+        if let Err(e) = index.save() {
+            debug!(
+                "Failed to update revision index in {}: {}",
+                dir.display(),
+                e
+            );
+        }
+    }
+    Ok(revs)
+}
+
+// Finds all valid revisions in `dir`, oldest first.
+fn discover(dir: &Path) -> Result<Vec<Rev>> {
+    Ok(discover_all(dir)?
+        .into_iter()
+        .filter_map(|r| r.ok())
+        .collect())
+}
+
+// Loads all valid revisions in `dir` together with their maps, oldest first.
+fn load(dir: &Path) -> Result<Vec<(Rev, Option<ChunkVec>)>> {
+    Ok(discover(dir)?
+        .into_iter()
+        .map(|rev| {
+            let map = ChunkVec::load(dir.join(rev.uuid.as_str())).ok();
+            (rev, map)
+        })
+        .collect())
+}
+
+// Counts how many revisions reference each chunk.
+fn refcounts<'a, I>(maps: I) -> HashMap<&'a ChunkId, usize>
+where
+    I: IntoIterator<Item = &'a ChunkVec>,
+{
+    let mut refs = HashMap::new();
+    for id in maps.into_iter().flat_map(ChunkVec::ids) {
+        *refs.entry(id).or_default() += 1;
+    }
+    refs
+}
+
+/// Lists all valid revisions in `dir`, oldest first.
+pub fn list<P: AsRef<Path>>(dir: P) -> Result<Vec<Revision>> {
+    let revs = load(dir.as_ref())?;
+    let refs = refcounts(revs.iter().filter_map(|(_, map)| map.as_ref()));
+    Ok(revs
+        .iter()
+        .map(|(rev, map)| Revision {
+            uuid: rev.uuid.to_string(),
+            timestamp: rev.timestamp,
+            size: map.as_ref().map(|m| m.size),
+            tags: rev.tags.clone(),
+            trust: rev.trust,
+            parent: rev.parent.as_ref().map(|p| p.to_string()),
+            chunks: map.as_ref().map_or(0, ChunkVec::unique),
+            unique_bytes: map.as_ref().map_or(0, |m| {
+                m.ids().filter(|id| refs[id] == 1).count() as u64 * CHUNKSZ as u64
+            }),
+        })
+        .collect())
+}
+
+/// Metadata of a revision without figures about the chunks it references, see
+/// [summaries](fn.summaries.html).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Summary {
+    pub uuid: String,
+    #[serde(serialize_with = "rfc3339")]
+    pub timestamp: DateTime<Utc>,
+    /// Image size in bytes, None if the revision map cannot be read
+    pub size: Option<u64>,
+    pub tags: Vec<String>,
+    pub trust: Trust,
+    pub parent: Option<String>,
+}
+
+// Image size from a revision map. The chunk mapping is skipped without being stored.
+#[derive(Deserialize)]
+struct MapSize {
+    size: u64,
+}
+
+/// Lists all valid revisions in `dir`, oldest first, without looking at the chunks they
+/// reference. Only the image size is read from the revision maps, which is much faster than
+/// [list](fn.list.html) for stores with many large revisions.
+pub fn summaries<P: AsRef<Path>>(dir: P) -> Result<Vec<Summary>> {
+    let dir = dir.as_ref();
+    Ok(discover(dir)?
+        .into_iter()
+        .map(|rev| {
+            let size = File::open(dir.join(rev.uuid.as_str()))
+                .ok()
+                .and_then(|f| serde_json::from_reader::<_, MapSize>(BufReader::new(f)).ok())
+                .map(|m| m.size);
+            Summary {
+                uuid: rev.uuid.to_string(),
+                timestamp: rev.timestamp,
+                size,
+                tags: rev.tags,
+                trust: rev.trust,
+                parent: rev.parent.map(|p| p.to_string()),
+            }
+        })
+        .collect())
+}
+
+/// Writes `revs` as aligned table with a header line.
+pub fn write_table<W: Write>(revs: &[Revision], mut out: W) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:<22}  {:<19}  {:>10}  {:>10}  {:<10}  {:<22}  TAGS",
+        "UUID", "TIMESTAMP", "SIZE", "UNIQUE", "TRUST", "PARENT"
+    )?;
+    for r in revs {
+        let line = format!(
+            "{:<22}  {:<19}  {:>10}  {:>10}  {:<10}  {:<22}  {}",
+            r.uuid,
+            r.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            r.size.map_or("?".to_owned(), |s| HumanBytes(s).to_string()),
+            HumanBytes(r.unique_bytes).to_string(),
+            r.trust.to_string(),
+            r.parent.as_deref().unwrap_or("-"),
+            r.tags.join(",")
+        );
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    out.flush()
+}
+
+/// Writes `revs` as CSV with a header line. Tags are separated by spaces.
+pub fn write_csv<W: Write>(revs: &[Revision], mut out: W) -> io::Result<()> {
+    writeln!(
+        out,
+        "uuid,timestamp,size,tags,trust,parent,chunks,unique_bytes"
+    )?;
+    for r in revs {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{}",
+            r.uuid,
+            r.timestamp.to_rfc3339(),
+            r.size.map(|s| s.to_string()).unwrap_or_default(),
+            r.tags.join(" "),
+            r.trust,
+            r.parent.as_deref().unwrap_or(""),
+            r.chunks,
+            r.unique_bytes
+        )?;
+    }
+    out.flush()
+}
+
+/// Expected restore volume of a revision.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Estimate {
+    pub uuid: String,
+    #[serde(serialize_with = "rfc3339")]
+    pub timestamp: DateTime<Utc>,
+    /// Image size in bytes
+    pub size: u64,
+    /// Chunk positions which are not mapped to any chunk and read back as zeros
+    pub hole_chunks: usize,
+    /// Chunk positions which are mapped to a chunk
+    pub mapped_chunks: usize,
+    /// Mapped positions whose chunk is known to contain only zeros
+    pub zero_chunks: usize,
+    /// Distinct chunks which must be read and decompressed
+    pub unique_chunks: usize,
+    /// Bytes written to a sparse target. This is an upper bound since zero blocks inside data
+    /// chunks are skipped as well.
+    pub sparse_bytes: u64,
+    /// Bytes written to a non-sparse target
+    pub full_bytes: u64,
+}
+
+impl Estimate {
+    fn new(rev: &Rev, map: &ChunkVec, zero_id: &str) -> Self {
+        let holes = map.zero_seqs().len();
+        let zero = map.seqs_of(zero_id).len();
+        let data = map.len() - holes - zero;
+        Self {
+            uuid: rev.uuid.to_string(),
+            timestamp: rev.timestamp,
+            size: map.size,
+            hole_chunks: holes,
+            mapped_chunks: map.len() - holes,
+            zero_chunks: zero,
+            unique_chunks: map.unique(),
+            sparse_bytes: data as u64 * CHUNKSZ as u64,
+            full_bytes: map.size,
+        }
+    }
+
+    /// Share of chunk positions which need not be written to a sparse target, from 0 to 1.
+    pub fn zero_ratio(&self) -> f64 {
+        match self.hole_chunks + self.mapped_chunks {
+            0 => 0.0,
+            n => (self.hole_chunks + self.zero_chunks) as f64 / n as f64,
+        }
+    }
+}
+
+/// Estimates the restore volume of all valid revisions in `dir`, oldest first. Only revision
+/// maps are read, no chunks. Revisions whose map cannot be read are left out.
+pub fn estimate<P: AsRef<Path>>(dir: P) -> Result<Vec<Estimate>> {
+    Ok(load(dir.as_ref())?
+        .iter()
+        .filter_map(|(rev, map)| Some(Estimate::new(rev, map.as_ref()?, backend::ZERO_HASH)))
+        .collect())
+}
+
+/// Writes `estimates` as aligned table with a header line.
+pub fn write_estimates<W: Write>(estimates: &[Estimate], mut out: W) -> io::Result<()> {
+    writeln!(
+        out,
+        "{:<22}  {:<19}  {:>10}  {:>8}  {:>8}  {:>6}  {:>10}  {:>10}",
+        "UUID", "TIMESTAMP", "SIZE", "HOLES", "MAPPED", "ZERO%", "SPARSE", "FULL"
+    )?;
+    for e in estimates {
+        writeln!(
+            out,
+            "{:<22}  {:<19}  {:>10}  {:>8}  {:>8}  {:>6.1}  {:>10}  {:>10}",
+            e.uuid,
+            e.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            HumanBytes(e.size).to_string(),
+            e.hole_chunks,
+            e.mapped_chunks,
+            100.0 * e.zero_ratio(),
+            HumanBytes(e.sparse_bytes).to_string(),
+            HumanBytes(e.full_bytes).to_string()
+        )?;
+    }
+    out.flush()
+}
+
+/// Chunks which two revisions A and B have in common.
+///
+/// Chunks are counted once, no matter how often a revision references them. Sizes are
+/// uncompressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SharedStats {
+    /// Distinct chunks which both revisions reference
+    pub shared_chunks: usize,
+    pub shared_bytes: u64,
+    /// Distinct chunks which only A references
+    pub only_a_chunks: usize,
+    pub only_a_bytes: u64,
+    /// Distinct chunks which only B references
+    pub only_b_chunks: usize,
+    pub only_b_bytes: u64,
+    /// Chunk positions which map to different chunks, counting positions beyond the end of the
+    /// smaller image
+    pub changed_positions: usize,
+    /// Chunk positions of the larger image
+    pub positions: usize,
+}
+
+impl SharedStats {
+    fn new(d: MapDiff) -> Self {
+        let bytes = |n: usize| n as u64 * CHUNKSZ as u64;
+        let (only_a, only_b) = (d.unique - d.shared, d.other_unique - d.shared);
+        Self {
+            shared_chunks: d.shared,
+            shared_bytes: bytes(d.shared),
+            only_a_chunks: only_a,
+            only_a_bytes: bytes(only_a),
+            only_b_chunks: only_b,
+            only_b_bytes: bytes(only_b),
+            changed_positions: d.changed,
+            positions: d.same + d.changed,
+        }
+    }
+
+    fn percent(shared: usize, only: usize) -> f64 {
+        match shared + only {
+            0 => 100.0,
+            total => 100.0 * shared as f64 / total as f64,
+        }
+    }
+
+    /// Percentage of the distinct chunks of A which B references as well.
+    pub fn shared_percent_a(&self) -> f64 {
+        Self::percent(self.shared_chunks, self.only_a_chunks)
+    }
+
+    /// Percentage of the distinct chunks of B which A references as well.
+    pub fn shared_percent_b(&self) -> f64 {
+        Self::percent(self.shared_chunks, self.only_b_chunks)
+    }
+}
+
+impl fmt::Display for SharedStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Shared:    {} chunks ({}), {:.1}% of A, {:.1}% of B",
+            self.shared_chunks,
+            HumanBytes(self.shared_bytes),
+            self.shared_percent_a(),
+            self.shared_percent_b()
+        )?;
+        writeln!(
+            f,
+            "Only in A: {} chunks ({})",
+            self.only_a_chunks,
+            HumanBytes(self.only_a_bytes)
+        )?;
+        writeln!(
+            f,
+            "Only in B: {} chunks ({})",
+            self.only_b_chunks,
+            HumanBytes(self.only_b_bytes)
+        )?;
+        writeln!(
+            f,
+            "Changed:   {} of {} chunk positions",
+            self.changed_positions, self.positions
+        )
+    }
+}
+
+fn read_map(path: &Path) -> Result<ChunkVec> {
+    let map = backend::map_file(path).map_err(|e| Error::ReadMap(path.to_owned(), e))?;
+    ChunkVec::from_slice(&map).map_err(|e| Error::ParseMap(path.to_owned(), e))
+}
+
+/// Compares the chunks of two revisions, given as paths to their revision maps. The revisions
+/// may live in different backup directories.
+pub fn similarity<P: AsRef<Path>, Q: AsRef<Path>>(rev_a: P, rev_b: Q) -> Result<SharedStats> {
+    let a = read_map(rev_a.as_ref())?;
+    let b = read_map(rev_b.as_ref())?;
+    Ok(SharedStats::new(a.compare(&b)))
+}
+
+/// Revision in the parent tree together with its children.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TreeNode {
+    pub uuid: String,
+    #[serde(serialize_with = "rfc3339")]
+    pub timestamp: DateTime<Utc>,
+    /// Image size in bytes, None if the revision map cannot be read
+    pub size: Option<u64>,
+    pub tags: Vec<String>,
+    pub trust: Trust,
+    /// Parent as recorded in the `.rev` file. Revisions whose parent has been purged show up
+    /// as roots.
+    pub parent: Option<String>,
+    /// Comparison with the parent as A = this revision, B = parent. None for roots and if one of
+    /// the maps cannot be read.
+    pub similarity: Option<SharedStats>,
+    /// Child revisions, oldest first
+    pub children: Vec<TreeNode>,
+}
+
+impl TreeNode {
+    fn new(rev: &Rev, map: Option<&ChunkVec>, diff: Option<MapDiff>) -> Self {
+        Self {
+            uuid: rev.uuid.to_string(),
+            timestamp: rev.timestamp,
+            size: map.map(|m| m.size),
+            tags: rev.tags.clone(),
+            trust: rev.trust,
+            parent: rev.parent.as_ref().map(|p| p.to_string()),
+            similarity: diff.map(SharedStats::new),
+            children: Vec::new(),
+        }
+    }
+
+    fn label(&self, root: bool) -> String {
+        let relation = match (&self.parent, self.similarity) {
+            (_, Some(s)) => format!(
+                "{:.1}% shared, {} changed",
+                s.shared_percent_a(),
+                s.changed_positions
+            ),
+            (Some(parent), None) if root => format!("parent {} missing", parent),
+            _ => String::new(),
+        };
+        let line = format!(
+            "{}  {}  {:>10}  {:<10}  {:<30}  {}",
+            self.uuid,
+            self.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            self.size
+                .map_or("?".to_owned(), |s| HumanBytes(s).to_string()),
+            self.trust.to_string(),
+            relation,
+            self.tags.join(",")
+        );
+        line.trim_end().to_owned()
+    }
+}
+
+/// Arranges all valid revisions in `dir` by their parent references. Returns the roots, oldest
+/// first.
+pub fn tree<P: AsRef<Path>>(dir: P) -> Result<Vec<TreeNode>> {
+    let revs = load(dir.as_ref())?;
+    let index: HashMap<&str, usize> = revs
+        .iter()
+        .enumerate()
+        .map(|(i, (rev, _))| (rev.uuid.as_str(), i))
+        .collect();
+    let parent = |i: usize| {
+        revs[i]
+            .0
+            .parent
+            .as_ref()
+            .and_then(|p| index.get(p.as_str()).copied())
+            .filter(|&p| p != i)
+    };
+    let mut children = vec![Vec::new(); revs.len()];
+    for i in 0..revs.len() {
+        if let Some(p) = parent(i) {
+            children[p].push(i);
+        }
+    }
+    fn build(
+        i: usize,
+        revs: &[(Rev, Option<ChunkVec>)],
+        parent: Option<usize>,
+        children: &[Vec<usize>],
+        done: &mut [bool],
+    ) -> TreeNode {
+        done[i] = true;
+        let (rev, map) = &revs[i];
+        let diff = match (map, parent.and_then(|p| revs[p].1.as_ref())) {
+            (Some(map), Some(pmap)) => Some(map.compare(pmap)),
+            _ => None,
+        };
+        let mut node = TreeNode::new(rev, map.as_ref(), diff);
+        for &c in &children[i] {
+            if !done[c] {
+                node.children.push(build(c, revs, Some(i), children, done));
+            }
+        }
+        node
+    }
+    let mut done = vec![false; revs.len()];
+    let mut roots = Vec::new();
+    for i in 0..revs.len() {
+        if parent(i).is_none() {
+            roots.push(build(i, &revs, None, &children, &mut done));
+        }
+    }
+    // parent references which form a cycle leave revisions unreachable from any root
+    for i in 0..revs.len() {
+        if !done[i] {
+            roots.push(build(i, &revs, None, &children, &mut done));
+        }
+    }
+    Ok(roots)
+}
+
+/// Writes the revision tree as indented text. Chains of only children are not indented any
+/// further, so that a linear history stays readable. Separate roots are separated by an empty
+/// line.
+pub fn write_tree<W: Write>(roots: &[TreeNode], mut out: W) -> io::Result<()> {
+    fn node<W: Write>(
+        n: &TreeNode,
+        root: bool,
+        first: &str,
+        rest: &str,
+        out: &mut W,
+    ) -> io::Result<()> {
+        writeln!(out, "{}{}", first, n.label(root))?;
+        if let [only] = &n.children[..] {
+            return node(only, false, rest, rest, out);
+        }
+        for (i, c) in n.children.iter().enumerate() {
+            if i + 1 < n.children.len() {
+                node(
+                    c,
+                    false,
+                    &format!("{}├─ ", rest),
+                    &format!("{}│  ", rest),
+                    out,
+                )?;
+            } else {
+                node(
+                    c,
+                    false,
+                    &format!("{}└─ ", rest),
+                    &format!("{}   ", rest),
+                    out,
+                )?;
+            }
+        }
+        Ok(())
+    }
+    for (i, root) in roots.iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        node(root, true, "", "", &mut out)?;
+    }
+    out.flush()
+}
+
+/// Revisions to be purged in a retention report. A revision is selected if it matches all
+/// criteria which are given.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selection {
+    /// Revision UUIDs
+    pub revisions: Vec<String>,
+    /// Revisions carrying at least one of these tags
+    pub tags: Vec<String>,
+    /// Revisions taken before this point in time
+    pub before: Option<DateTime<Utc>>,
+}
+
+impl Selection {
+    /// True if no criteria are given, which would select all revisions.
+    pub fn is_empty(&self) -> bool {
+        self.revisions.is_empty() && self.tags.is_empty() && self.before.is_none()
+    }
+
+    fn matches(&self, rev: &Rev) -> bool {
+        (self.revisions.is_empty() || self.revisions.iter().any(|r| r == rev.uuid.as_str()))
+            && (self.tags.is_empty() || rev.tags.iter().any(|t| self.tags.contains(t)))
+            && !matches!(self.before, Some(t) if rev.timestamp >= t)
+    }
+}
+
+/// Selected revision and what purging only this revision would free.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Candidate {
+    pub uuid: String,
+    #[serde(serialize_with = "rfc3339")]
+    pub timestamp: DateTime<Utc>,
+    pub tags: Vec<String>,
+    /// Number of chunks no other revision references
+    pub exclusive_chunks: usize,
+    /// Size of the chunk files of these chunks
+    pub reclaimable: u64,
+}
+
+/// Outcome of simulating a purge.
+///
+/// The numbers of a single candidate assume that all other revisions are kept. Chunks shared
+/// only among candidates are freed just when all of them are purged, so the totals may exceed
+/// the sum over all candidates.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RetentionReport {
+    /// Number of valid revisions in the backup directory
+    pub revisions: usize,
+    pub candidates: Vec<Candidate>,
+    /// Number of chunks which would be orphaned by purging all candidates
+    pub orphaned_chunks: usize,
+    /// Size of the chunk files of the orphaned chunks
+    pub reclaimable: u64,
+}
+
+/// Simulates purging the revisions in `dir` which match `selection`. Nothing is modified.
+///
+/// Fails if any `.rev` file or revision map cannot be read, since chunks of such a revision would
+/// wrongly count as reclaimable.
+///
+/// Chunk files are not read, only their sizes are looked up. Missing chunk files take up no
+/// space.
+pub fn retention_report<P: AsRef<Path>>(dir: P, selection: &Selection) -> Result<RetentionReport> {
+    let dir = dir.as_ref();
+    let be = Backend::open(dir)?;
+    let revs = discover_all(dir)?
+        .into_iter()
+        .map(|rev| {
+            let rev = rev.map_err(|e| Error::Damaged(dir.to_owned(), e))?;
+            match ChunkVec::load(dir.join(rev.uuid.as_str())) {
+                Ok(map) => Ok((rev, map)),
+                Err(_) => Err(Error::Unreadable(rev.uuid.to_string())),
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let refs = refcounts(revs.iter().map(|(_, map)| map));
+    let size = |id: &ChunkId| fs::metadata(be.filename(id)).map_or(0, |m| m.len());
+    let (selected, kept): (Vec<_>, Vec<_>) =
+        revs.iter().partition(|(rev, _)| selection.matches(rev));
+    let kept: HashSet<&ChunkId> = kept.iter().flat_map(|(_, map)| map.ids()).collect();
+    let orphaned: HashSet<&ChunkId> = selected
+        .iter()
+        .flat_map(|(_, map)| map.ids())
+        .filter(|id| !kept.contains(id))
+        .collect();
+    Ok(RetentionReport {
+        revisions: revs.len(),
+        candidates: selected
+            .iter()
+            .map(|(rev, map)| {
+                let exclusive: Vec<&ChunkId> = map.ids().filter(|id| refs[id] == 1).collect();
+                Candidate {
+                    uuid: rev.uuid.to_string(),
+                    timestamp: rev.timestamp,
+                    tags: rev.tags.clone(),
+                    exclusive_chunks: exclusive.len(),
+                    reclaimable: exclusive.into_iter().map(size).sum(),
+                }
+            })
+            .collect(),
+        orphaned_chunks: orphaned.len(),
+        reclaimable: orphaned.into_iter().map(size).sum(),
+    })
+}
+
+impl fmt::Display for RetentionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Purging {} of {} revisions would free {} chunks ({})",
+            self.candidates.len(),
+            self.revisions,
+            self.orphaned_chunks,
+            HumanBytes(self.reclaimable)
+        )?;
+        if self.candidates.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            f,
+            "{:<22}  {:<19}  {:>8}  {:>11}  TAGS",
+            "UUID", "TIMESTAMP", "CHUNKS", "RECLAIMABLE"
+        )?;
+        for c in &self.candidates {
+            let line = format!(
+                "{:<22}  {:<19}  {:>8}  {:>11}  {}",
+                c.uuid,
+                c.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                c.exclusive_chunks,
+                HumanBytes(c.reclaimable).to_string(),
+                c.tags.join(",")
+            );
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::store_tar;
+
+    const REV: &str = "VNzWKjnMqd6w58nzJwUZ98";
+
+    // Adds a child revision to the fixture store which replaces one of its two chunks.
+    fn second_revision(dir: &Path) {
+        let map = fs::read_to_string(dir.join(REV)).unwrap();
+        fs::write(
+            dir.join("Rev2zzzzzzzzzzzzzzzzzz"),
+            map.replace(
+                "4db6e194fd398e8edb76e11054d73eb0",
+                "ffff0000000000000000000000000000",
+            ),
+        )
+        .unwrap();
+        let rev = fs::read_to_string(dir.join(REV).with_extension("rev")).unwrap();
+        fs::write(
+            dir.join("Rev2zzzzzzzzzzzzzzzzzz.rev"),
+            rev.replace(REV, "Rev2zzzzzzzzzzzzzzzzzz")
+                .replace("parent: null", &format!("parent: {}", REV))
+                .replace("2019-01-11", "2019-01-12")
+                .replace("tags: [daily]", "tags: [daily, weekly]"),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn list_revisions() {
+        let store = store_tar();
+        second_revision(store.path());
+        fs::write(store.path().join("broken.rev"), "garbage").unwrap();
+        let revs = list(store.path()).unwrap();
+        assert_eq!(revs.len(), 2);
+        assert_eq!(revs[0].uuid, REV);
+        assert_eq!(revs[0].parent, None);
+        assert_eq!(revs[1].parent.as_deref(), Some(REV));
+        assert_eq!(revs[1].tags, &["daily", "weekly"]);
+        assert_eq!(revs[0].size, Some(4 * CHUNKSZ as u64));
+        assert_eq!((revs[0].chunks, revs[1].chunks), (2, 2));
+        assert_eq!(revs[0].unique_bytes, CHUNKSZ as u64);
+        assert_eq!(revs[1].unique_bytes, CHUNKSZ as u64);
+
+        let brief = summaries(store.path()).unwrap();
+        assert_eq!(brief.len(), 2);
+        assert_eq!(brief[1].tags, revs[1].tags);
+        assert_eq!(brief[0].size, revs[0].size);
+        assert_eq!(brief[1].parent, revs[1].parent);
+    }
+
+    // Adds a copy of the fixture revision with the given parent.
+    fn copy_revision(dir: &Path, uuid: &str, parent: &str, day: &str) {
+        fs::copy(dir.join(REV), dir.join(uuid)).unwrap();
+        let rev = fs::read_to_string(dir.join(REV).with_extension("rev")).unwrap();
+        fs::write(
+            dir.join(uuid).with_extension("rev"),
+            rev.replace(REV, uuid)
+                .replace("parent: null", &format!("parent: {}", parent))
+                .replace("2019-01-11", day),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn revision_tree() {
+        let store = store_tar();
+        second_revision(store.path());
+        copy_revision(store.path(), "Rev3zzzzzzzzzzzzzzzzzz", REV, "2019-01-13");
+        copy_revision(
+            store.path(),
+            "Rev4zzzzzzzzzzzzzzzzzz",
+            "Gonezzzzzzzzzzzzzzzzzz",
+            "2019-01-14",
+        );
+        let roots = tree(store.path()).unwrap();
+        assert_eq!(roots.len(), 2);
+        assert_eq!(roots[0].uuid, REV);
+        assert_eq!(roots[0].similarity, None);
+        let children = &roots[0].children;
+        assert_eq!(children.len(), 2);
+        let s = children[0].similarity.unwrap();
+        assert_eq!((s.shared_percent_a(), s.changed_positions), (50.0, 1));
+        let s = children[1].similarity.unwrap();
+        assert_eq!((s.shared_percent_a(), s.changed_positions), (100.0, 0));
+        assert_eq!(roots[1].uuid, "Rev4zzzzzzzzzzzzzzzzzz");
+        let mut out = Vec::new();
+        write_tree(&roots, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[1].starts_with("├─ Rev2zzzzzzzzzzzzzzzzzz  2019-01-12"));
+        assert!(lines[1].contains("50.0% shared, 1 changed"));
+        assert!(lines[2].starts_with("└─ Rev3zzzzzzzzzzzzzzzzzz"));
+        assert_eq!(lines[3], "");
+        assert!(lines[4].contains("parent Gonezzzzzzzzzzzzzzzzzz missing"));
+    }
+
+    #[test]
+    fn compare_revisions() {
+        let store = store_tar();
+        second_revision(store.path());
+        let s = similarity(
+            store.path().join(REV),
+            store.path().join("Rev2zzzzzzzzzzzzzzzzzz"),
+        )
+        .unwrap();
+        assert_eq!(
+            s,
+            SharedStats {
+                shared_chunks: 1,
+                shared_bytes: CHUNKSZ as u64,
+                only_a_chunks: 1,
+                only_a_bytes: CHUNKSZ as u64,
+                only_b_chunks: 1,
+                only_b_bytes: CHUNKSZ as u64,
+                changed_positions: 1,
+                positions: 4,
+            }
+        );
+        assert_eq!((s.shared_percent_a(), s.shared_percent_b()), (50.0, 50.0));
+        match similarity(store.path().join(REV), store.path().join("missing")) {
+            Err(Error::ReadMap(p, _)) => assert!(p.ends_with("missing")),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn estimate_restore_volume() {
+        let store = store_tar();
+        copy_revision(store.path(), "Rev3zzzzzzzzzzzzzzzzzz", REV, "2019-01-13");
+        fs::write(
+            store.path().join("Rev3zzzzzzzzzzzzzzzzzz"),
+            format!(
+                r#"{{"mapping": {{"0": "4db6e194fd398e8edb76e11054d73eb0", "2": "{}"}},
+                     "size": 16777216}}"#,
+                backend::ZERO_HASH
+            ),
+        )
+        .unwrap();
+        let est = estimate(store.path()).unwrap();
+        assert_eq!(est.len(), 2);
+        let chunk = CHUNKSZ as u64;
+        // three positions of the fixture refer to a chunk of zeros
+        assert_eq!(
+            (est[0].hole_chunks, est[0].mapped_chunks, est[0].zero_chunks),
+            (0, 4, 3)
+        );
+        assert_eq!((est[0].sparse_bytes, est[0].full_bytes), (chunk, 4 * chunk));
+        assert_eq!(
+            (est[1].hole_chunks, est[1].mapped_chunks, est[1].zero_chunks),
+            (2, 2, 1)
+        );
+        assert_eq!(est[1].unique_chunks, 2);
+        assert_eq!((est[1].sparse_bytes, est[1].full_bytes), (chunk, 4 * chunk));
+        assert!((est[1].zero_ratio() - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn simulate_purge() {
+        let store = store_tar();
+        second_revision(store.path());
+        let by_tag = Selection {
+            tags: vec!["weekly".into()],
+            ..Selection::default()
+        };
+        let r = retention_report(store.path(), &by_tag).unwrap();
+        assert_eq!(r.revisions, 2);
+        assert_eq!(r.candidates.len(), 1);
+        assert_eq!(r.candidates[0].uuid, "Rev2zzzzzzzzzzzzzzzzzz");
+        // the exclusive chunk file is missing and takes up no space
+        assert_eq!(r.candidates[0].exclusive_chunks, 1);
+        assert_eq!((r.orphaned_chunks, r.reclaimable), (1, 0));
+        let by_time = Selection {
+            before: Some("2019-01-12T00:00:00Z".parse().unwrap()),
+            ..Selection::default()
+        };
+        let r = retention_report(store.path(), &by_time).unwrap();
+        assert_eq!(r.candidates[0].uuid, REV);
+        assert_eq!((r.orphaned_chunks, r.reclaimable), (1, 46205));
+        // purging both frees the shared chunk as well
+        let both = Selection {
+            tags: vec!["daily".into()],
+            ..Selection::default()
+        };
+        let r = retention_report(store.path(), &both).unwrap();
+        assert_eq!(r.candidates.len(), 2);
+        assert_eq!(r.candidates[0].reclaimable, 46205);
+        assert_eq!((r.orphaned_chunks, r.reclaimable), (3, 46205 + 18628));
+        fs::write(store.path().join("Broken.rev"), "uuid: [").unwrap();
+        assert!(matches!(
+            retention_report(store.path(), &both),
+            Err(Error::Damaged(..))
+        ));
+    }
+
+    #[test]
+    fn csv_output() {
+        let store = store_tar();
+        let mut out = Vec::new();
+        write_csv(&list(store.path()).unwrap(), &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        assert_eq!(
+            csv.lines().nth(1).unwrap(),
+            "VNzWKjnMqd6w58nzJwUZ98,2019-01-11T17:45:25.666942+00:00,16777216,daily,trusted,,2,8388608"
+        );
+    }
+}
@@ -0,0 +1,102 @@
+//! Parsed revision specification.
+
+use crate::chunkvec::ChunkVec;
+
+/// Chunk map of a revision, parsed and validated once.
+///
+/// Returned by [Extractor::spec](struct.Extractor.html#method.spec) to inspect what a restore
+/// is going to do before running it. The image consists of `len()` chunks of
+/// [CHUNKSZ](constant.CHUNKSZ.html) bytes. Chunk `seq` starts at offset `seq * CHUNKSZ`.
+/// Positions which no chunk is mapped to are zero.
+#[derive(Debug, Clone)]
+pub struct RevisionSpec {
+    revision: String,
+    chunks: ChunkVec,
+}
+
+impl RevisionSpec {
+    pub(crate) fn new(revision: String, chunks: ChunkVec) -> Self {
+        Self { revision, chunks }
+    }
+
+    /// Revision ID
+    pub fn revision(&self) -> &str {
+        &self.revision
+    }
+
+    /// Image size in bytes
+    pub fn size(&self) -> u64 {
+        self.chunks.size
+    }
+
+    /// Number of chunk positions in the image
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// True for images of size 0
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of distinct chunks which have to be loaded from the store
+    pub fn unique(&self) -> usize {
+        self.chunks.unique()
+    }
+
+    /// Number of positions which are not mapped to any chunk
+    pub fn zero_chunks(&self) -> usize {
+        self.chunks.zero_seqs().len()
+    }
+
+    /// Distinct chunk IDs in ascending order, each with the positions it is mapped to in
+    /// ascending order.
+    pub fn chunks(&self) -> impl Iterator<Item = (&str, &[u32])> {
+        self.chunks
+            .entries()
+            .map(|(id, seqs)| (id.as_str(), &seqs[..]))
+    }
+
+    /// All positions of the image in order with the ID of the chunk mapped there, None for zero
+    /// chunks.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, Option<&str>)> {
+        let mut at = vec![None; self.len()];
+        for (id, seqs) in self.chunks() {
+            for &seq in seqs {
+                at[seq as usize] = Some(id);
+            }
+        }
+        at.into_iter().enumerate().map(|(seq, id)| (seq as u32, id))
+    }
+
+    pub(crate) fn chunk_vec(&self) -> &ChunkVec {
+        &self.chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inspect_spec() {
+        let map = r#"{"mapping": {"0": "a1", "3": "a1", "1": "b2"}, "size": 20971520}"#;
+        let spec = RevisionSpec::new("rev".into(), ChunkVec::decode(map).unwrap());
+        assert_eq!((spec.size(), spec.len(), spec.unique()), (20 << 20, 5, 2));
+        assert_eq!(spec.zero_chunks(), 2);
+        assert_eq!(
+            spec.chunks().collect::<Vec<_>>(),
+            vec![("a1", &[0, 3][..]), ("b2", &[1][..])]
+        );
+        assert_eq!(
+            spec.iter().collect::<Vec<_>>(),
+            vec![
+                (0, Some("a1")),
+                (1, Some("b2")),
+                (2, None),
+                (3, Some("a1")),
+                (4, None)
+            ]
+        );
+    }
+}
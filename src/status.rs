@@ -0,0 +1,260 @@
+//! Polling interface for restore progress.
+//!
+//! The restore pipeline updates atomic counters owned by the `Extractor`. Other threads take
+//! snapshots of them as [RestoreStatus](struct.RestoreStatus.html) at any time, without
+//! registering callbacks. Counters are read one by one, so a snapshot may mix values from
+//! slightly different points in time.
+
+use crate::progress::ProgressSink;
+use crate::{error_chain, ExtractError};
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Stage of a restore as seen by [Extractor::status](struct.Extractor.html#method.status).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// No restore has been started yet
+    Idle = 0,
+    /// Loading the chunk map and preparing the target
+    Loading,
+    /// Chunks are being decompressed and written
+    Restoring,
+    /// The last restore has completed successfully
+    Finished,
+    /// The last restore has failed
+    Failed,
+}
+
+impl Phase {
+    fn from_u8(n: u8) -> Self {
+        match n {
+            1 => Phase::Loading,
+            2 => Phase::Restoring,
+            3 => Phase::Finished,
+            4 => Phase::Failed,
+            _ => Phase::Idle,
+        }
+    }
+}
+
+/// Snapshot of the current (or last) restore of an `Extractor`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RestoreStatus {
+    pub phase: Phase,
+    /// Bytes written so far
+    pub bytes: u64,
+    /// Image size, 0 until the chunk map has been loaded
+    pub total_bytes: u64,
+    /// Unique chunks written so far
+    pub chunks: u64,
+    /// Unique chunks in the image, 0 until the chunk map has been loaded
+    pub total_chunks: u64,
+    /// Time since the restore has been started, frozen once it is over
+    pub elapsed: Duration,
+    /// Number of pipeline stages which have failed so far
+    pub errors: u64,
+    /// Description of the first error
+    pub error: Option<String>,
+}
+
+impl RestoreStatus {
+    /// Average write rate in bytes per second.
+    pub fn rate(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            t if t > 0.0 => self.bytes as f64 / t,
+            _ => 0.0,
+        }
+    }
+
+    /// Estimated time until the restore is complete, based on the average rate. None if there is
+    /// no estimate (yet).
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        if self.phase != Phase::Restoring || rate <= 0.0 {
+            return None;
+        }
+        let left = self.total_bytes.saturating_sub(self.bytes) as f64;
+        Some(Duration::from_secs_f64(left / rate))
+    }
+}
+
+/// Shared state behind `Extractor::status`.
+#[derive(Debug, Default)]
+pub(crate) struct Tracker {
+    phase: AtomicU8,
+    bytes: AtomicU64,
+    total_bytes: AtomicU64,
+    chunks: AtomicU64,
+    total_chunks: AtomicU64,
+    errors: AtomicU64,
+    /// Start of the current restore and its duration once it is over
+    times: Mutex<(Option<Instant>, Option<Duration>)>,
+    error: Mutex<Option<String>>,
+}
+
+impl Tracker {
+    fn set_phase(&self, phase: Phase) {
+        self.phase.store(phase as u8, Ordering::Release);
+    }
+
+    /// Resets all counters for a new restore.
+    pub(crate) fn begin(&self) {
+        for c in &[
+            &self.bytes,
+            &self.total_bytes,
+            &self.chunks,
+            &self.total_chunks,
+            &self.errors,
+        ] {
+            c.store(0, Ordering::Release);
+        }
+        *self.error.lock().expect("poisoned lock") = None;
+        *self.times.lock().expect("poisoned lock") = (Some(Instant::now()), None);
+        self.set_phase(Phase::Loading);
+    }
+
+    /// Notes a failed pipeline stage and passes its result on.
+    pub(crate) fn stage<T>(&self, res: Result<T, ExtractError>) -> Result<T, ExtractError> {
+        if let Err(e) = &res {
+            self.errors.fetch_add(1, Ordering::AcqRel);
+            self.error
+                .lock()
+                .expect("poisoned lock")
+                .get_or_insert_with(|| error_chain(e));
+        }
+        res
+    }
+
+    /// Marks the restore as over. The error is recorded unless a stage has reported one already.
+    pub(crate) fn end<T>(&self, res: &Result<T, ExtractError>) {
+        {
+            let mut times = self.times.lock().expect("poisoned lock");
+            times.1 = times.0.map(|t| t.elapsed());
+        }
+        match res {
+            Ok(_) => self.set_phase(Phase::Finished),
+            Err(e) => {
+                let mut error = self.error.lock().expect("poisoned lock");
+                if error.is_none() {
+                    *error = Some(error_chain(e));
+                    self.errors.fetch_add(1, Ordering::AcqRel);
+                }
+                self.set_phase(Phase::Failed);
+            }
+        }
+    }
+
+    pub(crate) fn status(&self) -> RestoreStatus {
+        let elapsed = match *self.times.lock().expect("poisoned lock") {
+            (_, Some(d)) => d,
+            (Some(t), None) => t.elapsed(),
+            (None, None) => Duration::default(),
+        };
+        RestoreStatus {
+            phase: Phase::from_u8(self.phase.load(Ordering::Acquire)),
+            bytes: self.bytes.load(Ordering::Acquire),
+            total_bytes: self.total_bytes.load(Ordering::Acquire),
+            chunks: self.chunks.load(Ordering::Acquire),
+            total_chunks: self.total_chunks.load(Ordering::Acquire),
+            elapsed,
+            errors: self.errors.load(Ordering::Acquire),
+            error: self.error.lock().expect("poisoned lock").clone(),
+        }
+    }
+}
+
+impl ProgressSink for Tracker {
+    fn start(&self, total: u64) {
+        self.total_bytes.store(total, Ordering::Release);
+        self.set_phase(Phase::Restoring);
+    }
+
+    fn chunks_total(&self, total: u64) {
+        self.total_chunks.store(total, Ordering::Release);
+    }
+
+    fn advance(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::AcqRel);
+    }
+
+    fn advance_chunks(&self, chunks: u64) {
+        self.chunks.fetch_add(chunks, Ordering::AcqRel);
+    }
+}
+
+/// Feeds the tracker along with the user-visible sink.
+pub(crate) struct Tee<'a> {
+    pub sink: &'a dyn ProgressSink,
+    pub tracker: &'a Tracker,
+}
+
+impl ProgressSink for Tee<'_> {
+    fn start(&self, total: u64) {
+        self.tracker.start(total);
+        self.sink.start(total);
+    }
+
+    fn chunks_total(&self, total: u64) {
+        self.tracker.chunks_total(total);
+        self.sink.chunks_total(total);
+    }
+
+    fn advance(&self, bytes: u64) {
+        self.tracker.advance(bytes);
+        self.sink.advance(bytes);
+    }
+
+    fn advance_chunks(&self, chunks: u64) {
+        self.tracker.advance_chunks(chunks);
+        self.sink.advance_chunks(chunks);
+    }
+
+    fn finish(&self) {
+        self.sink.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_restore() {
+        let t = Tracker::default();
+        assert_eq!(t.status().phase, Phase::Idle);
+        t.begin();
+        assert_eq!(t.status().phase, Phase::Loading);
+        t.start(1000);
+        t.chunks_total(4);
+        t.advance(250);
+        t.advance_chunks(1);
+        let st = t.status();
+        assert_eq!(st.phase, Phase::Restoring);
+        assert_eq!(
+            (st.bytes, st.total_bytes, st.chunks, st.total_chunks),
+            (250, 1000, 1, 4)
+        );
+        let st = RestoreStatus {
+            elapsed: Duration::from_secs(10),
+            ..st
+        };
+        assert!((st.rate() - 25.0).abs() < 1e-9);
+        assert_eq!(st.eta(), Some(Duration::from_secs(30)));
+        t.stage::<()>(Err(ExtractError::NoPartition(3))).ok();
+        t.stage::<()>(Err(ExtractError::NoPartition(4))).ok();
+        t.end::<()>(&Err(ExtractError::NoPartition(3)));
+        let st = t.status();
+        assert_eq!(st.phase, Phase::Failed);
+        assert_eq!(st.errors, 2);
+        assert_eq!(st.error.as_deref(), Some("Partition 3 not found"));
+        assert_eq!(st.eta(), None);
+        // elapsed time is frozen
+        assert_eq!(t.status().elapsed, st.elapsed);
+        t.begin();
+        assert_eq!((t.status().bytes, t.status().errors), (0, 0));
+    }
+}
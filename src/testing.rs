@@ -0,0 +1,195 @@
+//! Synthetic chunk stores for tests.
+//!
+//! [StoreBuilder](struct.StoreBuilder.html) writes complete backy stores (chunk files, revision
+//! maps and `.rev` files) with arbitrary sizes, holes and duplicate chunks. Stores can be
+//! damaged afterwards with [corrupt](fn.corrupt.html) to exercise error paths.
+
+use crate::backend::{self, Backend, Fsync};
+use crate::chunkvec::RevisionMap;
+use crate::{CHUNKSZ, CHUNKSZ_LOG};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Contents of a single chunk slot. `None` denotes a hole (zero chunk).
+pub type ChunkData = Option<Vec<u8>>;
+
+/// Creates chunk-sized, non-zero test data which differs for each `seed`.
+pub fn pattern(seed: u8) -> Vec<u8> {
+    (0..CHUNKSZ)
+        .map(|i| (i as u8).wrapping_mul(seed | 1) ^ seed.rotate_left((i >> 12) as u32))
+        .collect()
+}
+
+/// Image layout element used to describe revisions in a compact way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    /// Unmapped chunk which restores as zeros
+    Hole,
+    /// Chunk with contents created by `pattern(seed)`
+    Data(u8),
+    /// Copy of the chunk in slot n (which must be a preceding slot). Holes are copied as holes.
+    Dup(usize),
+}
+
+/// Expands a slot layout into chunk data.
+pub fn layout(slots: &[Slot]) -> Vec<ChunkData> {
+    let mut chunks: Vec<ChunkData> = Vec::with_capacity(slots.len());
+    for slot in slots {
+        let c = match *slot {
+            Slot::Hole => None,
+            Slot::Data(seed) => Some(pattern(seed)),
+            Slot::Dup(n) => chunks[n].clone(),
+        };
+        chunks.push(c);
+    }
+    chunks
+}
+
+/// Returns the image which a correct restore of `chunks` produces.
+pub fn image(chunks: &[ChunkData]) -> Vec<u8> {
+    let mut img = Vec::with_capacity(chunks.len() << CHUNKSZ_LOG);
+    for c in chunks {
+        match c {
+            Some(data) => img.extend_from_slice(data),
+            None => img.resize(img.len() + CHUNKSZ, 0),
+        }
+    }
+    img
+}
+
+/// Splits an image into chunks. Chunks which contain only zeros become holes. The image is
+/// padded with zeros to a multiple of CHUNKSZ.
+pub fn chunked(image: &[u8]) -> Vec<ChunkData> {
+    image
+        .chunks(CHUNKSZ)
+        .map(|c| {
+            if c.iter().all(|&b| b == 0) {
+                None
+            } else {
+                let mut data = c.to_vec();
+                data.resize(CHUNKSZ, 0);
+                Some(data)
+            }
+        })
+        .collect()
+}
+
+/// Returns the ID under which chunk data is stored.
+pub fn chunk_id(data: &[u8]) -> String {
+    backend::hash(data)
+}
+
+/// Path of the chunk file with `id` inside store `dir`.
+pub fn chunk_path<P: AsRef<Path>>(dir: P, id: &str) -> PathBuf {
+    dir.as_ref()
+        .join(format!("chunks/{}/{}.chunk.lzo", &id[0..2], id))
+}
+
+fn other<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Builds a chunk store with an arbitrary set of revisions.
+#[derive(Debug, Clone, Default)]
+pub struct StoreBuilder {
+    revisions: Vec<(String, Vec<ChunkData>)>,
+}
+
+impl StoreBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a revision. Each element of `chunks` must be either None or exactly CHUNKSZ bytes.
+    pub fn revision<S: Into<String>>(mut self, id: S, chunks: Vec<ChunkData>) -> Self {
+        self.revisions.push((id.into(), chunks));
+        self
+    }
+
+    /// Writes the store to `dir`, which must exist. Returns the paths of the revision maps in
+    /// the order the revisions have been added.
+    pub fn write<P: AsRef<Path>>(&self, dir: P) -> io::Result<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        // test stores need no durability
+        let be = Backend::create(dir)
+            .map_err(other)?
+            .with_fsync(Fsync::Never);
+        let mut maps = Vec::with_capacity(self.revisions.len());
+        for (rev, chunks) in &self.revisions {
+            fs::write(
+                dir.join(rev).with_extension("rev"),
+                format!(
+                    r#"backend_type: chunked
+parent: JZ3zfSHq24Fy5ENgTgYLGF
+stats:
+    bytes_written: {written}
+    ceph-verification: partial
+    chunk_stats: {{write_full: {nchunks}, write_partial: 0}}
+    duration: 216.60814833641052
+tags: [daily]
+timestamp: 2019-11-14 14:21:18.289+00:00
+trust: trusted
+uuid: {rev}
+"#,
+                    written = (chunks.len() + 1) << CHUNKSZ_LOG,
+                    nchunks = chunks.len(),
+                    rev = rev
+                ),
+            )?;
+            let mut map = RevisionMap {
+                size: (chunks.len() << CHUNKSZ_LOG) as u64,
+                mapping: Default::default(),
+            };
+            for (i, chunk) in chunks.iter().enumerate() {
+                if let Some(data) = chunk {
+                    let id = chunk_id(data);
+                    if !chunk_path(dir, &id).exists() {
+                        be.save(&id, data).map_err(other)?;
+                    }
+                    map.mapping.insert(i.to_string().into(), id.into());
+                }
+            }
+            let path = dir.join(rev);
+            serde_json::to_writer(File::create(&path)?, &map).map_err(other)?;
+            maps.push(path);
+        }
+        Ok(maps)
+    }
+}
+
+/// Ways to damage a chunk file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// Deletes the chunk file
+    Remove,
+    /// Truncates the chunk file to the given length
+    Truncate(u64),
+    /// Inverts a single byte at the given offset (modulo file size)
+    FlipByte(u64),
+}
+
+/// Damages the chunk file with `id` in store `dir`.
+pub fn corrupt<P: AsRef<Path>>(dir: P, id: &str, how: Corruption) -> io::Result<()> {
+    let path = chunk_path(dir, id);
+    if how == Corruption::Remove {
+        return fs::remove_file(path);
+    }
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+    let mut f = OpenOptions::new().read(true).write(true).open(&path)?;
+    match how {
+        Corruption::Truncate(len) => f.set_len(len)?,
+        Corruption::FlipByte(off) => {
+            let pos = off % f.metadata()?.len().max(1);
+            let mut b = [0];
+            f.seek(SeekFrom::Start(pos))?;
+            f.read_exact(&mut b)?;
+            f.seek(SeekFrom::Start(pos))?;
+            f.write_all(&[!b[0]])?;
+        }
+        Corruption::Remove => unreachable!(),
+    }
+    Ok(())
+}
@@ -0,0 +1,407 @@
+//! Continuous verification of chunks as they are written.
+//!
+//! A [Watch] monitors the chunk directories of a backup directory with inotify and verifies every
+//! chunk file once it has been written completely (closed after writing or renamed into place).
+//! Corruption introduced by the writing side is thus noticed right away instead of at the next
+//! damage report or restore. Verified chunks are added to the [hash cache](../struct.HashCache.html)
+//! so that damage reports can skip them. The cache file is written at most every
+//! `SAVE_INTERVAL` and when the watch is dropped.
+//!
+//! Failures are logged and counted. [prometheus] renders the counters for the node exporter's
+//! textfile collector. Only chunk files written on this host are noticed: inotify does not see
+//! changes made by other NFS clients.
+
+use crate::backend::{self, Backend, Fault};
+use crate::hashcache::HashCache;
+use crate::{error_chain, health};
+
+use log::{debug, error, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to open chunk store")]
+    Backend(#[from] backend::Error),
+    #[error("Failed to watch '{}'", .0.display())]
+    Watch(PathBuf, #[source] io::Error),
+    #[error("Failed to read inotify events")]
+    Read(#[source] io::Error),
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+const SUFFIX: &str = ".chunk.lzo";
+// Chunk directory events: new two-letter subdirectories
+const DIR_MASK: u32 = libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_ONLYDIR;
+// Subdirectory events: completely written chunk files
+const FILE_MASK: u32 = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+// Rewriting the whole cache file for every batch of chunks would cost more than verifying them
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counters of a running watch.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct WatchStats {
+    /// Chunk files which have passed verification
+    pub verified: u64,
+    /// Chunk files which have failed verification
+    pub failed: u64,
+    /// Time of the last failure (seconds since the epoch)
+    pub last_failure: Option<i64>,
+    /// Number of times the kernel has dropped events because they came in too fast
+    pub overflows: u64,
+}
+
+/// Result of verifying a single chunk file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Verified {
+    pub id: String,
+    /// Error message including all causes, None if the chunk is intact
+    pub error: Option<String>,
+    pub fault: Option<Fault>,
+}
+
+/// Watched chunk store, see the [module documentation](index.html).
+#[derive(Debug)]
+pub struct Watch {
+    dir: PathBuf,
+    be: Backend,
+    inotify: File,
+    /// Watch descriptor of `chunks/`
+    top: i32,
+    /// Watch descriptors of the chunk subdirectories
+    subdirs: HashMap<i32, PathBuf>,
+    cache: Option<HashCache>,
+    /// Verification results not written to the cache file yet
+    unsaved: bool,
+    last_save: Instant,
+    stats: WatchStats,
+}
+
+fn check(res: libc::c_int) -> io::Result<libc::c_int> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+fn add_watch(fd: &File, path: &Path, mask: u32) -> Result<i32> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::Watch(path.to_owned(), e.into()))?;
+    check(unsafe { libc::inotify_add_watch(fd.as_raw_fd(), cpath.as_ptr(), mask) })
+        .map_err(|e| Error::Watch(path.to_owned(), e))
+}
+
+// Chunk ID of a chunk file name, None for other files
+fn chunk_id(name: &OsStr) -> Option<&str> {
+    name.to_str()?.strip_suffix(SUFFIX)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+impl Watch {
+    /// Starts watching the chunk store in the backup directory `dir`. Chunk files which exist
+    /// already are not verified.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        let be = Backend::open(&dir)?;
+        let fd = check(unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) })
+            .map_err(|e| Error::Watch(dir.clone(), e))?;
+        let inotify = unsafe { File::from_raw_fd(fd) };
+        let chunks = dir.join("chunks");
+        let top = add_watch(&inotify, &chunks, DIR_MASK)?;
+        let mut w = Self {
+            dir,
+            be,
+            inotify,
+            top,
+            subdirs: HashMap::new(),
+            cache: None,
+            unsaved: false,
+            last_save: Instant::now(),
+            stats: WatchStats::default(),
+        };
+        let entries = fs::read_dir(&chunks).map_err(|e| Error::Watch(chunks.clone(), e))?;
+        for e in entries.flatten() {
+            if e.file_type().is_ok_and(|t| t.is_dir()) && e.file_name().len() == 2 {
+                w.add_subdir(&e.path())?;
+            }
+        }
+        Ok(w)
+    }
+
+    /// Adds verified chunks to the store's hash cache (see
+    /// [Extractor::hash_cache](../struct.Extractor.html#method.hash_cache)).
+    pub fn hash_cache(mut self, enable: bool) -> Self {
+        self.cache = match enable {
+            true => Some(HashCache::open(self.dir.join("chunks/hashcache"))),
+            false => None,
+        };
+        self
+    }
+
+    /// Backup directory being watched.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn stats(&self) -> &WatchStats {
+        &self.stats
+    }
+
+    fn add_subdir(&mut self, path: &Path) -> Result<i32> {
+        let wd = add_watch(&self.inotify, path, FILE_MASK)?;
+        self.subdirs.insert(wd, path.to_owned());
+        Ok(wd)
+    }
+
+    /// Waits up to `timeout` for chunk files to be written and verifies them. Returns the
+    /// results, which may be none at all.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Vec<Verified>> {
+        let mut pfd = libc::pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        match check(unsafe { libc::poll(&mut pfd, 1, ms) }) {
+            Ok(0) => return Ok(Vec::new()),
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(Vec::new()),
+            Err(e) => return Err(Error::Read(e)),
+        }
+        let mut buf = vec![0; 64 << 10];
+        let mut files = Vec::new();
+        loop {
+            let n = match self.inotify.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::Read(e)),
+            };
+            self.parse(&buf[..n], &mut files)?;
+        }
+        files.sort();
+        files.dedup();
+        let res: Vec<_> = files
+            .iter()
+            .map(|(id, path)| self.verify(id, path))
+            .collect();
+        self.unsaved |= !res.is_empty();
+        if self.last_save.elapsed() >= SAVE_INTERVAL {
+            self.save_cache();
+        }
+        Ok(res)
+    }
+
+    /// Writes pending hash cache entries. Also done on drop.
+    pub fn save_cache(&mut self) {
+        if let (Some(c), true) = (&self.cache, self.unsaved) {
+            if let Err(e) = c.save() {
+                warn!("Failed to write hash cache: {}", e);
+            }
+        }
+        self.unsaved = false;
+        self.last_save = Instant::now();
+    }
+
+    // Collects chunk files from a buffer full of inotify events
+    fn parse(&mut self, buf: &[u8], files: &mut Vec<(String, PathBuf)>) -> Result<()> {
+        let hdr = mem::size_of::<libc::inotify_event>();
+        let mut pos = 0;
+        while pos + hdr <= buf.len() {
+            let ev: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buf[pos..].as_ptr() as *const _) };
+            let name = &buf[pos + hdr..pos + hdr + ev.len as usize];
+            let name =
+                OsStr::from_bytes(&name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())]);
+            pos += hdr + ev.len as usize;
+            if ev.mask & libc::IN_Q_OVERFLOW != 0 {
+                warn!(
+                    "{}: inotify queue overflow, events lost",
+                    self.dir.display()
+                );
+                self.stats.overflows += 1;
+            } else if ev.wd == self.top && ev.mask & libc::IN_ISDIR != 0 && name.len() == 2 {
+                // files written before the watch is in place would go unnoticed
+                let path = self.dir.join("chunks").join(name);
+                self.add_subdir(&path)?;
+                for e in fs::read_dir(&path).into_iter().flatten().flatten() {
+                    if let Some(id) = chunk_id(&e.file_name()) {
+                        files.push((id.to_owned(), e.path()));
+                    }
+                }
+            } else if let (Some(dir), Some(id)) = (self.subdirs.get(&ev.wd), chunk_id(name)) {
+                files.push((id.to_owned(), dir.join(name)));
+            } else if ev.mask & libc::IN_IGNORED != 0 {
+                self.subdirs.remove(&ev.wd);
+            }
+        }
+        Ok(())
+    }
+
+    fn verify(&mut self, id: &str, path: &Path) -> Verified {
+        let stat = fs::metadata(path);
+        let res = self.be.load(id).and_then(|data| {
+            let actual = self.be.hash(&data);
+            if actual == id {
+                Ok(())
+            } else {
+                Err(backend::Error::Checksum(actual))
+            }
+        });
+        match res {
+            Ok(()) => {
+                debug!("{}: OK", path.display());
+                self.stats.verified += 1;
+                if let (Some(c), Ok(m)) = (&self.cache, &stat) {
+                    c.insert(id, m);
+                }
+                Verified {
+                    id: id.to_owned(),
+                    error: None,
+                    fault: None,
+                }
+            }
+            // purged again before we got to it
+            Err(backend::Error::NotFound(_)) if stat.is_err() => {
+                debug!("{}: vanished", path.display());
+                Verified {
+                    id: id.to_owned(),
+                    error: None,
+                    fault: None,
+                }
+            }
+            Err(e) => {
+                let msg = error_chain(&e);
+                error!("{}: {}", path.display(), msg);
+                self.stats.failed += 1;
+                self.stats.last_failure = Some(now());
+                if let Some(c) = &self.cache {
+                    c.remove(id);
+                }
+                Verified {
+                    id: id.to_owned(),
+                    error: Some(msg),
+                    fault: e.fault(),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.save_cache();
+    }
+}
+
+/// Renders the counters of `watch` in the Prometheus text exposition format.
+pub fn prometheus(watch: &Watch) -> String {
+    let dir = health::label(&watch.dir.to_string_lossy());
+    let s = &watch.stats;
+    let mut out = String::new();
+    let mut metric = |name, help, kind, value: Option<f64>| {
+        writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+        if let Some(v) = value {
+            writeln!(out, "{}{{dir=\"{}\"}} {}", name, dir, v).unwrap();
+        }
+    };
+    metric(
+        "backy_watch_chunks_verified_total",
+        "Number of chunk files verified since the watch started",
+        "counter",
+        Some(s.verified as f64),
+    );
+    metric(
+        "backy_watch_chunks_failed_total",
+        "Number of chunk files which failed verification",
+        "counter",
+        Some(s.failed as f64),
+    );
+    metric(
+        "backy_watch_last_failure_timestamp_seconds",
+        "Time of the last failed verification",
+        "gauge",
+        s.last_failure.map(|t| t as f64),
+    );
+    metric(
+        "backy_watch_overflows_total",
+        "Number of times inotify events have been lost",
+        "counter",
+        Some(s.overflows as f64),
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::store_tar;
+
+    // Polls until `n` results have come in
+    fn collect(w: &mut Watch, n: usize) -> Vec<Verified> {
+        let mut res = Vec::new();
+        for _ in 0..50 {
+            res.extend(w.poll(Duration::from_millis(100)).unwrap());
+            if res.len() >= n {
+                break;
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn verify_written_chunks() {
+        let store = store_tar();
+        let chunks = store.path().join("chunks");
+        let mut w = Watch::new(store.path()).unwrap().hash_cache(true);
+        assert!(w.poll(Duration::from_millis(10)).unwrap().is_empty());
+
+        // renamed into place
+        let good = chunks.join("4d/4db6e194fd398e8edb76e11054d73eb0.chunk.lzo");
+        let tmp = chunks.join("4d/tmp");
+        fs::copy(&good, &tmp).unwrap();
+        fs::rename(&tmp, &good).unwrap();
+        let res = collect(&mut w, 1);
+        assert_eq!(res.len(), 1, "{:?}", res);
+        assert_eq!(res[0].id, "4db6e194fd398e8edb76e11054d73eb0");
+        assert_eq!(res[0].error, None);
+
+        // written into a new subdirectory, with contents not matching the ID
+        fs::create_dir(chunks.join("ff")).unwrap();
+        fs::copy(
+            &good,
+            chunks.join("ff/ff000000000000000000000000000000.chunk.lzo"),
+        )
+        .unwrap();
+        let res = collect(&mut w, 1);
+        assert_eq!(res.len(), 1, "{:?}", res);
+        assert_eq!(res[0].fault, Some(Fault::Corrupt));
+        assert_eq!(w.stats().verified, 1);
+        assert_eq!(w.stats().failed, 1);
+
+        let out = prometheus(&w);
+        assert!(out.contains("backy_watch_chunks_failed_total{dir="));
+        assert!(out.contains("# TYPE backy_watch_chunks_verified_total counter\n"));
+        // written on drop at the latest
+        assert!(!chunks.join("hashcache").exists());
+        drop(w);
+        assert_eq!(HashCache::open(chunks.join("hashcache")).len(), 1);
+    }
+}
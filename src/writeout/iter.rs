@@ -0,0 +1,124 @@
+use super::stream::in_order;
+use super::{Error, Reorder, ReorderStats, Result, WriteOut, WriteOutBuilder};
+use crate::{chunk2pos, Chunk, Data, ExtractError, ExtractReport, Extractor, Progress, CHUNKSZ};
+
+use crossbeam::channel::{bounded, never, Receiver, Sender};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+// Positions handed over, but not yet taken by the consumer. Everything beyond is held back in
+// the reorder queue, which throttles the decoders.
+const HANDOVER: usize = 2;
+
+/// Decompressed image contents in sequence order, as returned by
+/// [Extractor::chunks](struct.Extractor.html#method.chunks).
+///
+/// Each item is the offset of a chunk position in the image together with its data. The restore
+/// runs in a background thread with the usual pipeline and stays at most a few chunks ahead of
+/// the consumer. If it fails, the error is the last item. Dropping the iterator early cancels the
+/// restore.
+pub struct ChunkIter {
+    rx: Receiver<(u64, Data)>,
+    worker: Option<JoinHandle<Result<ExtractReport, ExtractError>>>,
+}
+
+impl ChunkIter {
+    pub(crate) fn spawn(e: Extractor) -> Self {
+        let (tx, rx) = bounded(HANDOVER);
+        let worker = thread::Builder::new()
+            .name("chunk-iter".into())
+            .spawn(move || e.extract(ChunkSender::new(tx)))
+            .expect("failed to spawn restore thread");
+        Self {
+            rx,
+            worker: Some(worker),
+        }
+    }
+}
+
+impl Iterator for ChunkIter {
+    type Item = Result<(u64, Data), ExtractError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Ok(item) = self.rx.recv() {
+            return Some(Ok(item));
+        }
+        match self.worker.take()?.join() {
+            Ok(Ok(_)) => None,
+            Ok(Err(e)) => Some(Err(e)),
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+impl Drop for ChunkIter {
+    fn drop(&mut self) {
+        // hanging up makes the writer fail, which tears down the pipeline
+        drop(std::mem::replace(&mut self.rx, never()));
+        if let Some(worker) = self.worker.take() {
+            worker.join().ok();
+        }
+    }
+}
+
+impl fmt::Debug for ChunkIter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<ChunkIter>")
+    }
+}
+
+/// Writer which hands positions over to a [ChunkIter](struct.ChunkIter.html).
+pub(crate) struct ChunkSender {
+    tx: Sender<(u64, Data)>,
+    reorder: Arc<Reorder>,
+}
+
+impl ChunkSender {
+    fn new(tx: Sender<(u64, Data)>) -> Self {
+        Self {
+            tx,
+            reorder: Arc::default(),
+        }
+    }
+}
+
+impl WriteOutBuilder for ChunkSender {
+    type Impl = Self;
+
+    fn build(self, _size: u64, _threads: u8) -> Self::Impl {
+        self
+    }
+}
+
+impl WriteOut for ChunkSender {
+    fn receive(&mut self, chunks: Receiver<Chunk>, progress: Progress) -> Result<()> {
+        let mut stats = ReorderStats::default();
+        let reorder = Arc::clone(&self.reorder);
+        let tx = &self.tx;
+        let res = in_order(chunks, &reorder, &progress, &mut stats, |d, seq| {
+            // copies only chunks which are mapped to further positions
+            let data = Rc::try_unwrap(d).unwrap_or_else(|d| (*d).clone());
+            tx.send((chunk2pos(seq), data)).map_err(|_| Error::Hangup)?;
+            progress.add(CHUNKSZ);
+            Ok(())
+        });
+        self.reorder.finish(stats);
+        res.map(|_| ())
+    }
+
+    fn reorder(&self) -> Option<Arc<Reorder>> {
+        Some(Arc::clone(&self.reorder))
+    }
+
+    fn name(&self) -> String {
+        "iterator".to_owned()
+    }
+}
+
+impl fmt::Debug for ChunkSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<ChunkSender>")
+    }
+}
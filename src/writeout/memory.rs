@@ -0,0 +1,94 @@
+use super::{Error, Result, WriteOut, WriteOutBuilder};
+use crate::{chunk2pos, Chunk, Data, Progress, CHUNKSZ, CHUNKSZ_LOG};
+
+use crossbeam::channel::Receiver;
+use std::fmt;
+
+enum Buf<'a> {
+    Vec(&'a mut Vec<u8>),
+    Slice(&'a mut [u8]),
+}
+
+/// In-memory restore target.
+///
+/// Chunks are copied to their final position as they arrive, so unlike
+/// [Stream](struct.Stream.html) no reordering is necessary. Useful for embedding and tests which
+/// should not touch the file system.
+pub struct Memory<'a> {
+    buf: Buf<'a>,
+}
+
+impl<'a> Memory<'a> {
+    /// Restores into `vec`, which is resized to the image size. Previous contents are lost.
+    pub fn new(vec: &'a mut Vec<u8>) -> Self {
+        Self { buf: Buf::Vec(vec) }
+    }
+
+    /// Restores into a caller-provided buffer, e.g. a memory map. The restore fails if `buf` is
+    /// smaller than the image. Bytes beyond the image size are left alone.
+    pub fn slice(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf: Buf::Slice(buf),
+        }
+    }
+}
+
+impl<'a> WriteOutBuilder for Memory<'a> {
+    type Impl = MemoryWriteOut<'a>;
+
+    fn build(self, size: u64, _threads: u8) -> Self::Impl {
+        MemoryWriteOut {
+            buf: self.buf,
+            size,
+        }
+    }
+}
+
+pub struct MemoryWriteOut<'a> {
+    buf: Buf<'a>,
+    size: u64,
+}
+
+impl WriteOut for MemoryWriteOut<'_> {
+    fn receive(&mut self, chunks: Receiver<Chunk>, progress: Progress) -> Result<()> {
+        let size = self.size as usize;
+        // zero chunks need to be written only if the buffer has not been cleared
+        let (out, zeroed) = match &mut self.buf {
+            Buf::Vec(v) => {
+                v.clear();
+                v.resize(size, 0);
+                (&mut v[..], true)
+            }
+            Buf::Slice(s) if s.len() >= size => (&mut s[..size], false),
+            Buf::Slice(s) => {
+                // let decoders finish so that this error gets reported instead of theirs
+                chunks.iter().for_each(drop);
+                return Err(Error::BufferTooSmall(self.size, s.len()));
+            }
+        };
+        for chunk in chunks {
+            for &seq in &chunk.seqs {
+                let pos = chunk2pos(seq) as usize;
+                let dest = &mut out[pos..pos + CHUNKSZ];
+                match &chunk.data {
+                    Data::Some(data) => dest.copy_from_slice(data),
+                    Data::Zero if !zeroed => dest.iter_mut().for_each(|b| *b = 0),
+                    Data::Zero => (),
+                }
+            }
+            progress.add(chunk.seqs.len() << CHUNKSZ_LOG);
+            progress.add_chunks(1);
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        "memory".to_owned()
+    }
+}
+
+impl fmt::Debug for MemoryWriteOut<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<MemoryWriteOut {} bytes>", self.size)
+    }
+}
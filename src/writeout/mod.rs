@@ -1,14 +1,34 @@
+mod iter;
+mod memory;
+mod nbd;
 mod randomaccess;
+mod rbd;
 mod stream;
+#[cfg(feature = "tus")]
+mod tus;
+mod vhd;
 
-pub use self::randomaccess::RandomAccess;
-pub use self::stream::Stream;
-use crate::Chunk;
+pub use self::iter::ChunkIter;
+pub use self::memory::Memory;
+pub use self::nbd::Nbd;
+pub use self::randomaccess::{Prealloc, RandomAccess};
+pub use self::rbd::{RbdDiff, RbdDiffWriter};
+pub use self::stream::{Reorder, ReorderStats, Stream, StreamHeader};
+#[cfg(feature = "tus")]
+pub use self::tus::Tus;
+pub use self::vhd::Vhd;
+use crate::{chunk2pos, Chunk, Progress, CHUNKSZ_LOG};
 
-use crossbeam::channel::{Receiver, SendError, Sender};
-use std::fmt::Debug;
-use std::io;
-use std::path::PathBuf;
+use crossbeam::channel::Receiver;
+use log::warn;
+use std::fmt::{self, Debug};
+use std::fs::{self, File};
+use std::io::{self, IoSlice};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,10 +37,41 @@ pub enum Error {
     OutputFile(PathBuf, #[source] io::Error),
     #[error("Failed to write chunk #{0}")]
     WriteChunk(u32, #[source] io::Error),
+    #[error("Failed to read chunk #{0} from target")]
+    ReadChunk(u32, #[source] io::Error),
     #[error("Failed to write chunk #{} to `{}'", .0, .1.display())]
     WriteChunkFile(u32, PathBuf, #[source] io::Error),
-    #[error("IPC error")]
-    ChannelSend(#[from] SendError<usize>),
+    #[error("Image of {0} bytes does not fit into buffer of {1} bytes")]
+    BufferTooSmall(u64, usize),
+    #[error("Restore incomplete: chunk #{0} has not been received")]
+    Incomplete(u32),
+    #[error("Unknown preallocation mode '{0}' (expected none, truncate, fallocate or full)")]
+    Prealloc(String),
+    #[error("Failed to move `{}' into place as `{}'", .0.display(), .1.display())]
+    Rename(PathBuf, PathBuf, #[source] io::Error),
+    #[error("Invalid NBD URL '{0}' (expected nbd://HOST[:PORT]/EXPORT or nbd+unix:///EXPORT?socket=PATH)")]
+    NbdUrl(String),
+    #[error("NBD connection to {0} failed")]
+    Nbd(String, #[source] io::Error),
+    #[error(
+        "Invalid tus URL '{0}' (expected tus+https://HOST/ENDPOINT or tus+http://HOST/ENDPOINT)"
+    )]
+    TusUrl(String),
+    #[error("Upload to {0} failed")]
+    Tus(String, #[source] io::Error),
+    #[error("NBD export {0} has {1} bytes, but the image needs {2}")]
+    ExportSize(String, u64, u64),
+    #[error("Failed to write stream header")]
+    Header(#[source] io::Error),
+    #[error("Restore incomplete: {}", Regions(.0))]
+    Partial(Vec<FailedRange>),
+    #[error("Chunk consumer has gone away")]
+    Hangup,
+    #[error(
+        "Sparse restore left data in {} region(s) which should be zero, starting with {}..{}",
+        .0.len(), .0[0].start, .0[0].end
+    )]
+    NotZero(Vec<Range<u64>>),
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -32,18 +83,457 @@ type Result<T, E = Error> = std::result::Result<T, E>;
 /// invoking `build` to get the final WriteOut object.
 pub trait WriteOutBuilder {
     type Impl: WriteOut + Sync + Send;
+    /// `threads` is the number of writer threads requested. Writers which cannot write in
+    /// parallel ignore it.
     fn build(self, total_size: u64, threads: u8) -> Self::Impl;
 }
 
+/// Summary of a successful restore, passed to [WriteOut::finalize].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteReport {
+    /// Image size in bytes
+    pub size: u64,
+    /// Bytes written to the target
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
 /// Abstract writeout (restore) plugin.
 ///
-/// A concrete writer is instantiated via `WriteOutBuilder.build()`.
+/// A concrete writer is instantiated via `WriteOutBuilder.build()`. Its life cycle is
+/// `prepare`, `receive` and then either `finalize` if the whole restore has succeeded or `abort`
+/// otherwise. `prepare` and `receive` run in the writer thread, the others in the thread which
+/// has started the restore.
 pub trait WriteOut: Debug {
+    /// Sets up the target before any chunk is received, e.g. opens files or writes headers. If
+    /// this fails, no chunks are received and the restore is aborted.
+    fn prepare(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Gets an unordered stream of `Chunk`s which must be written to the restore target according
-    /// to the chunks' sequence numbers. Writer must send the number of bytes written to the
-    /// `progress` channel to indicate restore progress in real time.
-    fn receive(self, chunks: Receiver<Chunk>, progress: Sender<usize>) -> Result<()>;
+    /// to the chunks' sequence numbers. Writer must report the number of bytes written to
+    /// `progress` to indicate restore progress in real time. Each chunk is reported with
+    /// `add_chunks` once it has been written to all of its positions.
+    fn receive(&mut self, chunks: Receiver<Chunk>, progress: Progress) -> Result<()>;
+
+    /// Completes the target after all pipeline stages have succeeded, e.g. writes trailers or
+    /// moves a temporary file into place. The restore fails if this fails.
+    fn finalize(&mut self, _report: &WriteReport) -> Result<()> {
+        Ok(())
+    }
+
+    /// Cleans up after a failed restore, e.g. removes partial output. `error` is the reason the
+    /// restore has failed, which may have happened in any pipeline stage.
+    fn abort(&mut self, _error: &dyn std::error::Error) {}
+
+    /// Writers which must put chunks into sequence order before writing return their queue
+    /// state here. It is used to steer decoders and to collect statistics.
+    fn reorder(&self) -> Option<Arc<Reorder>> {
+        None
+    }
+
+    /// Positions which the writer leaves alone, e.g. because the target has them already. Their
+    /// chunks are not loaded and they are passed to `receive` as zero chunks.
+    fn skip(&self) -> &[u32] {
+        &[]
+    }
+
+    /// Where the restored image ends up once `finalize` has succeeded, for reports. Differs from
+    /// `name` for writers which restore into a temporary file.
+    fn target(&self) -> String {
+        self.name()
+    }
+
+    /// Optional scatter-gather interface: writes `bufs` back to back to the restore target,
+    /// starting at `offset`. Returns the number of bytes written, which may be less than the total
+    /// length of `bufs`. Writers use this to place a chunk at several adjacent positions with a
+    /// single system call, usually through `write_all_v`. Targets without random access don't
+    /// support it.
+    fn write_at_v(&self, _bufs: &[IoSlice<'_>], _offset: u64) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
 
     /// Short idenfication for user display. Should contain plugin type and file name.
     fn name(&self) -> String;
 }
+
+/// Groups `seqs` into runs of adjacent sequence numbers. Returns (first seq, length) for each
+/// run in the order given.
+pub(crate) fn runs(seqs: &[u32]) -> Vec<(u32, usize)> {
+    let mut runs: Vec<(u32, usize)> = Vec::new();
+    for &seq in seqs {
+        match runs.last_mut() {
+            Some((first, len)) if u64::from(*first) + *len as u64 == u64::from(seq) => *len += 1,
+            _ => runs.push((seq, 1)),
+        }
+    }
+    runs
+}
+
+/// Writes all of `bufs` starting at `offset` using `out.write_at_v`, retrying after short
+/// writes.
+pub(crate) fn write_all_v<W: WriteOut + ?Sized>(
+    out: &W,
+    mut bufs: &[IoSlice<'_>],
+    mut offset: u64,
+) -> io::Result<()> {
+    // part of the first buffer which has been written already
+    let mut skip = 0;
+    while !bufs.is_empty() {
+        let n = if skip > 0 {
+            out.write_at_v(&[IoSlice::new(&bufs[0][skip..])], offset)?
+        } else {
+            out.write_at_v(bufs, offset)?
+        };
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        offset += n as u64;
+        let mut n = n + skip;
+        skip = 0;
+        while let Some(first) = bufs.first() {
+            if n < first.len() {
+                skip = n;
+                break;
+            }
+            n -= first.len();
+            bufs = &bufs[1..];
+        }
+    }
+    Ok(())
+}
+
+/// How writers deal with chunks which cannot be written. The default gives up on the first
+/// error.
+///
+/// Only transient errors are retried: EIO, ENOSPC and EDQUOT (e.g. until some space has been
+/// cleaned up), EAGAIN, EINTR and ETIMEDOUT. A chunk is always rewritten as a whole, so writers
+/// must place data at fixed offsets. [Stream](struct.Stream.html) ignores the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Retry {
+    /// Number of attempts after the first one has failed
+    pub attempts: u32,
+    /// Pause before the first retry, doubled for each further one
+    pub delay: Duration,
+    /// Goes on with the remaining chunks if a chunk cannot be written. The restore fails in the
+    /// end anyway, see [ExtractError::failed_ranges](enum.ExtractError.html#method.failed_ranges).
+    pub keep_going: bool,
+}
+
+impl Retry {
+    /// Retries up to `attempts` times, waiting 1s, 2s, 4s etc. in between.
+    pub fn new(attempts: u32) -> Self {
+        Self {
+            attempts,
+            delay: Duration::from_secs(1),
+            keep_going: false,
+        }
+    }
+
+    /// See [keep_going](#structfield.keep_going).
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Calls `write` until it succeeds, fails with a permanent error or runs out of attempts.
+    /// `what` describes the written region for log messages.
+    pub(crate) fn run<F>(&self, what: fmt::Arguments<'_>, mut write: F) -> io::Result<()>
+    where
+        F: FnMut() -> io::Result<()>,
+    {
+        let mut delay = self.delay;
+        for _ in 0..self.attempts {
+            match write() {
+                Err(e) if is_transient(&e) => {
+                    warn!("Failed to write {}, retrying in {:?}: {}", what, delay, e);
+                    thread::sleep(delay);
+                    delay *= 2;
+                }
+                res => return res,
+            }
+        }
+        write()
+    }
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EIO | libc::ENOSPC | libc::EDQUOT | libc::EAGAIN | libc::ETIMEDOUT)
+    ) || e.kind() == io::ErrorKind::Interrupted
+}
+
+/// Image region which could not be written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailedRange {
+    pub offset: u64,
+    pub len: u64,
+    /// Last error message
+    pub error: String,
+}
+
+impl fmt::Display for FailedRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}..{} ({})",
+            self.offset,
+            self.offset + self.len,
+            self.error
+        )
+    }
+}
+
+// Message of Error::Partial, naming the first few regions.
+struct Regions<'a>(&'a [FailedRange]);
+
+impl fmt::Display for Regions<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const SHOWN: usize = 10;
+        let bytes: u64 = self.0.iter().map(|r| r.len).sum();
+        write!(
+            f,
+            "failed to write {} bytes in {} region(s):",
+            bytes,
+            self.0.len()
+        )?;
+        for r in self.0.iter().take(SHOWN) {
+            write!(f, " {}", r)?;
+        }
+        if self.0.len() > SHOWN {
+            write!(f, " and {} more", self.0.len() - SHOWN)?;
+        }
+        Ok(())
+    }
+}
+
+/// Regions which writers have given up on while going on with other chunks.
+#[derive(Debug, Default)]
+pub(crate) struct Failures(Mutex<Vec<FailedRange>>);
+
+impl Failures {
+    /// Records that `n` chunks starting at `seq` could not be written.
+    pub(crate) fn add(&self, seq: u32, n: usize, size: u64, e: &io::Error) {
+        let offset = chunk2pos(seq);
+        let len = ((n as u64) << CHUNKSZ_LOG).min(size.saturating_sub(offset));
+        warn!("Giving up on bytes {}..{}: {}", offset, offset + len, e);
+        self.0.lock().unwrap().push(FailedRange {
+            offset,
+            len,
+            error: e.to_string(),
+        });
+    }
+
+    /// Fails with [Error::Partial] if anything has been recorded. Adjacent regions which have
+    /// failed for the same reason are merged.
+    pub(crate) fn check(&self) -> Result<()> {
+        let mut ranges = std::mem::take(&mut *self.0.lock().unwrap());
+        if ranges.is_empty() {
+            return Ok(());
+        }
+        ranges.sort_by_key(|r| r.offset);
+        let mut merged: Vec<FailedRange> = Vec::with_capacity(ranges.len());
+        for r in ranges {
+            match merged.last_mut() {
+                Some(last) if last.offset + last.len == r.offset && last.error == r.error => {
+                    last.len += r.len
+                }
+                _ => merged.push(r),
+            }
+        }
+        Err(Error::Partial(merged))
+    }
+}
+
+/// Pending move of a completely restored temporary file to its final name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    /// Fail instead of replacing an existing file at `to`
+    pub exclusive: bool,
+}
+
+impl Rename {
+    /// Flushes the temporary file and renames it. The directory entry is flushed as well so that
+    /// the final name never refers to incomplete contents after a crash. Exclusive renames are
+    /// done by linking, which fails atomically if the final name has been taken in the meantime.
+    pub fn commit(&self) -> Result<()> {
+        let err = |e| Error::Rename(self.from.clone(), self.to.clone(), e);
+        File::open(&self.from)
+            .and_then(|f| f.sync_all())
+            .map_err(err)?;
+        if self.exclusive {
+            if let Err(e) = fs::hard_link(&self.from, &self.to) {
+                // someone else got there first: the image is not going to be published
+                self.abort();
+                return Err(err(e));
+            }
+            fs::remove_file(&self.from).map_err(err)?;
+        } else {
+            fs::rename(&self.from, &self.to).map_err(err)?;
+        }
+        match self.to.parent() {
+            Some(dir) if dir != Path::new("") => {
+                File::open(dir).and_then(|d| d.sync_all()).map_err(err)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the temporary file after a failed restore.
+    pub fn abort(&self) {
+        fs::remove_file(&self.from).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn group_adjacent_seqs() {
+        assert_eq!(runs(&[]), vec![]);
+        assert_eq!(runs(&[5]), vec![(5, 1)]);
+        assert_eq!(
+            runs(&[0, 1, 2, 7, 8, 10, 3]),
+            vec![(0, 3), (7, 2), (10, 1), (3, 1)]
+        );
+        assert_eq!(runs(&[u32::MAX - 1, u32::MAX]), vec![(u32::MAX - 1, 2)]);
+    }
+
+    // Accepts at most 5 bytes per call
+    #[derive(Debug, Default)]
+    struct Short(RefCell<Vec<(u64, Vec<u8>)>>);
+
+    impl WriteOut for Short {
+        fn receive(&mut self, _: Receiver<Chunk>, _: Progress) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_at_v(&self, bufs: &[IoSlice<'_>], offset: u64) -> io::Result<usize> {
+            let data: Vec<u8> = bufs
+                .iter()
+                .flat_map(|b| b.iter())
+                .take(5)
+                .copied()
+                .collect();
+            let n = data.len();
+            self.0.borrow_mut().push((offset, data));
+            Ok(n)
+        }
+
+        fn name(&self) -> String {
+            "short".to_owned()
+        }
+    }
+
+    #[test]
+    fn write_all_v_retries_short_writes() {
+        let out = Short::default();
+        let bufs = [
+            IoSlice::new(b"abc"),
+            IoSlice::new(b"defghij"),
+            IoSlice::new(b""),
+            IoSlice::new(b"k"),
+        ];
+        write_all_v(&out, &bufs, 100).unwrap();
+        assert_eq!(
+            out.0.into_inner(),
+            vec![
+                (100, b"abcde".to_vec()),
+                (105, b"fghij".to_vec()),
+                (110, b"k".to_vec())
+            ]
+        );
+    }
+
+    // Records which life cycle methods have been called
+    #[derive(Debug, Default, Clone)]
+    struct Recorder {
+        calls: Arc<std::sync::Mutex<Vec<&'static str>>>,
+        fail_prepare: bool,
+    }
+
+    impl Recorder {
+        fn log(&self, call: &'static str) {
+            self.calls.lock().unwrap().push(call);
+        }
+    }
+
+    impl WriteOutBuilder for Recorder {
+        type Impl = Recorder;
+
+        fn build(self, _size: u64, _threads: u8) -> Self::Impl {
+            self
+        }
+    }
+
+    impl WriteOut for Recorder {
+        fn prepare(&mut self) -> Result<()> {
+            self.log("prepare");
+            if self.fail_prepare {
+                return Err(Error::BufferTooSmall(1, 0));
+            }
+            Ok(())
+        }
+
+        fn receive(&mut self, chunks: Receiver<Chunk>, progress: Progress) -> Result<()> {
+            self.log("receive");
+            for c in chunks {
+                progress.add(c.seqs.len() << crate::CHUNKSZ_LOG);
+            }
+            Ok(())
+        }
+
+        fn finalize(&mut self, report: &WriteReport) -> Result<()> {
+            assert_eq!(report.bytes, report.size);
+            self.log("finalize");
+            Ok(())
+        }
+
+        fn abort(&mut self, _error: &dyn std::error::Error) {
+            self.log("abort");
+        }
+
+        fn name(&self) -> String {
+            "recorder".to_owned()
+        }
+    }
+
+    #[test]
+    fn life_cycle() {
+        let store = crate::test_helper::store_tar();
+        let e = crate::Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98")).unwrap();
+        let ok = Recorder::default();
+        e.extract(ok.clone()).unwrap();
+        assert_eq!(
+            *ok.calls.lock().unwrap(),
+            &["prepare", "receive", "finalize"]
+        );
+        let failing = Recorder {
+            fail_prepare: true,
+            ..Recorder::default()
+        };
+        assert!(e.extract(failing.clone()).is_err());
+        assert_eq!(*failing.calls.lock().unwrap(), &["prepare", "abort"]);
+    }
+
+    #[test]
+    fn write_at_v_unsupported_by_default() {
+        #[derive(Debug)]
+        struct Plain;
+        impl WriteOut for Plain {
+            fn receive(&mut self, _: Receiver<Chunk>, _: Progress) -> Result<()> {
+                Ok(())
+            }
+            fn name(&self) -> String {
+                "plain".to_owned()
+            }
+        }
+        assert!(write_all_v(&Plain, &[IoSlice::new(b"x")], 0).is_err());
+    }
+}
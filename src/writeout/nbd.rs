@@ -0,0 +1,479 @@
+//! Restore target which writes to an NBD server, e.g. qemu-nbd or qemu-storage-daemon.
+//!
+//! Only the parts of the protocol needed for writing are implemented: the fixed newstyle
+//! handshake with `NBD_OPT_GO` (falling back to `NBD_OPT_EXPORT_NAME` for old servers) and
+//! simple replies. See https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+
+use super::{Error, Failures, Result, Retry, WriteOut, WriteOutBuilder, WriteReport};
+use crate::{chunk2pos, zero_chunk, Chunk, Data, Progress, CHUNKSZ, CHUNKSZ_LOG};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crossbeam::channel::Receiver;
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+
+const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1;
+const FLAG_NO_ZEROES: u16 = 2;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_GO: u32 = 7;
+const REP_ACK: u32 = 1;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = 1 << 31 | 1;
+const INFO_EXPORT: u16 = 0;
+// Longest option reply accepted, replies carry at most short messages
+const MAX_REPLY: u32 = 64 << 10;
+
+const TFLAG_READ_ONLY: u16 = 1 << 1;
+const TFLAG_SEND_FLUSH: u16 = 1 << 2;
+const TFLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_WRITE_ZEROES: u16 = 6;
+
+const DEFAULT_PORT: u16 = 10809;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Addr {
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
+/// Restore target on an NBD server.
+///
+/// The export must be writable and at least as large as the image. Zero chunks are sent as
+/// `NBD_CMD_WRITE_ZEROES` if the server supports it. The export is flushed before the
+/// connection is closed.
+#[derive(Debug, Clone)]
+pub struct Nbd {
+    url: String,
+    addr: Addr,
+    export: String,
+    retry: Retry,
+}
+
+impl Nbd {
+    /// Parses an NBD URL: `nbd://HOST[:PORT]/EXPORT` or `nbd+unix:///EXPORT?socket=PATH`.
+    pub fn new(url: &str) -> Result<Self> {
+        let err = || Error::NbdUrl(url.to_owned());
+        let (addr, export) = if let Some(rest) = url.strip_prefix("nbd://") {
+            let (hostport, export) = rest.split_once('/').unwrap_or((rest, ""));
+            let (host, port) = match hostport.rsplit_once(':') {
+                // IPv6 addresses come in brackets
+                Some((h, p)) if !p.ends_with(']') => (h, p.parse().map_err(|_| err())?),
+                _ => (hostport, DEFAULT_PORT),
+            };
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            if host.is_empty() {
+                return Err(err());
+            }
+            (Addr::Tcp(host.to_owned(), port), export)
+        } else if let Some(rest) = url.strip_prefix("nbd+unix:///") {
+            let (export, query) = rest.split_once('?').ok_or_else(err)?;
+            let socket = query.strip_prefix("socket=").ok_or_else(err)?;
+            (Addr::Unix(socket.into()), export)
+        } else {
+            return Err(err());
+        };
+        Ok(Self {
+            url: url.to_owned(),
+            addr,
+            export: export.to_owned(),
+            retry: Retry::default(),
+        })
+    }
+
+    /// Returns true if `s` looks like an NBD URL rather than a file name.
+    pub fn is_url(s: &str) -> bool {
+        s.starts_with("nbd://") || s.starts_with("nbd+unix://")
+    }
+
+    /// Sets how write requests which the server fails are handled. By default, the restore is
+    /// aborted on the first error.
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+}
+
+impl WriteOutBuilder for Nbd {
+    type Impl = NbdWriteOut;
+
+    fn build(self, size: u64, _threads: u8) -> Self::Impl {
+        NbdWriteOut {
+            nbd: self,
+            size,
+            conn: None,
+            flags: 0,
+            handle: 0,
+            failures: Failures::default(),
+        }
+    }
+}
+
+trait Socket: Read + Write + Send + Sync {}
+impl<T: Read + Write + Send + Sync> Socket for T {}
+
+struct Conn {
+    rx: BufReader<Box<dyn Socket>>,
+    tx: BufWriter<Box<dyn Socket>>,
+}
+
+fn protocol(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Conn {
+    fn connect(addr: &Addr) -> io::Result<Self> {
+        let (rx, tx): (Box<dyn Socket>, Box<dyn Socket>) = match addr {
+            Addr::Tcp(host, port) => {
+                let s = TcpStream::connect((host.as_str(), *port))?;
+                s.set_nodelay(true)?;
+                (Box::new(s.try_clone()?), Box::new(s))
+            }
+            Addr::Unix(path) => {
+                let s = UnixStream::connect(path)?;
+                (Box::new(s.try_clone()?), Box::new(s))
+            }
+        };
+        Ok(Self {
+            rx: BufReader::new(rx),
+            tx: BufWriter::with_capacity(CHUNKSZ + 28, tx),
+        })
+    }
+
+    fn send_option(&mut self, opt: u32, data: &[u8]) -> io::Result<()> {
+        self.tx.write_u64::<BigEndian>(IHAVEOPT)?;
+        self.tx.write_u32::<BigEndian>(opt)?;
+        self.tx.write_u32::<BigEndian>(data.len() as u32)?;
+        self.tx.write_all(data)?;
+        self.tx.flush()
+    }
+
+    /// Negotiates `export` and returns its size and transmission flags.
+    fn handshake(&mut self, export: &str) -> io::Result<(u64, u16)> {
+        if self.rx.read_u64::<BigEndian>()? != NBDMAGIC
+            || self.rx.read_u64::<BigEndian>()? != IHAVEOPT
+        {
+            return Err(protocol(
+                "not an NBD server speaking the newstyle protocol".into(),
+            ));
+        }
+        let server = self.rx.read_u16::<BigEndian>()?;
+        if server & FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(protocol("server does not support fixed newstyle".into()));
+        }
+        let no_zeroes = server & FLAG_NO_ZEROES;
+        self.tx
+            .write_u32::<BigEndian>(u32::from(FLAG_FIXED_NEWSTYLE | no_zeroes))?;
+        let mut go = Vec::with_capacity(export.len() + 6);
+        go.write_u32::<BigEndian>(export.len() as u32)?;
+        go.extend_from_slice(export.as_bytes());
+        go.write_u16::<BigEndian>(0)?;
+        self.send_option(OPT_GO, &go)?;
+        let mut info = None;
+        loop {
+            if self.rx.read_u64::<BigEndian>()? != REPLY_MAGIC {
+                return Err(protocol("invalid option reply".into()));
+            }
+            let _opt = self.rx.read_u32::<BigEndian>()?;
+            let typ = self.rx.read_u32::<BigEndian>()?;
+            let len = self.rx.read_u32::<BigEndian>()?;
+            if len > MAX_REPLY {
+                return Err(protocol(format!(
+                    "option reply of {} bytes is too long",
+                    len
+                )));
+            }
+            let mut data = vec![0; len as usize];
+            self.rx.read_exact(&mut data)?;
+            match typ {
+                REP_ACK => break,
+                REP_INFO if data.len() >= 12 && data[..2] == INFO_EXPORT.to_be_bytes() => {
+                    let mut d = &data[2..];
+                    info = Some((d.read_u64::<BigEndian>()?, d.read_u16::<BigEndian>()?));
+                }
+                REP_INFO => (),
+                REP_ERR_UNSUP => return self.export_name(export, no_zeroes != 0),
+                t if t & 1 << 31 != 0 => {
+                    return Err(protocol(format!(
+                        "server refused export '{}' (error {}): {}",
+                        export,
+                        t & !(1 << 31),
+                        String::from_utf8_lossy(&data)
+                    )))
+                }
+                _ => (),
+            }
+        }
+        info.ok_or_else(|| protocol("server did not send export information".into()))
+    }
+
+    // Old-style export selection for servers which don't know NBD_OPT_GO.
+    fn export_name(&mut self, export: &str, no_zeroes: bool) -> io::Result<(u64, u16)> {
+        self.send_option(OPT_EXPORT_NAME, export.as_bytes())?;
+        let size = self.rx.read_u64::<BigEndian>()?;
+        let flags = self.rx.read_u16::<BigEndian>()?;
+        if !no_zeroes {
+            self.rx.read_exact(&mut [0; 124])?;
+        }
+        Ok((size, flags))
+    }
+
+    /// Sends a request and waits for its reply.
+    fn request(
+        &mut self,
+        cmd: u16,
+        handle: u64,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+    ) -> io::Result<()> {
+        self.tx.write_u32::<BigEndian>(REQUEST_MAGIC)?;
+        self.tx.write_u16::<BigEndian>(0)?;
+        self.tx.write_u16::<BigEndian>(cmd)?;
+        self.tx.write_u64::<BigEndian>(handle)?;
+        self.tx.write_u64::<BigEndian>(offset)?;
+        self.tx.write_u32::<BigEndian>(len)?;
+        self.tx.write_all(data)?;
+        self.tx.flush()?;
+        if cmd == CMD_DISC {
+            return Ok(());
+        }
+        if self.rx.read_u32::<BigEndian>()? != SIMPLE_REPLY_MAGIC {
+            return Err(protocol("invalid reply".into()));
+        }
+        let errno = self.rx.read_u32::<BigEndian>()?;
+        if self.rx.read_u64::<BigEndian>()? != handle {
+            return Err(protocol("reply for unknown request".into()));
+        }
+        match errno {
+            0 => Ok(()),
+            e => Err(io::Error::from_raw_os_error(e as i32)),
+        }
+    }
+}
+
+pub struct NbdWriteOut {
+    nbd: Nbd,
+    size: u64,
+    conn: Option<Conn>,
+    /// Transmission flags of the export
+    flags: u16,
+    handle: u64,
+    failures: Failures,
+}
+
+impl NbdWriteOut {
+    fn err(&self, e: io::Error) -> Error {
+        Error::Nbd(self.nbd.url.clone(), e)
+    }
+
+    fn send(&mut self, cmd: u16, offset: u64, len: u32, data: &[u8]) -> io::Result<()> {
+        self.handle += 1;
+        let conn = self
+            .conn
+            .as_mut()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))?;
+        conn.request(cmd, self.handle, offset, len, data)
+    }
+
+    fn write_chunk(&mut self, seq: u32, data: &Data) -> io::Result<()> {
+        let pos = chunk2pos(seq);
+        let len = (self.size - pos).min(CHUNKSZ as u64) as usize;
+        match data {
+            Data::Some(d) => self.send(CMD_WRITE, pos, len as u32, &d[..len]),
+            Data::Zero if self.flags & TFLAG_SEND_WRITE_ZEROES != 0 => {
+                self.send(CMD_WRITE_ZEROES, pos, len as u32, &[])
+            }
+            Data::Zero => self.send(CMD_WRITE, pos, len as u32, &zero_chunk()?[..len]),
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.send(CMD_DISC, 0, 0, &[]).ok();
+        self.conn = None;
+    }
+}
+
+impl WriteOut for NbdWriteOut {
+    fn prepare(&mut self) -> Result<()> {
+        let mut conn = Conn::connect(&self.nbd.addr).map_err(|e| self.err(e))?;
+        let (size, flags) = conn.handshake(&self.nbd.export).map_err(|e| self.err(e))?;
+        self.conn = Some(conn);
+        self.flags = flags;
+        if flags & TFLAG_READ_ONLY != 0 {
+            self.disconnect();
+            return Err(self.err(protocol("export is read-only".into())));
+        }
+        if size < self.size {
+            self.disconnect();
+            return Err(Error::ExportSize(self.nbd.url.clone(), size, self.size));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, chunks: Receiver<Chunk>, progress: Progress) -> Result<()> {
+        let retry = self.nbd.retry;
+        for chunk in chunks {
+            for &seq in &chunk.seqs {
+                let res = retry.run(format_args!("chunk #{}", seq), || {
+                    self.write_chunk(seq, &chunk.data)
+                });
+                match res {
+                    Err(e) if retry.keep_going => self.failures.add(seq, 1, self.size, &e),
+                    res => res.map_err(|e| Error::WriteChunk(seq, e))?,
+                }
+            }
+            progress.add(chunk.seqs.len() << CHUNKSZ_LOG);
+            progress.add_chunks(1);
+        }
+        self.failures.check()
+    }
+
+    fn finalize(&mut self, _report: &WriteReport) -> Result<()> {
+        if self.flags & TFLAG_SEND_FLUSH != 0 {
+            self.send(CMD_FLUSH, 0, 0, &[]).map_err(|e| self.err(e))?;
+        }
+        self.disconnect();
+        Ok(())
+    }
+
+    fn abort(&mut self, _error: &dyn std::error::Error) {
+        self.disconnect();
+    }
+
+    fn name(&self) -> String {
+        format!("nbd:{}", self.nbd.url)
+    }
+
+    fn target(&self) -> String {
+        self.nbd.url.clone()
+    }
+}
+
+impl fmt::Debug for NbdWriteOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<NbdWriteOut {} {} bytes>", self.nbd.url, self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{store_tar, IMAGE};
+    use crate::Extractor;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn parse_urls() {
+        let n = Nbd::new("nbd://localhost:10810/disk0").unwrap();
+        assert_eq!(n.addr, Addr::Tcp("localhost".into(), 10810));
+        assert_eq!(n.export, "disk0");
+        let n = Nbd::new("nbd://[::1]/").unwrap();
+        assert_eq!(n.addr, Addr::Tcp("::1".into(), DEFAULT_PORT));
+        assert_eq!(n.export, "");
+        let n = Nbd::new("nbd+unix:///vda?socket=/run/qsd.sock").unwrap();
+        assert_eq!(n.addr, Addr::Unix("/run/qsd.sock".into()));
+        assert_eq!(n.export, "vda");
+        for bad in &["nbd:///x", "nbd://h:port/x", "nbd+unix:///x", "/dev/nbd0"] {
+            assert!(Nbd::new(bad).is_err(), "{}", bad);
+        }
+        assert!(Nbd::is_url("nbd://h/x") && !Nbd::is_url("nbd.img"));
+    }
+
+    // Minimal NBD server which supports NBD_OPT_GO with write zeroes and flush. Returns the
+    // export contents after the client has disconnected.
+    fn serve(l: TcpListener, size: usize) -> Vec<u8> {
+        let (s, _) = l.accept().unwrap();
+        let mut rx = BufReader::new(s.try_clone().unwrap());
+        let mut tx = s;
+        let mut export = vec![0xaa; size];
+        tx.write_u64::<BigEndian>(NBDMAGIC).unwrap();
+        tx.write_u64::<BigEndian>(IHAVEOPT).unwrap();
+        tx.write_u16::<BigEndian>(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)
+            .unwrap();
+        assert_eq!(rx.read_u32::<BigEndian>().unwrap(), 3);
+        assert_eq!(rx.read_u64::<BigEndian>().unwrap(), IHAVEOPT);
+        assert_eq!(rx.read_u32::<BigEndian>().unwrap(), OPT_GO);
+        let mut data = vec![0; rx.read_u32::<BigEndian>().unwrap() as usize];
+        rx.read_exact(&mut data).unwrap();
+        assert_eq!(&data[4..9], b"disk0");
+        let mut reply = |typ: u32, data: &[u8]| {
+            tx.write_u64::<BigEndian>(REPLY_MAGIC).unwrap();
+            tx.write_u32::<BigEndian>(OPT_GO).unwrap();
+            tx.write_u32::<BigEndian>(typ).unwrap();
+            tx.write_u32::<BigEndian>(data.len() as u32).unwrap();
+            tx.write_all(data).unwrap();
+        };
+        let mut info = vec![0, 0];
+        info.write_u64::<BigEndian>(size as u64).unwrap();
+        info.write_u16::<BigEndian>(1 | TFLAG_SEND_FLUSH | TFLAG_SEND_WRITE_ZEROES)
+            .unwrap();
+        reply(REP_INFO, &info);
+        reply(REP_ACK, &[]);
+        let mut tx = rx.get_ref().try_clone().unwrap();
+        loop {
+            assert_eq!(rx.read_u32::<BigEndian>().unwrap(), REQUEST_MAGIC);
+            rx.read_u16::<BigEndian>().unwrap();
+            let cmd = rx.read_u16::<BigEndian>().unwrap();
+            let handle = rx.read_u64::<BigEndian>().unwrap();
+            let off = rx.read_u64::<BigEndian>().unwrap() as usize;
+            let len = rx.read_u32::<BigEndian>().unwrap() as usize;
+            match cmd {
+                CMD_WRITE => rx.read_exact(&mut export[off..off + len]).unwrap(),
+                CMD_WRITE_ZEROES => export[off..off + len].iter_mut().for_each(|b| *b = 0),
+                CMD_FLUSH => (),
+                CMD_DISC => return export,
+                c => panic!("unexpected command {}", c),
+            }
+            tx.write_u32::<BigEndian>(SIMPLE_REPLY_MAGIC).unwrap();
+            tx.write_u32::<BigEndian>(0).unwrap();
+            tx.write_u64::<BigEndian>(handle).unwrap();
+        }
+    }
+
+    #[test]
+    fn reject_long_option_reply() {
+        let mut server = Vec::new();
+        server.write_u64::<BigEndian>(NBDMAGIC).unwrap();
+        server.write_u64::<BigEndian>(IHAVEOPT).unwrap();
+        server.write_u16::<BigEndian>(FLAG_FIXED_NEWSTYLE).unwrap();
+        server.write_u64::<BigEndian>(REPLY_MAGIC).unwrap();
+        server.write_u32::<BigEndian>(OPT_GO).unwrap();
+        server.write_u32::<BigEndian>(REP_INFO).unwrap();
+        server.write_u32::<BigEndian>(u32::MAX).unwrap();
+        let mut conn = Conn {
+            rx: BufReader::new(Box::new(io::Cursor::new(server))),
+            tx: BufWriter::new(Box::new(io::Cursor::new(Vec::new()))),
+        };
+        let err = conn.handshake("disk0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn restore_to_nbd_server() {
+        let store = store_tar();
+        let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98")).unwrap();
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("nbd://{}/disk0", l.local_addr().unwrap());
+        // the export is larger than the image
+        let server = thread::spawn(move || serve(l, IMAGE.len() + 512));
+        e.extract(Nbd::new(&url).unwrap()).unwrap();
+        let export = server.join().unwrap();
+        assert!(export[..IMAGE.len()] == IMAGE[..]);
+        assert_eq!(export[IMAGE.len()..], [0xaa; 512][..]);
+    }
+}
@@ -1,25 +1,83 @@
-use super::{Error, Result, WriteOut, WriteOutBuilder};
-use crate::{chunk2pos, pos2chunk, Chunk, Data, CHUNKSZ, CHUNKSZ_LOG, ZERO_CHUNK};
+use super::{
+    runs, write_all_v, Error, Failures, Rename, Result, Retry, WriteOut, WriteOutBuilder,
+    WriteReport,
+};
+use crate::{
+    chunk2pos, is_zero, pos2chunk, zero_chunk, Chunk, Data, Progress, CHUNKSZ, CHUNKSZ_LOG,
+};
 
-use crossbeam::channel::{Receiver, Sender};
+use crossbeam::channel::Receiver;
+use crossbeam::thread;
 use rand::distributions::Uniform;
 use rand::prelude::*;
 use rand::rngs::ThreadRng;
 use std::fmt;
-use std::fs::File;
-use std::io;
-use std::io::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::*, IoSlice};
+use std::ops::Range;
 use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// How regular files are sized and allocated before chunks are written. Block devices are not
+/// affected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Prealloc {
+    /// The file grows as chunks are written and is extended to the image size at the end
+    None,
+    /// The file is resized to the image size, leaving a hole
+    #[default]
+    Truncate,
+    /// Disk space is reserved with fallocate(2). Filesystems like ext4 and XFS then allocate
+    /// large extents, so the image does not get fragmented by out-of-order writes.
+    Fallocate,
+    /// The whole file is filled with zeros before restoring, e.g. for benchmarks
+    Full,
+}
+
+impl fmt::Display for Prealloc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Prealloc::None => write!(f, "none"),
+            Prealloc::Truncate => write!(f, "truncate"),
+            Prealloc::Fallocate => write!(f, "fallocate"),
+            Prealloc::Full => write!(f, "full"),
+        }
+    }
+}
+
+impl FromStr for Prealloc {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Prealloc::None),
+            "truncate" => Ok(Prealloc::Truncate),
+            "fallocate" => Ok(Prealloc::Fallocate),
+            "full" => Ok(Prealloc::Full),
+            _ => Err(Error::Prealloc(s.to_owned())),
+        }
+    }
+}
 
 /// File/block device restore target.
 ///
 /// Chunks are written out-of-order as they are sent to the writer. Zeros are not written (i.e.,
-/// skipped over) if sparse mode is enabled.
+/// skipped over) if sparse mode is enabled. A chunk which occurs at several adjacent positions is
+/// written with a single `pwritev` call. Several writer threads may be used, see
+/// [Concurrency](struct.Concurrency.html).
 #[derive(Debug, Clone)]
 pub struct RandomAccess {
     path: PathBuf,
     sparse: Option<bool>,
+    in_place: bool,
+    atomic: bool,
+    exclusive: bool,
+    prealloc: Prealloc,
+    retry: Retry,
+    check_holes: bool,
 }
 
 impl RandomAccess {
@@ -32,29 +90,128 @@ impl RandomAccess {
         Self {
             path: path.as_ref().to_owned(),
             sparse,
+            in_place: false,
+            atomic: false,
+            exclusive: false,
+            prealloc: Prealloc::default(),
+            retry: Retry::default(),
+            check_holes: false,
         }
     }
+
+    /// Updates the target in place instead of truncating it first. Regular files are resized to
+    /// the image size. Sparse mode is disabled since zeros must overwrite previous contents.
+    pub fn in_place(mut self) -> Self {
+        self.in_place = true;
+        self.sparse = Some(false);
+        self
+    }
+
+    /// Restores into `<path>.partial` and renames it to `path` once the restore has completed
+    /// successfully, so that nobody sees a half-written image under the final name. The partial
+    /// file is removed if the restore fails. Only useful for regular files and not together with
+    /// [in_place](#method.in_place) or [Retry::keep_going](struct.Retry.html#method.keep_going),
+    /// whose partial results would be removed as well.
+    pub fn atomic(mut self) -> Self {
+        self.atomic = true;
+        self
+    }
+
+    /// Fails instead of overwriting an existing file, e.g. one another restore has just created
+    /// under the same name. With [atomic](#method.atomic), the partial file must not exist
+    /// either and the finished image is moved into place only if its name is still free.
+    pub fn exclusive(mut self) -> Self {
+        self.exclusive = true;
+        self
+    }
+
+    /// Sets how regular files are allocated before writing. Default is
+    /// [Truncate](enum.Prealloc.html#variant.Truncate).
+    pub fn prealloc(mut self, prealloc: Prealloc) -> Self {
+        self.prealloc = prealloc;
+        self
+    }
+
+    /// Sets how failing writes are handled. By default, the restore is aborted on the first
+    /// error.
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Checks after a sparse restore that everything which has been skipped over reads as zeros,
+    /// e.g. because the sparse mode heuristic has been wrong about a block device which still
+    /// holds old data. Holes reported by `SEEK_DATA` are trusted, everything else is read back.
+    /// The restore fails with `Error::NotZero` if data turns up. Without effect if zeros are
+    /// written anyway.
+    pub fn check_holes(mut self) -> Self {
+        self.check_holes = true;
+        self
+    }
 }
 
 impl WriteOutBuilder for RandomAccess {
     type Impl = RandomWriteOut;
 
     fn build(self, size: u64, threads: u8) -> Self::Impl {
+        let (path, rename) = if self.atomic {
+            let mut partial = self.path.clone().into_os_string();
+            partial.push(".partial");
+            let partial = PathBuf::from(partial);
+            let rename = Rename {
+                from: partial.clone(),
+                to: self.path,
+                exclusive: self.exclusive,
+            };
+            (partial, Some(rename))
+        } else {
+            (self.path, None)
+        };
         RandomWriteOut {
-            path: self.path,
+            path,
             sparse: self.sparse,
+            in_place: self.in_place,
+            exclusive: self.exclusive,
+            prealloc: self.prealloc,
+            retry: self.retry,
+            failures: Failures::default(),
+            rename,
             size,
             threads,
+            file: None,
+            created: false,
+            skipped: if self.check_holes {
+                Some(Mutex::default())
+            } else {
+                None
+            },
         }
     }
 }
 
-#[derive(Clone, Default)]
+// Linux' UIO_MAXIOV: maximum number of buffers passed to a single pwritev call
+const IOV_MAX: usize = 1024;
+
+#[derive(Default)]
 pub struct RandomWriteOut {
     path: PathBuf,
     sparse: Option<bool>,
+    in_place: bool,
+    exclusive: bool,
+    prealloc: Prealloc,
+    retry: Retry,
+    /// Regions given up on if `retry.keep_going` is set
+    failures: Failures,
+    rename: Option<Rename>,
     size: u64,
+    /// Number of writer threads
     threads: u8,
+    /// Restore target, open between `prepare` and `finalize`/`abort`
+    file: Option<File>,
+    /// The target has been opened, i.e. a partial file is ours to remove
+    created: bool,
+    /// Regions left out in sparse mode, recorded if holes are to be checked
+    skipped: Option<Mutex<Vec<Range<u64>>>>,
 }
 
 impl RandomWriteOut {
@@ -71,7 +228,7 @@ impl RandomWriteOut {
         for chunk in RandomSample::new(pos2chunk(self.size)) {
             dev.seek(io::SeekFrom::Start(chunk2pos(chunk)))?;
             dev.read_exact(&mut buf)?;
-            if *buf != ZERO_CHUNK[..] {
+            if !is_zero(&buf) {
                 return Ok(false);
             }
         }
@@ -81,8 +238,13 @@ impl RandomWriteOut {
     // Opens restore target (file/dev) as stated in self.path. Resizes file accordingly and gives a
     // guess if sparse mode can be used or not.
     fn open(&self) -> Result<(File, bool), io::Error> {
-        let mut f = File::create(&self.path)?;
-        let sparse_guess = match f.set_len(self.size) {
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .create_new(self.exclusive)
+            .truncate(!self.in_place)
+            .open(&self.path)?;
+        let sparse_guess = match self.preallocate(&f) {
             Err(err) => {
                 if err.raw_os_error().unwrap_or_default() == 22 {
                     // 22 (Invalid argument): cannot resize block devices
@@ -98,47 +260,173 @@ impl RandomWriteOut {
         Ok((f, sparse_guess))
     }
 
-    fn run(
-        &self,
-        f: &File,
-        rx: &Receiver<Chunk>,
-        prog: &Sender<usize>,
-        writer: &(dyn Writer),
-    ) -> Result<()> {
-        rx.into_iter().try_for_each(|chunk| -> Result<()> {
-            match chunk.data {
-                Data::Some(ref data) => {
-                    for seq in &chunk.seqs {
-                        writer
-                            .data(f, *seq, data)
-                            .map_err(|e| Error::WriteChunkFile(*seq, self.path.to_owned(), e))?;
-                    }
+    // Sizes and allocates the target if it is a regular file. Fails with EINVAL for block devices.
+    fn preallocate(&self, f: &File) -> io::Result<()> {
+        match self.prealloc {
+            // files updated in place must not be longer than the image
+            Prealloc::None => f.set_len(f.metadata()?.len().min(self.size)),
+            Prealloc::Truncate => f.set_len(self.size),
+            Prealloc::Fallocate => {
+                f.set_len(self.size)?;
+                fallocate(f, self.size)
+            }
+            Prealloc::Full => {
+                f.set_len(self.size)?;
+                let zeros = zero_chunk()?;
+                let mut pos = 0;
+                while pos < self.size {
+                    let n = (self.size - pos).min(CHUNKSZ as u64);
+                    f.write_all_at(&zeros[..n as usize], pos)?;
+                    pos += n;
                 }
-                Data::Zero => {
-                    for seq in &chunk.seqs {
-                        writer
-                            .zero(f, *seq)
-                            .map_err(|e| Error::WriteChunkFile(*seq, self.path.to_owned(), e))?;
-                    }
+                Ok(())
+            }
+        }
+    }
+
+    /// Restore target, open between `prepare` and `finalize`/`abort`
+    pub(super) fn file(&self) -> Option<&File> {
+        self.file.as_ref()
+    }
+
+    // Notes regions which sparse mode does not write.
+    fn skip(&self, ranges: &[Range<u64>]) {
+        if let Some(skipped) = &self.skipped {
+            skipped
+                .lock()
+                .expect("poisoned lock")
+                .extend_from_slice(ranges);
+        }
+    }
+
+    // Fails if any skipped region contains data.
+    fn check_holes(&self) -> Result<()> {
+        let skipped = match &self.skipped {
+            Some(skipped) => skipped.lock().expect("poisoned lock"),
+            None => return Ok(()),
+        };
+        let mut ranges: Vec<Range<u64>> = skipped
+            .iter()
+            .map(|r| r.start..r.end.min(self.size))
+            .filter(|r| !r.is_empty())
+            .collect();
+        ranges.sort_unstable_by_key(|r| r.start);
+        // the target is open for writing only
+        let dirty = File::open(&self.path)
+            .and_then(|f| dirty_regions(&f, &merge(ranges)))
+            .map_err(|e| Error::OutputFile(self.path.to_owned(), e))?;
+        if dirty.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::NotZero(dirty))
+        }
+    }
+
+    fn run(&self, rx: &Receiver<Chunk>, prog: &Progress, writer: &dyn Writer) -> Result<()> {
+        rx.into_iter().try_for_each(|chunk| -> Result<()> {
+            for (seq, n) in runs(&chunk.seqs) {
+                let res = self
+                    .retry
+                    .run(format_args!("chunk #{}", seq), || match chunk.data {
+                        Data::Some(ref data) => writer.data(self, seq, n, data),
+                        Data::Zero => writer.zero(self, seq, n),
+                    });
+                match res {
+                    Err(e) if self.retry.keep_going => self.failures.add(seq, n, self.size, &e),
+                    res => res.map_err(|e| Error::WriteChunkFile(seq, self.path.to_owned(), e))?,
                 }
             }
-            prog.send(chunk.seqs.len() << CHUNKSZ_LOG)?;
+            prog.add(chunk.seqs.len() << CHUNKSZ_LOG);
+            prog.add_chunks(1);
             Ok(())
         })
     }
 }
 
 impl WriteOut for RandomWriteOut {
-    fn receive(self, chunks: Receiver<Chunk>, progress: Sender<usize>) -> Result<()> {
+    fn prepare(&mut self) -> Result<()> {
         let (f, guess) = self
             .open()
             .map_err(|e| Error::OutputFile(self.path.to_owned(), e))?;
-        let writer: Box<dyn Writer> = if self.sparse.unwrap_or(guess) {
+        self.file = Some(f);
+        self.created = true;
+        self.sparse = Some(self.sparse.unwrap_or(guess));
+        Ok(())
+    }
+
+    fn receive(&mut self, chunks: Receiver<Chunk>, progress: Progress) -> Result<()> {
+        let writer: Box<dyn Writer + Sync> = if self.sparse == Some(true) {
             Box::new(Sparse)
         } else {
             Box::new(Continuous)
         };
-        self.run(&f, &chunks, &progress, &*writer)
+        let (this, writer) = (&*self, &*writer);
+        // additional writer threads inherit I/O class and nice value from this one
+        let res = thread::scope(|s| {
+            let helpers: Vec<_> = (1..self.threads)
+                .map(|_| s.spawn(|_| this.run(&chunks, &progress, writer)))
+                .collect();
+            let res = this.run(&chunks, &progress, writer);
+            helpers
+                .into_iter()
+                .map(|h| h.join().expect("unhandled panic"))
+                .fold(res, Result::and)
+        })
+        .expect("subthread panic")
+        .and_then(|()| self.failures.check());
+        match &self.file {
+            // trailing zero chunks may not have been written
+            Some(f) if res.is_ok() && self.prealloc == Prealloc::None => {
+                extend(f, self.size).map_err(|e| Error::OutputFile(self.path.to_owned(), e))
+            }
+            _ => res,
+        }
+    }
+
+    fn write_at_v(&self, bufs: &[IoSlice<'_>], offset: u64) -> io::Result<usize> {
+        let f = self
+            .file
+            .as_ref()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+        let cnt = bufs.len().min(IOV_MAX) as libc::c_int;
+        // IoSlice is guaranteed to be ABI compatible with struct iovec
+        let n = unsafe {
+            libc::pwritev(
+                f.as_raw_fd(),
+                bufs.as_ptr() as *const libc::iovec,
+                cnt,
+                offset as libc::off_t,
+            )
+        };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+
+    fn finalize(&mut self, _report: &WriteReport) -> Result<()> {
+        self.check_holes()?;
+        self.file = None;
+        match &self.rename {
+            Some(r) => r.commit(),
+            None => Ok(()),
+        }
+    }
+
+    fn abort(&mut self, _error: &dyn std::error::Error) {
+        self.file = None;
+        // the partial file of a concurrent exclusive restore is none of our business
+        if let (Some(r), true) = (&self.rename, self.created) {
+            r.abort();
+        }
+    }
+
+    fn target(&self) -> String {
+        match &self.rename {
+            Some(r) => r.to.display().to_string(),
+            None => self.name(),
+        }
     }
 
     fn name(&self) -> String {
@@ -146,6 +434,84 @@ impl WriteOut for RandomWriteOut {
     }
 }
 
+#[cfg(target_os = "linux")]
+fn fallocate(f: &File, len: u64) -> io::Result<()> {
+    if unsafe { libc::fallocate(f.as_raw_fd(), 0, 0, len as libc::off_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fallocate(_f: &File, _len: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Joins adjacent or overlapping ranges, which must be sorted by start.
+fn merge(ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for r in ranges {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    merged
+}
+
+// Repositions with SEEK_DATA or SEEK_HOLE. Returns None if there is no data after `pos` and
+// `pos` itself if the file does not know about holes, so that everything is read.
+fn seek(f: &File, pos: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    match unsafe { libc::lseek(f.as_raw_fd(), pos as libc::off_t, whence) } {
+        -1 => match io::Error::last_os_error() {
+            e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+            e if e.raw_os_error() == Some(libc::EINVAL) => Ok(Some(pos)),
+            e => Err(e),
+        },
+        off => Ok(Some(off as u64)),
+    }
+}
+
+// Parts of `ranges` (sorted and disjoint) which contain non-zero bytes, in blocks of up to
+// BLKSIZE.
+fn dirty_regions(f: &File, ranges: &[Range<u64>]) -> io::Result<Vec<Range<u64>>> {
+    let mut dirty = Vec::new();
+    let mut buf = vec![0; BLKSIZE];
+    for r in ranges {
+        let mut pos = r.start;
+        while pos < r.end {
+            let data = match seek(f, pos, libc::SEEK_DATA)? {
+                Some(d) if d < r.end => d,
+                Some(_) => break,
+                None => return Ok(merge(dirty)),
+            };
+            let hole = match seek(f, data, libc::SEEK_HOLE)? {
+                Some(h) if h > data => h.min(r.end),
+                _ => r.end,
+            };
+            pos = data;
+            while pos < hole {
+                let n = (hole - pos).min(BLKSIZE as u64) as usize;
+                f.read_exact_at(&mut buf[..n], pos)?;
+                if !is_zero(&buf[..n]) {
+                    dirty.push(pos..pos + n as u64);
+                }
+                pos += n as u64;
+            }
+        }
+    }
+    Ok(merge(dirty))
+}
+
+// Extends regular files which are shorter than `len`.
+fn extend(f: &File, len: u64) -> io::Result<()> {
+    let meta = f.metadata()?;
+    if meta.is_file() && meta.len() < len {
+        f.set_len(len)?;
+    }
+    Ok(())
+}
+
 impl fmt::Debug for RandomWriteOut {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "<RandomWriteOut {}>", self.path.display())
@@ -191,20 +557,21 @@ impl Iterator for RandomSample {
     }
 }
 
+// Writes a run of `n` adjacent chunks starting at `seq` which all have the same contents.
 trait Writer {
-    fn data(&self, file: &File, seq: u32, data: &[u8]) -> io::Result<()>;
-    fn zero(&self, file: &File, seq: u32) -> io::Result<()>;
+    fn data(&self, out: &RandomWriteOut, seq: u32, n: usize, data: &[u8]) -> io::Result<()>;
+    fn zero(&self, out: &RandomWriteOut, seq: u32, n: usize) -> io::Result<()>;
 }
 
 struct Continuous;
 
 impl Writer for Continuous {
-    fn data(&self, f: &File, seq: u32, data: &[u8]) -> io::Result<()> {
-        f.write_all_at(data, chunk2pos(seq))
+    fn data(&self, out: &RandomWriteOut, seq: u32, n: usize, data: &[u8]) -> io::Result<()> {
+        write_all_v(out, &vec![IoSlice::new(data); n], chunk2pos(seq))
     }
 
-    fn zero(&self, f: &File, seq: u32) -> io::Result<()> {
-        f.write_all_at(&ZERO_CHUNK, chunk2pos(seq))
+    fn zero(&self, out: &RandomWriteOut, seq: u32, n: usize) -> io::Result<()> {
+        write_all_v(out, &vec![IoSlice::new(zero_chunk()?); n], chunk2pos(seq))
     }
 }
 
@@ -213,18 +580,47 @@ struct Sparse;
 const BLKSIZE: usize = 64 * 1024;
 
 impl Writer for Sparse {
-    fn data(&self, f: &File, seq: u32, data: &[u8]) -> io::Result<()> {
+    // Consecutive non-zero blocks are collected into one vectored write, even across chunk
+    // boundaries.
+    fn data(&self, out: &RandomWriteOut, seq: u32, n: usize, data: &[u8]) -> io::Result<()> {
+        let blocks: Vec<Option<&[u8]>> = data
+            .chunks(BLKSIZE)
+            .map(|b| if !is_zero(b) { Some(b) } else { None })
+            .collect();
         let mut pos = chunk2pos(seq);
-        for slice in data.chunks(BLKSIZE) {
-            if slice != &ZERO_CHUNK[..BLKSIZE] {
-                f.write_all_at(slice, pos)?;
+        let mut start = pos;
+        let mut pending = Vec::new();
+        let mut skipped: Vec<Range<u64>> = Vec::new();
+        for block in blocks.iter().cycle().take(blocks.len() * n) {
+            match block {
+                Some(b) => pending.push(IoSlice::new(b)),
+                None if pending.is_empty() => (),
+                None => {
+                    write_all_v(out, &pending, start)?;
+                    pending.clear();
+                }
+            }
+            if block.is_none() {
+                match skipped.last_mut() {
+                    Some(r) if r.end == pos => r.end += BLKSIZE as u64,
+                    _ => skipped.push(pos..pos + BLKSIZE as u64),
+                }
             }
             pos += BLKSIZE as u64;
+            if pending.is_empty() {
+                start = pos;
+            }
+        }
+        if !pending.is_empty() {
+            write_all_v(out, &pending, start)?;
         }
+        out.skip(&skipped);
         Ok(())
     }
 
-    fn zero(&self, _f: &File, _seq: u32) -> io::Result<()> {
+    fn zero(&self, out: &RandomWriteOut, seq: u32, n: usize) -> io::Result<()> {
+        let chunks = chunk2pos(seq)..chunk2pos(seq + n as u32);
+        out.skip(&[chunks]);
         Ok(())
     }
 }
@@ -232,6 +628,9 @@ impl Writer for Sparse {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::writeout::FailedRange;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
     use tempdir::TempDir;
 
     fn sparse_mode_test<F>(modifier: F) -> io::Result<bool>
@@ -265,4 +664,213 @@ mod tests {
         })
         .unwrap())
     }
+
+    #[test]
+    fn preallocate_files() {
+        let td = TempDir::new("prealloc").unwrap();
+        let p = td.path().join("img");
+        let allocated = |prealloc| {
+            fs::write(&p, vec![0xff; 3 << CHUNKSZ_LOG]).unwrap();
+            let ra = RandomWriteOut {
+                path: p.clone(),
+                size: 2 << CHUNKSZ_LOG,
+                in_place: true,
+                prealloc,
+                ..RandomWriteOut::default()
+            };
+            let (f, guess) = ra.open().unwrap();
+            assert!(guess);
+            (f.metadata().unwrap().len(), fs::read(&p).unwrap())
+        };
+        let (len, img) = allocated(Prealloc::None);
+        assert_eq!(len, 2 << CHUNKSZ_LOG);
+        assert!(img.iter().all(|&b| b == 0xff));
+        let (len, _) = allocated(Prealloc::Truncate);
+        assert_eq!(len, 2 << CHUNKSZ_LOG);
+        let (len, img) = allocated(Prealloc::Full);
+        assert_eq!(len, 2 << CHUNKSZ_LOG);
+        assert_eq!(img, vec![0; 2 << CHUNKSZ_LOG]);
+        // start from scratch to see the effect of fallocate
+        fs::remove_file(&p).unwrap();
+        let ra = RandomWriteOut {
+            path: p.clone(),
+            size: 2 << CHUNKSZ_LOG,
+            prealloc: Prealloc::Fallocate,
+            ..RandomWriteOut::default()
+        };
+        match ra.open() {
+            Ok((f, _)) => assert!(f.metadata().unwrap().blocks() * 512 >= 2 << CHUNKSZ_LOG),
+            // e.g. tmpfs on old kernels
+            Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP)),
+        }
+    }
+
+    #[test]
+    fn prealloc_none_extends_file() {
+        let td = TempDir::new("prealloc").unwrap();
+        let p = td.path().join("img");
+        let f = File::create(&p).unwrap();
+        extend(&f, 2 << CHUNKSZ_LOG).unwrap();
+        assert_eq!(f.metadata().unwrap().len(), 2 << CHUNKSZ_LOG);
+        extend(&f, 1).unwrap();
+        assert_eq!(f.metadata().unwrap().len(), 2 << CHUNKSZ_LOG);
+    }
+
+    fn write_runs(writer: &dyn Writer) -> Vec<u8> {
+        let td = TempDir::new("runs").unwrap();
+        let p = td.path().join("img");
+        let mut f = File::create(&p).unwrap();
+        // pre-existing garbage shows which parts are skipped
+        f.write_all(&vec![0xff; 4 << CHUNKSZ_LOG]).unwrap();
+        let ra = RandomWriteOut {
+            file: Some(f),
+            ..RandomWriteOut::default()
+        };
+        let mut data = vec![0; CHUNKSZ];
+        data[0] = 1;
+        data[CHUNKSZ - 1] = 2;
+        writer.data(&ra, 1, 2, &data).unwrap();
+        writer.zero(&ra, 3, 1).unwrap();
+        drop(ra);
+        std::fs::read(&p).unwrap()
+    }
+
+    #[test]
+    fn continuous_writes_runs() {
+        let img = write_runs(&Continuous);
+        assert!(img[..CHUNKSZ].iter().all(|&b| b == 0xff));
+        for seq in 1..3 {
+            let c = &img[seq << CHUNKSZ_LOG..(seq + 1) << CHUNKSZ_LOG];
+            assert_eq!((c[0], c[1], c[CHUNKSZ - 1]), (1, 0, 2));
+        }
+        assert!(is_zero(&img[3 << CHUNKSZ_LOG..]));
+    }
+
+    #[test]
+    fn sparse_skips_zero_blocks_in_runs() {
+        let img = write_runs(&Sparse);
+        for seq in 1..3 {
+            let c = &img[seq << CHUNKSZ_LOG..(seq + 1) << CHUNKSZ_LOG];
+            assert_eq!((c[0], c[1], c[BLKSIZE]), (1, 0, 0xff));
+            assert_eq!(
+                (c[CHUNKSZ - BLKSIZE - 1], c[CHUNKSZ - 2], c[CHUNKSZ - 1]),
+                (0xff, 0, 2)
+            );
+        }
+        assert!(img[3 << CHUNKSZ_LOG..].iter().all(|&b| b == 0xff));
+    }
+
+    #[test]
+    fn check_holes_finds_stale_data() {
+        let td = TempDir::new("holes").unwrap();
+        let p = td.path().join("img");
+        let check = |garbage: bool| {
+            let f = File::create(&p).unwrap();
+            f.set_len(4 << CHUNKSZ_LOG).unwrap();
+            if garbage {
+                f.write_all_at(&[0xff; 10], (3 << CHUNKSZ_LOG) + 5).unwrap();
+            }
+            let ra = RandomWriteOut {
+                path: p.clone(),
+                file: Some(f),
+                size: 4 << CHUNKSZ_LOG,
+                skipped: Some(Mutex::default()),
+                ..RandomWriteOut::default()
+            };
+            let mut data = vec![0; CHUNKSZ];
+            data[0] = 1;
+            Sparse.data(&ra, 0, 3, &data).unwrap();
+            Sparse.zero(&ra, 3, 1).unwrap();
+            ra.check_holes()
+        };
+        check(false).unwrap();
+        match check(true) {
+            Err(Error::NotZero(dirty)) => {
+                assert_eq!(dirty.len(), 1);
+                assert!(dirty[0].contains(&((3 << CHUNKSZ_LOG) + 14)));
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn merge_ranges() {
+        assert_eq!(merge(vec![0..2, 2..4, 3..5, 7..8]), vec![0..5, 7..8]);
+    }
+
+    // Fails once with EIO at seq 1 and always with ENOSPC at seqs 2 and 3
+    #[derive(Default)]
+    struct Flaky(std::sync::Mutex<Vec<u32>>);
+
+    impl Writer for Flaky {
+        fn data(&self, _out: &RandomWriteOut, seq: u32, _n: usize, _: &[u8]) -> io::Result<()> {
+            let mut calls = self.0.lock().unwrap();
+            calls.push(seq);
+            match seq {
+                1 if calls.iter().filter(|&&s| s == 1).count() == 1 => {
+                    Err(io::Error::from_raw_os_error(libc::EIO))
+                }
+                2 | 3 => Err(io::Error::from_raw_os_error(libc::ENOSPC)),
+                _ => Ok(()),
+            }
+        }
+
+        fn zero(&self, out: &RandomWriteOut, seq: u32, n: usize) -> io::Result<()> {
+            self.data(out, seq, n, &[])
+        }
+    }
+
+    fn run_flaky(retry: Retry) -> (Result<()>, Vec<u32>) {
+        let ra = RandomWriteOut {
+            retry,
+            size: (4 << CHUNKSZ_LOG) + 512,
+            ..RandomWriteOut::default()
+        };
+        let (tx, rx) = crossbeam::channel::unbounded();
+        for seq in &[0, 1, 4, 2, 3] {
+            let seqs = [*seq].iter().copied().collect();
+            tx.send(Chunk {
+                data: Data::Zero,
+                seqs,
+            })
+            .unwrap();
+        }
+        drop(tx);
+        let (progress, _monitor) = crate::progress::channel();
+        let flaky = Flaky::default();
+        let res = ra
+            .run(&rx, &progress, &flaky)
+            .and_then(|()| ra.failures.check());
+        (res, flaky.0.into_inner().unwrap())
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        let retry = Retry {
+            delay: std::time::Duration::from_millis(1),
+            ..Retry::new(2)
+        };
+        match run_flaky(retry) {
+            (Err(Error::WriteChunkFile(2, _, _)), calls) => {
+                assert_eq!(calls, &[0, 1, 1, 4, 2, 2, 2])
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        match run_flaky(retry.keep_going(true)) {
+            (Err(Error::Partial(failed)), calls) => {
+                assert_eq!(calls, &[0, 1, 1, 4, 2, 2, 2, 3, 3, 3]);
+                assert_eq!(
+                    failed,
+                    vec![FailedRange {
+                        offset: 2 << CHUNKSZ_LOG,
+                        len: 2 << CHUNKSZ_LOG,
+                        error: io::Error::from_raw_os_error(libc::ENOSPC).to_string()
+                    }]
+                );
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        let (res, _) = run_flaky(Retry::default().keep_going(true));
+        assert!(matches!(res, Err(Error::Partial(f)) if f.len() == 2));
+    }
 }
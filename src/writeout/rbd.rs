@@ -0,0 +1,330 @@
+//! Restore target which writes a stream for Ceph's `rbd import-diff`.
+//!
+//! The format (version 1) is documented in Ceph's `doc/dev/rbd-diff.rst`: a header line
+//! followed by tagged records with little endian numbers. Metadata records come first:
+//!
+//! ```text
+//! 'f' len:u32 name    snapshot the diff starts from (optional)
+//! 't' len:u32 name    snapshot to create after import (optional)
+//! 's' size:u64        image size
+//! ```
+//!
+//! They are followed by data records and the end marker:
+//!
+//! ```text
+//! 'w' offset:u64 length:u64 data
+//! 'z' offset:u64 length:u64
+//! 'e'
+//! ```
+
+use super::stream::in_order;
+use super::{Error, Reorder, ReorderStats, Result, WriteOut, WriteOutBuilder};
+use crate::{chunk2pos, is_zero, Chunk, Data, Progress, CHUNKSZ};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use crossbeam::channel::Receiver;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+const HEADER: &[u8] = b"rbd diff v1\n";
+
+/// Encoder for rbd diff streams. Data records must be passed in ascending order and zero
+/// records which follow each other are merged.
+pub struct RbdDiffWriter<W: Write> {
+    out: W,
+    /// Zero range not written yet
+    zero: Option<Range<u64>>,
+}
+
+impl<W: Write> RbdDiffWriter<W> {
+    /// Writes header and metadata records.
+    pub fn new(
+        mut out: W,
+        size: u64,
+        from_snap: Option<&str>,
+        to_snap: Option<&str>,
+    ) -> io::Result<Self> {
+        out.write_all(HEADER)?;
+        for (tag, snap) in [(b'f', from_snap), (b't', to_snap)] {
+            if let Some(name) = snap {
+                out.write_u8(tag)?;
+                out.write_u32::<LittleEndian>(name.len() as u32)?;
+                out.write_all(name.as_bytes())?;
+            }
+        }
+        out.write_u8(b's')?;
+        out.write_u64::<LittleEndian>(size)?;
+        Ok(Self { out, zero: None })
+    }
+
+    /// Writes `data` at `offset`.
+    pub fn data(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.flush_zero()?;
+        self.out.write_u8(b'w')?;
+        self.out.write_u64::<LittleEndian>(offset)?;
+        self.out.write_u64::<LittleEndian>(data.len() as u64)?;
+        self.out.write_all(data)
+    }
+
+    /// Zeroes `len` bytes at `offset`.
+    pub fn zero(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match &mut self.zero {
+            Some(z) if z.end == offset => z.end += len,
+            _ => {
+                self.flush_zero()?;
+                self.zero = Some(offset..offset + len);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_zero(&mut self) -> io::Result<()> {
+        if let Some(z) = self.zero.take() {
+            self.out.write_u8(b'z')?;
+            self.out.write_u64::<LittleEndian>(z.start)?;
+            self.out.write_u64::<LittleEndian>(z.end - z.start)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the end marker and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_zero()?;
+        self.out.write_u8(b'e')?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Restore target which writes the image as rbd diff stream, e.g. to stdout for
+/// `rbd import-diff - POOL/IMAGE`.
+///
+/// Chunks are put into sequence order like with [Stream](struct.Stream.html). Unmapped chunks
+/// and chunks consisting of NUL bytes only become zero records.
+pub struct RbdDiff<W: Write> {
+    out: Option<W>,
+    enc: Option<RbdDiffWriter<W>>,
+    from_snap: Option<String>,
+    to_snap: Option<String>,
+    /// Positions left out of the diff, sorted
+    unchanged: Vec<u32>,
+    size: u64,
+    reorder: Arc<Reorder>,
+}
+
+impl<W: Write + Send + Sync> RbdDiff<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Some(out),
+            enc: None,
+            from_snap: None,
+            to_snap: None,
+            unchanged: Vec::new(),
+            size: 0,
+            reorder: Arc::default(),
+        }
+    }
+
+    /// Makes `rbd import-diff` create snapshot `name` after importing the image.
+    pub fn to_snap(mut self, name: &str) -> Self {
+        self.to_snap = Some(name.to_owned());
+        self
+    }
+
+    /// Writes a differential stream for an RBD image which has snapshot `from_snap` of the base
+    /// revision already. Positions listed in `unchanged` (see
+    /// [Extractor::unchanged_since](../struct.Extractor.html#method.unchanged_since)) are
+    /// neither loaded nor written.
+    pub fn since(mut self, from_snap: &str, mut unchanged: Vec<u32>) -> Self {
+        unchanged.sort_unstable();
+        self.from_snap = Some(from_snap.to_owned());
+        self.unchanged = unchanged;
+        self
+    }
+
+    fn write(&mut self, data: &Data, seq: u32, progress: &Progress) -> Result<()> {
+        if self.unchanged.binary_search(&seq).is_ok() {
+            progress.add(CHUNKSZ);
+            return Ok(());
+        }
+        let pos = chunk2pos(seq);
+        let len = (self.size - pos).min(CHUNKSZ as u64) as usize;
+        let enc = self.enc.as_mut().expect("prepare() not called");
+        match data {
+            Data::Some(d) if !is_zero(&d[..len]) => enc.data(pos, &d[..len]),
+            _ => enc.zero(pos, len as u64),
+        }
+        .map_err(|e| Error::WriteChunk(seq, e))?;
+        progress.add(CHUNKSZ);
+        Ok(())
+    }
+
+    fn run(
+        &mut self,
+        chunks: Receiver<Chunk>,
+        progress: &Progress,
+        stats: &mut ReorderStats,
+    ) -> Result<()> {
+        let reorder = Arc::clone(&self.reorder);
+        let end = in_order(chunks, &reorder, progress, stats, |d, seq| {
+            self.write(&d, seq, progress)
+        })?;
+        let enc = self.enc.take().expect("prepare() not called");
+        self.out = Some(
+            enc.finish()
+                .map_err(|e| Error::WriteChunk(end.saturating_sub(1), e))?,
+        );
+        Ok(())
+    }
+}
+
+impl<W: Write + Send + Sync> WriteOutBuilder for RbdDiff<W> {
+    type Impl = RbdDiff<W>;
+
+    fn build(mut self, size: u64, _threads: u8) -> Self::Impl {
+        self.size = size;
+        self
+    }
+}
+
+impl<W: Write + Send + Sync> WriteOut for RbdDiff<W> {
+    fn prepare(&mut self) -> Result<()> {
+        let out = self.out.take().expect("prepare() called twice");
+        let enc = RbdDiffWriter::new(
+            out,
+            self.size,
+            self.from_snap.as_deref(),
+            self.to_snap.as_deref(),
+        )
+        .map_err(Error::Header)?;
+        self.enc = Some(enc);
+        Ok(())
+    }
+
+    fn receive(&mut self, chunks: Receiver<Chunk>, progress: Progress) -> Result<()> {
+        let mut stats = ReorderStats::default();
+        let res = self.run(chunks, &progress, &mut stats);
+        self.reorder.finish(stats);
+        res
+    }
+
+    fn reorder(&self) -> Option<Arc<Reorder>> {
+        Some(Arc::clone(&self.reorder))
+    }
+
+    fn skip(&self) -> &[u32] {
+        &self.unchanged
+    }
+
+    fn name(&self) -> String {
+        "rbd-diff".to_owned()
+    }
+}
+
+impl<W: Write> fmt::Debug for RbdDiff<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<RbdDiff {} bytes>", self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{store_tar, IMAGE};
+    use crate::{Extractor, Stream};
+    use byteorder::ReadBytesExt;
+    use std::fs;
+    use std::io::Read;
+
+    // Applies an rbd diff stream to `image` like `rbd import-diff` does. Returns the snapshot
+    // names.
+    fn apply(mut diff: &[u8], image: &mut Vec<u8>) -> [Option<String>; 2] {
+        let mut header = [0; 12];
+        diff.read_exact(&mut header).unwrap();
+        assert_eq!(header, HEADER);
+        let mut snaps = [None, None];
+        loop {
+            match diff.read_u8().unwrap() {
+                t @ (b'f' | b't') => {
+                    let mut name = vec![0; diff.read_u32::<LittleEndian>().unwrap() as usize];
+                    diff.read_exact(&mut name).unwrap();
+                    snaps[(t == b't') as usize] = Some(String::from_utf8(name).unwrap());
+                }
+                b's' => image.resize(diff.read_u64::<LittleEndian>().unwrap() as usize, 0xaa),
+                b'w' => {
+                    let off = diff.read_u64::<LittleEndian>().unwrap() as usize;
+                    let len = diff.read_u64::<LittleEndian>().unwrap() as usize;
+                    diff.read_exact(&mut image[off..off + len]).unwrap();
+                }
+                b'z' => {
+                    let off = diff.read_u64::<LittleEndian>().unwrap() as usize;
+                    let len = diff.read_u64::<LittleEndian>().unwrap() as usize;
+                    image[off..off + len].iter_mut().for_each(|b| *b = 0);
+                }
+                b'e' => break,
+                t => panic!("unexpected record {:?}", t as char),
+            }
+        }
+        assert!(diff.is_empty(), "trailing data after end record");
+        snaps
+    }
+
+    #[test]
+    fn merge_zero_records() {
+        let mut w = RbdDiffWriter::new(Vec::new(), 40, Some("a"), None).unwrap();
+        w.zero(0, 10).unwrap();
+        w.zero(10, 10).unwrap();
+        w.data(20, b"x").unwrap();
+        w.zero(30, 10).unwrap();
+        let out = w.finish().unwrap();
+        assert_eq!(&out[12..21], b"f\x01\0\0\0as\x28\0");
+        assert_eq!(out.iter().filter(|&&b| b == b'z').count(), 2);
+        assert_eq!(out[out.len() - 18], b'z');
+        assert_eq!(out.len(), 12 + 6 + 9 + 2 * 17 + 18 + 1);
+    }
+
+    #[test]
+    fn restore_as_rbd_diff() {
+        let store = store_tar();
+        let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98")).unwrap();
+        let mut out = Vec::new();
+        e.extract(RbdDiff::new(&mut out).to_snap("backy-1"))
+            .unwrap();
+        let mut image = Vec::new();
+        let snaps = apply(&out, &mut image);
+        assert!(image == IMAGE[..]);
+        assert_eq!(snaps, [None, Some("backy-1".to_owned())]);
+    }
+
+    #[test]
+    fn differential() {
+        let store = store_tar();
+        let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98")).unwrap();
+        // first and last chunk differ
+        let base = store.path().join("BASE000000000000000000");
+        fs::write(
+            &base,
+            r#"{"mapping": {"0": "c72b4ba82d1f51b71c8a18195ad33fc8",
+                            "1": "c72b4ba82d1f51b71c8a18195ad33fc8",
+                            "2": "c72b4ba82d1f51b71c8a18195ad33fc8",
+                            "3": "4db6e194fd398e8edb76e11054d73eb0"},
+                "size": 16777216}"#,
+        )
+        .unwrap();
+        let base = Extractor::init(base).unwrap();
+        let unchanged = e.unchanged_since(&base).unwrap();
+        assert_eq!(unchanged, &[1, 2]);
+        let mut image = Vec::new();
+        base.extract(Stream::new(&mut image)).unwrap();
+        let mut out = Vec::new();
+        e.extract(RbdDiff::new(&mut out).since("base", unchanged))
+            .unwrap();
+        // two chunks: one data record, one zero record
+        assert!(out.len() < 2 * CHUNKSZ);
+        let snaps = apply(&out, &mut image);
+        assert!(image == IMAGE[..]);
+        assert_eq!(snaps, [Some("base".to_owned()), None]);
+    }
+}
@@ -1,17 +1,165 @@
 use super::{Error, Result, WriteOut, WriteOutBuilder};
-use crate::{Chunk, Data, CHUNKSZ, ZERO_CHUNK};
+use crate::{is_zero, zero_chunk, Chunk, Data, Progress, CHUNKSZ};
 
-use crossbeam::channel::{Receiver, Sender};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crossbeam::channel::Receiver;
 use std::collections::BinaryHeap;
 use std::fmt;
-use std::io::Write;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Statistics about the reorder queue of a [Stream](struct.Stream.html) writer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReorderStats {
+    /// Maximum number of chunk positions waiting to be written
+    pub max_depth: usize,
+    /// Maximum amount of decompressed data held back
+    pub max_buffered: u64,
+    /// Time spent waiting for the next chunk in sequence while others were queued
+    pub stall: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Position {
+    expect_seq: u32,
+    held: usize,
+}
+
+/// State shared between a reordering writer and the decoder threads.
+#[derive(Debug, Default)]
+pub struct Reorder {
+    pos: Mutex<Position>,
+    moved: Condvar,
+    stats: Mutex<ReorderStats>,
+}
+
+impl Reorder {
+    /// Delays loading the chunk starting at `seq` while more than `window` chunks are held back
+    /// and `seq` is not the one the writer is waiting for. This leaves CPU time to the thread
+    /// which is about to deliver the next chunk in sequence.
+    pub fn throttle(&self, seq: u32, window: usize) {
+        let pos = self.pos.lock().expect("poisoned lock");
+        drop(
+            self.moved
+                .wait_while(pos, |p| seq > p.expect_seq && p.held > window)
+                .expect("poisoned lock"),
+        );
+    }
+
+    /// Statistics of the last run.
+    pub fn stats(&self) -> ReorderStats {
+        self.stats.lock().expect("poisoned lock").clone()
+    }
+
+    fn update(&self, expect_seq: u32, held: usize) {
+        *self.pos.lock().expect("poisoned lock") = Position { expect_seq, held };
+        self.moved.notify_all();
+    }
+
+    // Releases all throttled threads for good.
+    pub(super) fn finish(&self, stats: ReorderStats) {
+        self.update(u32::MAX, 0);
+        *self.stats.lock().expect("poisoned lock") = stats;
+    }
+}
+
+/// Header which announces the image size at the start of a stream, for consumers which need to
+/// know it before the data, e.g. to allocate the target.
+///
+/// ```text
+/// magic:"BACKYIMG" version:u16 header_len:u16 chunk_size:u32 size:u64 reserved:u64
+/// ```
+///
+/// All numbers are big endian. `header_len` is the total length of the header including
+/// the magic. Consumers must skip any bytes of longer headers which they don't know about:
+/// fields are only ever appended. `version` changes only if the layout above changes
+/// incompatibly, so consumers reject versions they don't know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamHeader {
+    pub version: u16,
+    /// Size of the chunks the image is composed of
+    pub chunk_size: u32,
+    /// Number of image bytes which follow the header
+    pub size: u64,
+}
+
+impl StreamHeader {
+    pub const MAGIC: &'static [u8] = b"BACKYIMG";
+    pub const VERSION: u16 = 1;
+    /// Length of a version 1 header
+    pub const LEN: usize = 32;
+
+    pub fn new(size: u64) -> Self {
+        Self {
+            version: Self::VERSION,
+            chunk_size: CHUNKSZ as u32,
+            size,
+        }
+    }
+
+    pub fn write_to<W: Write + ?Sized>(&self, w: &mut W) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(Self::LEN);
+        buf.extend_from_slice(Self::MAGIC);
+        buf.write_u16::<BigEndian>(self.version)?;
+        buf.write_u16::<BigEndian>(Self::LEN as u16)?;
+        buf.write_u32::<BigEndian>(self.chunk_size)?;
+        buf.write_u64::<BigEndian>(self.size)?;
+        buf.write_u64::<BigEndian>(0)?;
+        w.write_all(&buf)
+    }
+
+    /// Reads a header and leaves `r` at the first image byte. Fails with `InvalidData` if the
+    /// stream does not start with a header of a known version and with `UnexpectedEof` if the
+    /// stream ends within the header.
+    pub fn read_from<R: Read + ?Sized>(r: &mut R) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if magic != Self::MAGIC {
+            return Err(invalid("stream does not start with an image header".into()));
+        }
+        let version = r.read_u16::<BigEndian>()?;
+        if version != Self::VERSION {
+            return Err(invalid(format!("unknown image header version {}", version)));
+        }
+        let len = usize::from(r.read_u16::<BigEndian>()?);
+        if len < Self::LEN {
+            return Err(invalid("image header too short".into()));
+        }
+        let chunk_size = r.read_u32::<BigEndian>()?;
+        let size = r.read_u64::<BigEndian>()?;
+        // everything after `size`, starting with the reserved field
+        let rest = (len - 24) as u64;
+        if io::copy(&mut r.take(rest), &mut io::sink())? < rest {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Self {
+            version,
+            chunk_size,
+            size,
+        })
+    }
+}
 
 /// Streaming restore target, i.e. write to stdout.
 ///
 /// The incoming chunk stream is assembled into sequence order in memory. Chunks are
 /// written out eagerly to keep memory usage to a minimum.
+///
+/// Targets which can seek, e.g. regular files, may be created with [sparse](#method.sparse) to
+/// skip over zero chunks instead of writing them. With [header](#method.header), the image is
+/// preceded by a [StreamHeader](struct.StreamHeader.html).
 pub struct Stream<W: ?Sized + Write> {
+    reorder: Arc<Reorder>,
+    header: bool,
+    /// Image size, known once built
+    size: u64,
+    /// Moves the write position forward, set for sparse output
+    skip: Option<fn(&mut W, i64) -> io::Result<u64>>,
+    /// Zero bytes to skip before the next write
+    hole: u64,
     out: Box<W>,
 }
 
@@ -19,48 +167,195 @@ pub struct Stream<W: ?Sized + Write> {
 impl<W: Write + Send + Sync> WriteOutBuilder for Stream<W> {
     type Impl = Stream<W>;
 
-    // Does not really do anything except fulfilling the trait requirement.
-    fn build(self, _size: u64, _threads: u8) -> Self::Impl {
+    fn build(mut self, size: u64, _threads: u8) -> Self::Impl {
+        self.size = size;
         self
     }
 }
 
 impl<W: Write + Send + Sync> Stream<W> {
     pub fn new(out: W) -> Self {
-        Self { out: Box::new(out) }
+        Self {
+            reorder: Arc::default(),
+            header: false,
+            size: 0,
+            skip: None,
+            hole: 0,
+            out: Box::new(out),
+        }
     }
 
-    fn write(&mut self, data: &Data, seq: u32, progress: &Sender<usize>) -> Result<()> {
-        self.out
-            .write_all(match data {
-                Data::Some(d) => d,
-                Data::Zero => &ZERO_CHUNK,
-            })
-            .map_err(|e| Error::WriteChunk(seq, e))?;
-        progress.send(CHUNKSZ)?;
+    /// Writes a [StreamHeader](struct.StreamHeader.html) before the image.
+    pub fn header(mut self, enable: bool) -> Self {
+        self.header = enable;
+        self
+    }
+
+    fn write(&mut self, data: &Data, seq: u32, progress: &Progress) -> Result<()> {
+        let zero = match data {
+            Data::Some(d) => is_zero(d),
+            Data::Zero => true,
+        };
+        match data {
+            _ if zero && self.skip.is_some() => self.hole += CHUNKSZ as u64,
+            _ => {
+                self.skip_hole().map_err(|e| Error::WriteChunk(seq, e))?;
+                let buf = match data {
+                    Data::Some(d) => &d[..],
+                    Data::Zero => zero_chunk().map_err(|e| Error::WriteChunk(seq, e))?,
+                };
+                self.out
+                    .write_all(buf)
+                    .map_err(|e| Error::WriteChunk(seq, e))?;
+            }
+        }
+        progress.add(CHUNKSZ);
         Ok(())
     }
+
+    // Seeks over pending zero chunks. Targets which turn out not to be seekable get zeroes
+    // written instead.
+    fn skip_hole(&mut self) -> io::Result<()> {
+        if self.hole == 0 {
+            return Ok(());
+        }
+        let skip = self.skip.expect("holes only occur with sparse output");
+        match skip(&mut self.out, self.hole as i64) {
+            Err(e) if e.raw_os_error() == Some(libc::ESPIPE) => {
+                self.skip = None;
+                let zeros = zero_chunk()?;
+                while self.hole > 0 {
+                    let n = self.hole.min(CHUNKSZ as u64);
+                    self.out.write_all(&zeros[..n as usize])?;
+                    self.hole -= n;
+                }
+            }
+            res => {
+                res?;
+            }
+        }
+        self.hole = 0;
+        Ok(())
+    }
+
+    // Makes sure that a trailing hole counts towards the size of the output.
+    fn finish_hole(&mut self) -> io::Result<()> {
+        if self.hole == 0 {
+            return Ok(());
+        }
+        self.hole -= 1;
+        self.skip_hole()?;
+        self.out.write_all(&[0])
+    }
+
+    fn run(
+        &mut self,
+        chunks: Receiver<Chunk>,
+        progress: &Progress,
+        stats: &mut ReorderStats,
+    ) -> Result<()> {
+        let reorder = Arc::clone(&self.reorder);
+        let end = in_order(chunks, &reorder, progress, stats, |d, seq| {
+            self.write(&d, seq, progress)
+        })?;
+        self.finish_hole()
+            .map_err(|e| Error::WriteChunk(end.saturating_sub(1), e))
+    }
 }
 
-impl<W: Write + Send + Sync> WriteOut for Stream<W> {
-    fn receive(mut self, chunks: Receiver<Chunk>, progress: Sender<usize>) -> Result<()> {
-        let mut queue = Queue::new();
-        let mut expect_seq = 0;
-        for chunk in chunks {
-            let data = Rc::new(chunk.data);
-            chunk
-                .seqs
-                .into_iter()
-                .for_each(|seq| queue.put(seq, Rc::clone(&data)));
-            while let Some(d) = queue.get(expect_seq) {
-                self.write(&d, expect_seq, &progress)?;
-                expect_seq += 1;
+/// Sorts `chunks` back into sequence order and passes each position to `write`, which also
+/// accounts for the bytes written. Chunks mapped to several positions are passed once per
+/// position in ascending order; the last position gets the only remaining reference. Returns
+/// the number of positions written.
+pub(super) fn in_order<F>(
+    chunks: Receiver<Chunk>,
+    reorder: &Reorder,
+    progress: &Progress,
+    stats: &mut ReorderStats,
+    mut write: F,
+) -> Result<u32>
+where
+    F: FnMut(Rc<Data>, u32) -> Result<()>,
+{
+    let mut queue = Queue::new();
+    let mut expect_seq = 0;
+    let mut held = 0;
+    loop {
+        let waiting = Instant::now();
+        let chunk = match chunks.recv() {
+            Ok(chunk) => chunk,
+            Err(_) => break,
+        };
+        if !queue.is_empty() {
+            stats.stall += waiting.elapsed();
+        }
+        if let Data::Some(_) = chunk.data {
+            held += 1;
+        }
+        let data = Rc::new(chunk.data);
+        let max = chunk.seqs.iter().copied().max();
+        chunk
+            .seqs
+            .into_iter()
+            .for_each(|seq| queue.put(seq, Rc::clone(&data), Some(seq) == max));
+        drop(data);
+        // all positions of a chunk have been written after the last one
+        while let Some((d, last)) = queue.get(expect_seq) {
+            let zero = *d == Data::Zero;
+            write(d, expect_seq)?;
+            expect_seq += 1;
+            if last {
+                progress.add_chunks(1);
+                if !zero {
+                    held -= 1;
+                }
             }
         }
-        assert!(queue.is_empty());
+        stats.max_depth = stats.max_depth.max(queue.len());
+        stats.max_buffered = stats.max_buffered.max((held * CHUNKSZ) as u64);
+        reorder.update(expect_seq, held);
+    }
+    if !queue.is_empty() {
+        // some chunk never arrived, i.e. an upstream stage failed
+        return Err(Error::Incomplete(expect_seq));
+    }
+    Ok(expect_seq)
+}
+
+impl<W: Write + Seek + Send + Sync> Stream<W> {
+    /// Like [new](#method.new), but seeks over zero chunks (unmapped or all NUL bytes) to create a
+    /// sparse file. Chunks are
+    /// still written strictly in order. Falls back to writing zeroes if `out` turns out not to
+    /// be seekable, e.g. if it is a pipe.
+    pub fn sparse(out: W) -> Self {
+        Self {
+            skip: Some(|w, n| w.seek(SeekFrom::Current(n))),
+            ..Self::new(out)
+        }
+    }
+}
+
+impl<W: Write + Send + Sync> WriteOut for Stream<W> {
+    fn prepare(&mut self) -> Result<()> {
+        if self.header {
+            StreamHeader::new(self.size)
+                .write_to(&mut self.out)
+                .map_err(Error::Header)?;
+        }
         Ok(())
     }
 
+    fn receive(&mut self, chunks: Receiver<Chunk>, progress: Progress) -> Result<()> {
+        let mut stats = ReorderStats::default();
+        let res = self.run(chunks, &progress, &mut stats);
+        self.reorder.finish(stats);
+        res
+    }
+
+    fn reorder(&self) -> Option<Arc<Reorder>> {
+        Some(Arc::clone(&self.reorder))
+    }
+
     fn name(&self) -> String {
         "stdout".to_owned()
     }
@@ -75,6 +370,8 @@ impl<W: Write + Send + Sync> fmt::Debug for Stream<W> {
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq)]
 struct WaitingChunk {
     prio: isize,
+    /// Highest position of this chunk
+    last: bool,
     data: Rc<Data>,
 }
 
@@ -88,17 +385,19 @@ impl Queue {
         Queue(BinaryHeap::new())
     }
 
-    fn put(&mut self, seq: u32, data: Rc<Data>) {
+    fn put(&mut self, seq: u32, data: Rc<Data>, last: bool) {
         self.0.push(WaitingChunk {
             prio: -(seq as isize),
+            last,
             data,
         })
     }
 
-    fn get(&mut self, expect_seq: u32) -> Option<Rc<Data>> {
+    fn get(&mut self, expect_seq: u32) -> Option<(Rc<Data>, bool)> {
         if let Some(e) = self.0.peek() {
             if e.prio == -(expect_seq as isize) {
-                return Some(self.0.pop().unwrap().data);
+                let e = self.0.pop().unwrap();
+                return Some((e.data, e.last));
             }
         }
         None
@@ -108,7 +407,6 @@ impl Queue {
         self.0.is_empty()
     }
 
-    #[allow(dead_code)]
     fn len(&self) -> usize {
         self.0.len()
     }
@@ -139,13 +437,130 @@ mod tests {
             .expect("cannot send chunks");
         }
         drop(raw);
-        let s = Stream::new(&mut buf);
-        let (p_tx, _p_rx) = unbounded();
-        s.receive(raw_rx, p_tx)?;
+        let mut s = Stream::new(&mut buf);
+        let reorder = s.reorder().unwrap();
+        let (progress, _monitor) = crate::progress::channel();
+        s.receive(raw_rx, progress)?;
+        drop(s);
         assert_eq!(
             (0..4).map(|i| buf[i * CS]).collect::<Vec<_>>(),
             &[0, 1, 2, 3]
         );
+        let stats = reorder.stats();
+        // 1 waits for 0; 3 waits for 2
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(stats.max_buffered, 2 * CS as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn sparse_output() -> Result<()> {
+        let td = tempdir::TempDir::new("stream").unwrap();
+        let path = td.path().join("img");
+        let (tx, rx) = unbounded();
+        tx.send(Chunk {
+            seqs: smallvec![2],
+            data: Data::Some(CHUNKS[2].to_vec()),
+        })
+        .unwrap();
+        tx.send(Chunk {
+            seqs: smallvec![0, 3],
+            data: Data::Zero,
+        })
+        .unwrap();
+        tx.send(Chunk {
+            seqs: smallvec![1],
+            data: Data::Some(CHUNKS[0].to_vec()),
+        })
+        .unwrap();
+        drop(tx);
+        let f = std::fs::File::create(&path).unwrap();
+        let (progress, _monitor) = crate::progress::channel();
+        Stream::sparse(f).receive(rx, progress)?;
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), 4 * CS);
+        assert!(data[..2 * CS].iter().all(|&b| b == 0));
+        assert!(data[2 * CS..3 * CS].iter().all(|&b| b == 2));
+        assert!(data[3 * CS..].iter().all(|&b| b == 0));
+        use std::os::unix::fs::MetadataExt;
+        assert!(std::fs::metadata(&path).unwrap().blocks() * 512 < 4 * CS as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn sparse_falls_back_to_zeroes() -> Result<()> {
+        // a writer which can't seek, like a pipe
+        struct Pipe(Vec<u8>);
+        impl Write for Pipe {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        impl Seek for Pipe {
+            fn seek(&mut self, _: SeekFrom) -> io::Result<u64> {
+                Err(io::Error::from_raw_os_error(libc::ESPIPE))
+            }
+        }
+        let (tx, rx) = unbounded();
+        tx.send(Chunk {
+            seqs: smallvec![0, 2],
+            data: Data::Zero,
+        })
+        .unwrap();
+        tx.send(Chunk {
+            seqs: smallvec![1],
+            data: Data::Some(CHUNKS[1].to_vec()),
+        })
+        .unwrap();
+        drop(tx);
+        let mut s = Stream::sparse(Pipe(Vec::new()));
+        let (progress, _monitor) = crate::progress::channel();
+        s.receive(rx, progress)?;
+        let data = &s.out.0;
+        assert_eq!(data.len(), 3 * CS);
+        assert!(data[CS..2 * CS].iter().all(|&b| b == 1));
+        assert_eq!(data.iter().filter(|&&b| b == 0).count(), 2 * CS);
+        Ok(())
+    }
+
+    #[test]
+    fn header_precedes_image() -> Result<()> {
+        let (tx, rx) = unbounded();
+        tx.send(Chunk {
+            seqs: smallvec![0],
+            data: Data::Some(CHUNKS[3].to_vec()),
+        })
+        .unwrap();
+        drop(tx);
+        let mut s = Stream::new(Vec::new()).header(true).build(CS as u64, 1);
+        let (progress, _monitor) = crate::progress::channel();
+        s.prepare()?;
+        s.receive(rx, progress)?;
+        let mut data = &s.out[..];
+        assert_eq!(data.len(), StreamHeader::LEN + CS);
+        assert_eq!(
+            StreamHeader::read_from(&mut data).unwrap(),
+            StreamHeader::new(CS as u64)
+        );
+        assert!(data.iter().all(|&b| b == 3));
+
+        // longer headers with additional fields
+        let mut longer = s.out[..StreamHeader::LEN].to_vec();
+        longer[11] = 40;
+        longer.extend_from_slice(b"EXTENDEDimage");
+        let mut r = &longer[..];
+        assert_eq!(StreamHeader::read_from(&mut r).unwrap().size, CS as u64);
+        assert_eq!(r, b"image");
+        let err = StreamHeader::read_from(&mut &CHUNKS[0][..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = StreamHeader::read_from(&mut &longer[..36]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        longer[9] = 2;
+        let err = StreamHeader::read_from(&mut &longer[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
         Ok(())
     }
 }
@@ -0,0 +1,504 @@
+//! Restore target which uploads the image to a server speaking the tus resumable upload
+//! protocol, e.g. a cloud DR provider.
+//!
+//! Only the core protocol and the creation extension are used: the upload is created with a
+//! `POST` to the endpoint and filled with sequential `PATCH` requests. If a request fails in
+//! transit or the server is temporarily unavailable, the current upload offset is queried with
+//! `HEAD` and the upload continues from there. See https://tus.io/protocols/resumable-upload
+
+use super::stream::in_order;
+use super::{Error, Reorder, ReorderStats, Result, Retry, WriteOut, WriteOutBuilder, WriteReport};
+use crate::{Chunk, Data, Progress, CHUNKSZ};
+
+use crossbeam::channel::Receiver;
+use log::{info, warn};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const TUS_VERSION: &str = "1.0.0";
+/// Amount of image data sent with each PATCH request. A failed request is repeated from the
+/// offset the server reports, so this much must be kept in memory.
+const PART_SIZE: usize = 16 * CHUNKSZ;
+/// Upper bound for the pause between retries
+const MAX_DELAY: Duration = Duration::from_secs(60);
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Restore target on a tus server.
+///
+/// Uploads survive connection resets and server restarts as long as the server keeps the
+/// partial upload: failed or partially accepted requests are retried up to 10 times per part
+/// by default, waiting 1s, 2s, 4s etc. (at most one minute) after failures. Zero chunks are uploaded as well since the
+/// protocol has no notion of holes.
+#[derive(Debug, Clone)]
+pub struct Tus {
+    url: String,
+    endpoint: String,
+    headers: Vec<(String, String)>,
+    retry: Retry,
+    part_size: usize,
+}
+
+impl Tus {
+    /// Parses a tus URL: `tus+https://HOST/ENDPOINT` or `tus+http://HOST/ENDPOINT`. The upload
+    /// is created at the endpoint, i.e. the URL without the `tus+` prefix.
+    pub fn new(url: &str) -> Result<Self> {
+        let endpoint = url
+            .strip_prefix("tus+")
+            .filter(|u| u.starts_with("https://") || u.starts_with("http://"))
+            .filter(|u| !host(u).is_empty())
+            .ok_or_else(|| Error::TusUrl(url.to_owned()))?;
+        Ok(Self {
+            url: url.to_owned(),
+            endpoint: endpoint.to_owned(),
+            headers: Vec::new(),
+            retry: Retry::new(10),
+            part_size: PART_SIZE,
+        })
+    }
+
+    /// Returns true if `s` looks like a tus URL rather than a file name.
+    pub fn is_url(s: &str) -> bool {
+        s.starts_with("tus+https://") || s.starts_with("tus+http://")
+    }
+
+    /// Adds a header to all requests, e.g. for authentication.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Sets how often failed requests are repeated. `keep_going` is not supported since
+    /// uploads cannot have gaps.
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+}
+
+impl WriteOutBuilder for Tus {
+    type Impl = TusWriteOut;
+
+    fn build(self, size: u64, _threads: u8) -> Self::Impl {
+        TusWriteOut {
+            size,
+            agent: ureq::AgentBuilder::new()
+                .timeout_read(TIMEOUT)
+                .timeout_write(TIMEOUT)
+                .build(),
+            upload: None,
+            offset: 0,
+            part: Vec::with_capacity(self.part_size),
+            reorder: Arc::default(),
+            tus: self,
+        }
+    }
+}
+
+// Part of `url` between scheme and path.
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, r)| r);
+    rest.split('/').next().unwrap_or_default()
+}
+
+/// Resolves the `Location` header `loc` relative to the endpoint `base`.
+fn resolve(base: &str, loc: &str) -> String {
+    if loc.contains("://") {
+        return loc.to_owned();
+    }
+    if loc.starts_with('/') {
+        let (scheme, _) = base.split_once("://").unwrap_or(("http", ""));
+        return format!("{}://{}{}", scheme, host(base), loc);
+    }
+    format!("{}/{}", base.trim_end_matches('/'), loc)
+}
+
+/// Failed request.
+#[derive(Debug)]
+struct Failure {
+    /// Transport errors and server states which may clear up by themselves
+    transient: bool,
+    err: io::Error,
+}
+
+impl From<ureq::Error> for Failure {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            // 409: offset mismatch after a lost response, 423: upload locked by a stale request
+            ureq::Error::Status(code, resp) => Self {
+                transient: matches!(code, 409 | 423 | 429) || code >= 500,
+                err: io::Error::other(format!("server replied {} {}", code, resp.status_text())),
+            },
+            ureq::Error::Transport(t) => Self {
+                transient: true,
+                err: io::Error::other(t.to_string()),
+            },
+        }
+    }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Self {
+            transient: false,
+            err,
+        }
+    }
+}
+
+type Response<T> = std::result::Result<T, Failure>;
+
+fn protocol(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn upload_offset(resp: &ureq::Response) -> io::Result<u64> {
+    resp.header("Upload-Offset")
+        .and_then(|o| o.trim().parse().ok())
+        .ok_or_else(|| protocol("response lacks Upload-Offset".into()))
+}
+
+pub struct TusWriteOut {
+    tus: Tus,
+    size: u64,
+    agent: ureq::Agent,
+    /// Upload URL, known after `prepare`
+    upload: Option<String>,
+    /// Number of bytes the server has confirmed
+    offset: u64,
+    /// Data which follows `offset`
+    part: Vec<u8>,
+    reorder: Arc<Reorder>,
+}
+
+impl TusWriteOut {
+    fn err(&self, e: io::Error) -> Error {
+        Error::Tus(self.tus.url.clone(), e)
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let req = self
+            .agent
+            .request(method, url)
+            .set("Tus-Resumable", TUS_VERSION);
+        self.tus
+            .headers
+            .iter()
+            .fold(req, |req, (n, v)| req.set(n, v))
+    }
+
+    fn create(&self) -> Response<String> {
+        let resp = self
+            .request("POST", &self.tus.endpoint)
+            .set("Upload-Length", &self.size.to_string())
+            .send_bytes(&[])?;
+        match resp.header("Location") {
+            Some(loc) if resp.status() == 201 => Ok(resolve(&self.tus.endpoint, loc)),
+            _ => Err(ureq::Error::Status(resp.status(), resp).into()),
+        }
+    }
+
+    fn upload(&self) -> io::Result<&str> {
+        self.upload
+            .as_deref()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    // Sends `data` which starts at `offset` and returns the new offset.
+    fn patch(&self, offset: u64, data: &[u8]) -> Response<u64> {
+        let resp = self
+            .request("PATCH", self.upload()?)
+            .set("Upload-Offset", &offset.to_string())
+            .set("Content-Type", "application/offset+octet-stream")
+            .send_bytes(data)?;
+        Ok(upload_offset(&resp)?)
+    }
+
+    // Asks the server how much it has received.
+    fn head(&self) -> Response<u64> {
+        let resp = self.request("HEAD", self.upload()?).call()?;
+        Ok(upload_offset(&resp)?)
+    }
+
+    /// Uploads the pending part, resuming after transient errors. Requests the server takes only
+    /// partially count against the retry budget as well, so a server which never catches up
+    /// cannot keep us busy forever.
+    fn send_part(&mut self) -> io::Result<()> {
+        let start = self.offset;
+        let end = start + self.part.len() as u64;
+        let mut delay = self.tus.retry.delay;
+        let mut attempts = 0;
+        while self.offset < end {
+            let data = &self.part[(self.offset - start) as usize..];
+            match self.patch(self.offset, data) {
+                Ok(o) => {
+                    if o < end {
+                        attempts += 1;
+                    }
+                    self.offset = o;
+                }
+                Err(f) if f.transient && attempts < self.tus.retry.attempts => {
+                    attempts += 1;
+                    warn!(
+                        "Failed to upload at offset {}, resuming in {:?}: {}",
+                        self.offset, delay, f.err
+                    );
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_DELAY);
+                    match self.head() {
+                        Ok(o) => self.offset = o,
+                        Err(f) => warn!("Failed to query upload offset: {}", f.err),
+                    }
+                }
+                Err(f) => return Err(f.err),
+            }
+            if self.offset < start || self.offset > end {
+                return Err(protocol(format!(
+                    "server reports offset {} outside of {}..{}",
+                    self.offset, start, end
+                )));
+            }
+            if attempts > self.tus.retry.attempts {
+                return Err(protocol(format!(
+                    "upload stuck at offset {} after {} partial requests",
+                    self.offset, attempts
+                )));
+            }
+        }
+        self.part.clear();
+        Ok(())
+    }
+
+    fn write(&mut self, data: &Data, seq: u32, progress: &Progress) -> Result<()> {
+        let len = (self.size - self.offset - self.part.len() as u64).min(CHUNKSZ as u64);
+        let len = len as usize;
+        match data {
+            Data::Some(d) => self.part.extend_from_slice(&d[..len]),
+            Data::Zero => self.part.resize(self.part.len() + len, 0),
+        }
+        if self.part.len() >= self.tus.part_size {
+            self.send_part().map_err(|e| Error::WriteChunk(seq, e))?;
+        }
+        progress.add(len);
+        Ok(())
+    }
+
+    fn run(
+        &mut self,
+        chunks: Receiver<Chunk>,
+        progress: &Progress,
+        stats: &mut ReorderStats,
+    ) -> Result<()> {
+        let reorder = Arc::clone(&self.reorder);
+        let end = in_order(chunks, &reorder, progress, stats, |d, seq| {
+            self.write(&d, seq, progress)
+        })?;
+        self.send_part()
+            .map_err(|e| Error::WriteChunk(end.saturating_sub(1), e))
+    }
+}
+
+impl WriteOut for TusWriteOut {
+    fn prepare(&mut self) -> Result<()> {
+        let mut delay = self.tus.retry.delay;
+        let mut attempts = 0;
+        loop {
+            match self.create() {
+                Ok(url) => {
+                    info!("Uploading to {}", url);
+                    self.upload = Some(url);
+                    return Ok(());
+                }
+                Err(f) if f.transient && attempts < self.tus.retry.attempts => {
+                    attempts += 1;
+                    warn!(
+                        "Failed to create upload, retrying in {:?}: {}",
+                        delay, f.err
+                    );
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(MAX_DELAY);
+                }
+                Err(f) => return Err(self.err(f.err)),
+            }
+        }
+    }
+
+    fn receive(&mut self, chunks: Receiver<Chunk>, progress: Progress) -> Result<()> {
+        let mut stats = ReorderStats::default();
+        let res = self.run(chunks, &progress, &mut stats);
+        self.reorder.finish(stats);
+        res
+    }
+
+    fn finalize(&mut self, _report: &WriteReport) -> Result<()> {
+        if self.offset != self.size {
+            return Err(self.err(protocol(format!(
+                "upload ends at {} of {} bytes",
+                self.offset, self.size
+            ))));
+        }
+        Ok(())
+    }
+
+    fn abort(&mut self, _error: &dyn std::error::Error) {
+        // servers discard unfinished uploads after a while or on DELETE (termination extension)
+        if let Ok(url) = self.upload() {
+            self.request("DELETE", url).call().ok();
+        }
+    }
+
+    fn reorder(&self) -> Option<Arc<Reorder>> {
+        Some(Arc::clone(&self.reorder))
+    }
+
+    fn name(&self) -> String {
+        format!("tus:{}", self.tus.endpoint)
+    }
+
+    fn target(&self) -> String {
+        self.upload
+            .clone()
+            .unwrap_or_else(|| self.tus.endpoint.clone())
+    }
+}
+
+impl fmt::Debug for TusWriteOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<TusWriteOut {} {} bytes>", self.tus.endpoint, self.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{store_tar, IMAGE};
+    use crate::Extractor;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn parse_urls() {
+        let t = Tus::new("tus+https://dr.example.com/files/").unwrap();
+        assert_eq!(t.endpoint, "https://dr.example.com/files/");
+        for bad in &[
+            "https://h/files",
+            "tus+ftp://h/",
+            "tus+http:///files",
+            "nbd://h/x",
+        ] {
+            assert!(Tus::new(bad).is_err(), "{}", bad);
+        }
+        assert!(Tus::is_url("tus+http://h/") && !Tus::is_url("tus.img"));
+    }
+
+    #[test]
+    fn locations() {
+        let base = "https://h:8443/files/";
+        assert_eq!(resolve(base, "https://x/y"), "https://x/y");
+        assert_eq!(resolve(base, "/uploads/1"), "https://h:8443/uploads/1");
+        assert_eq!(resolve(base, "1"), "https://h:8443/files/1");
+    }
+
+    // Reads one request and returns method, Upload-Offset and body.
+    fn request(s: &TcpStream) -> Option<(String, u64, Vec<u8>)> {
+        let mut r = BufReader::new(s);
+        let mut line = String::new();
+        if r.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let method = line.split(' ').next().unwrap().to_owned();
+        let (mut offset, mut len) = (0, 0);
+        loop {
+            let mut h = String::new();
+            r.read_line(&mut h).unwrap();
+            let h = h.trim_end();
+            if h.is_empty() {
+                break;
+            }
+            let (name, value) = h.split_once(':').unwrap();
+            match name.to_ascii_lowercase().as_str() {
+                "upload-offset" => offset = value.trim().parse().unwrap(),
+                "content-length" => len = value.trim().parse().unwrap(),
+                "tus-resumable" => assert_eq!(value.trim(), TUS_VERSION),
+                _ => (),
+            }
+        }
+        let mut body = vec![0; len];
+        r.read_exact(&mut body).unwrap();
+        Some((method, offset, body))
+    }
+
+    // Minimal tus server which drops the connection in the middle of the second PATCH request
+    // after having stored half of it and takes at most `max_patch` bytes of each request.
+    // Returns the upload after it is complete or deleted.
+    fn serve(l: TcpListener, max_patch: usize) -> Vec<u8> {
+        let mut upload = Vec::new();
+        let mut patches = 0;
+        for s in l.incoming() {
+            let mut s = s.unwrap();
+            let (method, offset, body) = match request(&s) {
+                Some(r) => r,
+                None => continue,
+            };
+            let reply = match method.as_str() {
+                "POST" => "201 Created\r\nLocation: /files/1".to_owned(),
+                "HEAD" => format!("200 OK\r\nUpload-Offset: {}", upload.len()),
+                "PATCH" if offset != upload.len() as u64 => "409 Conflict".to_owned(),
+                "PATCH" => {
+                    patches += 1;
+                    if patches == 2 {
+                        upload.extend_from_slice(&body[..body.len() / 2]);
+                        continue;
+                    }
+                    upload.extend_from_slice(&body[..body.len().min(max_patch)]);
+                    format!("204 No Content\r\nUpload-Offset: {}", upload.len())
+                }
+                "DELETE" => return upload,
+                m => panic!("unexpected method {}", m),
+            };
+            write!(
+                s,
+                "HTTP/1.1 {}\r\nTus-Resumable: 1.0.0\r\nContent-Length: 0\r\n\
+                 Connection: close\r\n\r\n",
+                reply
+            )
+            .unwrap();
+            if upload.len() == IMAGE.len() {
+                return upload;
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn resume_after_connection_reset() {
+        let store = store_tar();
+        let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98")).unwrap();
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tus+http://{}/files/", l.local_addr().unwrap());
+        let server = thread::spawn(move || serve(l, usize::MAX));
+        let mut retry = Retry::new(3);
+        retry.delay = Duration::from_millis(10);
+        let mut tus = Tus::new(&url).unwrap().retry(retry);
+        tus.part_size = CHUNKSZ;
+        e.extract(tus).unwrap();
+        assert!(server.join().unwrap() == IMAGE[..]);
+    }
+
+    #[test]
+    fn give_up_on_partial_progress() {
+        let store = store_tar();
+        let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98")).unwrap();
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("tus+http://{}/files/", l.local_addr().unwrap());
+        let server = thread::spawn(move || serve(l, 4096));
+        let mut retry = Retry::new(3);
+        retry.delay = Duration::from_millis(10);
+        let mut tus = Tus::new(&url).unwrap().retry(retry);
+        tus.part_size = CHUNKSZ;
+        assert!(e.extract(tus).is_err());
+        assert!(server.join().unwrap().len() < IMAGE.len());
+    }
+}
@@ -0,0 +1,225 @@
+use super::randomaccess::RandomWriteOut;
+use super::{Error, RandomAccess, Result, WriteOut, WriteOutBuilder, WriteReport};
+use crate::{Chunk, Progress};
+
+use byteorder::{BigEndian, ByteOrder};
+use crossbeam::channel::Receiver;
+use std::fmt;
+use std::io::{self, IoSlice};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+const SECTOR: u64 = 512;
+// 2000-01-01 00:00:00 UTC, start of the VHD epoch
+const EPOCH: Duration = Duration::from_secs(946_684_800);
+
+/// Fixed VHD restore target.
+///
+/// Writes the raw image like [RandomAccess](struct.RandomAccess.html) and appends the 512 byte
+/// footer of a fixed-size VHD, so the result can be attached in Hyper-V or uploaded to Azure
+/// without conversion. Images whose size is not a multiple of 512 are padded with zeros. Note
+/// that Azure additionally requires the size to be a multiple of 1 MiB.
+#[derive(Debug, Clone)]
+pub struct Vhd {
+    raw: RandomAccess,
+}
+
+impl Vhd {
+    /// Wraps a configured file target. Block devices must be at least one sector larger than
+    /// the image to hold the footer.
+    pub fn new(raw: RandomAccess) -> Self {
+        Self { raw }
+    }
+}
+
+impl WriteOutBuilder for Vhd {
+    type Impl = VhdWriteOut;
+
+    fn build(self, size: u64, threads: u8) -> Self::Impl {
+        VhdWriteOut {
+            raw: self.raw.build(size, threads),
+            footer: VhdFooter::new(size),
+        }
+    }
+}
+
+pub struct VhdWriteOut {
+    raw: RandomWriteOut,
+    footer: VhdFooter,
+}
+
+impl WriteOut for VhdWriteOut {
+    fn prepare(&mut self) -> Result<()> {
+        self.raw.prepare()
+    }
+
+    fn receive(&mut self, chunks: Receiver<Chunk>, progress: Progress) -> Result<()> {
+        self.raw.receive(chunks, progress)
+    }
+
+    fn write_at_v(&self, bufs: &[IoSlice<'_>], offset: u64) -> io::Result<usize> {
+        self.raw.write_at_v(bufs, offset)
+    }
+
+    fn finalize(&mut self, report: &WriteReport) -> Result<()> {
+        let f = self
+            .raw
+            .file()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF));
+        f.and_then(|f| {
+            f.write_all_at(&self.footer.encode(SystemTime::now()), self.footer.size)?;
+            f.sync_data()
+        })
+        .map_err(|e| Error::OutputFile(PathBuf::from(self.raw.name()), e))?;
+        self.raw.finalize(report)
+    }
+
+    fn abort(&mut self, error: &dyn std::error::Error) {
+        self.raw.abort(error)
+    }
+
+    fn target(&self) -> String {
+        self.raw.target()
+    }
+
+    fn name(&self) -> String {
+        format!("vhd:{}", self.raw.name())
+    }
+}
+
+impl fmt::Debug for VhdWriteOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<VhdWriteOut {}>", self.raw.name())
+    }
+}
+
+/// Footer of a fixed VHD as described in Microsoft's "Virtual Hard Disk Image Format
+/// Specification".
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VhdFooter {
+    /// Disk size, padded to whole sectors. The footer starts here.
+    size: u64,
+    uuid: [u8; 16],
+}
+
+impl VhdFooter {
+    fn new(size: u64) -> Self {
+        let mut uuid: [u8; 16] = rand::random();
+        // random UUID (version 4, RFC 4122 variant)
+        uuid[6] = (uuid[6] & 0x0f) | 0x40;
+        uuid[8] = (uuid[8] & 0x3f) | 0x80;
+        Self {
+            size: size.div_ceil(SECTOR) * SECTOR,
+            uuid,
+        }
+    }
+
+    fn encode(&self, now: SystemTime) -> [u8; SECTOR as usize] {
+        let mut f = [0; SECTOR as usize];
+        f[0..8].copy_from_slice(b"conectix");
+        BigEndian::write_u32(&mut f[8..], 2); // features: reserved bit always set
+        BigEndian::write_u32(&mut f[12..], 0x0001_0000); // format version 1.0
+        BigEndian::write_u64(&mut f[16..], u64::MAX); // no dynamic header
+        let secs = now
+            .duration_since(SystemTime::UNIX_EPOCH + EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        BigEndian::write_u32(&mut f[24..], secs as u32);
+        f[28..32].copy_from_slice(b"bkex");
+        BigEndian::write_u32(&mut f[32..], 0x0001_0000);
+        f[36..40].copy_from_slice(b"Wi2k");
+        BigEndian::write_u64(&mut f[40..], self.size); // original size
+        BigEndian::write_u64(&mut f[48..], self.size); // current size
+        let (cylinders, heads, sectors) = geometry(self.size / SECTOR);
+        BigEndian::write_u16(&mut f[56..], cylinders);
+        f[58] = heads;
+        f[59] = sectors;
+        BigEndian::write_u32(&mut f[60..], 2); // disk type: fixed
+        f[68..84].copy_from_slice(&self.uuid);
+        let sum = f.iter().fold(0u32, |s, &b| s.wrapping_add(b.into()));
+        BigEndian::write_u32(&mut f[64..], !sum);
+        f
+    }
+}
+
+// CHS geometry as computed by the algorithm in the VHD specification's appendix. Disks larger
+// than about 127 GiB get the maximum geometry.
+fn geometry(sectors: u64) -> (u16, u8, u8) {
+    let total = sectors.min(65535 * 16 * 255);
+    let (spt, heads, cth) = if total >= 65535 * 16 * 63 {
+        (255, 16, total / 255)
+    } else {
+        let mut spt = 17;
+        let mut cth = total / spt;
+        let mut heads = cth.div_ceil(1024).max(4);
+        if cth >= heads * 1024 || heads > 16 {
+            spt = 31;
+            heads = 16;
+            cth = total / spt;
+        }
+        if cth >= heads * 1024 {
+            spt = 63;
+            heads = 16;
+            cth = total / spt;
+        }
+        (spt, heads, cth)
+    };
+    ((cth / heads) as u16, heads as u8, spt as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::store_tar;
+    use crate::Extractor;
+    use tempdir::TempDir;
+
+    #[test]
+    fn chs_geometry() {
+        // 10 MiB, 1 GiB, 127 GiB, 2 TiB
+        assert_eq!(geometry(20480), (301, 4, 17));
+        assert_eq!(geometry(2 << 20), (2080, 16, 63));
+        assert_eq!(geometry(127 << 21), (65278, 16, 255));
+        assert_eq!(geometry(1 << 32), (65535, 16, 255));
+    }
+
+    #[test]
+    fn footer_layout() {
+        let footer = VhdFooter {
+            size: 10 << 20,
+            uuid: [7; 16],
+        };
+        let f = footer.encode(SystemTime::UNIX_EPOCH + EPOCH + Duration::from_secs(42));
+        assert_eq!(&f[..8], b"conectix");
+        assert_eq!(BigEndian::read_u32(&f[24..]), 42);
+        assert_eq!(BigEndian::read_u64(&f[48..]), 10 << 20);
+        assert_eq!(BigEndian::read_u32(&f[60..]), 2);
+        // checksum is the complement of the byte sum with the checksum field zeroed
+        let mut g = f;
+        g[64..68].copy_from_slice(&[0; 4]);
+        let sum = g.iter().map(|&b| u32::from(b)).sum::<u32>();
+        assert_eq!(BigEndian::read_u32(&f[64..]), !sum);
+        assert_eq!(VhdFooter::new(1000).size, 1024);
+    }
+
+    #[test]
+    fn restore_appends_footer() {
+        let store = store_tar();
+        let td = TempDir::new("vhd").unwrap();
+        let raw = td.path().join("raw");
+        let vhd = td.path().join("img.vhd");
+        let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98")).unwrap();
+        e.extract(RandomAccess::new(&raw, None)).unwrap();
+        e.extract(Vhd::new(RandomAccess::new(&vhd, None).atomic()))
+            .unwrap();
+        let (raw, vhd) = (std::fs::read(raw).unwrap(), std::fs::read(vhd).unwrap());
+        assert_eq!(vhd.len(), raw.len() + 512);
+        assert_eq!(vhd[..raw.len()], raw[..]);
+        assert_eq!(&vhd[raw.len()..raw.len() + 8], b"conectix");
+        assert_eq!(
+            BigEndian::read_u64(&vhd[raw.len() + 48..]),
+            raw.len() as u64
+        );
+    }
+}
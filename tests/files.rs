@@ -0,0 +1,343 @@
+//! File extraction from guest file systems. Images are created with mke2fs, tests are skipped
+//! if it is not installed.
+
+#![cfg(not(feature = "read-only"))]
+
+use backy_extract::testing::{self, StoreBuilder};
+use backy_extract::*;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tempdir::TempDir;
+
+const REV: &str = "FilesRevisionzzzzzzzzz";
+const MIB: usize = 1 << 20;
+
+fn populate(dir: &Path) {
+    fs::create_dir_all(dir.join("etc/deep/er")).unwrap();
+    fs::write(dir.join("etc/hostname"), "guest\n").unwrap();
+    fs::write(dir.join("etc/deep/er/empty"), "").unwrap();
+    let big: Vec<u8> = (0..5 * MIB).map(|i| (i * 7 % 251) as u8).collect();
+    fs::write(dir.join("etc/big"), &big).unwrap();
+    let f = File::create(dir.join("etc/sparse")).unwrap();
+    f.set_len(3 * MIB as u64).unwrap();
+    symlink("hostname", dir.join("etc/fast")).unwrap();
+    symlink("x".repeat(120), dir.join("etc/slow")).unwrap();
+    fs::hard_link(dir.join("etc/hostname"), dir.join("etc/hardlink")).unwrap();
+    fs::write(dir.join("etc").join("n".repeat(150)), "long name").unwrap();
+    fs::create_dir(dir.join("var")).unwrap();
+    symlink("../etc", dir.join("var/etc")).unwrap();
+}
+
+// Creates an image with a file system of `size` made by mke2fs, starting `offset` bytes into the
+// image. The file system is populated from `tmp/src`, which is created if missing.
+fn mkfs(tmp: &Path, args: &[&str], size: &str, offset: usize) -> Option<Vec<u8>> {
+    let src = tmp.join("src");
+    if !src.exists() {
+        populate(&src);
+    }
+    let fsimg = tmp.join("fs.img");
+    fs::remove_file(&fsimg).ok();
+    let ok = Command::new("mke2fs")
+        .args(args)
+        .arg("-q")
+        .arg("-F")
+        .arg("-d")
+        .arg(&src)
+        .arg(&fsimg)
+        .arg(size)
+        .stdout(Stdio::null())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false);
+    if !ok {
+        eprintln!("mke2fs not available, skipping");
+        return None;
+    }
+    let mut img = vec![0; offset];
+    img.extend(fs::read(&fsimg).unwrap());
+    img.resize(img.len().div_ceil(16 * MIB) * 16 * MIB, 0);
+    Some(img)
+}
+
+fn store(tmp: &Path, img: &[u8]) -> Extractor {
+    let maps = StoreBuilder::new()
+        .revision(REV, testing::chunked(img))
+        .write(tmp.join("store"))
+        .unwrap();
+    Extractor::init(&maps[0]).unwrap()
+}
+
+fn untar(tmp: &Path, buf: &[u8]) -> std::path::PathBuf {
+    let out = tmp.join("out");
+    fs::remove_dir_all(&out).ok();
+    tar::Archive::new(buf).unpack(&out).unwrap();
+    out
+}
+
+fn check_etc(src: &Path, out: &Path) {
+    for f in &["hostname", "big", "sparse", "deep/er/empty"] {
+        assert!(
+            fs::read(src.join("etc").join(f)).unwrap()
+                == fs::read(out.join("etc").join(f)).unwrap(),
+            "{} differs",
+            f
+        );
+    }
+    let long = "n".repeat(150);
+    assert_eq!(fs::read(out.join("etc").join(long)).unwrap(), b"long name");
+    assert_eq!(
+        fs::read_link(out.join("etc/fast")).unwrap(),
+        Path::new("hostname")
+    );
+    assert_eq!(
+        fs::read_link(out.join("etc/slow")).unwrap(),
+        Path::new(&"x".repeat(120))
+    );
+    assert_eq!(
+        fs::metadata(out.join("etc/hardlink")).unwrap().ino(),
+        fs::metadata(out.join("etc/hostname")).unwrap().ino()
+    );
+}
+
+#[test]
+fn extract_from_ext4() {
+    let tmp = TempDir::new("files").unwrap();
+    let img = match mkfs(tmp.path(), &["-t", "ext4"], "12M", 0) {
+        Some(img) => img,
+        None => return,
+    };
+    let e = store(tmp.path(), &img);
+    let mut buf = Vec::new();
+    let stats = e.files(&["/var/etc/"], None, &mut buf).unwrap();
+    assert_eq!(stats.symlinks, 2);
+    let out = untar(tmp.path(), &buf);
+    assert!(out.join("var/etc/big").exists());
+
+    buf.clear();
+    let stats = e.files(&["/etc"], None, &mut buf).unwrap();
+    assert_eq!(
+        (stats.files, stats.dirs, stats.symlinks, stats.hardlinks),
+        (5, 3, 2, 1)
+    );
+    assert_eq!(stats.bytes, (8 * MIB + 6 + 9) as u64);
+    let out = untar(tmp.path(), &buf);
+    check_etc(&tmp.path().join("src"), &out);
+
+    match e.files(&["/etc/nonexistent"], None, &mut Vec::new()) {
+        Err(ExtractError::GuestFs(guestfs::Error::NoSuchPath(p))) => {
+            assert_eq!(p, "/etc/nonexistent")
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn extract_from_ext2_partition() {
+    let tmp = TempDir::new("files").unwrap();
+    // 1k blocks need double indirect blocks for etc/big
+    let mut img = match mkfs(tmp.path(), &["-t", "ext2", "-b", "1024"], "12M", MIB) {
+        Some(img) => img,
+        None => return,
+    };
+    // MBR with a single Linux partition starting at 1 MiB
+    let entry = &mut img[446..462];
+    entry[4] = 0x83;
+    entry[8..12].copy_from_slice(&2048u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&(12 * 2048u32).to_le_bytes());
+    img[510] = 0x55;
+    img[511] = 0xAA;
+    let e = store(tmp.path(), &img);
+    let mut buf = Vec::new();
+    e.files(&["/etc"], None, &mut buf).unwrap();
+    check_etc(&tmp.path().join("src"), &untar(tmp.path(), &buf));
+    assert!(e.files(&["/etc"], Some(1), &mut Vec::new()).is_ok());
+    assert!(matches!(
+        e.files(&["/etc"], Some(2), &mut Vec::new()),
+        Err(ExtractError::NoPartition(2))
+    ));
+}
+
+#[test]
+fn tar_stream_is_readable_by_tar() {
+    let tmp = TempDir::new("files").unwrap();
+    let img = match mkfs(tmp.path(), &["-t", "ext4"], "12M", 0) {
+        Some(img) => img,
+        None => return,
+    };
+    let e = store(tmp.path(), &img);
+    let mut buf = Vec::new();
+    e.files(&["/"], None, &mut buf).unwrap();
+    let mut names: Vec<String> = tar::Archive::new(&buf[..])
+        .entries()
+        .unwrap()
+        .map(|e| e.unwrap().path().unwrap().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert!(names.contains(&"./etc/hostname".to_string()));
+    assert!(names.contains(&"./lost+found/".to_string()));
+    // etc/hardlink sorts first and carries the data
+    let mut hostname = String::new();
+    for entry in tar::Archive::new(&buf[..]).entries().unwrap() {
+        let mut entry = entry.unwrap();
+        if entry.path().unwrap() == Path::new("./etc/hardlink") {
+            entry.read_to_string(&mut hostname).unwrap();
+        }
+    }
+    assert_eq!(hostname, "guest\n");
+}
+
+#[test]
+fn scrub_file_contents() {
+    let tmp = TempDir::new("files").unwrap();
+    let img = match mkfs(tmp.path(), &["-t", "ext4"], "12M", 0) {
+        Some(img) => img,
+        None => return,
+    };
+    let mut e = store(tmp.path(), &img);
+    let ranges = e.file_ranges(&["/etc/big"], None).unwrap();
+    assert_eq!(
+        ranges.iter().map(|r| r.end - r.start).sum::<u64>(),
+        5 * MIB as u64
+    );
+    assert!(matches!(
+        e.file_ranges(&["/etc"], None),
+        Err(ExtractError::GuestFs(guestfs::Error::NotAFile(p))) if p == "/etc"
+    ));
+    let scrub = filter::builtin("scrub-file:/etc/big", &e).unwrap();
+    let mut out = Vec::new();
+    e.filter(scrub).extract(Memory::new(&mut out)).unwrap();
+    let mut pos = 0;
+    for r in ranges {
+        let (start, end) = (r.start as usize, r.end as usize);
+        assert_eq!(out[pos..start], img[pos..start]);
+        assert!(out[start..end].iter().all(|&b| b == 0));
+        assert!(img[start..end].iter().any(|&b| b != 0));
+        pos = end;
+    }
+    assert_eq!(out[pos..], img[pos..]);
+}
+
+// Free block ranges as listed by dumpe2fs
+fn dumpe2fs(img: &Path) -> Vec<(u64, u64)> {
+    let out = Command::new("dumpe2fs").arg(img).output().unwrap();
+    let mut free = Vec::new();
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        // per-group lines are indented, the summary line is not
+        if !line.starts_with("  Free blocks: ") {
+            continue;
+        }
+        for r in line["  Free blocks: ".len()..]
+            .split(", ")
+            .filter(|r| !r.is_empty())
+        {
+            let mut ends = r.split('-').map(|n| n.parse::<u64>().unwrap());
+            let start = ends.next().unwrap();
+            let end = ends.next().unwrap_or(start) + 1;
+            match free.last_mut() {
+                Some((_, e)) if *e == start => *e = end,
+                _ => free.push((start, end)),
+            }
+        }
+    }
+    free
+}
+
+// Creates a file system with small block groups, some of them uninitialized, and removes a large
+// file afterwards so that its data stays behind in free blocks.
+fn mkfs_with_garbage(tmp: &Path) -> Option<Vec<u8>> {
+    let src = tmp.join("src");
+    fs::create_dir_all(src.join("etc")).unwrap();
+    fs::write(src.join("etc/hostname"), "guest\n").unwrap();
+    let big: Vec<u8> = (0..20 * MIB).map(|i| (i % 253) as u8 | 1).collect();
+    fs::write(src.join("etc/big"), &big).unwrap();
+    let img = mkfs(tmp, &["-t", "ext4", "-b", "4096", "-g", "2048"], "64M", 0)?;
+    let fsimg = tmp.join("fs.img");
+    fs::write(&fsimg, &img).unwrap();
+    let ok = Command::new("debugfs")
+        .args(["-w", "-R", "rm /etc/big"])
+        .arg(&fsimg)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap()
+        .success();
+    assert!(ok, "debugfs failed");
+    Some(fs::read(&fsimg).unwrap())
+}
+
+fn check_unallocated(fsimg: &Path) {
+    let mut ext4 = guestfs::ext4::Ext4::open(File::open(fsimg).unwrap(), 0).unwrap();
+    let bs = ext4.block_size();
+    let free: Vec<(u64, u64)> = ext4
+        .unallocated()
+        .unwrap()
+        .into_iter()
+        .map(|r| (r.start / bs, r.end / bs))
+        .collect();
+    assert_eq!(free, dumpe2fs(fsimg));
+}
+
+#[test]
+fn unallocated_matches_dumpe2fs() {
+    let tmp = TempDir::new("files").unwrap();
+    if mkfs(tmp.path(), &["-t", "ext2", "-b", "1024"], "12M", 0).is_none() {
+        return;
+    }
+    check_unallocated(&tmp.path().join("fs.img"));
+    let mut img = mkfs_with_garbage(tmp.path()).unwrap();
+    check_unallocated(&tmp.path().join("fs.img"));
+    // journal needs recovery
+    img[1024 + 0x60] |= 0x4;
+    let mut ext4 = guestfs::ext4::Ext4::open(&mut img[..], 0).unwrap();
+    assert!(matches!(ext4.unallocated(), Err(guestfs::Error::Unclean)));
+}
+
+#[test]
+fn skip_unallocated_chunks() {
+    let tmp = TempDir::new("files").unwrap();
+    let img = match mkfs_with_garbage(tmp.path()) {
+        Some(img) => img,
+        None => return,
+    };
+    let mut e = store(tmp.path(), &img);
+    let mut restored = Vec::new();
+    let report = e
+        .skip_unallocated(true)
+        .extract(Stream::new(&mut restored))
+        .unwrap();
+    assert!(report.unallocated >= 3, "{:?}", report);
+    assert_eq!(restored.len(), img.len());
+    let free = dumpe2fs(&tmp.path().join("fs.img"));
+    let mut zeroed = 0;
+    for (i, (a, b)) in img
+        .chunks(4 * MIB)
+        .zip(restored.chunks(4 * MIB))
+        .enumerate()
+    {
+        if a != b {
+            assert!(b.iter().all(|&x| x == 0), "chunk {} not zeroed", i);
+            // 1024 blocks per chunk
+            let (start, end) = (i as u64 * 1024, (i as u64 + 1) * 1024);
+            assert!(
+                free.iter().any(|&(s, e)| s <= start && end <= e),
+                "chunk {} is allocated",
+                i
+            );
+            zeroed += 1;
+        }
+    }
+    assert_eq!(zeroed, report.unallocated);
+    let restored_img = tmp.path().join("restored.img");
+    fs::write(&restored_img, &restored).unwrap();
+    let fsck = Command::new("e2fsck")
+        .arg("-fn")
+        .arg(&restored_img)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(fsck.success());
+}
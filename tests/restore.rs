@@ -3,7 +3,10 @@ mod common;
 use anyhow::{ensure, Result};
 use backy_extract::*;
 use common::{store_tar, store_with_rev, IMAGE};
-use std::fs::{read, remove_file};
+use std::collections::BTreeSet;
+use std::fs::{copy, read, read_to_string, remove_file, write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 
 #[test]
 fn restore_to_stream() -> Result<()> {
@@ -16,6 +19,20 @@ fn restore_to_stream() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn restore_to_memory() -> Result<()> {
+    let store = store_tar();
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    let mut buf = b"previous contents".to_vec();
+    let report = e.threads(3).extract(Memory::new(&mut buf))?;
+    ensure!(buf == *IMAGE, "restored image contents mismatch");
+    assert_eq!(report.bytes, IMAGE.len() as u64);
+    let mut short = vec![0; IMAGE.len() - 1];
+    let err = e.extract(Memory::slice(&mut short)).unwrap_err();
+    assert_eq!(err.class(), "write");
+    Ok(())
+}
+
 #[test]
 fn restore_to_file() -> Result<()> {
     let store = store_tar();
@@ -34,6 +51,274 @@ fn restore_to_file() -> Result<()> {
     Ok(())
 }
 
+#[derive(Default)]
+struct Counts {
+    bytes: AtomicU64,
+    chunks: AtomicU64,
+    total_chunks: AtomicU64,
+}
+
+#[test]
+fn name_restore_after_revision() -> Result<()> {
+    let store = store_tar();
+    let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    let vm = store.path().file_name().unwrap().to_string_lossy();
+    assert_eq!(
+        e.auto_name("raw")?,
+        format!("{}-20190111T174525Z-VNzWKjnMqd6w58nzJwUZ98.raw", vm)
+    );
+    remove_file(store.path().join("VNzWKjnMqd6w58nzJwUZ98.rev"))?;
+    assert_eq!(e.auto_name("raw").unwrap_err().class(), "revision");
+    Ok(())
+}
+
+#[derive(Default, Clone)]
+struct Counter(Arc<Counts>);
+
+impl ProgressSink for Counter {
+    fn chunks_total(&self, total: u64) {
+        self.0.total_chunks.store(total, Ordering::SeqCst);
+    }
+
+    fn advance(&self, bytes: u64) {
+        self.0.bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn advance_chunks(&self, chunks: u64) {
+        self.0.chunks.fetch_add(chunks, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn progress_counts_unique_chunks() -> Result<()> {
+    let store = store_tar();
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    for &stream in &[false, true] {
+        let counter = Counter::default();
+        e.progress_sink(counter.clone());
+        let mut buf = Vec::new();
+        let report = if stream {
+            e.extract(Stream::new(&mut buf))?
+        } else {
+            e.extract(Memory::new(&mut buf))?
+        };
+        ensure!(buf == *IMAGE, "restored image contents mismatch");
+        let c = &counter.0;
+        assert_eq!(c.bytes.load(Ordering::SeqCst), IMAGE.len() as u64);
+        // 4 positions, but only 2 distinct chunks
+        assert_eq!(report.chunks, 2);
+        assert_eq!(c.chunks.load(Ordering::SeqCst), 2);
+        assert_eq!(c.total_chunks.load(Ordering::SeqCst), 2);
+    }
+    Ok(())
+}
+
+#[test]
+fn restore_with_separate_stages() -> Result<()> {
+    let store = store_tar();
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    e.threads(Concurrency {
+        readers: 3,
+        decompressors: 2,
+        writers: 3,
+        read_queue: 1,
+        write_queue: 1,
+    });
+    let tgt = store.path().join("target_image");
+    e.extract(RandomAccess::new(&tgt, None))?;
+    ensure!(read(&tgt)? == *IMAGE, "restored image contents mismatch");
+    let mut buf = Vec::new();
+    let report = e.verify(true).extract(Stream::new(&mut buf))?;
+    ensure!(buf == *IMAGE, "restored image contents mismatch (stream)");
+    // CPU time is accounted per stage thread
+    let cpu = &report.cpu;
+    assert_eq!(
+        (cpu.load.threads, cpu.read.threads, cpu.decompress.threads),
+        (1, 3, 2)
+    );
+    assert!(cpu.verify.threads > 0);
+    assert_eq!(cpu.write.threads, 1);
+    // read errors surface as such
+    remove_file(
+        store
+            .path()
+            .join("chunks/4d/4db6e194fd398e8edb76e11054d73eb0.chunk.lzo"),
+    )?;
+    let err = e.extract(Stream::new(&mut Vec::new())).unwrap_err();
+    assert!(
+        matches!(err, ExtractError::InvalidChunk { .. }),
+        "{:?}",
+        err
+    );
+    Ok(())
+}
+
+#[test]
+fn poll_status_from_another_thread() -> Result<()> {
+    let store = store_tar();
+    let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    assert_eq!(e.status().phase, Phase::Idle);
+    let mut buf = Vec::new();
+    let done = AtomicBool::new(false);
+    let polled = crossbeam::thread::scope(|s| {
+        let restore = s.spawn(|_| {
+            let res = e.extract(Memory::new(&mut buf));
+            done.store(true, Ordering::SeqCst);
+            res
+        });
+        let mut polled = Vec::new();
+        while !done.load(Ordering::SeqCst) {
+            polled.push(e.status());
+        }
+        restore.join().unwrap().map(|_| polled)
+    })
+    .unwrap()?;
+    assert!(polled.windows(2).all(|w| w[0].bytes <= w[1].bytes));
+    let st = e.status();
+    assert_eq!(st.phase, Phase::Finished);
+    assert_eq!(
+        (st.bytes, st.total_bytes),
+        (IMAGE.len() as u64, IMAGE.len() as u64)
+    );
+    assert_eq!((st.chunks, st.total_chunks, st.errors), (2, 2, 0));
+    assert_eq!(st.eta(), None);
+    remove_file(
+        store
+            .path()
+            .join("chunks/4d/4db6e194fd398e8edb76e11054d73eb0.chunk.lzo"),
+    )?;
+    assert!(e.extract(Memory::new(&mut buf)).is_err());
+    let st = e.status();
+    assert_eq!(st.phase, Phase::Failed);
+    assert_eq!(st.errors, 1);
+    assert!(st
+        .error
+        .unwrap()
+        .contains("4db6e194fd398e8edb76e11054d73eb0"));
+    Ok(())
+}
+
+#[test]
+fn restore_with_reorder_window() -> Result<()> {
+    let store = store_tar();
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    let mut buf = Vec::with_capacity(4 << CHUNKSZ_LOG);
+    let report = e
+        .threads(4)
+        .reorder_window(1)
+        .extract(Stream::new(&mut buf))?;
+    ensure!(buf == *IMAGE, "restored image contents mismatch");
+    assert_eq!(report.bytes, IMAGE.len() as u64);
+    let stats = report.reorder.expect("stream writer reports reorder stats");
+    assert!(stats.max_depth < 4);
+    Ok(())
+}
+
+#[test]
+fn restore_with_limits() -> Result<()> {
+    let store = store_tar();
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    let mut buf = Vec::with_capacity(4 << CHUNKSZ_LOG);
+    e.threads(8).limits(Limits {
+        threads: Some(2),
+        memory: Some(3 * CHUNKSZ),
+        io_class: Some(IoClass::Idle),
+        ..Limits::default()
+    });
+    e.extract(Stream::new(&mut buf))?;
+    ensure!(buf == *IMAGE, "restored image contents mismatch");
+    Ok(())
+}
+
+#[test]
+fn restore_audited() -> Result<()> {
+    let store = store_tar();
+    let log = store.path().join("audit.log");
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    e.audit(audit::AuditLog::open(&log)?, "job-1");
+    e.extract(Stream::new(&mut Vec::new()))?;
+    let mut chunks = BTreeSet::new();
+    for line in read_to_string(&log)?.lines() {
+        let rec: serde_json::Value = serde_json::from_str(line)?;
+        assert_eq!(rec["job"], "job-1");
+        assert_eq!(rec["revision"], "VNzWKjnMqd6w58nzJwUZ98");
+        chunks.insert(rec["chunk"].as_str().unwrap().to_owned());
+    }
+    let expected: BTreeSet<_> = [
+        "4db6e194fd398e8edb76e11054d73eb0",
+        "c72b4ba82d1f51b71c8a18195ad33fc8",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    assert_eq!(chunks, expected);
+    Ok(())
+}
+
+#[test]
+fn restore_pool() -> Result<()> {
+    let (good, bad) = (store_tar(), store_tar());
+    let chunks = bad.path().join("chunks");
+    let victim = chunks.join("c7/c72b4ba82d1f51b71c8a18195ad33fc8.chunk.lzo");
+    remove_file(&victim)?;
+    copy(
+        chunks.join("4d/4db6e194fd398e8edb76e11054d73eb0.chunk.lzo"),
+        &victim,
+    )?;
+    let job = |store: &tempdir::TempDir, name: &str| -> Result<_> {
+        let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+        e.verify(true);
+        Ok((e, RandomAccess::new(good.path().join(name), Some(true))))
+    };
+    let jobs = vec![job(&good, "a")?, job(&bad, "b")?, job(&good, "c")?];
+    let res = RestorePool::new(2).jobs(2).run(jobs);
+    assert_eq!(res.len(), 3);
+    assert!(matches!(res[1], Err(ExtractError::Checksum { .. })));
+    for (r, name) in [(&res[0], "a"), (&res[2], "c")].iter() {
+        assert_eq!(r.as_ref().unwrap().bytes, IMAGE.len() as u64);
+        ensure!(read(good.path().join(name))? == *IMAGE, "{} mismatch", name);
+    }
+    Ok(())
+}
+
+#[test]
+fn verify_sample() -> Result<()> {
+    let (store, single) = store_with_rev(
+        r#"{"mapping": {"0": "4db6e194fd398e8edb76e11054d73eb0",
+                        "1": "c72b4ba82d1f51b71c8a18195ad33fc8"}, "size": 8388608}"#,
+    );
+    let shared = store.path().join("REV0000000000000000001");
+    write(
+        &shared,
+        r#"{"mapping": {"0": "c72b4ba82d1f51b71c8a18195ad33fc8",
+                        "1": "c72b4ba82d1f51b71c8a18195ad33fc8"}, "size": 8388608}"#,
+    )?;
+    let chunks = store.path().join("chunks");
+    let victim = chunks.join("c7/c72b4ba82d1f51b71c8a18195ad33fc8.chunk.lzo");
+    remove_file(&victim)?;
+    copy(
+        chunks.join("4d/4db6e194fd398e8edb76e11054d73eb0.chunk.lzo"),
+        &victim,
+    )?;
+    let restore = |rev, percent| {
+        Extractor::init(rev)
+            .unwrap()
+            .verify_sample(percent)
+            .extract(Stream::new(&mut Vec::new()))
+    };
+    assert!(restore(&single, 0.0).is_ok());
+    assert!(matches!(
+        restore(&single, 100.0),
+        Err(ExtractError::Checksum { .. })
+    ));
+    // chunks used more than once are always verified
+    assert!(matches!(
+        restore(&shared, 0.0),
+        Err(ExtractError::Checksum { .. })
+    ));
+    Ok(())
+}
+
 #[test]
 fn restore_rev_with_holes() -> Result<()> {
     let (_store, rev) = store_with_rev(
@@ -57,3 +342,307 @@ fn unaligned_size() {
         _ => panic!("expected ExtractError::UnalignedSize"),
     }
 }
+
+#[test]
+fn restore_verified() -> Result<()> {
+    let store = store_tar();
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    let mut buf = Vec::with_capacity(4 << CHUNKSZ_LOG);
+    e.threads(3).verify(true).extract(Stream::new(&mut buf))?;
+    ensure!(buf == *IMAGE, "restored image contents mismatch");
+    Ok(())
+}
+
+#[test]
+fn verify_detects_swapped_chunk() {
+    let store = store_tar();
+    let chunks = store.path().join("chunks");
+    let victim = chunks.join("c7/c72b4ba82d1f51b71c8a18195ad33fc8.chunk.lzo");
+    remove_file(&victim).unwrap();
+    copy(
+        chunks.join("4d/4db6e194fd398e8edb76e11054d73eb0.chunk.lzo"),
+        &victim,
+    )
+    .unwrap();
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98")).unwrap();
+    match e.verify(true).extract(Stream::new(&mut Vec::new())) {
+        Err(ExtractError::Checksum { id, .. }) => {
+            assert_eq!(id, "c72b4ba82d1f51b71c8a18195ad33fc8")
+        }
+        other => panic!("expected ExtractError::Checksum, got {:?}", other),
+    }
+}
+
+#[test]
+fn iterate_chunks() -> Result<()> {
+    let store = store_tar();
+    let rev = store.path().join("VNzWKjnMqd6w58nzJwUZ98");
+    let mut e = Extractor::init(&rev)?;
+    e.threads(3);
+    let mut buf = Vec::new();
+    for item in e.chunks() {
+        let (offset, data) = item?;
+        assert_eq!(offset, buf.len() as u64);
+        match data {
+            Data::Some(d) => buf.extend_from_slice(&d),
+            Data::Zero => buf.resize(buf.len() + CHUNKSZ, 0),
+        }
+    }
+    ensure!(buf == *IMAGE, "iterated image contents mismatch");
+
+    // stopping early cancels the restore
+    let mut it = Extractor::init(&rev)?.chunks();
+    ensure!(it.next().is_some());
+    drop(it);
+
+    remove_file(
+        store
+            .path()
+            .join("chunks/c7/c72b4ba82d1f51b71c8a18195ad33fc8.chunk.lzo"),
+    )?;
+    let last = Extractor::init(&rev)?.chunks().last();
+    ensure!(matches!(last, Some(Err(ExtractError::InvalidChunk { .. }))));
+    Ok(())
+}
+
+#[test]
+fn classify_chunk_errors() -> Result<()> {
+    let store = store_tar();
+    let chunk = store
+        .path()
+        .join("chunks/c7/c72b4ba82d1f51b71c8a18195ad33fc8.chunk.lzo");
+    let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    let mut data = read(&chunk)?;
+    data.truncate(100);
+    remove_file(&chunk)?;
+    let err = e.extract(Stream::new(Vec::new())).unwrap_err();
+    assert_eq!(err.fault(), Some(Fault::NotFound));
+    assert!(!err.is_transient());
+    assert_eq!(err.chunk_file(), Some(chunk.as_path()));
+    assert_eq!(err.failed_check(), None);
+    write(&chunk, &data)?;
+    let err = e.extract(Stream::new(Vec::new())).unwrap_err();
+    assert_eq!(err.fault(), Some(Fault::Corrupt));
+    assert_eq!(err.failed_check(), Some(Check::Lzo));
+    match err {
+        ExtractError::InvalidChunk { file, .. } => assert_eq!(file.compressed, Some(100)),
+        other => panic!("unexpected error {:?}", other),
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "read-only"))]
+#[test]
+fn quarantine_corrupt_chunks() -> Result<()> {
+    let store = store_tar();
+    let chunks = store.path().join("chunks");
+    let corrupt = chunks.join("4d/4db6e194fd398e8edb76e11054d73eb0.chunk.lzo");
+    let mut data = read(&corrupt)?;
+    data.truncate(100);
+    write(&corrupt, &data)?;
+    remove_file(chunks.join("c7/c72b4ba82d1f51b71c8a18195ad33fc8.chunk.lzo"))?;
+    let e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    let report = e.damage_report()?;
+    assert_eq!(report.chunks.len(), 2);
+    // missing chunks are not moved
+    let moved = e.quarantine(&report)?;
+    assert_eq!(moved.len(), 1);
+    assert_eq!(read(&moved[0])?, data);
+    assert!(!corrupt.exists());
+    let manifest = read_to_string(chunks.join("quarantine/manifest.jsonl"))?;
+    assert_eq!(manifest.lines().count(), 1);
+    assert!(manifest.contains("4db6e194fd398e8edb76e11054d73eb0"));
+    let report = e.damage_report()?;
+    assert!(report
+        .chunks
+        .iter()
+        .all(|c| c.fault == Some(Fault::NotFound)));
+    Ok(())
+}
+
+#[test]
+fn damage_report_skips_verified_chunks() -> Result<()> {
+    let store = store_tar();
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    e.hash_cache(true);
+    let report = e.damage_report()?;
+    assert_eq!((report.checked, report.cached), (2, 0));
+    ensure!(store.path().join("chunks/hashcache").exists());
+    let report = e.damage_report()?;
+    assert_eq!(report.cached, 2);
+
+    // valid chunk file with the wrong contents
+    let chunk = store
+        .path()
+        .join("chunks/4d/4db6e194fd398e8edb76e11054d73eb0.chunk.lzo");
+    let other = read(
+        store
+            .path()
+            .join("chunks/c7/c72b4ba82d1f51b71c8a18195ad33fc8.chunk.lzo"),
+    )?;
+    write(&chunk, &other)?;
+    let report = e.damage_report()?;
+    assert_eq!(report.cached, 1);
+    assert_eq!(report.chunks.len(), 1);
+    assert_eq!(report.chunks[0].fault, Some(Fault::Corrupt));
+    assert!(report.chunks[0].error.contains("checksum"));
+    // damaged chunks are not cached
+    assert_eq!(e.damage_report()?.chunks.len(), 1);
+    assert_eq!(e.hash_cache(false).damage_report()?.cached, 0);
+    Ok(())
+}
+
+#[test]
+fn precheck_finds_missing_chunk() -> Result<()> {
+    let store = store_tar();
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    let pc = e.threads(2).precheck(None)?;
+    ensure!(pc.is_complete() && pc.is_clean(), "unexpected {:?}", pc);
+    remove_file(
+        store
+            .path()
+            .join("chunks/c7/c72b4ba82d1f51b71c8a18195ad33fc8.chunk.lzo"),
+    )?;
+    let pc = e.precheck(None)?;
+    assert_eq!(pc.chunks.len(), 1);
+    assert_eq!(pc.chunks[0].id, "c72b4ba82d1f51b71c8a18195ad33fc8");
+    assert_eq!(pc.chunks[0].fault, Some(Fault::NotFound));
+    assert_eq!(pc.checked, pc.total);
+    // nothing gets checked if time is up already
+    let pc = e.precheck(Some(std::time::Duration::from_secs(0)))?;
+    ensure!(!pc.is_complete() && pc.checked == 0, "unexpected {:?}", pc);
+    Ok(())
+}
+
+#[test]
+fn restore_atomic() -> Result<()> {
+    let store = store_tar();
+    let tgt = store.path().join("image");
+    let partial = store.path().join("image.partial");
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    e.threads(2)
+        .extract(RandomAccess::new(&tgt, None).atomic())?;
+    ensure!(read(&tgt)? == *IMAGE, "restored image contents mismatch");
+    ensure!(!partial.exists(), "partial file left behind");
+    // a failed restore leaves the previous image alone
+    remove_file(
+        store
+            .path()
+            .join("chunks/c7/c72b4ba82d1f51b71c8a18195ad33fc8.chunk.lzo"),
+    )?;
+    ensure!(e.extract(RandomAccess::new(&tgt, None).atomic()).is_err());
+    ensure!(read(&tgt)? == *IMAGE, "target has been modified");
+    ensure!(!partial.exists(), "partial file left behind");
+    Ok(())
+}
+
+#[test]
+fn restore_exclusive() -> Result<()> {
+    let store = store_tar();
+    let tgt = store.path().join("image");
+    let partial = store.path().join("image.partial");
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    e.threads(2);
+    write(&tgt, b"taken")?;
+    ensure!(e
+        .extract(RandomAccess::new(&tgt, None).exclusive())
+        .is_err());
+    ensure!(e
+        .extract(RandomAccess::new(&tgt, None).atomic().exclusive())
+        .is_err());
+    ensure!(read(&tgt)? == b"taken", "existing file has been replaced");
+    ensure!(!partial.exists(), "partial file left behind");
+    // a concurrent restore's partial file is left alone
+    remove_file(&tgt)?;
+    write(&partial, b"busy")?;
+    ensure!(e
+        .extract(RandomAccess::new(&tgt, None).atomic().exclusive())
+        .is_err());
+    ensure!(read(&partial)? == b"busy", "foreign partial file touched");
+    remove_file(&partial)?;
+    e.extract(RandomAccess::new(&tgt, None).atomic().exclusive())?;
+    ensure!(read(&tgt)? == *IMAGE, "restored image contents mismatch");
+    Ok(())
+}
+
+#[test]
+fn hooks_describe_job() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    let store = store_tar();
+    let jobs = Arc::new(Mutex::new(Vec::new()));
+    let (ok, failed) = (Arc::clone(&jobs), Arc::clone(&jobs));
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    e.on_success(move |job| ok.lock().unwrap().push(job.clone()))
+        .on_failure(move |job| failed.lock().unwrap().push(job.clone()));
+    e.extract(Stream::new(Vec::new()))?;
+    remove_file(
+        store
+            .path()
+            .join("chunks/c7/c72b4ba82d1f51b71c8a18195ad33fc8.chunk.lzo"),
+    )?;
+    ensure!(e.extract(Stream::new(Vec::new())).is_err());
+    let jobs = jobs.lock().unwrap();
+    assert_eq!(jobs.len(), 2);
+    assert!(jobs[0].succeeded());
+    assert_eq!(jobs[0].revision, "VNzWKjnMqd6w58nzJwUZ98");
+    assert_eq!(jobs[0].bytes, IMAGE.len() as u64);
+    assert!(!jobs[1].succeeded());
+    assert_eq!(jobs[1].error_class, Some("chunk"));
+    assert_eq!(jobs[1].bytes, 0);
+    Ok(())
+}
+
+#[test]
+fn require_trust() -> Result<()> {
+    let store = store_tar();
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    assert_eq!(e.trust(), Trust::Trusted);
+    e.require_trust(Trust::Trusted)
+        .extract(Stream::new(Vec::new()))?;
+    match e
+        .require_trust(Trust::Verified)
+        .extract(Stream::new(Vec::new()))
+    {
+        Err(ExtractError::Untrusted {
+            trust, required, ..
+        }) => {
+            assert_eq!((trust, required), (Trust::Trusted, Trust::Verified))
+        }
+        other => panic!("expected ExtractError::Untrusted, got {:?}", other),
+    }
+    Ok(())
+}
+
+#[test]
+fn patch_rewrites_differing_chunks() -> Result<()> {
+    let store = store_tar();
+    let tgt = store.path().join("image");
+    let mut e = Extractor::init(store.path().join("VNzWKjnMqd6w58nzJwUZ98"))?;
+    e.threads(2);
+    // empty target: every non-zero chunk needs to be written
+    write(&tgt, b"")?;
+    let report = e.patch(&tgt)?;
+    ensure!(read(&tgt)? == *IMAGE, "patched image contents mismatch");
+    assert_eq!(report.chunks, IMAGE.len() >> CHUNKSZ_LOG);
+    // holes of the freshly extended target are not read
+    assert_eq!(
+        report.bytes_read + (report.holes << CHUNKSZ_LOG) as u64,
+        IMAGE.len() as u64
+    );
+    let first = report.patched;
+    assert!(first > 0);
+    // nothing to do when up to date
+    let report = e.patch(&tgt)?;
+    assert_eq!((report.patched, report.bytes_written), (0, 0));
+    // damage one chunk and append garbage
+    let mut img = IMAGE.clone();
+    img[(1 << CHUNKSZ_LOG) + 17] ^= 0xff;
+    img.extend_from_slice(b"trailing garbage");
+    write(&tgt, &img)?;
+    let report = e.patch(&tgt)?;
+    assert_eq!(report.patched, 1);
+    assert_eq!(report.bytes_written, 1 << CHUNKSZ_LOG);
+    ensure!(read(&tgt)? == *IMAGE, "patched image contents mismatch");
+    Ok(())
+}
@@ -0,0 +1,87 @@
+//! Property-based restore tests on synthetic stores.
+
+// synthetic stores cannot be written by read-only builds
+#![cfg(not(feature = "read-only"))]
+
+use backy_extract::testing::{self, Corruption, Slot, StoreBuilder};
+use backy_extract::*;
+use proptest::prelude::*;
+use std::fs::read;
+use tempdir::TempDir;
+
+fn slots() -> impl Strategy<Value = Vec<Slot>> {
+    prop::collection::vec((0u8..3, any::<u8>(), any::<usize>()), 1..6).prop_map(|v| {
+        v.iter()
+            .enumerate()
+            .map(|(i, &(kind, seed, dup))| match kind {
+                0 => Slot::Hole,
+                1 if i > 0 => Slot::Dup(dup % i),
+                _ => Slot::Data(seed),
+            })
+            .collect()
+    })
+}
+
+fn store(slots: &[Slot]) -> (TempDir, Extractor, Vec<u8>) {
+    let tmp = TempDir::new("roundtrip").unwrap();
+    let chunks = testing::layout(slots);
+    let maps = StoreBuilder::new()
+        .revision("RoundtripRevision00000", chunks.clone())
+        .write(tmp.path())
+        .unwrap();
+    let e = Extractor::init(&maps[0]).unwrap();
+    (tmp, e, testing::image(&chunks))
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(12))]
+
+    #[test]
+    fn roundtrip_stream(slots in slots(), threads in 1u8..5) {
+        let (_tmp, mut e, expected) = store(&slots);
+        let mut buf = Vec::new();
+        e.threads(threads).verify(true).extract(Stream::new(&mut buf)).unwrap();
+        prop_assert!(buf == expected);
+    }
+
+    #[test]
+    fn roundtrip_memory(slots in slots(), threads in 1u8..5) {
+        let (_tmp, mut e, expected) = store(&slots);
+        let mut buf = Vec::new();
+        e.threads(threads).extract(Memory::new(&mut buf)).unwrap();
+        prop_assert!(buf == expected);
+        // caller-provided buffers are overwritten completely, including zero chunks
+        let mut buf = vec![0xaa; expected.len() + 1];
+        e.extract(Memory::slice(&mut buf)).unwrap();
+        prop_assert!(buf[..expected.len()] == expected[..]);
+        prop_assert_eq!(buf[expected.len()], 0xaa);
+    }
+
+    #[test]
+    fn roundtrip_random_access(slots in slots(), sparse in any::<bool>(), threads in 1u8..5) {
+        let (tmp, mut e, expected) = store(&slots);
+        let tgt = tmp.path().join("target");
+        e.threads(threads).extract(RandomAccess::new(&tgt, Some(sparse))).unwrap();
+        prop_assert!(read(&tgt).unwrap() == expected);
+    }
+
+    #[test]
+    fn corruption_is_detected(slots in slots(), victim in any::<usize>(), trunc in any::<u64>(),
+                              remove in any::<bool>()) {
+        let chunks = testing::layout(&slots);
+        let ids: Vec<String> = chunks.iter().flatten().map(|c| testing::chunk_id(c)).collect();
+        prop_assume!(!ids.is_empty());
+        let id = &ids[victim % ids.len()];
+        let (tmp, mut e, _) = store(&slots);
+        let len = testing::chunk_path(tmp.path(), id).metadata().unwrap().len();
+        testing::corrupt(tmp.path(), id, if remove {
+            Corruption::Remove
+        } else {
+            Corruption::Truncate(trunc % len)
+        }).unwrap();
+        prop_assert!(e.verify(true).extract(Stream::new(&mut Vec::new())).is_err());
+        let report = e.damage_report().unwrap();
+        prop_assert_eq!(report.chunks.len(), 1);
+        prop_assert_eq!(&report.chunks[0].id, id);
+    }
+}